@@ -0,0 +1,892 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, Write};
+use std::ops::Deref;
+
+use crate::back::abi::{AAPCS64, ArgLoc, CallConv};
+use crate::back::frame::FrameLayout;
+use crate::back::isel::{any, bin, imm, Match, Rule, Selection, Selector};
+use crate::back::regalloc::{AllocFn, Location, RegAlloc};
+use crate::back::switch::{Lowering, Node, Switch, SwitchConf};
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{BinOp, CastOp, Inst, RmwOp, UnOp};
+use crate::lang::layout::DataLayout;
+use crate::lang::Program;
+use crate::lang::value::{GlobalVar, Linkage, Symbol, SymbolRef, Type, Typed, Value};
+
+const CONV: CallConv = AAPCS64;
+
+/// Action of an instruction selection rule, which emits code for the matched tree
+type Emit = fn(&mut A64Gen<'_>, &Match) -> Result<(), Error>;
+
+/// Instruction selection rules, tried in order
+fn rules() -> Vec<Rule<Emit>> {
+    vec![
+        Rule {
+            name: "madd",
+            pat: bin(BinOp::Add, bin(BinOp::Mul, any("x"), any("y")), any("z")),
+            emit: |gen, m| {
+                gen.load(&RefCell::new(m.get("x").clone()), "x9")?;
+                gen.load(&RefCell::new(m.get("y").clone()), "x10")?;
+                gen.load(&RefCell::new(m.get("z").clone()), "x11")?;
+                writeln!(gen.writer, "\tmadd x9, x9, x10, x11")?;
+                gen.extend("x9", &m.dst.get_type())?;
+                gen.store("x9", &m.dst)
+            },
+        },
+        Rule {
+            name: "add_imm",
+            pat: bin(BinOp::Add, any("x"), imm("c", |c| (0..4096).contains(&c))),
+            emit: |gen, m| {
+                gen.load(&RefCell::new(m.get("x").clone()), "x9")?;
+                writeln!(gen.writer, "\tadd x9, x9, #{}", m.imm("c"))?;
+                gen.extend("x9", &m.dst.get_type())?;
+                gen.store("x9", &m.dst)
+            },
+        },
+    ]
+}
+
+/// Code generator for AArch64 assembly in GNU syntax, following AAPCS64 on Linux.
+/// Like the x86-64 one, phi instructions are eliminated by parallel copies on the CFG edges.
+/// Note that division by zero does not trap on this target, and the result is zero.
+///
+/// `x9` to `x12` are scratch registers. `x11` is also used for addresses of global variables,
+/// and `x12` for frame offsets that cannot be encoded in an instruction. `x13` and `x14` are
+/// used by loops of `memcpy` and `memset`, and `x13` also by atomic read-modify-write.
+pub struct A64Gen<'a> {
+    writer: &'a mut dyn Write,
+    /// Register allocation result of current function
+    alloc: Option<AllocFn>,
+    /// Offsets to frame pointer of memory allocated by `alloc` instructions
+    frame: HashMap<SymbolRef, i64>,
+    /// Size of stack frame of current function, excluding the frame record
+    frame_size: i64,
+    /// Counter for generating local labels
+    label_num: usize,
+    /// Block placed right after the current one, which can be reached by fallthrough
+    next: Option<BlockRef>,
+    /// Data layout of the program, with pointer size of this target
+    layout: DataLayout,
+    /// Configuration of lowering `eq` branch chains
+    switch: SwitchConf,
+}
+
+impl A64Gen<'_> {
+    pub fn new(writer: &mut dyn Write) -> A64Gen<'_> {
+        A64Gen {
+            writer,
+            alloc: None,
+            frame: Default::default(),
+            frame_size: 0,
+            label_num: 0,
+            next: None,
+            layout: DataLayout::x64(),
+            switch: SwitchConf::default(),
+        }
+    }
+
+    /// Set configuration of lowering `eq` branch chains to jump tables or compare trees.
+    pub fn switch(mut self, conf: SwitchConf) -> Self {
+        self.switch = conf;
+        self
+    }
+
+    /// Generate assembly for the whole program. Note that register allocation may insert spill
+    /// slots into the functions.
+    pub fn emit(&mut self, pro: &Program) -> Result<(), Error> {
+        self.layout = DataLayout { ptr_size: 8, ..pro.layout };
+
+        // Emit global variables, where constant ones are placed in read-only section
+        for (is_const, sect) in [(false, ".data"), (true, ".section .rodata")] {
+            let vars: Vec<_> = pro.vars.iter().filter(|g| g.is_const == is_const).collect();
+            if vars.is_empty() { continue; }
+            writeln!(self.writer, "\t{}", sect)?;
+            for g in vars {
+                self.emit_global(g)?;
+            }
+            writeln!(self.writer)?;
+        }
+
+        // Emit functions
+        writeln!(self.writer, "\t.text")?;
+        for func in &pro.func {
+            self.emit_fn(func)?;
+            writeln!(self.writer)?;
+        }
+
+        // Mark stack as non-executable
+        writeln!(self.writer, "\t.section .note.GNU-stack,\"\",%progbits")
+    }
+
+    fn emit_global(&mut self, g: &GlobalVar) -> Result<(), Error> {
+        self.emit_linkage(&g.name, g.linkage)?;
+        writeln!(self.writer, "\t.balign {}", g.ty.align_of(&self.layout))?;
+        writeln!(self.writer, "{}:", g.name)?;
+        match (&g.init, self.size_of(&g.ty)) {
+            (Some(c), 1) => writeln!(self.writer, "\t.byte {}", c.as_i64()),
+            (Some(c), 2) => writeln!(self.writer, "\t.short {}", c.as_i64()),
+            (Some(c), 4) => writeln!(self.writer, "\t.long {}", c.as_i64()),
+            (Some(c), _) => writeln!(self.writer, "\t.quad {}", c.as_i64()),
+            (None, size) => writeln!(self.writer, "\t.zero {}", size.max(1))
+        }
+    }
+
+    fn emit_linkage(&mut self, name: &str, linkage: Linkage) -> Result<(), Error> {
+        match linkage {
+            Linkage::Internal => Ok(()),
+            Linkage::Export => writeln!(self.writer, "\t.globl {}", name),
+            Linkage::Weak => writeln!(self.writer, "\t.weak {}", name)
+        }
+    }
+
+    fn emit_fn(&mut self, func: &FnRef) -> Result<(), Error> {
+        // Allocate registers and stack frame
+        let alloc = RegAlloc::new(CONV.saved_regs.len()).alloc(func);
+        let n_saved = alloc.n_used as i64;
+        let frame = FrameLayout::new().layout(func, &alloc, &self.layout, n_saved * 8);
+        self.alloc = Some(alloc);
+        self.frame = frame.offset;
+        self.frame_size = frame.size;
+
+        // Emit prologue, which pushes frame record of `x29` and `x30`
+        self.emit_linkage(&func.name, func.effective_linkage())?;
+        writeln!(self.writer, "\t.p2align 2")?;
+        writeln!(self.writer, "{}:", func.name)?;
+        writeln!(self.writer, "\tstp x29, x30, [sp, #-16]!")?;
+        writeln!(self.writer, "\tmov x29, sp")?;
+        if self.frame_size > 0 {
+            self.mov_imm("x9", self.frame_size)?;
+            writeln!(self.writer, "\tsub sp, sp, x9")?;
+        }
+        for (i, reg) in CONV.saved_regs[..n_saved as usize].iter().enumerate() {
+            let addr = self.frame_addr(-8 * (i as i64 + 1))?;
+            writeln!(self.writer, "\tstr {}, {}", reg, addr)?;
+        }
+
+        // Move parameters to their locations
+        for (i, param) in func.param.iter().enumerate() {
+            let param = param.borrow().clone();
+            match CONV.param_loc(i) {
+                ArgLoc::Reg(reg) => self.store(reg, &param)?,
+                ArgLoc::Stack(off) => {
+                    let addr = self.frame_addr(off)?;
+                    writeln!(self.writer, "\tldr x9, {}", addr)?;
+                    self.store("x9", &param)?
+                }
+            }
+        }
+
+        // Emit blocks in layout order, with instructions selected by rules. Comparisons of
+        // switches are replaced by their dispatch code, so the chain blocks are not emitted.
+        let isel = Selector::new(rules());
+        let mut sel = isel.select(func);
+        let switch: HashMap<_, _> = self.switch.find(func).into_iter()
+            .map(|sw| (sw.head.clone(), sw)).collect();
+        let chain: HashSet<_> = switch.values().flat_map(|sw| sw.chain.clone()).collect();
+        let layout: Vec<_> = func.layout().into_iter().filter(|b| !chain.contains(b)).collect();
+        for (i, block) in layout.iter().enumerate() {
+            self.next = layout.get(i + 1).cloned();
+            writeln!(self.writer, "{}:", self.block_label(func, block))?;
+            let mut inst: Vec<_> = block.inst.borrow().iter().cloned().collect();
+            if switch.contains_key(block) { inst.truncate(inst.len() - 2); }
+            for (instr, sel) in inst.iter().zip(sel.remove(block).unwrap()) {
+                match sel {
+                    Selection::Default => self.emit_instr(func, block, instr.as_ref())?,
+                    Selection::Covered => {}
+                    Selection::Rule(rule, m) => (rule.emit)(self, &m)?
+                }
+            }
+            if let Some(sw) = switch.get(block) { self.emit_switch(func, sw)?; }
+        }
+        Ok(())
+    }
+
+    /// Emit dispatch code of `sw`, as a jump table or a compare tree.
+    fn emit_switch(&mut self, func: &FnRef, sw: &Switch) -> Result<(), Error> {
+        self.load(&RefCell::new(Value::Var(sw.val.clone())), "x9")?;
+        let default = self.block_label(func, &sw.default);
+        match self.switch.lower(sw) {
+            Lowering::Table { low, tgt } => {
+                // Values out of range wrap to large unsigned indices after rebasing
+                if low != 0 {
+                    self.mov_imm("x10", low)?;
+                    writeln!(self.writer, "\tsub x9, x9, x10")?;
+                }
+                self.mov_imm("x10", tgt.len() as i64 - 1)?;
+                writeln!(self.writer, "\tcmp x9, x10")?;
+                writeln!(self.writer, "\tb.hi {}", default)?;
+
+                // Entries of the table are offsets of targets to the table itself, so that
+                // the code is position independent
+                self.label_num += 1;
+                let table = format!(".L{}.{}", func.name, self.label_num);
+                self.global_addr(&table, "x10")?;
+                writeln!(self.writer, "\tldrsw x11, [x10, x9, lsl #2]")?;
+                writeln!(self.writer, "\tadd x10, x10, x11")?;
+                writeln!(self.writer, "\tbr x10")?;
+                writeln!(self.writer, "\t.section .rodata")?;
+                writeln!(self.writer, "\t.p2align 2")?;
+                writeln!(self.writer, "{}:", table)?;
+                for blk in &tgt {
+                    writeln!(self.writer, "\t.word {}-{}", self.block_label(func, blk), table)?;
+                }
+                writeln!(self.writer, "\t.text")
+            }
+            Lowering::Tree(node) => self.emit_node(func, &node, &default)
+        }
+    }
+
+    /// Emit compare tree rooted at `node`, with the value in `x9`.
+    fn emit_node(&mut self, func: &FnRef, node: &Node, default: &str) -> Result<(), Error> {
+        match node {
+            Node::Leaf(cases) => {
+                for (val, tgt) in cases {
+                    self.mov_imm("x10", *val)?;
+                    writeln!(self.writer, "\tcmp x9, x10")?;
+                    writeln!(self.writer, "\tb.eq {}", self.block_label(func, tgt))?;
+                }
+                writeln!(self.writer, "\tb {}", default)
+            }
+            Node::Split { pivot, tgt, lt, gt } => {
+                self.label_num += 1;
+                let lt_label = format!(".L{}.{}", func.name, self.label_num);
+                self.mov_imm("x10", *pivot)?;
+                writeln!(self.writer, "\tcmp x9, x10")?;
+                writeln!(self.writer, "\tb.eq {}", self.block_label(func, tgt))?;
+                writeln!(self.writer, "\tb.lt {}", lt_label)?;
+                self.emit_node(func, gt, default)?;
+                writeln!(self.writer, "{}:", lt_label)?;
+                self.emit_node(func, lt, default)
+            }
+        }
+    }
+
+    fn emit_instr(&mut self, func: &FnRef, block: &BlockRef, instr: &Inst)
+                  -> Result<(), Error>
+    {
+        match instr {
+            Inst::Phi { src: _, dst: _ } => {} // handled on edges
+            Inst::Mov { src, dst } => {
+                self.load(src, "x9")?;
+                self.store("x9", &dst.borrow())?;
+            }
+            Inst::Un { op, opd, dst } => {
+                self.load(opd, "x9")?;
+                let ty = dst.borrow().get_type();
+                match op {
+                    UnOp::Neg => writeln!(self.writer, "\tneg x9, x9")?,
+                    UnOp::Not if ty == Type::I(1) => writeln!(self.writer, "\teor x9, x9, #1")?,
+                    UnOp::Not => writeln!(self.writer, "\tmvn x9, x9")?
+                }
+                self.extend("x9", &ty)?;
+                self.store("x9", &dst.borrow())?;
+            }
+            Inst::Cast { op, opd, dst } => {
+                // Values are kept sign-extended in registers, so only extension from narrower
+                // bits needs extra work.
+                self.load(opd, "x9")?;
+                match (op, opd.borrow().get_type().orig()) {
+                    (CastOp::ZExt, Type::I(8)) => writeln!(self.writer, "\tand x9, x9, #0xff")?,
+                    (CastOp::ZExt, Type::I(16)) => writeln!(self.writer, "\tand x9, x9, #0xffff")?,
+                    (CastOp::ZExt, Type::I(32)) => writeln!(self.writer, "\tmov w9, w9")?,
+                    (CastOp::SExt, Type::I(1)) => writeln!(self.writer, "\tneg x9, x9")?,
+                    _ => {}
+                }
+                self.extend("x9", &dst.borrow().get_type())?;
+                self.store("x9", &dst.borrow())?;
+            }
+            Inst::Bin { op, fst, snd, dst } => {
+                self.load(fst, "x9")?;
+                self.load(snd, "x10")?;
+                let ty = dst.borrow().get_type();
+                match op {
+                    BinOp::Add => writeln!(self.writer, "\tadd x9, x9, x10")?,
+                    BinOp::Sub => writeln!(self.writer, "\tsub x9, x9, x10")?,
+                    BinOp::Mul => writeln!(self.writer, "\tmul x9, x9, x10")?,
+                    BinOp::Div => writeln!(self.writer, "\tsdiv x9, x9, x10")?,
+                    BinOp::Mod => {
+                        writeln!(self.writer, "\tsdiv x11, x9, x10")?;
+                        writeln!(self.writer, "\tmsub x9, x11, x10, x9")?;
+                    }
+                    BinOp::And => writeln!(self.writer, "\tand x9, x9, x10")?,
+                    BinOp::Or => writeln!(self.writer, "\torr x9, x9, x10")?,
+                    BinOp::Xor => writeln!(self.writer, "\teor x9, x9, x10")?,
+                    BinOp::Shl | BinOp::Shr => {
+                        // Shift amount is masked by bit width
+                        let bits = match ty.orig() { Type::I(b) => b, _ => 64 };
+                        match bits {
+                            1 => writeln!(self.writer, "\tmov x10, #0")?,
+                            _ => writeln!(self.writer, "\tand x10, x10, #{}", bits - 1)?
+                        }
+                        let instr = if *op == BinOp::Shl { "lsl" } else { "asr" };
+                        writeln!(self.writer, "\t{} x9, x9, x10", instr)?;
+                    }
+                    BinOp::AddOv | BinOp::SubOv | BinOp::MulOv => {
+                        let opd_ty = fst.borrow().get_type();
+                        match (op, opd_ty.orig()) {
+                            (_, Type::I(b)) if b < 64 => {
+                                // Exact result fits in 64 bits, check whether it is preserved
+                                // by truncation.
+                                let instr = match op {
+                                    BinOp::AddOv => "add",
+                                    BinOp::SubOv => "sub",
+                                    _ => "mul"
+                                };
+                                writeln!(self.writer, "\t{} x9, x9, x10", instr)?;
+                                writeln!(self.writer, "\tmov x10, x9")?;
+                                self.extend("x10", &opd_ty)?;
+                                writeln!(self.writer, "\tcmp x9, x10")?;
+                                writeln!(self.writer, "\tcset x9, ne")?;
+                            }
+                            (BinOp::MulOv, _) => {
+                                // Overflow if high half is not the sign extension of low half
+                                writeln!(self.writer, "\tmul x11, x9, x10")?;
+                                writeln!(self.writer, "\tsmulh x9, x9, x10")?;
+                                writeln!(self.writer, "\tcmp x9, x11, asr #63")?;
+                                writeln!(self.writer, "\tcset x9, ne")?;
+                            }
+                            _ => {
+                                let instr = if *op == BinOp::AddOv { "adds" } else { "subs" };
+                                writeln!(self.writer, "\t{} x9, x9, x10", instr)?;
+                                writeln!(self.writer, "\tcset x9, vs")?;
+                            }
+                        }
+                    }
+                    cmp => {
+                        let cond = match cmp {
+                            BinOp::Eq => "eq",
+                            BinOp::Ne => "ne",
+                            BinOp::Lt => "lt",
+                            BinOp::Le => "le",
+                            BinOp::Gt => "gt",
+                            BinOp::Ge => "ge",
+                            _ => unreachable!()
+                        };
+                        writeln!(self.writer, "\tcmp x9, x10")?;
+                        writeln!(self.writer, "\tcset x9, {}", cond)?;
+                    }
+                }
+                self.extend("x9", &ty)?;
+                self.store("x9", &dst.borrow())?;
+            }
+            Inst::Call { func: callee, arg, dst } =>
+                self.emit_call(arg, dst, &callee.ret, |gen| {
+                    writeln!(gen.writer, "\tbl {}", callee.name)
+                })?,
+            Inst::CallInd { func_ptr, arg, dst } => {
+                let ret = func_ptr.borrow().get_type().fn_sig().unwrap().1;
+                self.emit_call(arg, dst, &ret, |gen| {
+                    gen.load(func_ptr, "x16")?;
+                    writeln!(gen.writer, "\tblr x16")
+                })?
+            }
+            Inst::Ret { val } => {
+                for (val, reg) in val.iter().zip(CONV.ret_loc(&func.ret)?) {
+                    self.load(val, reg)?;
+                }
+                if val.is_empty() && func.is_entry() {
+                    writeln!(self.writer, "\tmov x0, #0")?;
+                }
+                let n_saved = self.alloc.as_ref().unwrap().n_used;
+                for (i, reg) in CONV.saved_regs[..n_saved].iter().enumerate() {
+                    let addr = self.frame_addr(-8 * (i as i64 + 1))?;
+                    writeln!(self.writer, "\tldr {}, {}", reg, addr)?;
+                }
+                writeln!(self.writer, "\tmov sp, x29")?;
+                writeln!(self.writer, "\tldp x29, x30, [sp], #16")?;
+                writeln!(self.writer, "\tret")?;
+            }
+            Inst::Unreachable => writeln!(self.writer, "\tbrk #1")?,
+            Inst::Abort { msg: _ } => writeln!(self.writer, "\tbl abort")?,
+            Inst::Jmp { tgt } => {
+                self.emit_phi_copy(block, &tgt.borrow())?;
+                if !self.is_next(&tgt.borrow()) {
+                    writeln!(self.writer, "\tb {}", self.block_label(func, &tgt.borrow()))?;
+                }
+            }
+            Inst::Br { cond, tr, fls } => {
+                // Phi copies on the false edge are emitted in a separate stub
+                self.load(cond, "x9")?;
+                let tr_phi = tr.borrow().inst.borrow().iter().any(|i| i.is_phi());
+                if self.is_next(&fls.borrow()) && !tr_phi {
+                    // Fall through to the false target
+                    writeln!(self.writer, "\tcbnz x9, {}", self.block_label(func, &tr.borrow()))?;
+                    self.emit_phi_copy(block, &fls.borrow())?;
+                    return Ok(());
+                }
+                let fls_label = self.block_label(func, &fls.borrow());
+                let has_phi = fls.borrow().inst.borrow().iter().any(|i| i.is_phi());
+                let stub = if has_phi {
+                    self.label_num += 1;
+                    format!(".L{}.{}", func.name, self.label_num)
+                } else { fls_label.clone() };
+                writeln!(self.writer, "\tcbz x9, {}", stub)?;
+                self.emit_phi_copy(block, &tr.borrow())?;
+                if has_phi || !self.is_next(&tr.borrow()) {
+                    writeln!(self.writer, "\tb {}", self.block_label(func, &tr.borrow()))?;
+                }
+                if has_phi {
+                    writeln!(self.writer, "{}:", stub)?;
+                    self.emit_phi_copy(block, &fls.borrow())?;
+                    writeln!(self.writer, "\tb {}", fls_label)?;
+                }
+            }
+            Inst::Alloc { dst } => {
+                // Spill slots are not assigned locations, and are accessed directly
+                if self.location(&dst.borrow()).is_none() { return Ok(()); }
+                let off = self.frame[&dst.borrow()];
+                self.frame_ptr(off, "x9")?;
+                self.store("x9", &dst.borrow())?;
+            }
+            Inst::New { dst, len } => {
+                // Zero-initialized by `calloc`
+                match len {
+                    Some(len) => self.load(len, "x0")?,
+                    None => writeln!(self.writer, "\tmov x0, #1")?
+                }
+                let size = self.size_of(&dst.borrow().get_type().tgt_type());
+                self.mov_imm("x1", size as i64)?;
+                writeln!(self.writer, "\tbl calloc")?;
+                self.store("x0", &dst.borrow())?;
+            }
+            Inst::Ptr { base, off, ind, dst } => {
+                self.load(base, "x9")?;
+                let mut ty = base.borrow().get_type().tgt_type();
+                if let Some(off) = off {
+                    self.add_offset(off, self.size_of(&ty))?;
+                }
+                for idx in ind {
+                    match (ty.orig(), idx.borrow().deref()) {
+                        (Type::Array { elem, len: _ }, Value::Var(_)) => {
+                            self.add_offset(idx, self.size_of(&elem))?;
+                            ty = elem.deref().clone();
+                        }
+                        (_, Value::Const(c)) => {
+                            let i = c.as_i64() as usize;
+                            let off = ty.field_offset(i, &self.layout);
+                            if off != 0 {
+                                self.mov_imm("x10", off as i64)?;
+                                writeln!(self.writer, "\tadd x9, x9, x10")?;
+                            }
+                            ty = ty.elem_type(i);
+                        }
+                        _ => unreachable!()
+                    }
+                }
+                self.store("x9", &dst.borrow())?;
+            }
+            Inst::Ld { ptr, dst } => {
+                self.load(ptr, "x10")?;
+                let ty = dst.borrow().get_type();
+                writeln!(self.writer, "\t{} {}, [x10]", load_instr(&ty), load_reg("x9", &ty))?;
+                self.store("x9", &dst.borrow())?;
+            }
+            Inst::St { src, ptr } => {
+                self.load(ptr, "x10")?;
+                self.load(src, "x9")?;
+                let size = self.size_of(&src.borrow().get_type());
+                writeln!(self.writer, "\t{} {}, [x10]", store_instr(size), sub_reg("x9", size))?;
+            }
+            Inst::Memcpy { src, ptr, len } => {
+                let ty = src.borrow().get_type().tgt_type();
+                self.load(src, "x13")?;
+                self.emit_mem_loop(func, ptr, len, &ty, |gen| {
+                    writeln!(gen.writer, "\t{} {}, [x13]", load_instr(&ty), load_reg("x9", &ty))?;
+                    writeln!(gen.writer, "\tadd x13, x13, #{}", gen.size_of(&ty))
+                })?;
+            }
+            Inst::Memset { src, ptr, len } => {
+                self.load(src, "x9")?;
+                self.emit_mem_loop(func, ptr, len, &src.borrow().get_type(), |_| Ok(()))?;
+            }
+            // Acquire and release semantics are provided by load-acquire and store-release
+            // instructions, which do not extend the loaded value.
+            Inst::AtomicLd { ord, ptr, dst } => {
+                self.load(ptr, "x10")?;
+                let ty = dst.borrow().get_type();
+                let size = self.size_of(&ty);
+                if ord.is_acquire() {
+                    writeln!(self.writer, "\tldar{} {}, [x10]", suffix(size), sub_reg("x9", size))?;
+                    self.extend("x9", &ty)?;
+                } else {
+                    writeln!(self.writer, "\t{} {}, [x10]", load_instr(&ty),
+                             load_reg("x9", &ty))?;
+                }
+                self.store("x9", &dst.borrow())?;
+            }
+            Inst::AtomicSt { ord, src, ptr } => {
+                self.load(ptr, "x10")?;
+                self.load(src, "x9")?;
+                let size = self.size_of(&src.borrow().get_type());
+                let instr = if ord.is_release() {
+                    format!("stlr{}", suffix(size))
+                } else {
+                    store_instr(size).to_string()
+                };
+                writeln!(self.writer, "\t{} {}, [x10]", instr, sub_reg("x9", size))?;
+            }
+            // Retry exclusive load and store until memory is not changed by others
+            Inst::AtomicRmw { op, ord, ptr, val, dst } => {
+                self.load(ptr, "x10")?;
+                self.load(val, "x11")?;
+                let ty = dst.borrow().get_type();
+                let size = self.size_of(&ty);
+                self.label_num += 1;
+                let head = format!(".L{}.{}", func.name, self.label_num);
+                writeln!(self.writer, "{}:", head)?;
+                let (acq, rel) = (if ord.is_acquire() { "a" } else { "" },
+                                  if ord.is_release() { "l" } else { "" });
+                writeln!(self.writer, "\tld{}xr{} {}, [x10]", acq, suffix(size),
+                         sub_reg("x9", size))?;
+                match op {
+                    RmwOp::Xchg => writeln!(self.writer, "\tmov x12, x11")?,
+                    RmwOp::Add => writeln!(self.writer, "\tadd x12, x9, x11")?,
+                    RmwOp::Sub => writeln!(self.writer, "\tsub x12, x9, x11")?,
+                    RmwOp::And => writeln!(self.writer, "\tand x12, x9, x11")?,
+                    RmwOp::Or => writeln!(self.writer, "\torr x12, x9, x11")?,
+                    RmwOp::Xor => writeln!(self.writer, "\teor x12, x9, x11")?,
+                }
+                writeln!(self.writer, "\tst{}xr{} w13, {}, [x10]", rel, suffix(size),
+                         sub_reg("x12", size))?;
+                writeln!(self.writer, "\tcbnz w13, {}", head)?;
+                self.extend("x9", &ty)?;
+                self.store("x9", &dst.borrow())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit a loop that stores `x9` to `len` elements of type `ty` starting from `ptr`. `next`
+    /// is emitted at the beginning of each iteration to compute the stored value.
+    fn emit_mem_loop(&mut self, func: &FnRef, ptr: &RefCell<Value>, len: &RefCell<Value>,
+                     ty: &Type, next: impl Fn(&mut Self) -> Result<(), Error>)
+                     -> Result<(), Error> {
+        self.load(ptr, "x10")?;
+        self.load(len, "x14")?;
+        self.label_num += 2;
+        let head = format!(".L{}.{}", func.name, self.label_num - 1);
+        let end = format!(".L{}.{}", func.name, self.label_num);
+        writeln!(self.writer, "{}:", head)?;
+        writeln!(self.writer, "\tcmp x14, #0")?;
+        writeln!(self.writer, "\tb.le {}", end)?;
+        next(self)?;
+        let size = self.size_of(ty);
+        writeln!(self.writer, "\t{} {}, [x10]", store_instr(size), sub_reg("x9", size))?;
+        writeln!(self.writer, "\tadd x10, x10, #{}", size)?;
+        writeln!(self.writer, "\tsub x14, x14, #1")?;
+        writeln!(self.writer, "\tb {}", head)?;
+        writeln!(self.writer, "{}:", end)
+    }
+
+    /// Emit parallel copies for phi instructions in `succ` along the edge from `pred`.
+    /// All the sources are pushed to stack before any of the destinations are written, so that
+    /// the copies do not interfere with each other.
+    fn emit_phi_copy(&mut self, pred: &BlockRef, succ: &BlockRef) -> Result<(), Error> {
+        let mut copies = vec![];
+        for instr in succ.inst.borrow().iter() {
+            if let Inst::Phi { src, dst } = instr.as_ref() {
+                let opd = src.iter().find(|(b, _)| b.borrow().deref() == pred).unwrap();
+                let opd = opd.1.borrow().clone();
+                let dst = dst.borrow().clone();
+                // Copies between coalesced variables are not needed
+                if let Value::Var(sym) = &opd {
+                    if self.location(sym).is_some() && self.location(sym) == self.location(&dst) {
+                        continue;
+                    }
+                }
+                copies.push((dst, opd));
+            }
+        }
+        if copies.len() == 1 {
+            let (dst, opd) = copies.pop().unwrap();
+            self.load(&RefCell::new(opd), "x9")?;
+            return self.store("x9", &dst);
+        }
+        // Stack pointer must be kept 16-byte aligned
+        for (_, opd) in copies.iter() {
+            self.load(&RefCell::new(opd.clone()), "x9")?;
+            writeln!(self.writer, "\tstr x9, [sp, #-16]!")?;
+        }
+        for (dst, _) in copies.iter().rev() {
+            writeln!(self.writer, "\tldr x9, [sp], #16")?;
+            self.store("x9", dst)?;
+        }
+        Ok(())
+    }
+
+    /// Add `idx * size` to the address in `x9`.
+    fn add_offset(&mut self, idx: &RefCell<Value>, size: usize) -> Result<(), Error> {
+        match idx.borrow().deref() {
+            Value::Const(c) => {
+                self.mov_imm("x10", c.as_i64() * size as i64)?;
+                writeln!(self.writer, "\tadd x9, x9, x10")
+            }
+            _ => {
+                self.load(idx, "x10")?;
+                self.mov_imm("x11", size as i64)?;
+                writeln!(self.writer, "\tmadd x9, x10, x11, x9")
+            }
+        }
+    }
+
+    /// Move a 64-bit constant to register, with `movz` and `movk` for each nonzero 16-bit chunk
+    /// if it cannot be encoded in a single `mov`.
+    fn mov_imm(&mut self, reg: &str, val: i64) -> Result<(), Error> {
+        if (-65536..65536).contains(&val) {
+            return writeln!(self.writer, "\tmov {}, #{}", reg, val);
+        }
+        let mut first = true;
+        for i in 0..4 {
+            let chunk = (val as u64 >> (16 * i)) & 0xffff;
+            if chunk == 0 { continue; }
+            let instr = if first { "movz" } else { "movk" };
+            writeln!(self.writer, "\t{} {}, #{}, lsl #{}", instr, reg, chunk, 16 * i)?;
+            first = false;
+        }
+        Ok(())
+    }
+
+    /// Get memory operand at `off` from frame pointer. If the offset cannot be encoded, the
+    /// address is computed in `x12`.
+    fn frame_addr(&mut self, off: i64) -> Result<String, Error> {
+        if (-256..256).contains(&off) { return Ok(format!("[x29, #{}]", off)); }
+        self.frame_ptr(off, "x12")?;
+        Ok("[x12]".to_string())
+    }
+
+    /// Compute address at `off` from frame pointer in register.
+    fn frame_ptr(&mut self, off: i64, reg: &str) -> Result<(), Error> {
+        match off {
+            0..=4095 => writeln!(self.writer, "\tadd {}, x29, #{}", reg, off),
+            -4095..=-1 => writeln!(self.writer, "\tsub {}, x29, #{}", reg, -off),
+            _ => {
+                self.mov_imm(reg, off)?;
+                writeln!(self.writer, "\tadd {}, x29, {}", reg, reg)
+            }
+        }
+    }
+
+    /// Compute address of global symbol in register.
+    fn global_addr(&mut self, name: &str, reg: &str) -> Result<(), Error> {
+        writeln!(self.writer, "\tadrp {}, {}", reg, name)?;
+        writeln!(self.writer, "\tadd {}, {}, :lo12:{}", reg, reg, name)
+    }
+
+    /// Load value to a 64-bit register. Narrower integers are kept sign-extended in registers,
+    /// except `i1`, which is zero-extended.
+    fn load(&mut self, opd: &RefCell<Value>, reg: &str) -> Result<(), Error> {
+        match opd.borrow().deref() {
+            Value::Const(c) => self.mov_imm(reg, c.as_i64()),
+            Value::Var(sym) => match sym.as_ref() {
+                Symbol::Global(g) => {
+                    self.global_addr(&g.name, "x11")?;
+                    writeln!(self.writer, "\t{} {}, [x11]", load_instr(&g.ty),
+                             load_reg(reg, &g.ty))
+                }
+                Symbol::Func(f) => self.global_addr(&f.name, reg),
+                _ => match self.location(sym) {
+                    Some(Location::Reg(r)) =>
+                        writeln!(self.writer, "\tmov {}, {}", reg, CONV.saved_regs[r]),
+                    Some(Location::Stack(slot)) => {
+                        let addr = self.frame_addr(self.frame[&slot])?;
+                        writeln!(self.writer, "\tldr {}, {}", reg, addr)
+                    }
+                    // Spill slots are addresses in stack frame
+                    None => self.frame_ptr(self.frame[sym], reg)
+                }
+            }
+        }
+    }
+
+    /// Emit a call sequence, where `call` emits the call instruction itself after arguments
+    /// are passed.
+    fn emit_call(&mut self, arg: &[RefCell<Value>], dst: &[RefCell<SymbolRef>], ret: &Type,
+                 call: impl FnOnce(&mut Self) -> Result<(), Error>) -> Result<(), Error> {
+        // Store arguments that cannot be passed by registers, keeping stack pointer aligned
+        let n_stack = CONV.n_stack_arg(arg.len());
+        let size = (n_stack as i64 * 8 + 15) / 16 * 16;
+        if size > 0 {
+            self.mov_imm("x9", size)?;
+            writeln!(self.writer, "\tsub sp, sp, x9")?;
+        }
+        for (i, a) in arg[arg.len() - n_stack..].iter().enumerate() {
+            self.load(a, "x9")?;
+            self.mov_imm("x10", 8 * i as i64)?;
+            writeln!(self.writer, "\tstr x9, [sp, x10]")?;
+        }
+        for (a, reg) in arg.iter().zip(CONV.arg_regs.iter()) {
+            self.load(a, reg)?;
+        }
+        call(self)?;
+        if size > 0 {
+            self.mov_imm("x9", size)?;
+            writeln!(self.writer, "\tadd sp, sp, x9")?;
+        }
+        let regs = CONV.ret_loc(ret)?;
+        for ((dst, reg), ty) in dst.iter().zip(regs).zip(ret.ret_types().iter()) {
+            self.extend(reg, ty)?;
+            self.store(reg, &dst.borrow())?;
+        }
+        Ok(())
+    }
+
+    /// Store value in a register to the location of destination symbol.
+    fn store(&mut self, reg: &str, dst: &SymbolRef) -> Result<(), Error> {
+        match dst.as_ref() {
+            Symbol::Global(g) => {
+                let size = self.size_of(&g.ty);
+                self.global_addr(&g.name, "x11")?;
+                writeln!(self.writer, "\t{} {}, [x11]", store_instr(size), sub_reg(reg, size))
+            }
+            _ => match self.location(dst) {
+                Some(Location::Reg(r)) =>
+                    writeln!(self.writer, "\tmov {}, {}", CONV.saved_regs[r], reg),
+                Some(Location::Stack(slot)) => {
+                    let addr = self.frame_addr(self.frame[&slot])?;
+                    writeln!(self.writer, "\tstr {}, {}", reg, addr)
+                }
+                None => Ok(()) // variable is never used
+            }
+        }
+    }
+
+    /// Normalize the value in register according to its type.
+    fn extend(&mut self, reg: &str, ty: &Type) -> Result<(), Error> {
+        let instr = match ty.orig() {
+            Type::I(1) => return writeln!(self.writer, "\tand {}, {}, #1", reg, reg),
+            Type::I(8) => "sxtb",
+            Type::I(16) => "sxth",
+            Type::I(32) => "sxtw",
+            _ => return Ok(())
+        };
+        writeln!(self.writer, "\t{} {}, {}", instr, reg, sub_reg(reg, 4))
+    }
+
+    fn location(&self, sym: &SymbolRef) -> Option<Location> {
+        self.alloc.as_ref().unwrap().get(sym).cloned()
+    }
+
+    fn is_next(&self, block: &BlockRef) -> bool { self.next.as_ref() == Some(block) }
+
+    fn block_label(&self, func: &FnRef, block: &BlockRef) -> String {
+        format!(".L{}.{}", func.name, block.name)
+    }
+
+    fn size_of(&self, ty: &Type) -> usize { ty.size_of(&self.layout) }
+}
+
+/// Instruction that loads value of given type from memory to a register, extended as it is
+/// kept in registers.
+fn load_instr(ty: &Type) -> &'static str {
+    match ty.orig() {
+        Type::I(1) => "ldrb",
+        Type::I(8) => "ldrsb",
+        Type::I(16) => "ldrsh",
+        Type::I(32) => "ldrsw",
+        _ => "ldr"
+    }
+}
+
+/// Destination register of `load_instr`. Zero-extending byte load only accepts 32-bit register.
+fn load_reg(reg: &str, ty: &Type) -> String {
+    match ty.orig() {
+        Type::I(1) => sub_reg(reg, 4),
+        _ => reg.to_string()
+    }
+}
+
+/// Suffix of exclusive and ordered memory access instructions of given size in bytes.
+fn suffix(size: usize) -> &'static str {
+    match size {
+        1 => "b",
+        2 => "h",
+        _ => ""
+    }
+}
+
+/// Instruction that stores value of given size in bytes from register to memory.
+fn store_instr(size: usize) -> &'static str {
+    match size {
+        1 => "strb",
+        2 => "strh",
+        _ => "str"
+    }
+}
+
+/// Get register operand for storing value of given size in bytes. Values narrower than 64
+/// bits are stored from the 32-bit view of the register.
+fn sub_reg(reg: &str, size: usize) -> String {
+    match size {
+        1 | 2 | 4 => reg.replacen('x', "w", 1),
+        _ => reg.to_string()
+    }
+}
+
+#[test]
+fn test_aarch64() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use std::io::stdout;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::borrow::BorrowMut;
+
+    let file = File::open("test/sum.ir").unwrap();
+    let pro = Builder::new(Parser::new(Lexer::try_from(file).unwrap()).parse().unwrap())
+        .build().unwrap();
+    let mut out = stdout();
+    A64Gen::new(out.borrow_mut()).emit(&pro).unwrap();
+}
+
+#[test]
+fn test_aarch64_switch() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+
+    let pro = Builder::new(Parser::new(Lexer::from(r#"
+fn @f($x: i64) -> i64 {
+%A:
+    $c.1 <- eq i64 $x, 3
+    br $c.1 ? %R1 : %B
+%B:
+    $c.2 <- eq i64 $x, 5
+    br $c.2 ? %R2 : %C
+%C:
+    $c.3 <- eq i64 $x, 2
+    br $c.3 ? %R3 : %D
+%D:
+    $c.4 <- eq i64 $x, 6
+    br $c.4 ? %R1 : %R4
+%R1:
+    ret 1
+%R2:
+    ret 2
+%R3:
+    ret 3
+%R4:
+    ret 4
+}
+"#)).parse().unwrap()).build().unwrap();
+    let emit = |conf: SwitchConf| {
+        let mut out = vec![];
+        A64Gen::new(&mut out).switch(conf).emit(&pro).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    // Dense cases are lowered to jump table
+    let out = emit(SwitchConf::default());
+    println!("{}", out);
+    assert!(out.contains("br x10"));
+    assert_eq!(out.matches(".word .Lf.R4-").count(), 1);
+    assert!(!out.contains(".Lf.B:"));
+
+    // With a higher density threshold, compare tree is used instead
+    let out = emit(SwitchConf { density: 0.9, ..SwitchConf::default() });
+    println!("{}", out);
+    assert!(!out.contains("br x10"));
+    assert!(out.contains("b.lt"));
+    assert_eq!(out.matches("cmp x9, x10").count(), 4);
+}
@@ -0,0 +1,68 @@
+use std::io::{Error, ErrorKind};
+
+use crate::lang::value::Type;
+
+/// Calling convention of a target, which decides where arguments are passed and which
+/// registers survive function calls. Only integer and pointer values are considered, each
+/// occupying one register or one 8-byte stack slot.
+pub struct CallConv {
+    /// Registers for passing arguments, in order
+    pub arg_regs: &'static [&'static str],
+    /// Registers for returning values, in order. A function returning a tuple uses one
+    /// register for each element.
+    pub ret_regs: &'static [&'static str],
+    /// Callee-saved registers. Only these are used for register allocation, so that values in
+    /// them survive function calls without extra saving.
+    pub saved_regs: &'static [&'static str],
+    /// Offset from frame pointer of the callee to the first argument passed on stack
+    pub stack_arg_off: i64,
+}
+
+/// Location of a parameter on entry of the callee
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ArgLoc {
+    Reg(&'static str),
+    /// Offset from frame pointer
+    Stack(i64),
+}
+
+/// System V ABI for x86-64
+pub const SYSV_X64: CallConv = CallConv {
+    arg_regs: &["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"],
+    ret_regs: &["%rax", "%rdx"],
+    saved_regs: &["%rbx", "%r12", "%r13", "%r14", "%r15"],
+    stack_arg_off: 16,
+};
+
+/// Procedure Call Standard for the Arm 64-bit Architecture
+pub const AAPCS64: CallConv = CallConv {
+    arg_regs: &["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7"],
+    ret_regs: &["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7"],
+    saved_regs: &["x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27", "x28"],
+    stack_arg_off: 16,
+};
+
+impl CallConv {
+    /// Where the `i`-th parameter is found in the callee
+    pub fn param_loc(&self, i: usize) -> ArgLoc {
+        match self.arg_regs.get(i) {
+            Some(reg) => ArgLoc::Reg(reg),
+            None => ArgLoc::Stack(self.stack_arg_off + 8 * (i - self.arg_regs.len()) as i64)
+        }
+    }
+
+    /// Registers holding values returned with type `ret`. Returning more values than registers
+    /// is not supported.
+    pub fn ret_loc(&self, ret: &Type) -> Result<&'static [&'static str], Error> {
+        let n = ret.ret_types().len();
+        self.ret_regs.get(..n).ok_or_else(|| Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot return {} values in {} registers", n, self.ret_regs.len()),
+        ))
+    }
+
+    /// Number of arguments passed on stack for a call with `n_arg` arguments
+    pub fn n_stack_arg(&self, n_arg: usize) -> usize {
+        n_arg.saturating_sub(self.arg_regs.len())
+    }
+}
@@ -0,0 +1,672 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io::{Error, Write};
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, FnAttrib, FnRef};
+use crate::lang::inst::{BinOp, CastOp, Inst, MemOrd, RmwOp, UnOp};
+use crate::lang::Program;
+use crate::lang::value::{Const, Linkage, Symbol, SymbolKind, SymbolRef, Type, Typed, Value};
+
+/// Emitter of C source code.
+/// Each function is translated to a C function, with basic blocks as labels and control flow
+/// as `goto` statements. Aggregates are translated to C structures, and arrays are wrapped in
+/// structures so that they can be pointed to. Data layout of the program is decided by the C
+/// compiler, instead of `Program::layout`. Integer arithmetic wraps on overflow, as it does in
+/// the VM. When `@main` returns, values of global variables are printed in the same format as
+/// the VM record, which makes the output suitable for differential testing.
+pub struct CGen<'a> {
+    writer: &'a mut dyn Write,
+    /// Aggregate types and their names in C
+    names: Vec<(Type, String)>,
+    /// Names of aggregates whose definitions are already emitted
+    defined: HashSet<String>,
+    /// Definitions of aggregate types
+    defs: String,
+    /// Counter for naming anonymous aggregates and temporaries
+    num: usize,
+}
+
+impl CGen<'_> {
+    pub fn new(writer: &mut dyn Write) -> CGen<'_> {
+        CGen {
+            writer,
+            names: vec![],
+            defined: Default::default(),
+            defs: String::new(),
+            num: 0,
+        }
+    }
+
+    /// Generate C source for the whole program.
+    pub fn emit(&mut self, pro: &Program) -> Result<(), Error> {
+        writeln!(self.writer, "#include <stdbool.h>")?;
+        writeln!(self.writer, "#include <stdint.h>")?;
+        writeln!(self.writer, "#include <stdio.h>")?;
+        writeln!(self.writer, "#include <stdlib.h>")?;
+        writeln!(self.writer, "#include <string.h>")?;
+        writeln!(self.writer)?;
+
+        // Name aggregate types. Alias types are registered first so that their names are
+        // preferred to anonymous ones.
+        let mut alias: Vec<_> = pro.global.collect().into_iter()
+            .filter(|s| matches!(s.as_ref(), Symbol::Type { name: _, ty: _ })).collect();
+        alias.sort_by(|a, b| a.name().cmp(b.name()));
+        for sym in alias.iter() {
+            if Self::is_aggregate(&sym.get_type()) {
+                let name = format!("{} t_{}", Self::tag(&sym.get_type()), mangle(sym.name()));
+                self.names.push((Type::Alias(sym.clone()), name));
+            }
+        }
+        let mut types: Vec<Type> = alias.iter().map(|a| Type::Alias(a.clone())).collect();
+        types.extend(pro.vars.iter().map(|g| g.ty.clone()));
+        for func in &pro.func {
+            types.push(func.ret.clone());
+            let mut local = func.scope.collect();
+            local.sort_by(|a, b| a.name().cmp(b.name()));
+            types.extend(local.iter().map(|s| s.get_type()));
+        }
+        types.iter().for_each(|ty| self.define(ty));
+
+        // Emit aggregate types
+        if !self.names.is_empty() {
+            for (_, name) in self.names.iter() {
+                writeln!(self.writer, "{};", name)?;
+            }
+            writeln!(self.writer)?;
+            write!(self.writer, "{}", self.defs)?;
+        }
+
+        // Emit global variables
+        let mut vars = pro.vars.clone();
+        vars.sort_by(|a, b| a.name.cmp(&b.name));
+        if !vars.is_empty() {
+            for g in vars.iter() {
+                let qual = format!("{}{}", Self::storage(g.linkage),
+                                   if g.is_const { "const " } else { "" });
+                match &g.init {
+                    Some(c) => writeln!(self.writer, "{}{} {} = {};", qual, self.c_type(&g.ty),
+                                        c_global(&g.name), self.c_const(c))?,
+                    None if g.is_const => writeln!(self.writer, "{}{} {} = 0;", qual,
+                                                   self.c_type(&g.ty), c_global(&g.name))?,
+                    None => writeln!(self.writer, "{} {};", self.c_type(&g.ty),
+                                     c_global(&g.name))?
+                }
+            }
+            writeln!(self.writer)?;
+        }
+
+        // Print global variables at exit of program
+        if pro.func.iter().any(|f| f.name == "main") {
+            writeln!(self.writer, "static void dump_global(void) {{")?;
+            for g in vars.iter() {
+                match g.ty.orig() {
+                    Type::I(_) => writeln!(self.writer,
+                                           "    printf(\"@{} = %lld\\n\", (long long) {});",
+                                           g.name, c_global(&g.name))?,
+                    ty => writeln!(self.writer, "    printf(\"@{} = {}\\n\");", g.name,
+                                   ty.to_string())?
+                }
+            }
+            writeln!(self.writer, "}}\n")?;
+        }
+
+        // Emit function prototypes
+        for func in &pro.func {
+            writeln!(self.writer, "{}{};", self.fn_attrib(func), self.signature(func))?;
+        }
+        writeln!(self.writer)?;
+
+        // Emit function definitions
+        for func in &pro.func {
+            self.emit_fn(func)?;
+            writeln!(self.writer)?;
+        }
+        Ok(())
+    }
+
+    fn emit_fn(&mut self, func: &FnRef) -> Result<(), Error> {
+        writeln!(self.writer, "{} {{", self.signature(func))?;
+
+        // Convert arguments of `main` to types of its parameters
+        if func.name == "main" {
+            for (p, arg) in func.param.iter().zip(["argc", "argv"].iter()) {
+                let ty = self.c_type(&p.borrow().get_type());
+                writeln!(self.writer, "    {} {} = ({}) {};", ty, self.c_var(&p.borrow()), ty,
+                         arg)?;
+            }
+        }
+
+        // Declare local variables
+        let param: HashSet<SymbolRef> = func.param.iter().map(|p| p.borrow().clone()).collect();
+        let local: Vec<_> = func.scope.of_kind(SymbolKind::Local).into_iter()
+            .filter(|s| !param.contains(s)).collect();
+        for sym in local.iter() {
+            writeln!(self.writer, "    {} {};", self.c_type(&sym.get_type()), self.c_var(sym))?;
+        }
+
+        // Declare memory allocated on stack
+        for block in func.rpo() {
+            for instr in block.inst.borrow().iter() {
+                if let Inst::Alloc { dst } = instr.as_ref() {
+                    let ty = dst.borrow().get_type().tgt_type();
+                    writeln!(self.writer, "    {} m_{};", self.c_type(&ty),
+                             mangle(dst.borrow().name()))?;
+                }
+            }
+        }
+
+        // Emit blocks
+        for block in func.layout() {
+            writeln!(self.writer, "{}:;", self.c_label(&block))?;
+            for instr in block.inst.borrow().iter() {
+                self.emit_instr(func, &block, instr.as_ref())?;
+            }
+        }
+        writeln!(self.writer, "}}")
+    }
+
+    fn emit_instr(&mut self, func: &FnRef, block: &BlockRef, instr: &Inst)
+                  -> Result<(), Error>
+    {
+        let stmt = match instr {
+            Inst::Phi { src: _, dst: _ } => return Ok(()), // handled on edges
+            Inst::Mov { src, dst } =>
+                format!("{} = {};", self.c_var(&dst.borrow()), self.c_val(&src.borrow())),
+            Inst::Un { op, opd, dst } => {
+                let ty = dst.borrow().get_type();
+                let opd = self.c_val(&opd.borrow());
+                let expr = match op {
+                    UnOp::Not if ty == Type::I(1) => format!("!{}", opd),
+                    UnOp::Not => format!("~{}", opd),
+                    UnOp::Neg => format!("({})(0 - ({}) {})", self.c_type(&ty),
+                                         Self::unsigned(&ty), opd)
+                };
+                format!("{} = {};", self.c_var(&dst.borrow()), expr)
+            }
+            Inst::Cast { op, opd, dst } => {
+                let from = opd.borrow().get_type();
+                let to = dst.borrow().get_type();
+                let opd = self.c_val(&opd.borrow());
+                let expr = match (op, from.orig(), to.orig()) {
+                    (CastOp::ZExt, Type::I(b), _) if b > 1 =>
+                        format!("({})(uint{}_t) {}", self.c_type(&to), b, opd),
+                    (CastOp::SExt, Type::I(1), _) => format!("-({}) {}", self.c_type(&to), opd),
+                    (CastOp::Trunc, _, Type::I(1)) => format!("({} & 1) != 0", opd),
+                    (CastOp::PtrToInt, _, _) | (CastOp::IntToPtr, _, _) =>
+                        format!("({})(intptr_t) {}", self.c_type(&to), opd),
+                    _ => format!("({}) {}", self.c_type(&to), opd)
+                };
+                format!("{} = {};", self.c_var(&dst.borrow()), expr)
+            }
+            Inst::Bin { op, fst, snd, dst } => {
+                let ty = fst.borrow().get_type();
+                let fst = self.c_val(&fst.borrow());
+                let mut snd = self.c_val(&snd.borrow());
+                if let (BinOp::Shl, Type::I(b)) | (BinOp::Shr, Type::I(b)) = (op, ty.orig()) {
+                    snd = format!("({} & {})", snd, b - 1); // shift amount is masked
+                }
+                let expr = match op {
+                    // Wrap on overflow by computing in unsigned type
+                    BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Shl => {
+                        let sym = match op {
+                            BinOp::Add => "+",
+                            BinOp::Sub => "-",
+                            BinOp::Mul => "*",
+                            _ => "<<"
+                        };
+                        let uty = Self::unsigned(&ty);
+                        format!("({})(({}) {} {} ({}) {})", self.c_type(&ty), uty, fst, sym,
+                                uty, snd)
+                    }
+                    BinOp::AddOv | BinOp::SubOv | BinOp::MulOv => {
+                        let func = match op {
+                            BinOp::AddOv => "__builtin_add_overflow",
+                            BinOp::SubOv => "__builtin_sub_overflow",
+                            _ => "__builtin_mul_overflow"
+                        };
+                        format!("{}({}, {}, &({}){{0}})", func, fst, snd, self.c_type(&ty))
+                    }
+                    // `MIN / -1` is undefined in C, so division by -1 is done by negation
+                    BinOp::Div => {
+                        let uty = Self::unsigned(&ty);
+                        format!("{} == -1 ? ({})(-({}) {}) : {} / {}", snd, self.c_type(&ty), uty,
+                                fst, fst, snd)
+                    }
+                    BinOp::Mod => format!("{} == -1 ? 0 : {} % {}", snd, fst, snd),
+                    op => {
+                        let sym = match op {
+                            BinOp::And => "&",
+                            BinOp::Or => "|",
+                            BinOp::Xor => "^",
+                            BinOp::Shr => ">>",
+                            BinOp::Eq => "==",
+                            BinOp::Ne => "!=",
+                            BinOp::Lt => "<",
+                            BinOp::Le => "<=",
+                            BinOp::Gt => ">",
+                            BinOp::Ge => ">=",
+                            _ => unreachable!()
+                        };
+                        format!("{} {} {}", fst, sym, snd)
+                    }
+                };
+                format!("{} = {};", self.c_var(&dst.borrow()), expr)
+            }
+            Inst::Call { func: callee, arg, dst } => {
+                let arg: Vec<_> = arg.iter().map(|a| self.c_val(&a.borrow())).collect();
+                let call = format!("{}({})", c_fn(callee), arg.join(", "));
+                self.call_dst(dst, &callee.ret, call)
+            }
+            Inst::CallInd { func_ptr, arg, dst } => {
+                // Function pointers are opaque, and converted to the exact type when called
+                let (param, ret) = func_ptr.borrow().get_type().fn_sig().unwrap();
+                let param: Vec<_> = param.iter().map(|p| self.c_type(p)).collect();
+                let param = if param.is_empty() { "void".to_string() } else { param.join(", ") };
+                let arg: Vec<_> = arg.iter().map(|a| self.c_val(&a.borrow())).collect();
+                let call = format!("(({} (*)({})) {})({})", self.c_type(&ret), param,
+                                   self.c_val(&func_ptr.borrow()), arg.join(", "));
+                self.call_dst(dst, &ret, call)
+            }
+            Inst::Ret { val } => match val.as_slice() {
+                [] if func.name == "main" => "dump_global();\n    return 0;".to_string(),
+                [val] if func.name == "main" =>
+                    format!("dump_global();\n    return (int) {};", self.c_val(&val.borrow())),
+                [] => "return;".to_string(),
+                [val] => format!("return {};", self.c_val(&val.borrow())),
+                _ => {
+                    let val: Vec<_> = val.iter().map(|v| self.c_val(&v.borrow())).collect();
+                    format!("return ({}) {{ {} }};", self.c_type(&func.ret), val.join(", "))
+                }
+            }
+            Inst::Unreachable => "__builtin_unreachable();".to_string(),
+            Inst::Abort { msg } => format!("fputs(\"abort: {}\\n\", stderr);\n    abort();",
+                                           msg.replace('\\', "\\\\")),
+            Inst::Jmp { tgt } => format!("{}goto {};", self.phi_copy(block, &tgt.borrow()),
+                                         self.c_label(&tgt.borrow())),
+            Inst::Br { cond, tr, fls } => format!(
+                "if ({}) {{ {}goto {}; }} else {{ {}goto {}; }}", self.c_val(&cond.borrow()),
+                self.phi_copy(block, &tr.borrow()), self.c_label(&tr.borrow()),
+                self.phi_copy(block, &fls.borrow()), self.c_label(&fls.borrow())
+            ),
+            Inst::Alloc { dst } =>
+                format!("{} = &m_{};", self.c_var(&dst.borrow()), mangle(dst.borrow().name())),
+            Inst::New { dst, len } => {
+                let len = len.as_ref().map(|l| self.c_val(&l.borrow()))
+                    .unwrap_or_else(|| "1".to_string());
+                let ty = self.c_type(&dst.borrow().get_type().tgt_type());
+                format!("{} = calloc({}, sizeof({}));", self.c_var(&dst.borrow()), len, ty)
+            }
+            Inst::Ptr { base, off, ind, dst } => {
+                let mut expr = match off {
+                    Some(off) => format!("({} + {})", self.c_val(&base.borrow()),
+                                         self.c_val(&off.borrow())),
+                    None => self.c_val(&base.borrow())
+                };
+                if !ind.is_empty() {
+                    let mut ty = base.borrow().get_type().tgt_type();
+                    expr = format!("(*{})", expr);
+                    for idx in ind {
+                        match ty.orig() {
+                            Type::Array { elem, len: _ } => {
+                                expr += &format!(".e[{}]", self.c_val(&idx.borrow()));
+                                ty = elem.deref().clone();
+                            }
+                            Type::Struct { field } | Type::Union { field } => {
+                                let idx = match idx.borrow().deref() {
+                                    Value::Const(c) => c.as_i64() as usize,
+                                    _ => unreachable!()
+                                };
+                                expr += &format!(".f{}", idx);
+                                ty = field[idx].clone();
+                            }
+                            _ => unreachable!()
+                        }
+                    }
+                    expr = format!("&{}", expr);
+                }
+                format!("{} = {};", self.c_var(&dst.borrow()), expr)
+            }
+            Inst::Ld { ptr, dst } =>
+                format!("{} = *{};", self.c_var(&dst.borrow()), self.c_val(&ptr.borrow())),
+            Inst::St { src, ptr } =>
+                format!("*{} = {};", self.c_val(&ptr.borrow()), self.c_val(&src.borrow())),
+            Inst::Memcpy { src, ptr, len } => {
+                let (src, len) = (self.c_val(&src.borrow()), self.c_val(&len.borrow()));
+                format!("if ({} > 0) memcpy({}, {}, (size_t) {} * sizeof(*{}));", len,
+                        self.c_val(&ptr.borrow()), src, len, src)
+            }
+            Inst::Memset { src, ptr, len } => {
+                self.num += 1;
+                format!("for (int64_t t{0} = 0; t{0} < {1}; t{0}++) {2}[t{0}] = {3};", self.num,
+                        self.c_val(&len.borrow()), self.c_val(&ptr.borrow()),
+                        self.c_val(&src.borrow()))
+            }
+            // Atomics are translated to builtins of GCC and Clang
+            Inst::AtomicLd { ord, ptr, dst } =>
+                format!("{} = __atomic_load_n({}, {});", self.c_var(&dst.borrow()),
+                        self.c_val(&ptr.borrow()), c_mem_ord(*ord)),
+            Inst::AtomicSt { ord, src, ptr } =>
+                format!("__atomic_store_n({}, {}, {});", self.c_val(&ptr.borrow()),
+                        self.c_val(&src.borrow()), c_mem_ord(*ord)),
+            Inst::AtomicRmw { op, ord, ptr, val, dst } => {
+                let func = match op {
+                    RmwOp::Xchg => "__atomic_exchange_n",
+                    RmwOp::Add => "__atomic_fetch_add",
+                    RmwOp::Sub => "__atomic_fetch_sub",
+                    RmwOp::And => "__atomic_fetch_and",
+                    RmwOp::Or => "__atomic_fetch_or",
+                    RmwOp::Xor => "__atomic_fetch_xor",
+                };
+                format!("{} = {}({}, {}, {});", self.c_var(&dst.borrow()), func,
+                        self.c_val(&ptr.borrow()), self.c_val(&val.borrow()), c_mem_ord(*ord))
+            }
+        };
+        writeln!(self.writer, "    {}", stmt)
+    }
+
+    /// Create parallel copies for phi instructions in `succ` along the edge from `pred`.
+    /// Sources are first saved to temporaries, so that the copies do not interfere with each
+    /// other.
+    fn phi_copy(&mut self, pred: &BlockRef, succ: &BlockRef) -> String {
+        let mut save = String::new();
+        let mut restore = String::new();
+        for instr in succ.inst.borrow().iter() {
+            if let Inst::Phi { src, dst } = instr.as_ref() {
+                let opd = &src.iter().find(|(b, _)| b.borrow().deref() == pred).unwrap().1;
+                self.num += 1;
+                save += &format!("{} t{} = {}; ", self.c_type(&dst.borrow().get_type()),
+                                 self.num, self.c_val(&opd.borrow()));
+                restore += &format!("{} = t{}; ", self.c_var(&dst.borrow()), self.num);
+            }
+        }
+        if save.is_empty() { save } else { format!("{{ {}{}}} ", save, restore) }
+    }
+
+    /// Assign result of call to destinations. Tuples are returned as structures, and their
+    /// fields are copied to each destination.
+    fn call_dst(&mut self, dst: &[RefCell<SymbolRef>], ret: &Type, call: String) -> String {
+        match dst {
+            [] => format!("{};", call),
+            [dst] => format!("{} = {};", self.c_var(&dst.borrow()), call),
+            _ => {
+                self.num += 1;
+                let mut stmt = format!("{{ {} t{} = {}; ", self.c_type(ret), self.num, call);
+                for (i, dst) in dst.iter().enumerate() {
+                    stmt += &format!("{} = t{}.f{}; ", self.c_var(&dst.borrow()), self.num, i);
+                }
+                stmt + "}"
+            }
+        }
+    }
+
+    /// Register aggregate types and emit their definitions, possibly recursively.
+    fn define(&mut self, ty: &Type) {
+        match ty {
+            Type::Ptr(tgt) => self.define(tgt),
+            Type::Fn { param, ret } => {
+                param.iter().for_each(|p| self.define(p));
+                self.define(ret);
+            }
+            _ if Self::is_aggregate(ty) => {
+                let name = self.register(ty);
+                if self.defined.contains(&name) { return; }
+                self.defined.insert(name.clone());
+
+                // Types contained by value should be defined first
+                let member = match ty.orig() {
+                    Type::Array { elem, len: _ } => vec![elem.deref().clone()],
+                    Type::Struct { field } | Type::Union { field } | Type::Tuple(field) => field,
+                    _ => unreachable!()
+                };
+                member.iter().for_each(|m| { self.register(m); });
+                let def = match ty.orig() {
+                    Type::Array { elem, len } =>
+                        format!("{} {{ {} e[{}]; }};\n", name, self.c_type(&elem), len),
+                    Type::Struct { field } | Type::Union { field } | Type::Tuple(field) => {
+                        let mut def = format!("{} {{", name);
+                        for (i, f) in field.iter().enumerate() {
+                            def += &format!(" {} f{};", self.c_type(f), i);
+                        }
+                        if field.is_empty() { def += " char f0;"; }
+                        def + " };\n"
+                    }
+                    _ => unreachable!()
+                };
+                member.iter().for_each(|m| if !m.is_ptr() { self.define(m) });
+                self.defs += &def;
+                member.iter().for_each(|m| if m.is_ptr() { self.define(m) });
+            }
+            _ => {}
+        }
+    }
+
+    /// C keyword of aggregate type. Arrays are wrapped in structures, so that they can be
+    /// passed by value.
+    fn tag(ty: &Type) -> &'static str {
+        if let Type::Union { field: _ } = ty.orig() { "union" } else { "struct" }
+    }
+
+    fn is_aggregate(ty: &Type) -> bool {
+        matches!(ty.orig(), Type::Array { elem: _, len: _ } | Type::Struct { field: _ }
+            | Type::Union { field: _ } | Type::Tuple(_))
+    }
+
+    /// Give name to the aggregate type, or the aggregate pointed to. Return the name of the
+    /// aggregate type.
+    fn register(&mut self, ty: &Type) -> String {
+        match ty.orig() {
+            Type::Ptr(tgt) => self.register(&tgt),
+            _ if Self::is_aggregate(ty) => match self.names.iter().find(|(t, _)| t == ty) {
+                Some((_, name)) => name.clone(),
+                None => {
+                    self.num += 1;
+                    let name = format!("{} anon{}", Self::tag(ty), self.num);
+                    self.names.push((ty.clone(), name.clone()));
+                    name
+                }
+            }
+            _ => String::new()
+        }
+    }
+
+    /// Get C type for IR type. Aggregate types should be registered before.
+    fn c_type(&self, ty: &Type) -> String {
+        match ty {
+            Type::Void => "void".to_string(),
+            Type::I(1) => "bool".to_string(),
+            Type::I(b) => format!("int{}_t", b),
+            Type::Ptr(tgt) if ty.fn_sig().is_some() => self.c_type(tgt),
+            Type::Ptr(tgt) => format!("{} *", self.c_type(tgt)),
+            Type::Fn { param: _, ret: _ } => "void *".to_string(),
+            Type::Alias(_) if !Self::is_aggregate(ty) => self.c_type(&ty.orig()),
+            _ => self.names.iter().find(|(t, _)| t == ty).unwrap().1.clone()
+        }
+    }
+
+    /// Get unsigned type used for wrapping arithmetic. Integers narrower than 32 bits are
+    /// promoted to avoid overflow of `int` in C.
+    fn unsigned(ty: &Type) -> &'static str {
+        match ty.orig() {
+            Type::I(64) => "uint64_t",
+            _ => "uint32_t"
+        }
+    }
+
+    fn signature(&self, func: &FnRef) -> String {
+        match func.name.as_str() {
+            "main" if func.param.is_empty() => return "int main(void)".to_string(),
+            "main" => return "int main(int argc, char **argv)".to_string(),
+            _ => {}
+        }
+        let param: Vec<_> = func.param.iter().map(|p| {
+            format!("{} {}", self.c_type(&p.borrow().get_type()), self.c_var(&p.borrow()))
+        }).collect();
+        let param = if param.is_empty() { "void".to_string() } else { param.join(", ") };
+        format!("{}{} {}({})", Self::storage(func.effective_linkage()), self.c_type(&func.ret),
+                c_fn(func), param)
+    }
+
+    /// Internal symbols are `static` in C, and weak ones are marked with GNU C attribute.
+    fn storage(linkage: Linkage) -> &'static str {
+        match linkage {
+            Linkage::Internal => "static ",
+            Linkage::Export => "",
+            Linkage::Weak => "__attribute__((weak)) "
+        }
+    }
+
+    /// Translate function attributes to GNU C attributes. Note that `pure` in GNU C is
+    /// `readonly` here, and `const` in GNU C is `pure` here.
+    fn fn_attrib(&self, func: &FnRef) -> String {
+        if func.name == "main" { return "".to_string(); }
+        let attrib: Vec<_> = func.attrib.iter().filter_map(|a| match a {
+            FnAttrib::NoInline => Some("noinline"),
+            FnAttrib::ReadOnly => Some("pure"),
+            FnAttrib::Pure => Some("const"),
+            FnAttrib::NoReturn => Some("noreturn"),
+            _ => None
+        }).collect();
+        if attrib.is_empty() { "".to_string() } else {
+            format!("__attribute__(({})) ", attrib.join(", "))
+        }
+    }
+
+    fn c_label(&self, block: &BlockRef) -> String { format!("b_{}", mangle(&block.name)) }
+
+    fn c_var(&self, sym: &SymbolRef) -> String {
+        match sym.as_ref() {
+            Symbol::Local { name, ty: _ } => format!("v_{}", mangle(name)),
+            Symbol::Func(f) => format!("(void *) {}", c_fn(f)),
+            _ => c_global(sym.name())
+        }
+    }
+
+    fn c_val(&self, val: &Value) -> String {
+        match val {
+            Value::Var(sym) => self.c_var(sym),
+            Value::Const(c) => self.c_const(c)
+        }
+    }
+
+    fn c_const(&self, c: &Const) -> String {
+        match c {
+            Const::I1(b) => (*b as i32).to_string(),
+            Const::I64(i64::MIN) => "INT64_MIN".to_string(),
+            Const::I64(v) => format!("INT64_C({})", v),
+            c => format!("({}) {}", match c {
+                Const::I8(_) => "int8_t",
+                Const::I16(_) => "int16_t",
+                _ => "int32_t"
+            }, c.as_i64())
+        }
+    }
+}
+
+/// Make identifier valid in C. `_` is escaped to `__`, and `.` is replaced by `_`.
+fn mangle(name: &str) -> String {
+    name.replace('_', "__").replace('.', "_")
+}
+
+/// Name of global variable in C. Like other kinds of symbols, it is prefixed, so that it never
+/// collides with C keywords, names in the standard library, or generated names.
+fn c_global(name: &str) -> String { format!("g_{}", mangle(name)) }
+
+/// Name of function in C. `@main` keeps its name, since it is the entry of C programs.
+fn c_fn(func: &FnRef) -> String {
+    if func.name == "main" { "main".to_string() } else { format!("f_{}", mangle(&func.name)) }
+}
+
+/// Memory order constant of atomic builtins
+fn c_mem_ord(ord: MemOrd) -> &'static str {
+    match ord {
+        MemOrd::Relaxed => "__ATOMIC_RELAXED",
+        MemOrd::Acquire => "__ATOMIC_ACQUIRE",
+        MemOrd::Release => "__ATOMIC_RELEASE",
+        MemOrd::AcqRel => "__ATOMIC_ACQ_REL",
+        MemOrd::SeqCst => "__ATOMIC_SEQ_CST",
+    }
+}
+
+#[test]
+fn test_c() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use std::io::stdout;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+    use std::borrow::BorrowMut;
+
+    let mut file = File::open("test/example.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let pro = builder.build().unwrap();
+
+    let mut out = stdout();
+    let mut gen = CGen::new(out.borrow_mut());
+    gen.emit(&pro).unwrap();
+}
+
+#[test]
+fn test_c_names() {
+    use std::process::Command;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::vm::exec::Machine;
+
+    // Symbols named after C keywords, library functions and generated names
+    let pro = Builder::new(Parser::new(Lexer::from(r#"
+type @anon1 = { i64, i64 }
+@int: i64 <- 3
+@malloc: i64 <- 4
+
+fn @printf($x: i64) -> i64 {
+%Begin:
+    $p <- alloc @anon1
+    $q <- ptr *i64 $p [1]
+    st i64 $x -> $q
+    $y <- ld i64 $q
+    ret $y
+}
+
+fn @exit() -> i64 {
+%Begin:
+    $a <- call i64 @printf(@int)
+    $b <- add i64 $a, @malloc
+    ret $b
+}
+
+fn @main() -> i64 {
+%Begin:
+    $r <- call i64 @exit()
+    @int <- mov i64 $r
+    ret $r
+}
+"#)).parse().unwrap()).build().unwrap();
+    let rcd = Machine::new().run(&pro).unwrap();
+    assert_eq!(rcd.exit, 7);
+
+    let mut out = vec![];
+    CGen::new(&mut out).emit(&pro).unwrap();
+    let src = String::from_utf8(out).unwrap();
+    assert!(src.contains("int64_t g_int = INT64_C(3);"));
+    assert!(src.contains("static int64_t f_printf(int64_t v_x)"));
+
+    // Compile and run the generated code if there is a C compiler
+    if Command::new("cc").arg("--version").output().is_err() { return; }
+    let dir = std::env::temp_dir().join(format!("irl-names-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (path, exe) = (dir.join("names.c"), dir.join("names.out"));
+    std::fs::write(&path, src).unwrap();
+    let status = Command::new("cc").arg("-o").arg(&exe).arg(&path).status().unwrap();
+    assert!(status.success());
+    let out = Command::new(&exe).output().unwrap();
+    assert_eq!(out.status.code(), Some(7));
+    assert!(String::from_utf8(out.stdout).unwrap().contains("@int = 7"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
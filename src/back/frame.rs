@@ -0,0 +1,165 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::back::regalloc::{AllocFn, Location};
+use crate::lang::escape::PtrSrc;
+use crate::lang::func::FnRef;
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::layout::DataLayout;
+use crate::lang::value::{SymbolRef, Typed};
+
+/// Stack frame of a function, as seen from the frame pointer
+#[derive(Debug)]
+pub struct Frame {
+    /// Offsets to frame pointer of memory allocated by `alloc` instructions, including spill
+    /// slots, keyed by their pointer symbols
+    pub offset: HashMap<SymbolRef, i64>,
+    /// Size of the frame, including the reserved area, aligned to 16 bytes
+    pub size: i64,
+}
+
+/// Stack frame layout. Memory of `alloc` instructions and spill slots is placed below an area
+/// reserved for saved registers, at decreasing offsets in 8-byte units.
+///
+/// Slots whose lifetimes do not overlap share memory. A spill slot is alive as long as its
+/// spilled variable is. An `alloc` slot is alive as long as any pointer derived from it is, or
+/// in the whole function if its address escapes. Slots are placed greedily in decreasing order
+/// of size, each in the first group of placed slots it does not overlap with.
+pub struct FrameLayout {
+    /// Whether to share memory between slots
+    coalesce: bool,
+}
+
+/// Slot to be placed in stack frame
+struct Slot {
+    sym: SymbolRef,
+    size: i64,
+    /// Local variables whose lifetimes make up the one of this slot
+    live: HashSet<SymbolRef>,
+    /// Whether this slot is alive in the whole function
+    escaped: bool,
+}
+
+impl FrameLayout {
+    pub fn new() -> FrameLayout { FrameLayout { coalesce: true } }
+
+    /// Whether to share memory between slots with disjoint lifetimes. Enabled by default.
+    pub fn coalesce(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+
+    /// Lay out stack frame of `func` with register allocation result `alloc`. `reserved` bytes
+    /// right below the frame pointer are kept for saved registers.
+    pub fn layout(&self, func: &FnRef, alloc: &AllocFn, layout: &DataLayout, reserved: i64)
+                  -> Frame
+    {
+        let slots = self.collect_slots(func, alloc, layout);
+        let graph = func.liveness().interference(func);
+        let overlap = |a: &Slot, b: &Slot| {
+            !self.coalesce || a.escaped || b.escaped || !a.live.is_disjoint(&b.live)
+                || a.live.iter().any(|x| b.live.iter().any(|y| graph.interferes(x, y)))
+        };
+
+        // Place larger slots first, so that smaller ones fit in the memory of groups
+        let mut order: Vec<usize> = (0..slots.len()).collect();
+        order.sort_by_key(|&i| -slots[i].size);
+        let mut groups: Vec<(i64, Vec<usize>)> = vec![];
+        for i in order {
+            let slot = &slots[i];
+            match groups.iter_mut().find(|(_, g)| g.iter().all(|&j| !overlap(slot, &slots[j]))) {
+                Some((size, g)) => {
+                    *size = (*size).max(slot.size);
+                    g.push(i);
+                }
+                None => groups.push((slot.size, vec![i]))
+            }
+        }
+
+        // Assign offsets to groups, in order of their first slots in the function
+        groups.sort_by_key(|(_, g)| *g.iter().min().unwrap());
+        let mut offset = HashMap::new();
+        let mut size = reserved;
+        for (len, group) in groups {
+            size += len;
+            group.iter().for_each(|&i| { offset.insert(slots[i].sym.clone(), -size); });
+        }
+        Frame { offset, size: (size + 15) / 16 * 16 }
+    }
+
+    /// Collect slots of `alloc` instructions in reverse post-order.
+    fn collect_slots(&self, func: &FnRef, alloc: &AllocFn, layout: &DataLayout) -> Vec<Slot> {
+        let info = func.escape();
+        let mut spilled: HashMap<SymbolRef, HashSet<SymbolRef>> = HashMap::new();
+        for (sym, loc) in &alloc.loc {
+            if let Location::Stack(slot) = loc {
+                spilled.entry(slot.clone()).or_default().insert(sym.clone());
+            }
+        }
+        let inst: Vec<InstRef> = func.rpo().into_iter()
+            .flat_map(|b| b.inst.borrow().clone()).collect();
+        inst.iter().filter_map(|instr| match instr.as_ref() {
+            Inst::Alloc { dst } => Some((instr, dst.borrow().clone())),
+            _ => None
+        }).map(|(instr, sym)| {
+            let len = sym.get_type().tgt_type().size_of(layout) as i64;
+            let src = PtrSrc::Alloc(instr.clone());
+            let mut live: HashSet<SymbolRef> = info.src.iter()
+                .filter(|(_, s)| s.contains(&src)).map(|(v, _)| v.clone()).collect();
+            live.extend(spilled.remove(&sym).unwrap_or_default());
+            Slot { sym, size: (len + 7) / 8 * 8, live, escaped: info.escapes(instr) }
+        }).collect()
+    }
+}
+
+#[test]
+fn test_frame() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::back::regalloc::RegAlloc;
+
+    let pro = Builder::new(Parser::new(Lexer::from(r#"
+@g: *i64
+
+fn @f($n: i64) -> i64 {
+%Begin:
+    $a <- alloc [4]i64
+    $p <- ptr *i64 $a [0]
+    st i64 $n -> $p
+    $x <- ld i64 $p
+    $b <- alloc [2]i64
+    $q <- ptr *i64 $b [1]
+    st i64 $x -> $q
+    $y <- ld i64 $q
+    $c <- alloc i64
+    st i64 $y -> $c
+    $d <- alloc i64
+    @g <- mov *i64 $d
+    $e <- alloc i64
+    st i64 $n -> $e
+    $z <- ld i64 $e
+    $s <- add i64 $z, $y
+    ret $s
+}
+"#)).parse().unwrap()).build().unwrap();
+    let func = &pro.func[0];
+    let alloc = RegAlloc::new(4).alloc(func);
+    let layout = DataLayout::x64();
+    let frame = FrameLayout::new().layout(func, &alloc, &layout, 16);
+    println!("{:?}", frame.offset);
+    let off = |name: &str| frame.offset.iter().find(|(s, _)| s.name() == name).unwrap().1;
+
+    // Pointers into `$a`, `$b` and `$c` are never alive at the same time, and `$d` escapes
+    assert_eq!(off("a"), off("b"));
+    assert_eq!(off("a"), off("c"));
+    assert_eq!(off("a"), off("e"));
+    assert_ne!(off("d"), off("a"));
+    assert!(frame.offset.values().all(|o| *o <= -24));
+    assert_eq!(frame.size, (16 + 32 + 8 + 15) / 16 * 16);
+
+    // Without coalescing, slots are placed one after another
+    let frame = FrameLayout::new().coalesce(false).layout(func, &alloc, &layout, 16);
+    assert_eq!(frame.size, (16 + 32 + 16 + 8 * 3 + 15) / 16 * 16);
+    let offsets: HashSet<i64> = frame.offset.values().cloned().collect();
+    assert_eq!(offsets.len(), 5);
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{BinOp, Inst, InstRef, UnOp};
+use crate::lang::value::{Symbol, SymbolRef, Value};
+
+/// Pattern over a tree of instructions, where an operand defined by another instruction is
+/// the child of the instruction using it.
+#[derive(Clone, Debug)]
+pub enum Pat {
+    /// Any operand, bound to the name
+    Any(&'static str),
+    /// Constant operand satisfying the predicate, bound to the name
+    Const(&'static str, fn(i64) -> bool),
+    /// Unary instruction with operator and operand pattern
+    Un(UnOp, Box<Pat>),
+    /// Binary instruction with operator and operand patterns. Operands of a commutative
+    /// operator are also matched in swapped order.
+    Bin(BinOp, Box<Pat>, Box<Pat>),
+}
+
+pub fn any(name: &'static str) -> Pat { Pat::Any(name) }
+
+pub fn imm(name: &'static str, pred: fn(i64) -> bool) -> Pat { Pat::Const(name, pred) }
+
+pub fn un(op: UnOp, opd: Pat) -> Pat { Pat::Un(op, Box::new(opd)) }
+
+pub fn bin(op: BinOp, fst: Pat, snd: Pat) -> Pat { Pat::Bin(op, Box::new(fst), Box::new(snd)) }
+
+/// Target-specific lowering rule. `emit` is the action of the target when the pattern matches,
+/// whose type is decided by the target.
+pub struct Rule<T> {
+    pub name: &'static str,
+    pub pat: Pat,
+    pub emit: T,
+}
+
+/// Operands bound by a matched pattern
+#[derive(Clone, Debug)]
+pub struct Match {
+    /// Destination of the root instruction
+    pub dst: SymbolRef,
+    bind: HashMap<&'static str, Value>,
+}
+
+impl Match {
+    /// Operand bound to the name
+    pub fn get(&self, name: &str) -> &Value { &self.bind[name] }
+
+    /// Constant bound to the name
+    pub fn imm(&self, name: &str) -> i64 {
+        match self.get(name) {
+            Value::Const(c) => c.as_i64(),
+            v => panic!("operand {} is not constant", v.to_string())
+        }
+    }
+}
+
+/// How an instruction is lowered
+pub enum Selection<'a, T> {
+    /// No rule applies, so the target lowers it as is
+    Default,
+    /// The instruction is part of a tree matched at a later instruction, so nothing is emitted
+    Covered,
+    /// The instruction is the root of a tree matched by the rule
+    Rule(&'a Rule<T>, Match),
+}
+
+/// Instruction selector, which covers instruction trees in blocks with rules in order of
+/// priority. A defining instruction is only folded into its user if the result has no other use,
+/// and the folded instructions are right before the root in the same block, so that their
+/// operands are not overwritten when the root is emitted.
+pub struct Selector<T> {
+    rules: Vec<Rule<T>>,
+}
+
+impl<T> Selector<T> {
+    pub fn new(rules: Vec<Rule<T>>) -> Selector<T> { Selector { rules } }
+
+    /// Select rules for each instruction of the blocks in a function.
+    pub fn select(&self, func: &FnRef) -> HashMap<BlockRef, Vec<Selection<'_, T>>> {
+        let mut uses: HashMap<SymbolRef, usize> = HashMap::new();
+        func.iter_dom().for_each(|block| block.inst.borrow().iter().for_each(|instr| {
+            instr.src().into_iter().for_each(|opd| {
+                if let Value::Var(sym) = opd.borrow().deref() {
+                    *uses.entry(sym.clone()).or_insert(0) += 1
+                }
+            })
+        }));
+        func.iter_dom().map(|block| {
+            let sel = self.select_block(&block, &uses);
+            (block, sel)
+        }).collect()
+    }
+
+    fn select_block(&self, block: &BlockRef, uses: &HashMap<SymbolRef, usize>)
+                    -> Vec<Selection<'_, T>> {
+        let inst: Vec<InstRef> = block.inst.borrow().iter().cloned().collect();
+        let def: HashMap<SymbolRef, usize> = inst.iter().enumerate()
+            .filter_map(|(i, instr)| instr.dst().map(|d| (d.borrow().clone(), i))).collect();
+        let mut sel: Vec<_> = inst.iter().map(|_| Selection::Default).collect();
+
+        // Visit roots backwards, so that instructions are covered before they are visited
+        for root in (0..inst.len()).rev() {
+            if let Selection::Covered = sel[root] { continue; }
+            let dst = match inst[root].dst() {
+                Some(dst) => dst.borrow().clone(),
+                None => continue
+            };
+            for rule in self.rules.iter() {
+                let mut ctx = MatchCtx { inst: &inst, def: &def, uses, bind: HashMap::new(),
+                    covered: vec![] };
+                if !ctx.match_inst(&inst[root], &rule.pat) { continue; }
+                // Covered instructions must be contiguous and right before the root
+                ctx.covered.sort();
+                if ctx.covered.iter().rev().enumerate().any(|(k, &i)| i + k + 1 != root) {
+                    continue;
+                }
+                ctx.covered.iter().for_each(|&i| sel[i] = Selection::Covered);
+                sel[root] = Selection::Rule(rule, Match { dst, bind: ctx.bind });
+                break;
+            }
+        }
+        sel
+    }
+}
+
+struct MatchCtx<'a> {
+    inst: &'a [InstRef],
+    /// Index of defining instruction of each symbol in this block
+    def: &'a HashMap<SymbolRef, usize>,
+    uses: &'a HashMap<SymbolRef, usize>,
+    bind: HashMap<&'static str, Value>,
+    /// Indices of instructions folded into the root
+    covered: Vec<usize>,
+}
+
+impl MatchCtx<'_> {
+    fn match_inst(&mut self, instr: &InstRef, pat: &Pat) -> bool {
+        match (instr.as_ref(), pat) {
+            (Inst::Un { op, opd, dst: _ }, Pat::Un(p_op, p_opd)) if op == p_op =>
+                self.match_opd(&opd.borrow(), p_opd),
+            (Inst::Bin { op, fst, snd, dst: _ }, Pat::Bin(p_op, p_fst, p_snd)) if op == p_op => {
+                let (fst, snd) = (fst.borrow().clone(), snd.borrow().clone());
+                self.try_match(|ctx| ctx.match_opd(&fst, p_fst) && ctx.match_opd(&snd, p_snd))
+                    || op.is_comm() && self.try_match(|ctx| {
+                    ctx.match_opd(&snd, p_fst) && ctx.match_opd(&fst, p_snd)
+                })
+            }
+            _ => false
+        }
+    }
+
+    fn match_opd(&mut self, opd: &Value, pat: &Pat) -> bool {
+        match (opd, pat) {
+            (_, Pat::Any(name)) => self.bind(name, opd),
+            (Value::Const(c), Pat::Const(name, pred)) => pred(c.as_i64()) && self.bind(name, opd),
+            (Value::Var(sym), Pat::Un(_, _) | Pat::Bin(_, _, _)) => {
+                if !matches!(sym.as_ref(), Symbol::Local { name: _, ty: _ }) { return false; }
+                if self.uses.get(sym) != Some(&1) { return false; }
+                let i = match self.def.get(sym) {
+                    Some(&i) if !self.covered.contains(&i) => i,
+                    _ => return false
+                };
+                self.covered.push(i);
+                self.match_inst(&self.inst[i], pat)
+            }
+            _ => false
+        }
+    }
+
+    /// Bind operand to name. If the name is already bound, the operand must be the same one.
+    fn bind(&mut self, name: &'static str, opd: &Value) -> bool {
+        match self.bind.get(name) {
+            Some(v) => match (v, opd) {
+                (Value::Var(a), Value::Var(b)) => a == b,
+                (Value::Const(a), Value::Const(b)) => a == b,
+                _ => false
+            }
+            None => {
+                self.bind.insert(name, opd.clone());
+                true
+            }
+        }
+    }
+
+    /// Run matching, and restore bindings and covered instructions if it fails.
+    fn try_match(&mut self, f: impl FnOnce(&mut Self) -> bool) -> bool {
+        let (bind, covered) = (self.bind.clone(), self.covered.clone());
+        if f(self) { return true; }
+        self.bind = bind;
+        self.covered = covered;
+        false
+    }
+}
+
+#[test]
+fn test_isel() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+
+    let pro = Builder::new(Parser::new(Lexer::from(r#"
+fn @f($x: i64, $y: i64) -> i64 {
+%Begin:
+    $a <- mul i64 $x, 4
+    $b <- add i64 $y, $a // matched by `scaled`
+    $c <- mul i64 $b, 3
+    $d <- add i64 $c, $c // used twice
+    $e <- mul i64 $d, 2
+    $g <- add i64 $x, 1 // not adjacent to its operand
+    $h <- add i64 $e, $g
+    $i <- sub i64 0, $h // matched by `neg`
+    $j <- shl i64 $i, 3
+    $k <- add i64 $j, $j
+    ret $k
+}
+"#)).parse().unwrap()).build().unwrap();
+
+    // A toy target which only names the rules
+    let sel = Selector::new(vec![
+        Rule { name: "scaled", pat: bin(BinOp::Add, bin(BinOp::Mul, any("x"), imm("s", |s| {
+            [1, 2, 4, 8].contains(&s)
+        })), any("y")), emit: () },
+        Rule { name: "double", pat: bin(BinOp::Add, any("x"), any("x")), emit: () },
+        Rule { name: "neg", pat: bin(BinOp::Sub, imm("z", |z| z == 0), any("x")), emit: () },
+    ]);
+    let func = &pro.func[0];
+    let res = sel.select(func);
+    let names: Vec<_> = res[&func.ent.borrow().clone()].iter().map(|s| match s {
+        Selection::Default => "",
+        Selection::Covered => "-",
+        Selection::Rule(rule, m) => {
+            println!("{}: {:?}", rule.name, m);
+            rule.name
+        }
+    }).collect();
+    assert_eq!(names, vec!["-", "scaled", "", "double", "", "", "", "neg", "", "double", ""]);
+}
@@ -0,0 +1,160 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::meta::MetaVal;
+use crate::lang::Program;
+use crate::lang::value::{SymbolRef, Value};
+use crate::pass::{FnPass, Pass};
+
+/// Code layout and scheduling, as a preparation step before assembly emission.
+/// Blocks are placed in chains so that the most probable successor of each block follows it,
+/// turning the taken branch into a fallthrough. Branch probabilities are read from profile
+/// metadata: `!likely` of `br` instructions, and `!freq` of blocks. Without metadata, blocks
+/// are kept in reverse post-order. The order is stored in `Fn::layout`.
+///
+/// Within each block, pure instructions are sunk to their first use in the block, or to the
+/// end of the block if they are only used by other blocks, to shorten live ranges.
+pub struct CodeLayout {}
+
+impl CodeLayout {
+    pub fn new() -> CodeLayout { CodeLayout {} }
+
+    /// Order successors of `block` by decreasing probability. Ties are broken by reverse
+    /// post-order index.
+    fn rank_succ(func: &FnRef, block: &BlockRef, rpo: &HashMap<BlockRef, usize>)
+                 -> Vec<BlockRef>
+    {
+        let tail = block.tail();
+        let likely = match (tail.as_ref(), func.inst_meta(&tail, "likely")) {
+            (Inst::Br { cond: _, tr, fls }, Some(MetaVal::Int(l))) =>
+                Some(if l != 0 { tr.borrow().clone() } else { fls.borrow().clone() }),
+            _ => None
+        };
+        let freq = |b: &BlockRef| match b.meta.borrow().get("freq") {
+            Some(MetaVal::Int(f)) => *f,
+            _ => 0
+        };
+        let mut succ = block.succ.borrow().clone();
+        succ.sort_by_key(|s| (Some(s) != likely.as_ref(), -freq(s), rpo[s]));
+        succ
+    }
+
+    /// Whether `instr` can be moved forward in its block without changing semantics.
+    fn can_sink(instr: &InstRef) -> bool {
+        match instr.as_ref() {
+            Inst::Phi { src: _, dst: _ } | Inst::Alloc { dst: _ } | Inst::Ld { ptr: _, dst: _ }
+            | Inst::Call { func: _, arg: _, dst: _ } => false,
+            Inst::Bin { op, fst: _, snd: _, dst: _ } if op.is_trapping() => false,
+            instr => instr.is_assign() && !instr.is_ctrl() && !instr.has_side_effect()
+        }
+    }
+
+    /// Sink instructions in `block` towards their uses. Returns whether any is moved.
+    fn sink(block: &BlockRef) -> bool {
+        let mut moved = false;
+        let mut inst: Vec<InstRef> = block.inst.borrow().iter().cloned().collect();
+        let is_var = |v: &Value, sym: &SymbolRef| matches!(v, Value::Var(s) if s == sym);
+        for i in (0..inst.len()).rev() {
+            if !Self::can_sink(&inst[i]) { continue; }
+            let dst = inst[i].dst().unwrap().borrow().clone();
+            let opd: HashSet<SymbolRef> = inst[i].src().iter()
+                .filter_map(|v| match v.borrow().deref() {
+                    Value::Var(sym) => Some(sym.clone()),
+                    _ => None
+                }).collect();
+            // Stop before the first instruction that uses the result, redefines the result or
+            // any operand, or transfers control.
+            let stop = (i + 1..inst.len()).find(|&j| {
+                let next = &inst[j];
+                next.is_ctrl() || next.src().iter().any(|v| is_var(v.borrow().deref(), &dst))
+                    || next.dst().is_some_and(|d| {
+                        let d = d.borrow();
+                        d.deref() == &dst || opd.contains(d.deref())
+                    })
+            }).unwrap_or(inst.len());
+            if stop > i + 1 {
+                let instr = inst.remove(i);
+                inst.insert(stop - 1, instr);
+                moved = true;
+            }
+        }
+        block.inst.replace(inst.into_iter().collect());
+        moved
+    }
+}
+
+impl Pass for CodeLayout {
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+}
+
+impl FnPass for CodeLayout {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        // Build chains greedily, starting from the entrance
+        let order: Vec<BlockRef> = func.rpo().collect();
+        let rpo: HashMap<BlockRef, usize> = order.iter().cloned().enumerate()
+            .map(|(i, b)| (b, i)).collect();
+        let mut placed: HashSet<BlockRef> = HashSet::new();
+        let mut layout = vec![];
+        for start in order.iter() {
+            let mut cur = start.clone();
+            while placed.insert(cur.clone()) {
+                layout.push(cur.clone());
+                match Self::rank_succ(func, &cur, &rpo).into_iter()
+                    .find(|s| !placed.contains(s)) {
+                    Some(next) => cur = next,
+                    None => break
+                }
+            }
+        }
+        let changed = func.layout.replace(layout.clone()) != layout;
+
+        // Schedule instructions in each block
+        order.iter().fold(changed, |changed, block| Self::sink(block) || changed)
+    }
+}
+
+#[test]
+fn test_layout() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::back::x64::X64Gen;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+    use std::io::stdout;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+    use std::borrow::BorrowMut;
+
+    let mut file = File::open("test/layout.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let mut pro = builder.build().unwrap();
+    let mut mach = Machine::new();
+    let before = mach.run(&mut pro).unwrap();
+
+    let mut opt = CodeLayout::new();
+    Pass::run(&mut opt, &mut pro);
+    let mut out = stdout();
+    Printer::new(out.borrow_mut()).print(&pro).unwrap();
+    X64Gen::new(out.borrow_mut()).emit(&pro).unwrap();
+
+    // Likely successors follow their predecessors
+    let names = |func: &FnRef| func.layout().iter().map(|b| b.name.clone()).collect::<Vec<_>>();
+    assert_eq!(names(&pro.func[0]), vec!["Begin", "Pos", "Neg"]);
+    assert_eq!(names(&pro.func[1]), vec!["Begin", "Cond", "Loop", "End"]);
+
+    // Definition used only by successors is sunk to the end of block
+    let ent = pro.func[0].ent.borrow().clone();
+    let before_br = ent.before(&ent.tail()).unwrap();
+    assert_eq!(before_br.dst().unwrap().borrow().name(), "a");
+
+    let mut mach = Machine::new();
+    let after = mach.run(&mut pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
@@ -0,0 +1,1038 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Error, Formatter};
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{BinOp, CastOp, Inst, InstRef, MemOrd, RmwOp, UnOp};
+use crate::lang::layout::DataLayout;
+use crate::lang::value::{Symbol, SymbolRef, Type, Typed, Value};
+
+/// Constraints of a target instruction set on machine instructions
+pub struct Target {
+    /// Whether unary and binary operations overwrite their first operand, as on x86. Otherwise,
+    /// the first operand must be a register, as on load-store architectures.
+    pub two_addr: bool,
+    /// Scales allowed for index registers of memory operands
+    pub scales: &'static [i64],
+    /// Whether a memory operand can have both an index register and a displacement
+    pub index_disp: bool,
+    /// Whether an index must be scaled by either one or the size of the accessed data
+    pub scale_by_size: bool,
+    /// Whether a global symbol can be the base of a memory operand, which is addressed relative
+    /// to the program counter
+    pub sym_base: bool,
+    /// Range of displacement
+    pub disp: (i64, i64),
+}
+
+pub const X64: Target = Target {
+    two_addr: true,
+    scales: &[1, 2, 4, 8],
+    index_disp: true,
+    scale_by_size: false,
+    sym_base: true,
+    disp: (i32::MIN as i64, i32::MAX as i64),
+};
+
+pub const AARCH64: Target = Target {
+    two_addr: false,
+    scales: &[1, 2, 4, 8],
+    index_disp: false,
+    scale_by_size: true,
+    sym_base: false,
+    disp: (-256, 4095),
+};
+
+impl Target {
+    /// Whether a memory operand at `addr` accessing `size` bytes can be encoded.
+    pub fn is_legal(&self, addr: &Addr, size: usize) -> bool {
+        if let Base::Sym(_) = addr.base {
+            if !self.sym_base || addr.index.is_some() { return false; }
+        }
+        if addr.disp < self.disp.0 || addr.disp > self.disp.1 { return false; }
+        match addr.index {
+            Some((_, scale)) => self.scales.contains(&scale)
+                && (!self.scale_by_size || scale == 1 || scale == size as i64)
+                && (self.index_disp || addr.disp == 0),
+            None => true
+        }
+    }
+}
+
+/// Virtual register, numbered in its function
+pub type VReg = usize;
+
+/// Operand of machine instruction
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Operand {
+    Reg(VReg),
+    Imm(i64),
+    /// Address of a global symbol
+    Sym(String),
+}
+
+/// Base of memory address
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Base {
+    Reg(VReg),
+    /// Stack slot of the function
+    Slot(usize),
+    /// Global symbol
+    Sym(String),
+}
+
+/// Memory address `base + index * scale + disp`
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Addr {
+    pub base: Base,
+    pub index: Option<(VReg, i64)>,
+    pub disp: i64,
+}
+
+impl Addr {
+    fn new(base: Base) -> Addr { Addr { base, index: None, disp: 0 } }
+}
+
+/// Machine instruction. Operations with two-address constraints keep the first operand
+/// explicitly, which is then the destination itself.
+#[derive(Clone, Debug)]
+pub enum MInst {
+    Mov { dst: VReg, src: Operand },
+    Un { op: UnOp, dst: VReg, opd: Operand },
+    Bin { op: BinOp, dst: VReg, fst: Operand, snd: Operand },
+    /// Extension or truncation, between widths given by types of the registers
+    Ext { op: CastOp, dst: VReg, src: VReg },
+    /// Compute an address, which is not necessarily encodable as a memory operand
+    Lea { dst: VReg, addr: Addr },
+    /// Load data of `size` bytes
+    Ld { dst: VReg, addr: Addr, size: usize },
+    /// Store data of `size` bytes
+    St { src: Operand, addr: Addr, size: usize },
+    /// Atomically load data of `size` bytes
+    AtomicLd { ord: MemOrd, dst: VReg, addr: Addr, size: usize },
+    /// Atomically store data of `size` bytes
+    AtomicSt { ord: MemOrd, src: Operand, addr: Addr, size: usize },
+    /// Atomically read, modify and write data of `size` bytes. `dst` receives the data read.
+    AtomicRmw { op: RmwOp, ord: MemOrd, dst: VReg, val: Operand, addr: Addr, size: usize },
+    Call { func: Operand, arg: Vec<Operand>, dst: Vec<VReg> },
+    Ret { val: Vec<Operand> },
+    Jmp { tgt: usize },
+    Br { cond: Operand, tr: usize, fls: usize },
+    Trap,
+}
+
+impl MInst {
+    /// Registers defined by this instruction
+    pub fn dst(&self) -> Vec<VReg> {
+        match self {
+            MInst::Mov { dst, src: _ } | MInst::Un { op: _, dst, opd: _ }
+            | MInst::Bin { op: _, dst, fst: _, snd: _ } | MInst::Ext { op: _, dst, src: _ }
+            | MInst::Lea { dst, addr: _ } | MInst::Ld { dst, addr: _, size: _ }
+            | MInst::AtomicLd { ord: _, dst, addr: _, size: _ }
+            | MInst::AtomicRmw { op: _, ord: _, dst, val: _, addr: _, size: _ } => vec![*dst],
+            MInst::Call { func: _, arg: _, dst } => dst.clone(),
+            _ => vec![]
+        }
+    }
+
+    /// Registers used by this instruction, including the ones in addresses
+    pub fn uses(&self) -> Vec<VReg> {
+        let reg = |o: &Operand| match o {
+            Operand::Reg(r) => Some(*r),
+            _ => None
+        };
+        let addr = |a: &Addr| {
+            let base = match a.base {
+                Base::Reg(r) => Some(r),
+                _ => None
+            };
+            base.into_iter().chain(a.index.map(|(r, _)| r))
+        };
+        match self {
+            MInst::Mov { dst: _, src: o } | MInst::Un { op: _, dst: _, opd: o }
+            | MInst::Br { cond: o, tr: _, fls: _ } => reg(o).into_iter().collect(),
+            MInst::Bin { op: _, dst: _, fst, snd } => reg(fst).into_iter().chain(reg(snd))
+                .collect(),
+            MInst::Ext { op: _, dst: _, src } => vec![*src],
+            MInst::Lea { dst: _, addr: a } | MInst::Ld { dst: _, addr: a, size: _ }
+            | MInst::AtomicLd { ord: _, dst: _, addr: a, size: _ } => addr(a).collect(),
+            MInst::St { src, addr: a, size: _ } | MInst::AtomicSt { ord: _, src, addr: a, size: _ }
+            | MInst::AtomicRmw { op: _, ord: _, dst: _, val: src, addr: a, size: _ } =>
+                reg(src).into_iter().chain(addr(a)).collect(),
+            MInst::Call { func, arg, dst: _ } => reg(func).into_iter()
+                .chain(arg.iter().filter_map(reg)).collect(),
+            MInst::Ret { val } => val.iter().filter_map(reg).collect(),
+            _ => vec![]
+        }
+    }
+
+    /// Register which must be both the destination and the first operand on a two-address
+    /// target
+    pub fn tied(&self) -> Option<VReg> {
+        match self {
+            MInst::Un { op: _, dst, opd: _ } | MInst::Bin { op: _, dst, fst: _, snd: _ } =>
+                Some(*dst),
+            _ => None
+        }
+    }
+
+    /// Successor blocks of a terminator
+    pub fn succ(&self) -> Vec<usize> {
+        match self {
+            MInst::Jmp { tgt } => vec![*tgt],
+            MInst::Br { cond: _, tr, fls } => vec![*tr, *fls],
+            _ => vec![]
+        }
+    }
+
+    pub fn is_term(&self) -> bool {
+        matches!(self, MInst::Jmp { tgt: _ } | MInst::Br { cond: _, tr: _, fls: _ }
+            | MInst::Ret { val: _ } | MInst::Trap)
+    }
+
+    /// Whether this instruction can be removed if its result is not used
+    fn is_pure(&self) -> bool {
+        match self {
+            MInst::Bin { op, dst: _, fst: _, snd: _ } => !op.is_trapping(),
+            MInst::Mov { dst: _, src: _ } | MInst::Un { op: _, dst: _, opd: _ }
+            | MInst::Ext { op: _, dst: _, src: _ } | MInst::Lea { dst: _, addr: _ }
+            | MInst::Ld { dst: _, addr: _, size: _ } => true,
+            _ => false
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MBlock {
+    pub name: String,
+    pub inst: Vec<MInst>,
+}
+
+/// Function in machine IR. Phis are replaced by copies, placed in blocks created for critical
+/// edges where necessary.
+#[derive(Debug)]
+pub struct MFn {
+    pub name: String,
+    pub param: Vec<VReg>,
+    /// Type of each virtual register
+    pub ty: Vec<Type>,
+    /// Virtual register of each local variable of the IR function
+    pub var: HashMap<SymbolRef, VReg>,
+    /// Blocks, where the first one is the entrance
+    pub blocks: Vec<MBlock>,
+    /// Size of each stack slot in bytes
+    pub slots: Vec<usize>,
+}
+
+impl MFn {
+    /// Check that instructions satisfy constraints of `target`, and each block ends with its
+    /// only terminator.
+    pub fn check(&self, target: &Target) -> Result<(), String> {
+        for block in &self.blocks {
+            let err = |msg: String| Err(format!("%{}: {}", block.name, msg));
+            match block.inst.last() {
+                Some(last) if last.is_term() => {}
+                _ => return err("block is not terminated".to_string())
+            }
+            for (i, instr) in block.inst.iter().enumerate() {
+                if instr.is_term() && i + 1 != block.inst.len() {
+                    return err("terminator is not at the end".to_string());
+                }
+                if instr.succ().iter().any(|&s| s >= self.blocks.len()) {
+                    return err("branch to nonexistent block".to_string());
+                }
+                let fst = match instr {
+                    MInst::Un { op: _, dst: _, opd } => opd,
+                    MInst::Bin { op: _, dst: _, fst, snd: _ } => fst,
+                    _ => &Operand::Imm(0)
+                };
+                match (target.two_addr, instr.tied(), fst) {
+                    (true, Some(dst), fst) if *fst != Operand::Reg(dst) =>
+                        return err(format!("first operand of v{} is not tied", dst)),
+                    (false, Some(dst), Operand::Imm(_) | Operand::Sym(_)) =>
+                        return err(format!("first operand of v{} is not register", dst)),
+                    _ => {}
+                }
+                let (addr, size) = match instr {
+                    MInst::Ld { dst: _, addr, size } => (addr, *size),
+                    MInst::St { src: _, addr, size } => (addr, *size),
+                    MInst::AtomicLd { ord: _, dst: _, addr, size }
+                    | MInst::AtomicSt { ord: _, src: _, addr, size }
+                    | MInst::AtomicRmw { op: _, ord: _, dst: _, val: _, addr, size } =>
+                        (addr, *size),
+                    _ => continue
+                };
+                if !target.is_legal(addr, size) {
+                    return err(format!("address {} cannot be encoded", addr));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Build interference graph of virtual registers. The destination of a move does not
+    /// interfere with its source, so that they can share a register.
+    pub fn interference(&self) -> HashMap<VReg, HashSet<VReg>> {
+        // Compute live-out sets of blocks backwards to fixed point
+        let n = self.blocks.len();
+        let succ: Vec<Vec<usize>> = self.blocks.iter()
+            .map(|b| b.inst.last().map(|i| i.succ()).unwrap_or_default()).collect();
+        let mut live_in: Vec<HashSet<VReg>> = vec![HashSet::new(); n];
+        let live_out = |live_in: &Vec<HashSet<VReg>>, b: usize| -> HashSet<VReg> {
+            succ[b].iter().flat_map(|&s| live_in[s].iter().cloned()).collect()
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..n).rev() {
+                let mut live = live_out(&live_in, b);
+                for instr in self.blocks[b].inst.iter().rev() {
+                    instr.dst().iter().for_each(|d| { live.remove(d); });
+                    live.extend(instr.uses());
+                }
+                if live != live_in[b] {
+                    live_in[b] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        // Add edges between each definition and registers live after it
+        let mut graph: HashMap<VReg, HashSet<VReg>> = HashMap::new();
+        let mut add = |a: VReg, b: VReg| {
+            graph.entry(a).or_default().insert(b);
+            graph.entry(b).or_default().insert(a);
+        };
+        for b in 0..n {
+            let mut live = live_out(&live_in, b);
+            for instr in self.blocks[b].inst.iter().rev() {
+                let src = match instr {
+                    MInst::Mov { dst: _, src: Operand::Reg(r) } => Some(*r),
+                    _ => None
+                };
+                for d in instr.dst() {
+                    live.iter().filter(|&&l| l != d && Some(l) != src).for_each(|&l| add(d, l));
+                }
+                instr.dst().iter().for_each(|d| { live.remove(d); });
+                live.extend(instr.uses());
+            }
+        }
+        for (i, &p) in self.param.iter().enumerate() {
+            self.param[i + 1..].iter().for_each(|&q| add(p, q));
+            live_in.first().into_iter().flatten().filter(|&&l| l != p).for_each(|&l| add(p, l));
+        }
+        self.blocks.iter().flat_map(|b| b.inst.iter())
+            .flat_map(|i| i.dst().into_iter().chain(i.uses()))
+            .chain(self.param.iter().cloned())
+            .for_each(|r| { graph.entry(r).or_default(); });
+        graph
+    }
+}
+
+/// Convert `func` to machine IR under constraints of `target`, with sizes of data decided by
+/// `layout`. In SSA functions, addresses computed by `ptr` are folded into memory operands of
+/// loads and stores in the same block, or in any block if they are constant offsets into stack
+/// slots. Heap
+/// allocations, `memcpy` and `memset` are lowered to library calls and loops.
+pub fn lower(func: &FnRef, target: &Target, layout: &DataLayout) -> MFn {
+    let blocks: Vec<BlockRef> = func.rpo().collect();
+    let mut lower = Lower {
+        target,
+        layout,
+        f: MFn {
+            name: func.name.clone(),
+            param: vec![],
+            ty: vec![],
+            var: Default::default(),
+            blocks: blocks.iter().map(|b| MBlock { name: b.name.clone(), inst: vec![] })
+                .collect(),
+            slots: vec![],
+        },
+        block: blocks.iter().enumerate().map(|(i, b)| (b.clone(), i)).collect(),
+        cur: 0,
+        addr: Default::default(),
+        fixed: Default::default(),
+        ssa: func.ssa.get(),
+        n_new: 0,
+    };
+    lower.f.param = func.param.iter().map(|p| lower.var(&p.borrow())).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        lower.cur = i;
+        lower.addr.clear();
+        let inst: Vec<InstRef> = block.inst.borrow().iter().cloned().collect();
+        inst.iter().filter(|i| !i.is_phi()).for_each(|i| lower.lower_inst(block, i));
+    }
+    lower.remove_dead();
+    lower.f
+}
+
+/// State of lowering a function
+struct Lower<'a> {
+    target: &'a Target,
+    layout: &'a DataLayout,
+    f: MFn,
+    /// Index of each IR block
+    block: HashMap<BlockRef, usize>,
+    /// Block where instructions are appended
+    cur: usize,
+    /// Addresses computed by `ptr` in current block
+    addr: HashMap<VReg, Addr>,
+    /// Addresses in stack slots, which are valid in all blocks
+    fixed: HashMap<VReg, Addr>,
+    ssa: bool,
+    /// Number of blocks created for edges and loops
+    n_new: usize,
+}
+
+impl Lower<'_> {
+    fn lower_inst(&mut self, block: &BlockRef, instr: &InstRef) {
+        match instr.as_ref() {
+            Inst::Mov { src, dst } => {
+                let src = self.opd(&src.borrow());
+                self.assign(&dst.borrow(), |this, dst| this.push(MInst::Mov { dst, src }))
+            }
+            Inst::Un { op, opd, dst } => {
+                let opd = self.opd(&opd.borrow());
+                self.assign(&dst.borrow(), |this, dst| this.un(*op, dst, opd))
+            }
+            Inst::Bin { op, fst, snd, dst } => {
+                let fst = self.opd(&fst.borrow());
+                let snd = self.opd(&snd.borrow());
+                self.assign(&dst.borrow(), |this, dst| this.bin(*op, dst, fst, snd))
+            }
+            Inst::Cast { op, opd, dst } => {
+                let ty = opd.borrow().get_type();
+                let opd = self.opd(&opd.borrow());
+                self.assign(&dst.borrow(), |this, dst| match op {
+                    CastOp::PtrToInt | CastOp::IntToPtr => this.push(MInst::Mov { dst, src: opd }),
+                    op => {
+                        let src = this.reg(opd, ty);
+                        this.push(MInst::Ext { op: *op, dst, src })
+                    }
+                })
+            }
+            Inst::Call { func, arg, dst } =>
+                self.call(Operand::Sym(func.name.clone()), arg, dst),
+            Inst::CallInd { func_ptr, arg, dst } => {
+                let func = self.opd(&func_ptr.borrow());
+                self.call(func, arg, dst)
+            }
+            Inst::Ret { val } => {
+                let val = val.iter().map(|v| self.opd(&v.borrow())).collect();
+                self.push(MInst::Ret { val })
+            }
+            Inst::Unreachable => self.push(MInst::Trap),
+            Inst::Abort { msg: _ } => {
+                self.push(MInst::Call { func: Operand::Sym("abort".to_string()), arg: vec![],
+                    dst: vec![] });
+                self.push(MInst::Trap)
+            }
+            Inst::Jmp { tgt } => {
+                let tgt = self.edge(block, &tgt.borrow());
+                self.push(MInst::Jmp { tgt })
+            }
+            Inst::Br { cond, tr, fls } => {
+                let cond = self.opd(&cond.borrow());
+                let tr = self.edge(block, &tr.borrow());
+                let fls = self.edge(block, &fls.borrow());
+                self.push(MInst::Br { cond, tr, fls })
+            }
+            Inst::Phi { src: _, dst: _ } => unreachable!(),
+            Inst::Alloc { dst } => {
+                let size = self.size_of(&dst.borrow().get_type().tgt_type());
+                let slot = self.f.slots.len();
+                self.f.slots.push(size);
+                self.assign(&dst.borrow(), |this, dst| {
+                    let addr = Addr::new(Base::Slot(slot));
+                    if this.ssa { this.fixed.insert(dst, addr.clone()); }
+                    this.push(MInst::Lea { dst, addr })
+                })
+            }
+            Inst::New { dst, len } => {
+                let len = match len {
+                    Some(len) => self.opd(&len.borrow()),
+                    None => Operand::Imm(1)
+                };
+                // Zero-initialized by `calloc`
+                let size = self.size_of(&dst.borrow().get_type().tgt_type()) as i64;
+                self.assign(&dst.borrow(), |this, dst| this.push(MInst::Call {
+                    func: Operand::Sym("calloc".to_string()),
+                    arg: vec![len, Operand::Imm(size)],
+                    dst: vec![dst],
+                }))
+            }
+            Inst::Ptr { base, off, ind, dst } => {
+                let mut addr = self.addr_of(&base.borrow());
+                let mut ty = base.borrow().get_type().tgt_type();
+                if let Some(off) = off {
+                    let size = self.size_of(&ty);
+                    self.add_index(&mut addr, &off.borrow(), size);
+                }
+                for idx in ind {
+                    match (ty.orig(), idx.borrow().deref()) {
+                        (Type::Array { elem, len: _ }, Value::Var(_)) => {
+                            let size = self.size_of(&elem);
+                            self.add_index(&mut addr, &idx.borrow(), size);
+                            ty = elem.deref().clone();
+                        }
+                        (_, Value::Const(c)) => {
+                            let i = c.as_i64() as usize;
+                            addr.disp += ty.field_offset(i, self.layout) as i64;
+                            ty = ty.elem_type(i);
+                        }
+                        _ => unreachable!()
+                    }
+                }
+                self.assign(&dst.borrow(), |this, dst| {
+                    match (this.ssa, &addr.base, addr.index) {
+                        (false, _, _) => {}
+                        (true, Base::Slot(_), None) => { this.fixed.insert(dst, addr.clone()); }
+                        (true, _, _) => { this.addr.insert(dst, addr.clone()); }
+                    }
+                    this.push(MInst::Lea { dst, addr })
+                })
+            }
+            Inst::Ld { ptr, dst } => {
+                let size = self.size_of(&dst.borrow().get_type());
+                let addr = self.addr_of(&ptr.borrow());
+                let addr = self.legalize(addr, size);
+                self.assign(&dst.borrow(), |this, dst| this.push(MInst::Ld { dst, addr, size }))
+            }
+            Inst::St { src, ptr } => {
+                let size = self.size_of(&src.borrow().get_type());
+                let src = self.st_opd(&src.borrow());
+                let addr = self.addr_of(&ptr.borrow());
+                let addr = self.legalize(addr, size);
+                self.push(MInst::St { src, addr, size })
+            }
+            Inst::Memcpy { src, ptr, len } => {
+                let size = self.size_of(&src.borrow().get_type().tgt_type());
+                let elem = src.borrow().get_type().tgt_type();
+                let src = self.opd(&src.borrow());
+                let src = self.reg(src, Type::I(64));
+                let ptr = self.opd(&ptr.borrow());
+                let ptr = self.reg(ptr, Type::I(64));
+                self.mem_loop(&len.borrow(), |this, i| {
+                    let val = this.temp(elem.clone());
+                    let addr = this.legalize(
+                        Addr { base: Base::Reg(src), index: Some((i, size as i64)), disp: 0 },
+                        size,
+                    );
+                    this.push(MInst::Ld { dst: val, addr, size });
+                    let addr = this.legalize(
+                        Addr { base: Base::Reg(ptr), index: Some((i, size as i64)), disp: 0 },
+                        size,
+                    );
+                    this.push(MInst::St { src: Operand::Reg(val), addr, size })
+                })
+            }
+            Inst::Memset { src, ptr, len } => {
+                let size = self.size_of(&src.borrow().get_type());
+                let val = self.st_opd(&src.borrow());
+                let ptr = self.opd(&ptr.borrow());
+                let ptr = self.reg(ptr, Type::I(64));
+                self.mem_loop(&len.borrow(), |this, i| {
+                    let addr = this.legalize(
+                        Addr { base: Base::Reg(ptr), index: Some((i, size as i64)), disp: 0 },
+                        size,
+                    );
+                    this.push(MInst::St { src: val.clone(), addr, size })
+                })
+            }
+            Inst::AtomicLd { ord, ptr, dst } => {
+                let size = self.size_of(&dst.borrow().get_type());
+                let addr = self.addr_of(&ptr.borrow());
+                let addr = self.legalize(addr, size);
+                self.assign(&dst.borrow(), |this, dst| {
+                    this.push(MInst::AtomicLd { ord: *ord, dst, addr, size })
+                })
+            }
+            Inst::AtomicSt { ord, src, ptr } => {
+                let size = self.size_of(&src.borrow().get_type());
+                let src = self.st_opd(&src.borrow());
+                let addr = self.addr_of(&ptr.borrow());
+                let addr = self.legalize(addr, size);
+                self.push(MInst::AtomicSt { ord: *ord, src, addr, size })
+            }
+            Inst::AtomicRmw { op, ord, ptr, val, dst } => {
+                let size = self.size_of(&dst.borrow().get_type());
+                // Operand is always in a register, as it receives the data read on x86-64
+                let opd = self.opd(&val.borrow());
+                let val = Operand::Reg(self.reg(opd, val.borrow().get_type()));
+                let addr = self.addr_of(&ptr.borrow());
+                let addr = self.legalize(addr, size);
+                self.assign(&dst.borrow(), |this, dst| {
+                    this.push(MInst::AtomicRmw { op: *op, ord: *ord, dst, val, addr, size })
+                })
+            }
+        }
+    }
+
+    fn push(&mut self, instr: MInst) { self.f.blocks[self.cur].inst.push(instr) }
+
+    fn size_of(&self, ty: &Type) -> usize { ty.size_of(self.layout) }
+
+    /// Get virtual register of a local variable.
+    fn var(&mut self, sym: &SymbolRef) -> VReg {
+        if let Some(&r) = self.f.var.get(sym) { return r; }
+        let r = self.temp(sym.get_type());
+        self.f.var.insert(sym.clone(), r);
+        r
+    }
+
+    fn temp(&mut self, ty: Type) -> VReg {
+        self.f.ty.push(ty);
+        self.f.ty.len() - 1
+    }
+
+    /// Convert a value to operand. Global variables are loaded to registers.
+    fn opd(&mut self, val: &Value) -> Operand {
+        match val {
+            Value::Const(c) => Operand::Imm(c.as_i64()),
+            Value::Var(sym) => match sym.as_ref() {
+                Symbol::Global(g) => {
+                    let size = self.size_of(&g.ty);
+                    let addr = self.legalize(Addr::new(Base::Sym(g.name.clone())), size);
+                    let dst = self.temp(g.ty.clone());
+                    self.push(MInst::Ld { dst, addr, size });
+                    Operand::Reg(dst)
+                }
+                Symbol::Func(f) => Operand::Sym(f.name.clone()),
+                _ => Operand::Reg(self.var(sym))
+            }
+        }
+    }
+
+    /// Operand of store, which must be a register on load-store targets
+    fn st_opd(&mut self, val: &Value) -> Operand {
+        let opd = self.opd(val);
+        match self.target.two_addr {
+            true => opd,
+            false => Operand::Reg(self.reg(opd, val.get_type()))
+        }
+    }
+
+    /// Move an operand to a register if it is not.
+    fn reg(&mut self, opd: Operand, ty: Type) -> VReg {
+        match opd {
+            Operand::Reg(r) => r,
+            src => {
+                let dst = self.temp(ty);
+                self.push(MInst::Mov { dst, src });
+                dst
+            }
+        }
+    }
+
+    /// Assign to `dst` with the instructions emitted by `f`, which are given the destination
+    /// register. A global variable is written back from a temporary register.
+    fn assign(&mut self, dst: &SymbolRef, f: impl FnOnce(&mut Self, VReg)) {
+        match dst.as_ref() {
+            Symbol::Global(g) => {
+                let reg = self.temp(g.ty.clone());
+                f(self, reg);
+                let size = self.size_of(&g.ty);
+                let addr = self.legalize(Addr::new(Base::Sym(g.name.clone())), size);
+                self.push(MInst::St { src: Operand::Reg(reg), addr, size })
+            }
+            _ => {
+                let reg = self.var(dst);
+                f(self, reg)
+            }
+        }
+    }
+
+    fn un(&mut self, op: UnOp, dst: VReg, opd: Operand) {
+        if self.target.two_addr {
+            if opd != Operand::Reg(dst) { self.push(MInst::Mov { dst, src: opd }) }
+            self.push(MInst::Un { op, dst, opd: Operand::Reg(dst) })
+        } else {
+            let opd = Operand::Reg(self.reg(opd, self.f.ty[dst].clone()));
+            self.push(MInst::Un { op, dst, opd })
+        }
+    }
+
+    fn bin(&mut self, op: BinOp, dst: VReg, fst: Operand, snd: Operand) {
+        let ty = self.f.ty[dst].clone();
+        if self.target.two_addr {
+            // The second operand would be overwritten by the copy of the first one
+            let snd = match snd {
+                Operand::Reg(r) if r == dst && fst != snd =>
+                    Operand::Reg(self.copy_to_temp(Operand::Reg(r), ty)),
+                snd => snd
+            };
+            if fst != Operand::Reg(dst) { self.push(MInst::Mov { dst, src: fst }) }
+            self.push(MInst::Bin { op, dst, fst: Operand::Reg(dst), snd })
+        } else {
+            let fst = Operand::Reg(self.reg(fst, ty));
+            self.push(MInst::Bin { op, dst, fst, snd })
+        }
+    }
+
+    fn call(&mut self, func: Operand, arg: &[RefCell<Value>], dst: &[RefCell<SymbolRef>]) {
+        let arg = arg.iter().map(|a| self.opd(&a.borrow())).collect();
+        let mut back = vec![];
+        let regs = dst.iter().map(|d| {
+            let d = d.borrow().clone();
+            match d.as_ref() {
+                Symbol::Global(_) => {
+                    let reg = self.temp(d.get_type());
+                    back.push((d.clone(), reg));
+                    reg
+                }
+                _ => self.var(&d)
+            }
+        }).collect();
+        self.push(MInst::Call { func, arg, dst: regs });
+        for (d, reg) in back {
+            self.assign(&d, |this, dst| this.push(MInst::Mov { dst, src: Operand::Reg(reg) }))
+        }
+    }
+
+    /// Get address pointed to by `ptr`, folding the computation of it if possible.
+    fn addr_of(&mut self, ptr: &Value) -> Addr {
+        if let Value::Var(sym) = ptr {
+            if let Some(&r) = self.f.var.get(sym) {
+                if let Some(addr) = self.fixed.get(&r).or_else(|| self.addr.get(&r)) {
+                    return addr.clone();
+                }
+            }
+        }
+        let opd = self.opd(ptr);
+        Addr::new(Base::Reg(self.reg(opd, Type::I(64))))
+    }
+
+    /// Add `idx * scale` to the address. If the address already has an index, or the scale is
+    /// not supported, the index is computed separately.
+    fn add_index(&mut self, addr: &mut Addr, idx: &Value, scale: usize) {
+        let scale = scale as i64;
+        let opd = self.opd(idx);
+        let idx = match opd {
+            Operand::Imm(c) => {
+                addr.disp += c * scale;
+                return;
+            }
+            opd => self.reg(opd, Type::I(64))
+        };
+        if addr.index.is_some() {
+            let base = self.temp(Type::I(64));
+            self.push(MInst::Lea { dst: base, addr: Addr { disp: 0, ..addr.clone() } });
+            addr.base = Base::Reg(base);
+            addr.index = None;
+        }
+        if self.target.scales.contains(&scale) {
+            addr.index = Some((idx, scale))
+        } else {
+            let off = self.temp(Type::I(64));
+            self.bin(BinOp::Mul, off, Operand::Reg(idx), Operand::Imm(scale));
+            addr.index = Some((off, 1))
+        }
+    }
+
+    /// Make address encodable as memory operand accessing `size` bytes, by computing it to a
+    /// register if it is not.
+    fn legalize(&mut self, addr: Addr, size: usize) -> Addr {
+        if self.target.is_legal(&addr, size) { return addr; }
+        // Try computing base and index separately, and keeping the displacement
+        let inner = Addr { disp: 0, ..addr.clone() };
+        let outer = |reg| Addr { base: Base::Reg(reg), index: None, disp: addr.disp };
+        let split = addr.index.is_some() && self.target.is_legal(&inner, size)
+            && self.target.is_legal(&outer(0), size);
+        let base = self.temp(Type::I(64));
+        if split {
+            self.push(MInst::Lea { dst: base, addr: inner });
+            outer(base)
+        } else {
+            self.push(MInst::Lea { dst: base, addr });
+            Addr::new(Base::Reg(base))
+        }
+    }
+
+    /// Get the block to branch to for IR edge `from -> to`. If `to` has phis, their copies are
+    /// placed at the end of current block if it only jumps there, or in a new block on the edge
+    /// otherwise.
+    fn edge(&mut self, from: &BlockRef, to: &BlockRef) -> usize {
+        let tgt = self.block[to];
+        let copies: Vec<(VReg, Value)> = to.inst.borrow().iter().take_while(|i| i.is_phi())
+            .filter_map(|phi| match phi.as_ref() {
+                Inst::Phi { src, dst } => src.iter()
+                    .find(|(p, _)| p.borrow().deref() == from)
+                    .map(|(_, v)| (dst.borrow().clone(), v.borrow().clone())),
+                _ => None
+            })
+            .map(|(dst, v)| (self.var(&dst), v)).collect();
+        if copies.is_empty() { return tgt; }
+        if from.succ.borrow().len() == 1 {
+            self.copy(copies);
+            return tgt;
+        }
+        let prev = self.cur;
+        self.cur = self.new_block(&format!("{}.{}", from.name, to.name));
+        self.copy(copies);
+        self.push(MInst::Jmp { tgt });
+        let edge = self.cur;
+        self.cur = prev;
+        edge
+    }
+
+    /// Emit parallel copies. Sources are first copied to temporaries if any of them is
+    /// overwritten by another copy.
+    fn copy(&mut self, copies: Vec<(VReg, Value)>) {
+        let copies: Vec<(VReg, Operand)> = copies.into_iter()
+            .map(|(dst, v)| (dst, self.opd(&v)))
+            .filter(|(dst, src)| *src != Operand::Reg(*dst)).collect();
+        let clobber = copies.iter().any(|(_, src)| {
+            copies.iter().any(|(dst, _)| *src == Operand::Reg(*dst))
+        });
+        if !clobber {
+            copies.into_iter().for_each(|(dst, src)| self.push(MInst::Mov { dst, src }));
+            return;
+        }
+        let tmp: Vec<VReg> = copies.iter().map(|(dst, src)| {
+            let ty = self.f.ty[*dst].clone();
+            self.copy_to_temp(src.clone(), ty)
+        }).collect();
+        copies.into_iter().zip(tmp).for_each(|((dst, _), t)| {
+            self.push(MInst::Mov { dst, src: Operand::Reg(t) })
+        })
+    }
+
+    fn copy_to_temp(&mut self, src: Operand, ty: Type) -> VReg {
+        let dst = self.temp(ty);
+        self.push(MInst::Mov { dst, src });
+        dst
+    }
+
+    /// Emit a loop counting from zero to `len`, with body emitted by `body` given the counter.
+    /// Instructions after the loop are appended to a new block.
+    fn mem_loop(&mut self, len: &Value, body: impl FnOnce(&mut Self, VReg)) {
+        let len = self.opd(len);
+        let name = self.f.blocks[self.cur].name.clone();
+        let (head, body_blk, next) = (self.new_block(&name), self.new_block(&name),
+                                      self.new_block(&name));
+        let i = self.temp(Type::I(64));
+        self.push(MInst::Mov { dst: i, src: Operand::Imm(0) });
+        self.push(MInst::Jmp { tgt: head });
+
+        self.cur = head;
+        let cond = self.temp(Type::I(1));
+        self.bin(BinOp::Lt, cond, Operand::Reg(i), len);
+        self.push(MInst::Br { cond: Operand::Reg(cond), tr: body_blk, fls: next });
+
+        self.cur = body_blk;
+        body(self, i);
+        self.bin(BinOp::Add, i, Operand::Reg(i), Operand::Imm(1));
+        self.push(MInst::Jmp { tgt: head });
+
+        // Addresses computed before the loop are still valid, but the folding is only done in
+        // the same block for simplicity
+        self.cur = next;
+        self.addr.clear();
+    }
+
+    fn new_block(&mut self, prefix: &str) -> usize {
+        self.n_new += 1;
+        self.f.blocks.push(MBlock { name: format!("{}.{}", prefix, self.n_new), inst: vec![] });
+        self.f.blocks.len() - 1
+    }
+
+    /// Remove instructions without side effects whose results are not used. A tied operand is
+    /// not counted as a use of the instruction's own result.
+    fn remove_dead(&mut self) {
+        loop {
+            let mut used = HashSet::new();
+            self.f.blocks.iter().flat_map(|b| b.inst.iter()).for_each(|instr| {
+                let mut tied = instr.tied();
+                for r in instr.uses() {
+                    if Some(r) == tied {
+                        tied = None;
+                        continue;
+                    }
+                    used.insert(r);
+                }
+            });
+            let mut changed = false;
+            for block in self.f.blocks.iter_mut() {
+                let len = block.inst.len();
+                block.inst.retain(|i| !i.is_pure() || i.dst().iter().any(|d| used.contains(d)));
+                changed |= block.inst.len() != len;
+            }
+            if !changed { break; }
+        }
+    }
+}
+
+impl Display for Addr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match &self.base {
+            Base::Reg(r) => write!(f, "[v{}", r)?,
+            Base::Slot(s) => write!(f, "[slot{}", s)?,
+            Base::Sym(s) => write!(f, "[@{}", s)?,
+        }
+        if let Some((r, s)) = self.index { write!(f, " + v{} * {}", r, s)?; }
+        if self.disp != 0 { write!(f, " + {}", self.disp)?; }
+        write!(f, "]")
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Operand::Reg(r) => write!(f, "v{}", r),
+            Operand::Imm(c) => write!(f, "{}", c),
+            Operand::Sym(s) => write!(f, "@{}", s),
+        }
+    }
+}
+
+impl Display for MFn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let list = |opd: &[Operand]| opd.iter().map(|o| o.to_string()).collect::<Vec<_>>()
+            .join(", ");
+        let param: Vec<_> = self.param.iter().map(|p| format!("v{}", p)).collect();
+        writeln!(f, "fn @{}({}) {{", self.name, param.join(", "))?;
+        for block in &self.blocks {
+            writeln!(f, "%{}:", block.name)?;
+            for instr in &block.inst {
+                write!(f, "    ")?;
+                match instr {
+                    MInst::Mov { dst, src } => write!(f, "v{} <- mov {}", dst, src)?,
+                    MInst::Un { op, dst, opd } =>
+                        write!(f, "v{} <- {} {}", dst, op.to_string(), opd)?,
+                    MInst::Bin { op, dst, fst, snd } =>
+                        write!(f, "v{} <- {} {}, {}", dst, op.to_string(), fst, snd)?,
+                    MInst::Ext { op, dst, src } =>
+                        write!(f, "v{} <- {} v{}", dst, op.to_string(), src)?,
+                    MInst::Lea { dst, addr } => write!(f, "v{} <- lea {}", dst, addr)?,
+                    MInst::Ld { dst, addr, size } =>
+                        write!(f, "v{} <- ld{} {}", dst, size, addr)?,
+                    MInst::St { src, addr, size } => write!(f, "st{} {} -> {}", size, src, addr)?,
+                    MInst::AtomicLd { ord, dst, addr, size } =>
+                        write!(f, "v{} <- ld{}.atomic {} {}", dst, size, ord.to_string(), addr)?,
+                    MInst::AtomicSt { ord, src, addr, size } =>
+                        write!(f, "st{}.atomic {} {} -> {}", size, ord.to_string(), src, addr)?,
+                    MInst::AtomicRmw { op, ord, dst, val, addr, size } =>
+                        write!(f, "v{} <- {}{} {} {}, {}", dst, op.to_string(), size,
+                               ord.to_string(), addr, val)?,
+                    MInst::Call { func, arg, dst } => {
+                        let dst: Vec<_> = dst.iter().map(|d| format!("v{}", d)).collect();
+                        if !dst.is_empty() { write!(f, "{} <- ", dst.join(", "))?; }
+                        write!(f, "call {}({})", func, list(arg))?
+                    }
+                    MInst::Ret { val } => write!(f, "ret {}", list(val))?,
+                    MInst::Jmp { tgt } => write!(f, "jmp %{}", self.blocks[*tgt].name)?,
+                    MInst::Br { cond, tr, fls } => write!(f, "br {} ? %{} : %{}", cond,
+                                                          self.blocks[*tr].name,
+                                                          self.blocks[*fls].name)?,
+                    MInst::Trap => write!(f, "trap")?,
+                }
+                writeln!(f)?;
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[test]
+fn test_mir() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::back::regalloc::{MirLocation, RegAlloc};
+
+    let pro = Builder::new(Parser::new(Lexer::from(r#"
+type @Rec = { i64, [4]i64 }
+
+@g: i64
+
+[ssa]
+fn @f($p: *@Rec, $n: i64) -> i64 {
+%Begin:
+    $a <- alloc [4]i64
+    $b <- ptr *i64 $a [0]
+    memset i64 0 -> $b, 4
+    $z <- eq i64 $n, 0
+    br $z ? %End : %Cond
+%Cond:
+    $i.0 <- phi i64 [%Begin: 0] [%Body: $i.1]
+    $s.0 <- phi i64 [%Begin: 0] [%Body: $s.1]
+    $u.0 <- phi i64 [%Begin: 1] [%Body: $v.0]
+    $v.0 <- phi i64 [%Begin: 2] [%Body: $u.0]
+    $c <- lt i64 $i.0, $n
+    br $c ? %Body : %End
+%Body:
+    $e <- ptr *i64 $p [1, $i.0]
+    $x <- ld i64 $e
+    $s.1 <- add i64 $s.0, $x
+    $i.1 <- add i64 $i.0, 1
+    jmp %Cond
+%End:
+    $r.0 <- phi i64 [%Begin: 0] [%Cond: $s.0]
+    $w.0 <- phi i64 [%Begin: 0] [%Cond: $u.0]
+    $r.1 <- sub i64 $w.0, $r.0
+    $t <- ptr *i64 $b, 2
+    st i64 $r.1 -> $t
+    @g <- mov i64 $r.1
+    ret $r.1
+}
+"#)).parse().unwrap()).build().unwrap();
+    let func = &pro.func[0];
+    let var = |mfn: &MFn, name: &str| mfn.var.iter().find(|(s, _)| s.name() == name)
+        .map(|(_, r)| *r).unwrap();
+    let insts = |mfn: &MFn| mfn.blocks.iter().flat_map(|b| b.inst.clone())
+        .collect::<Vec<_>>();
+
+    // On x64, operations are in two-address form, and the indexed field is loaded with a single
+    // memory operand
+    let x64 = lower(func, &X64, &DataLayout::x64());
+    println!("{}", x64);
+    x64.check(&X64).unwrap();
+    let (i, e) = (var(&x64, "i.0"), var(&x64, "e"));
+    assert!(insts(&x64).iter().any(|instr| matches!(instr, MInst::Ld {
+        dst: _, addr: Addr { base: Base::Reg(_), index: Some((idx, 8)), disp: 8 }, size: 8
+    } if *idx == i)));
+    assert!(insts(&x64).iter().all(|instr| !instr.dst().contains(&e)));
+    assert!(insts(&x64).iter().any(|instr| matches!(instr, MInst::St {
+        src: _, addr: Addr { base: Base::Slot(0), index: None, disp: 16 }, size: 8
+    })));
+
+    // Critical edges are split for phi copies, and swapped phis are copied through
+    // temporaries. `memset` becomes a loop.
+    for edge in ["Begin.End", "Begin.Cond", "Cond.End"] {
+        assert!(x64.blocks.iter().any(|b| b.name.starts_with(edge)));
+    }
+    assert_eq!(x64.blocks.len(), 4 + 3 + 3);
+
+    // On AArch64, an address cannot have both index and displacement, so part of it is computed
+    // separately
+    let a64 = lower(func, &AARCH64, &DataLayout::x64());
+    println!("{}", a64);
+    a64.check(&AARCH64).unwrap();
+    assert!(X64.two_addr && a64.check(&X64).is_err());
+    assert!(insts(&a64).iter().any(|instr| matches!(instr, MInst::Lea {
+        dst: _, addr: Addr { base: Base::Reg(_), index: Some((_, 8)), disp: 0 }
+    })));
+    assert!(insts(&a64).iter().any(|instr| matches!(instr, MInst::Ld {
+        dst: _, addr: Addr { base: Base::Reg(_), index: None, disp: 8 }, size: 8
+    })));
+
+    // Interfering registers are never assigned the same register, and the copy before a
+    // two-address operation is coalesced if its source dies
+    let mut x64 = x64;
+    let graph = x64.interference();
+    let res = RegAlloc::new(4).alloc_mir(&mut x64);
+    for (r, adj) in &graph {
+        for other in adj {
+            if let (Some(MirLocation::Reg(a)), Some(MirLocation::Reg(b)))
+            = (res.loc.get(r), res.loc.get(other)) {
+                assert_ne!(a, b, "v{} and v{} interfere", r, other)
+            }
+        }
+    }
+    let (s0, s1) = (var(&x64, "s.0"), var(&x64, "s.1"));
+    if let (Some(MirLocation::Reg(a)), Some(MirLocation::Reg(b)))
+    = (res.loc.get(&s0), res.loc.get(&s1)) {
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,65 @@
+pub mod abi;
+pub mod regalloc;
+pub mod isel;
+pub mod switch;
+pub mod x64;
+pub mod aarch64;
+pub mod c;
+pub mod layout;
+pub mod mir;
+pub mod frame;
+
+#[test]
+fn test_div_wrap() {
+    use std::process::Command;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::value::Const;
+    use crate::vm::exec::Machine;
+
+    // `MIN / -1` wraps to `MIN` and `MIN % -1` is zero in every backend
+    let pro = Builder::new(Parser::new(Lexer::from(r#"
+@q: i64 <- 0
+@r: i64 <- 0
+
+fn @main() -> i64 {
+%Begin:
+    $a <- sub i64 -9223372036854775807, 1
+    $b <- mov i64 -1
+    $q <- div i64 $a, $b
+    $r <- mod i64 $a, $b
+    @q <- mov i64 $q
+    @r <- mov i64 $r
+    $c <- eq i64 $q, $a
+    $d <- eq i64 $r, 0
+    $e <- and i1 $c, $d
+    $f <- zext i1 $e -> i64
+    ret $f
+}
+"#)).parse().unwrap()).build().unwrap();
+    let rcd = Machine::new().run(&pro).unwrap();
+    let get = |name: &str| rcd.global.iter().find(|(g, _)| g.name == name).unwrap().1
+        .get_const();
+    assert_eq!((get("q"), get("r")), (Const::I64(i64::MIN), Const::I64(0)));
+
+    let mut c = vec![];
+    c::CGen::new(&mut c).emit(&pro).unwrap();
+    let mut asm = vec![];
+    x64::X64Gen::new(&mut asm).emit(&pro).unwrap();
+
+    // Run the generated code if there is a C compiler for the target
+    if !cfg!(all(target_arch = "x86_64", target_os = "linux"))
+        || Command::new("cc").arg("--version").output().is_err() { return; }
+    let dir = std::env::temp_dir().join(format!("irl-div-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (src, ext) in [(c, "c"), (asm, "s")] {
+        let (path, exe) = (dir.join(format!("div.{}", ext)), dir.join(format!("div.{}.out", ext)));
+        std::fs::write(&path, src).unwrap();
+        let status = Command::new("cc").arg("-o").arg(&exe).arg(&path).status().unwrap();
+        assert!(status.success());
+        let out = Command::new(&exe).output().unwrap();
+        assert_eq!(out.status.code(), Some(1), "{}", ext);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,241 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::ops::Deref;
+
+use crate::back::mir::{MFn, MInst, Operand, VReg};
+
+use crate::lang::func::FnRef;
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::util::ExtRc;
+use crate::lang::value::{SymbolGen, SymbolRef, Type, Typed, Value};
+
+/// Storage location assigned to a local variable.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Location {
+    /// Physical register, numbered from 0
+    Reg(usize),
+    /// Stack slot, referred to by the pointer produced by an `alloc` instruction in the entrance
+    /// block
+    Stack(SymbolRef),
+}
+
+/// Function annotated with register allocation result
+#[derive(Debug)]
+pub struct AllocFn {
+    /// The allocated function, possibly with spill slots inserted
+    pub func: FnRef,
+    /// Location of each local variable
+    pub loc: HashMap<SymbolRef, Location>,
+    /// Number of registers actually used
+    pub n_used: usize,
+}
+
+impl AllocFn {
+    /// Get location of the given local variable.
+    pub fn get(&self, sym: &SymbolRef) -> Option<&Location> { self.loc.get(sym) }
+
+    /// Get all spill slots created for this function.
+    pub fn slots(&self) -> Vec<SymbolRef> {
+        let mut slots: Vec<SymbolRef> = self.loc.values().filter_map(|loc| match loc {
+            Location::Stack(slot) => Some(slot.clone()),
+            _ => None
+        }).collect();
+        slots.sort_by(|a, b| a.name().cmp(b.name()));
+        slots.dedup();
+        slots
+    }
+}
+
+/// Storage location assigned to a virtual register of machine IR
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MirLocation {
+    /// Physical register, numbered from 0
+    Reg(usize),
+    /// Stack slot of the function
+    Slot(usize),
+}
+
+/// Machine function annotated with register allocation result
+#[derive(Debug)]
+pub struct MirAlloc {
+    /// Location of each virtual register
+    pub loc: HashMap<VReg, MirLocation>,
+    /// Number of registers actually used
+    pub n_used: usize,
+}
+
+/// Graph-coloring register allocator, following the Chaitin-Briggs approach with optimistic
+/// coloring. Variables that cannot be colored are spilled to stack slots.
+pub struct RegAlloc {
+    /// Number of available physical registers
+    n_reg: usize,
+}
+
+impl RegAlloc {
+    pub fn new(n_reg: usize) -> RegAlloc { RegAlloc { n_reg } }
+
+    /// Allocate registers for local variables in the function.
+    pub fn alloc(&self, func: &FnRef) -> AllocFn {
+        let graph = func.liveness().interference(func);
+        let hint = Self::collect_hints(func);
+
+        let (color, mut spilled) = self.color(&graph.edges, &hint, |sym| sym.name().to_string());
+
+        // Create stack slots for spilled variables
+        let mut loc: HashMap<SymbolRef, Location> = color.iter()
+            .map(|(sym, c)| (sym.clone(), Location::Reg(*c))).collect();
+        let mut gen = SymbolGen::new(func.scope.clone(), "spill");
+        let ent = func.ent.borrow().clone();
+        spilled.sort_by(|a, b| b.name().cmp(a.name()));
+        for sym in spilled {
+            let slot = gen.gen(&Type::Ptr(Box::new(sym.get_type())));
+            ent.inst.borrow_mut().push_front(ExtRc::new(Inst::Alloc {
+                dst: RefCell::new(slot.clone())
+            }));
+            loc.insert(sym, Location::Stack(slot));
+        }
+
+        AllocFn {
+            func: func.clone(),
+            loc,
+            n_used: color.values().max().map(|c| c + 1).unwrap_or(0),
+        }
+    }
+
+    /// Allocate registers for virtual registers of a machine function. Spilled registers are
+    /// given new stack slots of the function. Registers related by moves, including the copies
+    /// made for two-address operations, are preferably assigned the same register.
+    pub fn alloc_mir(&self, func: &mut MFn) -> MirAlloc {
+        let graph = func.interference();
+        let mut hint: HashMap<VReg, Vec<VReg>> = HashMap::new();
+        for instr in func.blocks.iter().flat_map(|b| b.inst.iter()) {
+            if let MInst::Mov { dst, src: Operand::Reg(src) } = instr {
+                hint.entry(*dst).or_default().push(*src);
+                hint.entry(*src).or_default().push(*dst);
+            }
+        }
+        let (color, mut spilled) = self.color(&graph, &hint, |r| *r);
+        let mut loc: HashMap<VReg, MirLocation> = color.iter()
+            .map(|(r, c)| (*r, MirLocation::Reg(*c))).collect();
+        spilled.sort();
+        for r in spilled {
+            loc.insert(r, MirLocation::Slot(func.slots.len()));
+            func.slots.push(8);
+        }
+        MirAlloc { loc, n_used: color.values().max().map(|c| c + 1).unwrap_or(0) }
+    }
+
+    /// Color nodes of an interference graph, and return the colors and the nodes to spill.
+    /// Ties are broken by `key`, so that the result is deterministic.
+    fn color<K, O>(&self, edges: &HashMap<K, HashSet<K>>, hint: &HashMap<K, Vec<K>>,
+                   key: impl Fn(&K) -> O) -> (HashMap<K, usize>, Vec<K>)
+        where K: Clone + Eq + Hash, O: Ord
+    {
+        // Simplify: repeatedly remove nodes with insignificant degree. When there is none,
+        // optimistically push the node with the highest degree as potential spill.
+        let mut degree: HashMap<K, usize> = edges.iter()
+            .map(|(sym, adj)| (sym.clone(), adj.len())).collect();
+        let mut stack = Vec::with_capacity(degree.len());
+        while !degree.is_empty() {
+            let node = degree.iter().filter(|(_, d)| **d < self.n_reg)
+                .min_by_key(|(n, _)| key(n))
+                .or_else(|| degree.iter().max_by(|(a, x), (b, y)| {
+                    x.cmp(y).then_with(|| key(b).cmp(&key(a)))
+                }))
+                .map(|(sym, _)| sym.clone()).unwrap();
+            degree.remove(&node);
+            edges[&node].iter().for_each(|n| {
+                if let Some(d) = degree.get_mut(n) { *d -= 1 }
+            });
+            stack.push(node);
+        }
+
+        // Select: pop nodes and assign the colors, preferring the ones of related variables.
+        let mut color: HashMap<K, usize> = HashMap::new();
+        let mut spilled = vec![];
+        while let Some(node) = stack.pop() {
+            match self.select(&node, edges, &color, hint) {
+                Some(c) => { color.insert(node, c); }
+                None => spilled.push(node)
+            }
+        }
+
+        (color, spilled)
+    }
+
+    /// Choose a color for the node that does not conflict with its neighbors.
+    fn select<K>(&self, node: &K, edges: &HashMap<K, HashSet<K>>, color: &HashMap<K, usize>,
+                 hint: &HashMap<K, Vec<K>>) -> Option<usize>
+        where K: Eq + Hash
+    {
+        let used: HashSet<usize> = edges[node].iter()
+            .filter_map(|n| color.get(n).cloned()).collect();
+        hint.get(node).into_iter().flatten().filter_map(|h| color.get(h).cloned())
+            .find(|c| !used.contains(c))
+            .or_else(|| (0..self.n_reg).find(|c| !used.contains(c)))
+    }
+
+    /// Collect variables related by moves and phi instructions. Assigning them the same
+    /// register makes the copy unnecessary.
+    fn collect_hints(func: &FnRef) -> HashMap<SymbolRef, Vec<SymbolRef>> {
+        let mut hint: HashMap<SymbolRef, Vec<SymbolRef>> = HashMap::new();
+        let mut add = |a: &SymbolRef, b: &SymbolRef| {
+            hint.entry(a.clone()).or_default().push(b.clone());
+            hint.entry(b.clone()).or_default().push(a.clone());
+        };
+        func.iter_dom().for_each(|block| {
+            block.inst.borrow().iter().for_each(|instr: &InstRef| {
+                let src: Vec<_> = match instr.as_ref() {
+                    Inst::Mov { src, dst: _ } => vec![src],
+                    Inst::Phi { src, dst: _ } => src.iter().map(|(_, opd)| opd).collect(),
+                    _ => return
+                };
+                let dst = instr.dst().unwrap().borrow().clone();
+                if !dst.is_local_var() { return; }
+                src.into_iter().for_each(|opd| {
+                    if let Value::Var(sym) = opd.borrow().deref() {
+                        if sym.is_local_var() { add(&dst, sym) }
+                    }
+                })
+            })
+        });
+        hint
+    }
+}
+
+#[test]
+fn test_regalloc() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::{Read, stdout};
+
+    let mut file = File::open("test/sum.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let pro = builder.build().unwrap();
+
+    for func in &pro.func {
+        let graph = func.liveness().interference(func);
+        let res = RegAlloc::new(2).alloc(func);
+        for (sym, adj) in graph.edges.iter() {
+            adj.iter().for_each(|other| {
+                if let (Some(Location::Reg(a)), Some(Location::Reg(b)))
+                = (res.get(sym), res.get(other)) {
+                    assert_ne!(a, b, "{:?} and {:?} interfere", sym, other)
+                }
+            });
+        }
+        println!("fn @{}: {:?}", func.name, res.loc);
+    }
+
+    let mut out = stdout();
+    let mut printer = Printer::new(&mut out);
+    printer.print(&pro).unwrap();
+}
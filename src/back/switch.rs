@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, Fn};
+use crate::lang::inst::{BinOp, Inst};
+use crate::lang::value::{SymbolRef, Type, Typed, Value};
+
+/// Multi-way branch recognized from a chain of `eq` branches on the same variable:
+/// ```text
+/// %A:
+///     $c.1 <- eq i64 $x, 1
+///     br $c.1 ? %One : %B
+/// %B:
+///     $c.2 <- eq i64 $x, 5
+///     br $c.2 ? %Five : %Default
+/// ```
+/// The comparisons are done in block `head`, and then in `chain` blocks, which are only
+/// reached from the previous comparison and contain nothing else.
+#[derive(Clone, Debug)]
+pub struct Switch {
+    /// Block where the chain begins
+    pub head: BlockRef,
+    /// Variable compared against the cases
+    pub val: SymbolRef,
+    /// Case values and targets, sorted by value
+    pub cases: Vec<(i64, BlockRef)>,
+    /// Target when no case matches
+    pub default: BlockRef,
+    /// Blocks of the chain after `head`, whose code is replaced by the switch
+    pub chain: Vec<BlockRef>,
+}
+
+/// How a switch is lowered by a backend
+#[derive(Clone, Debug)]
+pub enum Lowering {
+    /// Jump table indexed by value minus `low`. Holes in the table jump to the default.
+    Table { low: i64, tgt: Vec<BlockRef> },
+    /// Balanced tree of comparisons
+    Tree(Node),
+}
+
+/// Node of a compare tree
+#[derive(Clone, Debug)]
+pub enum Node {
+    /// Compare with each case in turn, and jump to the default if none matches
+    Leaf(Vec<(i64, BlockRef)>),
+    /// Jump to `tgt` if the value equals `pivot`, otherwise search in `lt` if it is less,
+    /// or in `gt` if it is greater
+    Split { pivot: i64, tgt: BlockRef, lt: Box<Node>, gt: Box<Node> },
+}
+
+/// Configuration of switch lowering
+#[derive(Copy, Clone, Debug)]
+pub struct SwitchConf {
+    /// Minimal number of cases for a chain to be lowered as a switch
+    pub min_cases: usize,
+    /// Minimal ratio of cases to the size of jump table. Sparser switches are lowered to
+    /// compare trees.
+    pub density: f64,
+}
+
+impl Default for SwitchConf {
+    fn default() -> Self { SwitchConf { min_cases: 4, density: 0.4 } }
+}
+
+/// Maximal number of cases compared linearly in a leaf of compare tree
+const LEAF_SIZE: usize = 3;
+
+impl SwitchConf {
+    /// Find switches in `func`, with at least `min_cases` cases.
+    pub fn find(&self, func: &Fn) -> Vec<Switch> {
+        // Count uses of each symbol, so that comparisons used elsewhere are not removed
+        let mut uses: HashMap<SymbolRef, usize> = HashMap::new();
+        for block in func.dfs() {
+            for instr in block.inst.borrow().iter() {
+                for opd in instr.src() {
+                    if let Value::Var(sym) = opd.borrow().deref() {
+                        *uses.entry(sym.clone()).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+
+        let mut found: Vec<Switch> = vec![];
+        let mut visited = HashSet::new();
+        for block in func.rpo() {
+            if visited.contains(&block) { continue; }
+            let (val, case, mut next) = match Self::test(&block, &uses) {
+                Some(test) => test,
+                None => continue
+            };
+            let mut sw = Switch {
+                head: block.clone(),
+                val,
+                cases: vec![case],
+                default: next.clone(),
+                chain: vec![],
+            };
+            // Extend the chain with blocks only containing the comparison
+            loop {
+                if next.pred().len() != 1 || next.inst.borrow().len() != 2 { break; }
+                match Self::test(&next, &uses) {
+                    Some((val, case, fls)) if val == sw.val => {
+                        visited.insert(next.clone());
+                        sw.chain.push(next.clone());
+                        sw.cases.push(case);
+                        next = fls;
+                    }
+                    _ => break
+                }
+            }
+            sw.default = next;
+
+            // Phi copies are not supported, and earlier cases take precedence over later
+            // ones with the same value
+            let mut tgt = sw.cases.iter().map(|(_, b)| b).chain(Some(&sw.default));
+            if tgt.any(|b| b.inst.borrow().front().is_some_and(|i| i.is_phi())) { continue; }
+            let mut seen = HashSet::new();
+            sw.cases.retain(|(v, _)| seen.insert(*v));
+            if sw.cases.len() < self.min_cases { continue; }
+            sw.cases.sort_by_key(|(v, _)| *v);
+            found.push(sw);
+        }
+        found
+    }
+
+    /// Decide how `sw` is lowered.
+    pub fn lower(&self, sw: &Switch) -> Lowering {
+        let low = sw.cases.first().unwrap().0;
+        let high = sw.cases.last().unwrap().0;
+        let range = (high as i128 - low as i128 + 1) as f64;
+        if sw.cases.len() as f64 >= range * self.density {
+            let mut tgt = vec![sw.default.clone(); range as usize];
+            sw.cases.iter().for_each(|(v, b)| tgt[(v - low) as usize] = b.clone());
+            Lowering::Table { low, tgt }
+        } else {
+            Lowering::Tree(Self::tree(&sw.cases))
+        }
+    }
+
+    fn tree(cases: &[(i64, BlockRef)]) -> Node {
+        if cases.len() <= LEAF_SIZE { return Node::Leaf(cases.to_vec()); }
+        let mid = cases.len() / 2;
+        let (pivot, tgt) = cases[mid].clone();
+        Node::Split {
+            pivot,
+            tgt,
+            lt: Box::new(Self::tree(&cases[..mid])),
+            gt: Box::new(Self::tree(&cases[mid + 1..])),
+        }
+    }
+
+    /// Match `block` ending with `br` on `eq $x, K` computed right before it, where the
+    /// result is not used elsewhere. Returns `$x`, the case and the false target.
+    fn test(block: &BlockRef, uses: &HashMap<SymbolRef, usize>)
+            -> Option<(SymbolRef, (i64, BlockRef), BlockRef)>
+    {
+        let inst = block.inst.borrow();
+        let mut rev = inst.iter().rev();
+        let (br, eq) = (rev.next()?, rev.next()?);
+        let (cond, tr, fls) = match br.as_ref() {
+            Inst::Br { cond, tr, fls } => (cond, tr.borrow().clone(), fls.borrow().clone()),
+            _ => return None
+        };
+        let (fst, snd, dst) = match eq.as_ref() {
+            Inst::Bin { op: BinOp::Eq, fst, snd, dst } => (fst, snd, dst.borrow().clone()),
+            _ => return None
+        };
+        if !matches!(cond.borrow().deref(), Value::Var(c) if *c == dst) || uses[&dst] != 1 {
+            return None;
+        }
+        let (var, case) = match (fst.borrow().deref(), snd.borrow().deref()) {
+            (Value::Var(v), Value::Const(c)) | (Value::Const(c), Value::Var(v)) =>
+                (v.clone(), c.as_i64()),
+            _ => return None
+        };
+        if !var.is_local_var() || !matches!(var.get_type().orig(), Type::I(_)) || tr == fls {
+            return None;
+        }
+        Some((var, (case, tr), fls))
+    }
+}
+
+#[test]
+fn test_switch() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+
+    let pro = Builder::new(Parser::new(Lexer::from(r#"
+fn @f($x: i64) -> i64 {
+%A:
+    $c.1 <- eq i64 $x, 3
+    br $c.1 ? %R1 : %B
+%B:
+    $c.2 <- eq i64 $x, 1
+    br $c.2 ? %R2 : %C
+%C:
+    $c.3 <- eq i64 1000, $x
+    br $c.3 ? %R1 : %D
+%D:
+    $c.4 <- eq i64 $x, 3
+    br $c.4 ? %R2 : %E
+%E:
+    $c.5 <- eq i64 $x, 2
+    br $c.5 ? %R2 : %R3
+%R1:
+    ret 1
+%R2:
+    ret 2
+%R3:
+    ret 3
+}
+"#)).parse().unwrap()).build().unwrap();
+    let func = &pro.func[0];
+    let conf = SwitchConf::default();
+    let found = conf.find(func);
+    assert_eq!(found.len(), 1);
+    let sw = &found[0];
+    assert_eq!(sw.head.name, "A");
+    assert_eq!(sw.chain.len(), 4);
+    assert_eq!(sw.default.name, "R3");
+    // The second comparison with 3 never succeeds
+    let cases: Vec<_> = sw.cases.iter().map(|(v, b)| (*v, b.name.as_str())).collect();
+    assert_eq!(cases, vec![(1, "R2"), (2, "R2"), (3, "R1"), (1000, "R1")]);
+    assert!(matches!(conf.lower(sw), Lowering::Tree(Node::Split { pivot: 3, .. })));
+
+    let dense = SwitchConf { density: 0.001, ..conf };
+    match dense.lower(sw) {
+        Lowering::Table { low, tgt } => {
+            assert_eq!(low, 1);
+            assert_eq!(tgt.len(), 1000);
+            assert_eq!(tgt[500].name, "R3");
+        }
+        _ => panic!("switch should be lowered to jump table")
+    }
+}
@@ -0,0 +1,869 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, Write};
+use std::ops::Deref;
+
+use crate::back::abi::{ArgLoc, CallConv, SYSV_X64};
+use crate::back::frame::FrameLayout;
+use crate::back::isel::{any, bin, imm, Match, Rule, Selection, Selector};
+use crate::back::regalloc::{AllocFn, Location, RegAlloc};
+use crate::back::switch::{Lowering, Node, Switch, SwitchConf};
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{BinOp, CastOp, Inst, MemOrd, RmwOp, UnOp};
+use crate::lang::layout::DataLayout;
+use crate::lang::Program;
+use crate::lang::value::{GlobalVar, Linkage, Symbol, SymbolRef, Type, Typed, Value};
+
+const CONV: CallConv = SYSV_X64;
+
+/// Action of an instruction selection rule, which emits code for the matched tree
+type Emit = fn(&mut X64Gen<'_>, &Match) -> Result<(), Error>;
+
+/// Instruction selection rules, tried in order
+fn rules() -> Vec<Rule<Emit>> {
+    vec![
+        Rule {
+            name: "lea",
+            pat: bin(BinOp::Add, bin(BinOp::Mul, any("x"), imm("s", |s| [1, 2, 4, 8].contains(&s))),
+                     any("y")),
+            emit: |gen, m| {
+                gen.load(&RefCell::new(m.get("x").clone()), "%rax")?;
+                gen.load(&RefCell::new(m.get("y").clone()), "%rcx")?;
+                writeln!(gen.writer, "\tleaq (%rcx,%rax,{}), %rax", m.imm("s"))?;
+                gen.extend("%rax", &m.dst.get_type())?;
+                gen.store("%rax", &m.dst)
+            },
+        },
+        Rule {
+            name: "add_imm",
+            pat: bin(BinOp::Add, any("x"),
+                     imm("c", |c| (i32::MIN as i64..=i32::MAX as i64).contains(&c))),
+            emit: |gen, m| {
+                gen.load(&RefCell::new(m.get("x").clone()), "%rax")?;
+                writeln!(gen.writer, "\taddq ${}, %rax", m.imm("c"))?;
+                gen.extend("%rax", &m.dst.get_type())?;
+                gen.store("%rax", &m.dst)
+            },
+        },
+    ]
+}
+
+/// Code generator for x86-64 assembly in AT&T syntax.
+/// Phi instructions are eliminated during emission by inserting parallel copies on the CFG
+/// edges, so the functions can be given in SSA form.
+pub struct X64Gen<'a> {
+    writer: &'a mut dyn Write,
+    /// Register allocation result of current function
+    alloc: Option<AllocFn>,
+    /// Offsets to frame pointer of memory allocated by `alloc` instructions
+    frame: HashMap<SymbolRef, i64>,
+    /// Size of stack frame of current function
+    frame_size: i64,
+    /// Counter for generating local labels
+    label_num: usize,
+    /// Block placed right after the current one, which can be reached by fallthrough
+    next: Option<BlockRef>,
+    /// Data layout of the program, with pointer size of this target
+    layout: DataLayout,
+    /// Configuration of lowering `eq` branch chains
+    switch: SwitchConf,
+}
+
+impl X64Gen<'_> {
+    pub fn new(writer: &mut dyn Write) -> X64Gen<'_> {
+        X64Gen {
+            writer,
+            alloc: None,
+            frame: Default::default(),
+            frame_size: 0,
+            label_num: 0,
+            next: None,
+            layout: DataLayout::x64(),
+            switch: SwitchConf::default(),
+        }
+    }
+
+    /// Set configuration of lowering `eq` branch chains to jump tables or compare trees.
+    pub fn switch(mut self, conf: SwitchConf) -> Self {
+        self.switch = conf;
+        self
+    }
+
+    /// Generate assembly for the whole program. Note that register allocation may insert spill
+    /// slots into the functions.
+    pub fn emit(&mut self, pro: &Program) -> Result<(), Error> {
+        self.layout = DataLayout { ptr_size: 8, ..pro.layout };
+
+        // Emit global variables, where constant ones are placed in read-only section
+        for (is_const, sect) in [(false, ".data"), (true, ".section .rodata")] {
+            let vars: Vec<_> = pro.vars.iter().filter(|g| g.is_const == is_const).collect();
+            if vars.is_empty() { continue; }
+            writeln!(self.writer, "\t{}", sect)?;
+            for g in vars {
+                self.emit_global(g)?;
+            }
+            writeln!(self.writer)?;
+        }
+
+        // Emit functions
+        writeln!(self.writer, "\t.text")?;
+        for func in &pro.func {
+            self.emit_fn(func)?;
+            writeln!(self.writer)?;
+        }
+
+        // Mark stack as non-executable
+        writeln!(self.writer, "\t.section .note.GNU-stack,\"\",@progbits")
+    }
+
+    fn emit_global(&mut self, g: &GlobalVar) -> Result<(), Error> {
+        self.emit_linkage(&g.name, g.linkage)?;
+        writeln!(self.writer, "\t.balign {}", g.ty.align_of(&self.layout))?;
+        writeln!(self.writer, "{}:", g.name)?;
+        match (&g.init, self.size_of(&g.ty)) {
+            (Some(c), 1) => writeln!(self.writer, "\t.byte {}", c.as_i64()),
+            (Some(c), 2) => writeln!(self.writer, "\t.short {}", c.as_i64()),
+            (Some(c), 4) => writeln!(self.writer, "\t.long {}", c.as_i64()),
+            (Some(c), _) => writeln!(self.writer, "\t.quad {}", c.as_i64()),
+            (None, size) => writeln!(self.writer, "\t.zero {}", size.max(1))
+        }
+    }
+
+    fn emit_linkage(&mut self, name: &str, linkage: Linkage) -> Result<(), Error> {
+        match linkage {
+            Linkage::Internal => Ok(()),
+            Linkage::Export => writeln!(self.writer, "\t.globl {}", name),
+            Linkage::Weak => writeln!(self.writer, "\t.weak {}", name)
+        }
+    }
+
+    fn emit_fn(&mut self, func: &FnRef) -> Result<(), Error> {
+        // Allocate registers and stack frame
+        let alloc = RegAlloc::new(CONV.saved_regs.len()).alloc(func);
+        let n_saved = alloc.n_used as i64;
+        let frame = FrameLayout::new().layout(func, &alloc, &self.layout, n_saved * 8);
+        self.alloc = Some(alloc);
+        self.frame = frame.offset;
+        self.frame_size = frame.size;
+
+        // Emit prologue
+        self.emit_linkage(&func.name, func.effective_linkage())?;
+        writeln!(self.writer, "{}:", func.name)?;
+        writeln!(self.writer, "\tpushq %rbp")?;
+        writeln!(self.writer, "\tmovq %rsp, %rbp")?;
+        if self.frame_size > 0 {
+            writeln!(self.writer, "\tsubq ${}, %rsp", self.frame_size)?;
+        }
+        for (i, reg) in CONV.saved_regs[..n_saved as usize].iter().enumerate() {
+            writeln!(self.writer, "\tmovq {}, {}(%rbp)", reg, -8 * (i as i64 + 1))?;
+        }
+
+        // Move parameters to their locations
+        for (i, param) in func.param.iter().enumerate() {
+            let param = param.borrow().clone();
+            match CONV.param_loc(i) {
+                ArgLoc::Reg(reg) => self.store(reg, &param)?,
+                ArgLoc::Stack(off) => {
+                    writeln!(self.writer, "\tmovq {}(%rbp), %rax", off)?;
+                    self.store("%rax", &param)?
+                }
+            }
+        }
+
+        // Emit blocks in layout order, with instructions selected by rules. Comparisons of
+        // switches are replaced by their dispatch code, so the chain blocks are not emitted.
+        let isel = Selector::new(rules());
+        let mut sel = isel.select(func);
+        let switch: HashMap<_, _> = self.switch.find(func).into_iter()
+            .map(|sw| (sw.head.clone(), sw)).collect();
+        let chain: HashSet<_> = switch.values().flat_map(|sw| sw.chain.clone()).collect();
+        let layout: Vec<_> = func.layout().into_iter().filter(|b| !chain.contains(b)).collect();
+        for (i, block) in layout.iter().enumerate() {
+            self.next = layout.get(i + 1).cloned();
+            writeln!(self.writer, "{}:", self.block_label(func, block))?;
+            let mut inst: Vec<_> = block.inst.borrow().iter().cloned().collect();
+            if switch.contains_key(block) { inst.truncate(inst.len() - 2); }
+            for (instr, sel) in inst.iter().zip(sel.remove(block).unwrap()) {
+                match sel {
+                    Selection::Default => self.emit_instr(func, block, instr.as_ref())?,
+                    Selection::Covered => {}
+                    Selection::Rule(rule, m) => (rule.emit)(self, &m)?
+                }
+            }
+            if let Some(sw) = switch.get(block) { self.emit_switch(func, sw)?; }
+        }
+        Ok(())
+    }
+
+    /// Emit dispatch code of `sw`, as a jump table or a compare tree.
+    fn emit_switch(&mut self, func: &FnRef, sw: &Switch) -> Result<(), Error> {
+        self.load(&RefCell::new(Value::Var(sw.val.clone())), "%rax")?;
+        let default = self.block_label(func, &sw.default);
+        match self.switch.lower(sw) {
+            Lowering::Table { low, tgt } => {
+                // Values out of range wrap to large unsigned indices after rebasing
+                if low != 0 {
+                    let low = self.imm_opd(low, "%rcx")?;
+                    writeln!(self.writer, "\tsubq {}, %rax", low)?;
+                }
+                let max = self.imm_opd(tgt.len() as i64 - 1, "%rcx")?;
+                writeln!(self.writer, "\tcmpq {}, %rax", max)?;
+                writeln!(self.writer, "\tja {}", default)?;
+
+                // Entries of the table are offsets of targets to the table itself, so that
+                // the code is position independent
+                self.label_num += 1;
+                let table = format!(".L{}.{}", func.name, self.label_num);
+                writeln!(self.writer, "\tleaq {}(%rip), %rcx", table)?;
+                writeln!(self.writer, "\tmovslq (%rcx,%rax,4), %rax")?;
+                writeln!(self.writer, "\taddq %rcx, %rax")?;
+                writeln!(self.writer, "\tjmp *%rax")?;
+                writeln!(self.writer, "\t.section .rodata")?;
+                writeln!(self.writer, "\t.p2align 2")?;
+                writeln!(self.writer, "{}:", table)?;
+                for blk in &tgt {
+                    writeln!(self.writer, "\t.long {}-{}", self.block_label(func, blk), table)?;
+                }
+                writeln!(self.writer, "\t.text")
+            }
+            Lowering::Tree(node) => self.emit_node(func, &node, &default)
+        }
+    }
+
+    /// Emit compare tree rooted at `node`, with the value in `%rax`.
+    fn emit_node(&mut self, func: &FnRef, node: &Node, default: &str) -> Result<(), Error> {
+        match node {
+            Node::Leaf(cases) => {
+                for (val, tgt) in cases {
+                    let val = self.imm_opd(*val, "%rcx")?;
+                    writeln!(self.writer, "\tcmpq {}, %rax", val)?;
+                    writeln!(self.writer, "\tje {}", self.block_label(func, tgt))?;
+                }
+                writeln!(self.writer, "\tjmp {}", default)
+            }
+            Node::Split { pivot, tgt, lt, gt } => {
+                self.label_num += 1;
+                let lt_label = format!(".L{}.{}", func.name, self.label_num);
+                let pivot = self.imm_opd(*pivot, "%rcx")?;
+                writeln!(self.writer, "\tcmpq {}, %rax", pivot)?;
+                writeln!(self.writer, "\tje {}", self.block_label(func, tgt))?;
+                writeln!(self.writer, "\tjl {}", lt_label)?;
+                self.emit_node(func, gt, default)?;
+                writeln!(self.writer, "{}:", lt_label)?;
+                self.emit_node(func, lt, default)
+            }
+        }
+    }
+
+    /// Get operand of immediate `val`. If it cannot be encoded in 32 bits, it is loaded to
+    /// `reg` instead.
+    fn imm_opd(&mut self, val: i64, reg: &str) -> Result<String, Error> {
+        if (i32::MIN as i64..=i32::MAX as i64).contains(&val) { return Ok(format!("${}", val)); }
+        writeln!(self.writer, "\tmovabsq ${}, {}", val, reg)?;
+        Ok(reg.to_string())
+    }
+
+    fn emit_instr(&mut self, func: &FnRef, block: &BlockRef, instr: &Inst)
+                  -> Result<(), Error>
+    {
+        match instr {
+            Inst::Phi { src: _, dst: _ } => {} // handled on edges
+            Inst::Mov { src, dst } => {
+                self.load(src, "%rax")?;
+                self.store("%rax", &dst.borrow())?;
+            }
+            Inst::Un { op, opd, dst } => {
+                self.load(opd, "%rax")?;
+                let ty = dst.borrow().get_type();
+                match op {
+                    UnOp::Neg => writeln!(self.writer, "\tnegq %rax")?,
+                    UnOp::Not if ty == Type::I(1) => writeln!(self.writer, "\txorq $1, %rax")?,
+                    UnOp::Not => writeln!(self.writer, "\tnotq %rax")?
+                }
+                self.extend("%rax", &ty)?;
+                self.store("%rax", &dst.borrow())?;
+            }
+            Inst::Cast { op, opd, dst } => {
+                // Values are kept sign-extended in registers, so only extension from narrower
+                // bits needs extra work.
+                self.load(opd, "%rax")?;
+                match (op, opd.borrow().get_type().orig()) {
+                    (CastOp::ZExt, Type::I(8)) => writeln!(self.writer, "\tmovzbq %al, %rax")?,
+                    (CastOp::ZExt, Type::I(16)) => writeln!(self.writer, "\tmovzwq %ax, %rax")?,
+                    (CastOp::ZExt, Type::I(32)) => writeln!(self.writer, "\tmovl %eax, %eax")?,
+                    (CastOp::SExt, Type::I(1)) => writeln!(self.writer, "\tnegq %rax")?,
+                    _ => {}
+                }
+                self.extend("%rax", &dst.borrow().get_type())?;
+                self.store("%rax", &dst.borrow())?;
+            }
+            Inst::Bin { op, fst, snd, dst } => {
+                self.load(fst, "%rax")?;
+                self.load(snd, "%rcx")?;
+                let ty = dst.borrow().get_type();
+                match op {
+                    BinOp::Add => writeln!(self.writer, "\taddq %rcx, %rax")?,
+                    BinOp::Sub => writeln!(self.writer, "\tsubq %rcx, %rax")?,
+                    BinOp::Mul => writeln!(self.writer, "\timulq %rcx, %rax")?,
+                    BinOp::Div | BinOp::Mod => {
+                        // `MIN / -1` traps in `idivq`, so division by -1 is done by negation
+                        self.label_num += 2;
+                        let div = format!(".L{}.{}", func.name, self.label_num - 1);
+                        let end = format!(".L{}.{}", func.name, self.label_num);
+                        writeln!(self.writer, "\tcmpq $-1, %rcx")?;
+                        writeln!(self.writer, "\tjne {}", div)?;
+                        match op {
+                            BinOp::Div => writeln!(self.writer, "\tnegq %rax")?,
+                            _ => writeln!(self.writer, "\txorl %eax, %eax")?
+                        }
+                        writeln!(self.writer, "\tjmp {}", end)?;
+                        writeln!(self.writer, "{}:", div)?;
+                        writeln!(self.writer, "\tcqto")?;
+                        writeln!(self.writer, "\tidivq %rcx")?;
+                        if *op == BinOp::Mod { writeln!(self.writer, "\tmovq %rdx, %rax")? }
+                        writeln!(self.writer, "{}:", end)?;
+                    }
+                    BinOp::And => writeln!(self.writer, "\tandq %rcx, %rax")?,
+                    BinOp::Or => writeln!(self.writer, "\torq %rcx, %rax")?,
+                    BinOp::Xor => writeln!(self.writer, "\txorq %rcx, %rax")?,
+                    BinOp::Shl | BinOp::Shr => {
+                        // Shift amount is masked by bit width
+                        let bits = match ty.orig() { Type::I(b) => b, _ => 64 };
+                        writeln!(self.writer, "\tandq ${}, %rcx", bits - 1)?;
+                        let instr = if *op == BinOp::Shl { "shlq" } else { "sarq" };
+                        writeln!(self.writer, "\t{} %cl, %rax", instr)?;
+                    }
+                    BinOp::AddOv | BinOp::SubOv | BinOp::MulOv => {
+                        let instr = match op {
+                            BinOp::AddOv => "addq",
+                            BinOp::SubOv => "subq",
+                            _ => "imulq"
+                        };
+                        writeln!(self.writer, "\t{} %rcx, %rax", instr)?;
+                        let opd_ty = fst.borrow().get_type();
+                        match opd_ty.orig() {
+                            Type::I(b) if b < 64 => {
+                                // Exact result fits in 64 bits, check whether it is preserved
+                                // by truncation.
+                                writeln!(self.writer, "\tmovq %rax, %rcx")?;
+                                self.extend("%rcx", &opd_ty)?;
+                                writeln!(self.writer, "\tcmpq %rcx, %rax")?;
+                                writeln!(self.writer, "\tsetne %al")?;
+                            }
+                            _ => writeln!(self.writer, "\tseto %al")?
+                        }
+                    }
+                    cmp => {
+                        let set = match cmp {
+                            BinOp::Eq => "sete",
+                            BinOp::Ne => "setne",
+                            BinOp::Lt => "setl",
+                            BinOp::Le => "setle",
+                            BinOp::Gt => "setg",
+                            BinOp::Ge => "setge",
+                            _ => unreachable!()
+                        };
+                        writeln!(self.writer, "\tcmpq %rcx, %rax")?;
+                        writeln!(self.writer, "\t{} %al", set)?;
+                    }
+                }
+                self.extend("%rax", &ty)?;
+                self.store("%rax", &dst.borrow())?;
+            }
+            Inst::Call { func: callee, arg, dst } =>
+                self.emit_call(arg, dst, &callee.ret, |gen| {
+                    writeln!(gen.writer, "\tcall {}", callee.name)
+                })?,
+            Inst::CallInd { func_ptr, arg, dst } => {
+                let ret = func_ptr.borrow().get_type().fn_sig().unwrap().1;
+                self.emit_call(arg, dst, &ret, |gen| {
+                    gen.load(func_ptr, "%r11")?;
+                    writeln!(gen.writer, "\tcall *%r11")
+                })?
+            }
+            Inst::Ret { val } => {
+                for (val, reg) in val.iter().zip(CONV.ret_loc(&func.ret)?) {
+                    self.load(val, reg)?;
+                }
+                if val.is_empty() && func.is_entry() {
+                    writeln!(self.writer, "\txorl %eax, %eax")?;
+                }
+                let n_saved = self.alloc.as_ref().unwrap().n_used;
+                for (i, reg) in CONV.saved_regs[..n_saved].iter().enumerate() {
+                    writeln!(self.writer, "\tmovq {}(%rbp), {}", -8 * (i as i64 + 1), reg)?;
+                }
+                writeln!(self.writer, "\tmovq %rbp, %rsp")?;
+                writeln!(self.writer, "\tpopq %rbp")?;
+                writeln!(self.writer, "\tret")?;
+            }
+            Inst::Unreachable => writeln!(self.writer, "\tud2")?,
+            Inst::Abort { msg: _ } => writeln!(self.writer, "\tcall abort")?,
+            Inst::Jmp { tgt } => {
+                self.emit_phi_copy(block, &tgt.borrow())?;
+                if !self.is_next(&tgt.borrow()) {
+                    writeln!(self.writer, "\tjmp {}", self.block_label(func, &tgt.borrow()))?;
+                }
+            }
+            Inst::Br { cond, tr, fls } => {
+                // Phi copies on the false edge are emitted in a separate stub
+                self.load(cond, "%rax")?;
+                writeln!(self.writer, "\ttestq %rax, %rax")?;
+                let tr_phi = tr.borrow().inst.borrow().iter().any(|i| i.is_phi());
+                if self.is_next(&fls.borrow()) && !tr_phi {
+                    // Fall through to the false target
+                    writeln!(self.writer, "\tjne {}", self.block_label(func, &tr.borrow()))?;
+                    self.emit_phi_copy(block, &fls.borrow())?;
+                    return Ok(());
+                }
+                let fls_label = self.block_label(func, &fls.borrow());
+                let has_phi = fls.borrow().inst.borrow().iter().any(|i| i.is_phi());
+                let stub = if has_phi {
+                    self.label_num += 1;
+                    format!(".L{}.{}", func.name, self.label_num)
+                } else { fls_label.clone() };
+                writeln!(self.writer, "\tje {}", stub)?;
+                self.emit_phi_copy(block, &tr.borrow())?;
+                if has_phi || !self.is_next(&tr.borrow()) {
+                    writeln!(self.writer, "\tjmp {}", self.block_label(func, &tr.borrow()))?;
+                }
+                if has_phi {
+                    writeln!(self.writer, "{}:", stub)?;
+                    self.emit_phi_copy(block, &fls.borrow())?;
+                    writeln!(self.writer, "\tjmp {}", fls_label)?;
+                }
+            }
+            Inst::Alloc { dst } => {
+                // Spill slots are not assigned locations, and are accessed directly
+                if self.location(&dst.borrow()).is_none() { return Ok(()); }
+                writeln!(self.writer, "\tleaq {}(%rbp), %rax", self.frame[&dst.borrow()])?;
+                self.store("%rax", &dst.borrow())?;
+            }
+            Inst::New { dst, len } => {
+                // Zero-initialized by `calloc`
+                match len {
+                    Some(len) => self.load(len, "%rdi")?,
+                    None => writeln!(self.writer, "\tmovq $1, %rdi")?
+                }
+                let size = self.size_of(&dst.borrow().get_type().tgt_type());
+                writeln!(self.writer, "\tmovq ${}, %rsi", size)?;
+                writeln!(self.writer, "\tcall calloc")?;
+                self.store("%rax", &dst.borrow())?;
+            }
+            Inst::Ptr { base, off, ind, dst } => {
+                self.load(base, "%rax")?;
+                let mut ty = base.borrow().get_type().tgt_type();
+                if let Some(off) = off {
+                    self.add_offset(off, self.size_of(&ty))?;
+                }
+                for idx in ind {
+                    match (ty.orig(), idx.borrow().deref()) {
+                        (Type::Array { elem, len: _ }, Value::Var(_)) => {
+                            self.add_offset(idx, self.size_of(&elem))?;
+                            ty = elem.deref().clone();
+                        }
+                        (_, Value::Const(c)) => {
+                            let i = c.as_i64() as usize;
+                            let off = ty.field_offset(i, &self.layout);
+                            if off != 0 { writeln!(self.writer, "\taddq ${}, %rax", off)?; }
+                            ty = ty.elem_type(i);
+                        }
+                        _ => unreachable!()
+                    }
+                }
+                self.store("%rax", &dst.borrow())?;
+            }
+            Inst::Ld { ptr, dst } => {
+                self.load(ptr, "%rcx")?;
+                let ty = dst.borrow().get_type();
+                writeln!(self.writer, "\t{} (%rcx), %rax", load_instr(&ty))?;
+                self.store("%rax", &dst.borrow())?;
+            }
+            Inst::St { src, ptr } => {
+                self.load(ptr, "%rcx")?;
+                self.load(src, "%rax")?;
+                let ty = src.borrow().get_type();
+                let size = self.size_of(&ty);
+                writeln!(self.writer, "\t{} {}, (%rcx)", store_instr(size), sub_reg("%rax", size))?;
+            }
+            Inst::Memcpy { src, ptr, len } => {
+                let ty = src.borrow().get_type().tgt_type();
+                self.load(src, "%rsi")?;
+                self.emit_mem_loop(func, ptr, len, &ty, |gen| {
+                    writeln!(gen.writer, "\t{} (%rsi), %rax", load_instr(&ty))?;
+                    writeln!(gen.writer, "\taddq ${}, %rsi", gen.size_of(&ty))
+                })?;
+            }
+            Inst::Memset { src, ptr, len } => {
+                self.load(src, "%rax")?;
+                self.emit_mem_loop(func, ptr, len, &src.borrow().get_type(), |_| Ok(()))?;
+            }
+            // Plain loads and stores are already ordered on x86-64 except for a store followed
+            // by a load, so only sequentially consistent stores need a locked instruction.
+            Inst::AtomicLd { ord: _, ptr, dst } => {
+                self.load(ptr, "%rcx")?;
+                let ty = dst.borrow().get_type();
+                writeln!(self.writer, "\t{} (%rcx), %rax", load_instr(&ty))?;
+                self.store("%rax", &dst.borrow())?;
+            }
+            Inst::AtomicSt { ord, src, ptr } => {
+                self.load(ptr, "%rcx")?;
+                self.load(src, "%rax")?;
+                let size = self.size_of(&src.borrow().get_type());
+                let instr = match ord {
+                    MemOrd::SeqCst => format!("xchg{}", suffix(size)),
+                    _ => store_instr(size).to_string()
+                };
+                writeln!(self.writer, "\t{} {}, (%rcx)", instr, sub_reg("%rax", size))?;
+            }
+            Inst::AtomicRmw { op, ord: _, ptr, val, dst } => {
+                self.load(ptr, "%rcx")?;
+                self.load(val, "%rax")?;
+                let ty = dst.borrow().get_type();
+                let size = self.size_of(&ty);
+                let (sfx, reg) = (suffix(size), sub_reg("%rax", size));
+                match op {
+                    RmwOp::Xchg => writeln!(self.writer, "\txchg{} {}, (%rcx)", sfx, reg)?,
+                    RmwOp::Add => writeln!(self.writer, "\tlock xadd{} {}, (%rcx)", sfx, reg)?,
+                    RmwOp::Sub => {
+                        writeln!(self.writer, "\tnegq %rax")?;
+                        writeln!(self.writer, "\tlock xadd{} {}, (%rcx)", sfx, reg)?
+                    }
+                    // Retry compare-and-swap until memory is not changed by others
+                    _ => {
+                        writeln!(self.writer, "\tmovq %rax, %rsi")?;
+                        writeln!(self.writer, "\t{} (%rcx), %rax", load_instr(&ty))?;
+                        self.label_num += 1;
+                        let head = format!(".L{}.{}", func.name, self.label_num);
+                        writeln!(self.writer, "{}:", head)?;
+                        writeln!(self.writer, "\tmovq %rax, %rdx")?;
+                        writeln!(self.writer, "\t{}q %rsi, %rdx", op.bin_op().unwrap().to_string())?;
+                        writeln!(self.writer, "\tlock cmpxchg{} {}, (%rcx)", sfx,
+                                 sub_reg("%rdx", size))?;
+                        writeln!(self.writer, "\tjne {}", head)?
+                    }
+                }
+                self.extend("%rax", &ty)?;
+                self.store("%rax", &dst.borrow())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit a loop that stores `%rax` to `len` elements of type `ty` starting from `ptr`.
+    /// `next` is emitted at the beginning of each iteration to compute the stored value.
+    fn emit_mem_loop(&mut self, func: &FnRef, ptr: &RefCell<Value>, len: &RefCell<Value>,
+                     ty: &Type, next: impl Fn(&mut Self) -> Result<(), Error>)
+                     -> Result<(), Error> {
+        self.load(ptr, "%rdi")?;
+        self.load(len, "%rdx")?;
+        self.label_num += 2;
+        let head = format!(".L{}.{}", func.name, self.label_num - 1);
+        let end = format!(".L{}.{}", func.name, self.label_num);
+        writeln!(self.writer, "{}:", head)?;
+        writeln!(self.writer, "\ttestq %rdx, %rdx")?;
+        writeln!(self.writer, "\tjle {}", end)?;
+        next(self)?;
+        let size = self.size_of(ty);
+        writeln!(self.writer, "\t{} {}, (%rdi)", store_instr(size), sub_reg("%rax", size))?;
+        writeln!(self.writer, "\taddq ${}, %rdi", size)?;
+        writeln!(self.writer, "\tdecq %rdx")?;
+        writeln!(self.writer, "\tjmp {}", head)?;
+        writeln!(self.writer, "{}:", end)
+    }
+
+    /// Emit parallel copies for phi instructions in `succ` along the edge from `pred`.
+    /// All the sources are pushed to stack before any of the destinations are written, so that
+    /// the copies do not interfere with each other.
+    fn emit_phi_copy(&mut self, pred: &BlockRef, succ: &BlockRef) -> Result<(), Error> {
+        let mut copies = vec![];
+        for instr in succ.inst.borrow().iter() {
+            if let Inst::Phi { src, dst } = instr.as_ref() {
+                let opd = src.iter().find(|(b, _)| b.borrow().deref() == pred).unwrap();
+                let opd = opd.1.borrow().clone();
+                let dst = dst.borrow().clone();
+                // Copies between coalesced variables are not needed
+                if let Value::Var(sym) = &opd {
+                    if self.location(sym).is_some() && self.location(sym) == self.location(&dst) {
+                        continue;
+                    }
+                }
+                copies.push((dst, opd));
+            }
+        }
+        if copies.len() == 1 {
+            let (dst, opd) = copies.pop().unwrap();
+            self.load(&RefCell::new(opd), "%rax")?;
+            return self.store("%rax", &dst);
+        }
+        for (_, opd) in copies.iter() {
+            self.load(&RefCell::new(opd.clone()), "%rax")?;
+            writeln!(self.writer, "\tpushq %rax")?;
+        }
+        for (dst, _) in copies.iter().rev() {
+            writeln!(self.writer, "\tpopq %rax")?;
+            self.store("%rax", dst)?;
+        }
+        Ok(())
+    }
+
+    /// Add `idx * size` to the address in `%rax`.
+    fn add_offset(&mut self, idx: &RefCell<Value>, size: usize) -> Result<(), Error> {
+        match idx.borrow().deref() {
+            Value::Const(c) =>
+                writeln!(self.writer, "\taddq ${}, %rax", c.as_i64() * size as i64),
+            _ => {
+                self.load(idx, "%rcx")?;
+                writeln!(self.writer, "\timulq ${}, %rcx", size)?;
+                writeln!(self.writer, "\taddq %rcx, %rax")
+            }
+        }
+    }
+
+    /// Load value to a 64-bit register. Narrower integers are kept sign-extended in registers,
+    /// except `i1`, which is zero-extended.
+    fn load(&mut self, opd: &RefCell<Value>, reg: &str) -> Result<(), Error> {
+        match opd.borrow().deref() {
+            Value::Const(c) => {
+                let val = c.as_i64();
+                if val >= i32::MIN as i64 && val <= i32::MAX as i64 {
+                    writeln!(self.writer, "\tmovq ${}, {}", val, reg)
+                } else {
+                    writeln!(self.writer, "\tmovabsq ${}, {}", val, reg)
+                }
+            }
+            Value::Var(sym) => match sym.as_ref() {
+                Symbol::Global(g) =>
+                    writeln!(self.writer, "\t{} {}(%rip), {}", load_instr(&g.ty), g.name, reg),
+                Symbol::Func(f) => writeln!(self.writer, "\tleaq {}(%rip), {}", f.name, reg),
+                _ => match self.location(sym) {
+                    Some(Location::Reg(r)) =>
+                        writeln!(self.writer, "\tmovq {}, {}", CONV.saved_regs[r], reg),
+                    Some(Location::Stack(slot)) =>
+                        writeln!(self.writer, "\tmovq {}(%rbp), {}", self.frame[&slot], reg),
+                    // Spill slots are addresses in stack frame
+                    None => writeln!(self.writer, "\tleaq {}(%rbp), {}", self.frame[sym], reg)
+                }
+            }
+        }
+    }
+
+    /// Emit a call sequence, where `call` emits the call instruction itself after arguments
+    /// are passed.
+    fn emit_call(&mut self, arg: &[RefCell<Value>], dst: &[RefCell<SymbolRef>], ret: &Type,
+                 call: impl FnOnce(&mut Self) -> Result<(), Error>) -> Result<(), Error> {
+        // Push arguments that cannot be passed by registers
+        let n_stack = CONV.n_stack_arg(arg.len());
+        let pad = if n_stack % 2 == 1 { 8 } else { 0 };
+        if pad > 0 { writeln!(self.writer, "\tsubq ${}, %rsp", pad)?; }
+        for a in arg[arg.len() - n_stack..].iter().rev() {
+            self.load(a, "%rax")?;
+            writeln!(self.writer, "\tpushq %rax")?;
+        }
+        for (a, reg) in arg.iter().zip(CONV.arg_regs.iter()) {
+            self.load(a, reg)?;
+        }
+        call(self)?;
+        if n_stack > 0 {
+            writeln!(self.writer, "\taddq ${}, %rsp", n_stack * 8 + pad)?;
+        }
+        let regs = CONV.ret_loc(ret)?;
+        for ((dst, reg), ty) in dst.iter().zip(regs).zip(ret.ret_types().iter()) {
+            self.extend(reg, ty)?;
+            self.store(reg, &dst.borrow())?;
+        }
+        Ok(())
+    }
+
+    /// Store value in a scratch register to the location of destination symbol.
+    fn store(&mut self, reg: &str, dst: &SymbolRef) -> Result<(), Error> {
+        match dst.as_ref() {
+            Symbol::Global(g) => {
+                let size = self.size_of(&g.ty);
+                writeln!(self.writer, "\t{} {}, {}(%rip)", store_instr(size), sub_reg(reg, size),
+                         g.name)
+            }
+            _ => match self.location(dst) {
+                Some(Location::Reg(r)) =>
+                    writeln!(self.writer, "\tmovq {}, {}", reg, CONV.saved_regs[r]),
+                Some(Location::Stack(slot)) =>
+                    writeln!(self.writer, "\tmovq {}, {}(%rbp)", reg, self.frame[&slot]),
+                None => Ok(()) // variable is never used
+            }
+        }
+    }
+
+    /// Normalize the value in `%rax` according to its type.
+    fn extend(&mut self, reg: &str, ty: &Type) -> Result<(), Error> {
+        match ty.orig() {
+            Type::I(1) => writeln!(self.writer, "\tandq $1, {}", reg),
+            Type::I(b) if b < 64 => writeln!(self.writer, "\t{} {}, {}", load_instr(ty),
+                                             sub_reg(reg, b as usize / 8), reg),
+            _ => Ok(())
+        }
+    }
+
+    fn location(&self, sym: &SymbolRef) -> Option<Location> {
+        self.alloc.as_ref().unwrap().get(sym).cloned()
+    }
+
+    fn is_next(&self, block: &BlockRef) -> bool { self.next.as_ref() == Some(block) }
+
+    fn block_label(&self, func: &FnRef, block: &BlockRef) -> String {
+        format!(".L{}.{}", func.name, block.name)
+    }
+
+    fn size_of(&self, ty: &Type) -> usize { ty.size_of(&self.layout) }
+}
+
+/// Instruction that loads value of given type from memory to a 64-bit register.
+fn load_instr(ty: &Type) -> &'static str {
+    match ty.orig() {
+        Type::I(1) => "movzbq",
+        Type::I(8) => "movsbq",
+        Type::I(16) => "movswq",
+        Type::I(32) => "movslq",
+        _ => "movq"
+    }
+}
+
+/// Instruction that stores value of given size in bytes from register to memory.
+fn store_instr(size: usize) -> &'static str {
+    match size {
+        1 => "movb",
+        2 => "movw",
+        4 => "movl",
+        _ => "movq"
+    }
+}
+
+/// Suffix of instruction operating on data of given size in bytes.
+fn suffix(size: usize) -> &'static str {
+    match size {
+        1 => "b",
+        2 => "w",
+        4 => "l",
+        _ => "q"
+    }
+}
+
+/// Get sub-register of scratch register with given size in bytes.
+fn sub_reg(reg: &str, size: usize) -> &str {
+    match (reg, size) {
+        ("%rax", 1) => "%al",
+        ("%rax", 2) => "%ax",
+        ("%rax", 4) => "%eax",
+        ("%rcx", 1) => "%cl",
+        ("%rcx", 2) => "%cx",
+        ("%rcx", 4) => "%ecx",
+        ("%rdx", 1) => "%dl",
+        ("%rdx", 2) => "%dx",
+        ("%rdx", 4) => "%edx",
+        _ => reg
+    }
+}
+
+#[test]
+fn test_x64() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use std::io::stdout;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+    use std::borrow::BorrowMut;
+
+    let mut file = File::open("test/sum.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let pro = builder.build().unwrap();
+
+    let mut out = stdout();
+    let mut gen = X64Gen::new(out.borrow_mut());
+    gen.emit(&pro).unwrap();
+}
+
+#[test]
+fn test_x64_isel() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+
+    let pro = Builder::new(Parser::new(Lexer::from(r#"
+fn @f($x: i64, $y: i64) -> i64 {
+%Begin:
+    $a <- mul i64 $x, 8
+    $b <- add i64 $y, $a
+    $c <- add i64 $b, -5
+    $d <- mul i64 $c, 3
+    $e <- add i64 $d, $d
+    ret $e
+}
+"#)).parse().unwrap()).build().unwrap();
+    let mut out = vec![];
+    X64Gen::new(&mut out).emit(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    assert!(out.contains("leaq (%rcx,%rax,8), %rax"));
+    assert!(out.contains("addq $-5, %rax"));
+    // Multiplication by 3 cannot be folded into `lea`
+    assert_eq!(out.matches("imulq").count(), 1);
+}
+
+#[test]
+fn test_x64_switch() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+
+    let src = |cases: [i64; 4]| format!(r#"
+fn @f($x: i64) -> i64 {{
+%A:
+    $c.1 <- eq i64 $x, {}
+    br $c.1 ? %R1 : %B
+%B:
+    $c.2 <- eq i64 $x, {}
+    br $c.2 ? %R2 : %C
+%C:
+    $c.3 <- eq i64 $x, {}
+    br $c.3 ? %R3 : %D
+%D:
+    $c.4 <- eq i64 $x, {}
+    br $c.4 ? %R1 : %R4
+%R1:
+    ret 1
+%R2:
+    ret 2
+%R3:
+    ret 3
+%R4:
+    ret 4
+}}
+"#, cases[0], cases[1], cases[2], cases[3]);
+    let emit = |src: &str| {
+        let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+        let mut out = vec![];
+        X64Gen::new(&mut out).emit(&pro).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    // Dense cases are lowered to jump table, where the hole jumps to the default
+    let out = emit(&src([3, 5, 2, 6]));
+    println!("{}", out);
+    assert!(out.contains("subq $2, %rax"));
+    assert!(out.contains("cmpq $4, %rax"));
+    assert!(out.contains("jmp *%rax"));
+    assert_eq!(out.matches(".long .Lf.R4-").count(), 1);
+    assert!(!out.contains(".Lf.B:"));
+
+    // Sparse cases are lowered to compare tree
+    let out = emit(&src([1000, 10, -7, 1 << 40]));
+    println!("{}", out);
+    assert!(!out.contains("jmp *%rax"));
+    assert!(out.contains("cmpq $1000, %rax"));
+    assert!(out.contains("movabsq $1099511627776, %rcx"));
+    assert_eq!(out.matches("cmpq").count(), 4);
+    assert!(!out.contains("sete"));
+}
@@ -0,0 +1,93 @@
+use std::fmt::{Debug, Display, Error, Formatter};
+
+use crate::irc::CompileErr;
+use crate::irc::build::Builder;
+use crate::irc::lex::Lexer;
+use crate::irc::parse::Parser;
+use crate::lang::verify::VerifyErr;
+use crate::pass::Pass;
+use crate::pass::manager::PassManager;
+use crate::pass::verify::VerifyPass;
+use crate::vm::exec::{Machine, RuntimeErr, VmRcd};
+
+/// Error of `run_source`, tagged with the stage where it occurs
+pub enum RunErr {
+    /// Pipeline description is invalid
+    Pipeline(String),
+    /// Source cannot be lexed, parsed or built
+    Compile(CompileErr),
+    /// Optimized program fails verification, which is a bug of some pass in the pipeline
+    Verify(Vec<VerifyErr>),
+    /// Program stops with an error in the interpreter
+    Runtime(RuntimeErr),
+}
+
+impl Display for RunErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            RunErr::Pipeline(msg) => write!(f, "invalid pipeline: {}", msg),
+            RunErr::Compile(err) => write!(f, "{}", err),
+            RunErr::Verify(err) => {
+                writeln!(f, "verification failed after optimization:")?;
+                err.iter().try_for_each(|e| writeln!(f, "{}", e))
+            }
+            RunErr::Runtime(err) => write!(f, "{:?}", err)
+        }
+    }
+}
+
+impl Debug for RunErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> { Display::fmt(self, f) }
+}
+
+/// Compile source `src`, optimize it with pipeline `passes` and run it in the interpreter, all
+/// in one call. The pipeline is described as in `PassManager::parse`, e.g. `"sccp,gvn,dce"`.
+/// If it is not empty, functions are converted to SSA form before optimization, as most passes
+/// require, and the optimized program is verified before it is run.
+pub fn run_source(src: &str, passes: &str) -> Result<VmRcd, RunErr> {
+    let mut mgr = PassManager::parse(passes).map_err(RunErr::Pipeline)?;
+    let tree = Parser::new(Lexer::from(src)).parse().map_err(RunErr::Compile)?;
+    let mut pro = Builder::new(tree).build().map_err(RunErr::Compile)?;
+    if !mgr.is_empty() {
+        pro.func.iter().filter(|f| !f.has_block_args()).for_each(|f| f.to_ssa());
+        mgr.run(&mut pro);
+        let mut ver = VerifyPass::new();
+        ver.run(&mut pro);
+        if !ver.is_ok() { return Err(RunErr::Verify(ver.err)); }
+    }
+    Machine::new().run(&pro).map_err(RunErr::Runtime)
+}
+
+#[test]
+fn test_run_source() {
+    let src = r#"
+@r: i64
+
+fn @main() {
+%Begin:
+    $i <- mov i64 0
+    $s <- mov i64 0
+    jmp %Loop
+%Loop:
+    $t <- mul i64 $i, 2
+    $s <- add i64 $s, $t
+    $i <- add i64 $i, 1
+    $c <- lt i64 $i, 10
+    br $c ? %Loop : %End
+%End:
+    @r <- mov i64 $s
+    ret
+}
+"#;
+    let plain = run_source(src, "").unwrap();
+    println!("{:?}", plain);
+    assert!(format!("{:?}", plain).contains("@r = 90"));
+    let opt = run_source(src, "sccp,gvn,copy,dce --fixpoint=4").unwrap();
+    assert_eq!(format!("{:?}", plain.global), format!("{:?}", opt.global));
+
+    // Errors are reported from each stage
+    assert!(matches!(run_source(src, "sccp,nop"), Err(RunErr::Pipeline(_))));
+    assert!(matches!(run_source("fn @main() {\n%B:\n}", ""), Err(RunErr::Compile(_))));
+    let err = run_source("fn @main() {\n%B:\n    $x <- div i64 1, 0\n    ret\n}", "");
+    assert!(matches!(err, Err(RunErr::Runtime(e)) if e.msg() == "division by zero"));
+}
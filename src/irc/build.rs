@@ -4,17 +4,24 @@ use std::ops::Deref;
 use std::rc::Rc;
 use std::str::FromStr;
 
-use crate::irc::{CompileErr, Loc};
+use crate::irc::{CompileErr, ErrKind, Loc};
 use crate::irc::syntax::{Term, Token};
 use crate::lang::func::{BasicBlock, BlockRef, Fn, FnAttrib, FnRef};
-use crate::lang::inst::{BinOp, Inst, PhiSrc, UnOp};
+use crate::lang::inst::{BinOp, CastOp, Inst, InstRef, MemOrd, PhiSrc, RmwOp, UnOp};
+use crate::lang::meta::{Metadata, MetaVal};
 use crate::lang::Program;
 use crate::lang::ssa::Verifier;
 use crate::lang::util::ExtRc;
-use crate::lang::value::{Const, GlobalVar, Scope, Symbol, SymbolRef, Type, Typed, Value};
+use crate::lang::value::{Const, GlobalVar, Linkage, Scope, Symbol, SymbolRef, Type, Typed, Value};
 
 pub struct Builder {
     root: Term,
+    /// Whether to fold constant operations while building
+    fold: bool,
+    /// Whether to recover from semantic errors and continue building
+    recover: bool,
+    /// Errors found in error-recovery mode
+    err: RefCell<Vec<CompileErr>>,
 }
 
 struct Context {
@@ -25,31 +32,89 @@ struct Context {
 }
 
 impl Builder {
-    pub fn new(root: Term) -> Builder { Builder { root } }
+    pub fn new(root: Term) -> Builder {
+        Builder { root, fold: false, recover: false, err: Default::default() }
+    }
+
+    /// Set whether unary and binary operations with all constant operands are folded into
+    /// `mov` instructions.
+    pub fn fold_const(mut self, fold: bool) -> Builder {
+        self.fold = fold;
+        self
+    }
 
     /// Build program from passed syntax tree. Semantic analysis is also performed.
-    pub fn build(self) -> Result<Program, CompileErr> {
+    pub fn build(self) -> Result<Program, CompileErr> { self.build_prog() }
+
+    /// Build program in error-recovery mode. If an error is found in an instruction, the
+    /// instruction is skipped. If it is found in a top level definition, the definition is
+    /// skipped. `Err(v)` contains all the errors found in the program.
+    pub fn build_all(mut self) -> Result<Program, Vec<CompileErr>> {
+        self.recover = true;
+        let res = self.build_prog();
+        let mut err = self.err.into_inner();
+        match res {
+            Ok(pro) if err.is_empty() => Ok(pro),
+            Ok(_) => Err(err),
+            Err(e) => {
+                err.push(e);
+                Err(err)
+            }
+        }
+    }
+
+    /// Build the only function definition in the syntax tree, resolving global symbols in the
+    /// scope of `pro`. The function is not added to `pro`, and calls to itself still refer to
+    /// the function of the same name in `pro`, if there is one. See `Program::replace_fn` for
+    /// putting it in place.
+    pub fn build_fn(self, pro: &Program) -> Result<FnRef, CompileErr> {
+        let def = if let Term::Program { def } = &self.root { def } else { unreachable!() };
+        let (attrib, linkage, sig, meta, body) = match def.as_slice() {
+            [Term::FnDef { loc: _, attrib, linkage, sig, meta, body }] =>
+                (attrib, linkage, sig, meta, body),
+            _ => panic!("expect exactly one function definition")
+        };
+        let func = ExtRc::new(self.build_fn_sig(sig, attrib.as_ref(), &pro.global)?);
+        func.meta.replace(self.create_meta(meta)?);
+        func.linkage.set(self.create_linkage(linkage));
+        let blocks = match body.deref() {
+            Term::FnBody { loc: _, bb } => bb,
+            _ => unreachable!()
+        };
+        self.build_body(blocks, func.clone(), pro.global.clone())?;
+        Ok(func)
+    }
+
+    /// Record error `e` and return `Ok` in error-recovery mode. Otherwise, return `Err(e)`.
+    fn record(&self, e: CompileErr) -> Result<(), CompileErr> {
+        if !self.recover { return Err(e); }
+        self.err.borrow_mut().push(e);
+        Ok(())
+    }
+
+    fn build_prog(&self) -> Result<Program, CompileErr> {
         // Build top level scope
         let mut pro = Program {
             vars: vec![],
             func: vec![],
             global: Rc::new(Scope::new()),
+            layout: Default::default(),
         };
         let bodies = self.build_top_level(&mut pro)?;
 
         // Build basic blocks in each function
-        for (i, func) in pro.func.iter().enumerate() {
-            let blocks = match bodies[i] {
+        for (func, body) in bodies {
+            let blocks = match body {
                 Term::FnBody { loc: _, bb } => bb,
                 _ => unreachable!()
             };
-            self.build_body(blocks, func.clone(), pro.global.clone())?;
+            if let Err(e) = self.build_body(blocks, func, pro.global.clone()) { self.record(e)? }
         }
 
         Ok(pro)
     }
 
-    fn build_top_level(&self, pro: &mut Program) -> Result<Vec<&Term>, CompileErr> {
+    fn build_top_level(&self, pro: &mut Program) -> Result<Vec<(FnRef, &Term)>, CompileErr> {
         // Add type aliases to global scope
         let def = if let Term::Program { def } = &self.root { def } else { unreachable!() };
         for t in def {
@@ -62,70 +127,125 @@ impl Builder {
                     }
                 ));
                 if !added {
-                    return Err(CompileErr {
+                    self.record(CompileErr {
                         loc: loc.clone(),
-                        msg: format!("type {} already defined", name),
-                    });
+                        kind: ErrKind::Redefinition { what: "type", name: name.to_string() },
+                    })?
                 }
             }
         }
 
-        // Build global variables and function signatures
-        let mut bodies: Vec<&Term> = Vec::new();
+        // Resolve type aliases. Aliases are referred to by their symbols, so they can be
+        // defined recursively through pointers.
+        let mut bodies = Vec::new();
+        for t in def.iter().filter(|t| matches!(t, Term::AliasDef { loc: _, id: _, ty: _ })) {
+            if let Err(e) = self.build_def(t, pro, &mut bodies) { self.record(e)? }
+        }
+
+        // Reject aliases containing themselves by value. The type of such alias is reset, so
+        // that later uses do not resolve it infinitely.
         for t in def {
-            match t {
-                // Replace type alias symbol with real type
-                Term::AliasDef { loc: _, id: Token::GlobalId(_, id), ty: term } => {
-                    let name = self.trim_tag(id);
-                    match pro.global.find(name).unwrap().deref() {
-                        Symbol::Type { name: _, ty } => {
-                            ty.replace(self.create_type(term.deref(), &pro.global)?);
-                        }
-                        _ => unreachable!()
+            if let Term::AliasDef { loc, id: Token::GlobalId(_, id), ty: _ } = t {
+                let sym = pro.global.find(self.trim_tag(id)).unwrap();
+                if !Self::contains_alias(&sym.get_type(), &sym, &mut vec![]) { continue; }
+                if let Symbol::Type { name: _, ty } = sym.deref() { ty.replace(Type::Void); }
+                self.record(CompileErr {
+                    loc: loc.clone(),
+                    kind: ErrKind::RecursiveType(id.to_string()),
+                })?
+            }
+        }
+
+        // Build global variables and function signatures
+        for t in def.iter().filter(|t| !matches!(t, Term::AliasDef { loc: _, id: _, ty: _ })) {
+            if let Err(e) = self.build_def(t, pro, &mut bodies) { self.record(e)? }
+        }
+        Ok(bodies)
+    }
+
+    /// Whether type `ty` contains alias `sym` by value, that is, not through pointers.
+    /// Aliases in `visited` have been searched.
+    fn contains_alias(ty: &Type, sym: &SymbolRef, visited: &mut Vec<SymbolRef>) -> bool {
+        match ty {
+            Type::Alias(a) if a == sym => true,
+            Type::Alias(a) => {
+                if visited.contains(a) { return false; }
+                visited.push(a.clone());
+                Self::contains_alias(&a.get_type(), sym, visited)
+            }
+            Type::Array { elem, len: _ } => Self::contains_alias(elem, sym, visited),
+            Type::Struct { field } | Type::Union { field } =>
+                field.iter().any(|f| Self::contains_alias(f, sym, visited)),
+            _ => false
+        }
+    }
+
+    /// Build global variable or function signature. Function bodies are added to `bodies`.
+    fn build_def<'a>(&self, t: &'a Term, pro: &mut Program, bodies: &mut Vec<(FnRef, &'a Term)>)
+                     -> Result<(), CompileErr>
+    {
+        match t {
+            // Replace type alias symbol with real type
+            Term::AliasDef { loc: _, id: Token::GlobalId(_, id), ty: term } => {
+                let name = self.trim_tag(id);
+                match pro.global.find(name).unwrap().deref() {
+                    Symbol::Type { name: _, ty } => {
+                        ty.replace(self.create_type(term.deref(), &pro.global)?);
                     }
+                    _ => unreachable!()
                 }
-                // Create global variable, possibly with initial value
-                Term::VarDef { loc, id, init, ty } => {
-                    let var = ExtRc::new(self.build_global_var(id, ty, init, &pro.global)?);
-                    pro.vars.push(var.clone());
-                    let sym = ExtRc::new(Symbol::Global(var));
-                    let added = pro.global.insert(sym.clone());
-                    if !added {
-                        return Err(CompileErr {
-                            loc: loc.clone(),
-                            msg: format!("variable {} already defined", sym.name()),
-                        });
-                    }
+            }
+            // Create global variable, possibly with initial value
+            Term::VarDef { loc, linkage, is_const, id, init, ty } => {
+                let mut var = self.build_global_var(id, ty, init, *is_const, &pro.global)?;
+                var.linkage = self.create_linkage(linkage);
+                let var = ExtRc::new(var);
+                let sym = ExtRc::new(Symbol::Global(var.clone()));
+                let added = pro.global.insert(sym.clone());
+                if !added {
+                    return Err(CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::Redefinition {
+                            what: "variable",
+                            name: sym.name().to_string(),
+                        },
+                    });
                 }
-                // Create signature part for function, while its body are left empty for a later
-                // pass.
-                Term::FnDef { loc, attrib, sig, body } => {
-                    let func = ExtRc::new(self.build_fn_sig(sig, attrib.as_ref(), &pro.global)?);
-                    pro.func.push(func.clone());
-                    let sym = ExtRc::new(Symbol::Func(func));
-                    let added = pro.global.insert(sym.clone());
-                    if !added {
-                        return Err(CompileErr {
-                            loc: loc.clone(),
-                            msg: format!("function {} already defined", sym.name()),
-                        });
-                    }
-                    bodies.push(body.deref())
+                pro.vars.push(var);
+            }
+            // Create signature part for function, while its body are left empty for a later
+            // pass.
+            Term::FnDef { loc, attrib, linkage, sig, meta, body } => {
+                let func = ExtRc::new(self.build_fn_sig(sig, attrib.as_ref(), &pro.global)?);
+                func.meta.replace(self.create_meta(meta)?);
+                func.linkage.set(self.create_linkage(linkage));
+                let sym = ExtRc::new(Symbol::Func(func.clone()));
+                let added = pro.global.insert(sym.clone());
+                if !added {
+                    return Err(CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::Redefinition {
+                            what: "function",
+                            name: sym.name().to_string(),
+                        },
+                    });
                 }
-                _ => unreachable!()
+                pro.func.push(func.clone());
+                bodies.push((func, body.deref()))
             }
+            _ => unreachable!()
         }
-        Ok(bodies)
+        Ok(())
     }
 
-    fn build_global_var(&self, id: &Token, ty: &Term, init: &Option<Token>, global: &Rc<Scope>)
-                        -> Result<GlobalVar, CompileErr>
+    fn build_global_var(&self, id: &Token, ty: &Term, init: &Option<Token>, is_const: bool,
+                        global: &Rc<Scope>) -> Result<GlobalVar, CompileErr>
     {
         let ty = self.create_type(ty, global)?;
         if !ty.is_reg() {
             Err(CompileErr {
                 loc: id.loc(),
-                msg: format!("cannot create global variable of type {}", ty.to_string()),
+                kind: ErrKind::InvalidGlobal(ty.clone()),
             })?
         }
         let init = match init {
@@ -135,7 +255,15 @@ impl Builder {
         let name = if let Token::GlobalId(_, s) = id {
             self.trim_tag(s)
         } else { unreachable!() };
-        Ok(GlobalVar { name: name.to_string(), ty, init })
+        Ok(GlobalVar { name: name.to_string(), ty, init, is_const, linkage: Linkage::Internal })
+    }
+
+    /// Create linkage from optional token. Definitions are internal by default.
+    fn create_linkage(&self, tok: &Option<Token>) -> Linkage {
+        match tok {
+            Some(Token::Reserved(_, k)) => Linkage::from_str(k).unwrap(),
+            _ => Linkage::Internal
+        }
     }
 
     fn build_fn_sig(&self, sig: &Term, attrib: Option<&Box<Term>>, global: &Rc<Scope>)
@@ -151,13 +279,19 @@ impl Builder {
                             let a = FnAttrib::from_str(s.as_str()).map_err(|()| {
                                 CompileErr {
                                     loc: l.clone(),
-                                    msg: format!("invalid function attribute"),
+                                    kind: ErrKind::InvalidAttrib(s.clone()),
                                 }
                             })?;
                             if attrib.contains(&a) {
                                 Err(CompileErr {
                                     loc: l.clone(),
-                                    msg: format!("duplicated attribute {}", a.to_string()),
+                                    kind: ErrKind::DuplicatedAttrib(a.to_string()),
+                                })?
+                            }
+                            if let Some(b) = attrib.iter().find(|b| a.conflicts_with(b)) {
+                                Err(CompileErr {
+                                    loc: l.clone(),
+                                    kind: ErrKind::ConflictingAttrib(a.to_string(), b.to_string()),
                                 })?
                             }
                             attrib.push(a);
@@ -190,7 +324,10 @@ impl Builder {
                         if !added {
                             return Err(CompileErr {
                                 loc: loc.clone(),
-                                msg: format!("parameter {} already defined", sym.name()),
+                                kind: ErrKind::Redefinition {
+                                    what: "parameter",
+                                    name: sym.name().to_string(),
+                                },
                             });
                         }
                     } else { unreachable!() }
@@ -206,7 +343,8 @@ impl Builder {
             };
 
             // Check special function
-            self.check_special_fn(name, &plist, &ret, loc)?;
+            let entry = attrib.contains(&FnAttrib::Entry);
+            self.check_special_fn(name, entry, &plist, &ret, loc)?;
 
             // Return incomplete function object
             Ok(Fn::new(
@@ -220,26 +358,22 @@ impl Builder {
         } else { unreachable!() }
     }
 
-    fn check_special_fn(&self, name: &str, param: &Vec<RefCell<SymbolRef>>, ret: &Type, loc: &Loc)
-                        -> Result<(), CompileErr>
+    fn check_special_fn(&self, name: &str, entry: bool, param: &Vec<RefCell<SymbolRef>>,
+                        ret: &Type, loc: &Loc) -> Result<(), CompileErr>
     {
-        match name {
-            "main" => {
-                if !param.is_empty() {
-                    return Err(CompileErr {
-                        loc: loc.clone(),
-                        msg: format!("expect 0 parameter, got {}", param.len()),
-                    });
-                }
-                if *ret != Type::Void {
-                    return Err(CompileErr {
-                        loc: loc.clone(),
-                        msg: format!("expect void return type, got {}", ret.to_string()),
-                    });
-                }
-            }
-            _ => {}
-        }
+        if name != "main" && !entry { return Ok(()); }
+        let err = || CompileErr {
+            loc: loc.clone(),
+            kind: if name == "main" { ErrKind::InvalidMain } else {
+                ErrKind::InvalidEntry(name.to_string())
+            },
+        };
+
+        // Entry functions take either no parameter, or argument count and argument vector
+        let param: Vec<_> = param.iter().map(|p| p.borrow().get_type().orig()).collect();
+        let argv = Type::Ptr(Box::new(Type::Ptr(Box::new(Type::I(8)))));
+        if !param.is_empty() && param != [Type::I(64), argv] { return Err(err()); }
+        if *ret != Type::Void && ret.orig() != Type::I(64) { return Err(err()); }
         Ok(())
     }
 
@@ -250,11 +384,20 @@ impl Builder {
         let mut labels: HashMap<String, BlockRef> = HashMap::new();
         let mut blocks: Vec<(BlockRef, &Loc, &Vec<Term>)> = vec![];
         for i in 0..terms.len() {
-            if let Term::BlockDef { loc, id, instr } = &terms[i] {
+            if let Term::BlockDef { loc, id, meta, instr } = &terms[i] {
                 let name = if let Token::Label(_, s) = id {
                     self.trim_tag(s).to_string()
                 } else { unreachable!() };
                 let block = ExtRc::new(BasicBlock::new(name.clone()));
+                block.meta.replace(self.create_meta(meta)?);
+                block.loc.replace(Some(loc.clone()));
+                if labels.contains_key(&name) {
+                    self.record(CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::Redefinition { what: "block", name },
+                    })?;
+                    continue;
+                }
                 labels.insert(name, block.clone());
                 blocks.push((block.clone(), loc, instr));
                 if i == 0 { func.ent.replace(block); } // replace dummy entrance with real one
@@ -269,39 +412,37 @@ impl Builder {
             block: RefCell::new(func.ent.borrow().clone()),
         };
         let mut may_ssa = func.has_attrib(FnAttrib::Ssa);
+        let n_err = self.err.borrow().len();
         for (b, loc, terms) in blocks {
             let mut in_phis = true;
+            let mut failed = false;
             for t in terms {
-                // Build instruction
                 ctx.block.replace(b.clone());
-                let instr = ExtRc::new(self.build_instr(t, &ctx)?);
-
-                // Check SSA assumption
-                if !may_ssa { may_ssa = self.assume_ssa(&instr) }
-
-                // Check location of phi instruction
-                match instr.as_ref() {
-                    Inst::Phi { src: _, dst: _ } => if !in_phis {
-                        return Err(CompileErr {
-                            loc: loc.clone(),
-                            msg: format!(
-                                "non-phi instruction found before phi's in block {}", b.name
-                            ),
-                        });
+                match self.build_block_instr(t, &ctx, &mut in_phis) {
+                    Ok(instr) => for instr in instr {
+                        // Check SSA assumption
+                        if !may_ssa { may_ssa = self.assume_ssa(&instr) }
+                        b.push_back(instr);
                     }
-                    _ => in_phis = false
-                };
-                b.push_back(instr);
+                    Err(e) => {
+                        failed = true;
+                        self.record(e)?
+                    }
+                }
             }
-            // Check if the block is ended with control flow instruction
-            if !b.is_complete() {
-                return Err(CompileErr {
+            // Check if the block is ended with control flow instruction. If some instruction
+            // cannot be built, the block may also be incomplete, which is not reported again.
+            if !b.is_complete() && !failed {
+                self.record(CompileErr {
                     loc: loc.clone(),
-                    msg: format!("block {} is not complete", b.name),
-                });
+                    kind: ErrKind::IncompleteBlock(b.name.clone()),
+                })?
             }
         }
 
+        // The function is not further analyzed if there are errors in its instructions
+        if self.err.borrow().len() > n_err { return Ok(()); }
+
         // Build dominator tree of blocks
         func.build_dom();
 
@@ -309,10 +450,10 @@ impl Builder {
         if may_ssa {
             let mut ver = Verifier::new();
             func.walk_dom(&mut ver);
-            if !ver.err.is_empty() {
+            if let Some(e) = ver.err.first() {
                 Err(CompileErr {
-                    loc: Loc { line: 0, col: 0 },
-                    msg: ver.err.first().unwrap().clone(),
+                    loc: e.loc.clone().unwrap_or(Loc { file: None, line: 0, col: 0 }),
+                    kind: ErrKind::NotSsa(Box::new(e.clone())),
                 })?
             }
         }
@@ -320,6 +461,207 @@ impl Builder {
         Ok(())
     }
 
+    /// Build instructions in current block of context `ctx`, along with their metadata and
+    /// source location. `in_phis` tells whether all the previous instructions are phi's. Some
+    /// instructions are expanded to several ones, see `build_expanded`.
+    fn build_block_instr(&self, t: &Term, ctx: &Context, in_phis: &mut bool)
+                         -> Result<Vec<InstRef>, CompileErr>
+    {
+        let (loc, meta) = match t {
+            Term::AssignInstr { loc, id: _, rhs: _, meta }
+            | Term::NonAssignInstr { loc, instr: _, meta } => (loc, self.create_meta(meta)?),
+            _ => unreachable!()
+        };
+        let instr = match self.build_expanded(t, ctx)? {
+            Some(instr) => instr,
+            None => vec![self.build_instr(t, ctx)?]
+        };
+
+        instr.into_iter().map(|mut instr| {
+            if self.fold { instr = instr.fold().unwrap_or(instr) }
+            let instr = ExtRc::new(instr);
+
+            // Check location of phi instruction
+            match instr.as_ref() {
+                Inst::Phi { src: _, dst: _ } => if !*in_phis {
+                    return Err(CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::PhiNotFirst(ctx.block.borrow().name.clone()),
+                    });
+                }
+                _ => *in_phis = false
+            };
+
+            ctx.func.inst_loc.borrow_mut().insert(instr.clone(), loc.clone());
+            if !meta.is_empty() {
+                ctx.func.inst_meta.borrow_mut().insert(instr.clone(), meta.clone());
+            }
+            Ok(instr)
+        }).collect()
+    }
+
+    /// Build instructions that are expanded to several ones: checked arithmetic assigning a
+    /// pair, and `ld` or `st` of an aggregate type. If `term` is not one of them, return `None`
+    /// and leave it to `build_instr`.
+    fn build_expanded(&self, term: &Term, ctx: &Context)
+                      -> Result<Option<Vec<Inst>>, CompileErr>
+    {
+        match term {
+            Term::AssignInstr { loc: _, id, rhs, meta: _ } if id.len() > 1 => match rhs.deref() {
+                Term::CommonRhs { loc, name: Token::Reserved(_, op), ty, opd } => {
+                    let ty = self.create_type(ty, &ctx.global)?;
+                    self.build_checked(id, &ty, op, opd, ctx, loc).map(Some)
+                }
+                _ => Ok(None)
+            }
+            Term::AssignInstr { loc: _, id, rhs, meta: _ } if id.len() == 1 => match rhs.deref() {
+                Term::CommonRhs { loc, name: Token::Reserved(_, op), ty, opd }
+                if op == "ld" && matches!(id[0], Token::LocalId(_, _)) => {
+                    let ty = self.create_type(ty, &ctx.global)?;
+                    if ty.is_reg() { return Ok(None); }
+                    self.build_agg_ld(&id[0], &ty, opd, ctx, loc).map(Some)
+                }
+                _ => Ok(None)
+            }
+            Term::NonAssignInstr { loc: _, instr, meta: _ } => match instr.deref() {
+                Term::StInstr { loc, ty, src: src @ Token::LocalId(_, _), dst } => {
+                    let ty = self.create_type(ty, &ctx.global)?;
+                    if ty.is_reg() { return Ok(None); }
+                    self.build_agg_st(&ty, src, dst, ctx, loc).map(Some)
+                }
+                _ => Ok(None)
+            }
+            _ => Ok(None)
+        }
+    }
+
+    /// Build `$v, $o <- op T $a, $b` where `op` is a checked arithmetic operator. The overflow
+    /// is checked first, so that `$v` may also be an operand in non-SSA functions.
+    fn build_checked(&self, dst: &[Token], ty: &Type, op: &str, opd: &Term, ctx: &Context,
+                     loc: &Loc) -> Result<Vec<Inst>, CompileErr>
+    {
+        if dst.len() != 2 {
+            return Err(CompileErr {
+                loc: loc.clone(),
+                kind: ErrKind::ReturnCount { expect: 2, found: dst.len() },
+            });
+        }
+        if let Some(id) = dst.iter().find(|id| self.is_const_global(id, ctx)) {
+            return Err(CompileErr {
+                loc: id.loc(),
+                kind: ErrKind::ConstVar(id.to_string()),
+            });
+        }
+        let wrap = BinOp::from_str(op).unwrap().unchecked().unwrap().to_string();
+        Ok(vec![
+            self.build_op(&dst[1], ty, op, opd, ctx, loc)?,
+            self.build_op(&dst[0], ty, &wrap, opd, ctx, loc)?,
+        ])
+    }
+
+    /// Build `$s <- ld T $p` where `T` is an aggregate type. Aggregates cannot be stored in
+    /// virtual registers, so each scalar element of `T` is loaded to a local named after its
+    /// indices, such as `$s.1.0` for `[1, 0]`. Together they hold value of `$s`, which can then
+    /// be stored as a whole by `st T $s -> $q`.
+    fn build_agg_ld(&self, dst: &Token, ty: &Type, opd: &Term, ctx: &Context, loc: &Loc)
+                    -> Result<Vec<Inst>, CompileErr>
+    {
+        let ptr = self.build_opd_list(vec![Type::Ptr(Box::new(ty.clone()))], opd, ctx)?;
+        let mut instr = vec![];
+        for (idx, elem) in self.agg_elems(ty, "ld", loc)? {
+            let elem_ptr = self.create_elem_ptr(&ptr[0], &idx, &elem, dst, ctx, &mut instr);
+            let elem_dst = self.create_symbol(&Self::elem_tok(dst, &idx), &elem, ctx)?;
+            instr.push(Inst::Ld {
+                ptr: RefCell::new(elem_ptr),
+                dst: RefCell::new(elem_dst),
+            });
+        }
+        Ok(instr)
+    }
+
+    /// Build `st T $s -> $q` where `T` is an aggregate type, by storing each scalar element
+    /// loaded or defined by `build_agg_ld`.
+    fn build_agg_st(&self, ty: &Type, src: &Token, dst: &Token, ctx: &Context, loc: &Loc)
+                    -> Result<Vec<Inst>, CompileErr>
+    {
+        if self.is_const_global(dst, ctx) {
+            Err(CompileErr {
+                loc: dst.loc(),
+                kind: ErrKind::ConstVar(dst.to_string()),
+            })?
+        }
+        let ptr = self.create_value(&Type::Ptr(Box::new(ty.clone())), dst, ctx)?;
+        let mut instr = vec![];
+        for (idx, elem) in self.agg_elems(ty, "st", loc)? {
+            let elem_tok = Self::elem_tok(src, &idx);
+            let elem_src = self.find_symbol(&elem_tok, ctx)?;
+            self.check_type(&elem_src, &elem, &elem_tok.loc())?;
+            let elem_ptr = self.create_elem_ptr(&ptr, &idx, &elem, dst, ctx, &mut instr);
+            instr.push(Inst::St {
+                src: RefCell::new(Value::Var(elem_src)),
+                ptr: RefCell::new(elem_ptr),
+            });
+        }
+        Ok(instr)
+    }
+
+    /// Indices and types of scalar elements in aggregate type `ty`, in memory order. Unions
+    /// cannot be accessed element-wise, as their fields overlap.
+    fn agg_elems(&self, ty: &Type, op: &str, loc: &Loc)
+                 -> Result<Vec<(Vec<usize>, Type)>, CompileErr>
+    {
+        match ty.orig() {
+            ty if ty.is_reg() => Ok(vec![(vec![], ty)]),
+            Type::Array { elem, len } => {
+                let elem = self.agg_elems(&elem, op, loc)?;
+                Ok((0..len).flat_map(|i| elem.iter().map(move |(idx, ty)| {
+                    (std::iter::once(i).chain(idx.iter().copied()).collect(), ty.clone())
+                })).collect())
+            }
+            Type::Struct { field } => {
+                let mut elems = vec![];
+                for (i, f) in field.iter().enumerate() {
+                    elems.extend(self.agg_elems(f, op, loc)?.into_iter().map(|(idx, ty)| {
+                        (std::iter::once(i).chain(idx).collect(), ty)
+                    }));
+                }
+                Ok(elems)
+            }
+            ty => Err(CompileErr {
+                loc: loc.clone(),
+                kind: ErrKind::UnsupportedOp { op: op.to_string(), ty },
+            })
+        }
+    }
+
+    /// Compute pointer to element at `idx` of aggregate pointed to by `base`, pushing the
+    /// instruction to `instr`. The pointer is held in a fresh local named after `tok`.
+    fn create_elem_ptr(&self, base: &Value, idx: &[usize], elem: &Type, tok: &Token,
+                       ctx: &Context, instr: &mut Vec<Inst>) -> Value
+    {
+        let name = self.trim_tag(&tok.to_string()).to_string();
+        let name = (0..).map(|n| format!("{}.ptr.{}", name, n))
+            .find(|n| ctx.func.scope.find(n).is_none()).unwrap();
+        let dst = ExtRc::new(Symbol::Local { name, ty: Type::Ptr(Box::new(elem.clone())) });
+        let _ = ctx.func.scope.insert(dst.clone());
+        instr.push(Inst::Ptr {
+            base: RefCell::new(base.clone()),
+            off: None,
+            ind: idx.iter().map(|i| RefCell::new(Value::Const(Const::I64(*i as i64)))).collect(),
+            dst: RefCell::new(dst.clone()),
+        });
+        Value::Var(dst)
+    }
+
+    /// Token of the local holding element at `idx` of aggregate `tok`.
+    fn elem_tok(tok: &Token, idx: &[usize]) -> Token {
+        match tok {
+            Token::LocalId(l, s) => Token::LocalId(l.clone(), idx.iter()
+                .fold(s.clone(), |s, i| format!("{}.{}", s, i))),
+            _ => unreachable!()
+        }
+    }
+
     /// Make assumption about whether the instruction is in SSA form.
     /// Whether the function is really in SSA form remained to be verified.
     fn assume_ssa(&self, instr: &Inst) -> bool {
@@ -330,23 +672,51 @@ impl Builder {
 
     fn build_instr(&self, term: &Term, ctx: &Context) -> Result<Inst, CompileErr> {
         match term {
-            Term::AssignInstr { loc: _, id, rhs } => self.build_assign(id, rhs, ctx),
-            Term::NonAssignInstr { loc: _, instr } => self.build_non_assign(instr, ctx),
+            Term::AssignInstr { loc: _, id, rhs, meta: _ } if id.len() == 1 =>
+                self.build_assign(&id[0], rhs, ctx),
+            Term::AssignInstr { loc: _, id, rhs, meta: _ } => self.build_multi_assign(id, rhs, ctx),
+            Term::NonAssignInstr { loc: _, instr, meta: _ } => self.build_non_assign(instr, ctx),
             _ => unreachable!()
         }
     }
 
     fn build_assign(&self, dst: &Token, rhs: &Term, ctx: &Context) -> Result<Inst, CompileErr> {
         let dst_loc = dst.loc();
+        if self.is_const_global(dst, ctx) {
+            return Err(CompileErr {
+                loc: dst_loc.clone(),
+                kind: ErrKind::ConstVar(dst.to_string()),
+            });
+        }
         match rhs {
             Term::CommonRhs { loc, name: Token::Reserved(_, op), ty, opd } => {
                 let ty = self.create_type(ty, &ctx.global)?;
                 self.build_op(dst, &ty, op, opd, ctx, loc)
             }
-            Term::CallRhs { loc: _, ty, call } => {
+            Term::CastRhs { loc, name: Token::Reserved(_, op), ty, opd, tgt } => {
                 let ty = self.create_type(ty, &ctx.global)?;
+                let tgt = self.create_type(tgt, &ctx.global)?;
+                let op = CastOp::from_str(op).unwrap();
+                if !op.is_avail_for(&ty, &tgt) {
+                    return Err(CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::InvalidCast { op: op.to_string(), from: ty, to: tgt },
+                    });
+                }
+                let opd = self.create_def_val(&ty, opd, ctx)?;
+                let dst = self.create_symbol(dst, &tgt, ctx)?;
+                Ok(Inst::Cast { op, opd: RefCell::new(opd), dst: RefCell::new(dst) })
+            }
+            Term::CallRhs { loc, ty, call } => {
+                let ty = self.create_type(ty, &ctx.global)?;
+                if let Type::Tuple(elem) = &ty {
+                    return Err(CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::ReturnCount { expect: elem.len(), found: 1 },
+                    });
+                }
                 let dst = self.create_symbol(dst, &ty, ctx)?;
-                self.build_fn_call(call, Some(dst), ctx)
+                self.build_fn_call(call, vec![dst], ctx)
             }
             Term::PhiRhs { loc, ty, list } => {
                 let ty = self.create_type(ty, &ctx.global)?;
@@ -355,7 +725,7 @@ impl Builder {
                     if !dst.is_local_var() {
                         return Err(CompileErr {
                             loc: dst_loc.clone(),
-                            msg: format!("destination {} is not local variable", dst.name()),
+                            kind: ErrKind::NonLocalPhi(dst.name().to_string()),
                         });
                     }
                     self.build_phi_instr(&ty, dst, list, ctx, loc)
@@ -381,6 +751,46 @@ impl Builder {
                 };
                 Ok(Inst::New { dst: RefCell::new(dst), len })
             }
+            Term::AtomicRhs { loc, name: Token::Reserved(_, op), ord, ty, opd } => {
+                let ty = self.create_type(ty, &ctx.global)?;
+                let ptr_ty = Type::Ptr(Box::new(ty.clone()));
+                if op == "ld.atomic" {
+                    if !ty.is_reg() {
+                        Err(CompileErr {
+                            loc: loc.clone(),
+                            kind: ErrKind::UnsupportedOp { op: op.to_string(), ty: ty.clone() },
+                        })?
+                    }
+                    let ord = self.create_mem_ord(ord, op, MemOrd::is_avail_for_ld)?;
+                    let dst = self.create_symbol(dst, &ty, ctx)?;
+                    let opd = self.build_opd_list(vec![ptr_ty], opd, ctx)?;
+                    return Ok(Inst::AtomicLd {
+                        ord,
+                        ptr: RefCell::new(opd[0].clone()),
+                        dst: RefCell::new(dst),
+                    });
+                }
+                let rmw = RmwOp::from_str(op).map_err(|_| CompileErr {
+                    loc: loc.clone(),
+                    kind: ErrKind::UnknownOp(op.to_string()),
+                })?;
+                if !rmw.is_avail_for(&ty) {
+                    Err(CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::UnsupportedOp { op: op.to_string(), ty: ty.clone() },
+                    })?
+                }
+                let ord = self.create_mem_ord(ord, op, |_| true)?;
+                let dst = self.create_symbol(dst, &ty, ctx)?;
+                let opd = self.build_opd_list(vec![ptr_ty, ty], opd, ctx)?;
+                Ok(Inst::AtomicRmw {
+                    op: rmw,
+                    ord,
+                    ptr: RefCell::new(opd[0].clone()),
+                    val: RefCell::new(opd[1].clone()),
+                    dst: RefCell::new(dst),
+                })
+            }
             _ => unreachable!()
         }
     }
@@ -403,7 +813,7 @@ impl Builder {
                 }
                 n => return Err(CompileErr {
                     loc: loc.clone(),
-                    msg: format!("expect 1 or 2 operands, got {}", n),
+                    kind: ErrKind::OperandCount { min: 1, max: 2, found: n },
                 })
             }
         } else { unreachable!() }
@@ -413,7 +823,7 @@ impl Builder {
             Type::Ptr(tgt) => tgt.deref().clone(),
             ty => return Err(CompileErr {
                 loc: loc.clone(),
-                msg: format!("expect pointer type, got {}", ty.to_string()),
+                kind: ErrKind::NotPointer(ty),
             })
         };
         let idx = match idx {
@@ -439,8 +849,7 @@ impl Builder {
         if dst_ty != elem_ptr_ty {
             return Err(CompileErr {
                 loc: loc.clone(),
-                msg: format!("expect type {}, got {}", elem_ptr_ty.to_string(),
-                             dst_ty.to_string()),
+                kind: ErrKind::TypeMismatch { expect: elem_ptr_ty, found: dst_ty },
             });
         }
 
@@ -459,31 +868,31 @@ impl Builder {
                     if *c as usize >= len {
                         return Err(CompileErr {
                             loc: tok.loc(),
-                            msg: format!("index {} out of range {}", c, len),
+                            kind: ErrKind::IndexOutOfRange { index: *c, len },
                         });
                     }
                 }
                 Ok(elem.deref().clone())
             }
-            Type::Struct { field } => {
+            Type::Struct { field } | Type::Union { field } => {
                 if let Value::Const(Const::I64(c)) = val {
                     if *c as usize >= field.len() {
                         return Err(CompileErr {
                             loc: tok.loc(),
-                            msg: format!("index {} out of range {}", c, field.len()),
+                            kind: ErrKind::IndexOutOfRange { index: *c, len: field.len() },
                         });
                     }
                     Ok(field.get(*c as usize).unwrap().clone())
                 } else {
                     return Err(CompileErr {
                         loc: tok.loc(),
-                        msg: format!("index into structure type is not constant"),
+                        kind: ErrKind::NonConstIndex,
                     });
                 }
             }
             ty => Err(CompileErr {
                 loc: tok.loc(),
-                msg: format!("type {} is not aggregate", ty.to_string()),
+                kind: ErrKind::NotAggregate(ty),
             })
         }
     }
@@ -496,7 +905,7 @@ impl Builder {
                 if !ty.is_reg() {
                     Err(CompileErr {
                         loc: loc.clone(),
-                        msg: format!("cannot move value of type {}", ty.to_string()),
+                        kind: ErrKind::UnsupportedOp { op: op.to_string(), ty: ty.clone() },
                     })?
                 }
                 let dst = self.create_symbol(dst, ty, ctx)?;
@@ -507,7 +916,7 @@ impl Builder {
                 if !ty.is_reg() {
                     Err(CompileErr {
                         loc: loc.clone(),
-                        msg: format!("cannot load value of type {}", ty.to_string()),
+                        kind: ErrKind::UnsupportedOp { op: op.to_string(), ty: ty.clone() },
                     })?
                 }
                 let dst = self.create_symbol(dst, ty, ctx)?;
@@ -523,8 +932,7 @@ impl Builder {
                 if !op.is_avail_for(ty) {
                     return Err(CompileErr {
                         loc: loc.clone(),
-                        msg: format!("unary operation {} not supported for type {}",
-                                     op.to_string(), ty.to_string()),
+                        kind: ErrKind::UnsupportedOp { op: op.to_string(), ty: ty.clone() },
                     });
                 }
                 let opd = self.build_opd_list(vec![ty.clone()], opd, ctx)?;
@@ -539,11 +947,10 @@ impl Builder {
                 if !op.is_avail_for(ty) {
                     return Err(CompileErr {
                         loc: loc.clone(),
-                        msg: format!("binary operation {} not supported for type {}",
-                                     op.to_string(), ty.to_string()),
+                        kind: ErrKind::UnsupportedOp { op: op.to_string(), ty: ty.clone() },
                     });
                 }
-                let dst = if op.is_cmp() { // compare result is always `i1`
+                let dst = if op.is_pred() { // compare result is always `i1`
                     self.create_symbol(dst, &Type::I(1), ctx)?
                 } else {
                     self.create_symbol(dst, ty, ctx)?
@@ -558,59 +965,114 @@ impl Builder {
             }
             _ => Err(CompileErr {
                 loc: loc.clone(),
-                msg: format!("unknown operator {}", op),
+                kind: ErrKind::UnknownOp(op.to_string()),
             })
         }
     }
 
-    fn build_fn_call(&self, call: &Term, dst: Option<SymbolRef>, ctx: &Context)
-        -> Result<Inst, CompileErr>
+    /// Build call whose returned values are destructured to several symbols.
+    fn build_multi_assign(&self, dst: &[Token], rhs: &Term, ctx: &Context)
+                          -> Result<Inst, CompileErr>
     {
-        if let Term::FnCall { loc, func: Token::GlobalId(_, id), arg } = call {
-            // Find function definition from context
-            let fn_name = self.trim_tag(id);
-            if fn_name == "main" {
-                Err(CompileErr {
+        if let Term::CallRhs { loc, ty, call } = rhs {
+            let elem = self.create_type(ty, &ctx.global)?.ret_types();
+            if elem.len() != dst.len() {
+                return Err(CompileErr {
                     loc: loc.clone(),
-                    msg: format!("cannot call function @main"),
-                })?
+                    kind: ErrKind::ReturnCount { expect: elem.len(), found: dst.len() },
+                });
             }
-            let fn_sym = ctx.global.find(fn_name).ok_or(
-                CompileErr {
-                    loc: loc.clone(),
-                    msg: format!("function {} not found", fn_name),
+            let mut sym = vec![];
+            for (id, ty) in dst.iter().zip(elem.iter()) {
+                if self.is_const_global(id, ctx) {
+                    return Err(CompileErr {
+                        loc: id.loc().clone(),
+                        kind: ErrKind::ConstVar(id.to_string()),
+                    });
                 }
-            )?;
-            let func = if let Symbol::Func(func) = fn_sym.deref() { func } else {
-                return Err(CompileErr {
+                sym.push(self.create_symbol(id, ty, ctx)?);
+            }
+            self.build_fn_call(call, sym, ctx)
+        } else { unreachable!() }
+    }
+
+    fn build_fn_call(&self, call: &Term, dst: Vec<SymbolRef>, ctx: &Context)
+        -> Result<Inst, CompileErr>
+    {
+        if let Term::FnCall { loc, func, arg } = call {
+            // Find function definition from context
+            let fn_sym = match func {
+                Token::GlobalId(_, id) => {
+                    let fn_name = self.trim_tag(id);
+                    if fn_name == "main" {
+                        Err(CompileErr {
+                            loc: loc.clone(),
+                            kind: ErrKind::CallMain,
+                        })?
+                    }
+                    ctx.global.find(fn_name).ok_or(
+                        CompileErr {
+                            loc: loc.clone(),
+                            kind: ErrKind::UndefinedFn(fn_name.to_string()),
+                        }
+                    )?
+                }
+                _ => self.find_symbol(func, ctx)?
+            };
+
+            // Get signature of the callee. Functions are called directly, and variables of
+            // function pointer type are called indirectly.
+            let (param_ty, ret) = match fn_sym.deref() {
+                Symbol::Func(func) => (
+                    func.param.iter().map(|p| p.borrow().get_type()).collect(),
+                    func.ret.clone()
+                ),
+                sym => sym.get_type().fn_sig().ok_or(CompileErr {
                     loc: loc.clone(),
-                    msg: format!("symbol {} is not a function", fn_sym.name()),
-                });
+                    kind: ErrKind::NotFn(fn_sym.name().to_string()),
+                })?
             };
 
             // Check argument type
-            let param_ty = func.param.iter().map(|p| p.borrow().get_type()).collect();
             let arg = self.build_opd_list(param_ty, arg, ctx)?
                 .into_iter().map(|a| RefCell::new(a)).collect();
 
-            // Check return type, if necessary
-            let dst = match dst {
-                Some(sym) => {
-                    let tgt_ty = sym.get_type();
-                    if tgt_ty != func.ret {
+            // Check return type, if necessary. Don't care its type, if returned values are not
+            // assigned.
+            if dst.len() == 1 && dst[0].get_type() != ret {
+                return Err(CompileErr {
+                    loc: loc.clone(),
+                    kind: ErrKind::TypeMismatch { expect: dst[0].get_type(), found: ret },
+                });
+            }
+            if dst.len() > 1 {
+                let ret = ret.ret_types();
+                if ret.len() != dst.len() {
+                    return Err(CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::ReturnCount { expect: ret.len(), found: dst.len() },
+                    });
+                }
+                for (sym, ty) in dst.iter().zip(ret) {
+                    if sym.get_type() != ty {
                         return Err(CompileErr {
                             loc: loc.clone(),
-                            msg: format!("expect type {}, got {}", tgt_ty.to_string(),
-                                         func.ret.to_string()),
+                            kind: ErrKind::TypeMismatch { expect: sym.get_type(), found: ty },
                         });
                     }
-                    Some(RefCell::new(sym))
                 }
-                None => None // Don't care its type, if returned value is not assigned.
-            };
+            }
+            let dst = dst.into_iter().map(RefCell::new).collect();
 
             // Build instruction
-            Ok(Inst::Call { func: func.clone(), arg, dst })
+            match fn_sym.deref() {
+                Symbol::Func(func) => Ok(Inst::Call { func: func.clone(), arg, dst }),
+                _ => Ok(Inst::CallInd {
+                    func_ptr: RefCell::new(Value::Var(fn_sym.clone())),
+                    arg,
+                    dst,
+                })
+            }
         } else { unreachable!() }
     }
 
@@ -621,9 +1083,7 @@ impl Builder {
         if dst.is_global_var() {
             return Err(CompileErr {
                 loc: loc.clone(),
-                msg: format!(
-                    "global variable cannot be used as destination of phi instruction",
-                ),
+                kind: ErrKind::NonLocalPhi(dst.name().to_string()),
             });
         }
 
@@ -637,9 +1097,7 @@ impl Builder {
                 if val.is_global_var() {
                     return Err(CompileErr {
                         loc: opd.loc(),
-                        msg: format!(
-                            "global variable cannot be used as source of phi instruction"
-                        ),
+                        kind: ErrKind::NonLocalPhi(opd.to_string()),
                     });
                 }
 
@@ -649,7 +1107,7 @@ impl Builder {
                     ctx.labels.get(self.trim_tag(s)).cloned().ok_or(
                         CompileErr {
                             loc: loc.clone(),
-                            msg: format!("label {} not found", s),
+                            kind: ErrKind::UndefinedLabel(s.to_string()),
                         }
                     )?
                 } else { unreachable!() };
@@ -668,7 +1126,7 @@ impl Builder {
             if ty.len() != list.len() {
                 return Err(CompileErr {
                     loc: loc.clone(),
-                    msg: format!("expect {} operand(s), got {}", ty.len(), list.len()),
+                    kind: ErrKind::OperandCount { min: ty.len(), max: ty.len(), found: list.len() },
                 });
             }
             for (ty, opd) in ty.iter().zip(list.iter()) {
@@ -705,27 +1163,24 @@ impl Builder {
         match term {
             Term::RetInstr { loc, opd } => {
                 ctx.func.exit.borrow_mut().push(ctx.block.borrow().clone());
-                match &ctx.func.ret {
-                    Type::Void => if opd.is_none() {
-                        Ok(Inst::Ret { val: None })
-                    } else {
-                        Err(CompileErr {
-                            loc: loc.clone(),
-                            msg: format!("expect void, got value"),
-                        })
+                let ret = ctx.func.ret.ret_types();
+                let kind = match (ret.len(), opd.len()) {
+                    (0, n) if n > 0 => ErrKind::ReturnMismatch(Type::Void),
+                    (_, 0) if !ret.is_empty() => ErrKind::ReturnMismatch(ctx.func.ret.clone()),
+                    (m, n) if m != n => ErrKind::ReturnCount { expect: m, found: n },
+                    _ => {
+                        let val = ret.iter().zip(opd.iter())
+                            .map(|(ty, tok)| self.create_def_val(ty, tok, ctx).map(RefCell::new))
+                            .collect::<Result<_, _>>()?;
+                        return Ok(Inst::Ret { val });
                     }
-                    ty => if opd.is_some() {
-                        let ret = self.create_def_val(ty, opd.as_ref().unwrap(), ctx)?;
-                        Ok(Inst::Ret { val: Some(RefCell::new(ret)) })
-                    } else {
-                        Err(CompileErr {
-                            loc: loc.clone(),
-                            msg: format!("expect value, got void"),
-                        })
-                    }
-                }
+                };
+                Err(CompileErr { loc: loc.clone(), kind })
             }
-            Term::NoRetCall { loc: _, call } => self.build_fn_call(call, None, ctx),
+            Term::NoRetCall { loc: _, call } => self.build_fn_call(call, vec![], ctx),
+            Term::UnreachableInstr { loc: _ } => Ok(Inst::Unreachable),
+            Term::AbortInstr { loc: _, msg: Token::Str(_, s) } =>
+                Ok(Inst::Abort { msg: s[1..s.len() - 1].to_string() }),
             Term::JmpInstr { loc: _, tgt: Token::Label(loc, tgt) } => {
                 let tgt = self.trim_tag(tgt);
                 match ctx.labels.get(tgt) {
@@ -734,14 +1189,14 @@ impl Builder {
                         if tgt == ctx.func.ent.borrow().deref() {
                             Err(CompileErr {
                                 loc: loc.clone(),
-                                msg: format!("cannot jump to function entry {:?}", tgt.name),
+                                kind: ErrKind::JumpToEntry(tgt.name.clone()),
                             })?
                         }
                         Ok(Inst::Jmp { tgt: RefCell::new(tgt.clone()) })
                     }
                     None => Err(CompileErr {
                         loc: loc.clone(),
-                        msg: format!("label {} not found", tgt),
+                        kind: ErrKind::UndefinedLabel(tgt.to_string()),
                     })
                 }
             }
@@ -754,14 +1209,14 @@ impl Builder {
                 let tr = ctx.labels.get(t_lab).ok_or(
                     CompileErr {
                         loc: t_loc.clone(),
-                        msg: format!("label {} not found", t_lab),
+                        kind: ErrKind::UndefinedLabel(t_lab.to_string()),
                     }
                 )?;
                 let f_lab = self.trim_tag(f_lab);
                 let fls = ctx.labels.get(f_lab).ok_or(
                     CompileErr {
                         loc: f_loc.clone(),
-                        msg: format!("label {} not found", f_lab),
+                        kind: ErrKind::UndefinedLabel(f_lab.to_string()),
                     }
                 )?;
                 ctx.block.borrow().connect(tr.clone());
@@ -777,7 +1232,14 @@ impl Builder {
                 if !ty.is_reg() {
                     Err(CompileErr {
                         loc: loc.clone(),
-                        msg: format!("cannot store value of type {}", ty.to_string()),
+                        kind: ErrKind::UnsupportedOp { op: "st".to_string(), ty: ty.clone() },
+                    })?
+                }
+                // Constant pointer variables can only be null
+                if self.is_const_global(dst, ctx) {
+                    Err(CompileErr {
+                        loc: dst.loc(),
+                        kind: ErrKind::ConstVar(dst.to_string()),
                     })?
                 }
                 let src = self.create_def_val(&ty, src, ctx)?;
@@ -787,10 +1249,73 @@ impl Builder {
                     ptr: RefCell::new(dst),
                 })
             }
+            Term::MemInstr { loc, op, ty, src, dst, len } => {
+                let ty = self.create_type(ty.deref(), &ctx.global)?;
+                if !ty.is_reg() {
+                    Err(CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::UnsupportedOp { op: op.to_string(), ty: ty.clone() },
+                    })?
+                }
+                if self.is_const_global(dst, ctx) {
+                    Err(CompileErr {
+                        loc: dst.loc(),
+                        kind: ErrKind::ConstVar(dst.to_string()),
+                    })?
+                }
+                let ptr_ty = Type::Ptr(Box::new(ty.clone()));
+                let src = match op.to_string().as_str() {
+                    "memcpy" => self.create_def_val(&ptr_ty, src, ctx)?,
+                    _ => self.create_def_val(&ty, src, ctx)?
+                };
+                let dst = self.create_value(&ptr_ty, dst, ctx)?;
+                let len = self.create_def_val(&Type::I(64), len, ctx)?;
+                let (src, ptr, len) = (RefCell::new(src), RefCell::new(dst), RefCell::new(len));
+                Ok(match op.to_string().as_str() {
+                    "memcpy" => Inst::Memcpy { src, ptr, len },
+                    _ => Inst::Memset { src, ptr, len }
+                })
+            }
+            Term::AtomicStInstr { loc, ord, ty, src, dst } => {
+                let ty = self.create_type(ty.deref(), &ctx.global)?;
+                if !ty.is_reg() {
+                    Err(CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::UnsupportedOp {
+                            op: "st.atomic".to_string(),
+                            ty: ty.clone(),
+                        },
+                    })?
+                }
+                if self.is_const_global(dst, ctx) {
+                    Err(CompileErr {
+                        loc: dst.loc(),
+                        kind: ErrKind::ConstVar(dst.to_string()),
+                    })?
+                }
+                let ord = self.create_mem_ord(ord, "st.atomic", MemOrd::is_avail_for_st)?;
+                let src = self.create_def_val(&ty, src, ctx)?;
+                let dst = self.create_value(&Type::Ptr(Box::new(ty.clone())), dst, ctx)?;
+                Ok(Inst::AtomicSt { ord, src: RefCell::new(src), ptr: RefCell::new(dst) })
+            }
             _ => unreachable!()
         }
     }
 
+    /// Create memory ordering of atomic operation `op` from token `ord`, which should be
+    /// accepted by `avail`.
+    fn create_mem_ord(&self, ord: &Token, op: &str, avail: impl std::ops::Fn(&MemOrd) -> bool)
+                      -> Result<MemOrd, CompileErr>
+    {
+        match MemOrd::from_str(&ord.to_string()) {
+            Ok(o) if avail(&o) => Ok(o),
+            _ => Err(CompileErr {
+                loc: ord.loc(),
+                kind: ErrKind::InvalidOrdering { op: op.to_string(), ord: ord.to_string() },
+            })
+        }
+    }
+
     /// This method use token `tok` to decide where to find symbol. If the symbol can be found,
     /// it checks whether it is of type `ty`. Otherwise, it create a new symbol in local scope of
     /// type `ty`.
@@ -805,7 +1330,7 @@ impl Builder {
                 }
                 None => Err(CompileErr {
                     loc: l.clone(),
-                    msg: format!("identifier {} not found in global scope", s),
+                    kind: ErrKind::UndefinedSymbol { name: s.to_string(), global: true },
                 })
             }
             Token::LocalId(l, s) => match ctx.func.scope.find(self.trim_tag(s)) {
@@ -823,6 +1348,22 @@ impl Builder {
         }
     }
 
+    /// Whether the token refers to a constant global variable.
+    fn is_const_global(&self, tok: &Token, ctx: &Context) -> bool {
+        match tok {
+            Token::GlobalId(_, s) => match ctx.global.find(self.trim_tag(s)) {
+                Some(sym) => match sym.deref() {
+                    Symbol::Global(g) => g.is_const,
+                    // Code of a function cannot be written
+                    Symbol::Func(_) => true,
+                    _ => false
+                }
+                None => false
+            }
+            _ => false
+        }
+    }
+
     /// This method find symbol with given token `tok`. If the symbol is not found, it returns an
     /// error.
     fn find_symbol(&self, tok: &Token, ctx: &Context) -> Result<SymbolRef, CompileErr> {
@@ -830,13 +1371,13 @@ impl Builder {
             Token::GlobalId(l, s) => ctx.global.find(self.trim_tag(s)).ok_or(
                 CompileErr {
                     loc: l.clone(),
-                    msg: format!("identifier {} not found in global scope", s),
+                    kind: ErrKind::UndefinedSymbol { name: s.to_string(), global: true },
                 }
             ),
             Token::LocalId(l, s) => ctx.func.scope.find(self.trim_tag(s)).ok_or(
                 CompileErr {
                     loc: l.clone(),
-                    msg: format!("identifier {} not found in local scope", s),
+                    kind: ErrKind::UndefinedSymbol { name: s.to_string(), global: false },
                 }
             ),
             _ => unreachable!()
@@ -848,8 +1389,7 @@ impl Builder {
         if ty != &sym_ty {
             Err(CompileErr {
                 loc: loc.clone(),
-                msg: format!("expect symbol of type {}, found {}", ty.to_string(),
-                             sym_ty.to_string()),
+                kind: ErrKind::TypeMismatch { expect: ty.clone(), found: sym_ty },
             })
         } else { Ok(()) }
     }
@@ -858,11 +1398,35 @@ impl Builder {
         if let Token::Integer(l, i) = tok {
             Const::from_str(i, ty).ok_or_else(|| CompileErr {
                 loc: l.clone(),
-                msg: format!("cannot create constant {} of type {}", i, ty.to_string()),
+                kind: ErrKind::InvalidConst { val: i.to_string(), ty: ty.clone() },
             })
         } else { unreachable!() }
     }
 
+    fn create_meta(&self, term: &Term) -> Result<Metadata, CompileErr> {
+        let mut meta = Metadata::new();
+        if let Term::MetaList { loc: _, list } = term {
+            for (key, val) in list {
+                let val = match val {
+                    Token::Integer(l, i) => MetaVal::Int(i.parse().map_err(|_| CompileErr {
+                        loc: l.clone(),
+                        kind: ErrKind::MetaOutOfRange(i.to_string()),
+                    })?),
+                    Token::Str(_, s) => MetaVal::Str(s[1..s.len() - 1].to_string()),
+                    _ => unreachable!()
+                };
+                let name = self.trim_tag(&key.to_string()).to_string();
+                if meta.insert(name.clone(), val).is_some() {
+                    return Err(CompileErr {
+                        loc: key.loc(),
+                        kind: ErrKind::DuplicatedMeta(name),
+                    });
+                }
+            }
+        } else { unreachable!() }
+        Ok(meta)
+    }
+
     fn create_local(&self, s: &str, ty: Type) -> Result<Symbol, CompileErr> {
         let name = self.trim_tag(s); // trim local tag
         Ok(Symbol::Local { name: name.to_string(), ty })
@@ -872,7 +1436,10 @@ impl Builder {
         if let Term::TypeDecl { loc: _, ty } = term {
             match ty.deref() {
                 Term::PrimType { loc, ty: Token::Reserved(_, s) } =>
-                    Type::from_str(s).map_err(|e| CompileErr { loc: loc.clone(), msg: e }),
+                    Type::from_str(s).map_err(|_| CompileErr {
+                        loc: loc.clone(),
+                        kind: ErrKind::InvalidType(s.to_string()),
+                    }),
                 Term::AliasName { loc, id: Token::GlobalId(_, id) } => {
                     let name = self.trim_tag(id);
                     match global.find(name) {
@@ -880,12 +1447,12 @@ impl Builder {
                             Symbol::Type { name: _, ty: _ } => Ok(Type::Alias(sym.clone())),
                             _ => return Err(CompileErr {
                                 loc: loc.clone(),
-                                msg: format!("{} is not a type", name),
+                                kind: ErrKind::NotType(name.to_string()),
                             })
                         },
                         None => return Err(CompileErr {
                             loc: loc.clone(),
-                            msg: format!("type {} not found", name),
+                            kind: ErrKind::UndefinedType(name.to_string()),
                         })
                     }
                 }
@@ -906,6 +1473,31 @@ impl Builder {
                     } else { unreachable!() }
                     Ok(Type::Struct { field: v })
                 }
+                Term::UnionType { loc: _, field } => {
+                    let mut v = vec![];
+                    if let Term::TypeList { loc: _, list } = field.deref() {
+                        for t in list.deref() {
+                            v.push(self.create_type(t, global)?)
+                        }
+                    } else { unreachable!() }
+                    Ok(Type::Union { field: v })
+                }
+                Term::FnType { loc: _, param, ret } => {
+                    let param = param.iter().map(|t| self.create_type(t, global))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let ret = match ret {
+                        Some(r) => match r.deref() {
+                            Term::FnRet { loc: _, ty } => self.create_type(ty, global)?,
+                            _ => unreachable!()
+                        }
+                        None => Type::Void
+                    };
+                    Ok(Type::Fn { param, ret: Box::new(ret) })
+                }
+                Term::TupleType { loc: _, elem } => Ok(Type::Tuple(
+                    elem.iter().map(|t| self.create_type(t, global))
+                        .collect::<Result<Vec<_>, _>>()?
+                )),
                 _ => unreachable!()
             }
         } else { unreachable!() }
@@ -913,8 +1505,111 @@ impl Builder {
 
     fn trim_tag<'a>(&self, s: &'a str) -> &'a str {
         match s.split_at(1).0 {
-            "@" | "$" | "%" => s.split_at(1).1,
+            "@" | "$" | "%" | "!" => s.split_at(1).1,
             _ => s
         }
     }
 }
+
+#[test]
+fn test_build_err() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+
+    let build = |src: &str| {
+        let tree = Parser::new(Lexer::from(src)).parse().unwrap();
+        let err = Builder::new(tree).build().err().unwrap();
+        println!("{}", err);
+        err
+    };
+    let err = build("@g: i32\n@g: i64\nfn @main() {\n%B:\n    ret\n}");
+    assert!(matches!(err.kind(), ErrKind::Redefinition { what: "variable", .. }));
+    assert_eq!(err.loc().line(), 1);
+    let err = build("fn @main() {\n%B:\n    $x <- add i32 $y, 1\n    ret\n}");
+    assert!(matches!(err.kind(), ErrKind::UndefinedSymbol { global: false, .. }));
+    let err = build("fn @main() {\n%B:\n    $x <- mov i32 0\n    $y <- mov i64 $x\n    ret\n}");
+    assert!(matches!(err.kind(),
+                     ErrKind::TypeMismatch { expect: Type::I(64), found: Type::I(32) }));
+    let err = build("fn @main() {\n%B:\n    $x <- mov i32 0\n}");
+    assert!(matches!(err.kind(), ErrKind::IncompleteBlock(b) if b == "B"));
+    let err = build("fn @main() {\n%B:\n    jmp %B\n%B:\n    ret\n}");
+    assert!(matches!(err.kind(), ErrKind::Redefinition { what: "block", .. }));
+    assert_eq!(err.loc().line(), 3);
+
+    let err = build("fn @f($x: i8) {\n%B:\n    $v, $o, $p <- add.ov i8 $x, 1\n    ret\n}");
+    assert!(matches!(err.kind(), ErrKind::ReturnCount { expect: 2, found: 3 }));
+
+    // Memory ordering must be allowed for the atomic operation
+    let err = build("fn @f($p: *i64) {\n%B:\n    $x <- ld.atomic release i64 $p\n    ret\n}");
+    assert!(matches!(err.kind(), ErrKind::InvalidOrdering { ord, .. } if ord == "release"));
+    let err = build("fn @f($p: *i64) {\n%B:\n    st.atomic seq i64 1 -> $p\n    ret\n}");
+    assert!(matches!(err.kind(), ErrKind::InvalidOrdering { op, .. } if op == "st.atomic"));
+    let err = build("fn @f($p: **i8) {\n%B:\n    $x <- rmw.add relaxed *i8 $p, $p\n    ret\n}");
+    assert!(matches!(err.kind(), ErrKind::UnsupportedOp { .. }));
+
+    // Recursive types must be defined through pointers
+    let err = build("type @A = { i64, @B }\ntype @B = [2]@A\nfn @main() {\n%B:\n    ret\n}");
+    assert!(matches!(err.kind(), ErrKind::RecursiveType(t) if t == "@A"));
+    let err = build("type @A = @A\n@g: @A\nfn @main() {\n%B:\n    ret\n}");
+    assert!(matches!(err.kind(), ErrKind::RecursiveType(_)));
+    let src = "type @A = { i64, *@B }\ntype @B = union { *@A, [2]@A }\nfn @main() {\n%B:\n    \
+               $a <- alloc @A\n    ret\n}";
+    assert!(Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().is_ok());
+
+    // Report all errors in error-recovery mode
+    let src = "@g: i32\n@g: i64\nfn @f($a: i32) -> i32 {\n%B:\n    $x <- add i32 $b, 1\n    \
+               $y <- mov i64 $a\n    ret $a\n}\nfn @main() {\n%B:\n    $z <- call i32 @h()\n    \
+               jmp %C\n}";
+    let tree = Parser::new(Lexer::from(src)).parse().unwrap();
+    let err = Builder::new(tree).build_all().err().unwrap();
+    for e in &err { println!("{}", e) }
+    let lines: Vec<_> = err.iter().map(|e| e.loc().line()).collect();
+    assert_eq!(lines, vec![1, 4, 5, 10, 11]);
+}
+
+#[test]
+fn test_agg_mem() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+type @P = { i64, [2]i32 }
+@r: i64 <- 0
+@s: i32 <- 0
+
+fn @main() {
+%Begin:
+    $a <- alloc @P
+    $b <- alloc @P
+    $v.0 <- mov i64 3
+    $v.1.0 <- mov i32 4
+    $v.1.1 <- mov i32 5
+    st @P $v -> $a
+    $w <- ld @P $a
+    st @P $w -> $b
+    $x <- ptr *i64 $b [0]
+    @r <- ld i64 $x
+    $y <- ptr *i32 $b [1, 1]
+    @s <- ld i32 $y
+    ret
+}
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let main = pro.func.iter().find(|f| f.name == "main").unwrap();
+    let n_inst: usize = main.dfs().map(|b| b.inst.borrow().len()).sum();
+    assert_eq!(n_inst, 2 + 3 + 6 * 3 + 4 + 1);
+    let mach = Machine::new().run(&pro).unwrap();
+    let global = format!("{:?}", mach.global);
+    assert!(global.contains("I64(3)") && global.contains("I32(5)"), "{}", global);
+
+    // Elements of the aggregate must be defined, and unions cannot be copied element-wise
+    let build = |src: &str| Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build()
+        .err().unwrap();
+    let err = build("fn @f($p: *{ i64, i8 }) {\n%B:\n    $v.0 <- mov i64 0\n    \
+                     st { i64, i8 } $v -> $p\n    ret\n}");
+    assert!(matches!(err.kind(), ErrKind::UndefinedSymbol { name, .. } if name == "$v.1"));
+    let err = build("fn @f($p: *union { i64, i8 }) {\n%B:\n    $v <- ld union { i64, i8 } $p\n    \
+                     ret\n}");
+    assert!(matches!(err.kind(), ErrKind::UnsupportedOp { op, .. } if op == "ld"));
+}
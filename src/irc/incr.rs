@@ -0,0 +1,254 @@
+use std::ops::Range;
+
+use crate::irc::{CompileErr, Loc};
+use crate::irc::build::Builder;
+use crate::irc::lex::Lexer;
+use crate::irc::parse::Parser;
+use crate::irc::syntax::{Term, Token};
+use crate::lang::func::FnRef;
+use crate::lang::Program;
+
+/// Source text along with the program built from it, which is kept up to date as the text is
+/// edited, such as in a language server. An edit inside the body of a function only re-parses
+/// and re-builds that function, and the rest of the program is kept intact. Other edits, or
+/// ones that change the name or type of a function, cause the whole program to be rebuilt.
+pub struct Document {
+    /// Current source text
+    text: String,
+    /// Program built from current text, or `None` if the text has errors
+    pro: Option<Program>,
+    /// Top level definitions in current text, in order
+    items: Vec<Item>,
+}
+
+/// Top level definition in the text of a document
+struct Item {
+    /// Byte range of this definition, which extends to the beginning of the next one
+    span: Range<usize>,
+    /// Function built from this definition, if it is one
+    func: Option<FnRef>,
+}
+
+/// How a document is rebuilt after an edit
+#[derive(Debug)]
+pub enum Rebuilt {
+    /// Only this function is rebuilt, and it replaces the previous one in the program
+    Fn(FnRef),
+    /// The whole program is rebuilt
+    All,
+}
+
+impl Document {
+    /// Create a document of `text`. Call `rebuild` to build the program.
+    pub fn new(text: &str) -> Document {
+        Document { text: text.to_string(), pro: None, items: vec![] }
+    }
+
+    /// Current source text
+    pub fn text(&self) -> &str { &self.text }
+
+    /// Program built from current text, or `None` if it is not built, or the last build failed.
+    pub fn program(&self) -> Option<&Program> { self.pro.as_ref() }
+
+    /// Rebuild the whole program from current text.
+    pub fn rebuild(&mut self) -> Result<(), CompileErr> {
+        self.pro = None;
+        self.items.clear();
+        let tree = Parser::new(Lexer::from(self.text.as_str())).parse()?;
+        let def = match &tree {
+            Term::Program { def } => def.iter().map(|t| (Self::def_loc(t), Self::fn_name(t)))
+                .collect::<Vec<_>>(),
+            _ => unreachable!()
+        };
+        let pro = Builder::new(tree).build()?;
+        let start: Vec<usize> = def.iter().map(|(loc, _)| offset(&self.text, loc)).collect();
+        for (i, (_, name)) in def.into_iter().enumerate() {
+            let end = start.get(i + 1).cloned().unwrap_or(self.text.len());
+            let func = name.and_then(|n| pro.func.iter().find(|f| f.name == n).cloned());
+            self.items.push(Item { span: start[i]..end, func });
+        }
+        self.pro = Some(pro);
+        Ok(())
+    }
+
+    /// Replace text in byte range `range` with `new`, and rebuild the program. Returns what is
+    /// rebuilt, or the first error found. If the edit is inside a function definition, only that
+    /// function is rebuilt.
+    pub fn edit(&mut self, range: Range<usize>, new: &str) -> Result<Rebuilt, CompileErr> {
+        let old_lines = self.text[range.clone()].matches('\n').count() as isize;
+        self.text.replace_range(range.clone(), new);
+        let line_delta = new.matches('\n').count() as isize - old_lines;
+        match self.rebuild_fn(range, new.len(), line_delta) {
+            Some(res) => res.map(Rebuilt::Fn),
+            None => self.rebuild().map(|_| Rebuilt::All)
+        }
+    }
+
+    /// Try to rebuild only the function containing edited range `range`, which is replaced by
+    /// `len` bytes. Returns `None` if the edit cannot be handled this way.
+    fn rebuild_fn(&mut self, range: Range<usize>, len: usize, line_delta: isize)
+                  -> Option<Result<FnRef, CompileErr>>
+    {
+        self.pro.as_ref()?;
+        let idx = self.items.iter()
+            .position(|it| it.span.start < range.start && range.end <= it.span.end)?;
+        let old = self.items[idx].func.clone()?;
+        let start = self.items[idx].span.start;
+        let end = self.items[idx].span.end + len - range.len();
+
+        // Definitions after this one should start on later lines, so that their columns are not
+        // changed by the edit.
+        if idx + 1 < self.items.len() && !self.text[range.start + len..end].contains('\n') {
+            return None;
+        }
+
+        // Parse the definition alone, at its original location
+        let loc = location(&self.text, start);
+        let src = "\n".repeat(loc.line) + &" ".repeat(loc.col) + &self.text[start..end];
+        let tree = match Parser::new(Lexer::from(src.as_str())).parse() {
+            Ok(Term::Program { def }) if def.len() == 1 && Self::fn_name(&def[0]).is_some() =>
+                Term::Program { def },
+            Ok(_) => return None,
+            Err(e) => {
+                self.pro = None;
+                return Some(Err(e));
+            }
+        };
+
+        // Build the function and put it in place, if it can still be called the same way
+        let new = match Builder::new(tree).build_fn(self.pro.as_ref().unwrap()) {
+            Ok(new) => new,
+            Err(e) => {
+                self.pro = None;
+                return Some(Err(e));
+            }
+        };
+        if new.as_ref() != old.as_ref() { return None; }
+        self.pro.as_mut().unwrap().replace_fn(&old, new.clone());
+        let delta = end as isize - self.items[idx].span.end as isize;
+        self.items[idx] = Item { span: start..end, func: Some(new.clone()) };
+        for item in &mut self.items[idx + 1..] {
+            item.span = shift(item.span.start, delta)..shift(item.span.end, delta);
+            if let Some(func) = &item.func { shift_fn_loc(func, line_delta) }
+        }
+        Some(Ok(new))
+    }
+
+    fn def_loc(t: &Term) -> Loc {
+        match t {
+            Term::VarDef { loc, .. } | Term::AliasDef { loc, .. } | Term::FnDef { loc, .. } =>
+                loc.clone(),
+            _ => unreachable!()
+        }
+    }
+
+    fn fn_name(t: &Term) -> Option<String> {
+        match t {
+            Term::FnDef { sig, .. } => match sig.as_ref() {
+                Term::FnSig { id: Token::GlobalId(_, id), .. } =>
+                    Some(id.trim_start_matches('@').to_string()),
+                _ => unreachable!()
+            }
+            _ => None
+        }
+    }
+}
+
+fn shift(pos: usize, delta: isize) -> usize { (pos as isize + delta) as usize }
+
+/// Shift lines of source locations in `func` by `delta`.
+fn shift_fn_loc(func: &FnRef, delta: isize) {
+    if delta == 0 { return; }
+    let shift_loc = |loc: &mut Loc| loc.line = shift(loc.line, delta);
+    for block in func.dfs() {
+        if let Some(loc) = block.loc.borrow_mut().as_mut() { shift_loc(loc) }
+    }
+    func.inst_loc.borrow_mut().values_mut().for_each(shift_loc);
+}
+
+/// Byte offset of location `loc` in `text`. Columns are counted in characters.
+fn offset(text: &str, loc: &Loc) -> usize {
+    let start: usize = text.split_inclusive('\n').take(loc.line).map(|l| l.len()).sum();
+    text[start..].char_indices().nth(loc.col).map(|(i, _)| start + i).unwrap_or(text.len())
+}
+
+/// Location of byte offset `pos` in `text`.
+fn location(text: &str, pos: usize) -> Loc {
+    let before = &text[..pos];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    Loc {
+        file: None,
+        line: before.matches('\n').count(),
+        col: before[line_start..].chars().count(),
+    }
+}
+
+#[test]
+fn test_incr() {
+    use crate::lang::inst::Inst;
+    use crate::vm::exec::Machine;
+
+    let src = r#"@r: i64
+
+fn @main() {
+%Begin:
+    $a <- call i64 @f(3)
+    @r <- mov i64 $a
+    ret
+}
+
+fn @f($x: i64) -> i64 {
+%Begin:
+    $y <- mul i64 $x, 2
+    ret $y
+}
+
+fn @g() -> i64 {
+%Begin:
+    $z <- call i64 @f(1)
+    ret $z
+}
+"#;
+    let mut doc = Document::new(src);
+    doc.rebuild().unwrap();
+    let run = |doc: &Document| format!("{:?}", Machine::new().run(doc.program().unwrap())
+        .unwrap().global);
+    assert!(run(&doc).contains("I64(6)"));
+    let main = doc.program().unwrap().func[0].clone();
+    let g = doc.program().unwrap().func[2].clone();
+
+    // Edit the body of `@f`, which adds a line. Only `@f` is rebuilt, and its callers refer to
+    // the new one.
+    let pos = doc.text().find("mul i64 $x, 2").unwrap();
+    let f = match doc.edit(pos..pos + 13, "mul i64 $x, 4\n    $v <- add i64 $y, 0").unwrap() {
+        Rebuilt::Fn(f) => f,
+        res => panic!("expect function to be rebuilt, found {:?}", res)
+    };
+    let pro = doc.program().unwrap();
+    assert!(pro.func[0] == main && pro.func[1] == f && pro.func[2] == g);
+    assert!(matches!(main.ent.borrow().head().as_ref(), Inst::Call { func, .. } if *func == f));
+    assert!(run(&doc).contains("I64(12)"));
+
+    // Locations in the rebuilt function and the following ones follow the text
+    let line = |doc: &Document, pat: &str| {
+        doc.text()[..doc.text().find(pat).unwrap()].matches('\n').count()
+    };
+    let loc = |func: &FnRef, idx: usize| {
+        let instr = func.ent.borrow().inst.borrow()[idx].clone();
+        func.inst_loc(&instr).unwrap().line()
+    };
+    assert_eq!(loc(&f, 1), line(&doc, "$v <-"));
+    assert_eq!(loc(&g, 0), line(&doc, "$z <-"));
+
+    // Errors are reported, and the program is rebuilt after they are fixed
+    let pos = doc.text().find("$x, 4").unwrap();
+    assert!(doc.edit(pos..pos + 2, "$q").is_err());
+    assert!(doc.program().is_none());
+    assert!(matches!(doc.edit(pos..pos + 2, "$x"), Ok(Rebuilt::All)));
+    assert!(run(&doc).contains("I64(12)"));
+
+    // Changing the signature rebuilds the whole program
+    let pos = doc.text().find("fn @g()").unwrap();
+    assert!(matches!(doc.edit(pos..pos + 7, "fn @h()"), Ok(Rebuilt::All)));
+    assert!(doc.program().unwrap().global.find("h").is_some());
+}
@@ -1,42 +1,110 @@
 use std::convert::TryFrom;
-use std::io::{self, Read};
+use std::fs::File;
+use std::io::{self, BufRead, Read};
 use std::iter::FromIterator;
 use std::str::FromStr;
 
-use crate::irc::{CompileErr, Loc};
+use crate::irc::{CompileErr, ErrKind, Loc};
 use crate::irc::syntax::Token;
 
-pub struct Lexer {
-    /// Characters from source
+/// Size of chunks read from a streaming source
+const CHUNK_SIZE: usize = 1 << 16;
+
+pub struct Lexer<'a> {
+    /// Characters from source. For a streaming source, this is a window starting at the line of
+    /// current location, and characters of previous lines are discarded as the window is refilled.
     chars: Vec<char>,
     /// Point to current location in the char vector
     ptr: usize,
+    /// Streaming source where more characters are read from, if there is one
+    reader: Option<Box<dyn Read + 'a>>,
+    /// Bytes read from source that do not form a complete UTF-8 character yet
+    pending: Vec<u8>,
     /// Location of current pointer in source file
     loc: Loc,
     /// If there was an error during lexing
     err: Option<CompileErr>,
+    /// Whether the iterator has reached the end of token stream
+    done: bool,
 }
 
-impl FromStr for Lexer {
-    type Err = io::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Lexer {
-            chars: s.to_string().chars().collect(),
+impl From<&str> for Lexer<'_> {
+    fn from(s: &str) -> Self {
+        Lexer {
+            chars: s.chars().collect(),
             ptr: 0,
-            loc: Loc { line: 0, col: 0 },
+            reader: None,
+            pending: vec![],
+            loc: Loc { file: None, line: 0, col: 0 },
             err: None,
-        })
+            done: false,
+        }
     }
 }
 
-impl TryFrom<&mut dyn Read> for Lexer {
+impl FromStr for Lexer<'_> {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> { Ok(Lexer::from(s)) }
+}
+
+/// The source is read incrementally as tokens are requested, like `Lexer::from_read`.
+impl<'a> TryFrom<&'a mut dyn Read> for Lexer<'a> {
+    type Error = io::Error;
+
+    fn try_from(read: &'a mut dyn Read) -> Result<Self, Self::Error> { Ok(Self::from_read(read)) }
+}
+
+/// The file is read incrementally as tokens are requested.
+impl TryFrom<File> for Lexer<'_> {
     type Error = io::Error;
 
-    fn try_from(read: &mut dyn Read) -> Result<Self, Self::Error> {
+    fn try_from(file: File) -> Result<Self, Self::Error> { Ok(Self::from_read(file)) }
+}
+
+impl<'a> Lexer<'a> {
+    /// Create lexer from any buffered reader. The whole source is read before lexing, so the
+    /// reader could borrow data.
+    pub fn from_buf_read<R: BufRead>(mut read: R) -> io::Result<Lexer<'a>> {
         let mut s = String::new();
         read.read_to_string(&mut s)?;
-        Ok(Self::from_str(&s)?)
+        Ok(Lexer::from(s.as_str()))
+    }
+
+    /// Create lexer that reads source from `read` in chunks when more characters are needed,
+    /// so that memory usage does not grow with the size of source. I/O errors and invalid
+    /// UTF-8 are reported as lexical errors where they are encountered.
+    pub fn from_read<R: Read + 'a>(read: R) -> Lexer<'a> {
+        let mut lexer = Lexer::from("");
+        lexer.reader = Some(Box::new(read));
+        lexer
+    }
+
+    /// Record `file` as name of the source file in locations of tokens and errors.
+    pub fn with_file(mut self, file: &str) -> Lexer<'a> {
+        self.loc.file = Some(file.into());
+        self
+    }
+}
+
+/// The lexer could be used as a token stream independent of the parser. The iterator stops
+/// right after an error is produced, and the final `Eof` token is not yielded.
+impl Iterator for Lexer<'_> {
+    type Item = LexResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done { return None; }
+        match self.next_token() {
+            Ok(Token::Eof(_)) => {
+                self.done = true;
+                None
+            }
+            Ok(tok) => Some(Ok(tok)),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
     }
 }
 
@@ -53,25 +121,35 @@ enum NfaState {
     LabelName,
     /// Expect name part for reserved words
     ResName,
+    /// Expect name part for metadata key
+    MetaName,
+    /// Expect name part for directive
+    DirName,
     /// Expect integer
     Int,
+    /// In string literal, expect any character until `"`
+    Str,
     /// In comment, ignore all characters until a new line
     Comment,
+    /// In block comment, ignore all characters until `*/`
+    BlockComment,
 }
 
 type LexResult = Result<Token, CompileErr>;
 
-impl Lexer {
+impl Lexer<'_> {
     /// Get next lexeme. This function simulates an NFA to perform lexical analysis.
-    /// `Ok(l)` if a valid lexeme is found.
+    /// `Ok(l)` if a valid lexeme is found. `Eof` is returned repeatedly at the end of source.
     /// `Err(e)` if there is some error occurred during lexing.
-    pub fn next(&mut self) -> LexResult {
+    pub fn next_token(&mut self) -> LexResult {
         // Early exit if there was an error
         if let Some(ref e) = self.err { return Err(e.clone()); }
 
         // Mutable data during lexing
         let mut buf = Vec::new();
         let mut state = NfaState::Start;
+        // Location where the current lexeme starts
+        let mut start = self.loc.clone();
 
         macro_rules! read_char {
             () => {
@@ -90,8 +168,9 @@ impl Lexer {
         }
 
         // Iterate until all the characters are consumed
-        while self.ptr < self.chars.len() {
+        while self.fill() {
             let c = self.peek();
+            if let NfaState::Start = state { start = self.loc.clone() }
             match state {
                 // A new round of lexing, not holding any data in buffer.
                 NfaState::Start => match c {
@@ -126,6 +205,25 @@ impl Lexer {
                         read_char!();
                         state = NfaState::ResName
                     }
+                    '!' => {
+                        // metadata key
+                        read_char!();
+                        if !Self::is_alpha_num_mark(self.peek()) {
+                            return self.err("expect [A-Za-z0-9_]");
+                        }
+                        read_char!();
+                        state = NfaState::MetaName
+                    }
+                    '#' if Self::is_alpha_mark(self.peek_next()) => {
+                        // directive
+                        read_char!();
+                        state = NfaState::DirName
+                    }
+                    '"' => {
+                        // string literal
+                        read_char!();
+                        state = NfaState::Str
+                    }
                     '-' => {
                         // signed integer or right arrow
                         read_char!();
@@ -136,7 +234,7 @@ impl Lexer {
                             }
                             '>' => {
                                 skip_char!();
-                                return Ok(Token::RightArrow(self.loc.clone()));
+                                return Ok(Token::RightArrow(start.clone()));
                             }
                             _ => return self.err("expect [0-9>]")
                         }
@@ -147,7 +245,7 @@ impl Lexer {
                             return self.err("expect -");
                         }
                         skip_char!(); // '-'
-                        return Ok(Token::LeftArrow(self.loc.clone()));
+                        return Ok(Token::LeftArrow(start.clone()));
                     }
                     '0'..='9' => {
                         read_char!();
@@ -155,86 +253,107 @@ impl Lexer {
                     }
                     ',' => {
                         skip_char!();
-                        return Ok(Token::Comma(self.loc.clone()));
+                        return Ok(Token::Comma(start.clone()));
                     }
                     '*' => {
                         skip_char!();
-                        return Ok(Token::Asterisk(self.loc.clone()));
+                        return Ok(Token::Asterisk(start.clone()));
                     }
                     '=' => {
                         skip_char!();
-                        return Ok(Token::Equal(self.loc.clone()));
+                        return Ok(Token::Equal(start.clone()));
                     }
                     '(' => {
                         skip_char!();
-                        return Ok(Token::LeftParent(self.loc.clone()));
+                        return Ok(Token::LeftParent(start.clone()));
                     }
                     ')' => {
                         skip_char!();
-                        return Ok(Token::RightParent(self.loc.clone()));
+                        return Ok(Token::RightParent(start.clone()));
                     }
                     '[' => {
                         skip_char!();
-                        return Ok(Token::LeftSquare(self.loc.clone()));
+                        return Ok(Token::LeftSquare(start.clone()));
                     }
                     ']' => {
                         skip_char!();
-                        return Ok(Token::RightSquare(self.loc.clone()));
+                        return Ok(Token::RightSquare(start.clone()));
                     }
                     '{' => {
                         skip_char!();
-                        return Ok(Token::LeftCurly(self.loc.clone()));
+                        return Ok(Token::LeftCurly(start.clone()));
                     }
                     '}' => {
                         skip_char!();
-                        return Ok(Token::RightCurly(self.loc.clone()));
+                        return Ok(Token::RightCurly(start.clone()));
                     }
                     ':' => {
                         skip_char!();
-                        return Ok(Token::Colon(self.loc.clone()));
+                        return Ok(Token::Colon(start.clone()));
                     }
                     ';' => {
                         skip_char!();
-                        return Ok(Token::Semicolon(self.loc.clone()));
+                        return Ok(Token::Semicolon(start.clone()));
                     }
                     '?' => {
                         skip_char!();
-                        return Ok(Token::Question(self.loc.clone()));
+                        return Ok(Token::Question(start.clone()));
                     }
                     '/' => {
                         skip_char!(); // `/`
-                        if self.peek() != '/' {
-                            return self.err("expect /");
-                        }
-                        skip_char!(); // `/`
-                        state = NfaState::Comment
+                        state = match self.peek() {
+                            '/' => NfaState::Comment,
+                            '*' => NfaState::BlockComment,
+                            _ => return self.err("expect / or *")
+                        };
+                        skip_char!(); // `/` or `*`
                     }
                     ' ' | '\t' | '\r' | '\n' => { skip_char!(); }
-                    _ => return self.err("unknown character")
+                    _ => return self.unexpected(c)
                 }
                 NfaState::GlobalName =>
                     if Self::is_alpha_num_mark(c) {
                         read_char!();
                     } else {
-                        return self.pop_buf(state, buf);
+                        return self.pop_buf(state, buf, start);
                     }
                 NfaState::LocalName => match c {
                     c if Self::is_alpha_num_mark(c) => { read_char!(); }
-                    _ => return self.pop_buf(state, buf),
+                    _ => return self.pop_buf(state, buf, start),
                 }
                 NfaState::LabelName =>
                     if Self::is_alpha_num_mark(c) {
                         read_char!();
                     } else {
-                        return self.pop_buf(state, buf);
+                        return self.pop_buf(state, buf, start);
                     }
                 NfaState::ResName => match c {
                     _ if Self::is_alpha_num_mark(c) => { read_char!(); }
-                    _ => return self.pop_buf(state, buf)
+                    _ => return self.pop_buf(state, buf, start)
                 }
+                NfaState::MetaName =>
+                    if Self::is_alpha_num_mark(c) {
+                        read_char!();
+                    } else {
+                        return self.pop_buf(state, buf, start);
+                    }
+                NfaState::DirName =>
+                    if Self::is_alpha_num_mark(c) {
+                        read_char!();
+                    } else {
+                        return self.pop_buf(state, buf, start);
+                    }
                 NfaState::Int => match c {
                     '0'..='9' => { read_char!(); }
-                    _ => return self.pop_buf(state, buf)
+                    _ => return self.pop_buf(state, buf, start)
+                }
+                NfaState::Str => match c {
+                    '"' => {
+                        read_char!();
+                        return self.pop_buf(state, buf, start);
+                    }
+                    '\n' => return self.err_at(start, "unterminated string"),
+                    _ => { read_char!(); }
                 }
                 NfaState::Comment => {
                     skip_char!();
@@ -243,43 +362,156 @@ impl Lexer {
                         _ => continue
                     }
                 }
+                NfaState::BlockComment => {
+                    skip_char!();
+                    if c == '*' && self.peek() == '/' {
+                        skip_char!();
+                        state = NfaState::Start
+                    }
+                }
             }
         }
 
         // Possibly clear the buffer and create the final lexeme
-        if buf.is_empty() {
+        if let Some(ref e) = self.err {
+            Err(e.clone())
+        } else if let NfaState::BlockComment = state {
+            self.err_at(start, "unterminated comment")
+        } else if let NfaState::Str = state {
+            self.err_at(start, "unterminated string")
+        } else if buf.is_empty() {
             Ok(Token::Eof(self.loc.clone()))
         } else {
-            self.pop_buf(state, buf)
+            self.pop_buf(state, buf, start)
         }
     }
 
     /// Look ahead one character in the buffer list.
     /// If EOF id reached, return `\0`.
-    fn peek(&self) -> char {
-        match self.chars.get(self.ptr) {
-            Some(c) => c.clone(),
-            None => '\0'
+    fn peek(&mut self) -> char {
+        if !self.fill() { return '\0'; }
+        self.chars[self.ptr]
+    }
+
+    /// Peek the character after the one at current location.
+    fn peek_next(&mut self) -> char {
+        while self.ptr + 1 >= self.chars.len() {
+            if !self.load() { return '\0'; }
+        }
+        self.chars[self.ptr + 1]
+    }
+
+    /// Make sure the character at current location is in the buffer. Return `false` if the end
+    /// of source is reached.
+    fn fill(&mut self) -> bool {
+        while self.ptr >= self.chars.len() {
+            if !self.load() { return false; }
         }
+        true
     }
 
-    fn err(&mut self, msg: &str) -> LexResult {
-        let err = CompileErr { loc: self.loc.clone(), msg: msg.to_string() };
+    /// Read another chunk from the streaming source, and discard characters before the current
+    /// line. Return `false` if nothing more could be read.
+    fn load(&mut self) -> bool {
+        let reader = match self.reader.as_mut() {
+            Some(reader) => reader,
+            None => return false
+        };
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let res = loop {
+            match reader.read(&mut chunk) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => break res
+            }
+        };
+        let len = match res {
+            Ok(0) if self.pending.is_empty() => {
+                self.reader = None;
+                return false;
+            }
+            Ok(0) => return self.stop("invalid UTF-8 at end of source"),
+            Ok(len) => len,
+            Err(e) => return self.stop(&format!("cannot read source: {}", e))
+        };
+
+        // Decode bytes, keeping incomplete character at the end for the next chunk
+        self.pending.extend_from_slice(&chunk[..len]);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return self.stop("invalid UTF-8 in source")
+        };
+        let begin = self.ptr - self.loc.col;
+        self.chars.drain(..begin);
+        self.ptr -= begin;
+        let rest = self.pending.split_off(valid);
+        self.chars.extend(String::from_utf8(std::mem::replace(&mut self.pending, rest))
+            .unwrap().chars());
+        true
+    }
+
+    /// Stop reading from the streaming source because of an error.
+    fn stop(&mut self, msg: &str) -> bool {
+        self.reader = None;
+        let _ = self.err(msg);
+        false
+    }
+
+    /// Clear the last error and skip the rest of the line where it occurs, so that lexing can
+    /// continue from the next line.
+    pub fn recover(&mut self) {
+        if self.err.take().is_none() { return; }
+        while self.fill() {
+            let c = self.chars[self.ptr];
+            self.ptr += 1;
+            if c == '\n' {
+                self.loc.new_line();
+                break;
+            } else { self.loc.shift() }
+        }
+    }
+
+    fn err(&mut self, msg: &str) -> LexResult { self.err_at(self.loc.clone(), msg) }
+
+    /// Report an error starting at `loc`, which may be before the current location.
+    fn err_at(&mut self, loc: Loc, msg: &str) -> LexResult {
+        self.fail(CompileErr { loc, kind: ErrKind::Lexical(msg.to_string()) })
+    }
+
+    /// Report character `c` at current location, along with the line where it is found.
+    fn unexpected(&mut self, c: char) -> LexResult {
+        let end = loop {
+            match self.chars[self.ptr..].iter().position(|&c| c == '\n') {
+                Some(n) => break self.ptr + n,
+                None if !self.load() => break self.chars.len(),
+                None => {}
+            }
+        };
+        let begin = self.ptr - self.loc.col;
+        let line = String::from_iter(&self.chars[begin..end]).trim_end().to_string();
+        let kind = ErrKind::UnexpectedChar { ch: c, line, col: self.loc.col };
+        self.fail(CompileErr { loc: self.loc.clone(), kind })
+    }
+
+    fn fail(&mut self, err: CompileErr) -> LexResult {
         self.err = Some(err.clone());
         Err(err)
     }
 
     /// Pop all characters in buffer to create a lexeme
-    fn pop_buf(&self, state: NfaState, buf: Vec<char>) -> LexResult {
+    fn pop_buf(&self, state: NfaState, buf: Vec<char>, loc: Loc) -> LexResult {
         let s = String::from_iter(buf.into_iter());
         match state {
             // When the buffer is not empty, it cannot be in the start state.
-            NfaState::Start | NfaState::Comment => unreachable!(),
-            NfaState::GlobalName => Ok(Token::GlobalId(self.loc.clone(), s)),
-            NfaState::LocalName => Ok(Token::LocalId(self.loc.clone(), s)),
-            NfaState::LabelName => Ok(Token::Label(self.loc.clone(), s)),
-            NfaState::ResName => Ok(Token::Reserved(self.loc.clone(), s)),
-            NfaState::Int => Ok(Token::Integer(self.loc.clone(), s))
+            NfaState::Start | NfaState::Comment | NfaState::BlockComment => unreachable!(),
+            NfaState::GlobalName => Ok(Token::GlobalId(loc.clone(), s)),
+            NfaState::LocalName => Ok(Token::LocalId(loc.clone(), s)),
+            NfaState::LabelName => Ok(Token::Label(loc.clone(), s)),
+            NfaState::ResName => Ok(Token::Reserved(loc.clone(), s)),
+            NfaState::MetaName => Ok(Token::Meta(loc.clone(), s)),
+            NfaState::DirName => Ok(Token::Directive(loc.clone(), s)),
+            NfaState::Int => Ok(Token::Integer(loc.clone(), s)),
+            NfaState::Str => Ok(Token::Str(loc.clone(), s)),
         }
     }
 
@@ -299,17 +531,114 @@ impl Lexer {
 
 #[test]
 fn test_lex() {
-    use std::fs::File;
     let mut file = File::open("test/example.ir").unwrap();
-    let mut lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
-    loop {
-        match lexer.next() {
-            Ok(Token::Eof(_)) => break,
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    for tok in lexer {
+        match tok {
             Ok(l) => println!("{:?}", l),
-            Err(e) => {
-                println!("{}", e);
-                break;
+            Err(e) => println!("{}", e)
+        }
+    }
+}
+
+#[test]
+fn test_lex_iter() {
+    let file = File::open("test/example.ir").unwrap();
+    let from_file: Vec<_> = Lexer::try_from(file).unwrap().collect::<Result<_, _>>().unwrap();
+    let mut src = String::new();
+    File::open("test/example.ir").unwrap().read_to_string(&mut src).unwrap();
+    let from_str: Vec<_> = Lexer::from(src.as_str()).map(Result::unwrap).collect();
+    let from_buf: Vec<_> = Lexer::from_buf_read(src.as_bytes()).unwrap()
+        .map(Result::unwrap).collect();
+    assert_eq!(format!("{:?}", from_file), format!("{:?}", from_str));
+    assert_eq!(format!("{:?}", from_file), format!("{:?}", from_buf));
+
+    let res: Vec<_> = Lexer::from("$a <- mov i32 1 #").collect();
+    assert_eq!(res.len(), 6);
+    assert!(res[5].is_err());
+}
+
+#[test]
+fn test_lex_comment() {
+    let lex = |src: &str| Lexer::from(src).collect::<Vec<_>>();
+
+    let src = "$a /* block\n * comment */ <- /**/ mov i32 1 // line comment\n/* last */";
+    let tok: Vec<_> = lex(src).into_iter().map(Result::unwrap).collect();
+    assert_eq!(tok.len(), 5);
+    assert!(matches!(&tok[1], Token::LeftArrow(l) if (l.line(), l.col()) == (1, 14)));
+
+    // Unterminated comments and strings are reported where they start
+    let res = lex("$a <- mov i32 1\n  /* comment *");
+    let err = res.last().unwrap().as_ref().unwrap_err();
+    println!("{}", err);
+    assert_eq!((err.loc().line(), err.loc().col()), (1, 2));
+    let res = lex("!name \"string\n\"");
+    let err = res.last().unwrap().as_ref().unwrap_err();
+    assert_eq!((err.loc().line(), err.loc().col()), (0, 6));
+    assert!(lex("$a / $b").last().unwrap().is_err());
+
+    // Unexpected character is shown with a caret below it
+    let res = lex("fn @f() {\n\t$a <- mov i32 1 # \n}");
+    let err = res.last().unwrap().as_ref().unwrap_err();
+    println!("{}", err);
+    assert_eq!((err.loc().line(), err.loc().col()), (1, 17));
+    assert_eq!(err.kind().to_string(),
+               "unexpected character '#' (0x23)\n\t$a <- mov i32 1 #\n\t                ^");
+}
+
+#[test]
+fn test_lex_stream() {
+    /// Reader that returns at most one byte on each read
+    struct ByteReader(Vec<u8>, usize);
+
+    impl Read for ByteReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.get(self.1) {
+                Some(b) => {
+                    buf[0] = *b;
+                    self.1 += 1;
+                    Ok(1)
+                }
+                None => Ok(0)
             }
         }
     }
-}
\ No newline at end of file
+
+    // Locations are tracked across chunks, even if a character is split between them
+    let mut src = String::new();
+    File::open("test/example.ir").unwrap().read_to_string(&mut src).unwrap();
+    src += "\n/* 注释 */ $a #";
+    let from_str: Vec<_> = Lexer::from(src.as_str()).collect();
+    let stream: Vec<_> = Lexer::from_read(ByteReader(src.clone().into_bytes(), 0)).collect();
+    assert_eq!(format!("{:?}", from_str), format!("{:?}", stream));
+    println!("{}", stream.last().unwrap().as_ref().unwrap_err());
+
+    // Invalid UTF-8 is reported as lexical error
+    let res: Vec<_> = Lexer::from_read(ByteReader(b"$a <- mov \xff".to_vec(), 0)).collect();
+    assert_eq!(res.len(), 4);
+    assert!(res[3].is_err());
+
+    // Only a bounded window of source is kept in memory
+    let line = "    $x <- add i64 $x, 1 // increment\n";
+    let n_line = CHUNK_SIZE / line.len() * 20;
+    let mut lexer = Lexer::from_read(io::Cursor::new(line.repeat(n_line).into_bytes()));
+    let mut n = 0;
+    while let Some(tok) = lexer.next() {
+        tok.unwrap();
+        n += 1;
+        assert!(lexer.chars.len() <= CHUNK_SIZE + line.len());
+    }
+    assert_eq!(n, n_line * 7);
+
+    // Borrowed readers are read in chunks as well
+    let src = line.repeat(n_line);
+    let mut read = io::Cursor::new(src.as_bytes());
+    let mut lexer = Lexer::try_from(&mut read as &mut dyn Read).unwrap();
+    let mut n = 0;
+    while let Some(tok) = lexer.next() {
+        tok.unwrap();
+        n += 1;
+        assert!(lexer.chars.len() <= CHUNK_SIZE + line.len());
+    }
+    assert_eq!(n, n_line * 7);
+}
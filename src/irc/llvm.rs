@@ -0,0 +1,1131 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::irc::{CompileErr, ErrKind, Loc};
+use crate::irc::syntax::{Term, Token};
+
+/// Importer of a restricted subset of LLVM IR text. The module is translated to a syntax tree
+/// of this language, so that it could be checked and built by `Builder` like a parsed one.
+///
+/// Supported constructs are:
+/// * Integer types `i1`, `i8`, `i16`, `i32` and `i64`, typed pointers, arrays and (named)
+///   structures.
+/// * Global variables with integer or zero initializers, and function definitions. Function
+///   declarations, attribute groups, metadata and target information are ignored.
+/// * Instructions `add`, `sub`, `mul`, `sdiv`, `srem`, `and`, `or`, `xor`, `shl`, `ashr`,
+///   signed `icmp`, `zext`, `sext`, `trunc`, `ptrtoint`, `inttoptr`, `phi`, `alloca`, `load`,
+///   `store`, `getelementptr`, `call`, `br` and `ret`.
+///
+/// Global variables in LLVM are addresses, while they are values in this language. Therefore
+/// they can only be loaded from and stored to directly, and taking their addresses is not
+/// supported.
+///
+/// Entry function `@main` returns `i64` in this language. If it returns a narrower integer,
+/// as `int main()` in C does, its return type is changed to `i64` and returned values are
+/// sign-extended. Its parameters are kept as they are, so it should still take none.
+///
+/// Anything else, such as opaque pointers, floating point values, unsigned operations or
+/// `switch`, is reported as `ErrKind::Unsupported`.
+pub struct LlvmParser {
+    /// Tokens of each line in source
+    lines: Vec<Vec<(Loc, Lex)>>,
+    /// Current line
+    line: usize,
+    /// Position of next token in current line
+    pos: usize,
+    /// Named structure types
+    types: HashMap<String, LType>,
+    /// Names of global variables
+    vars: HashSet<String>,
+    /// Whether results of the function being parsed are extended to `i64`, as in `@main`
+    ext_ret: bool,
+}
+
+/// Lexeme of LLVM IR
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Lex {
+    /// `%name`, `%"name"` or `%0`
+    Local(String),
+    /// `@name`, `@"name"` or `@0`
+    Global(String),
+    Int(String),
+    /// Keywords, type names and other bare words
+    Word(String),
+    Str(String),
+    /// Metadata `!name` or `!0`
+    Meta(String),
+    /// Attribute group `#0`
+    Attr(String),
+    /// `...` in variadic function types
+    Ellipsis,
+    Punct(char),
+}
+
+impl ToString for Lex {
+    fn to_string(&self) -> String {
+        match self {
+            Lex::Local(s) => format!("%{}", s),
+            Lex::Global(s) => format!("@{}", s),
+            Lex::Int(s) | Lex::Word(s) => s.clone(),
+            Lex::Str(s) => format!("{:?}", s),
+            Lex::Meta(s) => format!("!{}", s),
+            Lex::Attr(s) => format!("#{}", s),
+            Lex::Ellipsis => "...".to_string(),
+            Lex::Punct(c) => c.to_string(),
+        }
+    }
+}
+
+/// Type in LLVM IR
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum LType {
+    Void,
+    Int(u32),
+    Ptr(Box<LType>),
+    Array(usize, Box<LType>),
+    Struct(Vec<LType>),
+    /// Named structure `%name`
+    Named(String),
+    /// Function type with return and parameter types
+    Fn(Box<LType>, Vec<LType>),
+}
+
+impl LlvmParser {
+    pub fn new(src: &str) -> LlvmParser {
+        LlvmParser {
+            lines: src.lines().enumerate().map(|(i, l)| tokenize(i, l)).collect(),
+            line: 0,
+            pos: 0,
+            types: Default::default(),
+            vars: Default::default(),
+            ext_ret: false,
+        }
+    }
+
+    /// Parse the module into a program term.
+    pub fn parse(mut self) -> Result<Term, CompileErr> {
+        // Collect named types and global variables, which may be referred to before their
+        // definitions.
+        for i in 0..self.lines.len() {
+            self.goto(i);
+            match (self.peek(0).cloned(), self.peek(1).cloned(), self.peek(2).cloned()) {
+                (Some(Lex::Local(_)), Some(Lex::Punct('=')), Some(Lex::Word(w))) if w == "type" => {
+                    self.type_def(false)?;
+                }
+                (Some(Lex::Global(n)), Some(Lex::Punct('=')), _) => { self.vars.insert(n); }
+                _ => {}
+            }
+        }
+
+        let mut def = vec![];
+        let mut i = 0;
+        while i < self.lines.len() {
+            self.goto(i);
+            let term = match (self.peek(0).cloned(), self.peek(1).cloned(), self.peek(2).cloned()) {
+                (None, _, _) | (Some(Lex::Meta(_)), _, _) => None,
+                (Some(Lex::Local(_)), Some(Lex::Punct('=')), Some(Lex::Word(w))) if w == "type" =>
+                    self.type_def(true)?,
+                (Some(Lex::Global(_)), Some(Lex::Punct('=')), _) => Some(self.var_def()?),
+                (Some(Lex::Word(w)), _, _) => match w.as_str() {
+                    "define" => Some(self.fn_def()?),
+                    "declare" | "target" | "source_filename" | "attributes" => None,
+                    _ => return self.unsupported(&w)
+                }
+                _ => {
+                    let tok = self.next()?;
+                    return self.syntax(vec!["define", "{GlobalId}", "{LocalId}"], &tok);
+                }
+            };
+            def.extend(term);
+            i = self.line + 1;
+        }
+        Ok(Term::Program { def })
+    }
+
+    /// `%name = type { ... }`
+    fn type_def(&mut self, emit: bool) -> Result<Option<Term>, CompileErr> {
+        let loc = self.loc();
+        let name = if let Lex::Local(n) = self.next()? { n } else { unreachable!() };
+        self.next()?; // `=`
+        self.next()?; // `type`
+        if let Some(Lex::Word(w)) = self.peek(0) {
+            if w == "opaque" { return self.unsupported("opaque structure"); }
+        }
+        let ty = self.ty()?;
+        if !emit {
+            self.types.insert(name, ty);
+            return Ok(None);
+        }
+        Ok(Some(Term::AliasDef {
+            loc: loc.clone(),
+            id: Token::GlobalId(loc.clone(), format!("@{}", name)),
+            ty: Box::new(self.ty_term(&ty, &loc)?),
+        }))
+    }
+
+    /// `@name = linkage* (global | constant) type init (, align n)?`
+    fn var_def(&mut self) -> Result<Term, CompileErr> {
+        let loc = self.loc();
+        let name = if let Lex::Global(n) = self.next()? { n } else { unreachable!() };
+        self.next()?; // `=`
+        let mut linkage = "export";
+        let is_const = loop {
+            match self.next()? {
+                Lex::Word(w) if w == "global" => break false,
+                Lex::Word(w) if w == "constant" => break true,
+                Lex::Word(w) => match w.as_str() {
+                    "external" | "extern_weak" | "available_externally" =>
+                        return self.unsupported("external global variable"),
+                    "alias" | "ifunc" => return self.unsupported(&w),
+                    _ => if let Some(l) = linkage_of(&w) { linkage = l }
+                }
+                tok => return self.syntax(vec!["global", "constant"], &tok)
+            }
+        };
+        let ty = self.ty()?;
+        let init_loc = self.loc();
+        let init = match self.next()? {
+            Lex::Int(i) => Some(Token::Integer(init_loc, i)),
+            Lex::Word(w) if w == "true" => Some(Token::Integer(init_loc, "1".to_string())),
+            Lex::Word(w) if ["zeroinitializer", "false", "null"].contains(&w.as_str()) => None,
+            tok => return self.unsupported(&format!("initializer {}", tok.to_string()))
+        };
+        Ok(Term::VarDef {
+            loc: loc.clone(),
+            linkage: Some(Token::Reserved(loc.clone(), linkage.to_string())),
+            is_const,
+            id: Token::GlobalId(loc.clone(), format!("@{}", name)),
+            init,
+            ty: Box::new(self.ty_term(&ty, &loc)?),
+        })
+    }
+
+    /// Skip function header to return type, and record the linkage.
+    fn fn_header(&mut self) -> Result<&'static str, CompileErr> {
+        self.next()?; // `define`
+        let mut linkage = "export";
+        loop {
+            match self.peek(0) {
+                Some(Lex::Word(w)) if !is_type_start(w) => {
+                    if let Some(l) = linkage_of(w) { linkage = l }
+                    self.next()?;
+                }
+                _ => break Ok(linkage)
+            }
+        }
+    }
+
+    /// `define linkage* type @name(params) attrs* { body }`
+    fn fn_def(&mut self) -> Result<Term, CompileErr> {
+        let loc = self.loc();
+        let linkage = self.fn_header()?;
+        let ret = self.ty()?;
+        let id_loc = self.loc();
+        let name = match self.next()? {
+            Lex::Global(n) => n,
+            tok => return self.syntax(vec!["{GlobalId}"], &tok)
+        };
+        self.expect('(')?;
+
+        // `@main` should return `i64`, so narrower results are extended to it
+        let ret = match ret {
+            LType::Int(w) if name == "main" && w < 64 => {
+                self.ext_ret = true;
+                LType::Int(64)
+            }
+            ty => ty
+        };
+
+        // Parameters, which may be unnamed and thus numbered implicitly
+        let mut param = vec![];
+        let mut num = 0;
+        loop {
+            match self.peek(0) {
+                Some(Lex::Punct(')')) => break,
+                Some(Lex::Punct(',')) if !param.is_empty() => { self.next()?; }
+                Some(Lex::Ellipsis) => return self.unsupported("variadic function"),
+                _ => {}
+            }
+            let p_loc = self.loc();
+            let ty = self.ty()?;
+            self.skip_attrib()?;
+            let name = match self.peek(0) {
+                Some(Lex::Local(n)) => {
+                    let n = n.clone();
+                    self.next()?;
+                    if let Ok(i) = n.parse::<usize>() { num = i + 1 }
+                    n
+                }
+                _ => {
+                    num += 1;
+                    (num - 1).to_string()
+                }
+            };
+            param.push(Term::ParamDef {
+                loc: p_loc.clone(),
+                id: Token::LocalId(p_loc.clone(), format!("${}", name)),
+                ty: Box::new(self.ty_term(&ty, &p_loc)?),
+            });
+        }
+        self.next()?; // `)`
+        while self.pos < self.lines[self.line].len() - 1 { self.next()?; }
+        self.expect('{')?;
+
+        let sig = Term::FnSig {
+            loc: id_loc.clone(),
+            id: Token::GlobalId(id_loc.clone(), format!("@{}", name)),
+            param: Box::new(Term::ParamList { loc: id_loc.clone(), list: param }),
+            ret: match ret {
+                LType::Void => None,
+                ty => Some(Box::new(Term::FnRet {
+                    loc: id_loc.clone(),
+                    ty: Box::new(self.ty_term(&ty, &id_loc)?),
+                }))
+            },
+        };
+        let body = self.fn_body(num);
+        self.ext_ret = false;
+        let body = body?;
+        Ok(Term::FnDef {
+            loc: loc.clone(),
+            attrib: None,
+            linkage: Some(Token::Reserved(loc.clone(), linkage.to_string())),
+            sig: Box::new(sig),
+            meta: Box::new(Term::MetaList { loc: id_loc.clone(), list: vec![] }),
+            body: Box::new(body),
+        })
+    }
+
+    /// Parse lines of blocks, until `}`. `num` is the next number of unnamed values, which is
+    /// given to the entry block if it has no label.
+    fn fn_body(&mut self, num: usize) -> Result<Term, CompileErr> {
+        let loc = self.loc();
+        let mut bb: Vec<Term> = vec![];
+        let mut tmp = Temps { used: HashSet::new(), next: 0 };
+        for line in self.lines[self.line + 1..].iter() {
+            if line.first().map(|(_, l)| l) == Some(&Lex::Punct('}')) { break; }
+            line.iter().for_each(|(_, l)| if let Lex::Local(n) = l { tmp.used.insert(n.clone()); });
+        }
+        loop {
+            self.goto(self.line + 1);
+            if self.line >= self.lines.len() {
+                return self.syntax(vec!["}"], &Lex::Word("end of file".to_string()));
+            }
+            let loc = self.loc();
+            match (self.peek(0).cloned(), self.peek(1).cloned()) {
+                (None, _) => continue,
+                (Some(Lex::Punct('}')), _) if !bb.is_empty() => break,
+                (Some(Lex::Word(l)), Some(Lex::Punct(':')))
+                | (Some(Lex::Int(l)), Some(Lex::Punct(':')))
+                | (Some(Lex::Str(l)), Some(Lex::Punct(':'))) => {
+                    let id = Token::Label(loc.clone(), format!("%{}", l));
+                    bb.push(Term::BlockDef {
+                        loc: loc.clone(),
+                        id,
+                        meta: Box::new(Term::MetaList { loc: loc.clone(), list: vec![] }),
+                        instr: vec![],
+                    });
+                }
+                _ => {
+                    if bb.is_empty() {
+                        bb.push(Term::BlockDef {
+                            loc: loc.clone(),
+                            id: Token::Label(loc.clone(), format!("%{}", num)),
+                            meta: Box::new(Term::MetaList { loc: loc.clone(), list: vec![] }),
+                            instr: vec![],
+                        });
+                    }
+                    let mut instr = self.instr_def(&mut tmp)?;
+                    if let Some(Term::BlockDef { loc: _, id: _, meta: _, instr: list }) =
+                    bb.last_mut() {
+                        list.append(&mut instr);
+                    }
+                }
+            }
+        }
+        Ok(Term::FnBody { loc, bb })
+    }
+
+    /// Translate one instruction, which may produce several instructions.
+    fn instr_def(&mut self, tmp: &mut Temps) -> Result<Vec<Term>, CompileErr> {
+        let loc = self.loc();
+        let dst = match (self.peek(0), self.peek(1)) {
+            (Some(Lex::Local(n)), Some(Lex::Punct('='))) => {
+                let id = Token::LocalId(loc.clone(), format!("${}", n));
+                self.next()?;
+                self.next()?;
+                Some(id)
+            }
+            _ => None
+        };
+        let op_loc = self.loc();
+        let op = match self.next()? {
+            Lex::Word(w) if ["tail", "musttail", "notail"].contains(&w.as_str()) =>
+                self.next()?,
+            tok => tok
+        };
+        let op = match op {
+            Lex::Word(w) => w,
+            tok => return self.syntax(vec!["{Opcode}"], &tok)
+        };
+        let mut pre = vec![];
+        let rhs = match op.as_str() {
+            "add" | "sub" | "mul" | "sdiv" | "srem" | "and" | "or" | "xor" | "shl" | "ashr" => {
+                while let Some(Lex::Word(w)) = self.peek(0) {
+                    if !["nsw", "nuw", "exact"].contains(&w.as_str()) { break; }
+                    self.next()?;
+                }
+                let name = match op.as_str() {
+                    "sdiv" => "div",
+                    "srem" => "mod",
+                    "ashr" => "shr",
+                    op => op
+                };
+                self.bin_rhs(name, &op_loc)?
+            }
+            "icmp" => {
+                let cond = match self.next()? {
+                    Lex::Word(c) => c,
+                    tok => return self.syntax(vec!["{Condition}"], &tok)
+                };
+                let name = match cond.as_str() {
+                    "eq" | "ne" => cond.as_str(),
+                    "slt" => "lt",
+                    "sle" => "le",
+                    "sgt" => "gt",
+                    "sge" => "ge",
+                    _ => return self.unsupported(&format!("icmp {}", cond))
+                };
+                self.bin_rhs(name, &op_loc)?
+            }
+            "zext" | "sext" | "trunc" | "ptrtoint" | "inttoptr" => {
+                let (ty, opd) = self.typed_opd()?;
+                self.expect_word("to")?;
+                let tgt = self.ty()?;
+                Term::CastRhs {
+                    loc: op_loc.clone(),
+                    name: Token::Reserved(op_loc.clone(), op.clone()),
+                    ty: Box::new(self.ty_term(&ty, &op_loc)?),
+                    opd,
+                    tgt: Box::new(self.ty_term(&tgt, &op_loc)?),
+                }
+            }
+            "phi" => {
+                let ty = self.ty()?;
+                let mut list = vec![];
+                loop {
+                    let p_loc = self.loc();
+                    self.expect('[')?;
+                    let opd = self.opd()?;
+                    self.expect(',')?;
+                    let lab = self.block_ref()?;
+                    self.expect(']')?;
+                    list.push(Term::PhiOpd { loc: p_loc, lab, opd });
+                    match (self.peek(0), self.peek(1)) {
+                        (Some(Lex::Punct(',')), Some(Lex::Punct('['))) => { self.next()?; }
+                        _ => break
+                    }
+                }
+                Term::PhiRhs {
+                    loc: op_loc.clone(),
+                    ty: Box::new(self.ty_term(&ty, &op_loc)?),
+                    list: Box::new(Term::PhiList { loc: op_loc.clone(), list }),
+                }
+            }
+            "alloca" => {
+                let ty = self.ty()?;
+                if let (Some(Lex::Punct(',')), Some(Lex::Word(w))) = (self.peek(0), self.peek(1)) {
+                    if is_type_start(w) { return self.unsupported("alloca with element count"); }
+                }
+                Term::AllocRhs { loc: op_loc.clone(), ty: Box::new(self.ty_term(&ty, &op_loc)?) }
+            }
+            "load" => {
+                if let Some(Lex::Word(w)) = self.peek(0) {
+                    if w == "volatile" { self.next()?; }
+                }
+                let ty = self.ty()?;
+                self.expect(',')?;
+                self.ty()?;
+                // Load from global variable is a move from it
+                let (name, ptr) = match self.global_var() {
+                    Some(var) => ("mov", var),
+                    None => ("ld", self.opd()?)
+                };
+                Term::CommonRhs {
+                    loc: op_loc.clone(),
+                    name: Token::Reserved(op_loc.clone(), name.to_string()),
+                    ty: Box::new(self.ty_term(&ty, &op_loc)?),
+                    opd: Box::new(Term::OpdList { loc: op_loc.clone(), list: vec![ptr] }),
+                }
+            }
+            "getelementptr" => self.gep_rhs(&op_loc, &mut pre, tmp)?,
+            "call" => return self.call(dst, &loc, &op_loc),
+            "store" => {
+                if let Some(Lex::Word(w)) = self.peek(0) {
+                    if w == "volatile" { self.next()?; }
+                }
+                let (ty, src) = self.typed_opd()?;
+                self.expect(',')?;
+                self.ty()?;
+                let ty = Box::new(self.ty_term(&ty, &op_loc)?);
+                // Store to global variable is a move to it
+                if let Some(id) = self.global_var() {
+                    let opd = Box::new(Term::OpdList { loc: op_loc.clone(), list: vec![src] });
+                    let name = Token::Reserved(op_loc.clone(), "mov".to_string());
+                    return Ok(vec![Term::AssignInstr {
+                        loc: loc.clone(),
+                        id: vec![id],
+                        rhs: Box::new(Term::CommonRhs { loc: op_loc, name, ty, opd }),
+                        meta: Box::new(Term::MetaList { loc, list: vec![] }),
+                    }]);
+                }
+                let dst = self.opd()?;
+                return Ok(vec![self.non_assign(Term::StInstr { loc: op_loc, ty, src, dst }, &loc)]);
+            }
+            "br" => {
+                let instr = match self.peek(0) {
+                    Some(Lex::Word(w)) if w == "label" => {
+                        Term::JmpInstr { loc: op_loc.clone(), tgt: self.label()? }
+                    }
+                    _ => {
+                        let (_, cond) = self.typed_opd()?;
+                        self.expect(',')?;
+                        let tr = self.label()?;
+                        self.expect(',')?;
+                        let fls = self.label()?;
+                        Term::BrInstr { loc: op_loc.clone(), cond, tr, fls }
+                    }
+                };
+                return Ok(vec![self.non_assign(instr, &loc)]);
+            }
+            "ret" => {
+                let opd = match self.peek(0) {
+                    Some(Lex::Word(w)) if w == "void" => vec![],
+                    _ => {
+                        let (ty, opd) = self.typed_opd()?;
+                        if self.ext_ret {
+                            vec![self.sext_i64(ty, opd, &op_loc, &mut pre, tmp)?]
+                        } else { vec![opd] }
+                    }
+                };
+                pre.push(self.non_assign(Term::RetInstr { loc: op_loc, opd }, &loc));
+                return Ok(pre);
+            }
+            "unreachable" =>
+                return Ok(vec![self.non_assign(Term::UnreachableInstr { loc: op_loc }, &loc)]),
+            _ => return self.unsupported(&format!("instruction {}", op))
+        };
+        let id = match dst {
+            Some(id) => id,
+            None => return self.syntax(vec!["{LocalId}"], &Lex::Word(op))
+        };
+        pre.push(Term::AssignInstr {
+            loc: loc.clone(),
+            id: vec![id],
+            rhs: Box::new(rhs),
+            meta: Box::new(Term::MetaList { loc, list: vec![] }),
+        });
+        Ok(pre)
+    }
+
+    /// `type opd, opd`
+    fn bin_rhs(&mut self, name: &str, loc: &Loc) -> Result<Term, CompileErr> {
+        let (ty, fst) = self.typed_opd()?;
+        self.expect(',')?;
+        let snd = self.opd()?;
+        Ok(Term::CommonRhs {
+            loc: loc.clone(),
+            name: Token::Reserved(loc.clone(), name.to_string()),
+            ty: Box::new(self.ty_term(&ty, loc)?),
+            opd: Box::new(Term::OpdList { loc: loc.clone(), list: vec![fst, snd] }),
+        })
+    }
+
+    /// Sign-extend `opd` of integer type `ty` to `i64` by an instruction in `pre`, and return
+    /// the extended operand. Constants and `i64` operands are returned as they are.
+    fn sext_i64(&self, ty: LType, opd: Token, loc: &Loc, pre: &mut Vec<Term>, tmp: &mut Temps)
+                -> Result<Token, CompileErr>
+    {
+        if ty == LType::Int(64) || matches!(opd, Token::Integer(_, _)) { return Ok(opd); }
+        let id = tmp.fresh();
+        pre.push(Term::AssignInstr {
+            loc: loc.clone(),
+            id: vec![Token::LocalId(loc.clone(), id.clone())],
+            rhs: Box::new(Term::CastRhs {
+                loc: loc.clone(),
+                name: Token::Reserved(loc.clone(), "sext".to_string()),
+                ty: Box::new(self.ty_term(&ty, loc)?),
+                opd,
+                tgt: Box::new(self.ty_term(&LType::Int(64), loc)?),
+            }),
+            meta: Box::new(Term::MetaList { loc: loc.clone(), list: vec![] }),
+        });
+        Ok(Token::LocalId(loc.clone(), id))
+    }
+
+    /// `getelementptr inbounds? type, type* base (, type idx)*`
+    /// The first index is the offset of `ptr`, and the rest are its indices. Indices in
+    /// narrower types are extended to `i64` by instructions in `pre`.
+    fn gep_rhs(&mut self, loc: &Loc, pre: &mut Vec<Term>, tmp: &mut Temps)
+               -> Result<Term, CompileErr>
+    {
+        if let Some(Lex::Word(w)) = self.peek(0) {
+            if w == "inbounds" { self.next()?; }
+        }
+        let mut elem = self.ty()?;
+        self.expect(',')?;
+        let (_, base) = self.typed_opd()?;
+        let mut idx = vec![];
+        while let (Some(Lex::Punct(',')), Some(Lex::Word(_))) = (self.peek(0), self.peek(1)) {
+            self.next()?; // `,`
+            let (ty, opd) = self.typed_opd()?;
+            idx.push(self.sext_i64(ty, opd, loc, pre, tmp)?);
+        }
+
+        // The first index is an offset, which is omitted if it is zero
+        let mut opd = vec![base];
+        if !idx.is_empty() {
+            let off = idx.remove(0);
+            if !matches!(&off, Token::Integer(_, i) if i == "0") { opd.push(off) }
+        }
+        for i in idx.iter() {
+            elem = match self.resolve(&elem) {
+                LType::Array(_, elem) => *elem,
+                LType::Struct(field) => match i {
+                    Token::Integer(_, i) => match i.parse::<usize>().ok()
+                        .and_then(|i| field.get(i)) {
+                        Some(ty) => ty.clone(),
+                        None => return self.unsupported(&format!("structure index {}", i))
+                    }
+                    _ => return self.unsupported("non-constant structure index")
+                }
+                _ => return self.unsupported("index into non-aggregate type")
+            }
+        }
+        Ok(Term::PtrRhs {
+            loc: loc.clone(),
+            ty: Box::new(self.ty_term(&LType::Ptr(Box::new(elem)), loc)?),
+            opd: Box::new(Term::OpdList { loc: loc.clone(), list: opd }),
+            idx: if idx.is_empty() { None } else {
+                Some(Box::new(Term::IndexList {
+                    loc: loc.clone(),
+                    list: Box::new(Term::OpdList { loc: loc.clone(), list: idx }),
+                }))
+            },
+        })
+    }
+
+    /// `call attrs* type callee(args) attrs*`
+    fn call(&mut self, dst: Option<Token>, loc: &Loc, op_loc: &Loc)
+            -> Result<Vec<Term>, CompileErr>
+    {
+        while let Some(Lex::Word(w)) = self.peek(0) {
+            if is_type_start(w) { break; }
+            self.next()?;
+        }
+        let ty = match self.ty()? {
+            LType::Fn(ret, _) => *ret,
+            ty => ty
+        };
+        let f_loc = self.loc();
+        let func = match self.next()? {
+            Lex::Global(n) => Token::GlobalId(f_loc.clone(), format!("@{}", n)),
+            Lex::Local(n) => Token::LocalId(f_loc.clone(), format!("${}", n)),
+            tok => return self.syntax(vec!["{GlobalId}", "{LocalId}"], &tok)
+        };
+        self.expect('(')?;
+        let mut arg = vec![];
+        loop {
+            match self.peek(0) {
+                Some(Lex::Punct(')')) => break,
+                Some(Lex::Punct(',')) if !arg.is_empty() => { self.next()?; }
+                _ => {}
+            }
+            self.ty()?;
+            self.skip_attrib()?;
+            arg.push(self.opd()?);
+        }
+        self.next()?; // `)`
+        let call = Box::new(Term::FnCall {
+            loc: f_loc.clone(),
+            func,
+            arg: Box::new(Term::OpdList { loc: f_loc.clone(), list: arg }),
+        });
+        let instr = match (ty, dst) {
+            (LType::Void, None) => self.non_assign(Term::NoRetCall { loc: op_loc.clone(), call },
+                                                   loc),
+            (LType::Void, Some(id)) => {
+                return self.syntax(vec!["{Type}"], &Lex::Word(id.to_string()));
+            }
+            (ty, Some(id)) => Term::AssignInstr {
+                loc: loc.clone(),
+                id: vec![id],
+                rhs: Box::new(Term::CallRhs {
+                    loc: op_loc.clone(),
+                    ty: Box::new(self.ty_term(&ty, op_loc)?),
+                    call,
+                }),
+                meta: Box::new(Term::MetaList { loc: loc.clone(), list: vec![] }),
+            },
+            // Result is discarded
+            (_, None) => self.non_assign(Term::NoRetCall { loc: op_loc.clone(), call }, loc)
+        };
+        Ok(vec![instr])
+    }
+
+    fn non_assign(&self, instr: Term, loc: &Loc) -> Term {
+        Term::NonAssignInstr {
+            loc: loc.clone(),
+            instr: Box::new(instr),
+            meta: Box::new(Term::MetaList { loc: loc.clone(), list: vec![] }),
+        }
+    }
+
+    /// Skip parameter attributes, such as `noundef`, `align 4` or `byval(%T)`.
+    fn skip_attrib(&mut self) -> Result<(), CompileErr> {
+        while let Some(Lex::Word(w)) = self.peek(0) {
+            if is_const_word(w) { break; }
+            let with_int = ["align", "dereferenceable", "dereferenceable_or_null"]
+                .contains(&w.as_str());
+            self.next()?;
+            match self.peek(0) {
+                Some(Lex::Int(_)) if with_int => { self.next()?; }
+                Some(Lex::Punct('(')) => {
+                    let mut depth = 0;
+                    loop {
+                        match self.next()? {
+                            Lex::Punct('(') => depth += 1,
+                            Lex::Punct(')') if depth == 1 => break,
+                            Lex::Punct(')') => depth -= 1,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn typed_opd(&mut self) -> Result<(LType, Token), CompileErr> {
+        let ty = self.ty()?;
+        let opd = self.opd()?;
+        Ok((ty, opd))
+    }
+
+    fn opd(&mut self) -> Result<Token, CompileErr> {
+        let loc = self.loc();
+        match self.next()? {
+            Lex::Local(n) => Ok(Token::LocalId(loc, format!("${}", n))),
+            Lex::Global(n) if self.vars.contains(&n) =>
+                self.unsupported(&format!("address of global variable @{}", n)),
+            Lex::Global(n) => Ok(Token::GlobalId(loc, format!("@{}", n))),
+            Lex::Int(i) => Ok(Token::Integer(loc, i)),
+            Lex::Word(w) if w == "true" => Ok(Token::Integer(loc, "1".to_string())),
+            Lex::Word(w) if w == "false" => Ok(Token::Integer(loc, "0".to_string())),
+            Lex::Word(w) => self.unsupported(&format!("operand {}", w)),
+            tok => self.syntax(vec!["{Operand}"], &tok)
+        }
+    }
+
+    /// Consume global variable operand, if there is one.
+    fn global_var(&mut self) -> Option<Token> {
+        match self.peek(0) {
+            Some(Lex::Global(n)) if self.vars.contains(n) => {
+                let id = Token::GlobalId(self.loc(), format!("@{}", n));
+                self.pos += 1;
+                Some(id)
+            }
+            _ => None
+        }
+    }
+
+    /// `label %name`
+    fn label(&mut self) -> Result<Token, CompileErr> {
+        self.expect_word("label")?;
+        self.block_ref()
+    }
+
+    /// `%name` of block
+    fn block_ref(&mut self) -> Result<Token, CompileErr> {
+        let loc = self.loc();
+        match self.next()? {
+            Lex::Local(n) => Ok(Token::Label(loc, format!("%{}", n))),
+            tok => self.syntax(vec!["{Label}"], &tok)
+        }
+    }
+
+    fn ty(&mut self) -> Result<LType, CompileErr> {
+        let mut ty = match self.next()? {
+            Lex::Word(w) if w == "void" => LType::Void,
+            Lex::Word(w) if w == "ptr" => return self.unsupported("opaque pointer type"),
+            Lex::Word(w) if w.starts_with('i') && w[1..].parse::<u32>().is_ok() => {
+                let width = w[1..].parse().unwrap();
+                if ![1, 8, 16, 32, 64].contains(&width) { return self.unsupported(&w); }
+                LType::Int(width)
+            }
+            Lex::Local(n) => LType::Named(n),
+            Lex::Punct('[') => {
+                let len = match self.next()? {
+                    Lex::Int(i) => i.parse().unwrap(),
+                    tok => return self.syntax(vec!["{Integer}"], &tok)
+                };
+                self.expect_word("x")?;
+                let elem = self.ty()?;
+                self.expect(']')?;
+                LType::Array(len, Box::new(elem))
+            }
+            Lex::Punct('{') => {
+                let mut field = vec![];
+                loop {
+                    match self.peek(0) {
+                        Some(Lex::Punct('}')) => break,
+                        Some(Lex::Punct(',')) if !field.is_empty() => { self.next()?; }
+                        _ => {}
+                    }
+                    field.push(self.ty()?);
+                }
+                self.next()?; // `}`
+                LType::Struct(field)
+            }
+            Lex::Word(w) => return self.unsupported(&format!("type {}", w)),
+            Lex::Punct('<') => return self.unsupported("vector or packed structure type"),
+            tok => return self.syntax(vec!["{Type}"], &tok)
+        };
+        loop {
+            match self.peek(0) {
+                Some(Lex::Punct('*')) => {
+                    self.next()?;
+                    ty = LType::Ptr(Box::new(ty));
+                }
+                Some(Lex::Punct('(')) => {
+                    self.next()?;
+                    let mut param = vec![];
+                    loop {
+                        match self.peek(0) {
+                            Some(Lex::Punct(')')) => break,
+                            Some(Lex::Punct(',')) if !param.is_empty() => { self.next()?; }
+                            Some(Lex::Ellipsis) => return self.unsupported("variadic function"),
+                            _ => {}
+                        }
+                        param.push(self.ty()?);
+                    }
+                    self.next()?; // `)`
+                    ty = LType::Fn(Box::new(ty), param);
+                }
+                Some(Lex::Word(w)) if w == "addrspace" =>
+                    return self.unsupported("address space"),
+                _ => break Ok(ty)
+            }
+        }
+    }
+
+    /// Convert type to term in syntax tree.
+    fn ty_term(&self, ty: &LType, loc: &Loc) -> Result<Term, CompileErr> {
+        let term = match ty {
+            LType::Int(w) => Term::PrimType {
+                loc: loc.clone(),
+                ty: Token::Reserved(loc.clone(), format!("i{}", w)),
+            },
+            // `void*` is treated as `i8*`
+            LType::Ptr(tgt) if **tgt == LType::Void => Term::PtrType {
+                loc: loc.clone(),
+                tgt: Box::new(self.ty_term(&LType::Int(8), loc)?),
+            },
+            LType::Ptr(tgt) => Term::PtrType {
+                loc: loc.clone(),
+                tgt: Box::new(self.ty_term(tgt, loc)?),
+            },
+            LType::Array(len, elem) => Term::ArrayType {
+                loc: loc.clone(),
+                len: Token::Integer(loc.clone(), len.to_string()),
+                elem: Box::new(self.ty_term(elem, loc)?),
+            },
+            LType::Struct(field) => Term::StructType {
+                loc: loc.clone(),
+                field: Box::new(Term::TypeList {
+                    loc: loc.clone(),
+                    list: field.iter().map(|f| self.ty_term(f, loc))
+                        .collect::<Result<_, _>>()?,
+                }),
+            },
+            LType::Named(n) => Term::AliasName {
+                loc: loc.clone(),
+                id: Token::GlobalId(loc.clone(), format!("@{}", n)),
+            },
+            LType::Fn(ret, param) => Term::FnType {
+                loc: loc.clone(),
+                param: param.iter().map(|p| self.ty_term(p, loc)).collect::<Result<_, _>>()?,
+                ret: match ret.as_ref() {
+                    LType::Void => None,
+                    ret => Some(Box::new(Term::FnRet {
+                        loc: loc.clone(),
+                        ty: Box::new(self.ty_term(ret, loc)?),
+                    }))
+                },
+            },
+            LType::Void => return Err(CompileErr {
+                loc: loc.clone(),
+                kind: ErrKind::Unsupported("value of type void".to_string()),
+            })
+        };
+        Ok(Term::TypeDecl { loc: loc.clone(), ty: Box::new(term) })
+    }
+
+    /// Resolve named structure type to its definition.
+    fn resolve(&self, ty: &LType) -> LType {
+        match ty {
+            LType::Named(n) => self.types.get(n).cloned().unwrap_or(LType::Struct(vec![])),
+            ty => ty.clone()
+        }
+    }
+
+    fn goto(&mut self, line: usize) {
+        self.line = line;
+        self.pos = 0;
+    }
+
+    fn peek(&self, idx: usize) -> Option<&Lex> {
+        self.lines[self.line].get(self.pos + idx).map(|(_, l)| l)
+    }
+
+    fn next(&mut self) -> Result<Lex, CompileErr> {
+        match self.lines[self.line].get(self.pos) {
+            Some((_, l)) => {
+                self.pos += 1;
+                Ok(l.clone())
+            }
+            None => Err(CompileErr {
+                loc: self.loc(),
+                kind: ErrKind::Syntax { expect: vec![], found: "end of line".to_string() },
+            })
+        }
+    }
+
+    /// Location of next token, or the end of current line
+    fn loc(&self) -> Loc {
+        match self.lines.get(self.line).and_then(|l| l.get(self.pos).or_else(|| l.last())) {
+            Some((loc, _)) => loc.clone(),
+            None => Loc { file: None, line: self.line, col: 0 }
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), CompileErr> {
+        match self.next()? {
+            Lex::Punct(p) if p == c => Ok(()),
+            tok => self.syntax(vec![&c.to_string()], &tok)
+        }
+    }
+
+    fn expect_word(&mut self, w: &str) -> Result<(), CompileErr> {
+        match self.next()? {
+            Lex::Word(s) if s == w => Ok(()),
+            tok => self.syntax(vec![w], &tok)
+        }
+    }
+
+    fn syntax<T>(&self, expect: Vec<&str>, found: &Lex) -> Result<T, CompileErr> {
+        let mut loc = self.loc();
+        if self.pos > 0 {
+            loc = self.lines[self.line][self.pos - 1].0.clone();
+        }
+        Err(CompileErr {
+            loc,
+            kind: ErrKind::Syntax {
+                expect: expect.into_iter().map(|s| s.to_string()).collect(),
+                found: found.to_string(),
+            },
+        })
+    }
+
+    fn unsupported<T>(&self, what: &str) -> Result<T, CompileErr> {
+        Err(CompileErr { loc: self.loc(), kind: ErrKind::Unsupported(what.to_string()) })
+    }
+}
+
+/// Names of temporaries created by the importer, which should not clash with LLVM values
+struct Temps {
+    used: HashSet<String>,
+    next: usize,
+}
+
+impl Temps {
+    fn fresh(&mut self) -> String {
+        loop {
+            let name = format!("ext.{}", self.next);
+            self.next += 1;
+            if !self.used.contains(&name) { return format!("${}", name); }
+        }
+    }
+}
+
+fn linkage_of(word: &str) -> Option<&'static str> {
+    match word {
+        "private" | "internal" => Some("internal"),
+        "weak" | "weak_odr" | "linkonce" | "linkonce_odr" | "common" => Some("weak"),
+        "external" => Some("export"),
+        _ => None
+    }
+}
+
+fn is_type_start(word: &str) -> bool {
+    word == "void" || word == "ptr" || word.starts_with('i') && word[1..].parse::<u32>().is_ok()
+}
+
+fn is_const_word(word: &str) -> bool {
+    ["true", "false", "null", "undef", "poison", "zeroinitializer"].contains(&word)
+}
+
+fn tokenize(line: usize, src: &str) -> Vec<(Loc, Lex)> {
+    let chars: Vec<char> = src.chars().collect();
+    let is_name = |c: char| c.is_ascii_alphanumeric() || "-$._".contains(c);
+    let mut toks = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let loc = Loc { file: None, line, col: i };
+        // Read a name or a quoted string after position `i`
+        let name = |i: &mut usize| -> String {
+            if chars.get(*i) == Some(&'"') {
+                let start = *i + 1;
+                *i = start;
+                while *i < chars.len() && chars[*i] != '"' { *i += 1; }
+                let s = chars[start..*i].iter().collect();
+                *i += 1;
+                s
+            } else {
+                let start = *i;
+                while *i < chars.len() && is_name(chars[*i]) { *i += 1; }
+                chars[start..*i].iter().collect()
+            }
+        };
+        let lex = match c {
+            ';' => break,
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '%' | '@' | '!' | '#' => {
+                i += 1;
+                let n = name(&mut i);
+                match c {
+                    '%' => Lex::Local(n),
+                    '@' => Lex::Global(n),
+                    '!' => Lex::Meta(n),
+                    _ => Lex::Attr(n)
+                }
+            }
+            '"' => Lex::Str(name(&mut i)),
+            '.' if chars[i..].starts_with(&['.', '.', '.']) => {
+                i += 3;
+                Lex::Ellipsis
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() { i += 1; }
+                Lex::Int(chars[start..i].iter().collect())
+            }
+            c if is_name(c) => Lex::Word(name(&mut i)),
+            c => {
+                i += 1;
+                Lex::Punct(c)
+            }
+        };
+        toks.push((loc, lex));
+    }
+    toks
+}
+
+#[test]
+fn test_llvm() {
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::lang::value::Const;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+; ModuleID = 'test.c'
+source_filename = "test.c"
+target triple = "x86_64-pc-linux-gnu"
+
+%struct.Pair = type { i32, i64 }
+
+@sum = dso_local global i64 0, align 8
+@second = dso_local global i64 0, align 8
+
+define dso_local i64 @square(i64 noundef %0) #0 {
+  %2 = mul nsw i64 %0, %0
+  ret i64 %2
+}
+
+define dso_local void @main() #0 {
+entry:
+  %a = alloca [4 x i32], align 16
+  br label %fill
+
+fill:                                             ; preds = %fill, %entry
+  %i = phi i64 [ 0, %entry ], [ %next, %fill ]
+  %t = trunc i64 %i to i32
+  %p = getelementptr inbounds [4 x i32], [4 x i32]* %a, i64 0, i64 %i
+  store i32 %t, i32* %p, align 4
+  %next = add nuw nsw i64 %i, 1
+  %c = icmp slt i64 %next, 4
+  br i1 %c, label %fill, label %loop
+
+loop:                                             ; preds = %loop, %fill
+  %j = phi i32 [ 0, %fill ], [ %j.next, %loop ]
+  %s = phi i64 [ 0, %fill ], [ %s.next, %loop ]
+  %q = getelementptr inbounds [4 x i32], [4 x i32]* %a, i64 0, i32 %j
+  %v = load i32, i32* %q, align 4, !tbaa !3
+  %w = sext i32 %v to i64
+  %sq = tail call i64 @square(i64 noundef %w)
+  %s.next = add nsw i64 %s, %sq
+  %j.next = add nsw i32 %j, 1
+  %d = icmp ne i32 %j.next, 4
+  br i1 %d, label %loop, label %exit
+
+exit:                                             ; preds = %loop
+  store i64 %s.next, i64* @sum, align 8
+  %r = alloca %struct.Pair, align 8
+  %f = getelementptr inbounds %struct.Pair, %struct.Pair* %r, i64 0, i32 1
+  store i64 42, i64* %f, align 8
+  %g = load i64, i64* %f, align 8
+  store i64 %g, i64* @second, align 8
+  ret void
+}
+
+declare i32 @printf(i8*, ...)
+
+attributes #0 = { noinline nounwind }
+!3 = !{!4, !4, i64 0}
+"#;
+    let pro = Builder::new(LlvmParser::new(src).parse().unwrap()).build().unwrap();
+    Printer::new(&mut std::io::stdout()).print(&pro).unwrap();
+    let rcd = Machine::new().run(&pro).unwrap();
+    let get = |name: &str| rcd.global.iter().find(|(g, _)| g.name == name).unwrap().1
+        .get_const();
+    assert_eq!(get("sum"), Const::I64(14));
+    assert_eq!(get("second"), Const::I64(42));
+
+    // Narrower result of `@main` is sign-extended to `i64`
+    for (src, exit) in [
+        ("define dso_local i32 @main() #0 {\n  %a = sub nsw i32 3, 8\n  ret i32 %a\n}", -5),
+        ("define dso_local i32 @main() #0 {\n  ret i32 7\n}", 7),
+    ] {
+        let pro = Builder::new(LlvmParser::new(src).parse().unwrap()).build().unwrap();
+        assert_eq!(Machine::new().run(&pro).unwrap().exit, exit);
+    }
+
+    // Unsupported constructs are reported
+    for (src, what) in [
+        ("define void @f(ptr %p) {\n  ret void\n}", "opaque pointer type"),
+        ("define i32 @f(i32 %a) {\n  %b = udiv i32 %a, 2\n  ret i32 %b\n}", "instruction udiv"),
+        ("@g = external global i32", "external global variable"),
+        ("@g = global i32 0\ndefine i32* @f() {\n  ret i32* @g\n}",
+         "address of global variable @g"),
+    ].iter() {
+        let err = LlvmParser::new(src).parse().unwrap_err();
+        println!("{}", err);
+        assert!(matches!(&err.kind, ErrKind::Unsupported(s) if s == what));
+    }
+}
@@ -1,12 +1,26 @@
 use std::fmt::{Debug, Display, Error, Formatter};
+use std::sync::Arc;
 
-mod syntax;
+use crate::lang::value::Type;
+use crate::lang::verify::VerifyErr;
+
+pub mod syntax;
 pub mod lex;
 pub mod parse;
 pub mod build;
+pub mod llvm;
+pub mod incr;
 
-#[derive(Debug, Clone)]
+/// Current version of the textual syntax. Sources may declare the version they are written in
+/// with `#version` at the beginning, and those without it are assumed to be of this version.
+/// Future changes to the syntax increase this number, and sources of earlier versions are
+/// upgraded by the parser.
+pub const SYNTAX_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Loc {
+    /// Name of the source file, if the source is read from a file
+    file: Option<Arc<str>>,
     /// Line number (0-indexed) in the source file
     line: usize,
     /// Column number (0-indexed) in the source file
@@ -15,11 +29,21 @@ pub struct Loc {
 
 impl Display for Loc {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        if let Some(file) = &self.file { write!(f, "{}:", file)? }
         write!(f, "{}:{}", self.line, self.col)
     }
 }
 
 impl Loc {
+    /// Name of the source file, if the source is read from a file
+    pub fn file(&self) -> Option<&str> { self.file.as_deref() }
+
+    /// Line number (0-indexed) in the source file
+    pub fn line(&self) -> usize { self.line }
+
+    /// Column number (0-indexed) in the source file
+    pub fn col(&self) -> usize { self.col }
+
     fn shift(&mut self) { self.col += 1 }
     fn new_line(&mut self) {
         self.line += 1;
@@ -27,16 +51,203 @@ impl Loc {
     }
 }
 
+/// Kind of compilation error, with structured data of each kind
+#[derive(Debug, Clone)]
+pub enum ErrKind {
+    /// Invalid character sequence found by lexer
+    Lexical(String),
+    /// Character that cannot start any token, with the source line containing it and its
+    /// column in that line
+    UnexpectedChar { ch: char, line: String, col: usize },
+    /// Unexpected token found by parser
+    Syntax { expect: Vec<String>, found: String },
+    /// A type, variable, function or parameter is defined more than once.
+    /// `what` tells the kind of the redefined entity.
+    Redefinition { what: &'static str, name: String },
+    /// Identifier cannot be found in global or local scope
+    UndefinedSymbol { name: String, global: bool },
+    /// Type alias cannot be found
+    UndefinedType(String),
+    /// Type alias contains itself, not through a pointer, so it has infinite size
+    RecursiveType(String),
+    /// Called function cannot be found
+    UndefinedFn(String),
+    /// Block label cannot be found in the function
+    UndefinedLabel(String),
+    /// Symbol is used as a type, but it is not
+    NotType(String),
+    /// Symbol is called, but it is not a function
+    NotFn(String),
+    /// Primitive type is not recognized
+    InvalidType(String),
+    /// Type of some value is different from the expected one
+    TypeMismatch { expect: Type, found: Type },
+    /// Base operand of `ptr` is not a pointer
+    NotPointer(Type),
+    /// Type of indexed operand of `ptr` is not aggregate
+    NotAggregate(Type),
+    /// Constant index of `ptr` is out of bound of the aggregate
+    IndexOutOfRange { index: i64, len: usize },
+    /// Index into structure type is not a constant
+    NonConstIndex,
+    /// Number of operands is out of the acceptable range
+    OperandCount { min: usize, max: usize, found: usize },
+    /// Operation is not supported for values of some type
+    UnsupportedOp { op: String, ty: Type },
+    /// Cast operation is not available between two types
+    InvalidCast { op: String, from: Type, to: Type },
+    /// Operator is not recognized
+    UnknownOp(String),
+    /// Memory ordering is not recognized, or not allowed for the atomic operation
+    InvalidOrdering { op: String, ord: String },
+    /// Returned value does not match return type `Type` of the function
+    ReturnMismatch(Type),
+    /// Number of returned values, or identifiers receiving them, does not match the tuple
+    /// returned by the function
+    ReturnCount { expect: usize, found: usize },
+    /// Global variable cannot be created with this type
+    InvalidGlobal(Type),
+    /// Constant global variable is written
+    ConstVar(String),
+    /// Constant literal cannot be represented in the given type
+    InvalidConst { val: String, ty: Type },
+    /// Global variable is used as operand of phi instruction
+    NonLocalPhi(String),
+    /// Non-phi instruction appears before phi instructions in a block
+    PhiNotFirst(String),
+    /// Block is not ended with a control flow instruction
+    IncompleteBlock(String),
+    /// Jump instruction targets the entry block of function
+    JumpToEntry(String),
+    /// Function `@main` is called
+    CallMain,
+    /// Function `@main` has parameters other than `(i64, **i8)`, or returns value other than
+    /// `i64`
+    InvalidMain,
+    /// Entry function other than `@main` has signature not allowed for `@main`
+    InvalidEntry(String),
+    /// Function attribute is not recognized
+    InvalidAttrib(String),
+    /// Function attribute is specified more than once
+    DuplicatedAttrib(String),
+    /// Two function attributes cannot be specified together
+    ConflictingAttrib(String, String),
+    /// Metadata key is specified more than once
+    DuplicatedMeta(String),
+    /// Metadata integer value is out of range
+    MetaOutOfRange(String),
+    /// Function assumed to be in SSA form fails verification
+    NotSsa(Box<VerifyErr>),
+    /// Construct of imported source cannot be represented in this language
+    Unsupported(String),
+    /// Version of syntax declared by `#version` is not supported
+    UnsupportedVersion(String),
+    /// Source file cannot be opened
+    Io(String),
+}
+
+impl Display for ErrKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            ErrKind::Lexical(msg) => write!(f, "{}", msg),
+            ErrKind::UnexpectedChar { ch, line, col } => {
+                // Keep tabs in the line, so that the caret is aligned with the character
+                let pad: String = line.chars().take(*col)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+                write!(f, "unexpected character {:?} ({:#04x})\n{}\n{}^", ch, *ch as u32, line, pad)
+            }
+            ErrKind::Syntax { expect, found } =>
+                write!(f, "expect {:?}, found \"{}\"", expect, found),
+            ErrKind::Redefinition { what, name } => write!(f, "{} {} already defined", what, name),
+            ErrKind::UndefinedSymbol { name, global } => {
+                let scope = if *global { "global" } else { "local" };
+                write!(f, "identifier {} not found in {} scope", name, scope)
+            }
+            ErrKind::UndefinedType(name) => write!(f, "type {} not found", name),
+            ErrKind::RecursiveType(name) =>
+                write!(f, "type {} contains itself without indirection", name),
+            ErrKind::UndefinedFn(name) => write!(f, "function {} not found", name),
+            ErrKind::UndefinedLabel(name) => write!(f, "label {} not found", name),
+            ErrKind::NotType(name) => write!(f, "{} is not a type", name),
+            ErrKind::NotFn(name) => write!(f, "symbol {} is not a function", name),
+            ErrKind::InvalidType(name) => write!(f, "unknown type {}", name),
+            ErrKind::TypeMismatch { expect, found } =>
+                write!(f, "expect type {}, got {}", expect.to_string(), found.to_string()),
+            ErrKind::NotPointer(ty) => write!(f, "expect pointer type, got {}", ty.to_string()),
+            ErrKind::NotAggregate(ty) => write!(f, "type {} is not aggregate", ty.to_string()),
+            ErrKind::IndexOutOfRange { index, len } =>
+                write!(f, "index {} out of range {}", index, len),
+            ErrKind::NonConstIndex => write!(f, "index into structure type is not constant"),
+            ErrKind::OperandCount { min, max, found } if min == max =>
+                write!(f, "expect {} operand(s), got {}", min, found),
+            ErrKind::OperandCount { min, max, found } =>
+                write!(f, "expect {} to {} operands, got {}", min, max, found),
+            ErrKind::UnsupportedOp { op, ty } =>
+                write!(f, "operation {} not supported for type {}", op, ty.to_string()),
+            ErrKind::InvalidCast { op, from, to } =>
+                write!(f, "cannot {} value of type {} to {}", op, from.to_string(), to.to_string()),
+            ErrKind::UnknownOp(op) => write!(f, "unknown operator {}", op),
+            ErrKind::InvalidOrdering { op, ord } =>
+                write!(f, "invalid memory ordering {} for {}", ord, op),
+            ErrKind::ReturnMismatch(Type::Void) => write!(f, "expect void, got value"),
+            ErrKind::ReturnMismatch(ty) =>
+                write!(f, "expect value of type {}, got void", ty.to_string()),
+            ErrKind::ReturnCount { expect, found } =>
+                write!(f, "expect {} returned value(s), got {}", expect, found),
+            ErrKind::InvalidGlobal(ty) =>
+                write!(f, "cannot create global variable of type {}", ty.to_string()),
+            ErrKind::ConstVar(name) => write!(f, "cannot write to constant variable {}", name),
+            ErrKind::InvalidConst { val, ty } =>
+                write!(f, "cannot create constant {} of type {}", val, ty.to_string()),
+            ErrKind::NonLocalPhi(name) =>
+                write!(f, "global variable {} cannot be used in phi instruction", name),
+            ErrKind::PhiNotFirst(block) =>
+                write!(f, "non-phi instruction found before phi's in block {}", block),
+            ErrKind::IncompleteBlock(block) => write!(f, "block {} is not complete", block),
+            ErrKind::JumpToEntry(block) => write!(f, "cannot jump to function entry {}", block),
+            ErrKind::CallMain => write!(f, "cannot call function @main"),
+            ErrKind::InvalidMain =>
+                write!(f, "function @main should take no parameter or (i64, **i8), and return \
+                           nothing or i64"),
+            ErrKind::InvalidEntry(name) =>
+                write!(f, "entry function @{} should have the same signature as @main", name),
+            ErrKind::InvalidAttrib(name) => write!(f, "invalid function attribute {}", name),
+            ErrKind::DuplicatedAttrib(name) => write!(f, "duplicated attribute {}", name),
+            ErrKind::ConflictingAttrib(a, b) => write!(f, "attribute {} conflicts with {}", a, b),
+            ErrKind::DuplicatedMeta(name) => write!(f, "duplicated metadata {}", name),
+            ErrKind::MetaOutOfRange(val) => write!(f, "metadata value {} out of range", val),
+            ErrKind::NotSsa(err) => write!(f, "{}", err),
+            ErrKind::Unsupported(what) => write!(f, "{} is not supported", what),
+            ErrKind::UnsupportedVersion(ver) =>
+                write!(f, "syntax version {} is not supported, expect 1 to {}", ver,
+                       SYNTAX_VERSION),
+            ErrKind::Io(msg) => write!(f, "cannot read source: {}", msg),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CompileErr {
     /// Where this error starts in the source file
     loc: Loc,
     /// What causes this error
-    msg: String,
+    kind: ErrKind,
 }
 
 impl Display for CompileErr {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        write!(f, "{}\t{}", self.loc, self.msg)
+        write!(f, "{}\t{}", self.loc, self.kind)
     }
 }
+
+impl std::error::Error for CompileErr {}
+
+impl CompileErr {
+    pub fn new(loc: Loc, kind: ErrKind) -> CompileErr { CompileErr { loc, kind } }
+
+    /// Where this error starts in the source file
+    pub fn loc(&self) -> &Loc { &self.loc }
+
+    /// What causes this error
+    pub fn kind(&self) -> &ErrKind { &self.kind }
+}
@@ -1,13 +1,21 @@
 use std::collections::VecDeque;
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
 
-use crate::irc::{CompileErr, Loc};
+use crate::irc::{CompileErr, ErrKind, Loc, SYNTAX_VERSION};
 use crate::irc::lex::Lexer;
 use crate::irc::syntax::{Term, Token};
+use crate::lang::inst::BinOp;
 
-pub struct Parser {
-    lexer: Lexer,
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
     buf: VecDeque<Token>,
     loc: Loc,
+    /// Whether to recover from syntax errors and continue parsing
+    recover: bool,
+    /// Errors found in error-recovery mode
+    err: Vec<CompileErr>,
 }
 
 type ParseResult = Result<Term, CompileErr>;
@@ -20,36 +28,171 @@ macro_rules! check_op {
     };
 }
 
-impl Parser {
+/// Construct parser from source string. This never fails, and the error type is chosen so that
+/// it could be used along with `Parser::from_path`.
+impl FromStr for Parser<'_> {
+    type Err = CompileErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> { Ok(Parser::new(Lexer::from(s))) }
+}
+
+impl<'a> Parser<'a> {
     /// Construct parser from lexer object
-    pub fn new(lexer: Lexer) -> Parser {
+    pub fn new(lexer: Lexer<'a>) -> Parser<'a> {
         Parser {
             lexer,
             buf: VecDeque::new(),
-            loc: Loc { line: 0, col: 0 },
+            loc: Loc { file: None, line: 0, col: 0 },
+            recover: false,
+            err: vec![],
+        }
+    }
+
+    /// Construct parser reading source file at `path` incrementally. The path is recorded in
+    /// locations of tokens and errors, including the error if the file cannot be opened.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Parser<'a>, CompileErr> {
+        let name = path.as_ref().to_string_lossy();
+        match File::open(path.as_ref()) {
+            Ok(file) => Ok(Parser::new(Lexer::from_read(file).with_file(&name))),
+            Err(e) => Err(CompileErr {
+                loc: Loc { file: Some(name.as_ref().into()), line: 0, col: 0 },
+                kind: ErrKind::Io(e.to_string()),
+            })
         }
     }
 
     /// Parse the source file from token stream.
     /// `Ok(t)` if the source is successfully parsed, or `Err(e)` if some syntax error is found.
-    pub fn parse(mut self) -> Result<Term, CompileErr> {
+    pub fn parse(self) -> Result<Term, CompileErr> {
+        self.parse_prog(false).map_err(|mut err| err.remove(0))
+    }
+
+    /// Parse the source file in error-recovery mode. If a syntax error is found in an
+    /// instruction, the parser skips to the next line. Otherwise, it skips to the next top level
+    /// definition. `Err(v)` contains all the errors found in the source.
+    pub fn parse_all(self) -> Result<Term, Vec<CompileErr>> { self.parse_prog(true) }
+
+    fn parse_prog(mut self, recover: bool) -> Result<Term, Vec<CompileErr>> {
+        self.recover = recover;
+        self.version().map_err(|e| vec![e])?;
         let mut def = Vec::new();
         loop {
-            let term = match self.peek(0)? {
-                Token::GlobalId(_, _) => self.var_def()?,
+            match self.top_def() {
+                Ok(Some(term)) => def.push(term),
+                Ok(None) => break,
+                Err(e) if self.recover => {
+                    self.record(e);
+                    self.sync_top();
+                }
+                Err(e) => return Err(vec![e])
+            }
+        }
+        if self.err.is_empty() { Ok(Term::Program { def }) } else { Err(self.err) }
+    }
+
+    /// Parse the version directive at the beginning of source, if there is one. Returns the
+    /// version of syntax, which should be at most `SYNTAX_VERSION`.
+    fn version(&mut self) -> Result<u32, CompileErr> {
+        match self.peek(0)? {
+            Token::Directive(_, d) if d == "#version" => { self.consume()?; }
+            _ => return Ok(SYNTAX_VERSION)
+        }
+        match self.consume()? {
+            Token::Integer(loc, ver) => match ver.parse() {
+                Ok(v) if (1..=SYNTAX_VERSION).contains(&v) => Ok(v),
+                _ => Err(CompileErr { loc, kind: ErrKind::UnsupportedVersion(ver) })
+            }
+            tok => self.err(vec!["{Integer}"], tok).map(|_| SYNTAX_VERSION)
+        }
+    }
+
+    /// Parse one top level definition, or return `None` if the end of source is reached.
+    fn top_def(&mut self) -> Result<Option<Term>, CompileErr> {
+        let term = match self.peek(0)? {
+            Token::GlobalId(_, _) => self.var_def()?,
+            Token::Reserved(_, k) if &k == "const" => self.var_def()?,
+            Token::Reserved(_, k) if Self::is_linkage(&k) => match self.peek(1)? {
                 Token::Reserved(_, k) if &k == "fn" => self.fn_def()?,
-                Token::LeftSquare(_) => self.fn_def()?,
-                Token::Reserved(_, k) if &k == "type" => self.alias_def()?,
-                Token::Eof(_) => break,
-                tok => self.err(vec!["{GlobalId}", "fn", "type", "Eof"], tok)?
-            };
-            def.push(term);
+                _ => self.var_def()?
+            }
+            Token::Reserved(_, k) if &k == "fn" => self.fn_def()?,
+            Token::LeftSquare(_) => self.fn_def()?,
+            Token::Reserved(_, k) if &k == "type" => self.alias_def()?,
+            Token::Eof(_) => return Ok(None),
+            tok => self.err(vec!["{GlobalId}", "{Linkage}", "const", "fn", "type", "Eof"], tok)?
+        };
+        Ok(Some(term))
+    }
+
+    /// Whether the reserved word `k` is a linkage
+    fn is_linkage(k: &str) -> bool { k == "export" || k == "internal" || k == "weak" }
+
+    /// Whether the reserved word `k` could only start a top level definition
+    fn is_top_level(k: &str) -> bool {
+        k == "fn" || k == "type" || k == "const" || Self::is_linkage(k)
+    }
+
+    /// Whether token `tok` could start an instruction
+    fn is_instr_start(tok: &Token) -> bool {
+        match tok {
+            Token::Reserved(_, k) => !Self::is_top_level(k),
+            tok => tok.is_id()
+        }
+    }
+
+    /// Record an error in error-recovery mode. If it is a lexical error, the lexer skips the
+    /// rest of the line.
+    fn record(&mut self, e: CompileErr) {
+        if let ErrKind::Lexical(_) | ErrKind::UnexpectedChar { .. } = e.kind {
+            self.lexer.recover()
+        }
+        self.err.push(e);
+    }
+
+    /// Skip tokens until the beginning of the next top level definition. Global identifiers
+    /// and attribute lists only start a definition at the beginning of a line.
+    fn sync_top(&mut self) {
+        loop {
+            match self.peek(0) {
+                Ok(Token::Eof(_)) => return,
+                Ok(Token::Reserved(_, k)) if Self::is_top_level(&k) => return,
+                Ok(Token::GlobalId(l, _)) | Ok(Token::LeftSquare(l)) if l.col == 0 => return,
+                Ok(_) => { self.buf.pop_front(); }
+                Err(e) => self.record(e)
+            }
+        }
+    }
+
+    /// Skip the remaining tokens in line `line`, where an error is found.
+    fn sync_line(&mut self, line: usize) {
+        loop {
+            match self.peek(0) {
+                Ok(Token::Eof(_)) => return,
+                Ok(tok) if tok.loc().line <= line => { self.buf.pop_front(); }
+                Ok(_) => return,
+                Err(e) => self.record(e)
+            }
+        }
+    }
+
+    /// Parse linkage of a definition, if there is one.
+    fn linkage(&mut self) -> Result<Option<Token>, CompileErr> {
+        match self.peek(0)? {
+            Token::Reserved(_, k) if Self::is_linkage(&k) => Ok(Some(self.consume()?)),
+            _ => Ok(None)
         }
-        Ok(Term::Program { def })
     }
 
     fn var_def(&mut self) -> ParseResult {
         let loc = self.loc.clone();
+        let linkage = self.linkage()?;
+        let is_const = match self.peek(0)? {
+            Token::Reserved(_, k) if &k == "const" => {
+                self.consume()?; // `const`
+                true
+            }
+            _ => false
+        };
         let id = self.consume()?; // GlobalId
         if let Token::GlobalId(_, _) = id {} else {
             return self.err(vec!["{GlobalId}"], id);
@@ -68,7 +211,7 @@ impl Parser {
             }
             _ => None,
         };
-        Ok(Term::VarDef { loc, id, init, ty: Box::new(ty) })
+        Ok(Term::VarDef { loc, linkage, is_const, id, init, ty: Box::new(ty) })
     }
 
     fn alias_def(&mut self) -> ParseResult {
@@ -90,13 +233,22 @@ impl Parser {
             Token::LeftSquare(_) => Some(Box::new(self.fn_attrib_list()?)),
             _ => None
         };
+        let linkage = self.linkage()?;
         match self.consume()? {
             Token::Reserved(_, k) if &k == "fn" => (),
             k => return self.err(vec!["fn"], k)
         }
         let sig = self.fn_sig()?; // FnSig
+        let meta = self.meta_list()?; // MetaList
         let body = self.fn_body()?; // FnBody
-        Ok(Term::FnDef { loc, attrib, sig: Box::new(sig), body: Box::new(body) })
+        Ok(Term::FnDef {
+            loc,
+            attrib,
+            linkage,
+            sig: Box::new(sig),
+            meta: Box::new(meta),
+            body: Box::new(body),
+        })
     }
 
     fn fn_attrib_list(&mut self) -> ParseResult {
@@ -136,8 +288,8 @@ impl Parser {
         let ret: Option<Term>;
         match self.peek(0)? { // FnRet?
             Token::RightArrow(_) => ret = Some(self.fn_ret()?),
-            Token::LeftCurly(_) => ret = None,
-            tok => return self.err(vec!["->", "{"], tok)
+            Token::LeftCurly(_) | Token::Meta(_, _) => ret = None,
+            tok => return self.err(vec!["->", "{Meta}", "{"], tok)
         }
         Ok(Term::FnSig {
             loc,
@@ -180,10 +332,37 @@ impl Parser {
         let loc = self.loc.clone();
         let right_arr = self.consume()?;
         check_op!(self, right_arr, "->");
-        let ty = self.type_decl()?;
+        let ty = self.ret_type()?;
         Ok(Term::FnRet { loc, ty: Box::new(ty) })
     }
 
+    /// Parse return type, which could be a tuple.
+    fn ret_type(&mut self) -> ParseResult {
+        match self.peek(0)? {
+            Token::LeftParent(_) => {
+                let loc = self.loc.clone();
+                let ty = self.tuple_type()?;
+                Ok(Term::TypeDecl { loc, ty: Box::new(ty) })
+            }
+            _ => self.type_decl()
+        }
+    }
+
+    fn tuple_type(&mut self) -> ParseResult {
+        let loc = self.loc.clone();
+        self.consume()?; // `(`
+        let mut elem = vec![self.type_decl()?];
+        loop {
+            match self.consume()? {
+                Token::Comma(_) => elem.push(self.type_decl()?),
+                Token::RightParent(_) if elem.len() > 1 => break,
+                tok if elem.len() > 1 => return self.err(vec![",", ")"], tok),
+                tok => return self.err(vec![","], tok)
+            }
+        }
+        Ok(Term::TupleType { loc, elem })
+    }
+
     fn fn_body(&mut self) -> ParseResult {
         let loc = self.loc.clone();
         let left_cur = self.consume()?;
@@ -216,20 +395,55 @@ impl Parser {
         }
         let col = self.consume()?;
         check_op!(self, col, ":");
+        let meta = self.meta_list()?; // MetaList
         let mut instr = Vec::new();
+        let mut count = 0; // number of instructions, including those with errors
         loop {
             match self.peek(0)? {
-                id if id.is_id() => instr.push(self.instr_def()?), // AssignInstr
-                Token::Reserved(_, _) => instr.push(self.instr_def()?), // CtrlInstr
-                Token::Label(_, _) | Token::RightCurly(_) if !instr.is_empty() => break,
+                tok if Self::is_instr_start(&tok) => { // AssignInstr | CtrlInstr
+                    count += 1;
+                    match self.instr_def() {
+                        Ok(term) => instr.push(term),
+                        Err(e) if self.recover => {
+                            let line = e.loc.line;
+                            self.record(e);
+                            self.sync_line(line);
+                        }
+                        Err(e) => return Err(e)
+                    }
+                }
+                Token::Label(_, _) | Token::RightCurly(_) if count > 0 => break,
                 tok => {
                     let mut expect = vec!["{Id}", "{Reserved}"];
-                    if !instr.is_empty() { expect.append(&mut vec!["{Label}", "}"]) }
-                    return self.err(expect, tok);
+                    if count > 0 { expect.append(&mut vec!["{Label}", "}"]) }
+                    let e = self.err(expect, tok.clone()).unwrap_err();
+                    match tok {
+                        // Extra tokens after an instruction are skipped in error-recovery mode
+                        Token::Reserved(_, _) | Token::Eof(_) => return Err(e),
+                        _ if self.recover => {
+                            self.record(e);
+                            self.sync_line(tok.loc().line);
+                        }
+                        _ => return Err(e)
+                    }
                 }
             }
         }
-        Ok(Term::BlockDef { loc, id: lab, instr })
+        Ok(Term::BlockDef { loc, id: lab, meta: Box::new(meta), instr })
+    }
+
+    fn meta_list(&mut self) -> ParseResult {
+        let loc = self.loc.clone();
+        let mut list = vec![];
+        while let Token::Meta(_, _) = self.peek(0)? {
+            let key = self.consume()?; // Meta
+            let val = self.consume()?; // MetaVal
+            match val {
+                Token::Integer(_, _) | Token::Str(_, _) => list.push((key, val)),
+                tok => return self.err(vec!["{Integer}", "{Str}"], tok)
+            }
+        }
+        Ok(Term::MetaList { loc, list })
     }
 
     fn instr_def(&mut self) -> ParseResult {
@@ -242,12 +456,29 @@ impl Parser {
 
     fn assign_instr(&mut self) -> ParseResult {
         let loc = self.loc.clone();
-        let id = self.consume()?; // Id
-        if !id.is_id() { return self.err(vec!["{Id}"], id); }
+        let mut id = vec![];
+        loop {
+            let tok = self.consume()?; // Id
+            if !tok.is_id() { return self.err(vec!["{Id}"], tok); }
+            id.push(tok);
+            match self.peek(0)? {
+                Token::Comma(_) => { self.consume()?; }
+                _ => break
+            }
+        }
         let arr = self.consume()?;
         check_op!(self, arr, "<-");
-        let expr = self.assign_rhs()?;
-        Ok(Term::AssignInstr { loc, id, rhs: Box::new(expr) })
+        // Only calls and checked arithmetic could assign to more than one identifier
+        let expr = match self.peek(0)? {
+            Token::Reserved(_, k) if id.len() > 1 && &k != "call"
+                && !BinOp::from_str(&k).is_ok_and(|op| op.is_ov()) => {
+                let tok = self.consume()?;
+                return self.err(vec!["call"], tok);
+            }
+            _ => self.assign_rhs()?
+        };
+        let meta = self.meta_list()?;
+        Ok(Term::AssignInstr { loc, id, rhs: Box::new(expr), meta: Box::new(meta) })
     }
 
     fn assign_rhs(&mut self) -> ParseResult {
@@ -258,6 +489,9 @@ impl Parser {
                 "ptr" => self.ptr_rhs(),
                 "alloc" => self.alloc_rhs(),
                 "new" => self.new_rhs(),
+                "zext" | "sext" | "trunc" | "ptrtoint" | "inttoptr" => self.cast_rhs(),
+                "ld.atomic" => self.atomic_rhs(),
+                op if op.starts_with("rmw.") => self.atomic_rhs(),
                 _ => self.common_rhs()
             }
             tok => self.err(vec!["{Reserved}"], tok)
@@ -267,7 +501,7 @@ impl Parser {
     fn call_rhs(&mut self) -> ParseResult {
         let loc = self.loc.clone();
         self.consume()?; // `call`
-        let ty = self.type_decl()?;
+        let ty = self.ret_type()?;
         let call = self.fn_call()?;
         Ok(Term::CallRhs { loc, ty: Box::new(ty), call: Box::new(call) })
     }
@@ -334,6 +568,30 @@ impl Parser {
         Ok(Term::CommonRhs { loc, name, ty: Box::new(ty), opd: Box::new(opd) })
     }
 
+    fn cast_rhs(&mut self) -> ParseResult {
+        let loc = self.loc.clone();
+        let name = self.consume()?; // Reserved
+        let ty = self.type_decl()?; // TypeDecl
+        let opd = self.consume()?; // Opd
+        if !opd.is_opd() { return self.err(vec!["Operand"], opd); }
+        let arrow = self.consume()?;
+        check_op!(self, arrow, "->");
+        let tgt = self.type_decl()?; // TypeDecl
+        Ok(Term::CastRhs { loc, name, ty: Box::new(ty), opd, tgt: Box::new(tgt) })
+    }
+
+    fn atomic_rhs(&mut self) -> ParseResult {
+        let loc = self.loc.clone();
+        let name = self.consume()?; // Reserved
+        let ord = self.consume()?;
+        if let Token::Reserved(_, _) = ord {} else {
+            return self.err(vec!["{Reserved}"], ord);
+        }
+        let ty = self.type_decl()?; // TypeDecl
+        let opd = self.opd_list()?; // OpdList
+        Ok(Term::AtomicRhs { loc, name, ord, ty: Box::new(ty), opd: Box::new(opd) })
+    }
+
     fn opd_list(&mut self) -> ParseResult {
         let loc = self.loc.clone();
         let mut list = Vec::new();
@@ -385,9 +643,7 @@ impl Parser {
     fn fn_call(&mut self) -> ParseResult {
         let loc = self.loc.clone();
         let func = self.consume()?;
-        if let Token::GlobalId(_, _) = func {} else {
-            return self.err(vec!["{GlobalId}"], func);
-        }
+        if !func.is_id() { return self.err(vec!["{Id}"], func); }
         let left = self.consume()?;
         check_op!(self, left, "(");
         let arg = self.opd_list()?;
@@ -404,20 +660,45 @@ impl Parser {
             Token::Reserved(_, k) if &k == "call" => self.no_ret_call()?,
             Token::Reserved(_, k) if &k == "br" => self.br_instr()?,
             Token::Reserved(_, k) if &k == "st" => self.st_instr()?,
-            tok => self.err(vec!["ret", "jmp", "call", "br", "st"], tok)?
+            Token::Reserved(_, k) if &k == "memcpy" || &k == "memset" => self.mem_instr()?,
+            Token::Reserved(_, k) if &k == "st.atomic" => self.atomic_st_instr()?,
+            Token::Reserved(loc, k) if &k == "unreachable" => {
+                self.consume()?;
+                Term::UnreachableInstr { loc }
+            }
+            Token::Reserved(_, k) if &k == "abort" => self.abort_instr()?,
+            tok => self.err(vec!["ret", "jmp", "call", "br", "st", "memcpy", "memset",
+                                 "st.atomic", "unreachable", "abort"], tok)?
         };
-        Ok(Term::NonAssignInstr { loc, instr: Box::new(ctrl) })
+        let meta = self.meta_list()?;
+        Ok(Term::NonAssignInstr { loc, instr: Box::new(ctrl), meta: Box::new(meta) })
     }
 
     fn ret_instr(&mut self) -> ParseResult {
         let loc = self.loc.clone();
         self.consume()?; // `ret`
-        let opd = if self.peek(0)?.is_opd() {
-            Some(self.consume()?)
-        } else { None };
+        let mut opd = vec![];
+        if self.peek(0)?.is_opd() {
+            opd.push(self.consume()?);
+            while let Token::Comma(_) = self.peek(0)? {
+                self.consume()?; // `,`
+                let tok = self.consume()?;
+                if !tok.is_opd() { return self.err(vec!["{Operand}"], tok); }
+                opd.push(tok);
+            }
+        }
         Ok(Term::RetInstr { loc, opd })
     }
 
+    fn abort_instr(&mut self) -> ParseResult {
+        let loc = self.loc.clone();
+        self.consume()?; // `abort`
+        match self.consume()? {
+            Token::Str(l, s) => Ok(Term::AbortInstr { loc, msg: Token::Str(l, s) }),
+            tok => self.err(vec!["{String}"], tok)
+        }
+    }
+
     fn jmp_instr(&mut self) -> ParseResult {
         let loc = self.loc.clone();
         self.consume()?; // `jmp`
@@ -467,10 +748,50 @@ impl Parser {
         Ok(Term::StInstr { loc, ty: Box::new(ty), src, dst })
     }
 
+    fn mem_instr(&mut self) -> ParseResult {
+        let loc = self.loc.clone();
+        let op = self.consume()?; // `memcpy` or `memset`
+        let ty = self.type_decl()?;
+        let src = self.consume()?;
+        if !src.is_opd() { return self.err(vec!["Operand"], src); }
+        let arrow = self.consume()?;
+        check_op!(self, arrow, "->");
+        let dst = self.consume()?;
+        if !dst.is_opd() { return self.err(vec!["Operand"], dst); }
+        let comma = self.consume()?;
+        check_op!(self, comma, ",");
+        let len = self.consume()?;
+        if !len.is_opd() { return self.err(vec!["Operand"], len); }
+        Ok(Term::MemInstr { loc, op, ty: Box::new(ty), src, dst, len })
+    }
+
+    fn atomic_st_instr(&mut self) -> ParseResult {
+        let loc = self.loc.clone();
+        self.consume()?; // `st.atomic`
+        let ord = self.consume()?;
+        if let Token::Reserved(_, _) = ord {} else {
+            return self.err(vec!["{Reserved}"], ord);
+        }
+        let ty = self.type_decl()?;
+        let src = self.consume()?;
+        if !src.is_opd() { return self.err(vec!["Operand"], src); }
+        let arrow = self.consume()?;
+        check_op!(self, arrow, "->");
+        let dst = self.consume()?;
+        if !dst.is_opd() { return self.err(vec!["Operand"], dst); }
+        Ok(Term::AtomicStInstr { loc, ord, ty: Box::new(ty), src, dst })
+    }
+
     fn type_decl(&mut self) -> ParseResult {
         let loc = self.loc.clone();
         let ty = match self.peek(0)? {
-            Token::Reserved(_, _) => self.prim_type(),
+            // Only parse as function type if followed by parameters, so that a missing type
+            // before function definition could be recovered from.
+            Token::Reserved(_, k) if &k == "fn"
+                && matches!(self.peek(1), Ok(Token::LeftParent(_))) => self.fn_type(),
+            Token::Reserved(_, k) if &k == "union"
+                && matches!(self.peek(1), Ok(Token::LeftCurly(_))) => self.union_type(),
+            Token::Reserved(_, k) if !Self::is_top_level(&k) => self.prim_type(),
             Token::GlobalId(_, _) => self.alias_type(),
             Token::Asterisk(_) => self.ptr_type(),
             Token::LeftSquare(_) => self.array_type(),
@@ -527,6 +848,42 @@ impl Parser {
         Ok(Term::StructType { loc, field: Box::new(field) })
     }
 
+    fn union_type(&mut self) -> ParseResult {
+        let loc = self.loc.clone();
+        self.consume()?; // `union`
+        let left = self.consume()?;
+        check_op!(self, left, "{");
+        let field = self.type_list()?;
+        let right = self.consume()?;
+        check_op!(self, right, "}");
+        Ok(Term::UnionType { loc, field: Box::new(field) })
+    }
+
+    fn fn_type(&mut self) -> ParseResult {
+        let loc = self.loc.clone();
+        self.consume()?; // `fn`
+        let left = self.consume()?;
+        check_op!(self, left, "(");
+        let mut param = vec![];
+        loop {
+            match self.peek(0)? {
+                Token::RightParent(_) => break,
+                Token::Comma(_) if !param.is_empty() => {
+                    self.consume()?; // `,`
+                    param.push(self.type_decl()?);
+                }
+                _ if param.is_empty() => param.push(self.type_decl()?),
+                tok => return self.err(vec![",", ")"], tok)
+            }
+        }
+        self.consume()?; // `)`
+        let ret = match self.peek(0)? {
+            Token::RightArrow(_) => Some(Box::new(self.fn_ret()?)),
+            _ => None
+        };
+        Ok(Term::FnType { loc, param, ret })
+    }
+
     fn type_list(&mut self) -> ParseResult {
         let loc = self.loc.clone();
         let mut list = vec![];
@@ -547,7 +904,7 @@ impl Parser {
     fn consume(&mut self) -> Result<Token, CompileErr> {
         let tok = match self.buf.pop_front() {
             Some(l) => l,
-            None => self.lexer.next()?
+            None => self.lexer.next_token()?
         };
         self.loc = tok.loc().clone();
         Ok(tok)
@@ -557,7 +914,7 @@ impl Parser {
     fn peek(&mut self, idx: usize) -> Result<Token, CompileErr> {
         if idx >= self.buf.len() {
             for _ in 0..(idx - self.buf.len() + 1) {
-                self.buf.push_back(self.lexer.next()?)
+                self.buf.push_back(self.lexer.next_token()?)
             }
         }
         let lex = self.buf[idx].clone();
@@ -566,10 +923,18 @@ impl Parser {
     }
 
     /// Report error with current location
-    fn err(&self, exp: Vec<&str>, fnd: Token) -> ParseResult {
+    fn err(&mut self, exp: Vec<&str>, fnd: Token) -> ParseResult {
+        // In error-recovery mode, put the unexpected token back to the stream if it has been
+        // consumed, so that synchronization can start from it.
+        if self.recover && self.buf.front().map(|t| t.loc()) != Some(fnd.loc()) {
+            self.buf.push_front(fnd.clone())
+        }
         Err(CompileErr {
             loc: self.loc.clone(),
-            msg: format!("expect {:?}, found \"{}\"", exp, fnd.to_string()),
+            kind: ErrKind::Syntax {
+                expect: exp.iter().map(|e| e.to_string()).collect(),
+                found: fnd.to_string(),
+            },
         })
     }
 }
@@ -584,4 +949,49 @@ fn test_parse() {
     let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
     let parser = Parser::new(lexer);
     println!("{:#?}", parser.parse())
-}
\ No newline at end of file
+}
+#[test]
+fn test_parse_all() {
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut file = File::open("test/err.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let err = Parser::new(lexer).parse_all().err().unwrap();
+    for e in &err { println!("{}", e) }
+    let lines: Vec<_> = err.iter().map(|e| e.loc().line()).collect();
+    assert_eq!(lines, vec![3, 7, 9, 10, 16, 19]);
+}
+
+#[test]
+fn test_version() {
+    let parse = |src: &str| Parser::new(Lexer::from(src)).parse();
+    let body = "\nfn @main() {\n%Begin:\n    ret\n}\n";
+    assert!(parse(body).is_ok());
+    assert!(parse(&format!("#version 1\n{}", body)).is_ok());
+    let err = parse(&format!("#version 3\n{}", body)).err().unwrap();
+    assert!(matches!(&err.kind, ErrKind::UnsupportedVersion(v) if v == "3"));
+    assert_eq!(err.loc().line(), 0);
+    assert!(parse(&format!("#foo 1\n{}", body)).is_err());
+}
+
+#[test]
+fn test_from_path() {
+    use crate::lang::Program;
+
+    // Locations of errors contain name of the source file
+    let err = Parser::from_path("test/err.ir").unwrap().parse_all().err().unwrap();
+    assert!(err.iter().all(|e| e.loc().file() == Some("test/err.ir")));
+    assert!(err[0].to_string().starts_with("test/err.ir:3:"));
+    let err = Parser::from_path("test/none.ir").err().unwrap();
+    assert!(matches!(err.kind(), ErrKind::Io(_)));
+    assert_eq!(err.loc().file(), Some("test/none.ir"));
+    assert!(Program::from_file("test/thread.ir").is_ok());
+    let err = Program::from_file("test/err.ir").err().unwrap();
+    assert_eq!(err.loc().file(), Some("test/err.ir"));
+
+    // Sources from strings have no file name
+    let err = Parser::from_str("fn @main() {").unwrap().parse().err().unwrap();
+    assert_eq!(err.loc().file(), None);
+}
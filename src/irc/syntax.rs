@@ -4,34 +4,56 @@ use crate::irc::Loc;
 /// Technically speaking, this is an LL(2) grammar.
 #[derive(Clone, Debug)]
 pub enum Term {
-    /// Program : ( VarDef | AliasDef | FnDef)* ;
-    /// FIRST = { GlobalId -> VarDef, { `[`, `fn` } -> FnDef, `type` -> AliasDef, `` }
+    /// Program : Version? ( VarDef | AliasDef | FnDef)* ;
+    /// FIRST = { { Linkage, `const`, GlobalId } -> VarDef, { `[`, Linkage, `fn` } -> FnDef,
+    ///     `type` -> AliasDef, `` }
+    /// Linkage is followed by `fn` in FnDef, or other tokens in VarDef.
     /// FOLLOW = { EOF }
     Program { def: Vec<Term> },
 
-    /// VarDef : GlobalId ( `<-` Integer )? `:`  TypeDecl `;` ;
-    /// FIRST = { GlobalId }
-    VarDef { loc: Loc, id: Token, init: Option<Token>, ty: Box<Term> },
+    /// Version : `#version` Integer ;
+    /// Version of the syntax, which could only appear at the beginning of source. It is checked
+    /// by the parser, and not kept in the syntax tree.
+
+    /// Linkage : `export` | `internal` | `weak` ;
+
+    /// VarDef : Linkage? `const`? GlobalId `:` TypeDecl ( `<-` Integer )? `;` ;
+    /// FIRST = { Linkage, `const`, GlobalId }
+    VarDef {
+        loc: Loc,
+        linkage: Option<Token>,
+        is_const: bool,
+        id: Token,
+        init: Option<Token>,
+        ty: Box<Term>,
+    },
 
     /// AliasDef : `type` GlobalId `=` TypeDecl `;` ;
     /// FIRST = { `type` }
     AliasDef { loc: Loc, id: Token, ty: Box<Term> },
 
-    /// FnDef : FnAttribList ? `fn` FnSig FnBody ;
-    /// FIRST = { `[` -> FnAttribList, `fn` }
-    FnDef { loc: Loc, attrib: Option<Box<Term>>, sig: Box<Term>, body: Box<Term> },
+    /// FnDef : FnAttribList? Linkage? `fn` FnSig MetaList FnBody ;
+    /// FIRST = { `[` -> FnAttribList, Linkage, `fn` }
+    FnDef {
+        loc: Loc,
+        attrib: Option<Box<Term>>,
+        linkage: Option<Token>,
+        sig: Box<Term>,
+        meta: Box<Term>,
+        body: Box<Term>,
+    },
 
     /// FnAttribList : `[` ( Reserved ( `,` Reserved)* )? `]`
     FnAttribList { loc: Loc, list: Vec<Token> },
 
     /// FnSig : GlobalId `(` ParamList `)` FnRet? ;
     /// FIRST = { GlobalId }
-    /// FOLLOW = { `{` }
+    /// FOLLOW = { Meta, `{` }
     FnSig { loc: Loc, id: Token, param: Box<Term>, ret: Option<Box<Term>> },
 
-    /// FnRet : `->` TypeDecl ;
+    /// FnRet : `->` ( TypeDecl | TupleType ) ;
     /// FIRST = { `->`, `` }
-    /// FOLLOW = { `{` }
+    /// FOLLOW = { Meta, `{` }
     FnRet { loc: Loc, ty: Box<Term> },
 
     /// ParamList : ( ParamDef ( `,` ParamDef )* )?  ;
@@ -49,29 +71,41 @@ pub enum Term {
     /// FOLLOW = { GlobalId, `fn` }
     FnBody { loc: Loc, bb: Vec<Term> },
 
-    /// BlockDef : Label `:` InstrDef+ ;
+    /// BlockDef : Label `:` MetaList InstrDef+ ;
     /// FIRST = { Label }
     /// FOLLOW = { Label -> BlockDef, `}` -> FnBody }
-    BlockDef { loc: Loc, id: Token, instr: Vec<Term> },
+    BlockDef { loc: Loc, id: Token, meta: Box<Term>, instr: Vec<Term> },
+
+    /// MetaList : ( Meta MetaVal )* ;
+    /// FIRST = { Meta, `` }
+    /// FOLLOW = { `{` -> FnDef, Id -> BlockDef, Reserved -> BlockDef, InstrDef }
+    MetaList { loc: Loc, list: Vec<(Token, Token)> },
 
-    /// InstrDef : ( AssignInstr | NonAssignInstr ) `;` ;
+    /// MetaVal : Integer | Str ;
+
+    /// InstrDef : ( AssignInstr | NonAssignInstr ) MetaList ;
     /// FIRST = { Id -> AssignInstr, Reserved -> NonAssignInstr }
     /// FOLLOW = { Id -> AssignInstr, Label -> BlockDef , Reserved -> NonAssignInstr,
     /// `}` -> FnBody }
 
-    /// AssignInstr : Id `<-` AssignRhs ;
-    AssignInstr { loc: Loc, id: Token, rhs: Box<Term> },
+    /// AssignInstr : Id ( `,` Id )* `<-` AssignRhs ;
+    /// More than one identifier can only be assigned by CallRhs, which destructures the values
+    /// returned by the function, or by CommonRhs of a checked arithmetic operator, which assigns
+    /// the wrapped result and whether the operation overflows.
+    AssignInstr { loc: Loc, id: Vec<Token>, rhs: Box<Term>, meta: Box<Term> },
 
-    /// AssignRhs : CommonRhs | CallRhs | PhiRhs | PtrRhs | NewRhs ;
+    /// AssignRhs : CommonRhs | CallRhs | PhiRhs | PtrRhs | NewRhs | CastRhs | AtomicRhs ;
     /// FIRST = { `call` -> CallRhs, `phi` -> PhiRhs, `ptr` -> PtrRhs, `new` -> NewRhs,
-    ///     Reserved -> CommonRhs }
+    ///     { `zext`, `sext`, `trunc`, `ptrtoint`, `inttoptr` } -> CastRhs,
+    ///     { `ld.atomic`, `rmw.xchg`, `rmw.add`, `rmw.sub`, `rmw.and`, `rmw.or`, `rmw.xor` }
+    ///     -> AtomicRhs, Reserved -> CommonRhs }
     /// FOLLOW = { `;` }
     AssignRhs { loc: Loc, rhs: Box<Term> },
 
     /// CommonRhs : Reserved TypeDecl OpdList ;
     CommonRhs { loc: Loc, name: Token, ty: Box<Term>, opd: Box<Term> },
 
-    /// CallRhs : `call` TypeDecl FnCall ;
+    /// CallRhs : `call` ( TypeDecl | TupleType ) FnCall ;
     CallRhs { loc: Loc, ty: Box<Term>, call: Box<Term> },
 
     /// PhiRhs : `phi` TypeDecl PhiList;
@@ -86,6 +120,13 @@ pub enum Term {
     /// NewRhs : `new` ( `[` Integer `]` )? TypeDecl ;
     NewRhs { loc: Loc, ty: Box<Term>, len: Option<Token> },
 
+    /// CastRhs : Reserved TypeDecl Opd `->` TypeDecl ;
+    CastRhs { loc: Loc, name: Token, ty: Box<Term>, opd: Token, tgt: Box<Term> },
+
+    /// AtomicRhs : Reserved Reserved TypeDecl OpdList ;
+    /// The second reserved word is the memory ordering.
+    AtomicRhs { loc: Loc, name: Token, ord: Token, ty: Box<Term>, opd: Box<Term> },
+
     /// OpdList : ( Opd ( `,` Opd )* )?
     /// FIRST = { Opd, `` }
     /// FOLLOW = { `;` -> { EvalOpd, CtrlTgt }, `)` -> FnCall, `]` -> IndexList }
@@ -94,7 +135,9 @@ pub enum Term {
     /// IndexList : `[` OpdList `]`
     IndexList { loc: Loc, list: Box<Term> },
 
-    /// FnCall : GlobalId `(` OpdList `)` ;
+    /// FnCall : Id `(` OpdList `)` ;
+    /// A local identifier, or a global one that is not a function, is called indirectly as a
+    /// function pointer.
     FnCall { loc: Loc, func: Token, arg: Box<Term> },
 
     /// PhiList : PhiOpd+ ;
@@ -105,14 +148,17 @@ pub enum Term {
     /// PhiOpd : `[` Label `:` LocalOpd `]`
     PhiOpd { loc: Loc, lab: Token, opd: Token },
 
-    /// NonAssignInstr : RetInstr | JmpInstr | NoRetCall | BrInstr | StInstr;
+    /// NonAssignInstr : RetInstr | JmpInstr | NoRetCall | BrInstr | StInstr | MemInstr
+    ///     | AtomicStInstr | UnreachableInstr | AbortInstr ;
     /// FIRST = { `ret` -> RetInstr, `jmp` -> JmpInstr, `call` -> NoRetCall, `br` -> BrInstr,
-    ///     `st` -> StInstr }
+    ///     `st` -> StInstr, `memcpy` -> MemInstr, `memset` -> MemInstr,
+    ///     `st.atomic` -> AtomicStInstr,
+    ///     `unreachable` -> UnreachableInstr, `abort` -> AbortInstr }
     /// FOLLOW = { `;` }
-    NonAssignInstr { loc: Loc, instr: Box<Term> },
+    NonAssignInstr { loc: Loc, instr: Box<Term>, meta: Box<Term> },
 
-    /// RetInstr : `ret` Opd ;
-    RetInstr { loc: Loc, opd: Option<Token> },
+    /// RetInstr : `ret` ( Opd ( `,` Opd )* )? ;
+    RetInstr { loc: Loc, opd: Vec<Token> },
 
     /// NoRetCall : `call` FnCall ;
     NoRetCall { loc: Loc, call: Box<Term> },
@@ -126,13 +172,25 @@ pub enum Term {
     /// StInstr : `st` TypeDecl Opd `->` Opd ;
     StInstr { loc: Loc, ty: Box<Term>, src: Token, dst: Token },
 
+    /// MemInstr : ( `memcpy` | `memset` ) TypeDecl Opd `->` Opd `,` Opd ;
+    MemInstr { loc: Loc, op: Token, ty: Box<Term>, src: Token, dst: Token, len: Token },
+
+    /// AtomicStInstr : `st.atomic` Reserved TypeDecl Opd `->` Opd ;
+    AtomicStInstr { loc: Loc, ord: Token, ty: Box<Term>, src: Token, dst: Token },
+
+    /// UnreachableInstr : `unreachable` ;
+    UnreachableInstr { loc: Loc },
+
+    /// AbortInstr : `abort` String ;
+    AbortInstr { loc: Loc, msg: Token },
+
     /// Id : GlobalId | LocalId ;
 
     /// LocalOpd : LocalId | Integer ;
 
-    /// TypeDecl : PrimType | AliasName | PtrType | ArrayType | StructType
+    /// TypeDecl : PrimType | AliasName | PtrType | ArrayType | StructType | UnionType | FnType
     /// FIRST = { Reserved -> PrimType, GlobalId -> AliasName, `*` -> PtrType, `[` -> ArrayType,
-    ///     `{` -> StructType }
+    ///     `{` -> StructType, `union` -> UnionType, `fn` -> FnType }
     /// FOLLOW = { `;` -> { AliasDef, VarDef }, `,` -> { ParamList, TypeList }, `)` -> FnSig,
     ///     Opd -> { CommonRhs, PtrRhs }, GlobalId -> CallRhs, `[` -> PhiRhs  `;` -> AssignRhs
     /// }
@@ -153,6 +211,16 @@ pub enum Term {
     /// StructType : `{` TypeList `}` ;
     StructType { loc: Loc, field: Box<Term> },
 
+    /// UnionType : `union` `{` TypeList `}` ;
+    UnionType { loc: Loc, field: Box<Term> },
+
+    /// FnType : `fn` `(` ( TypeDecl ( `,` TypeDecl )* )? `)` FnRet? ;
+    FnType { loc: Loc, param: Vec<Term>, ret: Option<Box<Term>> },
+
+    /// TupleType : `(` TypeDecl ( `,` TypeDecl )+ `)` ;
+    /// Only appears as return type of functions, wrapped in TypeDecl.
+    TupleType { loc: Loc, elem: Vec<Term> },
+
     /// TypeList : ( TypeDecl | ( `,` TypeDecl )* )?
     /// FIRST = { Reserved, GlobalId, `*`, `[`, `{`, `` }
     /// FOLLOW = { `}` }
@@ -172,6 +240,12 @@ pub enum Token {
     Reserved(Loc, String),
     /// Integer `/-?[0-9]+/`
     Integer(Loc, String),
+    /// Metadata key `/![A-Za-z0-9._]+/`
+    Meta(Loc, String),
+    /// String literal `/"[^"\n]*"/`
+    Str(Loc, String),
+    /// Directive `/#[A-Za-z_][A-Za-z0-9._]*/`
+    Directive(Loc, String),
     /// Comma, for separating list elements `,`
     Comma(Loc),
     /// Colon, separating label and value in phi instruction `:`
@@ -200,7 +274,7 @@ pub enum Token {
     LeftArrow(Loc),
     /// Right arrow, used in function type `->`
     RightArrow(Loc),
-    /// Comment `//`
+    /// Comment `//` until end of line, or `/* */` which may span lines
     Comment(Loc),
     /// End-of-file indicator
     Eof(Loc),
@@ -210,7 +284,8 @@ impl ToString for Token {
     fn to_string(&self) -> String {
         match self {
             Token::GlobalId(_, s) | Token::LocalId(_, s) | Token::Label(_, s)
-            | Token::Reserved(_, s) | Token::Integer(_, s) => s.clone(),
+            | Token::Reserved(_, s) | Token::Integer(_, s) | Token::Meta(_, s)
+            | Token::Str(_, s) | Token::Directive(_, s) => s.clone(),
             Token::Comma(_) => ",".to_string(),
             Token::Colon(_) => ":".to_string(),
             Token::Semicolon(_) => ";".to_string(),
@@ -258,7 +333,8 @@ impl Token {
     pub fn loc(&self) -> Loc {
         match self {
             Token::GlobalId(l, _) | Token::LocalId(l, _) | Token::Label(l, _)
-            | Token::Reserved(l, _) | Token::Integer(l, _) => l.clone(),
+            | Token::Reserved(l, _) | Token::Integer(l, _) | Token::Meta(l, _)
+            | Token::Str(l, _) | Token::Directive(l, _) => l.clone(),
             Token::Comma(l) | Token::Semicolon(l)
             | Token::Colon(l) | Token::Question(l)
             | Token::Asterisk(l) | Token::Equal(l)
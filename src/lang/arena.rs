@@ -0,0 +1,793 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::irc::Loc;
+use crate::lang::func::{BasicBlock, BlockRef, Fn, FnAttrib, FnRef};
+use crate::lang::inst::{BinOp, CastOp, Inst, MemOrd, RmwOp, UnOp};
+use crate::lang::meta::Metadata;
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Const, GlobalVar, Linkage, Scope, Symbol, SymbolKind, SymbolRef, Type,
+                         Typed, Value};
+
+/// Define typed indices into the tables of this module.
+macro_rules! def_id {
+    ($($(#[$doc:meta])* $name:ident),*) => {$(
+        $(#[$doc])*
+        #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+        pub struct $name(u32);
+
+        impl $name {
+            fn new(idx: usize) -> $name { $name(idx as u32) }
+
+            /// Position of the indexed item in its table
+            pub fn index(self) -> usize { self.0 as usize }
+        }
+    )*};
+}
+
+def_id!(
+    /// Index of a block in `FuncBody::block`
+    BlockId,
+    /// Index of an instruction in `FuncBody::inst`
+    InstId,
+    /// Index of a local variable in `FuncBody::vars`
+    VarId,
+    /// Index of a type alias in `Globals::types`
+    TypeId,
+    /// Index of a global variable in `Globals::vars`
+    GlobalId,
+    /// Index of a function in `Globals::func`
+    FnId
+);
+
+/// Type whose aliases refer to the type table of `Globals`.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Ty {
+    Void,
+    I(u8),
+    Fn { param: Vec<Ty>, ret: Box<Ty> },
+    Ptr(Box<Ty>),
+    Array { elem: Box<Ty>, len: usize },
+    Struct { field: Vec<Ty> },
+    Union { field: Vec<Ty> },
+    Alias(TypeId),
+    Tuple(Vec<Ty>),
+}
+
+/// Symbol referred to by an instruction
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Sym {
+    Local(VarId),
+    Global(GlobalId),
+    /// A function used as a value
+    Func(FnId),
+}
+
+/// Operand of an instruction
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Operand {
+    Var(Sym),
+    Const(Const),
+}
+
+/// Instruction whose symbols and blocks are indices. Each variant corresponds to the one of
+/// `Inst` with the same name.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum InstData {
+    Mov { src: Operand, dst: Sym },
+    Un { op: UnOp, opd: Operand, dst: Sym },
+    Bin { op: BinOp, fst: Operand, snd: Operand, dst: Sym },
+    Cast { op: CastOp, opd: Operand, dst: Sym },
+    Call { func: FnId, arg: Vec<Operand>, dst: Vec<Sym> },
+    CallInd { func_ptr: Operand, arg: Vec<Operand>, dst: Vec<Sym> },
+    Ret { val: Vec<Operand> },
+    Unreachable,
+    Abort { msg: String },
+    Jmp { tgt: BlockId },
+    Br { cond: Operand, tr: BlockId, fls: BlockId },
+    Phi { src: Vec<(BlockId, Operand)>, dst: Sym },
+    Alloc { dst: Sym },
+    New { dst: Sym, len: Option<Operand> },
+    Ptr { base: Operand, off: Option<Operand>, ind: Vec<Operand>, dst: Sym },
+    Ld { ptr: Operand, dst: Sym },
+    St { src: Operand, ptr: Operand },
+    Memcpy { src: Operand, ptr: Operand, len: Operand },
+    Memset { src: Operand, ptr: Operand, len: Operand },
+    AtomicLd { ord: MemOrd, ptr: Operand, dst: Sym },
+    AtomicSt { ord: MemOrd, src: Operand, ptr: Operand },
+    AtomicRmw { op: RmwOp, ord: MemOrd, ptr: Operand, val: Operand, dst: Sym },
+}
+
+#[derive(Clone, Debug)]
+pub struct TypeData {
+    pub name: String,
+    pub ty: Ty,
+}
+
+#[derive(Clone, Debug)]
+pub struct GlobalData {
+    pub name: String,
+    pub ty: Ty,
+    pub init: Option<Const>,
+    pub is_const: bool,
+    pub linkage: Linkage,
+}
+
+/// Signature and attributes of a function, without its body
+#[derive(Clone, Debug)]
+pub struct FnDecl {
+    pub name: String,
+    pub param: Vec<Ty>,
+    pub ret: Ty,
+    pub attrib: Vec<FnAttrib>,
+    pub linkage: Linkage,
+}
+
+/// Table of the global symbols of a program, which function bodies refer to by indices.
+/// It holds no handle of the program, so it could be shared across threads.
+#[derive(Clone, Debug)]
+pub struct Globals {
+    pub types: Vec<TypeData>,
+    pub vars: Vec<GlobalData>,
+    pub func: Vec<FnDecl>,
+    type_id: HashMap<String, TypeId>,
+    var_id: HashMap<String, GlobalId>,
+    fn_id: HashMap<String, FnId>,
+}
+
+impl Globals {
+    /// Collect the type aliases, global variables and function signatures of `pro`.
+    pub fn new(pro: &Program) -> Globals {
+        let type_sym = pro.global.of_kind(SymbolKind::Type);
+        let mut glob = Globals {
+            types: vec![],
+            vars: vec![],
+            func: vec![],
+            type_id: type_sym.iter().enumerate()
+                .map(|(i, sym)| (sym.name().to_string(), TypeId::new(i))).collect(),
+            var_id: pro.vars.iter().enumerate()
+                .map(|(i, var)| (var.name.clone(), GlobalId::new(i))).collect(),
+            fn_id: pro.func.iter().enumerate()
+                .map(|(i, func)| (func.name.clone(), FnId::new(i))).collect(),
+        };
+        glob.types = type_sym.iter().map(|sym| match sym.as_ref() {
+            Symbol::Type { name, ty } =>
+                TypeData { name: name.clone(), ty: glob.ty(&ty.borrow()) },
+            _ => unreachable!()
+        }).collect();
+        glob.vars = pro.vars.iter().map(|var| GlobalData {
+            name: var.name.clone(),
+            ty: glob.ty(&var.ty),
+            init: var.init,
+            is_const: var.is_const,
+            linkage: var.linkage,
+        }).collect();
+        glob.func = pro.func.iter().map(|func| FnDecl {
+            name: func.name.clone(),
+            param: func.param.iter().map(|p| glob.ty(&p.borrow().get_type())).collect(),
+            ret: glob.ty(&func.ret),
+            attrib: func.attrib.clone(),
+            linkage: func.linkage.get(),
+        }).collect();
+        glob
+    }
+
+    pub fn find_type(&self, name: &str) -> Option<TypeId> { self.type_id.get(name).cloned() }
+
+    pub fn find_var(&self, name: &str) -> Option<GlobalId> { self.var_id.get(name).cloned() }
+
+    pub fn find_fn(&self, name: &str) -> Option<FnId> { self.fn_id.get(name).cloned() }
+
+    pub fn type_data(&self, id: TypeId) -> &TypeData { &self.types[id.index()] }
+
+    pub fn var(&self, id: GlobalId) -> &GlobalData { &self.vars[id.index()] }
+
+    pub fn decl(&self, id: FnId) -> &FnDecl { &self.func[id.index()] }
+
+    /// Convert `ty` to its indexed form. Aliases should be declared in the program.
+    pub fn ty(&self, ty: &Type) -> Ty {
+        match ty {
+            Type::Void => Ty::Void,
+            Type::I(b) => Ty::I(*b),
+            Type::Fn { param, ret } => Ty::Fn {
+                param: param.iter().map(|t| self.ty(t)).collect(),
+                ret: Box::new(self.ty(ret)),
+            },
+            Type::Ptr(tgt) => Ty::Ptr(Box::new(self.ty(tgt))),
+            Type::Array { elem, len } => Ty::Array { elem: Box::new(self.ty(elem)), len: *len },
+            Type::Struct { field } =>
+                Ty::Struct { field: field.iter().map(|t| self.ty(t)).collect() },
+            Type::Union { field } =>
+                Ty::Union { field: field.iter().map(|t| self.ty(t)).collect() },
+            Type::Alias(sym) => Ty::Alias(self.find_type(sym.name())
+                .unwrap_or_else(|| panic!("type @{} is not declared", sym.name()))),
+            Type::Tuple(elem) => Ty::Tuple(elem.iter().map(|t| self.ty(t)).collect()),
+        }
+    }
+
+    /// Convert `ty` back to a type, whose aliases are resolved in `scope`.
+    pub fn to_type(&self, ty: &Ty, scope: &Scope) -> Type {
+        match ty {
+            Ty::Void => Type::Void,
+            Ty::I(b) => Type::I(*b),
+            Ty::Fn { param, ret } => Type::Fn {
+                param: param.iter().map(|t| self.to_type(t, scope)).collect(),
+                ret: Box::new(self.to_type(ret, scope)),
+            },
+            Ty::Ptr(tgt) => Type::Ptr(Box::new(self.to_type(tgt, scope))),
+            Ty::Array { elem, len } =>
+                Type::Array { elem: Box::new(self.to_type(elem, scope)), len: *len },
+            Ty::Struct { field } =>
+                Type::Struct { field: field.iter().map(|t| self.to_type(t, scope)).collect() },
+            Ty::Union { field } =>
+                Type::Union { field: field.iter().map(|t| self.to_type(t, scope)).collect() },
+            Ty::Alias(id) => Type::Alias(self.resolve(scope, &self.type_data(*id).name,
+                                                      SymbolKind::Type)),
+            Ty::Tuple(elem) => Type::Tuple(elem.iter().map(|t| self.to_type(t, scope)).collect()),
+        }
+    }
+
+    fn resolve(&self, scope: &Scope, name: &str, kind: SymbolKind) -> SymbolRef {
+        scope.find_kind(name, kind)
+            .unwrap_or_else(|| panic!("{:?} symbol @{} is not found", kind, name))
+    }
+
+    /// Create a global scope declaring all the symbols in this table. Functions are stubs whose
+    /// bodies only contain `unreachable`, so that function bodies could be rebuilt in the scope
+    /// without the rest of the program.
+    pub fn declare(&self) -> Rc<Scope> {
+        let scope = Rc::new(Scope::new());
+        // Types are declared before resolved, since they may refer to each other.
+        let type_sym: Vec<SymbolRef> = self.types.iter().map(|data| {
+            let sym = ExtRc::new(Symbol::Type {
+                name: data.name.clone(),
+                ty: RefCell::new(Type::Void),
+            });
+            scope.insert(sym.clone());
+            sym
+        }).collect();
+        for (data, sym) in self.types.iter().zip(type_sym) {
+            if let Symbol::Type { name: _, ty } = sym.as_ref() {
+                ty.replace(self.to_type(&data.ty, &scope));
+            }
+        }
+        for data in &self.vars {
+            scope.insert(ExtRc::new(Symbol::Global(ExtRc::new(GlobalVar {
+                name: data.name.clone(),
+                ty: self.to_type(&data.ty, &scope),
+                init: data.init,
+                is_const: data.is_const,
+                linkage: data.linkage,
+            }))));
+        }
+        for decl in &self.func {
+            let param = decl.param.iter().enumerate().map(|(i, ty)| {
+                RefCell::new(ExtRc::new(Symbol::Local {
+                    name: format!("p{}", i),
+                    ty: self.to_type(ty, &scope),
+                }))
+            }).collect();
+            let ent = BasicBlock::new("stub".to_string());
+            ent.inst.borrow_mut().push_back(ExtRc::new(Inst::Unreachable));
+            let func = Fn::new(decl.name.clone(), Scope::new(), decl.attrib.clone(), param,
+                               self.to_type(&decl.ret, &scope), ent);
+            func.linkage.set(decl.linkage);
+            scope.insert(ExtRc::new(Symbol::Func(ExtRc::new(func))));
+        }
+        scope
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VarData {
+    pub name: String,
+    pub ty: Ty,
+}
+
+#[derive(Clone, Debug)]
+pub struct BlockData {
+    pub name: String,
+    /// Instructions of this block in order
+    pub inst: Vec<InstId>,
+    pub pred: Vec<BlockId>,
+    pub succ: Vec<BlockId>,
+    /// Parameters of this block, if the function is in block argument form
+    pub param: Vec<VarId>,
+    pub meta: Metadata,
+    pub loc: Option<Loc>,
+}
+
+/// Body of a function stored in tables and linked by indices.
+/// Unlike `Fn`, it owns all its blocks, instructions and local variables, and has no reference
+/// cycle or shared handle, so it is `Send` and `Sync`. Global symbols are referred to by their
+/// indices in `Globals`. Instructions removed from blocks stay in the instruction table until
+/// the body is converted back.
+#[derive(Clone, Debug)]
+pub struct FuncBody {
+    /// The function this body belongs to
+    pub func: FnId,
+    pub vars: Vec<VarData>,
+    pub param: Vec<VarId>,
+    pub block: Vec<BlockData>,
+    pub inst: Vec<InstData>,
+    pub ent: BlockId,
+    pub exit: Vec<BlockId>,
+    /// Stored order of blocks in emitted code. See `Fn::layout`.
+    pub layout: Vec<BlockId>,
+    pub ssa: bool,
+    pub meta: Metadata,
+    pub inst_meta: HashMap<InstId, Metadata>,
+    pub inst_loc: HashMap<InstId, Loc>,
+    /// Arguments passed along each edge, if the function is in block argument form
+    pub edge_arg: HashMap<(BlockId, BlockId), Vec<Operand>>,
+}
+
+impl FuncBody {
+    /// Convert body of `func`. Only blocks reachable from the entrance are included, in reverse
+    /// post-order, so the entrance is always the first block.
+    pub fn from_fn(func: &Fn, glob: &Globals) -> FuncBody {
+        let id = glob.find_fn(&func.name)
+            .unwrap_or_else(|| panic!("function @{} is not declared", func.name));
+        let blocks: Vec<BlockRef> = func.rpo().collect();
+        let mut imp = Import {
+            glob,
+            var: Default::default(),
+            blk: blocks.iter().enumerate().map(|(i, b)| (b.clone(), BlockId::new(i))).collect(),
+            body: FuncBody {
+                func: id,
+                vars: vec![],
+                param: vec![],
+                block: vec![],
+                inst: vec![],
+                ent: BlockId::new(0),
+                exit: vec![],
+                layout: vec![],
+                ssa: func.ssa.get(),
+                meta: func.meta.borrow().clone(),
+                inst_meta: Default::default(),
+                inst_loc: Default::default(),
+                edge_arg: Default::default(),
+            },
+        };
+        func.scope.for_each(|sym| { imp.var(&sym); });
+        imp.body.param = func.param.iter().map(|p| imp.var(&p.borrow())).collect();
+
+        for block in &blocks {
+            let mut inst = vec![];
+            for instr in block.inst.borrow().iter() {
+                let id = InstId::new(imp.body.inst.len());
+                let data = imp.inst(instr);
+                imp.body.inst.push(data);
+                if let Some(meta) = func.inst_meta.borrow().get(instr) {
+                    imp.body.inst_meta.insert(id, meta.clone());
+                }
+                if let Some(loc) = func.inst_loc(instr) { imp.body.inst_loc.insert(id, loc); }
+                inst.push(id);
+            }
+            let param = func.block_param(block).iter().map(|p| imp.var(p)).collect();
+            let data = BlockData {
+                name: block.name.clone(),
+                inst,
+                pred: imp.blocks(block.pred().iter()),
+                succ: imp.blocks(block.succ.borrow().iter()),
+                param,
+                meta: block.meta.borrow().clone(),
+                loc: block.loc.borrow().clone(),
+            };
+            imp.body.block.push(data);
+        }
+
+        imp.body.exit = imp.blocks(func.exit.borrow().iter());
+        imp.body.layout = imp.blocks(func.layout.borrow().iter());
+        for ((from, to), arg) in func.blk_arg.borrow().iter() {
+            let (from, to) = match (imp.blk.get(from), imp.blk.get(to)) {
+                (Some(from), Some(to)) => (*from, *to),
+                _ => continue
+            };
+            let arg = arg.iter().map(|v| imp.opd(v)).collect();
+            imp.body.edge_arg.insert((from, to), arg);
+        }
+        imp.body
+    }
+
+    /// Rebuild a function from this body, whose global symbols are resolved in `scope`.
+    pub fn to_fn(&self, glob: &Globals, scope: &Scope) -> FnRef {
+        let decl = glob.decl(self.func);
+        let exp = Export {
+            glob,
+            scope,
+            var: self.vars.iter().map(|v| ExtRc::new(Symbol::Local {
+                name: v.name.clone(),
+                ty: glob.to_type(&v.ty, scope),
+            })).collect(),
+            blk: self.block.iter().map(|b| {
+                let block = ExtRc::new(BasicBlock::new(b.name.clone()));
+                block.meta.replace(b.meta.clone());
+                block.loc.replace(b.loc.clone());
+                block
+            }).collect(),
+        };
+        let local = Scope::new();
+        local.append(exp.var.iter().cloned());
+        let param = self.param.iter().map(|p| RefCell::new(exp.var[p.index()].clone())).collect();
+        let func = Fn::new(decl.name.clone(), local, decl.attrib.clone(), param,
+                           glob.to_type(&decl.ret, scope), BasicBlock::default());
+        func.linkage.set(decl.linkage);
+        func.ent.replace(exp.blk[self.ent.index()].clone());
+        func.meta.replace(self.meta.clone());
+
+        for (data, block) in self.block.iter().zip(exp.blk.iter()) {
+            let inst: VecDeque<_> = data.inst.iter().map(|id| {
+                let instr = ExtRc::new(exp.inst(&self.inst[id.index()]));
+                if let Some(meta) = self.inst_meta.get(id) {
+                    func.inst_meta.borrow_mut().insert(instr.clone(), meta.clone());
+                }
+                if let Some(loc) = self.inst_loc.get(id) {
+                    func.inst_loc.borrow_mut().insert(instr.clone(), loc.clone());
+                }
+                instr
+            }).collect();
+            block.inst.replace(inst);
+            block.set_pred(exp.blocks(&data.pred));
+            block.succ.replace(exp.blocks(&data.succ));
+            if !data.param.is_empty() {
+                let param = data.param.iter().map(|p| RefCell::new(exp.var[p.index()].clone()))
+                    .collect();
+                func.blk_param.borrow_mut().insert(block.clone(), param);
+            }
+        }
+
+        func.exit.replace(exp.blocks(&self.exit));
+        func.layout.replace(exp.blocks(&self.layout));
+        for ((from, to), arg) in self.edge_arg.iter() {
+            let key = (exp.blk[from.index()].clone(), exp.blk[to.index()].clone());
+            func.blk_arg.borrow_mut().insert(key, arg.iter().map(|v| exp.opd(v)).collect());
+        }
+        func.build_dom();
+        func.ssa.set(self.ssa);
+        ExtRc::new(func)
+    }
+
+    pub fn block(&self, id: BlockId) -> &BlockData { &self.block[id.index()] }
+
+    pub fn inst(&self, id: InstId) -> &InstData { &self.inst[id.index()] }
+
+    pub fn var(&self, id: VarId) -> &VarData { &self.vars[id.index()] }
+
+    /// Ids of all the blocks, with the entrance first
+    pub fn blocks(&self) -> impl Iterator<Item=BlockId> {
+        (0..self.block.len()).map(BlockId::new)
+    }
+
+    pub fn pred(&self, id: BlockId) -> &[BlockId] { &self.block(id).pred }
+
+    pub fn succ(&self, id: BlockId) -> &[BlockId] { &self.block(id).succ }
+
+    /// Instructions of block `id` in order
+    pub fn block_inst(&self, id: BlockId) -> impl Iterator<Item=&InstData> {
+        self.block(id).inst.iter().map(move |i| self.inst(*i))
+    }
+
+    pub fn find_block(&self, name: &str) -> Option<BlockId> {
+        self.block.iter().position(|b| b.name == name).map(BlockId::new)
+    }
+
+    pub fn find_var(&self, name: &str) -> Option<VarId> {
+        self.vars.iter().position(|v| v.name == name).map(VarId::new)
+    }
+
+    /// Add a local variable, and return its index. The name should not be taken.
+    pub fn add_var(&mut self, name: &str, ty: Ty) -> VarId {
+        self.vars.push(VarData { name: name.to_string(), ty });
+        VarId::new(self.vars.len() - 1)
+    }
+
+    /// Add an instruction to the table, without placing it in any block.
+    pub fn add_inst(&mut self, data: InstData) -> InstId {
+        self.inst.push(data);
+        InstId::new(self.inst.len() - 1)
+    }
+}
+
+/// Conversion from a function to its body
+struct Import<'a> {
+    glob: &'a Globals,
+    var: HashMap<SymbolRef, VarId>,
+    blk: HashMap<BlockRef, BlockId>,
+    body: FuncBody,
+}
+
+impl Import<'_> {
+    fn var(&mut self, sym: &SymbolRef) -> VarId {
+        if let Some(id) = self.var.get(sym) { return *id; }
+        let id = VarId::new(self.body.vars.len());
+        let ty = self.glob.ty(&sym.get_type());
+        self.body.vars.push(VarData { name: sym.name().to_string(), ty });
+        self.var.insert(sym.clone(), id);
+        id
+    }
+
+    fn sym(&mut self, sym: &SymbolRef) -> Sym {
+        match sym.as_ref() {
+            Symbol::Local { name: _, ty: _ } => Sym::Local(self.var(sym)),
+            Symbol::Global(var) => Sym::Global(self.glob.find_var(&var.name)
+                .unwrap_or_else(|| panic!("global @{} is not declared", var.name))),
+            Symbol::Func(func) => Sym::Func(self.func(func)),
+            Symbol::Type { name, ty: _ } => panic!("type @{} used as value", name)
+        }
+    }
+
+    fn dst(&mut self, dst: &RefCell<SymbolRef>) -> Sym { self.sym(&dst.borrow()) }
+
+    fn func(&self, func: &FnRef) -> FnId {
+        self.glob.find_fn(&func.name)
+            .unwrap_or_else(|| panic!("function @{} is not declared", func.name))
+    }
+
+    fn opd(&mut self, opd: &RefCell<Value>) -> Operand {
+        match opd.borrow().deref() {
+            Value::Var(sym) => Operand::Var(self.sym(sym)),
+            Value::Const(c) => Operand::Const(*c)
+        }
+    }
+
+    fn opds(&mut self, opd: &[RefCell<Value>]) -> Vec<Operand> {
+        opd.iter().map(|v| self.opd(v)).collect()
+    }
+
+    fn blk(&self, blk: &RefCell<BlockRef>) -> BlockId { self.blk[blk.borrow().deref()] }
+
+    /// Indices of `blocks`, skipping unreachable ones
+    fn blocks<'b>(&self, blocks: impl Iterator<Item=&'b BlockRef>) -> Vec<BlockId> {
+        blocks.filter_map(|b| self.blk.get(b).cloned()).collect()
+    }
+
+    fn inst(&mut self, instr: &Inst) -> InstData {
+        match instr {
+            Inst::Mov { src, dst } => InstData::Mov { src: self.opd(src), dst: self.dst(dst) },
+            Inst::Un { op, opd, dst } =>
+                InstData::Un { op: *op, opd: self.opd(opd), dst: self.dst(dst) },
+            Inst::Bin { op, fst, snd, dst } => InstData::Bin {
+                op: *op,
+                fst: self.opd(fst),
+                snd: self.opd(snd),
+                dst: self.dst(dst),
+            },
+            Inst::Cast { op, opd, dst } =>
+                InstData::Cast { op: *op, opd: self.opd(opd), dst: self.dst(dst) },
+            Inst::Call { func, arg, dst } => InstData::Call {
+                func: self.func(func),
+                arg: self.opds(arg),
+                dst: dst.iter().map(|d| self.dst(d)).collect(),
+            },
+            Inst::CallInd { func_ptr, arg, dst } => InstData::CallInd {
+                func_ptr: self.opd(func_ptr),
+                arg: self.opds(arg),
+                dst: dst.iter().map(|d| self.dst(d)).collect(),
+            },
+            Inst::Ret { val } => InstData::Ret { val: self.opds(val) },
+            Inst::Unreachable => InstData::Unreachable,
+            Inst::Abort { msg } => InstData::Abort { msg: msg.clone() },
+            Inst::Jmp { tgt } => InstData::Jmp { tgt: self.blk(tgt) },
+            Inst::Br { cond, tr, fls } =>
+                InstData::Br { cond: self.opd(cond), tr: self.blk(tr), fls: self.blk(fls) },
+            Inst::Phi { src, dst } => {
+                // Operands from unreachable predecessors are dropped along with the blocks.
+                let src: Vec<_> = src.iter()
+                    .filter(|(b, _)| self.blk.contains_key(b.borrow().deref())).collect();
+                InstData::Phi {
+                    src: src.into_iter().map(|(b, v)| (self.blk(b), self.opd(v))).collect(),
+                    dst: self.dst(dst),
+                }
+            }
+            Inst::Alloc { dst } => InstData::Alloc { dst: self.dst(dst) },
+            Inst::New { dst, len } => InstData::New {
+                dst: self.dst(dst),
+                len: len.as_ref().map(|len| self.opd(len)),
+            },
+            Inst::Ptr { base, off, ind, dst } => InstData::Ptr {
+                base: self.opd(base),
+                off: off.as_ref().map(|off| self.opd(off)),
+                ind: self.opds(ind),
+                dst: self.dst(dst),
+            },
+            Inst::Ld { ptr, dst } => InstData::Ld { ptr: self.opd(ptr), dst: self.dst(dst) },
+            Inst::St { src, ptr } => InstData::St { src: self.opd(src), ptr: self.opd(ptr) },
+            Inst::Memcpy { src, ptr, len } => InstData::Memcpy {
+                src: self.opd(src),
+                ptr: self.opd(ptr),
+                len: self.opd(len),
+            },
+            Inst::Memset { src, ptr, len } => InstData::Memset {
+                src: self.opd(src),
+                ptr: self.opd(ptr),
+                len: self.opd(len),
+            },
+            Inst::AtomicLd { ord, ptr, dst } =>
+                InstData::AtomicLd { ord: *ord, ptr: self.opd(ptr), dst: self.dst(dst) },
+            Inst::AtomicSt { ord, src, ptr } =>
+                InstData::AtomicSt { ord: *ord, src: self.opd(src), ptr: self.opd(ptr) },
+            Inst::AtomicRmw { op, ord, ptr, val, dst } => InstData::AtomicRmw {
+                op: *op,
+                ord: *ord,
+                ptr: self.opd(ptr),
+                val: self.opd(val),
+                dst: self.dst(dst),
+            },
+        }
+    }
+}
+
+/// Conversion from a body back to a function
+struct Export<'a> {
+    glob: &'a Globals,
+    scope: &'a Scope,
+    var: Vec<SymbolRef>,
+    blk: Vec<BlockRef>,
+}
+
+impl Export<'_> {
+    fn sym(&self, sym: Sym) -> SymbolRef {
+        match sym {
+            Sym::Local(id) => self.var[id.index()].clone(),
+            Sym::Global(id) =>
+                self.glob.resolve(self.scope, &self.glob.var(id).name, SymbolKind::Global),
+            Sym::Func(id) =>
+                self.glob.resolve(self.scope, &self.glob.decl(id).name, SymbolKind::Func),
+        }
+    }
+
+    fn dst(&self, sym: Sym) -> RefCell<SymbolRef> { RefCell::new(self.sym(sym)) }
+
+    fn func(&self, id: FnId) -> FnRef {
+        match self.sym(Sym::Func(id)).as_ref() {
+            Symbol::Func(func) => func.clone(),
+            _ => unreachable!()
+        }
+    }
+
+    fn opd(&self, opd: &Operand) -> RefCell<Value> {
+        RefCell::new(match opd {
+            Operand::Var(sym) => Value::Var(self.sym(*sym)),
+            Operand::Const(c) => Value::Const(*c)
+        })
+    }
+
+    fn opds(&self, opd: &[Operand]) -> Vec<RefCell<Value>> {
+        opd.iter().map(|v| self.opd(v)).collect()
+    }
+
+    fn blk(&self, id: BlockId) -> RefCell<BlockRef> { RefCell::new(self.blk[id.index()].clone()) }
+
+    fn blocks(&self, ids: &[BlockId]) -> Vec<BlockRef> {
+        ids.iter().map(|id| self.blk[id.index()].clone()).collect()
+    }
+
+    fn inst(&self, data: &InstData) -> Inst {
+        match data {
+            InstData::Mov { src, dst } => Inst::Mov { src: self.opd(src), dst: self.dst(*dst) },
+            InstData::Un { op, opd, dst } =>
+                Inst::Un { op: *op, opd: self.opd(opd), dst: self.dst(*dst) },
+            InstData::Bin { op, fst, snd, dst } => Inst::Bin {
+                op: *op,
+                fst: self.opd(fst),
+                snd: self.opd(snd),
+                dst: self.dst(*dst),
+            },
+            InstData::Cast { op, opd, dst } =>
+                Inst::Cast { op: *op, opd: self.opd(opd), dst: self.dst(*dst) },
+            InstData::Call { func, arg, dst } => Inst::Call {
+                func: self.func(*func),
+                arg: self.opds(arg),
+                dst: dst.iter().map(|d| self.dst(*d)).collect(),
+            },
+            InstData::CallInd { func_ptr, arg, dst } => Inst::CallInd {
+                func_ptr: self.opd(func_ptr),
+                arg: self.opds(arg),
+                dst: dst.iter().map(|d| self.dst(*d)).collect(),
+            },
+            InstData::Ret { val } => Inst::Ret { val: self.opds(val) },
+            InstData::Unreachable => Inst::Unreachable,
+            InstData::Abort { msg } => Inst::Abort { msg: msg.clone() },
+            InstData::Jmp { tgt } => Inst::Jmp { tgt: self.blk(*tgt) },
+            InstData::Br { cond, tr, fls } =>
+                Inst::Br { cond: self.opd(cond), tr: self.blk(*tr), fls: self.blk(*fls) },
+            InstData::Phi { src, dst } => Inst::Phi {
+                src: src.iter().map(|(b, v)| (self.blk(*b), self.opd(v))).collect(),
+                dst: self.dst(*dst),
+            },
+            InstData::Alloc { dst } => Inst::Alloc { dst: self.dst(*dst) },
+            InstData::New { dst, len } => Inst::New {
+                dst: self.dst(*dst),
+                len: len.as_ref().map(|len| self.opd(len)),
+            },
+            InstData::Ptr { base, off, ind, dst } => Inst::Ptr {
+                base: self.opd(base),
+                off: off.as_ref().map(|off| self.opd(off)),
+                ind: self.opds(ind),
+                dst: self.dst(*dst),
+            },
+            InstData::Ld { ptr, dst } => Inst::Ld { ptr: self.opd(ptr), dst: self.dst(*dst) },
+            InstData::St { src, ptr } => Inst::St { src: self.opd(src), ptr: self.opd(ptr) },
+            InstData::Memcpy { src, ptr, len } => Inst::Memcpy {
+                src: self.opd(src),
+                ptr: self.opd(ptr),
+                len: self.opd(len),
+            },
+            InstData::Memset { src, ptr, len } => Inst::Memset {
+                src: self.opd(src),
+                ptr: self.opd(ptr),
+                len: self.opd(len),
+            },
+            InstData::AtomicLd { ord, ptr, dst } =>
+                Inst::AtomicLd { ord: *ord, ptr: self.opd(ptr), dst: self.dst(*dst) },
+            InstData::AtomicSt { ord, src, ptr } =>
+                Inst::AtomicSt { ord: *ord, src: self.opd(src), ptr: self.opd(ptr) },
+            InstData::AtomicRmw { op, ord, ptr, val, dst } => Inst::AtomicRmw {
+                op: *op,
+                ord: *ord,
+                ptr: self.opd(ptr),
+                val: self.opd(val),
+                dst: self.dst(*dst),
+            },
+        }
+    }
+}
+
+#[test]
+fn test_arena() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Globals>();
+    assert_send_sync::<FuncBody>();
+
+    fn print(func: &Fn) -> String {
+        let mut out = vec![];
+        Printer::new(&mut out).print_fn(func).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    for path in ["test/sum.ir", "test/rec_ty.ir", "test/atomic.ir", "test/meta.ir"] {
+        let src = std::fs::read_to_string(path).unwrap();
+        let mut pro = Builder::new(Parser::new(Lexer::from(src.as_str())).parse().unwrap())
+            .build().unwrap();
+        let expected = Machine::new().run(&pro).unwrap();
+        let glob = Globals::new(&pro);
+        let body: Vec<FuncBody> = pro.func.iter().map(|f| FuncBody::from_fn(f, &glob)).collect();
+
+        // Accessors see the same graph as the function
+        for (func, body) in pro.func.iter().zip(body.iter()) {
+            assert_eq!(body.block(body.ent).name, func.ent.borrow().name);
+            assert!(body.blocks()
+                .all(|b| body.succ(b).iter().all(|s| body.pred(*s).contains(&b))));
+            let exit: Vec<_> = body.blocks()
+                .filter(|b| body.block_inst(*b).any(|i| matches!(i, InstData::Ret { .. })))
+                .collect();
+            assert_eq!(body.exit.len(), exit.len());
+        }
+
+        // Rebuild the functions in a scope without the original program
+        let scope = glob.declare();
+        for (func, body) in pro.func.iter().zip(body.iter()) {
+            let new = body.to_fn(&glob, &scope);
+            assert_eq!(func.ssa.get(), new.ssa.get());
+            assert_eq!(print(func), print(&new));
+        }
+
+        // Replace the functions with ones rebuilt in the program
+        for (old, body) in pro.func.clone().iter().zip(body.iter()) {
+            let new = body.to_fn(&glob, &pro.global);
+            pro.replace_fn(old, new);
+        }
+        let rcd = Machine::new().run(&pro).unwrap();
+        assert_eq!(expected.global, rcd.global);
+    }
+}
@@ -0,0 +1,146 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, Fn};
+use crate::lang::inst::{Inst, InstRef, PhiSrc};
+use crate::lang::util::ExtRc;
+use crate::lang::value::{SymbolRef, Value};
+
+/// Arguments passed along each edge of the CFG, keyed by source and target blocks
+pub type EdgeArgMap = HashMap<(BlockRef, BlockRef), Vec<RefCell<Value>>>;
+
+impl Fn {
+    /// Whether this function is in block argument form.
+    pub fn has_block_args(&self) -> bool { !self.blk_param.borrow().is_empty() }
+
+    /// Convert phi instructions to block parameters.
+    /// Destinations of phis at the beginning of each block become parameters of that block, and
+    /// their operands become arguments passed along the edges from the predecessors, which are
+    /// recorded in `blk_param` and `blk_arg`. Operands from unreachable predecessors are
+    /// dropped. Other passes and the interpreter only understand phi form, so the function
+    /// should be converted back with `to_phi` before they are run.
+    pub fn to_block_args(&self) {
+        let mut param = self.blk_param.borrow_mut();
+        let mut arg = self.blk_arg.borrow_mut();
+        for block in self.dfs() {
+            let phi: Vec<InstRef> = block.inst.borrow().iter()
+                .take_while(|instr| instr.is_phi()).cloned().collect();
+            if phi.is_empty() { continue; }
+            let src: Vec<&Vec<PhiSrc>> = phi.iter().map(|instr| match instr.as_ref() {
+                Inst::Phi { src, dst: _ } => src,
+                _ => unreachable!()
+            }).collect();
+
+            // Collect arguments of each predecessor, if all the phis have operands for it
+            for pred in block.pred().iter() {
+                let val: Option<Vec<_>> = src.iter().map(|src| {
+                    src.iter().find(|(b, _)| b.borrow().deref() == pred)
+                        .map(|(_, v)| RefCell::new(v.borrow().clone()))
+                }).collect();
+                if let Some(val) = val { arg.insert((pred.clone(), block.clone()), val); }
+            }
+            param.insert(block.clone(), phi.iter()
+                .map(|instr| RefCell::new(instr.dst().unwrap().borrow().clone())).collect());
+
+            // Remove phis, along with their metadata
+            block.inst.borrow_mut().drain(..phi.len());
+            phi.iter().for_each(|instr| {
+                self.inst_meta.borrow_mut().remove(instr);
+                self.inst_loc.borrow_mut().remove(instr);
+            });
+        }
+    }
+
+    /// Convert block parameters back to phi instructions. Each parameter becomes a phi at the
+    /// beginning of its block, whose operands are the arguments passed from the predecessors.
+    pub fn to_phi(&self) {
+        let param: Vec<_> = self.blk_param.borrow_mut().drain().collect();
+        let mut arg = self.blk_arg.borrow_mut();
+        for (block, param) in param {
+            let mut src: Vec<Vec<PhiSrc>> = param.iter().map(|_| vec![]).collect();
+            for pred in block.pred().iter() {
+                if let Some(val) = arg.remove(&(pred.clone(), block.clone())) {
+                    src.iter_mut().zip(val)
+                        .for_each(|(src, v)| src.push((RefCell::new(pred.clone()), v)));
+                }
+            }
+            for (dst, src) in param.into_iter().zip(src).rev() {
+                block.push_front(ExtRc::new(Inst::Phi { src, dst }));
+            }
+        }
+        arg.clear();
+    }
+
+    /// Get parameters of `block` in block argument form.
+    pub fn block_param(&self, block: &BlockRef) -> Vec<SymbolRef> {
+        self.blk_param.borrow().get(block)
+            .map(|param| param.iter().map(|p| p.borrow().clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Get arguments passed along the edge from `from` to `to` in block argument form.
+    pub fn block_arg(&self, from: &BlockRef, to: &BlockRef) -> Vec<Value> {
+        self.blk_arg.borrow().get(&(from.clone(), to.clone()))
+            .map(|arg| arg.iter().map(|a| a.borrow().clone()).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[test]
+fn test_block_args() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+
+[ssa]
+fn @main() {
+%Begin:
+    jmp %Loop
+%Loop:
+    $i <- phi i64 [%Begin: 0] [%Body: $i.1]
+    $s <- phi i64 [%Begin: 0] [%Body: $s.1]
+    $c <- lt i64 $i, 5
+    br $c ? %Body : %End
+%Body:
+    $s.1 <- add i64 $s, $i
+    $i.1 <- add i64 $i, 1
+    jmp %Loop
+%End:
+    @r <- mov i64 $s
+    ret
+}
+"#;
+    let print = |pro| {
+        let mut out = vec![];
+        Printer::new(&mut out).print(pro).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        println!("{}", out);
+        out
+    };
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let before = print(&pro);
+    let func = &pro.func[0];
+    func.to_block_args();
+    assert!(func.has_block_args());
+    let out = print(&pro);
+    assert!(out.contains("%Loop($i: i64, $s: i64):"));
+    assert!(out.contains("jmp %Loop(0, 0)") && out.contains("jmp %Loop($i.1, $s.1)"));
+    assert!(func.dfs().all(|b| b.inst.borrow().iter().all(|i| !i.is_phi())));
+    let body = func.dfs().find(|b| b.name == "Body").unwrap();
+    let head = func.dfs().find(|b| b.name == "Loop").unwrap();
+    assert_eq!(func.block_param(&head).len(), 2);
+    assert_eq!(func.block_arg(&body, &head).len(), 2);
+
+    // Converting back gives the original program
+    func.to_phi();
+    assert!(!func.has_block_args());
+    assert_eq!(print(&pro), before);
+    let rcd = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", rcd.global[0].1.get_const()), "I64(10)");
+}
@@ -0,0 +1,218 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use crate::lang::func::{BasicBlock, BlockGen, BlockRef, Fn};
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::util::ExtRc;
+use crate::lang::value::{SymbolGen, SymbolRef, Value};
+
+/// Remapping table used when cloning blocks and instructions.
+/// Symbols and blocks found in this table are replaced by their mapped ones in the clones.
+/// Those not found are kept as they are, so global symbols and blocks outside of the cloned
+/// region are shared by the original and the clone.
+#[derive(Default, Debug)]
+pub struct CloneMap {
+    pub sym: HashMap<SymbolRef, SymbolRef>,
+    pub blk: HashMap<BlockRef, BlockRef>,
+}
+
+impl CloneMap {
+    pub fn new() -> CloneMap { Default::default() }
+
+    /// Map every local symbol defined in `blocks` to a renamed one created by `gen`, unless the
+    /// symbol is already mapped. This is required to keep SSA property of the cloned blocks.
+    pub fn rename_def(&mut self, blocks: &[BlockRef], gen: &mut SymbolGen) {
+        for block in blocks {
+            for instr in block.inst.borrow().iter() {
+                for dst in instr.dsts() {
+                    let sym = dst.borrow().clone();
+                    if !sym.is_local_var() || self.sym.contains_key(&sym) { continue; }
+                    let new = gen.rename(&sym);
+                    self.sym.insert(sym, new);
+                }
+            }
+        }
+    }
+
+    /// Clone a single instruction, with its symbols and blocks remapped.
+    pub fn clone_inst(&self, instr: &Inst) -> InstRef {
+        let instr = ExtRc::new(instr.clone());
+        instr.src().iter().for_each(|src| {
+            src.replace_with(|v| match v {
+                Value::Var(sym) => Value::Var(self.map_sym(sym)),
+                _ => v.clone()
+            });
+        });
+        instr.dsts().iter().for_each(|dst| { dst.replace_with(|sym| self.map_sym(sym)); });
+        instr.blk().iter().for_each(|blk| { blk.replace_with(|b| self.map_blk(b)); });
+        instr
+    }
+
+    fn map_sym(&self, sym: &SymbolRef) -> SymbolRef {
+        self.sym.get(sym).cloned().unwrap_or_else(|| sym.clone())
+    }
+
+    fn map_blk(&self, blk: &BlockRef) -> BlockRef {
+        self.blk.get(blk).cloned().unwrap_or_else(|| blk.clone())
+    }
+
+    /// Clone `blocks` of function `from` into function `to`.
+    fn clone_blocks(&mut self, from: &Fn, to: &Fn, blocks: &[BlockRef], gen: &mut BlockGen)
+                    -> Vec<BlockRef>
+    {
+        // Create corresponding blocks
+        let new_blk: Vec<BlockRef> = blocks.iter().map(|b| {
+            let new = gen.rename(b);
+            new.meta.replace(b.meta.borrow().clone());
+            new.loc.replace(b.loc.borrow().clone());
+            self.blk.insert(b.clone(), new.clone());
+            new
+        }).collect();
+
+        // Clone instructions, along with their metadata and locations
+        for (prev, new) in blocks.iter().zip(new_blk.iter()) {
+            new.inst.replace(prev.inst.borrow().iter().map(|instr| {
+                let new_instr = self.clone_inst(instr);
+                if let Some(meta) = from.inst_meta.borrow().get(instr) {
+                    to.inst_meta.borrow_mut().insert(new_instr.clone(), meta.clone());
+                }
+                if let Some(loc) = from.inst_loc(instr) {
+                    to.inst_loc.borrow_mut().insert(new_instr.clone(), loc);
+                }
+                new_instr
+            }).collect());
+        }
+
+        // Connect edges. Edges inside the subgraph are mapped, and edges leaving the subgraph
+        // are duplicated.
+        for (prev, new) in blocks.iter().zip(new_blk.iter()) {
+            new.set_pred(prev.pred().iter()
+                .filter(|p| self.blk.contains_key(p)).map(|p| self.blk[p].clone()).collect());
+            new.succ.replace(prev.succ.borrow().iter().map(|s| self.map_blk(s)).collect());
+            for succ in prev.succ.borrow().iter().filter(|s| !self.blk.contains_key(s)) {
+                succ.add_pred(new);
+                Self::dup_phi_src(succ, prev, new);
+            }
+        }
+        new_blk
+    }
+
+    /// For each phi in `block` with an operand from `prev`, add the same operand from `new`.
+    fn dup_phi_src(block: &BlockRef, prev: &BlockRef, new: &BlockRef) {
+        let mut inst = block.inst.borrow_mut();
+        for instr in inst.iter_mut() {
+            let (src, dst) = match instr.as_ref() {
+                Inst::Phi { src, dst } => (src, dst),
+                _ => break
+            };
+            let mut src = src.clone();
+            let val = src.iter().find(|(b, _)| b.borrow().deref() == prev)
+                .map(|(_, v)| v.clone());
+            if let Some(val) = val {
+                src.push((RefCell::new(new.clone()), val));
+                *instr = ExtRc::new(Inst::Phi { src, dst: dst.clone() });
+            }
+        }
+    }
+}
+
+impl BasicBlock {
+    /// Deep clone a subgraph consisting of `blocks` in function `func`, and return the new blocks
+    /// in the same order. New blocks are named by `gen`, and the mappings from original blocks
+    /// to new ones are added to `map`.
+    ///
+    /// Edges inside the subgraph are mapped to the new blocks. Edges from the subgraph to
+    /// outside blocks are duplicated, and phi instructions in those blocks receive operands for
+    /// the new predecessors. Edges from outside blocks into the subgraph are not duplicated, so
+    /// the caller is responsible for connecting the new blocks to the rest of the CFG, fixing
+    /// phi instructions in the new entry blocks and rebuilding the dominator tree.
+    pub fn clone_subgraph(func: &Fn, blocks: &[BlockRef], gen: &mut BlockGen, map: &mut CloneMap)
+                          -> Vec<BlockRef>
+    {
+        map.clone_blocks(func, func, blocks, gen)
+    }
+}
+
+impl Fn {
+    /// Deep clone the body of this function into function `to`. All the local symbols of this
+    /// function that are not in `map` are renamed and added to the scope of `to`. New blocks
+    /// are named by `gen`. Return the entry block and exit blocks of the cloned body. The
+    /// cloned body is not connected to any block of `to`.
+    pub fn clone_body(&self, to: &Fn, gen: &mut BlockGen, map: &mut CloneMap)
+                      -> (BlockRef, Vec<BlockRef>)
+    {
+        let mut sym_gen = SymbolGen::new(to.scope.clone(), "");
+        self.scope.for_each(|sym| {
+            map.sym.entry(sym.clone()).or_insert_with(|| sym_gen.rename(&sym));
+        });
+        let blocks: Vec<BlockRef> = self.iter_dom().collect();
+        map.clone_blocks(self, to, &blocks, gen);
+        let ent = map.blk[self.ent.borrow().deref()].clone();
+        let exit = self.exit.borrow().iter().map(|b| map.blk[b].clone()).collect();
+        (ent, exit)
+    }
+}
+
+#[test]
+fn test_clone() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::lang::print::Printer;
+    use crate::lang::value::Scope;
+    use crate::vm::exec::Machine;
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut file = File::open("test/sum.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let mut pro = builder.build().unwrap();
+    let mut mach = Machine::new();
+    let before = mach.run(&mut pro).unwrap();
+
+    // Replace a function with a clone of itself
+    let sum = pro.func[1].clone();
+    let mut func = Fn::new(sum.name.clone(), Scope::new(), sum.attrib.clone(), vec![],
+                           sum.ret.clone(), BasicBlock::default());
+    let mut map = CloneMap::new();
+    let (ent, exit) = sum.clone_body(&func, &mut BlockGen::new(&func, ""), &mut map);
+    func.param = sum.param.iter().map(|p| RefCell::new(map.sym[&*p.borrow()].clone())).collect();
+    func.ent.replace(ent);
+    func.exit.replace(exit);
+    func.build_dom();
+    let func = ExtRc::new(func);
+    Printer::new(&mut std::io::stdout()).print_fn(&func).unwrap();
+    let main = pro.func[2].clone();
+    for block in main.iter_dom() {
+        for instr in block.inst.borrow_mut().iter_mut() {
+            if let Inst::Call { func: _, arg, dst } = instr.as_ref() {
+                let (arg, dst) = (arg.clone(), dst.clone());
+                *instr = ExtRc::new(Inst::Call { func: func.clone(), arg, dst });
+            }
+        }
+    }
+    let mut mach = Machine::new();
+    let after = mach.run(&mut pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+
+    // Clone a loop body, which jumps back to the original header
+    let cond = main.iter_dom().find(|b| b.name == "Cond").unwrap();
+    let body = main.iter_dom().find(|b| b.name == "Loop").unwrap();
+    let mut map = CloneMap::new();
+    map.rename_def(std::slice::from_ref(&body), &mut SymbolGen::new(main.scope.clone(), ""));
+    let mut gen = BlockGen::new(&main, "");
+    let new = BasicBlock::clone_subgraph(&main, std::slice::from_ref(&body), &mut gen, &mut map);
+    assert_eq!(new[0].succ.borrow().as_slice(), std::slice::from_ref(&cond));
+    assert!(new[0].pred().is_empty());
+    assert!(cond.pred().contains(&new[0]));
+    match cond.head().as_ref() {
+        Inst::Phi { src, dst: _ } => assert_eq!(src.len(), 3),
+        _ => unreachable!()
+    }
+    assert!(new[0].inst.borrow().iter().zip(body.inst.borrow().iter()).all(|(n, o)| n != o));
+}
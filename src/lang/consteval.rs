@@ -0,0 +1,263 @@
+use std::ops::*;
+
+use crate::lang::inst::{BinOp, CastOp, UnOp};
+use crate::lang::value::{Const, Type};
+
+// Evaluation of operators on constants, shared by the builder, folding passes, SCCP and the
+// interpreter, so that they agree on the results. All operations are defined for every integer
+// width. Arithmetic wraps around on overflow, including `MIN / -1`, and shift amounts are masked
+// by the bit width. Division by zero traps at runtime, so it has no constant result.
+
+impl UnOp {
+    /// Evaluate constant according to unary operator `op`
+    pub fn eval(self, c: Const) -> Const {
+        match self {
+            UnOp::Not => !c,
+            UnOp::Neg => -c,
+        }
+    }
+}
+
+impl BinOp {
+    /// Evaluate constant according to binary operator `op`. Return `None` if a trapping
+    /// operation has zero divisor.
+    pub fn eval(self, l: Const, r: Const) -> Option<Const> {
+        if self.is_trapping() && r.is_zero() { return None; }
+        Some(match self {
+            BinOp::Add => l + r,
+            BinOp::Sub => l - r,
+            BinOp::Mul => l * r,
+            BinOp::Div => l / r,
+            BinOp::Mod => l % r,
+            BinOp::AddOv => l.add_ov(r),
+            BinOp::SubOv => l.sub_ov(r),
+            BinOp::MulOv => l.mul_ov(r),
+            BinOp::Shl => l << r,
+            BinOp::Shr => l >> r,
+            BinOp::And => l & r,
+            BinOp::Or => l | r,
+            BinOp::Xor => l ^ r,
+            BinOp::Eq => l.equal(r),
+            BinOp::Ne => l.not_eq(r),
+            BinOp::Lt => l.less_than(r),
+            BinOp::Le => l.less_eq(r),
+            BinOp::Gt => l.greater_than(r),
+            BinOp::Ge => l.greater_eq(r),
+        })
+    }
+}
+
+impl CastOp {
+    /// Evaluate constant conversion to type `ty`. Return `None` if the result is not an integer
+    /// constant.
+    pub fn eval(self, c: Const, ty: &Type) -> Option<Const> {
+        match self {
+            CastOp::ZExt => Some(Const::from_i64(c.as_u64() as i64, ty)),
+            CastOp::SExt => match c {
+                Const::I1(v) => Some(Const::from_i64(-(v as i64), ty)),
+                c => Some(Const::from_i64(c.as_i64(), ty))
+            }
+            CastOp::Trunc => Some(Const::from_i64(c.as_i64(), ty)),
+            CastOp::PtrToInt | CastOp::IntToPtr => None
+        }
+    }
+}
+
+impl Not for Const {
+    type Output = Self;
+
+    fn not(self) -> Self::Output {
+        match self {
+            Const::I1(v) => Const::I1(!v),
+            Const::I8(v) => Const::I8(!v),
+            Const::I16(v) => Const::I16(!v),
+            Const::I32(v) => Const::I32(!v),
+            Const::I64(v) => Const::I64(!v),
+        }
+    }
+}
+
+impl Neg for Const {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        match self {
+            Const::I1(v) => Const::I1(v),
+            Const::I8(v) => Const::I8(v.wrapping_neg()),
+            Const::I16(v) => Const::I16(v.wrapping_neg()),
+            Const::I32(v) => Const::I32(v.wrapping_neg()),
+            Const::I64(v) => Const::I64(v.wrapping_neg()),
+        }
+    }
+}
+
+/// Signed value of `i1` constant, where true means -1.
+fn sgn(v: bool) -> i8 { -(v as i8) }
+
+// Operator traits of constants panic on division by zero, so divisors not known to be nonzero
+// should go through `BinOp::eval`. Ordering and overflow of `i1` take its signed value, as
+// `sext` does.
+macro_rules! bin_arith_impl {
+    ($trait:ty, $func:ident, $wrap:ident) => {
+        impl $trait for Const {
+            type Output = Self;
+            fn $func(self, rhs: Self) -> Self::Output {
+                match (self, rhs) {
+                    (Const::I1(l), Const::I1(r)) => Const::I1(sgn(l).$wrap(sgn(r)) & 1 != 0),
+                    (Const::I8(l), Const::I8(r)) => Const::I8(l.$wrap(r)),
+                    (Const::I16(l), Const::I16(r)) => Const::I16(l.$wrap(r)),
+                    (Const::I32(l), Const::I32(r)) => Const::I32(l.$wrap(r)),
+                    (Const::I64(l), Const::I64(r)) => Const::I64(l.$wrap(r)),
+                    _ => unreachable!()
+                }
+            }
+        }
+    };
+}
+
+bin_arith_impl!(Add, add, wrapping_add);
+bin_arith_impl!(Sub, sub, wrapping_sub);
+bin_arith_impl!(Mul, mul, wrapping_mul);
+bin_arith_impl!(Div, div, wrapping_div);
+bin_arith_impl!(Rem, rem, wrapping_rem);
+
+macro_rules! bin_shift_impl {
+    ($trait:ty, $func:ident, $wrap:ident) => {
+        impl $trait for Const {
+            type Output = Self;
+            fn $func(self, rhs: Self) -> Self::Output {
+                match (self, rhs) {
+                    // Shift amount of `i1` is always masked to zero
+                    (Const::I1(l), Const::I1(_)) => Const::I1(l),
+                    (Const::I8(l), Const::I8(r)) => Const::I8(l.$wrap(r as u32)),
+                    (Const::I16(l), Const::I16(r)) => Const::I16(l.$wrap(r as u32)),
+                    (Const::I32(l), Const::I32(r)) => Const::I32(l.$wrap(r as u32)),
+                    (Const::I64(l), Const::I64(r)) => Const::I64(l.$wrap(r as u32)),
+                    _ => unreachable!()
+                }
+            }
+        }
+    };
+}
+
+bin_shift_impl!(Shl, shl, wrapping_shl);
+bin_shift_impl!(Shr, shr, wrapping_shr);
+
+macro_rules! bin_bitwise_impl {
+    ($trait:ty, $func:ident, $op:tt) => {
+        impl $trait for Const {
+            type Output = Self;
+
+            fn $func(self, rhs: Self) -> Self::Output {
+                match (self, rhs) {
+                    (Const::I1(l), Const::I1(r)) => Const::I1(l $op r),
+                    (Const::I8(l), Const::I8(r)) => Const::I8(l $op r),
+                    (Const::I16(l), Const::I16(r)) => Const::I16(l $op r),
+                    (Const::I32(l), Const::I32(r)) => Const::I32(l $op r),
+                    (Const::I64(l), Const::I64(r)) => Const::I64(l $op r),
+                    _ => unreachable!()
+                }
+            }
+        }
+    };
+}
+
+bin_bitwise_impl!(BitAnd, bitand, &);
+bin_bitwise_impl!(BitOr, bitor, |);
+bin_bitwise_impl!(BitXor, bitxor, ^);
+
+macro_rules! cmp_ord_impl {
+    ($func:ident, $op:tt) => {
+        impl Const {
+            pub fn $func(self, rhs: Self) -> Self {
+                match (self, rhs) {
+                    (Const::I1(l), Const::I1(r)) => Const::I1(sgn(l) $op sgn(r)),
+                    (Const::I8(l), Const::I8(r)) => Const::I1(l $op r),
+                    (Const::I16(l), Const::I16(r)) => Const::I1(l $op r),
+                    (Const::I32(l), Const::I32(r)) => Const::I1(l $op r),
+                    (Const::I64(l), Const::I64(r)) => Const::I1(l $op r),
+                    _ => unreachable!()
+                }
+            }
+        }
+    };
+}
+
+cmp_ord_impl!(less_than, <);
+cmp_ord_impl!(less_eq, <=);
+cmp_ord_impl!(greater_than, >);
+cmp_ord_impl!(greater_eq, >=);
+
+// Note that the result is `Const::I1`, not bool
+macro_rules! cmp_eq_impl {
+    ($func:ident, $op:tt) => {
+        impl Const {
+            pub fn $func(self, rhs: Self) -> Self {
+                match (self, rhs) {
+                    (Const::I1(l), Const::I1(r)) => Const::I1(l $op r),
+                    (Const::I8(l), Const::I8(r)) => Const::I1(l $op r),
+                    (Const::I16(l), Const::I16(r)) => Const::I1(l $op r),
+                    (Const::I32(l), Const::I32(r)) => Const::I1(l $op r),
+                    (Const::I64(l), Const::I64(r)) => Const::I1(l $op r),
+                    _ => unreachable!()
+                }
+            }
+        }
+    };
+}
+
+// Compute whether the signed arithmetic operation overflows. The result is `Const::I1`.
+macro_rules! overflow_impl {
+    ($func:ident, $ov:ident) => {
+        impl Const {
+            pub fn $func(self, rhs: Self) -> Self {
+                match (self, rhs) {
+                    (Const::I1(l), Const::I1(r)) => {
+                        let v = sgn(l).$ov(sgn(r)).0;
+                        Const::I1(!(-1..=0).contains(&v))
+                    }
+                    (Const::I8(l), Const::I8(r)) => Const::I1(l.$ov(r).1),
+                    (Const::I16(l), Const::I16(r)) => Const::I1(l.$ov(r).1),
+                    (Const::I32(l), Const::I32(r)) => Const::I1(l.$ov(r).1),
+                    (Const::I64(l), Const::I64(r)) => Const::I1(l.$ov(r).1),
+                    _ => unreachable!()
+                }
+            }
+        }
+    };
+}
+
+overflow_impl!(add_ov, overflowing_add);
+overflow_impl!(sub_ov, overflowing_sub);
+overflow_impl!(mul_ov, overflowing_mul);
+
+// TO avoid colliding with library trait `Eq` and `Ne`, its method name is `e` and `n`.
+cmp_eq_impl!(equal, ==);
+cmp_eq_impl!(not_eq, !=);
+
+#[test]
+fn test_const_eval() {
+    use crate::lang::value::INT_WIDTHS;
+
+    for w in INT_WIDTHS.iter() {
+        let ty = Type::I(*w);
+        let c = |v: i64| Const::from_i64(v, &ty);
+        let min_val = i64::MIN >> (64 - *w);
+        let min = c(min_val);
+
+        // Division by zero has no result, and overflow of division wraps
+        assert_eq!(BinOp::Div.eval(c(1), c(0)), None);
+        assert_eq!(BinOp::Mod.eval(c(1), c(0)), None);
+        assert_eq!(BinOp::Div.eval(min, c(-1)), Some(min));
+        assert_eq!(BinOp::Mod.eval(min, c(-1)), Some(c(0)));
+
+        // Arithmetic wraps and shift amounts are masked
+        assert_eq!(BinOp::Sub.eval(min, c(1)), Some(c(-1) ^ min));
+        assert_eq!(BinOp::SubOv.eval(min, c(1)), Some(Const::I1(*w > 1)));
+        assert_eq!(BinOp::Shl.eval(c(1), c(*w as i64)), Some(c(1)));
+        assert_eq!(UnOp::Neg.eval(min), min);
+        assert_eq!(CastOp::SExt.eval(min, &Type::I(64)), Some(Const::I64(min_val)));
+        assert_eq!(CastOp::ZExt.eval(c(-1), &Type::I(64)),
+                   Some(Const::I64(((1u128 << *w) - 1) as i64)));
+    }
+}
@@ -0,0 +1,120 @@
+use std::ops::{Add, Deref};
+
+use crate::lang::func::Fn;
+use crate::lang::inst::{BinOp, Inst, MemOrd};
+use crate::lang::value::{Type, Typed, Value};
+
+/// Estimates of costs of instructions, consulted by transformations whose profitability
+/// depends on the target, such as inlining.
+pub trait CostModel {
+    /// Estimated number of clock cycles to execute `instr`.
+    fn latency(&self, instr: &Inst) -> usize;
+
+    /// Estimated size of machine code of `instr`, in number of machine instructions.
+    fn size(&self, instr: &Inst) -> usize;
+
+    /// Estimated size of machine code of function `func`.
+    fn fn_size(&self, func: &Fn) -> usize {
+        func.dfs().map(|b| b.inst.borrow().iter().map(|i| self.size(i)).sum::<usize>()).sum()
+    }
+}
+
+/// Target-independent cost model
+/// Each instruction takes one machine instruction, except that phis take none, as they are
+/// usually coalesced away. Latencies are also used by the interpreter to count execution time.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct DefaultCost;
+
+impl CostModel for DefaultCost {
+    fn latency(&self, instr: &Inst) -> usize {
+        let mut time = match instr {
+            Inst::Mov { src: _, dst: _ } => MOV,
+            Inst::Un { op: _, opd: _, dst: _ } => UN_OP,
+            Inst::Cast { op: _, opd: _, dst: _ } => MOV,
+            Inst::Bin { op, fst, snd: _, dst: _ } => {
+                let ty = fst.borrow().get_type();
+                match op {
+                    op if op.is_bitwise() | op.is_cmp() | op.is_shift() => FAST_BIN,
+                    BinOp::Add | BinOp::Sub | BinOp::AddOv | BinOp::SubOv => FAST_BIN,
+                    BinOp::Mul | BinOp::MulOv => IMUL,
+                    BinOp::Div | BinOp::Mod => match ty {
+                        Type::I(64) => I64_DIV,
+                        Type::I(_) => IDIV,
+                        _ => unreachable!()
+                    }
+                    _ => unreachable!()
+                }
+            }
+            Inst::Call { func: _, arg, dst: _ } => CALL + arg.len() * MOV,
+            // Target of indirect call is moved to a register first
+            Inst::CallInd { func_ptr: _, arg, dst: _ } => CALL + (arg.len() + 1) * MOV,
+            Inst::Ret { val: _ } => RET,
+            Inst::Jmp { tgt: _ } | Inst::Br { cond: _, tr: _, fls: _ } => JMP,
+            Inst::Unreachable | Inst::Abort { msg: _ } => CALL,
+            Inst::Phi { src: _, dst: _ } => MOV,
+            Inst::Alloc { dst: _ } => MOV,
+            Inst::New { dst: _, len: _ } => NEW,
+            Inst::Ptr { base: _, off, ind, dst: _ } => {
+                let mut opd: Vec<_> = ind.iter().collect();
+                if let Some(off) = off.as_ref() { opd.push(off) }
+                opd.iter().map(|opd| {
+                    match opd.borrow().deref() {
+                        // all constant offset can be computed at irc time
+                        Value::Const(_) => 0,
+                        // variable offset require one multiplication and one addition
+                        Value::Var(_) => IMUL + FAST_BIN
+                    }
+                }).fold(1, Add::add)
+            }
+            Inst::Ld { ptr: _, dst: _ } | Inst::St { src: _, ptr: _ } => MEM,
+            // Length is only known at runtime, so they are counted as library calls
+            Inst::Memcpy { src: _, ptr: _, len: _ } | Inst::Memset { src: _, ptr: _, len: _ } =>
+                CALL + 3 * MOV,
+            // Relaxed accesses are plain ones on common targets, while others need barriers
+            Inst::AtomicLd { ord, ptr: _, dst: _ } | Inst::AtomicSt { ord, src: _, ptr: _ } =>
+                if *ord == MemOrd::Relaxed { MEM } else { ATOMIC },
+            Inst::AtomicRmw { op: _, ord: _, ptr: _, val: _, dst: _ } => ATOMIC,
+        };
+        instr.dsts().iter().filter(|dst| !dst.borrow().is_local_var())
+            .for_each(|_| time += GLB_PEN);
+        time
+    }
+
+    fn size(&self, instr: &Inst) -> usize {
+        match instr {
+            Inst::Phi { src: _, dst: _ } => 0,
+            _ => 1
+        }
+    }
+}
+
+// Weights for all basic operations
+// These values are based on number if clock cycles to do the corresponding computation in modern
+// processors. They can be changed to suit your need.
+// See [https://www.agner.org/optimize/]
+
+/// Move between registers
+const MOV: usize = 1;
+/// If the instruction writes to global variable, add this additional penalty.
+const GLB_PEN: usize = 1;
+/// Unary operations
+const UN_OP: usize = 1;
+/// Binary operations that can be done fast
+const FAST_BIN: usize = 1;
+/// Multiplication of integers
+const IMUL: usize = 2;
+/// Division of integers
+const IDIV: usize = 10;
+const I64_DIV: usize = 40;
+/// Call a function
+const CALL: usize = 3;
+/// Pop stack pointer
+const RET: usize = 1;
+/// Jump
+const JMP: usize = 1;
+/// Allocate memory on heap
+const NEW: usize = 10;
+/// Memory access
+const MEM: usize = 2;
+/// Atomic memory access with a barrier, or locked read-modify-write
+const ATOMIC: usize = 20;
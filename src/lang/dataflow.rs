@@ -0,0 +1,190 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, Fn};
+use crate::lang::graph::Vertex;
+use crate::lang::inst::InstRef;
+use crate::lang::value::SymbolRef;
+
+/// Direction in which facts are propagated along the CFG
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Direction {
+    /// From entrance to exits, along edges
+    Forward,
+    /// From exits to entrance, against edges
+    Backward,
+}
+
+/// A data-flow analysis, defined by a lattice of facts and transfer functions of blocks.
+/// The analysis is solved by `Fn::solve`, which iterates the data-flow equations until a fixed
+/// point is reached.
+pub trait DataFlow {
+    /// Lattice value of facts
+    type Val: Clone + Eq;
+
+    /// Direction of this analysis.
+    fn dir(&self) -> Direction;
+
+    /// Value flowing into the entrance (forward) or out of exits (backward) of the function.
+    fn boundary(&self, func: &Fn) -> Self::Val;
+
+    /// Initial value of other blocks, which is the identity of `meet`.
+    fn init(&self) -> Self::Val;
+
+    /// Combine values flowing from different edges.
+    fn meet(&self, a: &Self::Val, b: &Self::Val) -> Self::Val;
+
+    /// Compute value at the other end of `block`, given `val` at the end where facts flow in.
+    fn transfer(&self, block: &BlockRef, val: &Self::Val) -> Self::Val;
+
+    /// Value passed along the edge from `from` to `to`, which is `val` by default. For forward
+    /// analyses, `val` is out of `from`, otherwise it is into `to`.
+    fn edge(&self, _from: &BlockRef, _to: &BlockRef, val: &Self::Val) -> Self::Val {
+        val.clone()
+    }
+}
+
+/// Solution of a data-flow analysis, at both ends of each reachable block
+#[derive(Debug)]
+pub struct Solution<V> {
+    /// Value at the entrance of each block
+    pub inn: HashMap<BlockRef, V>,
+    /// Value at the exit of each block
+    pub out: HashMap<BlockRef, V>,
+}
+
+impl Fn {
+    /// Solve data-flow analysis `df` over reachable blocks of this function. Blocks are visited
+    /// in reverse post-order for forward analyses, and post-order for backward ones, so that
+    /// few iterations are needed.
+    pub fn solve<A: DataFlow>(&self, df: &A) -> Solution<A::Val> {
+        let fwd = df.dir() == Direction::Forward;
+        let ent = self.ent.borrow().clone();
+        let blocks: Vec<BlockRef> = if fwd { ent.rpo().collect() } else { ent.po().collect() };
+        let mut inn: HashMap<_, _> = blocks.iter().map(|b| (b.clone(), df.init())).collect();
+        let mut out = inn.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in &blocks {
+                // Meet values along incoming edges, including the boundary
+                let (from, to) = if fwd { (&mut inn, &mut out) } else { (&mut out, &mut inn) };
+                let mut val = None;
+                let mut meet = |v: A::Val| val = Some(match val.take() {
+                    Some(acc) => df.meet(&acc, &v),
+                    None => v
+                });
+                if fwd {
+                    if *block == ent { meet(df.boundary(self)) }
+                    block.pred().iter().filter(|p| to.contains_key(*p))
+                        .for_each(|p| meet(df.edge(p, block, &to[p])));
+                } else {
+                    if block.succ.borrow().is_empty() { meet(df.boundary(self)) }
+                    block.succ.borrow().iter()
+                        .for_each(|s| meet(df.edge(block, s, &to[s])));
+                }
+                let val = val.unwrap_or_else(|| df.init());
+
+                // Transfer through this block
+                let res = df.transfer(block, &val);
+                if val != from[block] || res != to[block] {
+                    changed = true;
+                    from.insert(block.clone(), val);
+                    to.insert(block.clone(), res);
+                }
+            }
+        }
+        Solution { inn, out }
+    }
+}
+
+/// A definition of a local variable, either by an instruction or as a parameter if the
+/// instruction is `None`.
+pub type Def = (SymbolRef, Option<InstRef>);
+
+/// Reaching definitions analysis of local variables.
+/// This is mostly useful for functions not in SSA form, where a variable may be defined by
+/// several instructions.
+struct ReachDefs {
+    /// Definitions in each block that reach its exit
+    gen: HashMap<BlockRef, HashSet<Def>>,
+    /// Variables defined in each block
+    defined: HashMap<BlockRef, HashSet<SymbolRef>>,
+}
+
+impl DataFlow for ReachDefs {
+    type Val = HashSet<Def>;
+
+    fn dir(&self) -> Direction { Direction::Forward }
+
+    fn boundary(&self, func: &Fn) -> Self::Val {
+        func.param.iter().map(|p| (p.borrow().clone(), None)).collect()
+    }
+
+    fn init(&self) -> Self::Val { HashSet::new() }
+
+    fn meet(&self, a: &Self::Val, b: &Self::Val) -> Self::Val { a.union(b).cloned().collect() }
+
+    fn transfer(&self, block: &BlockRef, val: &Self::Val) -> Self::Val {
+        val.iter().filter(|(sym, _)| !self.defined[block].contains(sym))
+            .chain(self.gen[block].iter()).cloned().collect()
+    }
+}
+
+impl Fn {
+    /// Compute definitions of local variables that reach both ends of each reachable block.
+    pub fn reach_defs(&self) -> Solution<HashSet<Def>> {
+        let mut df = ReachDefs { gen: HashMap::new(), defined: HashMap::new() };
+        for block in self.dfs() {
+            let mut last: HashMap<SymbolRef, InstRef> = HashMap::new();
+            for instr in block.inst.borrow().iter() {
+                for dst in instr.dsts() {
+                    let dst = dst.borrow();
+                    if dst.is_local_var() { last.insert(dst.deref().clone(), instr.clone()); }
+                }
+            }
+            df.defined.insert(block.clone(), last.keys().cloned().collect());
+            df.gen.insert(block, last.into_iter().map(|(sym, i)| (sym, Some(i))).collect());
+        }
+        self.solve(&df)
+    }
+}
+
+#[test]
+fn test_reach_defs() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+
+    let src = r#"
+fn @f($n: i64) -> i64 {
+%Begin:
+    $i <- mov i64 0
+    jmp %Cond
+%Cond:
+    $c <- lt i64 $i, $n
+    br $c ? %Body : %End
+%Body:
+    $i <- add i64 $i, 1
+    $n <- sub i64 $n, 1
+    jmp %Cond
+%End:
+    ret $i
+}
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let func = &pro.func[0];
+    let reach = func.reach_defs();
+    let block = |name: &str| func.dfs().find(|b| b.name == name).unwrap();
+    let count = |name: &str, var: &str, param: bool| reach.inn[&block(name)].iter()
+        .filter(|(sym, i)| sym.name() == var && i.is_none() == param).count();
+
+    // Both definitions of `$i` reach the condition, as well as the parameter `$n` and its
+    // redefinition in the loop
+    assert_eq!(count("Cond", "i", false), 2);
+    assert_eq!(count("Cond", "n", true), 1);
+    assert_eq!(count("Cond", "n", false), 1);
+    // Only the definition in the loop reaches its exit
+    assert_eq!(reach.out[&block("Body")].iter().filter(|(sym, _)| sym.name() == "i").count(), 1);
+    assert_eq!(reach.inn[&block("Begin")].len(), 1);
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Error, Formatter};
+use std::io::sink;
+
+use crate::lang::func::{BlockRef, Fn, FnRef};
+use crate::lang::print::Printer;
+use crate::lang::Program;
+
+/// Difference between two versions of a program.
+/// Functions are matched by name, and blocks are matched by label within matched functions.
+/// Instructions of matched blocks are compared by their printed form. Items are ordered as in
+/// the new program, followed by removed ones in their order in the old program, so the output
+/// is stable across runs.
+pub struct ProgramDiff {
+    pub func: Vec<FnDiff>,
+}
+
+/// Difference of a function
+pub enum FnDiff {
+    /// Function only in new program, with its signature
+    Added(String),
+    /// Function only in old program, with its signature
+    Removed(String),
+    /// Function in both programs, whose signature or body differs
+    Changed {
+        /// Signature in the new program
+        sig: String,
+        /// Old signature, if it differs from the new one
+        old_sig: Option<String>,
+        block: Vec<BlockDiff>,
+    },
+}
+
+/// Difference of a block
+pub enum BlockDiff {
+    /// Block only in new function, with its instructions
+    Added(String, Vec<String>),
+    /// Block only in old function, with its instructions
+    Removed(String, Vec<String>),
+    /// Block in both functions whose instructions differ
+    Changed(String, Vec<LineDiff>),
+}
+
+/// Difference of an instruction in matched blocks
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum LineDiff {
+    Same(String),
+    Added(String),
+    Removed(String),
+}
+
+/// Compute difference from `old` to `new`.
+pub fn diff(old: &Program, new: &Program) -> ProgramDiff {
+    let old_fn: HashMap<&str, &FnRef> = old.func.iter().map(|f| (f.name.as_str(), f)).collect();
+    let new_fn: HashMap<&str, &FnRef> = new.func.iter().map(|f| (f.name.as_str(), f)).collect();
+    let mut func = vec![];
+    for f in new.func.iter() {
+        match old_fn.get(f.name.as_str()) {
+            Some(o) => func.extend(diff_fn(o, f)),
+            None => func.push(FnDiff::Added(fmt_sig(f)))
+        }
+    }
+    old.func.iter().filter(|f| !new_fn.contains_key(f.name.as_str()))
+        .for_each(|f| func.push(FnDiff::Removed(fmt_sig(f))));
+    ProgramDiff { func }
+}
+
+impl ProgramDiff {
+    /// Whether the two programs are the same
+    pub fn is_empty(&self) -> bool { self.func.is_empty() }
+}
+
+impl Display for ProgramDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        for (i, func) in self.func.iter().enumerate() {
+            if i > 0 { writeln!(f)?; }
+            match func {
+                FnDiff::Added(sig) => writeln!(f, "+{}", sig)?,
+                FnDiff::Removed(sig) => writeln!(f, "-{}", sig)?,
+                FnDiff::Changed { sig, old_sig, block } => {
+                    match old_sig {
+                        Some(old) => writeln!(f, "-{}\n+{}", old, sig)?,
+                        None => writeln!(f, " {}", sig)?
+                    }
+                    for blk in block {
+                        match blk {
+                            BlockDiff::Added(name, inst) => {
+                                writeln!(f, "+%{}:", name)?;
+                                inst.iter().try_for_each(|s| writeln!(f, "+    {}", s))?;
+                            }
+                            BlockDiff::Removed(name, inst) => {
+                                writeln!(f, "-%{}:", name)?;
+                                inst.iter().try_for_each(|s| writeln!(f, "-    {}", s))?;
+                            }
+                            BlockDiff::Changed(name, line) => {
+                                writeln!(f, " %{}:", name)?;
+                                for l in line {
+                                    match l {
+                                        LineDiff::Same(s) => writeln!(f, "     {}", s)?,
+                                        LineDiff::Added(s) => writeln!(f, "+    {}", s)?,
+                                        LineDiff::Removed(s) => writeln!(f, "-    {}", s)?,
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn diff_fn(old: &Fn, new: &Fn) -> Option<FnDiff> {
+    let (old_sig, sig) = (fmt_sig(old), fmt_sig(new));
+    let old_blk: Vec<BlockRef> = old.rpo().collect();
+    let new_blk: Vec<BlockRef> = new.rpo().collect();
+    let find = |list: &Vec<BlockRef>, name: &str| list.iter().find(|b| b.name == name).cloned();
+    let mut block = vec![];
+    for b in new_blk.iter() {
+        let inst = fmt_block(new, b);
+        match find(&old_blk, &b.name) {
+            Some(o) => {
+                let old_inst = fmt_block(old, &o);
+                if old_inst != inst {
+                    block.push(BlockDiff::Changed(b.name.clone(), diff_lines(&old_inst, &inst)))
+                }
+            }
+            None => block.push(BlockDiff::Added(b.name.clone(), inst))
+        }
+    }
+    old_blk.iter().filter(|b| find(&new_blk, &b.name).is_none())
+        .for_each(|b| block.push(BlockDiff::Removed(b.name.clone(), fmt_block(old, b))));
+
+    if old_sig == sig && block.is_empty() { return None; }
+    Some(FnDiff::Changed {
+        old_sig: if old_sig != sig { Some(old_sig) } else { None },
+        sig,
+        block,
+    })
+}
+
+/// Compare two lists of lines with their longest common subsequence.
+fn diff_lines(old: &[String], new: &[String]) -> Vec<LineDiff> {
+    let (m, n) = (old.len(), new.len());
+    let mut lcs = vec![vec![0usize; n + 1]; m + 1];
+    for i in (0..m).rev() {
+        for j in (0..n).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            }
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut line = vec![];
+    while i < m || j < n {
+        if i < m && j < n && old[i] == new[j] {
+            line.push(LineDiff::Same(new[j].clone()));
+            i += 1;
+            j += 1;
+        } else if i < m && (j == n || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            // Removed lines go before added ones
+            line.push(LineDiff::Removed(old[i].clone()));
+            i += 1;
+        } else {
+            line.push(LineDiff::Added(new[j].clone()));
+            j += 1;
+        }
+    }
+    line
+}
+
+fn fmt_sig(func: &Fn) -> String {
+    let mut sink = sink();
+    let sig = Printer::new(&mut sink).fmt_sig(func);
+    if func.attrib.is_empty() { return sig; }
+    let attrib: Vec<_> = func.attrib.iter().map(|a| a.to_string()).collect();
+    format!("[{}] {}", attrib.join(", "), sig)
+}
+
+fn fmt_block(func: &Fn, block: &BlockRef) -> Vec<String> {
+    let mut sink = sink();
+    let printer = Printer::new(&mut sink);
+    block.inst.borrow().iter().map(|instr| printer.fmt_instr(func, instr)).collect()
+}
+
+#[test]
+fn test_diff() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::{FnPass, copy::CopyProp};
+
+    let src = r#"
+@r: i64
+
+[ssa]
+fn @f($a: i64) -> i64 {
+%Begin:
+    $b <- mov i64 $a
+    $c <- add i64 $b, 1
+    ret $c
+}
+
+[ssa]
+fn @main() {
+%Begin:
+    @r <- call i64 @f(1)
+    ret
+}
+"#;
+    let build = || Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let old = build();
+    let mut new = build();
+    assert!(diff(&old, &new).is_empty());
+
+    FnPass::run(&mut CopyProp::new(), &mut new);
+    let d = diff(&old, &new);
+    println!("{}", d);
+    assert_eq!(d.func.len(), 1);
+    let expect = " [ssa] fn @f($a: i64) -> i64\n %Begin:\n-    $b <- mov i64 $a\n\
+                  -    $c <- add i64 $b, 1\n+    $c <- add i64 $a, 1\n     ret $c\n";
+    assert_eq!(d.to_string(), expect);
+}
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+use std::iter::once;
+use std::ops::Deref;
+
+use crate::lang::func::Fn;
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::value::{SymbolRef, Typed, Value};
+
+/// Source of a pointer held by a local variable
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum PtrSrc {
+    /// Memory allocated by this `alloc` or `new` instruction in the function
+    Alloc(InstRef),
+    /// Pointer not known to be derived from allocations in the function, such as parameters,
+    /// loaded values and results of calls
+    Unknown,
+}
+
+/// Result of escape analysis of a function.
+/// An allocation escapes if its address, or any pointer derived from it, is stored to memory,
+/// assigned to a global variable, passed to a call, returned, or cast to other types, because
+/// the memory can then be accessed outside of what this analysis tracks.
+#[derive(Debug)]
+pub struct EscapeInfo {
+    /// Possible sources of pointers held by each local variable
+    pub src: HashMap<SymbolRef, HashSet<PtrSrc>>,
+    /// Allocations that escape the function
+    pub escaped: HashSet<InstRef>,
+    /// Allocations whose memory may be loaded in the function
+    pub read: HashSet<InstRef>,
+}
+
+impl EscapeInfo {
+    /// Whether the address of allocation `alloc` escapes.
+    pub fn escapes(&self, alloc: &InstRef) -> bool { self.escaped.contains(alloc) }
+
+    /// Allocations `opd` may point to. Returns `None` if it may point to other memory, or if
+    /// no source of it is found.
+    pub fn allocs_of(&self, opd: &Value) -> Option<Vec<InstRef>> {
+        let src = self.src_of(opd);
+        if src.is_empty() { return None; }
+        src.into_iter().map(|s| match s {
+            PtrSrc::Alloc(a) => Some(a),
+            PtrSrc::Unknown => None
+        }).collect()
+    }
+
+    /// Possible sources of pointer `opd`.
+    fn src_of(&self, opd: &Value) -> HashSet<PtrSrc> {
+        match opd {
+            Value::Var(sym) if sym.is_local_var() =>
+                self.src.get(sym).cloned().unwrap_or_default(),
+            Value::Var(_) => once(PtrSrc::Unknown).collect(),
+            Value::Const(_) => HashSet::new()
+        }
+    }
+}
+
+impl Fn {
+    /// Analyze whether addresses of allocations in this function escape. The analysis is flow
+    /// insensitive, so it also applies to functions not in SSA form.
+    pub fn escape(&self) -> EscapeInfo {
+        let inst: Vec<InstRef> = self.dfs().flat_map(|b| b.inst.borrow().clone()).collect();
+        let mut info = EscapeInfo {
+            src: HashMap::new(),
+            escaped: HashSet::new(),
+            read: HashSet::new(),
+        };
+        for param in &self.param {
+            info.src.insert(param.borrow().clone(), once(PtrSrc::Unknown).collect());
+        }
+
+        // Propagate sources along copies and pointer arithmetic until a fixed point is reached
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for instr in &inst {
+                let new: HashSet<PtrSrc> = match instr.as_ref() {
+                    Inst::Alloc { dst: _ } | Inst::New { dst: _, len: _ } =>
+                        once(PtrSrc::Alloc(instr.clone())).collect(),
+                    Inst::Mov { src, dst: _ } => info.src_of(&src.borrow()),
+                    Inst::Phi { src, dst: _ } =>
+                        src.iter().flat_map(|(_, v)| info.src_of(&v.borrow())).collect(),
+                    Inst::Ptr { base, off: _, ind: _, dst: _ } => info.src_of(&base.borrow()),
+                    _ => once(PtrSrc::Unknown).collect()
+                };
+                for dst in instr.dsts() {
+                    let dst = dst.borrow();
+                    if !dst.is_local_var() || !dst.get_type().orig().is_ptr() { continue; }
+                    let cur = info.src.entry(dst.deref().clone()).or_default();
+                    let len = cur.len();
+                    cur.extend(new.iter().cloned());
+                    changed |= cur.len() != len;
+                }
+            }
+        }
+
+        // Find escaping and loaded allocations
+        for instr in &inst {
+            let opd = match instr.as_ref() {
+                Inst::Mov { src, dst } if !dst.borrow().is_local_var() => vec![src],
+                Inst::Mov { src: _, dst: _ } | Inst::Phi { src: _, dst: _ } => vec![],
+                Inst::Ptr { base: _, off: _, ind: _, dst: _ } => vec![],
+                // Comparison of addresses does not expose the memory
+                Inst::Bin { op: _, fst: _, snd: _, dst: _ } => vec![],
+                Inst::Ld { ptr, dst: _ } | Inst::Memcpy { src: ptr, ptr: _, len: _ } => {
+                    for src in info.src_of(&ptr.borrow()) {
+                        if let PtrSrc::Alloc(a) = src { info.read.insert(a); }
+                    }
+                    vec![]
+                }
+                Inst::St { src, ptr: _ } | Inst::Memset { src, ptr: _, len: _ } => vec![src],
+                _ => instr.src()
+            };
+            for v in opd {
+                for src in info.src_of(&v.borrow()) {
+                    if let PtrSrc::Alloc(a) = src { info.escaped.insert(a); }
+                }
+            }
+        }
+        info
+    }
+}
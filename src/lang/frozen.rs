@@ -0,0 +1,151 @@
+use crate::lang::arena::{FuncBody, Globals};
+use crate::lang::layout::DataLayout;
+use crate::lang::Program;
+use crate::lang::stats::Stats;
+use crate::lang::value::{Symbol, SymbolKind};
+
+/// Immutable snapshot of a program, which is `Send + Sync`.
+/// The IR is built on `Rc` and `RefCell`, so a `Program` cannot be shared among threads. A
+/// frozen program stores the global symbols in `Globals`, and each function body in a
+/// `FuncBody`, whose types, symbols, blocks and instructions refer to each other by indices.
+/// A mutable program can be rebuilt from it by `thaw`.
+#[derive(Clone, Debug)]
+pub struct FrozenProgram {
+    /// Type aliases, global variables and function signatures
+    pub glob: Globals,
+    /// Function bodies, in the order of `glob.func`
+    pub func: Vec<FuncBody>,
+    /// Statistics of the program
+    pub stats: Stats,
+    pub layout: DataLayout,
+}
+
+impl Program {
+    /// Take an immutable snapshot of this program, which can be shared among threads.
+    pub fn freeze(&self) -> FrozenProgram {
+        let glob = Globals::new(self);
+        FrozenProgram {
+            func: self.func.iter().map(|f| FuncBody::from_fn(f, &glob)).collect(),
+            glob,
+            stats: self.stats(),
+            layout: self.layout,
+        }
+    }
+}
+
+impl FrozenProgram {
+    /// Find body of function by its name.
+    pub fn find_fn(&self, name: &str) -> Option<&FuncBody> {
+        self.glob.find_fn(name).map(|id| &self.func[id.index()])
+    }
+
+    /// Rebuild a mutable program from this snapshot.
+    pub fn thaw(&self) -> Program {
+        // Functions are declared as stubs first, and then replaced by the rebuilt ones, so
+        // that calls among them are redirected to the final functions.
+        let global = self.glob.declare();
+        let vars = self.glob.vars.iter().map(|data| {
+            match global.find_kind(&data.name, SymbolKind::Global).unwrap().as_ref() {
+                Symbol::Global(var) => var.clone(),
+                _ => unreachable!()
+            }
+        }).collect();
+        let func = self.glob.func.iter().map(|decl| {
+            match global.find_kind(&decl.name, SymbolKind::Func).unwrap().as_ref() {
+                Symbol::Func(func) => func.clone(),
+                _ => unreachable!()
+            }
+        }).collect();
+        let mut pro = Program { vars, func, global, layout: self.layout };
+        for (stub, body) in pro.func.clone().iter().zip(self.func.iter()) {
+            let func = body.to_fn(&self.glob, &pro.global);
+            pro.replace_fn(stub, func);
+        }
+        pro
+    }
+}
+
+#[test]
+fn test_freeze() {
+    use std::sync::Arc;
+    use std::thread;
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::lang::arena::{InstData, Operand, Sym, Ty};
+    use crate::lang::inst::BinOp;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+
+fn @main() {
+%Begin:
+    $a <- call i64 @max(1, 2)
+    @r <- mov i64 $a
+    ret
+}
+
+fn @max($x: i64, $y: i64) -> i64 {
+%A:
+    $c <- gt i64 $x, $y
+    br $c ? %B : %C
+%B:
+    ret $x
+%C:
+    ret $y
+}
+"#;
+    fn share<T: Send + Sync>(v: T) -> Arc<T> { Arc::new(v) }
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let frozen = share(pro.freeze());
+
+    // Query the snapshot from several threads
+    let handles: Vec<_> = (0..4).map(|_| {
+        let frozen = frozen.clone();
+        thread::spawn(move || {
+            let max = frozen.find_fn("max").unwrap();
+            let ent = max.block(max.ent);
+            let c = max.find_block("C").unwrap();
+            (ent.name.clone(), ent.succ.len(), max.pred(c) == [max.ent])
+        })
+    }).collect();
+    for h in handles {
+        assert_eq!(h.join().unwrap(), ("A".to_string(), 2, true));
+    }
+
+    // Instructions and signatures are structured
+    let max = frozen.find_fn("max").unwrap();
+    let decl = frozen.glob.decl(max.func);
+    assert_eq!((decl.param.as_slice(), &decl.ret), (&[Ty::I(64), Ty::I(64)][..], &Ty::I(64)));
+    let (x, y) = (Sym::Local(max.param[0]), Sym::Local(max.param[1]));
+    let inst: Vec<_> = max.block_inst(max.ent).collect();
+    let c = match inst[0] {
+        InstData::Bin { op: BinOp::Gt, fst, snd, dst } => {
+            assert_eq!((*fst, *snd), (Operand::Var(x), Operand::Var(y)));
+            *dst
+        }
+        i => panic!("unexpected {:?}", i)
+    };
+    assert!(matches!(inst[1], InstData::Br { cond, .. } if *cond == Operand::Var(c)));
+    let main = frozen.find_fn("main").unwrap();
+    assert!(matches!(main.block_inst(main.ent).next(),
+        Some(InstData::Call { func, .. }) if *func == max.func));
+    assert_eq!(frozen.stats, pro.stats());
+
+    // Thawed program is the same
+    let print = |pro: &Program| {
+        let mut out = vec![];
+        Printer::new(&mut out).print(pro).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let thawed = frozen.thaw();
+    assert_eq!(print(&thawed), print(&pro));
+    assert_eq!(format!("{:?}", Machine::new().run(&thawed).unwrap()),
+               format!("{:?}", Machine::new().run(&pro).unwrap()));
+    for path in ["test/sum.ir", "test/rec_ty.ir"] {
+        let pro = Program::from_file(path).unwrap();
+        assert_eq!(print(&pro.freeze().thaw()), print(&pro));
+    }
+}
@@ -1,17 +1,20 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{Debug, Error, Formatter};
 use std::iter::FromIterator;
 use std::ops::Deref;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::str::FromStr;
 
+use crate::irc::Loc;
+use crate::lang::blkarg::EdgeArgMap;
 use crate::lang::graph::DomBuilder;
-use crate::lang::inst::{Inst, InstRef};
-use crate::lang::ssa::SsaFlag;
+use crate::lang::inst::{Inst, InstRef, PhiSrc};
+use crate::lang::meta::Metadata;
+use crate::lang::ssa::{DefUseGraph, SsaFlag};
 use crate::lang::util::ExtRc;
-use crate::lang::value::{Scope, SymbolRef, Type, Typed};
+use crate::lang::value::{is_temp_name, Linkage, Scope, SymbolRef, Type, Typed, Value};
 
 #[derive(Debug)]
 pub struct Fn {
@@ -21,6 +24,8 @@ pub struct Fn {
     pub scope: Rc<Scope>,
     /// Attribute list
     pub attrib: Vec<FnAttrib>,
+    /// Visibility of this function outside the module
+    pub linkage: Cell<Linkage>,
     /// Parameter list
     pub param: Vec<RefCell<SymbolRef>>,
     /// Return type
@@ -32,6 +37,20 @@ pub struct Fn {
     /// Whether this function is in SSA form.
     /// This tag should only be set by verification and transformation function.
     pub ssa: SsaFlag,
+    /// Metadata of this function
+    pub meta: RefCell<Metadata>,
+    /// Metadata of instructions in this function.
+    /// Instructions are shared references, so their metadata are stored in this side table.
+    pub inst_meta: RefCell<HashMap<InstRef, Metadata>>,
+    /// Source locations of instructions in this function, if they are built from source.
+    pub inst_loc: RefCell<HashMap<InstRef, Loc>>,
+    /// Order of blocks in emitted code, computed by `CodeLayout`.
+    /// Empty if no layout is computed. Use `layout` method to get a valid order.
+    pub layout: RefCell<Vec<BlockRef>>,
+    /// Parameters of blocks, if this function is in block argument form. See `to_block_args`.
+    pub blk_param: RefCell<HashMap<BlockRef, Vec<RefCell<SymbolRef>>>>,
+    /// Arguments passed along each edge of the CFG, if this function is in block argument form.
+    pub blk_arg: RefCell<EdgeArgMap>,
 }
 
 impl PartialEq for Fn {
@@ -67,29 +86,141 @@ impl Fn {
             name,
             scope: Rc::new(scope),
             attrib,
+            linkage: Default::default(),
             param,
             ret,
             ent: RefCell::new(ExtRc::new(ent)),
             exit: RefCell::new(Default::default()),
             ssa: SsaFlag::new(),
+            meta: Default::default(),
+            inst_meta: Default::default(),
+            inst_loc: Default::default(),
+            layout: Default::default(),
+            blk_param: Default::default(),
+            blk_arg: Default::default(),
         }
     }
 
     pub fn has_attrib(&self, attrib: FnAttrib) -> bool { self.attrib.contains(&attrib) }
+
+    /// Whether this function is an entry point of the program, i.e. `@main` or one marked
+    /// `entry`.
+    pub fn is_entry(&self) -> bool { self.name == "main" || self.has_attrib(FnAttrib::Entry) }
+
+    /// Linkage of this function in emitted code. Entry points are always visible to the linker,
+    /// even if they are internal.
+    pub fn effective_linkage(&self) -> Linkage {
+        match self.linkage.get() {
+            Linkage::Internal if self.is_entry() => Linkage::Export,
+            linkage => linkage
+        }
+    }
+
+    /// Whether this function never writes memory or global variables.
+    pub fn is_readonly(&self) -> bool {
+        self.has_attrib(FnAttrib::ReadOnly) || self.has_attrib(FnAttrib::Pure)
+    }
+
+    /// Get source location of instruction `instr`, if there is one.
+    pub fn inst_loc(&self, instr: &InstRef) -> Option<Loc> {
+        self.inst_loc.borrow().get(instr).cloned()
+    }
+
+    /// Rename symbols in this function according to `map`. All definitions and uses of the
+    /// mapped symbols, including parameters, are replaced. Mapped local symbols are removed from
+    /// the scope, and their replacements are added.
+    pub fn rename_symbols(&self, map: &HashMap<SymbolRef, SymbolRef>) {
+        let get = |sym: &SymbolRef| map.get(sym).cloned().unwrap_or_else(|| sym.clone());
+        self.param.iter().for_each(|p| { p.replace_with(|sym| get(sym)); });
+        for block in self.iter_dom() {
+            for instr in block.inst.borrow().iter() {
+                instr.src().iter().for_each(|opd| {
+                    opd.replace_with(|v| match v {
+                        Value::Var(sym) => Value::Var(get(sym)),
+                        _ => v.clone()
+                    });
+                });
+                instr.dsts().iter().for_each(|dst| { dst.replace_with(|sym| get(sym)); });
+            }
+        }
+        let local: Vec<_> = map.iter().filter(|(old, _)| old.is_local_var()).collect();
+        for (old, _) in local.iter() {
+            if self.scope.find(old.name()).as_ref() == Some(*old) { self.scope.remove(old.name()) }
+        }
+        local.into_iter().for_each(|(_, new)| { self.scope.insert(new.clone()); });
+    }
+
+    /// Remove local variables from the scope of this function which are neither parameters nor
+    /// referred to by any instruction, and return the removed ones.
+    pub fn remove_unused_locals(&self) -> Vec<SymbolRef> {
+        let mut used: HashSet<SymbolRef> = self.param.iter().map(|p| p.borrow().clone())
+            .collect();
+        for block in self.dfs() {
+            for instr in block.inst.borrow().iter() {
+                instr.src().iter().for_each(|opd| if let Value::Var(sym) = opd.borrow().deref() {
+                    used.insert(sym.clone());
+                });
+                instr.dsts().iter().for_each(|dst| { used.insert(dst.borrow().clone()); });
+            }
+        }
+        self.scope.retain(|sym| !sym.is_local_var() || used.contains(sym))
+    }
+
+    /// Tear down the body of this function. Links among blocks and instructions are cleared,
+    /// so that reference cycles formed by loops and recursive calls are broken and the blocks
+    /// can be freed. Only the empty entrance block is left.
+    pub fn drop_body(&self) {
+        let blocks: Vec<_> = self.dfs().collect();
+        for block in blocks {
+            block.inst.borrow_mut().clear();
+            block.succ.borrow_mut().clear();
+            block.pred.borrow_mut().clear();
+            block.child.borrow_mut().clear();
+            block.parent.replace(None);
+        }
+        self.exit.borrow_mut().clear();
+        self.layout.borrow_mut().clear();
+        self.inst_meta.borrow_mut().clear();
+        self.inst_loc.borrow_mut().clear();
+        self.blk_param.borrow_mut().clear();
+        self.blk_arg.borrow_mut().clear();
+    }
 }
 
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
 pub enum FnAttrib {
+    /// Calls to this function should be inlined.
     Inline,
+    /// Calls to this function should never be inlined.
     NoInline,
+    /// This function may read memory, but never writes memory or global variables.
     ReadOnly,
-    Ssa
+    /// This function neither reads nor writes memory or global variables, so its result only
+    /// depends on its arguments.
+    Pure,
+    /// This function never returns to its caller.
+    NoReturn,
+    /// This function is in SSA form.
+    Ssa,
+    /// This function is an entry point of the program besides `@main`, such as `@_start`. It
+    /// has the same signature as `@main`.
+    Entry,
 }
 
 impl ToString for FnAttrib {
     fn to_string(&self) -> String { format!("{:?}", self).to_lowercase() }
 }
 
+impl FnAttrib {
+    /// Whether this attribute cannot be applied together with `other`.
+    pub fn conflicts_with(&self, other: &FnAttrib) -> bool {
+        match (self, other) {
+            (FnAttrib::Inline, FnAttrib::NoInline) | (FnAttrib::NoInline, FnAttrib::Inline) => true,
+            _ => false
+        }
+    }
+}
+
 impl FromStr for FnAttrib {
     type Err = ();
 
@@ -98,13 +229,15 @@ impl FromStr for FnAttrib {
             "inline" => Ok(FnAttrib::Inline),
             "noinline" => Ok(FnAttrib::NoInline),
             "readonly" => Ok(FnAttrib::ReadOnly),
+            "pure" => Ok(FnAttrib::Pure),
+            "noreturn" => Ok(FnAttrib::NoReturn),
             "ssa" => Ok(FnAttrib::Ssa),
+            "entry" => Ok(FnAttrib::Entry),
             _ => Err(())
         }
     }
 }
 
-#[derive(Eq)]
 pub struct BasicBlock {
     /// Name of this basic block
     pub name: String,
@@ -115,15 +248,22 @@ pub struct BasicBlock {
     /// the block. `Vec` is actually used here because we want to keep the insertion order of
     /// blocks.
     /// Predecessor blocks
-    pub pred: RefCell<Vec<BlockRef>>,
+    /// Links to predecessors are weak, so that they do not form reference cycles with links to
+    /// successors. They are accessed with `pred` and its companion methods.
+    pred: RefCell<Vec<Weak<BasicBlock>>>,
     /// Successor blocks
     pub succ: RefCell<Vec<BlockRef>>,
     /// Parent of this block in the dominator tree
     /// This and `child` is dependent on the structure of the CFG. They can only be modified by
-    /// the method of `Func` and granted read-only access by the public method.
-    parent: RefCell<Option<BlockRef>>,
+    /// the method of `Func` and granted read-only access by the public method. Link to parent
+    /// is weak, for the same reason as predecessors.
+    parent: RefCell<Option<Weak<BasicBlock>>>,
     /// Children of this block in the dominator tree
     child: RefCell<Vec<BlockRef>>,
+    /// Metadata of this block
+    pub meta: RefCell<Metadata>,
+    /// Source location of this block, if it is built from source
+    pub loc: RefCell<Option<Loc>>,
 }
 
 pub type BlockRef = ExtRc<BasicBlock>;
@@ -138,6 +278,8 @@ impl PartialEq for BasicBlock {
     fn eq(&self, other: &Self) -> bool { self.name == other.name }
 }
 
+impl Eq for BasicBlock {}
+
 impl Ord for BasicBlock {
     fn cmp(&self, other: &Self) -> Ordering { self.name.cmp(&other.name) }
 }
@@ -161,11 +303,37 @@ impl BasicBlock {
             succ: RefCell::new(vec![]),
             parent: RefCell::new(None),
             child: RefCell::new(Vec::new()),
+            meta: Default::default(),
+            loc: RefCell::new(None),
         }
     }
 
+    /// Whether this is a temporary block, which is created without a name and named by a
+    /// number.
+    pub fn is_temp(&self) -> bool { is_temp_name(&self.name) }
+
     /// Get parent of this block in the dominator tree.
-    pub fn parent(&self) -> Option<BlockRef> { self.parent.borrow().clone() }
+    pub fn parent(&self) -> Option<BlockRef> {
+        self.parent.borrow().as_ref().and_then(ExtRc::upgrade)
+    }
+
+    /// Get predecessors of this block. Those already dropped are skipped.
+    pub fn pred(&self) -> Vec<BlockRef> {
+        self.pred.borrow().iter().filter_map(ExtRc::upgrade).collect()
+    }
+
+    /// Replace predecessors of this block with `pred`.
+    pub fn set_pred(&self, pred: Vec<BlockRef>) {
+        self.pred.replace(pred.iter().map(ExtRc::downgrade).collect());
+    }
+
+    /// Append `block` to predecessors of this block.
+    pub fn add_pred(&self, block: &BlockRef) { self.pred.borrow_mut().push(block.downgrade()) }
+
+    /// Remove `block` from predecessors of this block.
+    pub fn remove_pred(&self, block: &BlockRef) {
+        self.pred.borrow_mut().retain(|p| !block.is_weak_of(p))
+    }
 
     /// Get children of this block in the dominator tree.
     pub fn children(&self) -> Vec<BlockRef> { self.child.borrow().clone() }
@@ -182,6 +350,15 @@ impl BasicBlock {
         }
     }
 
+    /// Whether this block ends with `unreachable` or `abort`, so that the function never
+    /// returns from it.
+    pub fn is_trap(&self) -> bool {
+        match self.inst.borrow().back() {
+            Some(back) => back.deref().is_trap(),
+            None => false
+        }
+    }
+
     /// Visit each instruction in this block
     pub fn for_each<F>(&self, f: F) where F: FnMut(InstRef) {
         self.inst.borrow().iter().cloned().for_each(f)
@@ -189,12 +366,39 @@ impl BasicBlock {
 
     /// Push instruction to the front of the instruction list.
     pub fn push_front(&self, ins: InstRef) {
-        self.inst.borrow_mut().push_front(ins)
+        self.inst.borrow_mut().push_front(ins);
+        if cfg!(debug_assertions) { self.assert_invariant() }
     }
 
     /// Push instruction to the back of the instruction list.
     pub fn push_back(&self, ins: InstRef) {
-        self.inst.borrow_mut().push_back(ins)
+        self.inst.borrow_mut().push_back(ins);
+        if cfg!(debug_assertions) { self.assert_invariant() }
+    }
+
+    /// Keep only the instructions for which `f` returns true. Returns whether any instruction
+    /// is removed.
+    pub fn retain<F>(&self, f: F) -> bool where F: FnMut(&InstRef) -> bool {
+        let len = self.inst.borrow().len();
+        self.inst.borrow_mut().retain(f);
+        if cfg!(debug_assertions) { self.assert_invariant() }
+        self.inst.borrow().len() != len
+    }
+
+    /// Check that the only control flow instruction of this block is at its end, and phis are
+    /// only at its beginning. A block under construction, without a terminator, is accepted.
+    /// This is checked after mutation helpers in debug build, so that bugs of passes are caught
+    /// where the block is corrupted.
+    pub fn assert_invariant(&self) {
+        let inst = self.inst.borrow();
+        for (i, instr) in inst.iter().enumerate() {
+            if instr.is_ctrl() && i + 1 != inst.len() {
+                panic!("%{}: {} is not at the end of block", self.name, instr.name())
+            }
+            if instr.is_phi() && i > 0 && !inst[i - 1].is_phi() {
+                panic!("%{}: phi is not at the beginning of block", self.name)
+            }
+        }
     }
 
     /// Get first instruction of this block
@@ -224,7 +428,8 @@ impl BasicBlock {
     pub fn insert_before_ctrl(&self, instr: InstRef) {
         if self.is_complete() {
             let idx = self.inst.borrow().len() - 1;
-            self.inst.borrow_mut().insert(idx, instr)
+            self.inst.borrow_mut().insert(idx, instr);
+            if cfg!(debug_assertions) { self.assert_invariant() }
         } else {
             self.push_back(instr)
         }
@@ -237,22 +442,31 @@ impl BlockRef {
     /// predecessor set of `to` block. It also modifies target of jump and branch instruction.
     pub fn connect(&self, to: BlockRef) {
         // Modify predecessor and successor list
-        if to.pred.borrow().iter().find(|b| *b == self).is_none() {
-            to.pred.borrow_mut().push(self.clone())
-        }
+        if !to.pred().contains(self) { to.add_pred(self) }
         if self.succ.borrow().iter().find(|b| *b == &to).is_none() {
             self.succ.borrow_mut().push(to.clone())
         }
+        if cfg!(debug_assertions) { self.assert_edge(&to) }
     }
 
     /// Remove a directed edge from this block to another.
     /// If there was an edge before, this is the inverse operation of `connect`. Otherwise,
     /// nothing will be actually done.
     pub fn disconnect(&self, to: &BlockRef) {
-        let pos = to.pred.borrow().iter().position(|b| b == self);
-        pos.map(|i| to.pred.borrow_mut().remove(i));
+        to.remove_pred(self);
         let pos = self.succ.borrow().iter().position(|b| b == to);
         pos.map(|i| self.succ.borrow_mut().remove(i));
+        if cfg!(debug_assertions) { self.assert_edge(to) }
+    }
+
+    /// Check that `to` is a successor of this block if and only if this block is a predecessor
+    /// of `to`.
+    pub fn assert_edge(&self, to: &BlockRef) {
+        let (succ, pred) = (self.succ.borrow().contains(to), to.pred().contains(self));
+        if succ != pred {
+            panic!("asymmetric edge %{} -> %{}: {} in successors, {} in predecessors",
+                   self.name, to.name, succ, pred)
+        }
     }
 
     /// Switch an edge from a block to another
@@ -274,6 +488,89 @@ impl BlockRef {
         }
     }
 
+    /// Replace successor `old` of this block with `new`, retargeting its control flow
+    /// instruction. Phis in `old` no longer take values from this block. Phis in `new` take from
+    /// this block the values they take from `old`, as `old` is bypassed, so `new` should either
+    /// have no phis or be a successor of `old`.
+    pub fn replace_succ(&self, old: &BlockRef, new: BlockRef) {
+        if *old == new { return; }
+        let was_pred = new.pred().contains(self);
+        self.switch_to(old, new.clone());
+        old.repair_phi();
+        if was_pred { return; }
+        new.map_phi_src(|mut src| {
+            let val = src.iter().find(|(p, _)| p.borrow().deref() == old)
+                .map(|(_, v)| v.borrow().clone())
+                .unwrap_or_else(|| {
+                    panic!("phi in %{} takes no value from %{}", new.name, old.name)
+                });
+            src.push((RefCell::new(self.clone()), RefCell::new(val)));
+            src
+        });
+    }
+
+    /// Remove the edge from this block to `succ`. A branch to `succ` and another block becomes a
+    /// jump to the other one, and a jump to `succ` becomes `unreachable`. Phis in `succ` no
+    /// longer take values from this block.
+    pub fn remove_edge(&self, succ: &BlockRef) {
+        let is_succ = |tgt: &RefCell<BlockRef>| tgt.borrow().deref() == succ;
+        let ctrl = match self.inst.borrow().back().map(|i| i.as_ref().clone()) {
+            Some(Inst::Jmp { tgt }) if is_succ(&tgt) => Some(Inst::Unreachable),
+            Some(Inst::Br { cond: _, tr, fls }) if is_succ(&tr) || is_succ(&fls) => {
+                let other = if is_succ(&tr) { fls } else { tr };
+                Some(if is_succ(&other) { Inst::Unreachable } else { Inst::Jmp { tgt: other } })
+            }
+            _ => None
+        };
+        if let Some(ctrl) = ctrl {
+            *self.inst.borrow_mut().back_mut().unwrap() = ExtRc::new(ctrl);
+        }
+        self.disconnect(succ);
+        succ.repair_phi();
+    }
+
+    /// Replace predecessor `old` of this block with `new`, which should not be a predecessor
+    /// yet. Control flow instruction of `old` is retargeted to `new`, and phis in this block take
+    /// from `new` the values they take from `old`. If `new` is not complete, a jump to this
+    /// block is appended to it, so that `new` is placed on the former edge from `old`.
+    pub fn redirect_pred(&self, old: &BlockRef, new: &BlockRef) {
+        if !new.is_complete() {
+            new.push_back(ExtRc::new(Inst::Jmp { tgt: RefCell::new(self.clone()) }));
+        }
+        new.connect(self.clone());
+        old.switch_to(self, new.clone());
+        for instr in self.inst.borrow().iter().take_while(|i| i.is_phi()) {
+            if let Inst::Phi { src, dst: _ } = instr.as_ref() {
+                src.iter().filter(|(p, _)| p.borrow().deref() == old)
+                    .for_each(|(p, _)| { p.replace(new.clone()); });
+            }
+        }
+    }
+
+    /// Remove operands of phis in this block which are taken from blocks that are not its
+    /// predecessors, or which duplicate an earlier operand from the same predecessor. Operands
+    /// missing for some predecessors cannot be repaired, and are left to the verifier.
+    pub fn repair_phi(&self) {
+        let pred = self.pred();
+        self.map_phi_src(|mut src| {
+            let mut found = HashSet::new();
+            src.retain(|(p, _)| {
+                let p = p.borrow().clone();
+                pred.contains(&p) && found.insert(p)
+            });
+            src
+        })
+    }
+
+    /// Rebuild phis in this block with operands mapped by `f`.
+    fn map_phi_src<F>(&self, mut f: F) where F: FnMut(Vec<PhiSrc>) -> Vec<PhiSrc> {
+        for instr in self.inst.borrow_mut().iter_mut().take_while(|i| i.is_phi()) {
+            if let Inst::Phi { src, dst } = instr.as_ref().clone() {
+                *instr = ExtRc::new(Inst::Phi { src: f(src), dst })
+            }
+        }
+    }
+
     /// Decide if this block dominates the given block.
     /// This method has logarithm time complexity. Though a linear time algorithm is possible,
     /// it requires keeping extra data in the block structure.
@@ -283,7 +580,7 @@ impl BlockRef {
             match cur {
                 Some(block) if *self == block => return true,
                 None => return false,
-                _ => cur = cur.unwrap().parent.borrow().clone()
+                _ => cur = cur.unwrap().parent()
             }
         }
     }
@@ -294,6 +591,94 @@ impl BlockRef {
     }
 }
 
+impl BlockRef {
+    /// Create a cursor before the first instruction of this block.
+    pub fn cursor(&self) -> InstCursor {
+        InstCursor { block: self.clone(), cur: None, next: 0, def_use: None }
+    }
+
+    /// Create a cursor which also reports its edits to `def_use`.
+    pub fn tracked_cursor(&self, def_use: &DefUseGraph) -> InstCursor {
+        InstCursor { def_use: Some(def_use.clone()), ..self.cursor() }
+    }
+}
+
+/// Cursor for iterating and rewriting instructions of a block.
+/// The instruction list is only borrowed inside each method call, so the block can be freely
+/// accessed and modified between calls. Instructions inserted after the current one will be
+/// visited, while those inserted before it will not.
+pub struct InstCursor {
+    block: BlockRef,
+    /// Index of current instruction, or `None` if there is no current instruction, which is
+    /// the case before iteration and after erasure
+    cur: Option<usize>,
+    /// Index of the next instruction to visit
+    next: usize,
+    /// Def-use graph updated by edits of this cursor
+    def_use: Option<DefUseGraph>,
+}
+
+impl Iterator for InstCursor {
+    type Item = InstRef;
+
+    fn next(&mut self) -> Option<InstRef> {
+        let instr = self.block.inst.borrow().get(self.next).cloned();
+        match instr {
+            Some(_) => {
+                self.cur = Some(self.next);
+                self.next += 1;
+            }
+            None => self.cur = None
+        }
+        instr
+    }
+}
+
+impl InstCursor {
+    /// Get the block this cursor points into.
+    pub fn block(&self) -> &BlockRef { &self.block }
+
+    /// Get current instruction.
+    pub fn current(&self) -> Option<InstRef> {
+        self.cur.and_then(|i| self.block.inst.borrow().get(i).cloned())
+    }
+
+    /// Insert `instr` before current instruction, or before the next one if there is no
+    /// current instruction.
+    pub fn insert_before(&mut self, instr: InstRef) {
+        let pos = self.cur.unwrap_or(self.next);
+        self.block.inst.borrow_mut().insert(pos, instr.clone());
+        self.cur = self.cur.map(|i| i + 1);
+        self.next += 1;
+        if let Some(du) = &self.def_use { du.insert(&self.block, &instr) }
+    }
+
+    /// Insert `instr` after current instruction, or before the next one if there is no
+    /// current instruction. It will be visited next.
+    pub fn insert_after(&mut self, instr: InstRef) {
+        self.block.inst.borrow_mut().insert(self.next, instr.clone());
+        if let Some(du) = &self.def_use { du.insert(&self.block, &instr) }
+    }
+
+    /// Replace current instruction with `instr`, and return the replaced one.
+    pub fn replace(&mut self, instr: InstRef) -> InstRef {
+        let cur = self.cur.expect("no current instruction");
+        let old = std::mem::replace(&mut self.block.inst.borrow_mut()[cur], instr.clone());
+        if let Some(du) = &self.def_use { du.replace(&self.block, &old, &instr) }
+        old
+    }
+
+    /// Remove current instruction, and return it. The cursor then has no current instruction,
+    /// and the next instruction stays the same.
+    pub fn erase(&mut self) -> InstRef {
+        let cur = self.cur.take().expect("no current instruction");
+        self.next -= 1;
+        let instr = self.block.inst.borrow_mut().remove(cur).unwrap();
+        if let Some(du) = &self.def_use { du.erase(&instr) }
+        instr
+    }
+}
+
 /// Generate block that has unique name in a function.
 pub struct BlockGen {
     name: HashSet<String>,
@@ -338,38 +723,69 @@ impl BlockGen {
 
 impl Fn {
     /// Split critical edge in the CFG. A critical edge is an CFG edge that whose predecessor has
-    /// several successors, and whose successor has several predecessors.
-    pub fn split_edge(&self) {
+    /// several successors, and whose successor has several predecessors. Returns whether any
+    /// edge is split.
+    pub fn split_edge(&self) -> bool {
         let mut blk_gen = BlockGen::new(self, "B");
+        let mut split = false;
         self.iter_dom().for_each(|ref block| {
             // Decide whether there are any critical edges
             if block.succ.borrow().len() <= 1 { return; }
             let to_split: Vec<_> = block.succ.borrow().iter().cloned().filter(|succ| {
-                succ.pred.borrow().len() > 1
+                succ.pred().len() > 1
             }).collect();
 
             // Split edges
             to_split.iter().for_each(|succ| {
-                // Reconnect edges
-                let mid = blk_gen.gen();
-                mid.push_back(ExtRc::new(Inst::Jmp {
-                    tgt: RefCell::new(succ.clone())
-                }));
-                mid.connect(succ.clone());
-                block.switch_to(&succ, mid.clone());
-
-                // Replace phi source in the split successor
-                succ.inst.borrow_mut().iter_mut().for_each(|instr| {
-                    if let Inst::Phi { src, dst } = instr.as_ref().clone() {
-                        let mut src = src.clone();
-                        src.iter_mut().filter(|(pred, _)| pred.borrow().deref() == block)
-                            .for_each(|(pred, _)| *pred = RefCell::new(mid.clone()));
-                        *instr = ExtRc::new(Inst::Phi { src, dst })
-                    }
-                })
+                // Reconnect edges, and replace phi source in the split successor
+                succ.redirect_pred(block, &blk_gen.gen());
+                split = true;
             })
         });
-        self.build_dom()
+        self.build_dom();
+        split
+    }
+
+    /// Rename blocks whose names are already taken by other blocks, so that the function can be
+    /// printed and parsed back. Blocks copied with their original names, e.g. by unrolling or
+    /// inlining, can be fixed this way. The first block visited in depth-first order keeps its
+    /// name. Names of blocks are immutable, so each renamed block is replaced by a new one, and
+    /// all references to it are updated. Returns whether any block is renamed.
+    pub fn uniquify_blocks(&self) -> bool {
+        self.build_dom();
+        let blocks: Vec<BlockRef> = self.dfs().collect();
+        let mut gen = BlockGen::new(self, "");
+        let mut seen = HashSet::new();
+        let mut map: HashMap<BlockRef, BlockRef> = HashMap::new();
+        for block in &blocks {
+            if seen.insert(block.name.clone()) { continue; }
+            let new = gen.rename(block);
+            new.inst.replace(block.inst.take());
+            new.succ.replace(block.succ.take());
+            new.set_pred(block.pred());
+            new.meta.replace(block.meta.take());
+            new.loc.replace(block.loc.take());
+            map.insert(block.clone(), new);
+        }
+        if map.is_empty() { return false; }
+
+        // Redirect references to renamed blocks
+        let get = |b: &BlockRef| map.get(b).cloned().unwrap_or_else(|| b.clone());
+        for block in blocks.iter().map(get) {
+            block.succ.replace_with(|succ| succ.iter().map(get).collect());
+            block.set_pred(block.pred().iter().map(get).collect());
+            for instr in block.inst.borrow().iter() {
+                instr.blk().into_iter().for_each(|b| { b.replace_with(|b| get(b)); });
+            }
+        }
+        self.exit.replace_with(|exit| exit.iter().map(get).collect());
+        self.layout.replace_with(|layout| layout.iter().map(get).collect());
+        self.blk_param.replace_with(|param| param.drain().map(|(b, p)| (get(&b), p)).collect());
+        self.blk_arg.replace_with(|arg| {
+            arg.drain().map(|((from, to), a)| ((get(&from), get(&to)), a)).collect()
+        });
+        self.build_dom();
+        true
     }
 
     /// Remove unreachable blocks in this function. This is necessary for algorithms that rely
@@ -381,20 +797,23 @@ impl Fn {
 
         // Sweep unmarked blocks in predecessors
         self.dfs().for_each(|block| {
-            let pred_list: Vec<BlockRef> = block.pred.borrow().clone();
+            let pred_list: Vec<BlockRef> = block.pred();
             pred_list.iter().for_each(|pred| {
                 // Disconnect this predecessor
                 if marked.contains(pred) { return; }
                 pred.disconnect(&block);
             });
 
-            // Rebuild phi instruction of this block
+            // Rebuild phi instruction of this block, only if its operands change, so that
+            // references to it are kept valid otherwise
             block.inst.borrow_mut().iter_mut().for_each(|instr| {
                 if let Inst::Phi { src, dst } = instr.as_ref() {
                     let prev_src = src.clone();
-                    let new_src: Vec<_> = block.pred.borrow().iter().map(|pred| {
-                        prev_src.iter().find(|(p, _)| p.borrow().deref() == pred).unwrap().clone()
+                    let new_src: Vec<_> = block.pred().iter().filter_map(|pred| {
+                        prev_src.iter().find(|(p, _)| p.borrow().deref() == pred).cloned()
                     }).collect();
+                    if new_src.iter().map(|(p, _)| p.borrow().clone())
+                        .eq(prev_src.iter().map(|(p, _)| p.borrow().clone())) { return; }
                     *instr = ExtRc::new(Inst::Phi {
                         src: new_src,
                         dst: dst.clone(),
@@ -417,10 +836,13 @@ impl Fn {
             block.child.borrow_mut().clear();
         });
         // Run the Lengauer-Tarjan algorithm
+        // Children are added in depth-first order, so that the tree is the same across runs.
         let result = DomBuilder::new(self.ent.borrow().clone()).build();
-        for (block, dom) in result {
-            block.parent.replace(Some(dom.clone()));
-            dom.child.borrow_mut().push(block);
+        for block in self.dfs() {
+            if let Some(dom) = result.get(&block) {
+                block.parent.replace(Some(dom.downgrade()));
+                dom.child.borrow_mut().push(block);
+            }
         }
     }
 }
@@ -517,7 +939,7 @@ impl DomTreeListener for DfBuilder {
     fn on_enter(&mut self, block: BlockRef) {
         self.stack.push(HashSet::new());
         for succ in block.succ.borrow().iter() {
-            if *succ.parent.borrow() != Some(block.clone()) {
+            if succ.parent().as_ref() != Some(&block) {
                 self.stack.last_mut().unwrap().insert(succ.clone());
             }
         }
@@ -538,3 +960,332 @@ impl DomTreeListener for DfBuilder {
         }
     }
 }
+
+#[test]
+fn test_cursor() {
+    use crate::lang::value::{Const, Symbol};
+
+    let block = ExtRc::new(BasicBlock::new("B".to_string()));
+    let sym = |name: &str| ExtRc::new(Symbol::Local { name: name.to_string(), ty: Type::I(64) });
+    let mov = |name: &str| ExtRc::new(Inst::Mov {
+        src: RefCell::new(Value::Const(Const::I64(0))),
+        dst: RefCell::new(sym(name)),
+    });
+    let names = |block: &BlockRef| block.inst.borrow().iter()
+        .map(|i| i.dst().unwrap().borrow().name().to_string()).collect::<Vec<_>>();
+    for name in ["a", "b", "c"] { block.push_back(mov(name)) }
+
+    let mut visited = vec![];
+    let mut cursor = block.cursor();
+    while let Some(instr) = cursor.next() {
+        let name = instr.dst().unwrap().borrow().name().to_string();
+        match name.as_str() {
+            "a" => {
+                cursor.insert_before(mov("x"));
+                cursor.insert_after(mov("y"));
+            }
+            "b" => { cursor.erase(); }
+            "c" => {
+                let old = cursor.replace(mov("z"));
+                assert_eq!(old, instr);
+            }
+            _ => {}
+        }
+        // The block can be accessed during iteration
+        assert!(block.inst.borrow().len() >= 3);
+        visited.push(name);
+    }
+    assert_eq!(visited, vec!["a", "y", "b", "c"]);
+    assert_eq!(names(&block), vec!["x", "a", "y", "z"]);
+}
+
+#[test]
+fn test_rename_symbols() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::lang::print::Printer;
+    use crate::lang::value::Symbol;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+
+fn @f($t0: i64) -> i64 {
+%Begin:
+    $t1.0 <- add i64 $t0, 1
+    $t1.1 <- mul i64 $t1.0, 2
+    ret $t1.1
+}
+
+fn @main() {
+%Begin:
+    @r <- call i64 @f(3)
+    ret
+}
+"#;
+    let tree = Parser::new(Lexer::from(src)).parse().unwrap();
+    let pro = Builder::new(tree).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+
+    // Versions of a name are also considered as taken
+    let func = pro.func[0].clone();
+    let name = func.scope.unique_name("t");
+    assert_eq!(name, "t2");
+
+    // Swap names of parameter and a temporary
+    let (t0, t1) = (func.scope.find("t0").unwrap(), func.scope.find("t1.0").unwrap());
+    let new = |name: &str, sym: &SymbolRef| ExtRc::new(Symbol::Local {
+        name: name.to_string(),
+        ty: sym.get_type(),
+    });
+    let map: HashMap<SymbolRef, SymbolRef> =
+        vec![(t0.clone(), new("t1.0", &t0)), (t1.clone(), new("t0", &t1))].into_iter().collect();
+    func.rename_symbols(&map);
+    Printer::new(&mut std::io::stdout()).print_fn(&func).unwrap();
+    assert_eq!(func.param[0].borrow().name(), "t1.0");
+    assert!(func.scope.find("t0").unwrap() == map[&t1]);
+    assert!(func.scope.find("t1.0").unwrap() == map[&t0]);
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
+
+#[test]
+fn test_drop_body() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut file = File::open("test/sum.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let pro = Builder::new(Parser::new(lexer).parse().unwrap()).build().unwrap();
+
+    // Predecessor and dominator links are weak
+    let func = pro.func[0].clone();
+    let cond = func.dfs().find(|b| b.name == "Cond").unwrap();
+    let body = func.dfs().find(|b| b.name == "Loop").unwrap();
+    assert!(cond.pred().contains(&body));
+    assert_eq!(body.parent(), Some(cond.clone()));
+    body.remove_pred(&cond);
+    assert!(body.pred().is_empty());
+    body.set_pred(vec![cond.clone()]);
+
+    // Loops keep blocks alive until the bodies are dropped
+    let weak: Vec<_> = pro.func.iter().flat_map(|f| f.dfs().map(|b| b.downgrade()))
+        .collect();
+    drop((func, cond, body));
+    pro.func.iter().for_each(|f| f.drop_body());
+    drop(pro);
+    assert!(weak.iter().all(|b| b.upgrade().is_none()));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "%B: jmp is not at the end of block")]
+fn test_invariant() {
+    use crate::lang::value::{Const, Symbol};
+
+    // Connecting blocks keeps edges symmetric
+    let (a, b) = (ExtRc::new(BasicBlock::new("A".to_string())),
+                  ExtRc::new(BasicBlock::new("B".to_string())));
+    a.connect(b.clone());
+    a.disconnect(&b);
+    b.connect(a.clone());
+
+    // Instructions cannot be appended after the terminator
+    let sym = ExtRc::new(Symbol::Local {
+        name: "x".to_string(),
+        ty: Type::I(64),
+    });
+    b.push_back(ExtRc::new(Inst::Jmp { tgt: RefCell::new(a.clone()) }));
+    b.push_back(ExtRc::new(Inst::Mov {
+        src: RefCell::new(Value::Const(Const::I64(0))),
+        dst: RefCell::new(sym),
+    }));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "%A: br is not at the end of block")]
+fn test_invariant_ctrl() {
+    use crate::lang::value::Const;
+
+    // Control flow instructions cannot be inserted before the terminator
+    let (a, b) = (ExtRc::new(BasicBlock::new("A".to_string())),
+                  ExtRc::new(BasicBlock::new("B".to_string())));
+    a.insert_before_ctrl(ExtRc::new(Inst::Jmp { tgt: RefCell::new(b.clone()) }));
+    a.insert_before_ctrl(ExtRc::new(Inst::Br {
+        cond: RefCell::new(Value::Const(Const::I1(true))),
+        tr: RefCell::new(b.clone()),
+        fls: RefCell::new(b.clone()),
+    }));
+}
+
+#[test]
+fn test_edge_edit() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::lang::print::Printer;
+    use crate::lang::ssa::Verifier;
+    use crate::lang::value::Const;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+
+[ssa]
+fn @main() {
+%Begin:
+    $a <- call i64 @f(0)
+    $b <- call i64 @f(1)
+    $c <- mul i64 $a, 10
+    @r <- add i64 $c, $b
+    ret
+}
+
+[ssa]
+fn @f($c: i1) -> i64 {
+%A:
+    br $c ? %B : %C
+%B:
+    jmp %D
+%C:
+    jmp %D
+%D:
+    $x <- phi i64 [%B: 1] [%C: 2]
+    ret $x
+}
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let func = pro.func[1].clone();
+    let block = |name: &str| func.dfs().find(|b| b.name == name).unwrap();
+    let (a, b, c, d) = (block("A"), block("B"), block("C"), block("D"));
+    let run = || {
+        func.build_dom();
+        let mut ver = Verifier::new();
+        func.walk_dom(&mut ver);
+        assert!(ver.err.is_empty() && func.ssa.get());
+        let rcd = Machine::new().run(&pro).unwrap();
+        rcd.global[0].1.get_const().as_i64()
+    };
+    let print = || {
+        let mut buf = vec![];
+        Printer::new(&mut buf).print_fn(&func).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+
+    // Place a new block on the edge from %B to %D
+    let e = ExtRc::new(BasicBlock::new("E".to_string()));
+    d.redirect_pred(&b, &e);
+    assert!(print().contains("[%E: 1] [%C: 2]"));
+    assert_eq!(run(), 21);
+
+    // Bypass %E, so that %B jumps to %D directly, and then detach %E
+    b.replace_succ(&e, d.clone());
+    assert!(e.pred().is_empty());
+    e.remove_edge(&d);
+    assert!(e.is_trap());
+    assert!(print().contains("[%C: 2] [%B: 1]"));
+    assert_eq!(run(), 21);
+
+    // Remove the edge to %C, and the branch becomes a jump
+    // The block is then unreachable.
+    a.remove_edge(&c);
+    assert!(c.pred().is_empty());
+    func.remove_unreachable();
+    let out = print();
+    println!("{}", out);
+    assert!(out.contains("jmp %B") && out.contains("phi i64 [%B: 1]\n"));
+    assert_eq!(run(), 11);
+
+    // Dangling and duplicate phi operands are reported, and then repaired
+    d.map_phi_src(|mut src| {
+        src.push((RefCell::new(c.clone()), RefCell::new(Value::Const(Const::I64(3)))));
+        src.push((RefCell::new(b.clone()), RefCell::new(Value::Const(Const::I64(4)))));
+        src
+    });
+    let mut ver = Verifier::new();
+    func.walk_dom(&mut ver);
+    let msg: Vec<_> = ver.err.iter().map(|e| e.msg.as_str()).collect();
+    assert_eq!(msg, ["phi operand from %C, which is not a predecessor",
+        "duplicate phi operand for %B"]);
+    d.repair_phi();
+    assert!(print().contains("phi i64 [%B: 1]\n"));
+    assert_eq!(run(), 11);
+}
+
+#[test]
+fn test_uniquify_blocks() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::lang::print::Printer;
+    use crate::lang::Program;
+    use crate::pass::Pass;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+
+fn @main() {
+%Begin:
+    $a <- call i64 @f(0)
+    $b <- call i64 @f(1)
+    $c <- mul i64 $a, 10
+    @r <- add i64 $c, $b
+    ret
+}
+
+[ssa]
+fn @f($c: i1) -> i64 {
+%A:
+    br $c ? %B : %C
+%B:
+    jmp %D
+%C:
+    jmp %D
+%D:
+    $x <- phi i64 [%B: 1] [%C: 2]
+    ret $x
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let expect = format!("{:?}", Machine::new().run(&pro).unwrap().global);
+    let func = pro.func[1].clone();
+    let block = |name: &str| func.dfs().find(|b| b.name == name).unwrap();
+    let (b, c, d) = (block("B"), block("C"), block("D"));
+    let print = |pro: &Program| {
+        let mut buf = vec![];
+        Printer::new(&mut buf).print(pro).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+
+    // Place blocks with taken names on edges to %D, which are reported by the verifier
+    d.redirect_pred(&b, &ExtRc::new(BasicBlock::new("C".to_string())));
+    d.redirect_pred(&c, &ExtRc::new(BasicBlock::new("D".to_string())));
+    func.build_dom();
+    let mut ver = VerifyPass::new();
+    ver.run(&mut pro);
+    let msg: Vec<_> = ver.err.iter().map(|e| e.msg.as_str()).collect();
+    assert_eq!(msg.len(), 2);
+    assert!(msg.iter().all(|m| m.ends_with("is not unique")));
+
+    // Renamed blocks keep their instructions and edges, and the function can be parsed back
+    assert!(func.uniquify_blocks());
+    let out = print(&pro);
+    println!("{}", out);
+    ver.run(&mut pro);
+    assert!(ver.is_ok());
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+    let names: HashSet<_> = func.dfs().map(|b| b.name.clone()).collect();
+    assert_eq!(names.len(), 6);
+    let parsed = Builder::new(Parser::new(Lexer::from(out.as_str())).parse().unwrap()).build()
+        .unwrap();
+    assert_eq!(print(&parsed), out);
+    assert!(!func.uniquify_blocks());
+}
@@ -307,7 +307,7 @@ impl<T> DomBuilder<T> where T: Vertex<T> + Eq + Clone + Hash {
 /// Represent an vertex in the forward CFG
 impl Vertex<BlockRef> for BlockRef {
     fn this(&self) -> BlockRef { self.clone() }
-    fn pred(&self) -> Vec<BlockRef> { self.pred.borrow().deref().clone() }
+    fn pred(&self) -> Vec<BlockRef> { self.as_ref().pred() }
     fn succ(&self) -> Vec<BlockRef> { self.succ.borrow().deref().clone() }
 }
 
@@ -320,6 +320,17 @@ impl Fn {
 
     /// Return an iterator for reverse post-order traversal.
     pub fn rpo(&self) -> RevPostOrd<BlockRef> { self.ent.borrow().rpo() }
+
+    /// Return blocks in the order they should be emitted. This is the stored layout if it still
+    /// starts with the entrance and covers exactly the reachable blocks, otherwise the reverse
+    /// post-order.
+    pub fn layout(&self) -> Vec<BlockRef> {
+        let rpo: Vec<BlockRef> = self.rpo().collect();
+        let layout = self.layout.borrow();
+        let valid = layout.first() == rpo.first() && layout.len() == rpo.len()
+            && HashSet::<&BlockRef>::from_iter(layout.iter()) == HashSet::from_iter(rpo.iter());
+        if valid { layout.clone() } else { rpo }
+    }
 }
 
 /// Represent an vertex in the reverse CFG
@@ -363,8 +374,9 @@ impl Vertex<RevVert> for RevVert {
         match self {
             // Predecessors of a block in the reverse CFG are successors of that block in the
             // forward CFG. For exit blocks of the function, its predecessor should be the
-            // `Exit` vertex, since it is not included in the original forward CFG
-            RevVert::Block(block, f) => if f.exit.borrow().contains(&block) {
+            // `Exit` vertex, since it is not included in the original forward CFG. Blocks ending
+            // with traps also leave the function, so they are treated as exit blocks.
+            RevVert::Block(block, f) => if f.exit.borrow().contains(&block) || block.is_trap() {
                 vec![RevVert::Exit(f.clone())]
             } else {
                 block.succ.borrow().iter().cloned()
@@ -390,7 +402,7 @@ impl Vertex<RevVert> for RevVert {
             RevVert::Block(block, f) => if f.ent.borrow().deref() == block {
                 vec![RevVert::Enter(f.clone())]
             } else {
-                block.pred.borrow().iter().cloned()
+                block.pred().into_iter()
                     .map(|pred| RevVert::Block(pred, f.clone())).collect()
             }
             // Function entrance has no successor in the reverse CFG, since it has no predecessor
@@ -401,6 +413,7 @@ impl Vertex<RevVert> for RevVert {
             // entrance as successor.
             RevVert::Exit(f) => {
                 let mut succ: Vec<_> = f.exit.borrow().iter().cloned()
+                    .chain(f.dfs().filter(|b| b.is_trap()))
                     .map(|exit| RevVert::Block(exit, f.clone())).collect();
                 succ.push(RevVert::Enter(f.clone()));
                 succ
@@ -418,7 +431,8 @@ impl FnRef {
         let parent = DomBuilder::new(root.clone()).build();
         let mut child: HashMap<RevVert, Vec<RevVert>> = nodes.iter().cloned()
             .map(|v| (v, vec![])).collect();
-        parent.into_iter().for_each(|(c, p)| child.get_mut(&p).unwrap().push(c));
+        nodes.iter().filter_map(|c| parent.get(c).map(|p| (c, p)))
+            .for_each(|(c, p)| child.get_mut(p).unwrap().push(c.clone()));
 
         // Traverse post-dominator tree
         let mut stack: Vec<RevVert> = child[&root].iter().cloned().collect();
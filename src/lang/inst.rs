@@ -1,10 +1,12 @@
 use std::cell::RefCell;
 use std::fmt::{Debug, Error, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
 use std::str::FromStr;
 
 use crate::lang::func::{BlockRef, FnAttrib, FnRef};
 use crate::lang::util::ExtRc;
-use crate::lang::value::{Const, Symbol, SymbolRef, Type, Value};
+use crate::lang::value::{Const, Symbol, SymbolRef, Type, Typed, Value};
 
 #[derive(Clone, Debug)]
 pub enum Inst {
@@ -14,10 +16,30 @@ pub enum Inst {
     Un { op: UnOp, opd: RefCell<Value>, dst: RefCell<SymbolRef> },
     /// Binary operations
     Bin { op: BinOp, fst: RefCell<Value>, snd: RefCell<Value>, dst: RefCell<SymbolRef> },
+    /// Type conversion
+    /// The operand is converted to the type of `dst`.
+    Cast { op: CastOp, opd: RefCell<Value>, dst: RefCell<SymbolRef> },
     /// Procedure call
-    Call { func: FnRef, arg: Vec<RefCell<Value>>, dst: Option<RefCell<SymbolRef>> },
-    /// Return computation results, or `None` if return type is `Void`.
-    Ret { val: Option<RefCell<Value>> },
+    /// `dst` receives the returned values in order. It is empty if the function returns `Void`
+    /// or the results are discarded, and has more than one symbol if the function returns a
+    /// tuple.
+    Call { func: FnRef, arg: Vec<RefCell<Value>>, dst: Vec<RefCell<SymbolRef>> },
+    /// Indirect procedure call through a function pointer
+    /// The called function is only known at runtime, so it is assumed to have side effects.
+    CallInd {
+        func_ptr: RefCell<Value>,
+        arg: Vec<RefCell<Value>>,
+        dst: Vec<RefCell<SymbolRef>>,
+    },
+    /// Return computation results, which is empty if return type is `Void`, and has more than
+    /// one value if the function returns a tuple.
+    Ret { val: Vec<RefCell<Value>> },
+    /// Mark a point that control never reaches
+    /// Reaching this instruction is undefined, so optimizations may assume that paths leading to
+    /// it are never taken.
+    Unreachable,
+    /// Stop the program with a runtime failure, reporting message `msg`
+    Abort { msg: String },
     /// Jump to another basic block
     Jmp { tgt: RefCell<BlockRef> },
     /// Conditional branch to labels
@@ -49,45 +71,179 @@ pub enum Inst {
     Ld { ptr: RefCell<Value>, dst: RefCell<SymbolRef> },
     /// Store data to a pointer
     St { src: RefCell<Value>, ptr: RefCell<Value> },
+    /// Copy `len` elements from pointer `src` to pointer `ptr`
+    /// Both pointers point to the element type, and `len` is an `i64` value. Nothing is copied
+    /// if `len` is not positive. The source and destination memory should not overlap.
+    Memcpy { src: RefCell<Value>, ptr: RefCell<Value>, len: RefCell<Value> },
+    /// Store value `src` to `len` consecutive elements starting from pointer `ptr`
+    /// Nothing is stored if `len` is not positive.
+    Memset { src: RefCell<Value>, ptr: RefCell<Value>, len: RefCell<Value> },
+    /// Atomically load data from a pointer, with memory ordering `ord`
+    AtomicLd { ord: MemOrd, ptr: RefCell<Value>, dst: RefCell<SymbolRef> },
+    /// Atomically store data to a pointer, with memory ordering `ord`
+    AtomicSt { ord: MemOrd, src: RefCell<Value>, ptr: RefCell<Value> },
+    /// Atomically read data from pointer `ptr`, combine it with `val` by operator `op`, and
+    /// write the result back. `dst` receives the data read before the operation.
+    AtomicRmw {
+        op: RmwOp,
+        ord: MemOrd,
+        ptr: RefCell<Value>,
+        val: RefCell<Value>,
+        dst: RefCell<SymbolRef>,
+    },
 }
 
 pub type PhiSrc = (RefCell<BlockRef>, RefCell<Value>);
 
 pub type InstRef = ExtRc<Inst>;
 
+/// Former name of `Inst`
+#[deprecated(note = "use `Inst` instead")]
+pub type Instr = Inst;
+
 impl Debug for InstRef {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         write!(f, "{}", self.0.name())
     }
 }
 
+/// Structural equality of instructions
+/// Two instructions are equal if they have the same operator and equal operands, where
+/// variables are compared by identity of their symbols, and blocks and functions by identity
+/// of their references. Destination symbols are not compared, but their types are, as they
+/// decide the results of some instructions, such as `cast` and `ld`. This allows instructions
+/// computing the same expression to be found in hash tables. Note that `Eq` and `Hash` of
+/// `InstRef` still compare the references.
+impl PartialEq for Inst {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name() && self.parts() == other.parts()
+    }
+}
+
+impl Eq for Inst {}
+
+impl Hash for Inst {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name().hash(state);
+        self.parts().hash(state);
+    }
+}
+
+/// Part of an instruction compared in structural equality
+#[derive(Eq, PartialEq, Debug)]
+enum InstPart {
+    Val(Value),
+    /// Optional operand, such as offset of `ptr`
+    Opt(Option<Value>),
+    Block(BlockRef),
+    Func(FnRef),
+    Msg(String),
+    /// Memory ordering of atomic instructions
+    Ord(MemOrd),
+    /// Type of a destination, which is not hashed
+    Type(Type),
+}
+
+impl Hash for InstPart {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            InstPart::Val(v) => v.hash(state),
+            InstPart::Opt(v) => v.hash(state),
+            InstPart::Block(b) => b.hash(state),
+            InstPart::Func(f) => f.hash(state),
+            InstPart::Msg(m) => m.hash(state),
+            InstPart::Ord(o) => o.hash(state),
+            InstPart::Type(_) => {}
+        }
+    }
+}
+
 impl Inst {
+    /// Parts of this instruction other than its operator, in order.
+    fn parts(&self) -> Vec<InstPart> {
+        let val = |v: &RefCell<Value>| InstPart::Val(v.borrow().clone());
+        let ty = |d: &RefCell<SymbolRef>| InstPart::Type(d.borrow().get_type());
+        let opt = |v: &Option<RefCell<Value>>| {
+            InstPart::Opt(v.as_ref().map(|v| v.borrow().clone()))
+        };
+        let mut parts = match self {
+            Inst::Mov { src, dst: _ } => vec![val(src)],
+            Inst::Un { op: _, opd, dst: _ } | Inst::Cast { op: _, opd, dst: _ } => vec![val(opd)],
+            Inst::Bin { op: _, fst, snd, dst: _ } => vec![val(fst), val(snd)],
+            Inst::Call { func, arg, dst: _ } =>
+                Some(InstPart::Func(func.clone())).into_iter().chain(arg.iter().map(val)).collect(),
+            Inst::CallInd { func_ptr, arg, dst: _ } =>
+                Some(func_ptr).into_iter().chain(arg.iter()).map(val).collect(),
+            Inst::Ret { val: v } => v.iter().map(val).collect(),
+            Inst::Unreachable | Inst::Alloc { dst: _ } => vec![],
+            Inst::Abort { msg } => vec![InstPart::Msg(msg.clone())],
+            Inst::Jmp { tgt } => vec![InstPart::Block(tgt.borrow().clone())],
+            Inst::Br { cond, tr, fls } => vec![val(cond), InstPart::Block(tr.borrow().clone()),
+                                               InstPart::Block(fls.borrow().clone())],
+            Inst::Phi { src, dst: _ } => src.iter().flat_map(|(b, v)| {
+                vec![InstPart::Block(b.borrow().clone()), val(v)]
+            }).collect(),
+            Inst::New { dst: _, len } => vec![opt(len)],
+            Inst::Ptr { base, off, ind, dst: _ } =>
+                vec![val(base), opt(off)].into_iter().chain(ind.iter().map(val)).collect(),
+            Inst::Ld { ptr, dst: _ } => vec![val(ptr)],
+            Inst::St { src, ptr } => vec![val(src), val(ptr)],
+            Inst::Memcpy { src, ptr, len } | Inst::Memset { src, ptr, len } =>
+                vec![val(src), val(ptr), val(len)],
+            Inst::AtomicLd { ord, ptr, dst: _ } => vec![InstPart::Ord(*ord), val(ptr)],
+            Inst::AtomicSt { ord, src, ptr } => vec![InstPart::Ord(*ord), val(src), val(ptr)],
+            Inst::AtomicRmw { op: _, ord, ptr, val: v, dst: _ } =>
+                vec![InstPart::Ord(*ord), val(ptr), val(v)],
+        };
+        parts.extend(self.dsts().into_iter().map(ty));
+        parts
+    }
+
     /// Get instruction name
     pub fn name(&self) -> String {
         match self {
             Inst::Mov { src: _, dst: _ } => "mov".to_string(),
             Inst::Un { op, opd: _, dst: _ } => op.to_string(),
             Inst::Bin { op, fst: _, snd: _, dst: _ } => op.to_string(),
+            Inst::Cast { op, opd: _, dst: _ } => op.to_string(),
             Inst::Jmp { tgt: _ } => "jmp".to_string(),
             Inst::Br { cond: _, tr: _, fls: _ } => "br".to_string(),
-            Inst::Call { func: _, arg: _, dst: _ } => "call".to_string(),
+            Inst::Call { func: _, arg: _, dst: _ }
+            | Inst::CallInd { func_ptr: _, arg: _, dst: _ } => "call".to_string(),
             Inst::Ret { val: _ } => "ret".to_string(),
+            Inst::Unreachable => "unreachable".to_string(),
+            Inst::Abort { msg: _ } => "abort".to_string(),
             Inst::Phi { src: _, dst: _ } => "phi".to_string(),
             Inst::Alloc { dst: _ } => "alloc".to_string(),
             Inst::New { dst: _, len: _ } => "new".to_string(),
             Inst::Ptr { base: _, off: _, ind: _, dst: _ } => "ptr".to_string(),
             Inst::Ld { ptr: _, dst: _ } => "ld".to_string(),
             Inst::St { src: _, ptr: _ } => "st".to_string(),
+            Inst::Memcpy { src: _, ptr: _, len: _ } => "memcpy".to_string(),
+            Inst::Memset { src: _, ptr: _, len: _ } => "memset".to_string(),
+            Inst::AtomicLd { ord: _, ptr: _, dst: _ } => "ld.atomic".to_string(),
+            Inst::AtomicSt { ord: _, src: _, ptr: _ } => "st.atomic".to_string(),
+            Inst::AtomicRmw { op, ord: _, ptr: _, val: _, dst: _ } => op.to_string(),
         }
     }
 
     /// Decide if this instruction is a control flow instruction.
     /// A control flow instruction correspond to a directed edge in the CFG.
-    /// Currently, only `jmp`, `br` and `ret`are control flow instructions.
+    /// Currently, only `jmp`, `br`, `ret`, `unreachable` and `abort` are control flow
+    /// instructions, and they terminate blocks.
     pub fn is_ctrl(&self) -> bool {
         match self {
             Inst::Jmp { tgt: _ } | Inst::Br { cond: _, tr: _, fls: _ }
             | Inst::Ret { val: _ } => true,
+            instr => instr.is_trap()
+        }
+    }
+
+    /// Decide if this instruction ends execution without returning, i.e. `unreachable` or
+    /// `abort`.
+    pub fn is_trap(&self) -> bool {
+        match self {
+            Inst::Unreachable | Inst::Abort { msg: _ } => true,
             _ => false
         }
     }
@@ -99,6 +255,13 @@ impl Inst {
         }
     }
 
+    /// Decide if this instruction is an atomic memory access.
+    pub fn is_atomic(&self) -> bool {
+        matches!(self, Inst::AtomicLd { ord: _, ptr: _, dst: _ }
+            | Inst::AtomicSt { ord: _, src: _, ptr: _ }
+            | Inst::AtomicRmw { op: _, ord: _, ptr: _, val: _, dst: _ })
+    }
+
     pub fn is_phi(&self) -> bool {
         match self {
             Inst::Phi { src: _, dst: _ } => true,
@@ -107,21 +270,42 @@ impl Inst {
     }
 
     /// Possible return the destination symbol of this instruction. This symbol is defined by
-    /// this instruction.
+    /// this instruction. Calls with multiple destinations have no single one, so use `dsts` to
+    /// find all symbols defined.
     pub fn dst(&self) -> Option<&RefCell<SymbolRef>> {
         match self {
             Inst::Mov { src: _, dst } => Some(dst),
             Inst::Un { op: _, opd: _, dst } => Some(dst),
             Inst::Bin { op: _, fst: _, snd: _, dst } => Some(dst),
-            Inst::Call { func: _, arg: _, dst } => dst.as_ref(),
+            Inst::Cast { op: _, opd: _, dst } => Some(dst),
+            Inst::Call { func: _, arg: _, dst } | Inst::CallInd { func_ptr: _, arg: _, dst } =>
+                match dst.as_slice() {
+                    [dst] => Some(dst),
+                    _ => None
+                }
             Inst::Phi { src: _, dst } => Some(dst),
             Inst::Jmp { tgt: _ } => None,
             Inst::Br { cond: _, tr: _, fls: _ } => None,
             Inst::Ret { val: _ } => None,
+            Inst::Unreachable | Inst::Abort { msg: _ } => None,
             Inst::Alloc { dst } | Inst::New { dst, len: _ } => Some(dst),
             Inst::Ptr { base: _, off: _, ind: _, dst } => Some(dst),
             Inst::Ld { ptr: _, dst } => Some(dst),
             Inst::St { src: _, ptr: _ } => None,
+            Inst::Memcpy { src: _, ptr: _, len: _ } | Inst::Memset { src: _, ptr: _, len: _ } =>
+                None,
+            Inst::AtomicLd { ord: _, ptr: _, dst }
+            | Inst::AtomicRmw { op: _, ord: _, ptr: _, val: _, dst } => Some(dst),
+            Inst::AtomicSt { ord: _, src: _, ptr: _ } => None,
+        }
+    }
+
+    /// Return list of all the destination symbols defined by this instruction.
+    pub fn dsts(&self) -> Vec<&RefCell<SymbolRef>> {
+        match self {
+            Inst::Call { func: _, arg: _, dst } | Inst::CallInd { func_ptr: _, arg: _, dst } =>
+                dst.iter().collect(),
+            instr => instr.dst().into_iter().collect()
         }
     }
 
@@ -131,12 +315,13 @@ impl Inst {
             Inst::Mov { src, dst: _ } => vec![src],
             Inst::Un { op: _, opd, dst: _ } => vec![opd],
             Inst::Bin { op: _, fst, snd, dst: _ } => vec![fst, snd],
+            Inst::Cast { op: _, opd, dst: _ } => vec![opd],
             Inst::Call { func: _, arg, dst: _ } => arg.iter().map(|a| a).collect(),
+            Inst::CallInd { func_ptr, arg, dst: _ } =>
+                std::iter::once(func_ptr).chain(arg.iter()).collect(),
             Inst::Phi { src, dst: _ } => src.iter().map(|(_, v)| v).collect(),
-            Inst::Ret { val } => match val {
-                Some(v) => vec![v],
-                None => vec![]
-            }
+            Inst::Ret { val } => val.iter().collect(),
+            Inst::Unreachable | Inst::Abort { msg: _ } => vec![],
             Inst::Jmp { tgt: _ } => vec![],
             Inst::Br { cond, tr: _, fls: _ } => vec![cond],
             Inst::Alloc { dst: _ } => vec![],
@@ -151,7 +336,11 @@ impl Inst {
                 v
             }
             Inst::Ld { ptr, dst: _ } => vec![ptr],
-            Inst::St { src, ptr } => vec![src, ptr]
+            Inst::St { src, ptr } => vec![src, ptr],
+            Inst::Memcpy { src, ptr, len } | Inst::Memset { src, ptr, len } => vec![src, ptr, len],
+            Inst::AtomicLd { ord: _, ptr, dst: _ } => vec![ptr],
+            Inst::AtomicSt { ord: _, src, ptr } => vec![src, ptr],
+            Inst::AtomicRmw { op: _, ord: _, ptr, val, dst: _ } => vec![ptr, val],
         }
     }
 
@@ -166,17 +355,27 @@ impl Inst {
     }
 
     /// Decide if this instruction assign to some variable
-    pub fn is_assign(&self) -> bool { self.dst().is_some() }
+    pub fn is_assign(&self) -> bool { !self.dsts().is_empty() }
 
     /// Decide whether this instruction has side effects
     pub fn has_side_effect(&self) -> bool {
         match self {
-            // If called function is not marked `readonly`, it has side effects.
-            Inst::Call { func, arg: _, dst: _ } => !func.has_attrib(FnAttrib::ReadOnly),
-            // Store instruction modifies memory
-            Inst::St { src: _, ptr: _ } => true,
+            // If called function is not marked `readonly` or `pure`, it has side effects. Calls
+            // to `noreturn` function cannot be removed, since they change the control flow.
+            Inst::Call { func, arg: _, dst: _ } =>
+                !func.is_readonly() || func.has_attrib(FnAttrib::NoReturn),
+            // Any function could be called through a pointer
+            Inst::CallInd { func_ptr: _, arg: _, dst: _ } => true,
+            // Store instruction modifies memory, and so do `memcpy` and `memset`
+            Inst::St { src: _, ptr: _ } | Inst::Memcpy { src: _, ptr: _, len: _ }
+            | Inst::Memset { src: _, ptr: _, len: _ } => true,
             // `new` instruction modifies heap memory
             Inst::New { dst: _, len: _ } => true,
+            // Atomic instructions synchronize with other threads, so even atomic loads are
+            // barriers that should neither be removed nor reordered with memory accesses.
+            instr if instr.is_atomic() => true,
+            // Abort is observable, and should never be removed
+            Inst::Abort { msg: _ } => true,
             // For other instructions, check if it assigns to global variable
             instr if instr.dst().is_some() => {
                 match instr.dst().unwrap().borrow().as_ref() {
@@ -187,6 +386,37 @@ impl Inst {
             _ => false
         }
     }
+
+    /// Evaluate this instruction at compile time if all of its operands are constants.
+    /// Trapping operations with zero divisor are not evaluated, since they should fail at
+    /// runtime.
+    pub fn eval_const(&self) -> Option<Const> {
+        match self {
+            Inst::Un { op, opd, dst: _ } => match opd.borrow().deref() {
+                Value::Const(c) => Some(op.eval(*c)),
+                _ => None
+            }
+            Inst::Bin { op, fst, snd, dst: _ } => {
+                match (fst.borrow().deref(), snd.borrow().deref()) {
+                    (Value::Const(l), Value::Const(r)) => op.eval(*l, *r),
+                    _ => None
+                }
+            }
+            Inst::Cast { op, opd, dst } => match opd.borrow().deref() {
+                Value::Const(c) => op.eval(*c, &dst.borrow().get_type()),
+                _ => None
+            }
+            _ => None
+        }
+    }
+
+    /// Fold this instruction to a `mov` of constant if possible.
+    pub fn fold(&self) -> Option<Inst> {
+        self.eval_const().map(|c| Inst::Mov {
+            src: RefCell::new(Value::Const(c)),
+            dst: RefCell::new(self.dst().unwrap().borrow().clone()),
+        })
+    }
 }
 
 #[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
@@ -229,16 +459,15 @@ impl UnOp {
     pub fn is_avail_for(&self, ty: &Type) -> bool {
         self.res_type(ty).is_some()
     }
-
-    /// Evaluate constant according to unary operator `op`
-    pub fn eval(self, c: Const) -> Const {
-        match self {
-            UnOp::Not => !c,
-            UnOp::Neg => -c,
-        }
-    }
 }
 
+/// Binary operators.
+/// Addition, subtraction, multiplication and left shift wrap around on overflow. Division and
+/// modulo trap if the divisor is zero, while overflow of `MIN / -1` also wraps. Shift amounts are
+/// masked by the bit width of operands. To detect overflow, use the checked variants, which
+/// compute whether the corresponding operation overflows. A checked operation may also assign
+/// a pair `$v, $o <- add.ov i32 $a, $b`, which is built as the check followed by the wrapping
+/// operation.
 #[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
 pub enum BinOp {
     /// Addition
@@ -251,6 +480,12 @@ pub enum BinOp {
     Div,
     /// Modulo, also known as remainder
     Mod,
+    /// Whether signed addition overflows `add.ov`
+    AddOv,
+    /// Whether signed subtraction overflows `sub.ov`
+    SubOv,
+    /// Whether signed multiplication overflows `mul.ov`
+    MulOv,
     /// Bitwise-AND
     And,
     /// Bitwise-OR
@@ -275,6 +510,178 @@ pub enum BinOp {
     Ge,
 }
 
+/// Type conversion operators.
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
+pub enum CastOp {
+    /// Extend integer to a wider one, filling high bits with zero
+    ZExt,
+    /// Extend integer to a wider one, filling high bits with the sign bit
+    SExt,
+    /// Truncate integer to a narrower one, discarding high bits
+    Trunc,
+    /// Convert pointer to `i64` integer
+    PtrToInt,
+    /// Convert `i64` integer to pointer
+    IntToPtr,
+}
+
+impl FromStr for CastOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zext" => Ok(CastOp::ZExt),
+            "sext" => Ok(CastOp::SExt),
+            "trunc" => Ok(CastOp::Trunc),
+            "ptrtoint" => Ok(CastOp::PtrToInt),
+            "inttoptr" => Ok(CastOp::IntToPtr),
+            _ => Err("not cast operation".to_string())
+        }
+    }
+}
+
+impl ToString for CastOp {
+    fn to_string(&self) -> String {
+        format!("{:?}", self).to_lowercase()
+    }
+}
+
+impl CastOp {
+    /// Whether this operator can convert value of type `from` to type `to`
+    pub fn is_avail_for(&self, from: &Type, to: &Type) -> bool {
+        match (self, from.orig(), to.orig()) {
+            (CastOp::ZExt, Type::I(f), Type::I(t)) | (CastOp::SExt, Type::I(f), Type::I(t)) =>
+                f < t,
+            (CastOp::Trunc, Type::I(f), Type::I(t)) => f > t,
+            (CastOp::PtrToInt, Type::Ptr(_), Type::I(64)) => true,
+            (CastOp::IntToPtr, Type::I(64), Type::Ptr(_)) => true,
+            _ => false
+        }
+    }
+}
+
+/// Memory ordering of atomic instructions, following the C++11 memory model. Loads cannot be
+/// `release` or `acq_rel`, and stores cannot be `acquire` or `acq_rel`.
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
+pub enum MemOrd {
+    /// Only the access itself is atomic, and no other memory access is ordered
+    Relaxed,
+    /// No memory access after this one can be reordered before it
+    Acquire,
+    /// No memory access before this one can be reordered after it
+    Release,
+    /// Both `acquire` and `release`
+    AcqRel,
+    /// `acq_rel`, and all sequentially consistent accesses have a single total order
+    SeqCst,
+}
+
+impl FromStr for MemOrd {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relaxed" => Ok(MemOrd::Relaxed),
+            "acquire" => Ok(MemOrd::Acquire),
+            "release" => Ok(MemOrd::Release),
+            "acq_rel" => Ok(MemOrd::AcqRel),
+            "seq_cst" => Ok(MemOrd::SeqCst),
+            _ => Err("not memory ordering".to_string())
+        }
+    }
+}
+
+impl ToString for MemOrd {
+    fn to_string(&self) -> String {
+        match self {
+            MemOrd::AcqRel => "acq_rel".to_string(),
+            MemOrd::SeqCst => "seq_cst".to_string(),
+            _ => format!("{:?}", self).to_lowercase()
+        }
+    }
+}
+
+impl MemOrd {
+    /// Whether memory accesses after this one cannot be reordered before it
+    pub fn is_acquire(&self) -> bool {
+        matches!(self, MemOrd::Acquire | MemOrd::AcqRel | MemOrd::SeqCst)
+    }
+
+    /// Whether memory accesses before this one cannot be reordered after it
+    pub fn is_release(&self) -> bool {
+        matches!(self, MemOrd::Release | MemOrd::AcqRel | MemOrd::SeqCst)
+    }
+
+    /// Whether this ordering is allowed for atomic loads
+    pub fn is_avail_for_ld(&self) -> bool { !matches!(self, MemOrd::Release | MemOrd::AcqRel) }
+
+    /// Whether this ordering is allowed for atomic stores
+    pub fn is_avail_for_st(&self) -> bool { !matches!(self, MemOrd::Acquire | MemOrd::AcqRel) }
+}
+
+/// Operators of atomic read-modify-write instructions. Arithmetic wraps around on overflow.
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
+pub enum RmwOp {
+    /// Exchange, which writes the operand
+    Xchg,
+    /// Addition
+    Add,
+    /// Subtraction
+    Sub,
+    /// Bitwise-AND
+    And,
+    /// Bitwise-OR
+    Or,
+    /// Bitwise-XOR
+    Xor,
+}
+
+impl FromStr for RmwOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rmw.xchg" => Ok(RmwOp::Xchg),
+            "rmw.add" => Ok(RmwOp::Add),
+            "rmw.sub" => Ok(RmwOp::Sub),
+            "rmw.and" => Ok(RmwOp::And),
+            "rmw.or" => Ok(RmwOp::Or),
+            "rmw.xor" => Ok(RmwOp::Xor),
+            _ => Err("not read-modify-write operation".to_string())
+        }
+    }
+}
+
+impl ToString for RmwOp {
+    fn to_string(&self) -> String {
+        format!("rmw.{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl RmwOp {
+    /// Whether this operator can operate on certain type. Exchange is available for all
+    /// register types, and others only for integers.
+    pub fn is_avail_for(&self, ty: &Type) -> bool {
+        match (self, ty.orig()) {
+            (RmwOp::Xchg, ty) => ty.is_reg(),
+            (_, Type::I(_)) => true,
+            _ => false
+        }
+    }
+
+    /// Binary operator computing the written value, or `None` for exchange
+    pub fn bin_op(&self) -> Option<BinOp> {
+        match self {
+            RmwOp::Xchg => None,
+            RmwOp::Add => Some(BinOp::Add),
+            RmwOp::Sub => Some(BinOp::Sub),
+            RmwOp::And => Some(BinOp::And),
+            RmwOp::Or => Some(BinOp::Or),
+            RmwOp::Xor => Some(BinOp::Xor),
+        }
+    }
+}
+
 impl FromStr for BinOp {
     type Err = String;
 
@@ -285,6 +692,9 @@ impl FromStr for BinOp {
             "mul" => Ok(BinOp::Mul),
             "div" => Ok(BinOp::Div),
             "mod" => Ok(BinOp::Mod),
+            "add.ov" => Ok(BinOp::AddOv),
+            "sub.ov" => Ok(BinOp::SubOv),
+            "mul.ov" => Ok(BinOp::MulOv),
             "and" => Ok(BinOp::And),
             "or" => Ok(BinOp::Or),
             "xor" => Ok(BinOp::Xor),
@@ -302,31 +712,17 @@ impl FromStr for BinOp {
 }
 
 impl ToString for BinOp {
-    fn to_string(&self) -> String { format!("{:?}", self).to_lowercase() }
-}
-
-impl BinOp {
-    pub fn eval(self, l: Const, r: Const) -> Const {
+    fn to_string(&self) -> String {
         match self {
-            BinOp::Add => l + r,
-            BinOp::Sub => l - r,
-            BinOp::Mul => l * r,
-            BinOp::Div => l / r,
-            BinOp::Mod => l % r,
-            BinOp::Shl => l << r,
-            BinOp::Shr => l >> r,
-            BinOp::And => l & r,
-            BinOp::Or => l | r,
-            BinOp::Xor => l ^ r,
-            BinOp::Eq => l.equal(r),
-            BinOp::Ne => l.not_eq(r),
-            BinOp::Lt => l.less_than(r),
-            BinOp::Le => l.less_eq(r),
-            BinOp::Gt => l.greater_than(r),
-            BinOp::Ge => l.greater_eq(r),
+            BinOp::AddOv => "add.ov".to_string(),
+            BinOp::SubOv => "sub.ov".to_string(),
+            BinOp::MulOv => "mul.ov".to_string(),
+            _ => format!("{:?}", self).to_lowercase()
         }
     }
+}
 
+impl BinOp {
     pub fn is_arith(&self) -> bool {
         match self {
             BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod => true,
@@ -338,7 +734,7 @@ impl BinOp {
     pub fn is_comm(&self) -> bool {
         match self {
             BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or | BinOp::Xor | BinOp::Eq
-            | BinOp::Ne => true,
+            | BinOp::Ne | BinOp::AddOv | BinOp::MulOv => true,
             _ => false
         }
     }
@@ -394,6 +790,27 @@ impl BinOp {
 
     pub fn is_cmp(&self) -> bool { self.is_ord() | self.is_eq() }
 
+    /// Whether this operator checks overflow of arithmetic operation.
+    pub fn is_ov(&self) -> bool {
+        matches!(self, BinOp::AddOv | BinOp::SubOv | BinOp::MulOv)
+    }
+
+    /// Wrapping operator whose overflow is checked by this one
+    pub fn unchecked(&self) -> Option<BinOp> {
+        match self {
+            BinOp::AddOv => Some(BinOp::Add),
+            BinOp::SubOv => Some(BinOp::Sub),
+            BinOp::MulOv => Some(BinOp::Mul),
+            _ => None
+        }
+    }
+
+    /// Whether the result of this operator is always `i1`.
+    pub fn is_pred(&self) -> bool { self.is_cmp() | self.is_ov() }
+
+    /// Whether this operator traps at runtime if the second operand is zero.
+    pub fn is_trapping(&self) -> bool { matches!(self, BinOp::Div | BinOp::Mod) }
+
     /// Get result type of operators
     pub fn res_type(&self, ty: &Type) -> Option<Type> {
         match (self, ty) {
@@ -401,6 +818,7 @@ impl BinOp {
             (op, Type::I(_)) if op.is_eq() => Some(Type::I(1)),
             (op, Type::I(b)) if (op.is_arith() | op.is_shift()) && *b != 1 => Some(Type::I(*b)),
             (op, Type::I(b)) if op.is_ord() && *b != 1 => Some(Type::I(*b)),
+            (op, Type::I(b)) if op.is_ov() && *b != 1 => Some(Type::I(1)),
             (op, Type::Ptr(_)) if op.is_cmp() => Some(Type::I(1)),
             _ => None
         }
@@ -411,3 +829,38 @@ impl BinOp {
         self.res_type(ty).is_some()
     }
 }
+
+#[test]
+fn test_inst_eq() {
+    use std::collections::HashSet;
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+
+    let src = r#"
+fn @f($x: i64, $p: *i64) -> i64 {
+%Begin:
+    $a <- add i64 $x, 1
+    $b <- add i64 $x, 1
+    $c <- add i64 1, $x
+    $d <- ld i64 $p
+    $e <- ld i64 $p
+    $h <- trunc i64 $x -> i32
+    $i <- trunc i64 $x -> i16
+    $s <- add i64 $a, $b
+    ret $s
+}
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let ent = pro.func[0].ent.borrow().clone();
+    let inst: Vec<_> = ent.inst.borrow().iter().cloned().collect();
+    assert_eq!(inst[0].as_ref(), inst[1].as_ref());
+    assert_ne!(inst[0], inst[1]);
+    // Operands are not reordered
+    assert_ne!(inst[0].as_ref(), inst[2].as_ref());
+    assert_eq!(inst[3].as_ref(), inst[4].as_ref());
+    // Result types of casts are compared
+    assert_ne!(inst[5].as_ref(), inst[6].as_ref());
+    let set: HashSet<Inst> = inst.iter().map(|i| i.as_ref().clone()).collect();
+    assert_eq!(set.len(), inst.len() - 2);
+}
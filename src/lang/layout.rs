@@ -0,0 +1,134 @@
+use std::ops::Deref;
+
+use crate::lang::value::Type;
+
+/// Data layout of the target, which decides how values of each type are placed in memory.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct DataLayout {
+    /// Size of pointers in bytes
+    pub ptr_size: usize,
+    /// Alignment of pointers in bytes
+    pub ptr_align: usize,
+    /// Maximal alignment of integers in bytes. An integer is aligned to its size, but not more
+    /// than this value.
+    pub max_int_align: usize,
+}
+
+impl Default for DataLayout {
+    fn default() -> Self { Self::x64() }
+}
+
+impl DataLayout {
+    /// Layout of x86-64 System V ABI, where all scalars are naturally aligned.
+    pub fn x64() -> DataLayout {
+        DataLayout { ptr_size: 8, ptr_align: 8, max_int_align: 8 }
+    }
+
+    /// Layout without any padding, with pointers of `ptr_size` bytes.
+    pub fn packed(ptr_size: usize) -> DataLayout {
+        DataLayout { ptr_size, ptr_align: 1, max_int_align: 1 }
+    }
+}
+
+impl Type {
+    /// Size of this type in bytes. Fields of a structure are placed in order, each at the next
+    /// offset satisfying its alignment, and the size of any type is a multiple of its alignment,
+    /// so that elements of an array are all aligned.
+    pub fn size_of(&self, layout: &DataLayout) -> usize {
+        match self {
+            Type::Void => 0,
+            Type::I(1) => 1,
+            Type::I(b) => *b as usize / 8,
+            // Function code is only accessed through pointers
+            Type::Ptr(_) | Type::Fn { param: _, ret: _ } => layout.ptr_size,
+            Type::Array { elem, len } => elem.size_of(layout) * len,
+            Type::Struct { field } => match field.last() {
+                Some(last) => align_to(
+                    self.field_offset(field.len() - 1, layout) + last.size_of(layout),
+                    self.align_of(layout),
+                ),
+                None => 0
+            }
+            Type::Union { field } => align_to(
+                field.iter().map(|f| f.size_of(layout)).max().unwrap_or(0),
+                self.align_of(layout),
+            ),
+            Type::Alias(_) => self.orig().size_of(layout),
+            Type::Tuple(_) => panic!("tuple {} has no storage", self.to_string())
+        }
+    }
+
+    /// Alignment of this type in bytes. An aggregate is aligned as its most aligned member.
+    pub fn align_of(&self, layout: &DataLayout) -> usize {
+        match self {
+            Type::Void => 1,
+            Type::I(_) => self.size_of(layout).min(layout.max_int_align).max(1),
+            Type::Ptr(_) | Type::Fn { param: _, ret: _ } => layout.ptr_align,
+            Type::Array { elem, len: _ } => elem.align_of(layout),
+            Type::Struct { field } | Type::Union { field } =>
+                field.iter().map(|f| f.align_of(layout)).max().unwrap_or(1),
+            Type::Alias(_) => self.orig().align_of(layout),
+            Type::Tuple(_) => panic!("tuple {} has no storage", self.to_string())
+        }
+    }
+
+    /// Offset in bytes of the `idx`-th element of this aggregate type, which is what `ptr`
+    /// computes for an index.
+    pub fn field_offset(&self, idx: usize, layout: &DataLayout) -> usize {
+        match self.orig() {
+            Type::Array { elem, len: _ } => elem.size_of(layout) * idx,
+            Type::Struct { field } => {
+                let mut off = 0;
+                for f in &field[..idx] {
+                    off = align_to(off, f.align_of(layout)) + f.size_of(layout);
+                }
+                align_to(off, field[idx].align_of(layout))
+            }
+            Type::Union { field: _ } => 0,
+            ty => panic!("type {} is not aggregate", ty.to_string())
+        }
+    }
+
+    /// Type of the `idx`-th element of this aggregate type.
+    pub fn elem_type(&self, idx: usize) -> Type {
+        match self.orig() {
+            Type::Array { elem, len: _ } => elem.deref().clone(),
+            Type::Struct { field } | Type::Union { field } => field[idx].clone(),
+            ty => panic!("type {} is not aggregate", ty.to_string())
+        }
+    }
+}
+
+/// Round `off` up to a multiple of `align`.
+fn align_to(off: usize, align: usize) -> usize { off.div_ceil(align) * align }
+
+#[test]
+fn test_layout() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::value::Typed;
+
+    let src = r#"
+type @S = { i8, i32, i16, *i8 }
+type @U = union { i8, [3]i16 }
+type @T = { @U, i8, [2]@S, {} }
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let ty = |name: &str| pro.global.find(name).unwrap().get_type();
+    let (s, u, t) = (ty("S"), ty("U"), ty("T"));
+
+    let x64 = DataLayout::x64();
+    assert_eq!(pro.layout, x64);
+    assert_eq!((s.size_of(&x64), s.align_of(&x64)), (24, 8));
+    assert_eq!((0..4).map(|i| s.field_offset(i, &x64)).collect::<Vec<_>>(), vec![0, 4, 8, 16]);
+    assert_eq!((u.size_of(&x64), u.align_of(&x64)), (6, 2));
+    assert_eq!((0..4).map(|i| t.field_offset(i, &x64)).collect::<Vec<_>>(), vec![0, 6, 8, 56]);
+    assert_eq!((t.size_of(&x64), t.align_of(&x64)), (56, 8));
+    assert_eq!(t.elem_type(2).field_offset(1, &x64), 24);
+
+    let packed = DataLayout::packed(4);
+    assert_eq!((s.size_of(&packed), s.align_of(&packed)), (11, 1));
+    assert_eq!((0..4).map(|i| s.field_offset(i, &packed)).collect::<Vec<_>>(), vec![0, 1, 5, 7]);
+    assert_eq!((t.size_of(&packed), t.align_of(&packed)), (29, 1));
+}
@@ -0,0 +1,139 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use crate::lang::func::{BlockRef, Fn};
+use crate::lang::inst::{Inst, PhiSrc};
+use crate::lang::util::ExtRc;
+use crate::lang::value::{SymbolRef, Value};
+use crate::pass::util::LoopNodeRef;
+
+impl Fn {
+    /// Convert this function to loop-closed SSA form, in which every variable defined in a loop
+    /// and used outside of it only flows out through phis at the exit blocks of the loop. Loop
+    /// transforms that duplicate or version a loop then only have to patch these phis.
+    ///
+    /// A phi is inserted at each exit block where such a variable is live, and definitions and
+    /// uses of the variable are repaired with `repair_ssa`, so that uses outside the loop refer
+    /// to the phis. Exits of nested loops are closed as well. Returns whether any phi is
+    /// inserted.
+    pub fn to_lcssa(&self) -> bool {
+        self.assert_ssa();
+        self.build_dom();
+        let live = self.liveness();
+        let mut loops = self.analyze_loop();
+        let mut sym: Vec<SymbolRef> = vec![];
+        while let Some(node) = loops.pop() {
+            loops.extend(node.borrow().nested.iter().cloned());
+            for (exit, def) in Self::loop_exits(&node) {
+                let mut def: Vec<SymbolRef> = def.into_iter()
+                    .filter(|s| live.live_in[&exit].contains(s)).collect();
+                def.sort_by(|a, b| a.name().cmp(b.name()));
+                for s in def.into_iter().rev() {
+                    let src: Vec<PhiSrc> = exit.pred().into_iter().map(|pred| {
+                        (RefCell::new(pred), RefCell::new(Value::Var(s.clone())))
+                    }).collect();
+                    exit.push_front(ExtRc::new(Inst::Phi { src, dst: RefCell::new(s.clone()) }));
+                    if !sym.contains(&s) { sym.push(s) }
+                }
+            }
+        }
+        if sym.is_empty() { return false; }
+        self.repair_ssa(&sym);
+        true
+    }
+
+    /// Find exit blocks of a loop, along with local variables defined in the loop.
+    fn loop_exits(node: &LoopNodeRef) -> Vec<(BlockRef, HashSet<SymbolRef>)> {
+        let blocks = node.borrow().all_blocks();
+        let mut def = HashSet::new();
+        let mut exits: Vec<BlockRef> = vec![];
+        for block in &blocks {
+            for instr in block.inst.borrow().iter() {
+                instr.dsts().iter().filter(|d| d.borrow().is_local_var())
+                    .for_each(|d| { def.insert(d.borrow().clone()); });
+            }
+            for succ in block.succ.borrow().iter() {
+                if !blocks.contains(succ) && !exits.contains(succ) { exits.push(succ.clone()) }
+            }
+        }
+        exits.into_iter().map(|exit| (exit, def.clone())).collect()
+    }
+}
+
+#[test]
+fn test_lcssa() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::Pass;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let pro = || Builder::new(Parser::new(Lexer::from(r#"
+@r: i64
+
+[ssa]
+fn @main() {
+%Begin:
+    jmp %Outer
+%Outer:
+    $i <- phi i64 [%Begin: 0] [%Next: $i.1]
+    $s <- phi i64 [%Begin: 0] [%Next: $t.1]
+    jmp %Inner
+%Inner:
+    $j <- phi i64 [%Outer: 0] [%Inner: $j.1]
+    $t <- phi i64 [%Outer: $s] [%Inner: $t.1]
+    $t.1 <- add i64 $t, $j
+    $j.1 <- add i64 $j, 1
+    $c <- lt i64 $j.1, 3
+    br $c ? %Inner : %Latch
+%Latch:
+    $i.1 <- add i64 $i, 1
+    $b <- eq i64 $t.1, 9
+    br $b ? %Early : %Next
+%Next:
+    $d <- lt i64 $i.1, 5
+    br $d ? %Outer : %End
+%Early:
+    $e <- mul i64 $i.1, 100
+    jmp %End
+%End:
+    $x <- phi i64 [%Next: 0] [%Early: $e]
+    $y <- add i64 $t.1, $x
+    @r <- mov i64 $y
+    ret
+}
+"#)).parse().unwrap()).build().unwrap();
+    let expect = format!("{:?}", Machine::new().run(&pro()).unwrap().global);
+
+    let mut pro = pro();
+    let func = pro.func[0].clone();
+    assert!(func.to_lcssa());
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    let mut ver = VerifyPass::new();
+    ver.run(&mut pro);
+    assert!(ver.is_ok());
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+
+    // Variables defined in loops are only used outside of them by phis
+    for node in func.analyze_loop() {
+        let blocks = node.borrow().all_blocks();
+        for block in func.dfs().filter(|b| !blocks.contains(b)) {
+            let def: HashSet<SymbolRef> = blocks.iter()
+                .flat_map(|b| b.inst.borrow().clone())
+                .flat_map(|i| i.dsts().iter().map(|d| d.borrow().clone()).collect::<Vec<_>>())
+                .collect();
+            for instr in block.inst.borrow().iter().filter(|i| !i.is_phi()) {
+                assert!(instr.src().iter().all(|v| match &*v.borrow() {
+                    Value::Var(s) => !def.contains(s),
+                    _ => true
+                }));
+            }
+        }
+    }
+    assert!(!func.to_lcssa());
+}
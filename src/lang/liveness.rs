@@ -0,0 +1,257 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+
+use crate::lang::dataflow::{DataFlow, Direction};
+use crate::lang::func::{BlockRef, Fn};
+use crate::lang::graph::Vertex;
+use crate::lang::inst::Inst;
+use crate::lang::value::{SymbolRef, Value};
+
+/// Liveness information of local variables in a function.
+/// Phi instructions are handled in the SSA way: a phi source operand is live out of its
+/// corresponding predecessor, but not live into the block where the phi is defined.
+#[derive(Debug)]
+pub struct Liveness {
+    /// Variables live at the entrance of each block
+    pub live_in: HashMap<BlockRef, HashSet<SymbolRef>>,
+    /// Variables live at the exit of each block
+    pub live_out: HashMap<BlockRef, HashSet<SymbolRef>>,
+}
+
+/// Backward data-flow analysis of live variables
+struct LiveVars {
+    /// Variables used in each block before defined, excluding phi operands
+    uses: HashMap<BlockRef, HashSet<SymbolRef>>,
+    /// Variables defined in each block
+    defs: HashMap<BlockRef, HashSet<SymbolRef>>,
+    /// Phi operands used along each edge
+    phi_uses: HashMap<(BlockRef, BlockRef), HashSet<SymbolRef>>,
+}
+
+impl DataFlow for LiveVars {
+    type Val = HashSet<SymbolRef>;
+
+    fn dir(&self) -> Direction { Direction::Backward }
+
+    fn boundary(&self, _: &Fn) -> Self::Val { HashSet::new() }
+
+    fn init(&self) -> Self::Val { HashSet::new() }
+
+    fn meet(&self, a: &Self::Val, b: &Self::Val) -> Self::Val { a.union(b).cloned().collect() }
+
+    // live_in(B) = uses(B) U (live_out(B) - defs(B))
+    fn transfer(&self, block: &BlockRef, val: &Self::Val) -> Self::Val {
+        let mut inn = self.uses[block].clone();
+        val.iter().filter(|sym| !self.defs[block].contains(*sym))
+            .for_each(|sym| { inn.insert(sym.clone()); });
+        inn
+    }
+
+    // live_out(B) = U_{S in succ(B)} (live_in(S) U phi_uses(B -> S))
+    fn edge(&self, from: &BlockRef, to: &BlockRef, val: &Self::Val) -> Self::Val {
+        match self.phi_uses.get(&(from.clone(), to.clone())) {
+            Some(phi) => val.union(phi).cloned().collect(),
+            None => val.clone()
+        }
+    }
+}
+
+impl Fn {
+    /// Compute live-in and live-out sets of all reachable blocks in this function.
+    /// This analysis does not require SSA form, but handles phi instructions correctly if there
+    /// are any.
+    pub fn liveness(&self) -> Liveness {
+        // Compute local use and definition sets for each block
+        let blocks: Vec<BlockRef> = self.ent.borrow().po().collect();
+        let mut uses: HashMap<BlockRef, HashSet<SymbolRef>> = HashMap::new();
+        let mut defs: HashMap<BlockRef, HashSet<SymbolRef>> = HashMap::new();
+        let mut phi_uses: HashMap<(BlockRef, BlockRef), HashSet<SymbolRef>> = HashMap::new();
+        for block in &blocks {
+            let mut blk_use = HashSet::new();
+            let mut blk_def = HashSet::new();
+            if block == self.ent.borrow().deref() {
+                self.param.iter().for_each(|p| { blk_def.insert(p.borrow().clone()); });
+            }
+            for instr in block.inst.borrow().iter() {
+                match instr.as_ref() {
+                    Inst::Phi { src, dst: _ } => src.iter().for_each(|(pred, opd)| {
+                        if let Value::Var(sym) = opd.borrow().deref() {
+                            if !sym.is_local_var() { return; }
+                            phi_uses.entry((pred.borrow().clone(), block.clone()))
+                                .or_default().insert(sym.clone());
+                        }
+                    }),
+                    _ => instr.src().iter().for_each(|opd| {
+                        if let Value::Var(sym) = opd.borrow().deref() {
+                            if sym.is_local_var() && !blk_def.contains(sym) {
+                                blk_use.insert(sym.clone());
+                            }
+                        }
+                    })
+                }
+                for dst in instr.dsts() {
+                    if dst.borrow().is_local_var() { blk_def.insert(dst.borrow().clone()); }
+                }
+            }
+            uses.insert(block.clone(), blk_use);
+            defs.insert(block.clone(), blk_def);
+        }
+
+        // Solve backward data-flow equations
+        let sol = self.solve(&LiveVars { uses, defs, phi_uses });
+        Liveness { live_in: sol.inn, live_out: sol.out }
+    }
+}
+
+/// Interference graph of local variables.
+/// Two variables interfere if one of them is defined at a point where the other is live.
+#[derive(Debug)]
+pub struct InterfGraph {
+    /// Adjacency set of each variable
+    pub edges: HashMap<SymbolRef, HashSet<SymbolRef>>,
+}
+
+impl InterfGraph {
+    fn new() -> InterfGraph { InterfGraph { edges: HashMap::new() } }
+
+    /// Add a variable to the graph without any edge.
+    fn add_vert(&mut self, sym: &SymbolRef) {
+        self.edges.entry(sym.clone()).or_default();
+    }
+
+    /// Add an undirected edge between two distinct variables.
+    fn add_edge(&mut self, a: &SymbolRef, b: &SymbolRef) {
+        if a == b { return; }
+        self.edges.entry(a.clone()).or_default().insert(b.clone());
+        self.edges.entry(b.clone()).or_default().insert(a.clone());
+    }
+
+    /// Whether two variables interfere with each other.
+    pub fn interferes(&self, a: &SymbolRef, b: &SymbolRef) -> bool {
+        self.edges.get(a).map(|adj| adj.contains(b)).unwrap_or(false)
+    }
+
+    /// Get all variables interfering with the given one.
+    pub fn neighbors(&self, sym: &SymbolRef) -> Vec<SymbolRef> {
+        self.edges.get(sym).map(|adj| adj.iter().cloned().collect()).unwrap_or_default()
+    }
+}
+
+impl Liveness {
+    /// Build interference graph of local variables in the function using this liveness
+    /// information. The source of a `mov` does not interfere with its destination, so that they
+    /// could be possibly coalesced.
+    pub fn interference(&self, func: &Fn) -> InterfGraph {
+        let mut graph = InterfGraph::new();
+
+        // Parameters are defined simultaneously at function entrance
+        let param: Vec<SymbolRef> = func.param.iter().map(|p| p.borrow().clone()).collect();
+        let ent = func.ent.borrow().clone();
+        for (i, p) in param.iter().enumerate() {
+            graph.add_vert(p);
+            param[i + 1..].iter().for_each(|q| graph.add_edge(p, q));
+            if let Some(live) = self.live_in.get(&ent) {
+                live.iter().for_each(|l| graph.add_edge(p, l));
+            }
+        }
+
+        for (block, out) in self.live_out.iter() {
+            // Walk instructions backward, maintaining the current live set
+            let mut live = out.clone();
+            let instr: Vec<_> = block.inst.borrow().iter().cloned().collect();
+            let phi_dst: Vec<SymbolRef> = instr.iter().filter(|i| i.is_phi())
+                .map(|i| i.dst().unwrap().borrow().clone()).collect();
+            for instr in instr.iter().rev() {
+                // Destinations of the same instruction are defined at the same time
+                let dsts: Vec<SymbolRef> = instr.dsts().iter().map(|d| d.borrow().clone())
+                    .filter(|d| d.is_local_var()).collect();
+                for dst in dsts.iter() {
+                    graph.add_vert(dst);
+                    let mov_src = match instr.as_ref() {
+                        Inst::Mov { src, dst: _ } => match src.borrow().deref() {
+                            Value::Var(sym) => Some(sym.clone()),
+                            _ => None
+                        }
+                        _ => None
+                    };
+                    live.iter().filter(|l| Some(*l) != mov_src.as_ref())
+                        .for_each(|l| graph.add_edge(dst, l));
+                    // All phi destinations are defined in parallel at block entrance
+                    if instr.is_phi() {
+                        phi_dst.iter().for_each(|other| graph.add_edge(dst, other));
+                    }
+                    dsts.iter().filter(|other| *other != dst)
+                        .for_each(|other| graph.add_edge(dst, other));
+                }
+                dsts.iter().for_each(|dst| { live.remove(dst); });
+                if instr.is_phi() { continue; } // phi operands are live out of predecessors
+                instr.src().iter().for_each(|opd| {
+                    if let Value::Var(sym) = opd.borrow().deref() {
+                        if sym.is_local_var() { live.insert(sym.clone()); }
+                    }
+                });
+            }
+        }
+
+        graph
+    }
+}
+
+#[test]
+fn test_liveness() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut file = File::open("test/sum.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let pro = builder.build().unwrap();
+
+    for func in &pro.func {
+        let live = func.liveness();
+        println!("fn @{}", func.name);
+        for block in func.rpo() {
+            println!("%{}: in {:?} out {:?}", block.name, live.live_in[&block],
+                     live.live_out[&block]);
+        }
+        let graph = live.interference(func);
+        for (sym, adj) in graph.edges.iter() {
+            println!("{:?} -- {:?}", sym, adj);
+        }
+    }
+
+    // Check the loop of `@sum_four`
+    let func = &pro.func[0];
+    let live = func.liveness();
+    let graph = live.interference(func);
+    let block = |name: &str| func.rpo().find(|b| b.name == name).unwrap();
+    let names = |set: &HashSet<SymbolRef>| {
+        let mut names: Vec<_> = set.iter().map(|s| s.name().to_string()).collect();
+        names.sort();
+        names
+    };
+    let sym = |name: &str| func.scope.find(name).unwrap();
+    assert!(names(&live.live_in[&block("Init")]).is_empty());
+    assert_eq!(names(&live.live_out[&block("Init")]), ["i.0", "n", "s.0"]);
+    assert_eq!(names(&live.live_in[&block("Cond")]), ["n"]);
+    assert_eq!(names(&live.live_out[&block("Cond")]), ["e", "i.1", "n", "s.1"]);
+    assert_eq!(names(&live.live_in[&block("Loop")]), ["e", "i.1", "n", "s.1"]);
+    assert_eq!(names(&live.live_out[&block("Loop")]), ["i.2", "n", "s.2"]);
+    assert_eq!(names(&live.live_in[&block("End")]), ["s.1"]);
+
+    // `$n` is live across the phis, and `$s.1` is live from its phi to the end of `%Cond`
+    for (a, b) in [("n", "s.1"), ("n", "i.1"), ("s.1", "i.1"), ("s.1", "e"), ("c", "s.1"),
+                   ("c", "e"), ("i.1", "s.2"), ("i.2", "s.2"), ("i.0", "s.0")] {
+        assert!(graph.interferes(&sym(a), &sym(b)), "{} -- {}", a, b);
+    }
+    // Operands dying at definitions do not interfere with the results
+    for (a, b) in [("s.1", "s.2"), ("e", "s.2"), ("i.1", "i.2"), ("s.0", "s.1")] {
+        assert!(!graph.interferes(&sym(a), &sym(b)), "{} -- {}", a, b);
+    }
+}
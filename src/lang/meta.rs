@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+
+use crate::lang::func::Fn;
+use crate::lang::inst::InstRef;
+
+/// Value of a metadata entry
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum MetaVal {
+    Int(i64),
+    Str(String),
+}
+
+impl ToString for MetaVal {
+    fn to_string(&self) -> String {
+        match self {
+            MetaVal::Int(i) => i.to_string(),
+            MetaVal::Str(s) => format!("\"{}\"", s)
+        }
+    }
+}
+
+/// Metadata map from key to value.
+/// Metadata does not affect semantics of the program. It carries extra information, such as
+/// profiling data and pass annotations, through the pipeline. `BTreeMap` is used so that the
+/// entries are always printed in the same order.
+pub type Metadata = BTreeMap<String, MetaVal>;
+
+/// Format metadata as a suffix of a line, in the form ` !key val !key val`.
+pub fn fmt_meta(meta: &Metadata) -> String {
+    meta.iter().map(|(k, v)| format!(" !{} {}", k, v.to_string())).collect()
+}
+
+impl Fn {
+    /// Get metadata value of instruction `instr` with `key`.
+    pub fn inst_meta(&self, instr: &InstRef, key: &str) -> Option<MetaVal> {
+        self.inst_meta.borrow().get(instr).and_then(|m| m.get(key).cloned())
+    }
+
+    /// Set metadata value of instruction `instr` with `key`.
+    pub fn set_inst_meta(&self, instr: &InstRef, key: &str, val: MetaVal) {
+        self.inst_meta.borrow_mut().entry(instr.clone()).or_default()
+            .insert(key.to_string(), val);
+    }
+
+    /// Move all metadata and source location of instruction `from` to `to`. This should be
+    /// called when a pass replaces an instruction with an equivalent one.
+    pub fn move_inst_meta(&self, from: &InstRef, to: &InstRef) {
+        let meta = self.inst_meta.borrow_mut().remove(from);
+        if let Some(meta) = meta {
+            self.inst_meta.borrow_mut().entry(to.clone()).or_default().extend(meta)
+        }
+        let loc = self.inst_loc.borrow_mut().remove(from);
+        if let Some(loc) = loc { self.inst_loc.borrow_mut().insert(to.clone(), loc); }
+    }
+}
+
+#[test]
+fn test_meta() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::lang::print::Printer;
+    use crate::pass::fold::ConstFold;
+    use crate::pass::Pass;
+    use std::fs::File;
+    use std::io::Read;
+    use std::convert::TryFrom;
+
+    // Build program from source
+    let mut file = File::open("test/meta.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let mut pro = builder.build().unwrap();
+    let func = pro.func[0].clone();
+    assert_eq!(func.meta.borrow().get("note"), Some(&MetaVal::Str("pure".to_string())));
+    assert_eq!(func.ent.borrow().meta.borrow().get("freq"), Some(&MetaVal::Int(100)));
+
+    // Metadata are kept when instructions are folded
+    let mut opt = ConstFold::new();
+    opt.run(&mut pro);
+    let block = func.iter_dom().find(|b| b.name == "Else").unwrap();
+    let instr = block.inst.borrow().front().cloned().unwrap();
+    assert_eq!(func.inst_meta(&instr, "prof"), Some(MetaVal::Int(40)));
+
+    // Printed program can be parsed again, with the same metadata
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let src = String::from_utf8(out).unwrap();
+    println!("{}", src);
+    let tree = Parser::new(Lexer::from(src.as_str())).parse().unwrap();
+    let pro = Builder::new(tree).build().unwrap();
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    assert_eq!(src, String::from_utf8(out).unwrap());
+}
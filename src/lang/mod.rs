@@ -1,15 +1,40 @@
+use std::ops::Deref;
+use std::path::Path;
 use std::rc::Rc;
 
+use crate::irc::CompileErr;
+use crate::irc::build::Builder;
+use crate::irc::parse::Parser;
 use crate::lang::func::FnRef;
-use crate::lang::value::{GlobalVarRef, Scope};
+use crate::lang::inst::Inst;
+use crate::lang::layout::DataLayout;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{GlobalVarRef, Scope, Symbol, Value};
 
 pub mod util;
 pub mod value;
+pub mod consteval;
 pub mod inst;
 pub mod func;
 pub mod ssa;
 pub mod print;
 pub mod graph;
+pub mod liveness;
+pub mod verify;
+pub mod meta;
+pub mod clone;
+pub mod diff;
+pub mod layout;
+pub mod stats;
+pub mod blkarg;
+pub mod region;
+pub mod dataflow;
+pub mod escape;
+pub mod cost;
+pub mod frozen;
+pub mod lcssa;
+pub mod motion;
+pub mod arena;
 
 /// Top level program structure
 pub struct Program {
@@ -19,4 +44,62 @@ pub struct Program {
     pub func: Vec<FnRef>,
     /// Scope for global symbols
     pub global: Rc<Scope>,
+    /// Data layout of the target
+    pub layout: DataLayout,
+}
+
+impl Program {
+    /// Read, parse and build program from source file at `path`. Errors are located with the
+    /// path of file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Program, CompileErr> {
+        Builder::new(Parser::from_path(path)?.parse()?).build()
+    }
+
+    /// Entry points of this program, which are `@main` and functions marked `entry`.
+    pub fn entries(&self) -> Vec<FnRef> {
+        self.func.iter().filter(|f| f.is_entry()).cloned().collect()
+    }
+
+    /// Function where execution of this program starts, which is `@main` if there is one, or
+    /// the first other entry point otherwise.
+    pub fn entry(&self) -> Option<FnRef> {
+        self.func.iter().find(|f| f.name == "main")
+            .or_else(|| self.func.iter().find(|f| f.is_entry())).cloned()
+    }
+
+    /// Replace function `old` with `new`, which should have the same name and type. The new
+    /// function takes the place of the old one in the function list and the global scope, and
+    /// calls to the old function, as well as its uses as function pointer, are redirected to it
+    /// throughout the program.
+    pub fn replace_fn(&mut self, old: &FnRef, new: FnRef) {
+        if let Some(f) = self.func.iter_mut().find(|f| *f == old) { *f = new.clone() }
+        let sym = ExtRc::new(Symbol::Func(new.clone()));
+        self.global.remove(&old.name);
+        self.global.insert(sym.clone());
+        for func in &self.func {
+            for block in func.dfs() {
+                let mut cursor = block.cursor();
+                while let Some(instr) = cursor.next() {
+                    for opd in instr.src() {
+                        let is_old = match opd.borrow().deref() {
+                            Value::Var(s) => matches!(s.as_ref(), Symbol::Func(f) if f == old),
+                            _ => false
+                        };
+                        if is_old { opd.replace(Value::Var(sym.clone())); }
+                    }
+                    let (arg, dst) = match instr.as_ref() {
+                        Inst::Call { func, arg, dst } if func == old => (arg, dst),
+                        _ => continue
+                    };
+                    let call = ExtRc::new(Inst::Call {
+                        func: new.clone(),
+                        arg: arg.clone(),
+                        dst: dst.clone(),
+                    });
+                    cursor.replace(call.clone());
+                    func.move_inst_meta(&instr, &call);
+                }
+            }
+        }
+    }
 }
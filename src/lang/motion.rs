@@ -0,0 +1,268 @@
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, Fn, FnAttrib};
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::value::{SymbolRef, Value};
+
+impl Inst {
+    /// Whether this instruction can be executed where it was not, without changing behavior of
+    /// the program. Only pure instructions which cannot trap are speculatable.
+    pub fn is_speculatable(&self) -> bool {
+        match self {
+            Inst::Mov { src: _, dst: _ } | Inst::Un { op: _, opd: _, dst: _ }
+            | Inst::Cast { op: _, opd: _, dst: _ }
+            | Inst::Ptr { base: _, off: _, ind: _, dst: _ } => {}
+            Inst::Bin { op, fst: _, snd: _, dst: _ } if !op.is_trapping() => {}
+            _ => return false
+        }
+        !self.has_side_effect()
+    }
+
+    /// Whether this instruction can be moved right before `other`, which is executed right
+    /// before it, i.e. whether the two instructions can be swapped. They must not depend on each
+    /// other through their operands or destinations, must not access the same memory or
+    /// global variables if one of them writes, and must not reorder a possible trap with side
+    /// effects. Phis and control flow instructions are never movable.
+    pub fn can_move_before(&self, other: &Inst) -> bool {
+        if [self, other].iter().any(|i| i.is_phi() || i.is_ctrl()) { return false; }
+
+        // Check data dependencies
+        let defs = |i: &Inst| -> Vec<SymbolRef> {
+            i.dsts().iter().map(|d| d.borrow().clone()).collect()
+        };
+        let uses = |i: &Inst| -> Vec<SymbolRef> {
+            i.src().iter().filter_map(|v| match v.borrow().deref() {
+                Value::Var(sym) => Some(sym.clone()),
+                _ => None
+            }).collect()
+        };
+        let (def_a, def_b) = (defs(self), defs(other));
+        if def_a.iter().any(|d| def_b.contains(d) || uses(other).contains(d))
+            || def_b.iter().any(|d| uses(self).contains(d)) { return false; }
+
+        // Check memory dependencies. Direct accesses of global variables are already checked,
+        // so only ones through calls are left. Atomic instructions may synchronize with other
+        // threads, so no memory access or global variable is reordered with them.
+        let conflict = |(r1, w1): (bool, bool), (r2, w2): (bool, bool)| {
+            (w1 && (r2 || w2)) || (w2 && r1)
+        };
+        if conflict(self.mem_access(), other.mem_access()) { return false; }
+        if (self.is_call() || other.is_call() || self.is_atomic() || other.is_atomic())
+            && conflict(self.global_access(), other.global_access()) { return false; }
+
+        // Check traps
+        !(self.may_trap() && (other.may_trap() || other.has_side_effect())
+            || other.may_trap() && self.has_side_effect())
+    }
+
+    fn is_call(&self) -> bool {
+        matches!(self, Inst::Call { func: _, arg: _, dst: _ }
+            | Inst::CallInd { func_ptr: _, arg: _, dst: _ })
+    }
+
+    /// Whether this instruction may end execution of the program abnormally.
+    fn may_trap(&self) -> bool {
+        match self {
+            Inst::Bin { op, fst: _, snd: _, dst: _ } => op.is_trapping(),
+            // Callee may trap or never return
+            Inst::Call { func: _, arg: _, dst: _ }
+            | Inst::CallInd { func_ptr: _, arg: _, dst: _ } => true,
+            instr => instr.is_trap()
+        }
+    }
+
+    /// Whether this instruction reads and writes memory.
+    fn mem_access(&self) -> (bool, bool) {
+        match self {
+            Inst::Ld { ptr: _, dst: _ } => (true, false),
+            Inst::St { src: _, ptr: _ } | Inst::Memset { src: _, ptr: _, len: _ }
+            | Inst::New { dst: _, len: _ } => (false, true),
+            Inst::Memcpy { src: _, ptr: _, len: _ } => (true, true),
+            Inst::Call { func, arg: _, dst: _ } => Self::callee_access(func),
+            Inst::CallInd { func_ptr: _, arg: _, dst: _ } => (true, true),
+            instr if instr.is_atomic() => (true, true),
+            _ => (false, false)
+        }
+    }
+
+    /// Whether this instruction reads and writes global variables.
+    fn global_access(&self) -> (bool, bool) {
+        match self {
+            Inst::Call { func, arg: _, dst: _ } => Self::callee_access(func),
+            Inst::CallInd { func_ptr: _, arg: _, dst: _ } => (true, true),
+            instr if instr.is_atomic() => (true, true),
+            _ => (
+                self.src().iter().any(|v| match v.borrow().deref() {
+                    Value::Var(sym) => sym.is_global_var(),
+                    _ => false
+                }),
+                self.dsts().iter().any(|d| d.borrow().is_global_var())
+            )
+        }
+    }
+
+    fn callee_access(func: &Fn) -> (bool, bool) {
+        if func.has_attrib(FnAttrib::Pure) {
+            (false, false)
+        } else {
+            (true, !func.is_readonly())
+        }
+    }
+}
+
+impl Fn {
+    /// Move instruction `from` right before `to`, if this preserves behavior of the function.
+    /// Returns whether it is moved. See `can_move_inst` for the conditions.
+    pub fn move_inst(&self, from: &InstRef, to: &InstRef) -> bool {
+        if !self.can_move_inst(from, to) { return false; }
+        let (orig, _) = self.locate_inst(from).unwrap();
+        orig.retain(|i| i != from);
+        let (tgt, idx) = self.locate_inst(to).unwrap();
+        tgt.inst.borrow_mut().insert(idx, from.clone());
+        true
+    }
+
+    /// Whether instruction `from` can be moved right before `to`.
+    /// Within a block, `from` must be able to move across every instruction between the two
+    /// positions. Moving to another block changes when `from` is executed, so it must be
+    /// speculatable, and the function must be in SSA form: the new position must be dominated
+    /// by definitions of its operands, and dominate all uses of its destination. The dominator
+    /// tree should be built.
+    pub fn can_move_inst(&self, from: &InstRef, to: &InstRef) -> bool {
+        let ((orig, i), (tgt, j)) = match (self.locate_inst(from), self.locate_inst(to)) {
+            (Some(f), Some(t)) => (f, t),
+            _ => return false
+        };
+        if from == to || from.is_phi() || from.is_ctrl() || to.is_phi() { return false; }
+
+        // Move within the block, across instructions in between
+        if orig == tgt {
+            let inst = orig.inst.borrow();
+            return if j < i {
+                inst.range(j..i).all(|other| from.can_move_before(other))
+            } else {
+                inst.range(i + 1..j).all(|other| other.can_move_before(from))
+            };
+        }
+
+        // Move to another block
+        if !self.ssa.get() || !from.is_speculatable() { return false; }
+        let ops_avail = from.src().iter().all(|v| match v.borrow().deref() {
+            Value::Var(sym) if sym.is_local_var() => match self.def_pos(sym) {
+                Some((blk, k)) => if blk == tgt { k < j } else { blk.strict_dom(&tgt) }
+                None => true // parameter
+            }
+            _ => true
+        });
+        if !ops_avail { return false; }
+        let dst = from.dst().unwrap().borrow().clone();
+        self.dfs().all(|blk| blk.inst.borrow().iter().enumerate().all(|(k, instr)| {
+            match instr.as_ref() {
+                Inst::Phi { src, dst: _ } => src.iter().all(|(pred, v)| match v.borrow().deref() {
+                    Value::Var(sym) if *sym == dst => tgt.dominates(&pred.borrow()),
+                    _ => true
+                }),
+                _ if instr.src().iter().any(|v| *v.borrow() == Value::Var(dst.clone())) =>
+                    if blk == tgt { j <= k } else { tgt.strict_dom(&blk) },
+                _ => true
+            }
+        }))
+    }
+
+    /// Find block containing `instr` and its index there.
+    fn locate_inst(&self, instr: &InstRef) -> Option<(BlockRef, usize)> {
+        self.dfs().find_map(|blk| {
+            let idx = blk.inst.borrow().iter().position(|i| i == instr);
+            idx.map(|idx| (blk, idx))
+        })
+    }
+
+    /// Find block and index of instruction defining `sym`, or `None` if it is a parameter.
+    fn def_pos(&self, sym: &SymbolRef) -> Option<(BlockRef, usize)> {
+        self.dfs().find_map(|blk| {
+            let idx = blk.inst.borrow().iter()
+                .position(|i| i.dsts().iter().any(|d| *d.borrow() == *sym));
+            idx.map(|idx| (blk, idx))
+        })
+    }
+}
+
+#[test]
+fn test_move_inst() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::Pass;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let mut pro = Builder::new(Parser::new(Lexer::from(r#"
+@r: i64
+@g: i64 <- 2
+
+[readonly]
+fn @get() -> i64 {
+%Begin:
+    ret @g
+}
+
+[ssa]
+fn @main() {
+%Begin:
+    $p <- alloc i64
+    st i64 7 -> $p
+    $a <- add i64 @g, 1
+    $c <- call i64 @get()
+    @g <- mov i64 $a
+    $x <- ld i64 $p
+    $d <- div i64 $x, $c
+    $b <- mul i64 $a, 3
+    $k <- lt i64 $x, $d
+    br $k ? %Then : %End
+%Then:
+    $e <- sub i64 $b, $x
+    jmp %End
+%End:
+    $f <- phi i64 [%Begin: $d] [%Then: $e]
+    @r <- add i64 $f, $b
+    ret
+}
+"#)).parse().unwrap()).build().unwrap();
+    let expect = format!("{:?}", Machine::new().run(&pro).unwrap().global);
+    let func = pro.func.iter().find(|f| f.name == "main").unwrap().clone();
+    func.build_dom();
+    let find = |blk: &str, idx: usize| {
+        func.dfs().find(|b| b.name == blk).unwrap().inst.borrow()[idx].clone()
+    };
+    let (st, add, call, mov, ld, div, mul) =
+        (find("Begin", 1), find("Begin", 2), find("Begin", 3), find("Begin", 4),
+         find("Begin", 5), find("Begin", 6), find("Begin", 7));
+
+    // Data, memory, global variable and trap dependencies
+    assert!(!func.can_move_inst(&ld, &st));
+    assert!(!func.can_move_inst(&mov, &call));
+    assert!(func.can_move_inst(&add, &find("Begin", 0)));
+    assert!(!func.can_move_inst(&div, &ld));
+    assert!(!func.can_move_inst(&mov, &add));
+    assert!(func.can_move_inst(&ld, &add));
+    assert!(add.can_move_before(&st));
+    assert!(!mul.can_move_before(&add));
+
+    // Speculatable instructions can be moved to blocks where operands are available and uses
+    // are dominated
+    let then = find("Then", 0);
+    assert!(!func.can_move_inst(&then, &div));
+    assert!(!func.can_move_inst(&mul, &find("Then", 1)));
+    assert!(!func.can_move_inst(&div, &then));
+    assert!(!func.can_move_inst(&mul, &add));
+    assert!(func.move_inst(&mul, &call));
+    assert!(func.move_inst(&ld, &call));
+    assert!(!func.move_inst(&call, &call));
+
+    let mut ver = VerifyPass::new();
+    ver.run(&mut pro);
+    assert!(ver.is_ok());
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+    let names: Vec<_> = func.ent.borrow().inst.borrow().iter().map(|i| i.name()).collect();
+    assert_eq!(names, ["alloc", "st", "add", "mul", "ld", "call", "mov", "div", "lt", "br"]);
+}
@@ -1,22 +1,185 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io::{Error, Write};
 use std::ops::Deref;
 
+use crate::irc::Loc;
 use crate::lang::func::{BlockRef, Fn};
 use crate::lang::inst::{Inst, InstRef, PhiSrc};
+use crate::lang::meta::fmt_meta;
 use crate::lang::Program;
-use crate::lang::value::{GlobalVar, Symbol, Type, Typed, Value};
+use crate::lang::value::{GlobalVar, Symbol, SymbolRef, Type, Typed, Value};
 
 pub struct Printer<'a> {
     writer: &'a mut dyn Write,
+    /// Whether to print source locations of blocks and instructions as comments
+    loc: bool,
+    /// Whether to print statistics of program as comments
+    stats: bool,
+    /// Whether to number temporaries and temporary blocks
+    number: bool,
+    /// Numbering of the function being printed
+    temp: RefCell<Option<Numbering>>,
+    /// Whether to annotate instructions with definitions of operands and users of results
+    def_use: bool,
+    /// Def-use index of the function being printed
+    index: RefCell<Option<DefUseIndex>>,
 }
 
-macro_rules! fmt_val { ($v:ident) => {$v.borrow().to_string()}; }
+/// Numbers of temporaries and temporary blocks in a function
+/// They are numbered in reverse post-order of blocks, which is also the printing order, so
+/// each definition is numbered before the uses it dominates. Temporaries that are used but not
+/// defined are numbered after the defined ones.
+struct Numbering {
+    func: String,
+    sym: HashMap<SymbolRef, usize>,
+    blk: HashMap<BlockRef, usize>,
+}
+
+impl Numbering {
+    fn new(func: &Fn) -> Numbering {
+        let mut sym = HashMap::new();
+        let mut add = |s: &SymbolRef| if s.is_temp() && !sym.contains_key(s) {
+            sym.insert(s.clone(), sym.len());
+        };
+        func.param.iter().for_each(|p| add(&p.borrow()));
+        let blocks: Vec<BlockRef> = func.rpo().collect();
+        for b in &blocks {
+            if let Some(param) = func.blk_param.borrow().get(b) {
+                param.iter().for_each(|p| add(&p.borrow()));
+            }
+            b.inst.borrow().iter().for_each(|i| i.dsts().iter().for_each(|d| add(&d.borrow())));
+        }
+        for b in &blocks {
+            for instr in b.inst.borrow().iter() {
+                let mut opd = instr.src();
+                if let Inst::Phi { src, dst: _ } = instr.as_ref() {
+                    opd = src.iter().map(|(_, v)| v).collect();
+                }
+                opd.iter().for_each(|v| if let Value::Var(s) = v.borrow().deref() { add(s) });
+            }
+        }
+        let blk = blocks.iter().filter(|b| b.is_temp()).enumerate()
+            .map(|(i, b)| (b.clone(), i)).collect();
+        Numbering { func: func.name.clone(), sym, blk }
+    }
+}
+
+/// Positions of definitions and uses of local variables in a function
+/// Only reachable blocks are indexed. If the function is not in SSA form, a variable may have
+/// several definitions, and its users are the ones of all definitions.
+struct DefUseIndex {
+    func: String,
+    /// Block and index in block of each instruction
+    pos: HashMap<InstRef, (BlockRef, usize)>,
+    /// Definitions of each variable, in printing order
+    def: HashMap<SymbolRef, Vec<Def>>,
+    /// Instructions using each variable, in printing order
+    users: HashMap<SymbolRef, Vec<InstRef>>,
+}
+
+/// Definition of a local variable
+#[derive(Clone)]
+enum Def {
+    Param,
+    BlockParam(BlockRef),
+    Inst(InstRef),
+}
+
+impl DefUseIndex {
+    fn new(func: &Fn) -> DefUseIndex {
+        let mut index = DefUseIndex {
+            func: func.name.clone(),
+            pos: Default::default(),
+            def: Default::default(),
+            users: Default::default(),
+        };
+        func.param.iter()
+            .for_each(|p| index.def.entry(p.borrow().clone()).or_default().push(Def::Param));
+        for b in func.rpo() {
+            if let Some(param) = func.blk_param.borrow().get(&b) {
+                param.iter().for_each(|p| {
+                    index.def.entry(p.borrow().clone()).or_default()
+                        .push(Def::BlockParam(b.clone()))
+                });
+            }
+            for (i, instr) in b.inst.borrow().iter().enumerate() {
+                index.pos.insert(instr.clone(), (b.clone(), i));
+                instr.dsts().iter().filter(|d| d.borrow().is_local_var()).for_each(|d| {
+                    index.def.entry(d.borrow().clone()).or_default()
+                        .push(Def::Inst(instr.clone()))
+                });
+                let mut opd = instr.src();
+                if let Inst::Phi { src, dst: _ } = instr.as_ref() {
+                    opd = src.iter().map(|(_, v)| v).collect();
+                }
+                for v in opd {
+                    if let Value::Var(sym) = v.borrow().deref() {
+                        if !sym.is_local_var() { continue; }
+                        let users = index.users.entry(sym.clone()).or_default();
+                        if users.last() != Some(instr) { users.push(instr.clone()) }
+                    }
+                }
+            }
+        }
+        index
+    }
+}
+
+/// Operand whose name may be numbered by the printer
+trait Operand {
+    fn fmt_with(&self, p: &Printer<'_>) -> String;
+}
+
+impl Operand for Value {
+    fn fmt_with(&self, p: &Printer<'_>) -> String { p.fmt_val(self) }
+}
+
+impl Operand for SymbolRef {
+    fn fmt_with(&self, p: &Printer<'_>) -> String { p.fmt_sym(self) }
+}
+
+macro_rules! fmt_val { ($p:ident, $v:ident) => {$v.borrow().fmt_with($p)}; }
 macro_rules! fmt_ty { ($v:ident) => {$v.borrow().get_type().to_string()}; }
 
 impl Printer<'_> {
     pub fn new(writer: &mut dyn Write) -> Printer {
-        Printer { writer }
+        Printer {
+            writer,
+            loc: false,
+            stats: false,
+            number: false,
+            temp: RefCell::new(None),
+            def_use: false,
+            index: RefCell::new(None),
+        }
+    }
+
+    /// Set whether source locations of blocks and instructions are printed as comments.
+    pub fn show_loc(mut self, show: bool) -> Self {
+        self.loc = show;
+        self
+    }
+
+    /// Set whether a table of program statistics is printed as comments after the program.
+    pub fn show_stats(mut self, show: bool) -> Self {
+        self.stats = show;
+        self
+    }
+
+    /// Set whether temporaries and temporary blocks, which are named by numbers, are numbered
+    /// again in order, so that the printed text does not depend on how they were created.
+    pub fn number_temps(mut self, number: bool) -> Self {
+        self.number = number;
+        self
+    }
+
+    /// Set whether each instruction is annotated with a comment listing where its operands are
+    /// defined and which instructions use its results, as `%Block[index]`. This helps tracing
+    /// SSA transforms, but the output is verbose.
+    pub fn show_def_use(mut self, show: bool) -> Self {
+        self.def_use = show;
+        self
     }
 
     pub fn print(&mut self, pro: &Program) -> Result<(), Error> {
@@ -36,6 +199,13 @@ impl Printer<'_> {
             self.print_fn(f.deref())?;
             writeln!(self.writer, "")?;
         }
+
+        // Print statistics
+        if self.stats {
+            for line in pro.stats().to_string().lines() {
+                writeln!(self.writer, "// {}", line)?;
+            }
+        }
         Ok(())
     }
 
@@ -56,11 +226,15 @@ impl Printer<'_> {
 
     fn print_global_var(&mut self, g: &GlobalVar) -> Result<(), Error> {
         let mut s = format!("@{}: {}", g.name, g.ty.to_string());
+        if g.is_const { s = "const ".to_string() + s.as_str() }
+        if g.linkage.is_visible() { s = g.linkage.to_string() + " " + s.as_str() }
         g.init.as_ref().map(|v| s += format!(" <- {}", v.to_string()).as_str());
-        writeln!(self.writer, "{};", s)
+        writeln!(self.writer, "{}", s)
     }
 
     pub fn print_fn(&mut self, func: &Fn) -> Result<(), Error> {
+        self.index.replace(None);
+
         // Print attributes
         if func.attrib.len() > 0 {
             let s: Vec<_> = func.attrib.iter().map(|v| v.to_string()).collect();
@@ -68,110 +242,279 @@ impl Printer<'_> {
         }
 
         // Print signature
+        writeln!(self.writer, "{} {{", self.fmt_sig(func))?;
+
+        // Print blocks
+        for ref b in func.rpo() {
+            self.print_block(func, b)?;
+        }
+
+        writeln!(self.writer, "{}", '}')?;
+        Ok(())
+    }
+
+    /// Format signature of function, including its metadata.
+    pub fn fmt_sig(&self, func: &Fn) -> String {
+        self.number_fn(func);
         let mut s = format!("fn @{}(", func.name);
+        let linkage = func.linkage.get();
+        if linkage.is_visible() { s = linkage.to_string() + " " + s.as_str() }
         let params: Vec<String> = func.param.iter().map(|s| {
-            format!("${}: {}", s.borrow().name(), s.borrow().get_type().to_string())
+            format!("{}: {}", self.fmt_sym(&s.borrow()), s.borrow().get_type().to_string())
         }).collect();
         s += &params.join(", ");
         s += ")";
         if let Type::Void = func.ret {} else {
             s += format!(" -> {}", func.ret.to_string()).as_str()
         }
-        s += " {";
-        writeln!(self.writer, "{}", s)?;
-
-        // Print blocks
-        for ref b in func.rpo() {
-            self.print_block(b)?;
-        }
-
-        writeln!(self.writer, "{}", '}')?;
-        Ok(())
+        s += &fmt_meta(&func.meta.borrow());
+        s
     }
 
-    fn print_block(&mut self, block: &BlockRef) -> Result<(), Error> {
-        writeln!(self.writer, "%{}:", block.name)?;
+    fn print_block(&mut self, func: &Fn, block: &BlockRef) -> Result<(), Error> {
+        let loc = self.fmt_loc(block.loc.borrow().clone());
+        let param = match func.blk_param.borrow().get(block) {
+            Some(param) => {
+                let param: Vec<_> = param.iter()
+                    .map(|p| format!("{}: {}", fmt_val!(self, p), fmt_ty!(p))).collect();
+                format!("({})", param.join(", "))
+            }
+            None => "".to_string()
+        };
+        writeln!(self.writer, "{}{}:{}{}", self.fmt_blk(block), param,
+                 fmt_meta(&block.meta.borrow()), loc)?;
         for instr in block.inst.borrow().iter() {
-            self.print_instr(instr)?;
+            writeln!(self.writer, "    {}", self.fmt_instr_in(func, Some(block), instr))?;
         }
         Ok(())
     }
 
-    fn print_instr(&mut self, instr: &InstRef) -> Result<(), Error> {
+    /// Format instruction of function, including its metadata and location if required.
+    pub fn fmt_instr(&self, func: &Fn, instr: &InstRef) -> String {
+        self.index.replace(None);
+        self.fmt_instr_in(func, None, instr)
+    }
+
+    /// Format instruction in `block`. If the function is in block argument form, arguments
+    /// passed to the targets of control flow instructions are also printed.
+    fn fmt_instr_in(&self, func: &Fn, block: Option<&BlockRef>, instr: &InstRef) -> String {
+        self.number_fn(func);
+        let tgt = |tgt: &RefCell<BlockRef>| {
+            let tgt = tgt.borrow();
+            match block.and_then(|b| func.blk_arg.borrow().get(&(b.clone(), tgt.clone()))
+                .map(|arg| self.fmt_opd_list(arg))) {
+                Some(arg) => format!("{}({})", self.fmt_blk(&tgt), arg),
+                None => self.fmt_blk(&tgt)
+            }
+        };
         let s = match instr.deref() {
             Inst::Mov { src, dst } =>
-                format!("{} <- mov {} {}", fmt_val!(dst), fmt_ty!(dst), fmt_val!(src)),
+                format!("{} <- mov {} {}", fmt_val!(self, dst), fmt_ty!(dst), fmt_val!(self, src)),
             Inst::Un { op, opd, dst } =>
-                format!("{} <- {} {} {}", fmt_val!(dst), op.to_string(), fmt_ty!(dst),
-                        fmt_val!(opd)),
+                format!("{} <- {} {} {}", fmt_val!(self, dst), op.to_string(), fmt_ty!(dst),
+                        fmt_val!(self, opd)),
             Inst::Bin { op, fst, snd, dst } => {
-                let opd_ty = if op.is_cmp() {
+                let opd_ty = if op.is_pred() {
                     fst.borrow().get_type()
                 } else {
                     dst.borrow().get_type()
                 };
-                format!("{} <- {} {} {}, {}", fmt_val!(dst), op.to_string(), opd_ty.to_string(),
-                        fmt_val!(fst), fmt_val!(snd))
+                format!("{} <- {} {} {}, {}", fmt_val!(self, dst), op.to_string(),
+                        opd_ty.to_string(), fmt_val!(self, fst), fmt_val!(self, snd))
             }
+            Inst::Cast { op, opd, dst } =>
+                format!("{} <- {} {} {} -> {}", fmt_val!(self, dst), op.to_string(),
+                        opd.borrow().get_type().to_string(), fmt_val!(self, opd), fmt_ty!(dst)),
             Inst::Call { func, arg, dst } => {
                 let ty = if let Type::Void = func.ret { "".to_string() } else {
                     func.ret.to_string() + " "
                 };
-                let mut s = format!("call {}@{}({})", ty, func.name, self.fmt_opd_list(arg));
-                dst.as_ref().map(|dst| s = format!("{} <- ", fmt_val!(dst)) + s.as_str());
-                s
+                let s = format!("call {}@{}({})", ty, func.name, self.fmt_opd_list(arg));
+                self.fmt_call_dst(dst, s)
+            }
+            Inst::CallInd { func_ptr, arg, dst } => {
+                let ret = func_ptr.borrow().get_type().fn_sig().unwrap().1;
+                let ty = if let Type::Void = ret { "".to_string() } else {
+                    ret.to_string() + " "
+                };
+                let s = format!("call {}{}({})", ty, fmt_val!(self, func_ptr),
+                                self.fmt_opd_list(arg));
+                self.fmt_call_dst(dst, s)
             }
             Inst::Phi { src, dst } =>
-                format!("{} <- phi {} {}", fmt_val!(dst), fmt_ty!(dst), self.fmt_phi_list(src)),
+                format!("{} <- phi {} {}", fmt_val!(self, dst), fmt_ty!(dst),
+                        self.fmt_phi_list(src)),
             Inst::Ret { val } => {
-                let mut s = "ret".to_string();
-                val.as_ref().map(|v| s += format!(" {}", fmt_val!(v)).as_str());
-                s
+                if val.is_empty() { "ret".to_string() } else {
+                    format!("ret {}", self.fmt_opd_list(val))
+                }
             }
-            Inst::Jmp { tgt } => format!("jmp %{}", tgt.borrow().name),
+            Inst::Unreachable => "unreachable".to_string(),
+            Inst::Abort { msg } => format!("abort \"{}\"", msg),
+            Inst::Jmp { tgt: t } => format!("jmp {}", tgt(t)),
             Inst::Br { cond, tr, fls } =>
-                format!("br {} ? %{} : %{}", fmt_val!(cond), tr.borrow().name, fls.borrow().name),
+                format!("br {} ? {} : {}", fmt_val!(self, cond), tgt(tr), tgt(fls)),
             Inst::Alloc { dst } => {
                 let dst_ty = dst.borrow().get_type();
-                format!("{} <- alloc {}", fmt_val!(dst), dst_ty.tgt_type().to_string())
+                format!("{} <- alloc {}", fmt_val!(self, dst), dst_ty.tgt_type().to_string())
             }
             Inst::New { dst, len } => {
                 let dst_ty = dst.borrow().get_type();
                 let len = match len {
-                    Some(len) => format!("[{}]", fmt_val!(len)),
+                    Some(len) => format!("[{}]", fmt_val!(self, len)),
                     None => "".to_string()
                 };
-                format!("{} <- new {}{}", fmt_val!(dst), len, dst_ty.tgt_type().to_string())
+                format!("{} <- new {}{}", fmt_val!(self, dst), len, dst_ty.tgt_type().to_string())
             }
             Inst::Ptr { base, off, ind, dst } => {
-                let mut s = format!("{} <- ptr {} {}", fmt_val!(dst), fmt_ty!(dst),
-                                    fmt_val!(base));
-                off.as_ref().map(|off| s += format!(", {}", fmt_val!(off)).as_str());
+                let mut s = format!("{} <- ptr {} {}", fmt_val!(self, dst), fmt_ty!(dst),
+                                    fmt_val!(self, base));
+                off.as_ref().map(|off| s += format!(", {}", fmt_val!(self, off)).as_str());
                 if !ind.is_empty() {
                     s += format!(" [{}]", self.fmt_opd_list(ind)).as_str()
                 }
                 s
             }
             Inst::Ld { ptr, dst } =>
-                format!("{} <- ld {} {}", fmt_val!(dst), fmt_ty!(dst), fmt_val!(ptr)),
+                format!("{} <- ld {} {}", fmt_val!(self, dst), fmt_ty!(dst), fmt_val!(self, ptr)),
             Inst::St { src, ptr } =>
-                format!("st {} {} -> {}", fmt_ty!(src), fmt_val!(src), fmt_val!(ptr))
+                format!("st {} {} -> {}", fmt_ty!(src), fmt_val!(self, src), fmt_val!(self, ptr)),
+            Inst::Memcpy { src, ptr, len } =>
+                format!("memcpy {} {} -> {}, {}", src.borrow().get_type().tgt_type().to_string(),
+                        fmt_val!(self, src), fmt_val!(self, ptr), fmt_val!(self, len)),
+            Inst::Memset { src, ptr, len } =>
+                format!("memset {} {} -> {}, {}", fmt_ty!(src), fmt_val!(self, src),
+                        fmt_val!(self, ptr), fmt_val!(self, len)),
+            Inst::AtomicLd { ord, ptr, dst } =>
+                format!("{} <- ld.atomic {} {} {}", fmt_val!(self, dst), ord.to_string(),
+                        fmt_ty!(dst), fmt_val!(self, ptr)),
+            Inst::AtomicSt { ord, src, ptr } =>
+                format!("st.atomic {} {} {} -> {}", ord.to_string(), fmt_ty!(src),
+                        fmt_val!(self, src), fmt_val!(self, ptr)),
+            Inst::AtomicRmw { op, ord, ptr, val, dst } =>
+                format!("{} <- {} {} {} {}, {}", fmt_val!(self, dst), op.to_string(),
+                        ord.to_string(), fmt_ty!(dst), fmt_val!(self, ptr), fmt_val!(self, val))
         };
 
-        writeln!(self.writer, "    {}", s)?;
-        Ok(())
+        let meta = func.inst_meta.borrow().get(instr).map(fmt_meta).unwrap_or_default();
+        let def_use = self.fmt_def_use(func, instr);
+        let loc = self.fmt_loc(func.inst_loc(instr));
+        format!("{}{}{}{}", s, meta, def_use, loc)
+    }
+
+    /// Format definitions of operands and users of results of `instr`, if required.
+    fn fmt_def_use(&self, func: &Fn, instr: &InstRef) -> String {
+        if !self.def_use { return "".to_string(); }
+        let cur = matches!(self.index.borrow().as_ref(), Some(i) if i.func == func.name);
+        if !cur { self.index.replace(Some(DefUseIndex::new(func))); }
+        let index = self.index.borrow();
+        let index = index.as_ref().unwrap();
+        let pos = |i: &InstRef| match index.pos.get(i) {
+            Some((b, n)) => format!("{}[{}]", self.fmt_blk(b), n),
+            None => "?".to_string()
+        };
+
+        // Definitions of local variables used, in order of first appearance
+        let mut opd: Vec<SymbolRef> = vec![];
+        let src = match instr.as_ref() {
+            Inst::Phi { src, dst: _ } => src.iter().map(|(_, v)| v).collect(),
+            _ => instr.src()
+        };
+        for v in src {
+            if let Value::Var(sym) = v.borrow().deref() {
+                if sym.is_local_var() && !opd.contains(sym) { opd.push(sym.clone()) }
+            }
+        }
+        let defs: Vec<String> = opd.iter().map(|sym| {
+            let def: Vec<String> = index.def.get(sym).into_iter().flatten().map(|d| match d {
+                Def::Param => "param".to_string(),
+                Def::BlockParam(b) => format!("{}(param)", self.fmt_blk(b)),
+                Def::Inst(i) => pos(i),
+            }).collect();
+            let def = if def.is_empty() { "?".to_string() } else { def.join(" | ") };
+            format!("{} = {}", self.fmt_sym(sym), def)
+        }).collect();
+
+        // Users of results, each listed once
+        let mut users: Vec<InstRef> = vec![];
+        for dst in instr.dsts() {
+            for u in index.users.get(dst.borrow().deref()).into_iter().flatten() {
+                if !users.contains(u) { users.push(u.clone()) }
+            }
+        }
+        let users: Vec<String> = users.iter().map(pos).collect();
+
+        let mut parts = vec![];
+        if !defs.is_empty() { parts.push(format!("defs: {}", defs.join(", "))) }
+        if instr.is_assign() { parts.push(format!("users: {}", users.join(", "))) }
+        if parts.is_empty() { "".to_string() } else { format!(" // {}", parts.join("; ")) }
+    }
+
+    fn fmt_loc(&self, loc: Option<Loc>) -> String {
+        match loc {
+            Some(loc) if self.loc => format!(" // {}", loc),
+            _ => "".to_string()
+        }
     }
 
     fn fmt_opd_list(&self, opd: &Vec<RefCell<Value>>) -> String {
-        let vec: Vec<String> = opd.iter().map(|v| v.borrow().to_string()).collect();
+        let vec: Vec<String> = opd.iter().map(|v| self.fmt_val(&v.borrow())).collect();
         vec.join(", ")
     }
 
+    /// Prepend destinations of call, if there is any.
+    fn fmt_call_dst(&self, dst: &[RefCell<SymbolRef>], call: String) -> String {
+        if dst.is_empty() { return call; }
+        let vec: Vec<String> = dst.iter().map(|d| self.fmt_sym(&d.borrow())).collect();
+        format!("{} <- {}", vec.join(", "), call)
+    }
+
     fn fmt_phi_list(&self, list: &Vec<PhiSrc>) -> String {
         let vec: Vec<String> = list.iter()
-            .map(|(b, v)| format!("[%{}: {}]", b.borrow().name, v.borrow().to_string())).collect();
+            .map(|(b, v)| format!("[{}: {}]", self.fmt_blk(&b.borrow()), fmt_val!(self, v)))
+            .collect();
         vec.join(" ")
     }
+
+    /// Number temporaries of `func`, if required and not numbered yet.
+    fn number_fn(&self, func: &Fn) {
+        if !self.number { return; }
+        let cur = matches!(self.temp.borrow().as_ref(), Some(n) if n.func == func.name);
+        if !cur { self.temp.replace(Some(Numbering::new(func))); }
+    }
+
+    fn fmt_sym(&self, sym: &SymbolRef) -> String {
+        match self.temp.borrow().as_ref().and_then(|n| n.sym.get(sym)) {
+            Some(n) => format!("${}", n),
+            None => sym.to_string()
+        }
+    }
+
+    fn fmt_val(&self, val: &Value) -> String {
+        match val {
+            Value::Var(sym) => self.fmt_sym(sym),
+            Value::Const(c) => c.to_string()
+        }
+    }
+
+    fn fmt_blk(&self, block: &BlockRef) -> String {
+        match self.temp.borrow().as_ref().and_then(|n| n.blk.get(block)) {
+            Some(n) => format!("%{}", n),
+            None => format!("%{}", block.name)
+        }
+    }
+}
+
+impl Inst {
+    /// Format this instruction in `func`, annotated with definitions of its operands and users
+    /// of its results. Returns `None` if the instruction is not in any reachable block of
+    /// `func`.
+    pub fn display_verbose(&self, func: &Fn) -> Option<String> {
+        let instr = func.rpo().flat_map(|b| b.inst.borrow().clone())
+            .find(|i| std::ptr::eq(i.as_ref(), self))?;
+        let mut sink = std::io::sink();
+        Some(Printer::new(&mut sink).show_def_use(true).fmt_instr(func, &instr))
+    }
 }
 
 #[test]
@@ -193,7 +536,136 @@ fn test_print() {
 
     // Print program
     let mut output = stdout();
-    let mut printer = Printer::new(&mut output);
+    let mut printer = Printer::new(&mut output).show_loc(true);
     printer.print(&pro).unwrap();
 }
 
+
+#[test]
+fn test_print_linkage() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+
+    let src = r#"
+export @a: i32 <- 1
+weak const @b: i64 <- 2
+internal @c: i8
+
+fn @f() {
+%Begin:
+    ret
+}
+
+export fn @g() -> i32 {
+%Begin:
+    ret @a
+}
+
+[noinline] weak fn @h() {
+%Begin:
+    ret
+}
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    for s in ["export @a: i32 <- 1", "weak const @b: i64 <- 2", "\n@c: i8", "\nfn @f()",
+        "export fn @g() -> i32", "[noinline]\nweak fn @h()"] {
+        assert!(out.contains(s), "{} not printed", s);
+    }
+
+    // Printed program should be parsed again
+    Builder::new(Parser::new(Lexer::from(out.as_str())).parse().unwrap()).build().unwrap();
+}
+
+#[test]
+fn test_number_temps() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+
+    let src = r#"
+@r: i64
+
+fn @main() {
+%Begin:
+    $9 <- call i64 @f(3)
+    @r <- mov i64 $9
+    ret
+}
+
+fn @f($5: i64) -> i64 {
+%7:
+    $c <- gt i64 $5, 0
+    br $c ? %12 : %4
+%12:
+    $3.1 <- sub i64 $5, 1
+    jmp %4
+%4:
+    $2 <- phi i64 [%7: $5] [%12: $3.1]
+    ret $2
+}
+"#;
+    let print = |src: &str| {
+        let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+        let mut out = vec![];
+        Printer::new(&mut out).number_temps(true).print(&pro).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let out = print(src);
+    println!("{}", out);
+    for s in ["$0 <- call i64 @f(3)", "fn @f($0: i64) -> i64", "%0:", "br $c ? %1 : %2",
+        "$1 <- sub i64 $0, 1", "$2 <- phi i64 [%0: $0] [%1: $1]", "ret $2"] {
+        assert!(out.contains(s), "{} not printed", s);
+    }
+
+    // Numbered program is printed the same after parsed again
+    assert_eq!(print(&out), out);
+}
+
+#[test]
+fn test_print_def_use() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+
+    let src = r#"
+fn @f($n: i64) -> i64 {
+%Begin:
+    $z <- eq i64 $n, 0
+    br $z ? %End : %Body
+%Body:
+    $a <- add i64 $n, $n
+    $b <- mul i64 $a, 2
+    jmp %End
+%End:
+    $r <- phi i64 [%Begin: 0] [%Body: $b]
+    ret $r
+}
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let mut out = vec![];
+    Printer::new(&mut out).show_def_use(true).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    for s in ["$z <- eq i64 $n, 0 // defs: $n = param; users: %Begin[1]",
+        "$a <- add i64 $n, $n // defs: $n = param; users: %Body[1]",
+        "$b <- mul i64 $a, 2 // defs: $a = %Body[0]; users: %End[0]",
+        "$r <- phi i64 [%Begin: 0] [%Body: $b] // defs: $b = %Body[1]; users: %End[1]",
+        "ret $r // defs: $r = %End[0]\n", "jmp %End\n"] {
+        assert!(out.contains(s), "{} not printed", s);
+    }
+
+    // Annotations are comments, so the program can be parsed again
+    Builder::new(Parser::new(Lexer::from(out.as_str())).parse().unwrap()).build().unwrap();
+
+    // Single instruction is formatted the same way
+    let func = &pro.func[0];
+    let body = func.rpo().find(|b| b.name == "Body").unwrap();
+    let instr = body.inst.borrow()[1].clone();
+    assert_eq!(instr.display_verbose(func).unwrap(),
+               "$b <- mul i64 $a, 2 // defs: $a = %Body[0]; users: %End[0]");
+}
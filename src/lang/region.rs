@@ -0,0 +1,225 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{Display, Error, Formatter};
+
+use crate::lang::func::{BlockRef, Fn};
+use crate::lang::inst::Inst;
+
+/// Structured control region of a function.
+/// Branches between blocks are expressed by nesting of regions, and by `Break` and `Continue`
+/// to enclosing `Labeled` and `Loop` regions, which can be directly translated to structured
+/// control flow of targets like WASM.
+#[derive(Clone, Debug)]
+pub enum Region {
+    /// Instructions of a basic block, excluding `jmp` and `br`
+    Block(BlockRef),
+    /// Regions executed in order
+    Seq(Vec<Region>),
+    /// Two-way branch on the condition of `br` at the end of block `cond`
+    If { cond: BlockRef, then: Box<Region>, els: Box<Region> },
+    /// Loop headed by block `header`, which is entered again by `Continue`
+    Loop { header: BlockRef, body: Box<Region> },
+    /// Region that is exited by `Break`, after which control reaches block `follow`
+    Labeled { follow: BlockRef, body: Box<Region> },
+    /// Exit from the enclosing `Labeled` region followed by this block
+    Break(BlockRef),
+    /// Jump back to the header of the enclosing `Loop` headed by this block
+    Continue(BlockRef),
+}
+
+impl Fn {
+    /// Reconstruct structured control regions from the CFG. The dominator tree should be built.
+    /// Returns `None` if the CFG is irreducible, which cannot be structured without
+    /// duplicating blocks.
+    pub fn structure(&self) -> Option<Region> {
+        let rpo: HashMap<BlockRef, usize> = self.rpo().enumerate().map(|(i, b)| (b, i)).collect();
+        // Every back edge should go to a block dominating its source
+        let reducible = rpo.iter().all(|(b, i)| {
+            b.succ.borrow().iter().all(|s| rpo[s] > *i || s.dominates(b))
+        });
+        if !reducible { return None; }
+        Some(Structurer { rpo }.tree(&self.ent.borrow()))
+    }
+}
+
+/// Structuring of reducible CFG, following "Beyond Relooper" by Norman Ramsey.
+struct Structurer {
+    /// Reverse post-order number of blocks
+    rpo: HashMap<BlockRef, usize>,
+}
+
+impl Structurer {
+    /// Number of forward edges into `block`.
+    fn fwd_pred(&self, block: &BlockRef) -> usize {
+        block.pred().iter().filter(|p| self.rpo.get(*p).is_some_and(|i| *i < self.rpo[block]))
+            .count()
+    }
+
+    /// A merge block is reached by several forward edges.
+    fn is_merge(&self, block: &BlockRef) -> bool { self.fwd_pred(block) > 1 }
+
+    /// A loop header is the target of a back edge.
+    fn is_header(&self, block: &BlockRef) -> bool {
+        block.pred().iter().any(|p| self.rpo.get(p).is_some_and(|i| *i >= self.rpo[block]))
+    }
+
+    /// Structure the subtree of dominator tree rooted at `block`.
+    fn tree(&self, block: &BlockRef) -> Region {
+        // Merge blocks dominated by this block follow it, the last one in the outermost place
+        let mut merge: Vec<_> = block.children().into_iter().filter(|c| self.is_merge(c))
+            .collect();
+        merge.sort_by_key(|b| Reverse(self.rpo[b]));
+        let body = self.within(block, &merge);
+        if self.is_header(block) {
+            Region::Loop { header: block.clone(), body: Box::new(body) }
+        } else { body }
+    }
+
+    /// Structure `block` followed by `merge` blocks it dominates.
+    fn within(&self, block: &BlockRef, merge: &[BlockRef]) -> Region {
+        match merge.split_first() {
+            Some((follow, rest)) => seq(vec![
+                Region::Labeled {
+                    follow: follow.clone(),
+                    body: Box::new(self.within(block, rest)),
+                },
+                self.tree(follow),
+            ]),
+            None => {
+                let mut list = vec![Region::Block(block.clone())];
+                match block.tail().as_ref() {
+                    Inst::Jmp { tgt } => list.push(self.branch(block, &tgt.borrow())),
+                    Inst::Br { cond: _, tr, fls } => list.push(Region::If {
+                        cond: block.clone(),
+                        then: Box::new(self.branch(block, &tr.borrow())),
+                        els: Box::new(self.branch(block, &fls.borrow())),
+                    }),
+                    _ => {}
+                }
+                seq(list)
+            }
+        }
+    }
+
+    /// Structure the branch from `block` to `tgt`.
+    fn branch(&self, block: &BlockRef, tgt: &BlockRef) -> Region {
+        if self.rpo[tgt] <= self.rpo[block] {
+            Region::Continue(tgt.clone())
+        } else if self.is_merge(tgt) {
+            Region::Break(tgt.clone())
+        } else {
+            self.tree(tgt)
+        }
+    }
+}
+
+/// Create sequence of regions, with nested sequences flattened.
+fn seq(list: Vec<Region>) -> Region {
+    let mut flat = vec![];
+    for region in list {
+        match region {
+            Region::Seq(inner) => flat.extend(inner),
+            region => flat.push(region)
+        }
+    }
+    if flat.len() == 1 { flat.pop().unwrap() } else { Region::Seq(flat) }
+}
+
+impl Region {
+    fn fmt_indent(&self, f: &mut Formatter<'_>, indent: usize) -> Result<(), Error> {
+        let pad = "    ".repeat(indent);
+        match self {
+            Region::Block(b) => writeln!(f, "{}%{}", pad, b.name),
+            Region::Seq(list) => list.iter().try_for_each(|r| r.fmt_indent(f, indent)),
+            Region::If { cond, then, els } => {
+                writeln!(f, "{}if %{} {{", pad, cond.name)?;
+                then.fmt_indent(f, indent + 1)?;
+                writeln!(f, "{}}} else {{", pad)?;
+                els.fmt_indent(f, indent + 1)?;
+                writeln!(f, "{}}}", pad)
+            }
+            Region::Loop { header, body } => {
+                writeln!(f, "{}loop %{} {{", pad, header.name)?;
+                body.fmt_indent(f, indent + 1)?;
+                writeln!(f, "{}}}", pad)
+            }
+            Region::Labeled { follow, body } => {
+                writeln!(f, "{}block %{} {{", pad, follow.name)?;
+                body.fmt_indent(f, indent + 1)?;
+                writeln!(f, "{}}}", pad)
+            }
+            Region::Break(b) => writeln!(f, "{}break %{}", pad, b.name),
+            Region::Continue(b) => writeln!(f, "{}continue %{}", pad, b.name),
+        }
+    }
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> { self.fmt_indent(f, 0) }
+}
+
+#[test]
+fn test_region() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+
+    let src = r#"
+fn @f($n: i64) -> i64 {
+%Begin:
+    jmp %Cond
+%Cond:
+    $i.1 <- phi i64 [%Begin: 0] [%Next: $i.2]
+    $c <- lt i64 $i.1, $n
+    br $c ? %Body : %End
+%Body:
+    $d <- lt i64 $i.1, 5
+    br $d ? %Then : %Else
+%Then:
+    jmp %Next
+%Else:
+    jmp %Next
+%Next:
+    $i.2 <- add i64 $i.1, 1
+    jmp %Cond
+%End:
+    ret $i.1
+}
+
+fn @g($c: i1) {
+%Begin:
+    br $c ? %A : %B
+%A:
+    jmp %B
+%B:
+    jmp %A
+}
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let region = pro.func[0].structure().unwrap().to_string();
+    println!("{}", region);
+    assert_eq!(region, r#"%Begin
+loop %Cond {
+    %Cond
+    if %Cond {
+        block %Next {
+            %Body
+            if %Body {
+                %Then
+                break %Next
+            } else {
+                %Else
+                break %Next
+            }
+        }
+        %Next
+        continue %Cond
+    } else {
+        %End
+    }
+}
+"#);
+
+    // Irreducible CFG cannot be structured
+    assert!(pro.func[1].structure().is_none());
+}
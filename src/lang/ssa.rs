@@ -1,13 +1,14 @@
-use std::cell::{Cell, RefCell};
+use std::cell::{Cell, Ref, RefCell};
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::ops::Deref;
 use std::rc::Rc;
 
-use crate::lang::func::{BlockRef, DomTreeListener, Fn};
+use crate::lang::func::{BlockRef, DomTreeListener, Fn, FnRef};
 use crate::lang::inst::{Inst, InstRef, PhiSrc};
-use crate::lang::util::{ExtRc, WorkList};
-use crate::lang::value::{Scope, Symbol, SymbolRef, Typed, Value};
+use crate::lang::util::{ExtRc, MutRc, WorkList};
+use crate::lang::value::{Scope, Symbol, SymbolGen, SymbolRef, Typed, Value};
+use crate::lang::verify::{TypeChecker, VerifyErr};
 
 /// Wrapper of SSA flag to make it only modifiable in this module.
 #[derive(Debug)]
@@ -18,7 +19,7 @@ impl SsaFlag {
 
     pub fn get(&self) -> bool { self.0.get() }
 
-    fn set(&self, val: bool) { self.0.set(val) }
+    pub(crate) fn set(&self, val: bool) { self.0.set(val) }
 }
 
 impl Fn {
@@ -35,8 +36,8 @@ pub trait InstListener: DomTreeListener {
     fn on_begin(&mut self, _func: &Fn) {}
 
     fn on_enter(&mut self, block: BlockRef) {
-        // Visit instructions
-        for instr in block.inst.borrow().iter().cloned() {
+        // Visit instructions. The block is not borrowed when visiting, so it can be modified.
+        for instr in block.cursor() {
             self.on_instr(instr);
         }
 
@@ -70,7 +71,7 @@ pub trait ValueListener: InstListener {
                 for opd in instr.src() {
                     self.on_use(instr.clone(), opd);
                 }
-                if let Some(dst) = instr.dst() {
+                for dst in instr.dsts() {
                     self.on_def(instr.clone(), dst);
                 }
             }
@@ -91,18 +92,26 @@ pub trait ValueListener: InstListener {
     fn on_def(&mut self, instr: InstRef, def: &RefCell<SymbolRef>);
 }
 
+/// Verifier of SSA properties: phi operands correspond to predecessors, and every variable is
+/// defined once, before all of its uses.
 pub struct Verifier {
     // Whether a variable is found to be statically defined.
     def: HashSet<SymbolRef>,
     // Whether variables are available when reaching this block.
     // Organized as stack of frames, representing nodes on the path from root to current block
     avail: Vec<Vec<SymbolRef>>,
+    // Name of the function being verified
+    func: String,
+    // Block being visited
+    block: Option<BlockRef>,
     // Error information
-    pub err: Vec<String>,
+    pub err: Vec<VerifyErr>,
 }
 
 impl DomTreeListener for Verifier {
     fn on_begin(&mut self, func: &Fn) {
+        self.func = func.name.clone();
+
         // Add parameters as the first frame
         func.param.iter().for_each(|p| { self.def.insert(p.borrow().clone()); });
         self.avail.push(func.param.iter().map(|p| p.borrow().clone()).collect());
@@ -112,6 +121,7 @@ impl DomTreeListener for Verifier {
     }
 
     fn on_end(&mut self, func: &Fn) {
+        self.err.iter_mut().filter(|e| e.func == func.name).for_each(|e| e.locate(func));
         func.ssa.set(true);
         self.def.clear();
         self.avail.clear();
@@ -120,22 +130,15 @@ impl DomTreeListener for Verifier {
     fn on_enter(&mut self, block: BlockRef) {
         // Push current frame to stack
         self.avail.push(vec![]);
+        self.block = Some(block.clone());
 
-        // Build predecessor list
-        let req_pred: Vec<_> = block.pred.borrow().clone().into_iter()
-            .map(|b| RefCell::new(b)).collect();
-
-        // Check correspondence of phi operands to predecessors
+        // Check that phi operands correspond to predecessors one-to-one, so that none is
+        // missing, dangling or duplicate
         for instr in block.inst.borrow().iter() {
             match instr.deref() {
                 Inst::Phi { src, dst: _ } => {
-                    let phi_pred: Vec<_> = src.clone().into_iter().map(|(pred, _)| pred).collect();
-                    for pred in &req_pred {
-                        if !phi_pred.contains(pred) {
-                            self.err.push(format!(
-                                "phi operand not found for {}", pred.borrow().name
-                            ));
-                        }
+                    for msg in TypeChecker::check_phi(&block, src) {
+                        self.error(Some(instr), instr.dst().map(|d| d.borrow().clone()), msg);
                     }
                 }
                 _ => break
@@ -165,22 +168,22 @@ impl InstListener for Verifier {
 }
 
 impl ValueListener for Verifier {
-    fn on_use(&mut self, _: InstRef, opd: &RefCell<Value>) {
+    fn on_use(&mut self, instr: InstRef, opd: &RefCell<Value>) {
         match opd.borrow().deref() {
             Value::Var(sym) if sym.is_local_var() && !self.is_avail(sym) => {
-                self.err.push(format!(
-                    "variable {} is used before defined", sym.name()
-                ));
+                self.error(Some(&instr), Some(sym.clone()),
+                           format!("variable {} is used before defined", sym.name()));
             }
             _ => ()
         }
     }
 
-    fn on_def(&mut self, _: InstRef, def: &RefCell<SymbolRef>) {
+    fn on_def(&mut self, instr: InstRef, def: &RefCell<SymbolRef>) {
         if def.borrow().is_local_var() {
             let sym = def.borrow().clone();
             if self.def.contains(&sym) { // already statically defined
-                self.err.push(format!("variable {} already defined", sym.name()));
+                self.error(Some(&instr), Some(sym.clone()),
+                           format!("variable {} already defined", sym.name()));
             } else {
                 self.def.insert(sym.clone()); // mark this static definition
                 // add to current frame of availability stack
@@ -195,6 +198,8 @@ impl Verifier {
         Verifier {
             def: HashSet::new(),
             avail: vec![],
+            func: "".to_string(),
+            block: None,
             err: vec![],
         }
     }
@@ -202,6 +207,17 @@ impl Verifier {
     fn is_avail(&self, sym: &SymbolRef) -> bool {
         self.avail.iter().any(|frame| frame.contains(sym))
     }
+
+    fn error(&mut self, instr: Option<&InstRef>, sym: Option<SymbolRef>, msg: String) {
+        // The instruction is either in current block or a phi in one of its successors.
+        let cur = self.block.clone().unwrap();
+        let block = match instr {
+            Some(instr) if !cur.inst.borrow().contains(instr) => cur.succ.borrow().iter()
+                .find(|b| b.inst.borrow().contains(instr)).cloned().unwrap_or(cur.clone()),
+            _ => cur.clone()
+        };
+        self.err.push(VerifyErr::new(&self.func, &block, instr, sym.as_ref(), msg))
+    }
 }
 
 impl Fn {
@@ -210,8 +226,8 @@ impl Fn {
         let df = self.compute_df();
         self.insert_phi(&df);
         self.rename();
-        self.elim_dead_code();
         self.ssa.set(true);
+        self.elim_dead_code();
     }
 
     fn insert_phi(&self, df: &HashMap<BlockRef, Vec<BlockRef>>) {
@@ -243,7 +259,7 @@ impl Fn {
                 for tgt in df.get(&block).unwrap() {
                     // Insert phi instruction for this symbol
                     if ins_phi.get(tgt).unwrap().contains(&sym) { continue; }
-                    let src: Vec<PhiSrc> = tgt.pred.borrow().clone().into_iter().map(|pred| {
+                    let src: Vec<PhiSrc> = tgt.pred().into_iter().map(|pred| {
                         (RefCell::new(pred), RefCell::new(Value::Var(sym.clone())))
                     }).collect();
                     tgt.push_front(ExtRc::new(Inst::Phi {
@@ -273,7 +289,7 @@ impl Fn {
     fn defined_sym(&self, block: &BlockRef) -> HashSet<SymbolRef> {
         let mut def: HashSet<SymbolRef> = HashSet::new();
         for instr in block.inst.borrow().iter() {
-            for sym in instr.dst() {
+            for sym in instr.dsts() {
                 match sym.borrow().as_ref() {
                     Symbol::Local { name: _, ty: _ } => {
                         def.insert(sym.borrow().clone());
@@ -286,6 +302,152 @@ impl Fn {
     }
 }
 
+impl Fn {
+    /// Restore SSA form of this function after CFG edits, such as block duplication or
+    /// insertion of new definitions, which may leave symbols in `sym` defined more than once or
+    /// used where their definitions do not dominate. Only these symbols are repaired: phis are
+    /// inserted at the iterated dominance frontiers of their definitions, and all of their
+    /// definitions are renamed. Symbols already conforming to SSA form are not touched, so the
+    /// repair is idempotent. Inserted phis that turn out to be useless are removed. The dominator
+    /// tree is rebuilt before repairing.
+    pub fn repair_ssa(&self, sym: &[SymbolRef]) {
+        self.assert_ssa();
+        self.build_dom();
+        let sym: HashSet<SymbolRef> = sym.iter().filter(|s| s.is_local_var())
+            .filter(|s| !self.conforms_ssa(s)).cloned().collect();
+        if sym.is_empty() { return; }
+
+        // Insert phis at iterated dominance frontiers of definitions
+        let df = self.compute_df();
+        let mut inserted: Vec<(BlockRef, InstRef)> = vec![];
+        for s in &sym {
+            let mut def: HashSet<BlockRef> = self.dfs().filter(|b| {
+                b.inst.borrow().iter().any(|i| i.dsts().iter().any(|d| *d.borrow() == *s))
+            }).collect();
+            if self.param.iter().any(|p| *p.borrow() == *s) {
+                def.insert(self.ent.borrow().clone());
+            }
+            let mut work: WorkList<BlockRef> = def.iter().cloned().collect();
+            let mut has_phi = HashSet::new();
+            while let Some(block) = work.pick() {
+                for tgt in &df[&block] {
+                    if !has_phi.insert(tgt.clone()) { continue; }
+                    let exists = tgt.inst.borrow().iter().take_while(|i| i.is_phi())
+                        .any(|i| i.dsts().iter().any(|d| *d.borrow() == *s));
+                    if exists { continue; }
+                    let src: Vec<PhiSrc> = tgt.pred().into_iter().map(|pred| {
+                        (RefCell::new(pred), RefCell::new(Value::Var(s.clone())))
+                    }).collect();
+                    let phi = ExtRc::new(Inst::Phi { src, dst: RefCell::new(s.clone()) });
+                    tgt.push_front(phi.clone());
+                    inserted.push((tgt.clone(), phi));
+                    if def.insert(tgt.clone()) { work.insert(tgt.clone()); }
+                }
+            }
+        }
+
+        // Rename definitions and uses of the symbols along the dominator tree. Uses reached by
+        // no definition refer to the original symbols, as in `to_ssa`.
+        let mut stack: HashMap<SymbolRef, Vec<SymbolRef>> = sym.iter()
+            .map(|s| (s.clone(), vec![])).collect();
+        for p in &self.param {
+            let p = p.borrow().clone();
+            if let Some(st) = stack.get_mut(&p) { st.push(p); }
+        }
+        let mut gen = SymbolGen::new(self.scope.clone(), "");
+        self.repair_block(&self.ent.borrow(), &mut stack, &mut gen);
+
+        // Remove inserted phis whose results are not used
+        loop {
+            let mut used: HashSet<SymbolRef> = HashSet::new();
+            self.dfs().for_each(|b| b.inst.borrow().iter().for_each(|i| {
+                let dst: Vec<_> = i.dsts().iter().map(|d| d.borrow().clone()).collect();
+                i.src().iter().for_each(|v| if let Value::Var(s) = v.borrow().deref() {
+                    if !dst.contains(s) { used.insert(s.clone()); }
+                })
+            }));
+            let len = inserted.len();
+            inserted.retain(|(block, phi)| {
+                let live = used.contains(&phi.dst().unwrap().borrow());
+                if !live { block.retain(|i| i != phi); }
+                live
+            });
+            if inserted.len() == len { break; }
+        }
+    }
+
+    fn repair_block(&self, block: &BlockRef, stack: &mut HashMap<SymbolRef, Vec<SymbolRef>>,
+                    gen: &mut SymbolGen) {
+        let latest = |stack: &HashMap<SymbolRef, Vec<SymbolRef>>, opd: &RefCell<Value>| {
+            let new = match opd.borrow().deref() {
+                Value::Var(s) if stack.contains_key(s) =>
+                    Value::Var(stack[s].last().unwrap_or(s).clone()),
+                v => v.clone()
+            };
+            opd.replace(new);
+        };
+        let mut pushed = vec![];
+        for instr in block.inst.borrow().iter() {
+            if !instr.is_phi() { instr.src().into_iter().for_each(|v| latest(stack, v)); }
+            for dst in instr.dsts() {
+                let s = dst.borrow().clone();
+                if let Some(st) = stack.get_mut(&s) {
+                    let new = gen.rename(&s);
+                    st.push(new.clone());
+                    dst.replace(new);
+                    pushed.push(s);
+                }
+            }
+        }
+        for succ in block.succ.borrow().iter() {
+            for phi in succ.inst.borrow().iter().take_while(|i| i.is_phi()) {
+                if let Inst::Phi { src, dst: _ } = phi.as_ref() {
+                    src.iter().filter(|(b, _)| *b.borrow() == *block)
+                        .for_each(|(_, v)| latest(stack, v));
+                }
+            }
+        }
+        for child in block.children() {
+            self.repair_block(&child, stack, gen);
+        }
+        pushed.iter().for_each(|s| { stack.get_mut(s).unwrap().pop(); });
+    }
+
+    /// Whether `sym` has at most one definition, which dominates all of its uses.
+    fn conforms_ssa(&self, sym: &SymbolRef) -> bool {
+        // Find the only definition
+        let mut def = None;
+        let mut n_def = 0;
+        if self.param.iter().any(|p| *p.borrow() == *sym) {
+            n_def += 1;
+            def = Some((self.ent.borrow().clone(), None));
+        }
+        for block in self.dfs() {
+            for (i, instr) in block.inst.borrow().iter().enumerate() {
+                if instr.dsts().iter().any(|d| *d.borrow() == *sym) {
+                    n_def += 1;
+                    def = Some((block.clone(), Some(i)));
+                }
+            }
+        }
+        if n_def > 1 { return false; }
+
+        // Check every use against the definition
+        let is_sym = |v: &RefCell<Value>| matches!(v.borrow().deref(), Value::Var(s) if s == sym);
+        let avail = |block: &BlockRef, pos: Option<usize>| match &def {
+            Some((d, i)) => d.strict_dom(block) || (d == block && *i < pos),
+            None => false
+        };
+        self.dfs().all(|block| block.inst.borrow().iter().enumerate().all(|(i, instr)| {
+            match instr.as_ref() {
+                Inst::Phi { src, dst: _ } => src.iter().filter(|(_, v)| is_sym(v))
+                    .all(|(b, _)| avail(&b.borrow(), Some(usize::MAX))),
+                _ => !instr.src().into_iter().any(is_sym) || avail(&block, Some(i))
+            }
+        }))
+    }
+}
+
 struct RenamedSym {
     /// Original name of this symbol
     name: String,
@@ -499,10 +661,9 @@ impl ValueListener for DefUseBuilder {
     fn on_def(&mut self, instr: InstRef, def: &RefCell<SymbolRef>) {
         let def = def.borrow().clone();
         if def.is_local_var() {
-            self.info.insert(def.clone(), DefUse {
-                def: DefPos::Inst(self.blk.last().unwrap().clone(), instr),
-                uses: vec![],
-            });
+            // Keep uses found before the definition
+            let pos = DefPos::Inst(self.blk.last().unwrap().clone(), instr);
+            self.info.entry(def).or_insert(DefUse { def: DefPos::None, uses: vec![] }).def = pos;
         }
     }
 }
@@ -516,10 +677,8 @@ impl Fn {
         self.param.iter().for_each(|p| sym.push(p.borrow().clone()));
         self.dfs().for_each(|block| {
             block.inst.borrow().iter().for_each(|instr| {
-                match instr.dst() {
-                    Some(dst) if dst.borrow().is_local_var() => sym.push(dst.borrow().clone()),
-                    _ => {}
-                }
+                instr.dsts().into_iter().filter(|dst| dst.borrow().is_local_var())
+                    .for_each(|dst| sym.push(dst.borrow().clone()))
             })
         });
         self.scope.append(sym.into_iter());
@@ -540,9 +699,9 @@ impl Fn {
         listener.info
     }
 
-    /// Dead code elimination
+    /// Dead code elimination, which returns whether any instruction is removed.
     /// This is placed here, not in `pass` module, because SSA transformation need this procedure.
-    pub fn elim_dead_code(&self) {
+    pub fn elim_dead_code(&self) -> bool {
         // DCE should be performed on SSA form
         self.assert_ssa();
 
@@ -551,7 +710,9 @@ impl Fn {
 
         // Use work list algorithm to create target set
         let mut marked = HashSet::new();
-        let mut work: WorkList<SymbolRef> = WorkList::from_iter(def_use.keys().cloned());
+        let mut sym: Vec<SymbolRef> = def_use.keys().cloned().collect();
+        sym.sort_by_key(|s| s.name().to_string());
+        let mut work: WorkList<SymbolRef> = WorkList::from_iter(sym);
 
         while !work.is_empty() {
             // Search for instruction that can be removed
@@ -577,8 +738,10 @@ impl Fn {
                         _ => {}
                     }
                 }
-                // For any other instruction, remove if it has no uses and it has no side effects.
-                DefPos::Inst(_, instr) if def_use[sym].uses.is_empty()
+                // For any other instruction, remove if none of its destinations has uses and it
+                // has no side effects.
+                DefPos::Inst(_, instr) if instr.dsts().iter().all(|d| def_use.get(&d.borrow())
+                    .is_none_or(|du| du.uses.is_empty()))
                     && !instr.has_side_effect() => remove.push(instr),
                 _ => {}
             }
@@ -605,13 +768,192 @@ impl Fn {
 
         // Remove instruction if it is not marked before
         self.iter_dom().for_each(|block| {
-            block.inst.borrow_mut().retain(|instr| {
-                if marked.contains(instr) {
-                    instr.dst().map(|dst| self.scope.remove(&dst.borrow().name()));
-                    false
-                } else { true }
-            })
-        })
+            let mut cursor = block.cursor();
+            while let Some(instr) = cursor.next() {
+                if !marked.contains(&instr) { continue; }
+                instr.dsts().iter().for_each(|dst| { self.scope.remove(&dst.borrow().name()); });
+                cursor.erase();
+            }
+        });
+        !marked.is_empty()
+    }
+}
+
+impl Fn {
+    /// Replace every use of `old` in this function with `new`, including phi operands and
+    /// arguments passed along edges in block argument form. Returns the number of replaced
+    /// operands. Definition of `old` is kept, and can be removed by DCE if it is no longer used.
+    pub fn replace_all_uses(&self, old: &SymbolRef, new: Value) -> usize {
+        self.replace_uses_with(old, new, None)
+    }
+
+    /// Replace uses like `replace_all_uses`, and also report the rewritten instructions to
+    /// `def_use`.
+    pub fn tracked_replace_all_uses(&self, old: &SymbolRef, new: Value, def_use: &DefUseGraph)
+        -> usize
+    {
+        self.replace_uses_with(old, new, Some(def_use))
+    }
+
+    fn replace_uses_with(&self, old: &SymbolRef, new: Value, def_use: Option<&DefUseGraph>)
+        -> usize
+    {
+        let is_old = |opd: &RefCell<Value>| match opd.borrow().deref() {
+            Value::Var(sym) => sym == old,
+            _ => false
+        };
+        let mut count = 0;
+        for block in self.dfs() {
+            for instr in block.inst.borrow().iter() {
+                let opd: Vec<_> = instr.src().into_iter().filter(|o| is_old(o)).collect();
+                if opd.is_empty() { continue; }
+                if let Some(du) = def_use { du.remove(instr) }
+                opd.iter().for_each(|o| { o.replace(new.clone()); });
+                if let Some(du) = def_use { du.add(&block, instr) }
+                count += opd.len();
+            }
+        }
+        for arg in self.blk_arg.borrow().values().flatten().filter(|a| is_old(a)) {
+            arg.replace(new.clone());
+            count += 1;
+        }
+        if let Some(du) = def_use { du.check() }
+        count
+    }
+}
+
+/// Def-use information of a function which is kept up to date as instructions are inserted and
+/// erased, so that it does not need to be rebuilt for each query.
+/// The graph is a shared handle. Cursors created by `BlockRef::tracked_cursor` update it on each
+/// edit, and other edits should be reported with `insert` and `erase`. Phi operands are counted
+/// as uses regardless of whether their predecessors are reachable.
+#[derive(Clone)]
+pub struct DefUseGraph(MutRc<DefUseState>);
+
+struct DefUseState {
+    map: DefUseMap,
+    /// Function checked against after each update, in validation mode
+    check: Option<FnRef>,
+}
+
+impl DefUseGraph {
+    /// Build def-use graph of `func`, which should be in SSA form.
+    pub fn new(func: &Fn) -> DefUseGraph {
+        DefUseGraph(MutRc::new(DefUseState { map: func.def_use(), check: None }))
+    }
+
+    /// Enable or disable validation mode. In this mode, the graph is compared against a full
+    /// rebuild of `func` after each update, and any difference causes a panic.
+    pub fn set_check(&self, func: Option<FnRef>) { self.0.borrow_mut().check = func; }
+
+    /// Get definition position of `sym`, or `DefPos::None` if it is not defined.
+    pub fn def(&self, sym: &SymbolRef) -> DefPos {
+        self.0.borrow().map.get(sym).map(|du| du.def.clone()).unwrap_or(DefPos::None)
+    }
+
+    /// Get instructions using `sym`.
+    pub fn uses(&self, sym: &SymbolRef) -> Vec<InstRef> {
+        self.0.borrow().map.get(sym).map(|du| du.uses.clone()).unwrap_or_default()
+    }
+
+    /// Get the underlying def-use map.
+    pub fn map(&self) -> Ref<'_, DefUseMap> { Ref::map(self.0.borrow(), |s| &s.map) }
+
+    /// Record that `instr` is inserted into `block`.
+    pub fn insert(&self, block: &BlockRef, instr: &InstRef) {
+        self.add(block, instr);
+        self.check()
+    }
+
+    /// Record that `instr` is removed from its block.
+    pub fn erase(&self, instr: &InstRef) {
+        self.remove(instr);
+        self.check()
+    }
+
+    /// Record that `old` is replaced by `new` in `block`.
+    pub fn replace(&self, block: &BlockRef, old: &InstRef, new: &InstRef) {
+        self.remove(old);
+        self.add(block, new);
+        self.check()
+    }
+
+    fn add(&self, block: &BlockRef, instr: &InstRef) {
+        let map = &mut self.0.borrow_mut().map;
+        for sym in Self::local_opd(instr) {
+            map.entry(sym).or_insert(DefUse { def: DefPos::None, uses: vec![] })
+                .uses.push(instr.clone())
+        }
+        for dst in instr.dsts().into_iter().map(|d| d.borrow().clone()) {
+            if dst.is_local_var() {
+                map.entry(dst).or_insert(DefUse { def: DefPos::None, uses: vec![] }).def =
+                    DefPos::Inst(block.clone(), instr.clone());
+            }
+        }
+    }
+
+    fn remove(&self, instr: &InstRef) {
+        let map = &mut self.0.borrow_mut().map;
+        for sym in Self::local_opd(instr) {
+            let uses = &mut map.get_mut(&sym).unwrap().uses;
+            let pos = uses.iter().position(|u| u == instr).unwrap();
+            uses.remove(pos);
+            if uses.is_empty() && matches!(map[&sym].def, DefPos::None) { map.remove(&sym); }
+        }
+        // Uses of the destination stay, and are valid again once it is redefined
+        for dst in instr.dsts().into_iter().map(|d| d.borrow().clone()) {
+            match map.get_mut(&dst) {
+                Some(du) if du.uses.is_empty() => { map.remove(&dst); }
+                Some(du) => du.def = DefPos::None,
+                None => {}
+            }
+        }
+    }
+
+    /// Compare this graph against a full rebuild of `func`. Order of uses is not significant.
+    pub fn validate(&self, func: &Fn) -> Result<(), String> {
+        let full = func.def_use();
+        let map = &self.0.borrow().map;
+        let count = |uses: &Vec<InstRef>| {
+            let mut count: HashMap<InstRef, usize> = HashMap::new();
+            uses.iter().for_each(|u| *count.entry(u.clone()).or_default() += 1);
+            count
+        };
+        for (sym, du) in full.iter() {
+            let inc = map.get(sym).ok_or(format!("symbol {} not found", sym.name()))?;
+            let same_def = match (&du.def, &inc.def) {
+                (DefPos::Param, DefPos::Param) | (DefPos::None, DefPos::None) => true,
+                (DefPos::Inst(b1, i1), DefPos::Inst(b2, i2)) => b1 == b2 && i1 == i2,
+                _ => false
+            };
+            if !same_def {
+                return Err(format!("definition of {} differs: expect {:?}, found {:?}",
+                                   sym.name(), du.def, inc.def));
+            }
+            if count(&du.uses) != count(&inc.uses) {
+                return Err(format!("uses of {} differ", sym.name()));
+            }
+        }
+        match map.keys().find(|sym| !full.contains_key(sym)) {
+            Some(sym) => Err(format!("symbol {} should not be present", sym.name())),
+            None => Ok(())
+        }
+    }
+
+    /// Validate this graph if validation mode is enabled.
+    fn check(&self) {
+        let func = self.0.borrow().check.clone();
+        if let Some(func) = func {
+            if let Err(e) = self.validate(&func) { panic!("invalid def-use graph: {}", e) }
+        }
+    }
+
+    /// Local variables used by `instr`
+    fn local_opd(instr: &InstRef) -> Vec<SymbolRef> {
+        instr.src().iter().filter_map(|opd| match opd.borrow().deref() {
+            Value::Var(sym) if sym.is_local_var() => Some(sym.clone()),
+            _ => None
+        }).collect()
     }
 }
 
@@ -640,3 +982,244 @@ fn test_ssa() {
     let mut printer = Printer::new(out.borrow_mut());
     printer.print(&pro).unwrap();
 }
+
+#[test]
+fn test_def_use_graph() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::inst::BinOp;
+    use crate::lang::print::Printer;
+    use crate::lang::value::{Const, Type};
+    use std::io::stdout;
+    use std::borrow::BorrowMut;
+
+    let src = r#"
+@r: i64
+
+[ssa]
+fn @main() {
+%Begin:
+    $a <- mov i64 1
+    $b <- add i64 $a, 2
+    @r <- mul i64 $b, $b
+    ret
+}
+"#;
+    let tree = Parser::new(Lexer::from(src)).parse().unwrap();
+    let pro = Builder::new(tree).build().unwrap();
+    let func = pro.func[0].clone();
+    let graph = DefUseGraph::new(&func);
+    graph.set_check(Some(func.clone()));
+
+    let var = |name: &str| func.scope.find(name).unwrap_or_else(|| {
+        let sym = ExtRc::new(Symbol::Local { name: name.to_string(), ty: Type::I(64) });
+        func.scope.insert(sym.clone());
+        sym
+    });
+    let bin = |op, fst: Value, snd: i64, dst: SymbolRef| ExtRc::new(Inst::Bin {
+        op,
+        fst: RefCell::new(fst),
+        snd: RefCell::new(Value::Const(Const::I64(snd))),
+        dst: RefCell::new(dst),
+    });
+    let (a, b, c, d) = (var("a"), var("b"), var("c"), var("d"));
+    let ent = func.ent.borrow().clone();
+    let mut cursor = ent.tracked_cursor(&graph);
+    while let Some(instr) = cursor.next() {
+        match instr.dst().map(|d| d.borrow().name().to_string()).as_deref() {
+            Some("a") => cursor.insert_after(bin(BinOp::Mul, Value::Var(a.clone()), 3, d.clone())),
+            Some("d") => { cursor.erase(); }
+            Some("b") => {
+                cursor.insert_before(bin(BinOp::Add, Value::Var(a.clone()), 3, c.clone()));
+                cursor.replace(bin(BinOp::Sub, Value::Var(c.clone()), 1, b.clone()));
+            }
+            _ => {}
+        }
+    }
+    Printer::new(stdout().borrow_mut()).print(&pro).unwrap();
+
+    // Updates are consistent with a full rebuild
+    graph.validate(&func).unwrap();
+    assert_eq!(graph.uses(&a).len(), 1);
+    assert_eq!(graph.uses(&b).len(), 2);
+    assert!(matches!(graph.def(&c), DefPos::Inst(_, _)));
+    assert!(matches!(graph.def(&d), DefPos::None));
+}
+
+#[test]
+fn test_ssa_deterministic() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+
+    let src = r#"
+@r: i64
+
+fn @main() {
+%Begin:
+    $i <- mov i64 0
+    $s <- mov i64 0
+    $p <- mov i64 1
+    jmp %Cond
+%Cond:
+    $c <- lt i64 $i, 10
+    br $c ? %Body : %End
+%Body:
+    $t <- mod i64 $i, 2
+    $e <- eq i64 $t, 0
+    br $e ? %Even : %Odd
+%Even:
+    $s <- add i64 $s, $i
+    jmp %Next
+%Odd:
+    $p <- mul i64 $p, $i
+    jmp %Next
+%Next:
+    $i <- add i64 $i, 1
+    jmp %Cond
+%End:
+    @r <- add i64 $s, $p
+    ret
+}
+"#;
+    // Programs built separately are printed the same after SSA construction
+    let print = || {
+        let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+        pro.func.iter().for_each(|f| f.to_ssa());
+        let mut buf = vec![];
+        Printer::new(&mut buf).print(&pro).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+    let first = print();
+    println!("{}", first);
+    (0..10).for_each(|_| assert_eq!(print(), first));
+}
+
+#[test]
+fn test_repair_ssa() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::lang::Program;
+    use crate::lang::value::Const;
+    use crate::pass::Pass;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+
+[ssa]
+fn @main() {
+%Begin:
+    $a <- call i64 @f(0)
+    $b <- call i64 @f(1)
+    $c <- mul i64 $a, 10
+    @r <- add i64 $c, $b
+    ret
+}
+
+[ssa]
+fn @f($c: i1) -> i64 {
+%A:
+    $x <- mov i64 1
+    br $c ? %B : %C
+%B:
+    jmp %D
+%C:
+    jmp %D
+%D:
+    ret $x
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let func = pro.func.iter().find(|f| f.name == "f").unwrap().clone();
+    let x = func.scope.find("x").unwrap();
+    let blk_c = func.dfs().find(|b| b.name == "C").unwrap();
+    blk_c.push_front(ExtRc::new(Inst::Mov {
+        src: RefCell::new(Value::Const(Const::I64(2))),
+        dst: RefCell::new(x.clone()),
+    }));
+    func.repair_ssa(&[x.clone()]);
+
+    let print = |pro: &Program| {
+        let mut buf = vec![];
+        Printer::new(&mut buf).print(pro).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+    let out = print(&pro);
+    println!("{}", out);
+    assert_eq!(out.matches("<- phi i64").count(), 1);
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut pro);
+    assert!(ver.is_ok());
+    let res = Machine::new().run(&pro).unwrap();
+    // f(0) takes %C and returns 2, f(1) takes %B and returns 1
+    assert!(format!("{:?}", res.global).contains("I64(21)"));
+
+    // Repairing again changes nothing
+    func.repair_ssa(&[x]);
+    assert_eq!(print(&pro), out);
+}
+
+#[test]
+fn test_replace_all_uses() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::value::Const;
+    use crate::pass::Pass;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+
+[ssa]
+fn @main() {
+%Begin:
+    $a <- call i64 @f(3)
+    $b <- add i64 $a, 0
+    $k <- lt i64 $b, 5
+    br $k ? %Then : %End
+%Then:
+    $c <- mul i64 $b, $b
+    jmp %End
+%End:
+    $d <- phi i64 [%Begin: $b] [%Then: $c]
+    @r <- add i64 $d, $a
+    ret
+}
+
+[ssa]
+fn @f($x: i64) -> i64 {
+%Begin:
+    ret $x
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let expect = format!("{:?}", Machine::new().run(&pro).unwrap().global);
+    let func = pro.func[0].clone();
+    let graph = DefUseGraph::new(&func);
+    graph.set_check(Some(func.clone()));
+    let (a, b) = (func.scope.find("a").unwrap(), func.scope.find("b").unwrap());
+
+    // Uses in all blocks and phis are replaced, and the graph stays consistent
+    assert_eq!(func.tracked_replace_all_uses(&b, Value::Var(a.clone()), &graph), 4);
+    assert!(graph.uses(&b).is_empty());
+    assert_eq!(graph.uses(&a).len(), 6);
+    assert_eq!(func.replace_all_uses(&b, Value::Const(Const::I64(0))), 0);
+    let mut ver = VerifyPass::new();
+    ver.run(&mut pro);
+    assert!(ver.is_ok());
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+
+    // Replace with a constant without tracking
+    assert_eq!(func.replace_all_uses(&a, Value::Const(Const::I64(3))), 6);
+    graph.set_check(None);
+    assert!(graph.validate(&func).is_err());
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+}
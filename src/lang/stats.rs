@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Error, Formatter};
+
+use crate::lang::func::Fn;
+use crate::lang::Program;
+use crate::pass::util::LoopNodeRef;
+
+/// Statistics of a program, which are used to measure the effect of passes.
+#[derive(Clone, Eq, PartialEq, Default, Debug)]
+pub struct Stats {
+    /// Number of functions
+    pub func: usize,
+    /// Number of global variables
+    pub vars: usize,
+    /// Number of blocks in all functions
+    pub block: usize,
+    /// Number of instructions in all functions
+    pub inst: usize,
+    /// Number of instructions of each kind, keyed by instruction name
+    pub kind: BTreeMap<String, usize>,
+    /// Maximal depth of loop nests in all functions, which is zero if there is no loop
+    pub loop_depth: usize,
+}
+
+impl Program {
+    /// Collect statistics of this program.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats { func: self.func.len(), vars: self.vars.len(), ..Stats::default() };
+        self.func.iter().for_each(|func| stats.add_fn(func));
+        stats
+    }
+}
+
+impl Stats {
+    fn add_fn(&mut self, func: &Fn) {
+        func.iter_dom().for_each(|block| {
+            self.block += 1;
+            block.inst.borrow().iter().for_each(|instr| {
+                self.inst += 1;
+                *self.kind.entry(instr.name()).or_insert(0) += 1;
+            })
+        });
+        let depth = func.analyze_loop().iter().map(loop_depth).max().unwrap_or(0);
+        self.loop_depth = self.loop_depth.max(depth);
+    }
+
+    /// Number of instructions of a kind
+    pub fn count(&self, kind: &str) -> usize { self.kind.get(kind).cloned().unwrap_or(0) }
+
+    /// Average number of phi instructions per block
+    pub fn phi_density(&self) -> f64 {
+        if self.block == 0 { 0. } else { self.count("phi") as f64 / self.block as f64 }
+    }
+
+    /// Average number of instructions per block
+    pub fn block_size(&self) -> f64 {
+        if self.block == 0 { 0. } else { self.inst as f64 / self.block as f64 }
+    }
+}
+
+fn loop_depth(node: &LoopNodeRef) -> usize {
+    1 + node.borrow().nested.iter().map(loop_depth).max().unwrap_or(0)
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        writeln!(f, "{:<12}{:>8}", "functions", self.func)?;
+        writeln!(f, "{:<12}{:>8}", "globals", self.vars)?;
+        writeln!(f, "{:<12}{:>8}", "blocks", self.block)?;
+        writeln!(f, "{:<12}{:>8}", "insts", self.inst)?;
+        writeln!(f, "{:<12}{:>8}", "loop depth", self.loop_depth)?;
+        writeln!(f, "{:<12}{:>8.2}", "block size", self.block_size())?;
+        writeln!(f, "{:<12}{:>8.2}", "phi density", self.phi_density())?;
+        for (kind, n) in self.kind.iter() {
+            writeln!(f, "  {:<10}{:>8}", kind, n)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_stats() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::{FnPass, copy::CopyProp};
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut file = File::open("test/mat.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let mut pro = Builder::new(Parser::new(lexer).parse().unwrap()).build().unwrap();
+    let before = pro.stats();
+    println!("{}", before);
+    assert_eq!(before.func, pro.func.len());
+    assert_eq!(before.inst, before.kind.values().sum::<usize>());
+    assert_eq!(before.loop_depth, 2);
+
+    // Copy propagation removes `mov`s
+    FnPass::run(&mut CopyProp::new(), &mut pro);
+    let after = pro.stats();
+    assert!(after.count("mov") < before.count("mov"));
+    assert_eq!(after.block, before.block);
+
+    // Stats table is printed as comments after the program
+    let mut out = vec![];
+    Printer::new(&mut out).show_stats(true).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    after.to_string().lines().for_each(|l| assert!(out.contains(&format!("// {}\n", l))));
+}
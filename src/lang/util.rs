@@ -1,17 +1,29 @@
 use std::cell::{Ref, RefCell, RefMut};
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::hash::{Hash, Hasher};
 use std::iter::FromIterator;
 use std::ops::Deref;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 
 /// A auxiliary structure to make `Rc` act like pointer.
-/// The extended behavior include pointer-equality testing and hash.
+/// The extended behavior include pointer-equality testing and hash. Two references are equal if
+/// and only if they point to the same object, regardless of the value of the pointee. Ordering
+/// also compares addresses, so it is consistent with equality, but it is not stable across runs
+/// and should not decide the output of an algorithm.
 pub struct ExtRc<T>(pub Rc<T>);
 
 impl<T> ExtRc<T> {
     pub fn new(e: T) -> Self { ExtRc(Rc::new(e)) }
+
+    /// Create a weak reference to the pointee.
+    pub fn downgrade(&self) -> Weak<T> { Rc::downgrade(&self.0) }
+
+    /// Get strong reference from a weak one, if the pointee is not dropped yet.
+    pub fn upgrade(weak: &Weak<T>) -> Option<Self> { weak.upgrade().map(ExtRc) }
+
+    /// Whether `weak` points to the same object as this reference.
+    pub fn is_weak_of(&self, weak: &Weak<T>) -> bool { Rc::as_ptr(&self.0) == weak.as_ptr() }
 }
 
 impl<T> PartialEq for ExtRc<T> {
@@ -92,42 +104,159 @@ impl<T> MutRc<T> {
     pub fn borrow_mut(&self) -> RefMut<T> { self.0.deref().borrow_mut() }
 }
 
-/// Encapsulation of `HashSet` to aid work list algorithms
-/// A work list must allow quick testing of membership and quick extraction of an element,
-/// regardless of which element is moved.
+/// Encapsulation of a queue and a `HashSet` to aid work list algorithms
+/// A work list must allow quick testing of membership and quick extraction of an element.
+/// Elements are extracted in the order they are inserted, so that algorithms using work lists
+/// behave the same across runs. An element is kept at most once in the list: inserting one
+/// that is already there does nothing and keeps its place, while one that has been extracted
+/// can be inserted again.
 #[derive(Debug)]
 pub struct WorkList<T> where T: Eq + Hash + Clone {
-    set: HashSet<T>
+    queue: VecDeque<T>,
+    set: HashSet<T>,
 }
 
 impl<T> FromIterator<T> for WorkList<T> where T: Eq + Hash + Clone {
     fn from_iter<I>(iter: I) -> Self where I: IntoIterator<Item=T> {
-        WorkList { set: HashSet::from_iter(iter) }
+        let mut list = WorkList::new();
+        list.append(iter.into_iter());
+        list
+    }
+}
+
+impl<T> Extend<T> for WorkList<T> where T: Eq + Hash + Clone {
+    fn extend<I>(&mut self, iter: I) where I: IntoIterator<Item=T> {
+        iter.into_iter().for_each(|e| self.insert(e))
     }
 }
 
 impl<T> WorkList<T> where T: Eq + Hash + Clone {
     pub fn new() -> WorkList<T> {
-        WorkList { set: Default::default() }
+        WorkList { queue: Default::default(), set: Default::default() }
     }
 
-    pub fn insert(&mut self, item: T) { self.set.insert(item); }
-
-    pub fn append<I>(&mut self, iter: I) where I: Iterator<Item=T> {
-        iter.for_each(|e| self.insert(e))
+    /// Insert an element, if it is not already in the list.
+    pub fn insert(&mut self, item: T) {
+        if self.set.insert(item.clone()) { self.queue.push_back(item) }
     }
 
+    /// Insert elements of `iter` in order. Same as `extend`.
+    pub fn append<I>(&mut self, iter: I) where I: Iterator<Item=T> { self.extend(iter) }
+
+    /// Extract the earliest inserted element.
     pub fn pick(&mut self) -> Option<T> {
-        let mut elem = None;
-        for x in self.set.iter() {
-            elem = Some(x.clone());
-            break
-        }
-        elem.map(|e| {
+        self.queue.pop_front().map(|e| {
             self.set.remove(&e);
             e
         })
     }
 
+    /// Whether `item` is in the list, waiting to be extracted.
+    pub fn contains(&self, item: &T) -> bool { self.set.contains(item) }
+
+    pub fn len(&self) -> usize { self.queue.len() }
+
     pub fn is_empty(&self) -> bool { self.set.is_empty() }
 }
+
+/// Work list whose elements are extracted in order of their priorities, which helps algorithms
+/// converge faster when visiting in a certain order, such as reverse postorder of blocks.
+/// The element of least priority is extracted first, and elements of equal priority are
+/// extracted in the order they are first inserted. As in `WorkList`, an element is kept at most
+/// once. Inserting one that is already there lowers its priority if the new one is less, and
+/// does nothing otherwise.
+#[derive(Debug)]
+pub struct PriorityWorkList<T, P> where T: Eq + Hash + Clone, P: Ord + Clone {
+    queue: BTreeMap<(P, usize), T>,
+    key: HashMap<T, (P, usize)>,
+    count: usize,
+}
+
+impl<T, P> FromIterator<(T, P)> for PriorityWorkList<T, P>
+    where T: Eq + Hash + Clone, P: Ord + Clone {
+    fn from_iter<I>(iter: I) -> Self where I: IntoIterator<Item=(T, P)> {
+        let mut list = PriorityWorkList::new();
+        list.extend(iter);
+        list
+    }
+}
+
+impl<T, P> Extend<(T, P)> for PriorityWorkList<T, P> where T: Eq + Hash + Clone, P: Ord + Clone {
+    fn extend<I>(&mut self, iter: I) where I: IntoIterator<Item=(T, P)> {
+        iter.into_iter().for_each(|(e, prio)| self.insert(e, prio))
+    }
+}
+
+impl<T, P> PriorityWorkList<T, P> where T: Eq + Hash + Clone, P: Ord + Clone {
+    pub fn new() -> PriorityWorkList<T, P> {
+        PriorityWorkList { queue: Default::default(), key: Default::default(), count: 0 }
+    }
+
+    /// Insert an element with priority `prio`, or lower priority of the element if it is
+    /// already in the list.
+    pub fn insert(&mut self, item: T, prio: P) {
+        let seq = match self.key.get(&item) {
+            Some((prev, _)) if *prev <= prio => return,
+            Some(key) => {
+                let key = key.clone();
+                self.queue.remove(&key);
+                key.1
+            }
+            None => {
+                self.count += 1;
+                self.count
+            }
+        };
+        self.queue.insert((prio.clone(), seq), item.clone());
+        self.key.insert(item, (prio, seq));
+    }
+
+    /// Extract the element of least priority, along with its priority.
+    pub fn pick(&mut self) -> Option<(T, P)> {
+        let ((prio, _), item) = self.queue.pop_first()?;
+        self.key.remove(&item);
+        Some((item, prio))
+    }
+
+    /// Get priority of `item`, if it is in the list.
+    pub fn priority(&self, item: &T) -> Option<&P> { self.key.get(item).map(|(prio, _)| prio) }
+
+    pub fn contains(&self, item: &T) -> bool { self.key.contains_key(item) }
+
+    pub fn len(&self) -> usize { self.queue.len() }
+
+    pub fn is_empty(&self) -> bool { self.queue.is_empty() }
+}
+
+#[test]
+fn test_work_list() {
+    // Elements are extracted in order of insertion, without duplicates
+    let mut list: WorkList<_> = vec![3, 1, 3, 2].into_iter().collect();
+    list.extend(vec![1, 4]);
+    assert_eq!(list.len(), 4);
+    assert!(list.contains(&2));
+    assert_eq!(list.pick(), Some(3));
+    list.insert(3);
+    list.insert(2);
+    let rest: Vec<_> = std::iter::from_fn(|| list.pick()).collect();
+    assert_eq!(rest, [1, 2, 4, 3]);
+    assert!(list.is_empty());
+
+    // Elements are extracted in order of priority, and then of first insertion
+    let mut list: PriorityWorkList<_, _> = vec![("a", 2), ("b", 1), ("c", 2)].into_iter()
+        .collect();
+    list.extend(vec![("d", 1), ("a", 3), ("c", 0)]);
+    assert_eq!(list.priority(&"a"), Some(&2));
+    assert_eq!(list.pick(), Some(("c", 0)));
+    list.insert("c", 5);
+    let rest: Vec<_> = std::iter::from_fn(|| list.pick()).collect();
+    assert_eq!(rest, [("b", 1), ("d", 1), ("a", 2), ("c", 5)]);
+    assert!(list.is_empty());
+
+    // References are compared by identity
+    let (x, y) = (ExtRc::new(0), ExtRc::new(0));
+    let mut list = WorkList::new();
+    list.extend(vec![x.clone(), y.clone(), x.clone()]);
+    assert_eq!(list.len(), 2);
+    assert!(list.pick().unwrap() == x && list.pick().unwrap() == y);
+}
@@ -1,5 +1,5 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::{Debug, Error, Formatter};
 use std::ops::*;
 use std::rc::Rc;
@@ -22,8 +22,13 @@ pub enum Type {
     Array { elem: Box<Type>, len: usize },
     /// Structure type
     Struct { field: Vec<Type> },
+    /// Union type, whose fields all start at the beginning of its storage
+    Union { field: Vec<Type> },
     /// Type alias
     Alias(SymbolRef),
+    /// Values returned together by a function. It only appears as return type of functions,
+    /// and there is no value of this type.
+    Tuple(Vec<Type>),
 }
 
 impl PartialEq for Type {
@@ -37,6 +42,8 @@ impl PartialEq for Type {
             (Type::Array { elem: e1, len: l1 }, Type::Array { elem: e2, len: l2 }) =>
                 l1 == l2 && e1 == e2,
             (Type::Struct { field: f1 }, Type::Struct { field: f2 }) => f1 == f2,
+            (Type::Union { field: f1 }, Type::Union { field: f2 }) => f1 == f2,
+            (Type::Tuple(e1), Type::Tuple(e2)) => e1 == e2,
             // Nominal typing is used to decide equivalence for alias types, which means two alias
             // types with different names are not equivalent. However, an alias type can be equal
             // to its original type.
@@ -54,17 +61,15 @@ impl FromStr for Type {
     /// Currently, this method only recognize primitive type.
     /// Other type should be resolved by compiler, instead of this method.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "i1" => Ok(Type::I(1)),
-            "i8" => Ok(Type::I(8)),
-            "i16" => Ok(Type::I(16)),
-            "i32" => Ok(Type::I(32)),
-            "i64" => Ok(Type::I(64)),
-            _ => Err("unknown type".to_string())
-        }
+        s.strip_prefix('i').and_then(|w| w.parse().ok()).and_then(Type::int)
+            .ok_or_else(|| "unknown type".to_string())
     }
 }
 
+/// Bit widths of integer types supported by the language. Constants and their operations are
+/// defined for each of them.
+pub const INT_WIDTHS: [u8; 5] = [1, 8, 16, 32, 64];
+
 impl ToString for Type {
     fn to_string(&self) -> String {
         match self {
@@ -82,12 +87,34 @@ impl ToString for Type {
             Type::Array { elem, len } => format!("[{}]{}", len, elem.to_string()),
             Type::Struct { field } =>
                 format!("{{ {} }}", Self::vec_to_string(field)),
-            Type::Alias(def) => "@".to_owned() + def.name()
+            Type::Union { field } =>
+                format!("union {{ {} }}", Self::vec_to_string(field)),
+            Type::Alias(def) => "@".to_owned() + def.name(),
+            Type::Tuple(elem) => format!("({})", Self::vec_to_string(elem)),
         }
     }
 }
 
 impl Type {
+    /// Create integer type of `bits` bits. Returns `None` if the width is not in `INT_WIDTHS`.
+    pub fn int(bits: u8) -> Option<Type> {
+        if INT_WIDTHS.contains(&bits) { Some(Type::I(bits)) } else { None }
+    }
+
+    /// Whether all integer types in this type, not looking through aliases, have supported
+    /// widths.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Type::I(b) => INT_WIDTHS.contains(b),
+            Type::Void | Type::Alias(_) => true,
+            Type::Fn { param, ret } => param.iter().all(Type::is_valid) && ret.is_valid(),
+            Type::Ptr(tgt) => tgt.is_valid(),
+            Type::Array { elem, len: _ } => elem.is_valid(),
+            Type::Struct { field } | Type::Union { field } | Type::Tuple(field) =>
+                field.iter().all(Type::is_valid),
+        }
+    }
+
     /// Get original type for this type. This method is mainly for alias types.
     pub fn orig(&self) -> Type {
         let mut orig = self.clone();
@@ -128,19 +155,42 @@ impl Type {
         }
     }
 
+    /// Types of values returned by a function with this return type. A tuple returns each of
+    /// its elements, and `Void` returns nothing.
+    pub fn ret_types(&self) -> Vec<Type> {
+        match self {
+            Type::Void => vec![],
+            Type::Tuple(elem) => elem.clone(),
+            ty => vec![ty.clone()]
+        }
+    }
+
     /// Get target type for pointer types
     pub fn tgt_type(&self) -> Type {
         if let Type::Ptr(t) = self { t.deref().clone() } else {
             panic!("cannot get target type of non-pointer type")
         }
     }
+
+    /// Get parameter and return types of the function pointed to, if this is a function
+    /// pointer type.
+    pub fn fn_sig(&self) -> Option<(Vec<Type>, Type)> {
+        match self.orig() {
+            Type::Ptr(tgt) => match tgt.orig() {
+                Type::Fn { param, ret } => Some((param, ret.deref().clone())),
+                _ => None
+            }
+            _ => None
+        }
+    }
 }
 
 pub trait Typed {
     fn get_type(&self) -> Type;
 }
 
-#[derive(Clone, Debug)]
+/// Values are compared by identity of their symbols, or by their constants.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub enum Value {
     /// A variable holding reference to corresponding symbol
     Var(SymbolRef),
@@ -216,12 +266,23 @@ pub enum Symbol {
 
 pub type SymbolRef = ExtRc<Symbol>;
 
+/// Kind of a symbol. Local variables are named with `$`, and the other kinds share the namespace
+/// of `@`.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum SymbolKind {
+    Local,
+    Global,
+    Type,
+    Func,
+}
+
 impl Typed for Symbol {
     fn get_type(&self) -> Type {
         match self {
             Symbol::Local { name: _, ty } => ty.clone(),
             Symbol::Global(v) => v.ty.clone(),
-            Symbol::Func(f) => f.get_type(),
+            // A function used as a value is a pointer to it
+            Symbol::Func(f) => Type::Ptr(Box::new(f.get_type())),
             Symbol::Type { name: _, ty } => ty.borrow().clone()
         }
     }
@@ -261,6 +322,16 @@ impl Symbol {
         }
     }
 
+    /// Get kind of this symbol.
+    pub fn kind(&self) -> SymbolKind {
+        match self {
+            Symbol::Local { name: _, ty: _ } => SymbolKind::Local,
+            Symbol::Global(_) => SymbolKind::Global,
+            Symbol::Type { name: _, ty: _ } => SymbolKind::Type,
+            Symbol::Func(_) => SymbolKind::Func
+        }
+    }
+
     /// Whether this symbol is a local variable.
     pub fn is_local_var(&self) -> bool {
         match self {
@@ -276,6 +347,22 @@ impl Symbol {
             _ => false
         }
     }
+
+    /// Whether this symbol is a temporary, which is a local variable created without a name,
+    /// and named by a number such as `$3` or `$3.1`.
+    pub fn is_temp(&self) -> bool {
+        match self {
+            Symbol::Local { name, ty: _ } => is_temp_name(name),
+            _ => false
+        }
+    }
+}
+
+/// Whether `name` consists of numbers, which is the form of names of temporaries and temporary
+/// blocks.
+pub fn is_temp_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_digit() || c == '.')
 }
 
 #[derive(Eq, Clone, Debug)]
@@ -283,6 +370,10 @@ pub struct GlobalVar {
     pub name: String,
     pub ty: Type,
     pub init: Option<Const>,
+    /// Whether this variable is read-only after initialization
+    pub is_const: bool,
+    /// Visibility of this variable outside the module
+    pub linkage: Linkage,
 }
 
 impl Typed for GlobalVar {
@@ -295,6 +386,41 @@ impl PartialEq for GlobalVar {
 
 pub type GlobalVarRef = ExtRc<GlobalVar>;
 
+/// Linkage of global variables and functions, which decides their visibility outside the
+/// module and how definitions of the same name are resolved when modules are linked.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Linkage {
+    /// Only visible in this module. This is the default linkage.
+    #[default]
+    Internal,
+    /// Visible to other modules. There should be only one exported definition of a name.
+    Export,
+    /// Visible to other modules, but could be overridden by an exported definition.
+    Weak,
+}
+
+impl Linkage {
+    /// Whether the symbol could be referred to outside the module
+    pub fn is_visible(&self) -> bool { *self != Linkage::Internal }
+}
+
+impl ToString for Linkage {
+    fn to_string(&self) -> String { format!("{:?}", self).to_lowercase() }
+}
+
+impl FromStr for Linkage {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "export" => Ok(Linkage::Export),
+            "internal" => Ok(Linkage::Internal),
+            "weak" => Ok(Linkage::Weak),
+            _ => Err(())
+        }
+    }
+}
+
 impl Debug for GlobalVarRef {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         write!(f, "@{}", self.name)
@@ -302,12 +428,13 @@ impl Debug for GlobalVarRef {
 }
 
 #[derive(Debug)]
-/// Encapsulation of hash map to provide common operations to scope.
+/// Encapsulation of ordered map to provide common operations to scope.
 /// Internal mutability is utilized, so be careful not to violate the borrowing rules.
+/// Symbols are iterated in order of their identifiers.
 pub struct Scope {
     /// Maps variable identifier to symbol
     /// For local variable, its identifier is `{$name}(.{$ver})?`
-    map: RefCell<HashMap<String, SymbolRef>>,
+    map: RefCell<BTreeMap<String, SymbolRef>>,
 }
 
 impl Default for Scope {
@@ -320,7 +447,7 @@ impl Scope {
     /// Otherwise, a global scope will be created.
     pub fn new() -> Scope {
         Scope {
-            map: RefCell::new(HashMap::new())
+            map: RefCell::new(BTreeMap::new())
         }
     }
 
@@ -340,9 +467,50 @@ impl Scope {
         self.map.borrow_mut().get(id).cloned()
     }
 
+    /// Lookup a symbol with given `id` and `kind`.
+    pub fn find_kind(&self, id: &str, kind: SymbolKind) -> Option<SymbolRef> {
+        self.find(id).filter(|sym| sym.kind() == kind)
+    }
+
+    /// Lookup a symbol with its name qualified by the sigil of its namespace, as it is printed,
+    /// such as `$x` for a local variable and `@f` for a function.
+    pub fn lookup(&self, qual: &str) -> Option<SymbolRef> {
+        if let Some(id) = qual.strip_prefix('$') {
+            self.find_kind(id, SymbolKind::Local)
+        } else if let Some(id) = qual.strip_prefix('@') {
+            self.find(id).filter(|sym| !sym.is_local_var())
+        } else {
+            None
+        }
+    }
+
+    /// Return all the symbols of `kind` in the scope, ordered by their names.
+    pub fn of_kind(&self, kind: SymbolKind) -> Vec<SymbolRef> {
+        self.map.borrow().values().filter(|sym| sym.kind() == kind).cloned().collect()
+    }
+
+    /// Return all the variables and functions of type `ty` in the scope, ordered by their
+    /// names. Type symbols are not included.
+    pub fn of_type(&self, ty: &Type) -> Vec<SymbolRef> {
+        self.map.borrow().values()
+            .filter(|sym| sym.kind() != SymbolKind::Type && &sym.get_type() == ty)
+            .cloned().collect()
+    }
+
     /// Remove symbol with `id` from scope.
     pub fn remove(&self, id: &str) { self.map.borrow_mut().remove(id); }
 
+    /// Remove all the symbols that `pred` rejects from the scope, and return them.
+    pub fn retain<F>(&self, mut pred: F) -> Vec<SymbolRef> where F: FnMut(&SymbolRef) -> bool {
+        let mut removed = vec![];
+        self.map.borrow_mut().retain(|_, sym| {
+            let keep = pred(sym);
+            if !keep { removed.push(sym.clone()) }
+            keep
+        });
+        removed
+    }
+
     /// Clear all the symbols in the scope
     pub fn clear(&self) { self.map.borrow_mut().clear() }
 
@@ -355,6 +523,22 @@ impl Scope {
     pub fn for_each<F>(&self, f: F) where F: FnMut(SymbolRef) {
         self.map.borrow().values().cloned().for_each(f)
     }
+
+    /// Whether `name`, or any of its versions `{name}.{ver}`, is defined in this scope. Such
+    /// name cannot be used for a new symbol, since versions of the new symbol created by SSA
+    /// construction may conflict with existing ones. A dotted `name`, such as `{base}.{ver}`, is
+    /// taken only if it is defined exactly.
+    pub fn is_taken(&self, name: &str) -> bool {
+        self.map.borrow().keys().any(|k| k == name || k.split('.').next() == Some(name))
+    }
+
+    /// Generate a fresh name `{prefix}{num}` that is not taken in this scope. The name is not
+    /// added to this scope.
+    pub fn unique_name(&self, prefix: &str) -> String {
+        let taken: HashSet<String> = self.map.borrow().keys()
+            .map(|k| k.split('.').next().unwrap().to_string()).collect();
+        (0..).map(|i| format!("{}{}", prefix, i)).find(|n| !taken.contains(n)).unwrap()
+    }
 }
 
 /// Procedural symbol generator
@@ -378,7 +562,7 @@ impl SymbolGen {
         loop {
             let name = format!("{}{}", self.pre, self.num);
             self.num += 1;
-            if self.scope.find(&name).is_some() { continue; }
+            if self.scope.is_taken(&name) { continue; }
             let sym = ExtRc::new(Symbol::Local {
                 name,
                 ty: ty.clone(),
@@ -433,7 +617,7 @@ impl Const {
             Type::I(16) => Some(Const::I16(d as i16)),
             Type::I(32) => Some(Const::I32(d as i32)),
             Type::I(64) => Some(Const::I64(d as i64)),
-            _ => unreachable!()
+            _ => None
         }
     }
 
@@ -444,7 +628,7 @@ impl Const {
             Type::I(16) => Const::I16(0),
             Type::I(32) => Const::I32(0),
             Type::I(64) => Const::I64(0),
-            _ => unreachable!()
+            ty => panic!("{} is not a supported integer type", ty.to_string())
         }
     }
 
@@ -455,7 +639,44 @@ impl Const {
             Type::I(16) => Const::I16(1),
             Type::I(32) => Const::I32(1),
             Type::I(64) => Const::I64(1),
-            _ => unreachable!()
+            ty => panic!("{} is not a supported integer type", ty.to_string())
+        }
+    }
+
+    /// Create constant of type `ty` from the low bits of `v`.
+    pub fn from_i64(v: i64, ty: &Type) -> Const {
+        match ty.orig() {
+            Type::I(1) => Const::I1(v & 1 != 0),
+            Type::I(8) => Const::I8(v as i8),
+            Type::I(16) => Const::I16(v as i16),
+            Type::I(32) => Const::I32(v as i32),
+            Type::I(64) => Const::I64(v),
+            ty => panic!("{} is not a supported integer type", ty.to_string())
+        }
+    }
+
+    /// Whether this constant is zero.
+    pub fn is_zero(&self) -> bool { self.as_i64() == 0 }
+
+    /// Get value of this constant, sign-extended to 64 bits. `i1` constants are zero-extended.
+    pub fn as_i64(&self) -> i64 {
+        match self {
+            Const::I1(v) => *v as i64,
+            Const::I8(v) => *v as i64,
+            Const::I16(v) => *v as i64,
+            Const::I32(v) => *v as i64,
+            Const::I64(v) => *v,
+        }
+    }
+
+    /// Get value of this constant, zero-extended to 64 bits.
+    pub fn as_u64(&self) -> u64 {
+        match self {
+            Const::I1(v) => *v as u64,
+            Const::I8(v) => *v as u8 as u64,
+            Const::I16(v) => *v as u16 as u64,
+            Const::I32(v) => *v as u32 as u64,
+            Const::I64(v) => *v as u64,
         }
     }
 }
@@ -484,121 +705,89 @@ impl ToString for Const {
     }
 }
 
-impl Not for Const {
-    type Output = Self;
-
-    fn not(self) -> Self::Output {
-        match self {
-            Const::I1(v) => Const::I1(!v),
-            Const::I8(v) => Const::I8(!v),
-            Const::I16(v) => Const::I16(!v),
-            Const::I32(v) => Const::I32(!v),
-            Const::I64(v) => Const::I64(!v),
-        }
-    }
+#[test]
+fn test_int_width() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::lang::inst::Inst;
+
+    assert_eq!("i16".parse::<Type>(), Ok(Type::I(16)));
+    assert!("i24".parse::<Type>().is_err() && Type::int(0).is_none());
+    assert!(!Type::Ptr(Box::new(Type::I(24))).is_valid());
+    assert!(Const::from_str("1", &Type::I(24)).is_none());
+
+    // Constant operations are defined for every supported width
+    for w in INT_WIDTHS.iter() {
+        let ty = Type::I(*w);
+        let (zero, one) = (Const::zero(&ty), Const::one(&ty));
+        assert_eq!((one + one).as_u64(), 2 & ((1u128 << w) - 1) as u64);
+        assert_eq!(one - one, zero);
+        assert_eq!(one * one, one);
+        assert_eq!(one / one, one);
+        assert_eq!(one << one, if *w == 1 { one } else { Const::from_i64(2, &ty) });
+        assert_eq!(Const::from_i64(-1, &ty).less_than(zero), Const::I1(true));
+        assert_eq!(Const::from_i64(0x1_0000_0000, &ty).is_zero(), *w <= 32);
+    }
+    assert_eq!(Const::I1(true).add_ov(Const::I1(true)), Const::I1(true));
+    assert_eq!(Const::I1(false).sub_ov(Const::I1(false)), Const::I1(false));
+
+    // Verification finds unsupported widths in programs constructed by API
+    let pro = Builder::new(Parser::new(Lexer::from(r#"
+fn @f() {
+%Begin:
+    ret
+}
+"#)).parse().unwrap()).build().unwrap();
+    let func = &pro.func[0];
+    let sym = ExtRc::new(Symbol::Local { name: "x".to_string(), ty: Type::I(24) });
+    func.ent.borrow().push_front(ExtRc::new(Inst::Alloc { dst: RefCell::new(sym) }));
+    let err = func.verify_type();
+    assert_eq!(err[0].msg, "type i24 has unsupported integer width");
+}
+
+#[test]
+fn test_scope_query() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+
+    let pro = Builder::new(Parser::new(Lexer::from(r#"
+type @Pair = { i64, i64 }
+
+@g: i64 <- 0
+@h: i32 <- 0
+
+fn @f($n: i64) -> i64 {
+%Begin:
+    $a <- add i64 $n, 1
+    $b <- mul i64 $a, 2
+    ret $a
+}
+"#)).parse().unwrap()).build().unwrap();
+
+    // Symbols are queried by kind and type
+    let names = |syms: Vec<SymbolRef>| syms.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(names(pro.global.of_kind(SymbolKind::Global)), ["@g", "@h"]);
+    assert_eq!(names(pro.global.of_kind(SymbolKind::Type)), ["@Pair"]);
+    assert_eq!(names(pro.global.of_type(&Type::I(64))), ["@g"]);
+    let func = &pro.func[0];
+    assert_eq!(names(func.scope.of_type(&Type::I(64))), ["$a", "$b", "$n"]);
+
+    // Lookup with names qualified by namespaces
+    assert!(pro.global.lookup("@f").map(|f| f.kind()) == Some(SymbolKind::Func));
+    assert!(pro.global.lookup("$g").is_none() && func.scope.lookup("@a").is_none());
+    assert!(func.scope.lookup("$a").is_some() && func.scope.lookup("a").is_none());
+
+    // Versioned names are taken by their base names and by exact ones
+    func.scope.insert(ExtRc::new(Symbol::Local { name: "t.0".to_string(), ty: Type::I(64) }));
+    assert!(func.scope.is_taken("t") && func.scope.is_taken("t.0"));
+    assert!(!func.scope.is_taken("t.1") && !func.scope.is_taken("a.0"));
+    assert_eq!(func.scope.unique_name("t"), "t0");
+    func.scope.remove("t.0");
+
+    // Locals no longer referred to are removed
+    func.ent.borrow().inst.borrow_mut().remove(1);
+    assert_eq!(names(func.remove_unused_locals()), ["$b"]);
+    assert_eq!(names(func.scope.of_kind(SymbolKind::Local)), ["$a", "$n"]);
 }
-
-impl Neg for Const {
-    type Output = Self;
-
-    fn neg(self) -> Self::Output {
-        match self {
-            Const::I8(v) => Const::I8(-v),
-            Const::I16(v) => Const::I16(-v),
-            Const::I32(v) => Const::I32(-v),
-            Const::I64(v) => Const::I64(-v),
-            _ => unreachable!()
-        }
-    }
-}
-
-macro_rules! bin_arith_impl {
-    ($trait:ty, $func:ident, $op:tt) => {
-        impl $trait for Const {
-            type Output = Self;
-            fn $func(self, rhs: Self) -> Self::Output {
-                match (self, rhs) {
-                    (Const::I8(l), Const::I8(r)) => Const::I8(l $op r),
-                    (Const::I16(l), Const::I16(r)) => Const::I16(l $op r),
-                    (Const::I32(l), Const::I32(r)) => Const::I32(l $op r),
-                    (Const::I64(l), Const::I64(r)) => Const::I64(l $op r),
-                    _ => unreachable!()
-                }
-            }
-        }
-    };
-}
-
-bin_arith_impl!(Add, add, +);
-bin_arith_impl!(Sub, sub, -);
-bin_arith_impl!(Mul, mul, *);
-bin_arith_impl!(Div, div, /);
-bin_arith_impl!(Shl, shl, <<);
-bin_arith_impl!(Shr, shr, >>);
-bin_arith_impl!(Rem, rem, %);
-
-macro_rules! bin_bitwise_impl {
-    ($trait:ty, $func:ident, $op:tt) => {
-        impl $trait for Const {
-            type Output = Self;
-
-            fn $func(self, rhs: Self) -> Self::Output {
-                match (self, rhs) {
-                    (Const::I1(l), Const::I1(r)) => Const::I1(l $op r),
-                    (Const::I8(l), Const::I8(r)) => Const::I8(l $op r),
-                    (Const::I16(l), Const::I16(r)) => Const::I16(l $op r),
-                    (Const::I32(l), Const::I32(r)) => Const::I32(l $op r),
-                    (Const::I64(l), Const::I64(r)) => Const::I64(l $op r),
-                    _ => unreachable!()
-                }
-            }
-        }
-    };
-}
-
-bin_bitwise_impl!(BitAnd, bitand, &);
-bin_bitwise_impl!(BitOr, bitor, |);
-bin_bitwise_impl!(BitXor, bitxor, ^);
-
-macro_rules! cmp_ord_impl {
-    ($func:ident, $op:tt) => {
-        impl Const {
-            pub fn $func(self, rhs: Self) -> Self {
-                match (self, rhs) {
-                    (Const::I8(l), Const::I8(r)) => Const::I1(l $op r),
-                    (Const::I16(l), Const::I16(r)) => Const::I1(l $op r),
-                    (Const::I32(l), Const::I32(r)) => Const::I1(l $op r),
-                    (Const::I64(l), Const::I64(r)) => Const::I1(l $op r),
-                    _ => unreachable!()
-                }
-            }
-        }
-    };
-}
-
-cmp_ord_impl!(less_than, <);
-cmp_ord_impl!(less_eq, <=);
-cmp_ord_impl!(greater_than, >);
-cmp_ord_impl!(greater_eq, >=);
-
-// Note that the result is `Const::I1`, not bool
-macro_rules! cmp_eq_impl {
-    ($func:ident, $op:tt) => {
-        impl Const {
-            pub fn $func(self, rhs: Self) -> Self {
-                match (self, rhs) {
-                    (Const::I1(l), Const::I1(r)) => Const::I1(l $op r),
-                    (Const::I8(l), Const::I8(r)) => Const::I1(l $op r),
-                    (Const::I16(l), Const::I16(r)) => Const::I1(l $op r),
-                    (Const::I32(l), Const::I32(r)) => Const::I1(l $op r),
-                    (Const::I64(l), Const::I64(r)) => Const::I1(l $op r),
-                    _ => unreachable!()
-                }
-            }
-        }
-    };
-}
-
-// TO avoid colliding with library trait `Eq` and `Ne`, its method name is `e` and `n`.
-cmp_eq_impl!(equal, ==);
-cmp_eq_impl!(not_eq, !=);
@@ -0,0 +1,414 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt::{Display, Error, Formatter};
+use std::ops::Deref;
+
+use crate::irc::Loc;
+use crate::lang::func::{BlockRef, Fn, FnAttrib};
+use crate::lang::inst::{Inst, InstRef, PhiSrc};
+use crate::lang::Program;
+use crate::lang::value::{Symbol, SymbolRef, Type, Typed, Value};
+
+/// Error found in verification of a function
+#[derive(Clone, Debug)]
+pub struct VerifyErr {
+    /// Name of the function
+    pub func: String,
+    /// Name of the block where the error is found
+    pub block: String,
+    /// Index of the instruction in the block, or `None` if it is not related to an instruction
+    pub index: Option<usize>,
+    /// Name of the symbol involved, if any
+    pub sym: Option<String>,
+    /// What causes this error
+    pub msg: String,
+    /// Source location of the instruction or block, if there is one
+    pub loc: Option<Loc>,
+}
+
+impl Display for VerifyErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "@{}, %{}", self.func, self.block)?;
+        if let Some(i) = self.index { write!(f, ", #{}", i)?; }
+        if let Some(ref loc) = self.loc { write!(f, " ({})", loc)?; }
+        write!(f, "\t{}", self.msg)
+    }
+}
+
+impl VerifyErr {
+    /// Create error for instruction `instr` in `block` of function `func`.
+    pub fn new(func: &str, block: &BlockRef, instr: Option<&InstRef>, sym: Option<&SymbolRef>,
+               msg: String) -> VerifyErr
+    {
+        VerifyErr {
+            func: func.to_string(),
+            block: block.name.clone(),
+            index: instr.and_then(|instr| block.inst.borrow().iter().position(|i| i == instr)),
+            sym: sym.map(|sym| sym.name().to_string()),
+            msg,
+            loc: None,
+        }
+    }
+
+    /// Find source location of this error in function `func`.
+    pub fn locate(&mut self, func: &Fn) {
+        let block = func.iter_dom().find(|b| b.name == self.block);
+        self.loc = block.and_then(|block| match self.index {
+            Some(i) => block.inst.borrow().get(i).and_then(|instr| func.inst_loc(instr)),
+            None => block.loc.borrow().clone()
+        });
+    }
+}
+
+/// Type checker of a whole program, independent of the builder.
+/// Besides operand and result types of every instruction, it checks that callees and global
+/// variables referred to are part of the program, and that phi operands are consistent with
+/// predecessors of their blocks. This is useful for programs constructed or transformed outside
+/// of `irc::build`.
+pub struct TypeChecker {
+    /// Errors found in the last check
+    pub err: Vec<VerifyErr>,
+}
+
+impl TypeChecker {
+    pub fn new() -> TypeChecker { TypeChecker { err: vec![] } }
+
+    /// Check all functions in `pro`. Returns whether no error is found.
+    pub fn check(&mut self, pro: &Program) -> bool {
+        self.err.clear();
+        pro.func.iter().for_each(|func| self.check_fn(pro, func));
+        self.err.is_empty()
+    }
+
+    /// Check function `func` in `pro`, and append errors found.
+    pub fn check_fn(&mut self, pro: &Program, func: &Fn) {
+        self.err.append(&mut func.verify_type());
+        func.iter_dom().for_each(|block| {
+            block.inst.borrow().iter().enumerate().for_each(|(i, instr)| {
+                let mut push = |msg: String| {
+                    let sym = instr.dst().map(|dst| dst.borrow().clone());
+                    let mut e = VerifyErr::new(&func.name, &block, Some(instr), sym.as_ref(),
+                                               msg);
+                    e.loc = func.inst_loc(instr);
+                    self.err.push(e)
+                };
+                Self::check_ref(pro, instr).into_iter().for_each(&mut push);
+                if let Inst::Phi { src, dst: _ } = instr.as_ref() {
+                    if i > 0 && !block.inst.borrow()[i - 1].is_phi() {
+                        push("phi is not at the beginning of block".to_string())
+                    }
+                    Self::check_phi(&block, src).into_iter().for_each(&mut push);
+                }
+            })
+        })
+    }
+
+    /// Check that symbols referred to in `instr` are defined in `pro`.
+    fn check_ref(pro: &Program, instr: &Inst) -> Vec<String> {
+        let mut msg = vec![];
+        if let Inst::Call { func, arg: _, dst: _ } = instr {
+            if !pro.func.contains(func) {
+                msg.push(format!("function @{} not found in program", func.name))
+            }
+        }
+        instr.src().iter().for_each(|opd| if let Value::Var(sym) = opd.borrow().deref() {
+            match sym.as_ref() {
+                Symbol::Global(var) if !pro.vars.contains(var) =>
+                    msg.push(format!("global variable @{} not found in program", var.name)),
+                Symbol::Func(func) if !pro.func.contains(func) =>
+                    msg.push(format!("function @{} not found in program", func.name)),
+                _ => {}
+            }
+        });
+        msg
+    }
+
+    /// Check that operands of a phi in `block` correspond to its predecessors one-to-one.
+    pub(crate) fn check_phi(block: &BlockRef, src: &[PhiSrc]) -> Vec<String> {
+        let mut msg = vec![];
+        let mut found = HashSet::new();
+        for (pred, _) in src {
+            let pred = pred.borrow();
+            if !block.pred().contains(&pred) {
+                msg.push(format!("phi operand from %{}, which is not a predecessor", pred.name))
+            } else if !found.insert(pred.clone()) {
+                msg.push(format!("duplicate phi operand for %{}", pred.name))
+            }
+        }
+        block.pred().iter().filter(|pred| !found.contains(*pred)).for_each(|pred| {
+            msg.push(format!("phi operand not found for %{}", pred.name))
+        });
+        msg
+    }
+}
+
+impl Fn {
+    /// Check type consistency of every instruction in this function.
+    pub fn verify_type(&self) -> Vec<VerifyErr> {
+        let mut err = vec![];
+        self.iter_dom().for_each(|block| {
+            block.inst.borrow().iter().for_each(|instr| {
+                if let Some(msg) = self.check_instr_type(instr) {
+                    let sym = instr.dst().map(|dst| dst.borrow().clone());
+                    let mut e = VerifyErr::new(&self.name, &block, Some(instr), sym.as_ref(), msg);
+                    e.loc = self.inst_loc(instr);
+                    err.push(e)
+                }
+            })
+        });
+        err
+    }
+
+    /// Check that every block ends with exactly one terminator, which is its only control flow
+    /// instruction. Blocks ending with `ret`, `unreachable` or `abort` have no successors. Names
+    /// of blocks should be unique, otherwise labels cannot be resolved when the function is
+    /// printed and parsed back. See `uniquify_blocks`.
+    pub fn verify_cfg(&self) -> Vec<VerifyErr> {
+        let mut err = vec![];
+        let mut names = HashSet::new();
+        self.iter_dom().for_each(|block| {
+            let inst = block.inst.borrow();
+            let mut push = |instr: Option<&InstRef>, msg: String| {
+                let mut e = VerifyErr::new(&self.name, &block, instr, None, msg);
+                e.locate(self);
+                err.push(e)
+            };
+            if !names.insert(block.name.clone()) {
+                push(None, format!("block name %{} is not unique", block.name))
+            }
+            for instr in inst.iter().take(inst.len().saturating_sub(1)) {
+                if instr.is_ctrl() {
+                    push(Some(instr), format!("{} is not at the end of block", instr.name()))
+                }
+            }
+            match inst.back() {
+                Some(tail) if !tail.is_ctrl() => push(None, "block is not terminated".to_string()),
+                Some(tail) if (tail.is_ret() || tail.is_trap())
+                    && !block.succ.borrow().is_empty() =>
+                    push(Some(tail), format!("block ending with {} has successors", tail.name())),
+                None => push(None, "block is empty".to_string()),
+                _ => {}
+            }
+        });
+        err
+    }
+
+    /// Check whether the body of this function conforms to its attributes.
+    pub fn verify_attrib(&self) -> Vec<VerifyErr> {
+        let mut err = vec![];
+        self.iter_dom().for_each(|block| {
+            block.inst.borrow().iter().for_each(|instr| {
+                if let Some(msg) = self.check_instr_attrib(instr) {
+                    let mut e = VerifyErr::new(&self.name, &block, Some(instr), None, msg);
+                    e.loc = self.inst_loc(instr);
+                    err.push(e)
+                }
+            })
+        });
+        err
+    }
+
+    fn check_instr_attrib(&self, instr: &Inst) -> Option<String> {
+        let pure = self.has_attrib(FnAttrib::Pure);
+        match instr {
+            Inst::Ret { val: _ } if self.has_attrib(FnAttrib::NoReturn) =>
+                Some("cannot return from noreturn function".to_string()),
+            Inst::Call { func, arg: _, dst: _ } if pure && !func.has_attrib(FnAttrib::Pure) =>
+                Some(format!("cannot call non-pure function @{} in pure function", func.name)),
+            Inst::CallInd { func_ptr: _, arg: _, dst: _ } if pure =>
+                Some("cannot call function pointer in pure function".to_string()),
+            Inst::Ld { ptr: _, dst: _ } | Inst::Memcpy { src: _, ptr: _, len: _ } if pure =>
+                Some("cannot read memory in pure function".to_string()),
+            _ if pure && instr.src().iter().any(|v| v.borrow().is_global_var()) =>
+                Some("cannot read global variable in pure function".to_string()),
+            _ if self.is_readonly() && instr.has_side_effect() =>
+                Some(format!("instruction with side effect in {} function",
+                             if pure { "pure" } else { "readonly" })),
+            _ => None
+        }
+    }
+
+    /// Derive element type pointed to by result of a `ptr` instruction, by following indices
+    /// from target type of its base pointer.
+    fn ptr_elem_type(tgt: Type, ind: &[RefCell<Value>]) -> Result<Type, String> {
+        let mut elem = tgt;
+        for idx in ind {
+            let c = match idx.borrow().deref() {
+                Value::Const(c) => Some(c.as_i64()),
+                _ => None
+            };
+            elem = match elem.orig() {
+                Type::Array { elem, len } => match c {
+                    Some(c) if c < 0 || c as usize >= len =>
+                        return Err(format!("index {} out of range {}", c, len)),
+                    _ => elem.deref().clone()
+                }
+                Type::Struct { field } | Type::Union { field } => match c {
+                    Some(c) if c >= 0 && (c as usize) < field.len() => field[c as usize].clone(),
+                    Some(c) => return Err(format!("index {} out of range {}", c, field.len())),
+                    None => return Err("expect constant index for structure or union"
+                        .to_string())
+                }
+                ty => return Err(format!("cannot index into type {}", ty.to_string()))
+            };
+        }
+        Ok(elem)
+    }
+
+    fn check_instr_type(&self, instr: &Inst) -> Option<String> {
+        let ty_of = |v: &RefCell<Value>| v.borrow().get_type();
+        let expect = |exp: &Type, found: &Type| if exp == found { None } else {
+            Some(format!("expect type {}, found {}", exp.to_string(), found.to_string()))
+        };
+        let dst_ty = instr.dst().map(|dst| dst.borrow().get_type());
+        // Returned values must match return type in number and types
+        let expect_ret = |ret: &Type, found: Vec<Type>| {
+            let ret = ret.ret_types();
+            if ret.len() != found.len() {
+                return Some(format!("expect {} returned value(s), got {}", ret.len(),
+                                    found.len()));
+            }
+            ret.iter().zip(found.iter()).find_map(|(r, f)| expect(r, f))
+        };
+        let dst_tys = |dst: &Vec<RefCell<SymbolRef>>| -> Vec<Type> {
+            dst.iter().map(|d| d.borrow().get_type()).collect()
+        };
+        // Types of values constructed by API may have unsupported integer widths
+        let invalid = instr.dsts().into_iter().map(|d| d.borrow().get_type())
+            .chain(instr.src().into_iter().map(ty_of)).find(|ty| !ty.is_valid());
+        if let Some(ty) = invalid {
+            return Some(format!("type {} has unsupported integer width", ty.to_string()));
+        }
+        match instr {
+            Inst::Mov { src, dst: _ } => expect(dst_ty.as_ref().unwrap(), &ty_of(src)),
+            Inst::Un { op, opd, dst: _ } => match op.res_type(&ty_of(opd)) {
+                Some(res) => expect(&res, dst_ty.as_ref().unwrap()),
+                None => Some(format!("unary operation {} not supported for type {}",
+                                     op.to_string(), ty_of(opd).to_string()))
+            }
+            Inst::Bin { op, fst, snd, dst: _ } => {
+                if let Some(msg) = expect(&ty_of(fst), &ty_of(snd)) { return Some(msg); }
+                match op.res_type(&ty_of(fst)) {
+                    Some(_) if op.is_pred() => expect(&Type::I(1), dst_ty.as_ref().unwrap()),
+                    Some(res) => expect(&res, dst_ty.as_ref().unwrap()),
+                    None => Some(format!("binary operation {} not supported for type {}",
+                                         op.to_string(), ty_of(fst).to_string()))
+                }
+            }
+            Inst::Cast { op, opd, dst: _ } => if op.is_avail_for(&ty_of(opd), &dst_ty.unwrap()) {
+                None
+            } else {
+                Some(format!("invalid {} of type {}", op.to_string(), ty_of(opd).to_string()))
+            }
+            Inst::Call { func, arg, dst } => {
+                if arg.len() != func.param.len() {
+                    return Some(format!("expect {} argument(s), got {}", func.param.len(),
+                                        arg.len()));
+                }
+                for (a, p) in arg.iter().zip(func.param.iter()) {
+                    if let Some(msg) = expect(&p.borrow().get_type(), &ty_of(a)) {
+                        return Some(msg);
+                    }
+                }
+                if dst.is_empty() { None } else { expect_ret(&func.ret, dst_tys(dst)) }
+            }
+            Inst::CallInd { func_ptr, arg, dst } => {
+                let (param, ret) = match ty_of(func_ptr).fn_sig() {
+                    Some(sig) => sig,
+                    None => return Some(format!("expect function pointer, found {}",
+                                                ty_of(func_ptr).to_string()))
+                };
+                if arg.len() != param.len() {
+                    return Some(format!("expect {} argument(s), got {}", param.len(), arg.len()));
+                }
+                for (a, p) in arg.iter().zip(param.iter()) {
+                    if let Some(msg) = expect(p, &ty_of(a)) { return Some(msg); }
+                }
+                if dst.is_empty() { None } else { expect_ret(&ret, dst_tys(dst)) }
+            }
+            Inst::Ret { val } => expect_ret(&self.ret, val.iter().map(ty_of).collect()),
+            Inst::Unreachable | Inst::Abort { msg: _ } | Inst::Jmp { tgt: _ } => None,
+            Inst::Br { cond, tr: _, fls: _ } => expect(&Type::I(1), &ty_of(cond)),
+            Inst::Phi { src, dst: _ } => src.iter()
+                .find_map(|(_, v)| expect(dst_ty.as_ref().unwrap(), &ty_of(v))),
+            Inst::Alloc { dst: _ } => if dst_ty.unwrap().is_ptr() { None } else {
+                Some("expect pointer type".to_string())
+            }
+            Inst::New { dst: _, len } => {
+                if !dst_ty.unwrap().is_ptr() { return Some("expect pointer type".to_string()); }
+                len.as_ref().and_then(|len| match ty_of(len).orig() {
+                    Type::I(_) => None,
+                    ty => Some(format!("expect integer length, found {}", ty.to_string()))
+                })
+            }
+            Inst::Ptr { base, off, ind, dst: _ } => {
+                if !ty_of(base).is_ptr() || !dst_ty.as_ref().unwrap().is_ptr() {
+                    return Some("expect pointer type".to_string());
+                }
+                if let Some(msg) = off.iter().chain(ind.iter())
+                    .find_map(|i| expect(&Type::I(64), &ty_of(i))) { return Some(msg); }
+                match Self::ptr_elem_type(ty_of(base).tgt_type(), ind) {
+                    Ok(elem) => expect(&Type::Ptr(Box::new(elem)), dst_ty.as_ref().unwrap()),
+                    Err(msg) => Some(msg)
+                }
+            }
+            Inst::Ld { ptr, dst: _ } =>
+                expect(&Type::Ptr(Box::new(dst_ty.unwrap())), &ty_of(ptr)),
+            Inst::St { src, ptr } => expect(&Type::Ptr(Box::new(ty_of(src))), &ty_of(ptr)),
+            Inst::Memcpy { src, ptr, len } => {
+                if !ty_of(src).is_ptr() { return Some("expect pointer type".to_string()); }
+                expect(&ty_of(src), &ty_of(ptr)).or_else(|| expect(&Type::I(64), &ty_of(len)))
+            }
+            Inst::Memset { src, ptr, len } => expect(&Type::Ptr(Box::new(ty_of(src))), &ty_of(ptr))
+                .or_else(|| expect(&Type::I(64), &ty_of(len))),
+            Inst::AtomicLd { ord, ptr, dst: _ } if ord.is_avail_for_ld() =>
+                expect(&Type::Ptr(Box::new(dst_ty.unwrap())), &ty_of(ptr)),
+            Inst::AtomicSt { ord, src, ptr } if ord.is_avail_for_st() =>
+                expect(&Type::Ptr(Box::new(ty_of(src))), &ty_of(ptr)),
+            Inst::AtomicLd { ord, ptr: _, dst: _ } | Inst::AtomicSt { ord, src: _, ptr: _ } =>
+                Some(format!("invalid memory ordering {} for {}", ord.to_string(), instr.name())),
+            Inst::AtomicRmw { op, ord: _, ptr, val, dst: _ } => {
+                let ty = dst_ty.unwrap();
+                if !op.is_avail_for(&ty) {
+                    return Some(format!("operation {} not supported for type {}", op.to_string(),
+                                        ty.to_string()));
+                }
+                expect(&Type::Ptr(Box::new(ty.clone())), &ty_of(ptr))
+                    .or_else(|| expect(&ty, &ty_of(val)))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_type_check() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::value::Const;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut file = File::open("test/sum.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let pro = Builder::new(Parser::new(lexer).parse().unwrap()).build().unwrap();
+    let mut checker = TypeChecker::new();
+    assert!(checker.check(&pro));
+
+    // Break a phi and the result type of an instruction
+    let func = pro.func[0].clone();
+    let cond = func.dfs().find(|b| b.name == "Cond").unwrap();
+    let phi = cond.inst.borrow()[0].clone();
+    if let Inst::Phi { src, dst: _ } = phi.as_ref() {
+        let init = src[0].0.borrow().clone();
+        src[1].0.replace(init);
+    }
+    let mul = cond.inst.borrow()[2].clone();
+    mul.src()[1].replace(Value::Const(Const::I64(4)));
+    assert!(!checker.check(&pro));
+    for e in &checker.err { println!("{}", e) }
+    let msg: Vec<_> = checker.err.iter().map(|e| e.msg.as_str()).collect();
+    assert_eq!(msg, ["expect type i32, found i64", "duplicate phi operand for %Init",
+        "phi operand not found for %Loop"]);
+}
@@ -1,4 +1,21 @@
 pub mod lang;
 pub mod irc;
 pub mod pass;
-pub mod vm;
\ No newline at end of file
+pub mod back;
+pub mod vm;
+pub mod driver;
+pub mod test_util;
+pub mod prelude;
+
+pub use crate::driver::{run_source, RunErr};
+pub use crate::irc::CompileErr;
+pub use crate::lang::Program;
+pub use crate::lang::func::Fn;
+pub use crate::lang::inst::Inst;
+pub use crate::pass::Pass;
+
+/// Former name of `irc`, the front end of the language
+#[deprecated(note = "use `irl::irc` instead")]
+pub mod compile {
+    pub use crate::irc::*;
+}
@@ -13,6 +13,8 @@ use crate::pass::{FnPass, Pass};
 
 pub struct AdceOpt {
     rev_df: HashMap<BlockRef, Vec<BlockRef>>,
+    /// Immediate post-dominator of each block
+    ipdom: HashMap<BlockRef, BlockRef>,
     def_use: HashMap<SymbolRef, DefUse>,
     blk: HashSet<BlockRef>,
     instr: HashSet<InstRef>,
@@ -20,16 +22,21 @@ pub struct AdceOpt {
 }
 
 impl Pass for AdceOpt {
-    fn run(&mut self, pro: &mut Program) { FnPass::run(self, pro) }
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
 }
 
 impl FnPass for AdceOpt {
-    fn run_on_fn(&mut self, f: &FnRef) {
+    fn run_on_fn(&mut self, f: &FnRef) -> bool {
         // ADCE requires SSA form
         f.assert_ssa();
 
+        // Post-dominance is not defined for functions that never exit, such as `noreturn` ones.
+        if f.exit.borrow().is_empty() { return false; }
+
         // Build control dependence graph
-        self.rev_df = Self::rev_df(f);
+        let (rev_df, ipdom) = Self::rev_df(f);
+        self.rev_df = rev_df;
+        self.ipdom = ipdom;
 
         // Get def-use information for this function
         self.def_use = f.def_use();
@@ -54,9 +61,10 @@ impl FnPass for AdceOpt {
             }
         }
 
+        let mut changed = false;
         f.iter_dom().for_each(|blk| {
             // Remove unmarked instruction
-            blk.inst.borrow_mut().retain(|instr| {
+            changed |= blk.retain(|instr| {
                 match instr.as_ref() {
                     // Keep all control flow instructions
                     ctrl if ctrl.is_ctrl() => true,
@@ -65,23 +73,22 @@ impl FnPass for AdceOpt {
                 }
             });
 
-            // Deal with conditional branch
-            if let Inst::Br { cond: _, tr, fls } = blk.tail().as_ref() {
-                let mut tgt = vec![tr.borrow().clone(), fls.borrow().clone()];
-                tgt.retain(|blk| self.blk.contains(blk));
-                match tgt.len() {
-                    // Two blocks all active
-                    2 => {}
-                    // Only one is active, modify the control flow in this block.
-                    1 => {
-                        let succ = tgt[0].clone();
+            // Deal with conditional branch. A branch which is not marked decides no active
+            // instruction, so it can be replaced by a jump to its immediate post-dominator.
+            let tail = blk.tail();
+            if let Inst::Br { cond: _, tr, fls } = tail.as_ref() {
+                match self.ipdom.get(&blk) {
+                    Some(succ) if !self.instr.contains(&tail) => {
+                        blk.disconnect(&tr.borrow());
+                        blk.disconnect(&fls.borrow());
+                        blk.connect(succ.clone());
                         let jmp = ExtRc::new(Inst::Jmp {
                             tgt: RefCell::new(succ.clone())
                         });
                         *blk.inst.borrow_mut().back_mut().unwrap() = jmp;
-                        blk.succ.replace(vec![succ]);
+                        changed = true;
                     }
-                    _ => unreachable!()
+                    _ => {}
                 }
             }
         });
@@ -92,6 +99,7 @@ impl FnPass for AdceOpt {
         // Clear data structure for this function
         self.instr.clear();
         self.blk.clear();
+        changed
     }
 }
 
@@ -99,6 +107,7 @@ impl AdceOpt {
     pub fn new() -> AdceOpt {
         AdceOpt {
             rev_df: Default::default(),
+            ipdom: Default::default(),
             def_use: Default::default(),
             blk: Default::default(),
             instr: Default::default(),
@@ -122,6 +131,15 @@ impl AdceOpt {
             })
         });
 
+        // The value of a phi depends on the branches to its block
+        if let Inst::Phi { src, dst: _ } = instr.deref() {
+            src.iter().for_each(|(pred, _)| {
+                let pred = pred.borrow().clone();
+                let tail = pred.tail();
+                self.work.insert((pred, tail))
+            })
+        }
+
         // Add the definition points of its operands to work list
         instr.src().iter().for_each(|src| {
             let src = src.borrow().clone();
@@ -136,11 +154,15 @@ impl AdceOpt {
         });
     }
 
-    /// Compute reverse dominance frontier for a given function
-    fn rev_df(f: &FnRef) -> HashMap<BlockRef, Vec<BlockRef>> {
+    /// Compute reverse dominance frontier and immediate post-dominators for a given function
+    fn rev_df(f: &FnRef) -> (HashMap<BlockRef, Vec<BlockRef>>, HashMap<BlockRef, BlockRef>) {
         // Build post-dominator tree
         let root = RevVert::Exit(f.clone());
         let parent = DomBuilder::new(root.clone()).build();
+        let ipdom = parent.iter().filter_map(|(c, p)| match (c, p) {
+            (RevVert::Block(c, _), RevVert::Block(p, _)) => Some((c.clone(), p.clone())),
+            _ => None
+        }).collect();
         let mut child: HashMap<_, Vec<_>> = HashMap::new();
         parent.iter().for_each(|(c, p)| {
             match child.get_mut(p) {
@@ -171,7 +193,7 @@ impl AdceOpt {
                 })
             }
         });
-        blk_df
+        (blk_df, ipdom)
     }
 }
 
@@ -243,4 +265,115 @@ fn test_adce() {
     let mut out = stdout();
     let mut printer = Printer::new(out.borrow_mut());
     printer.print(&pro).unwrap();
-}
\ No newline at end of file
+}
+#[test]
+fn test_attrib() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::irc::ErrKind;
+    use crate::lang::print::Printer;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+    use std::io::stdout;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+    use std::borrow::BorrowMut;
+
+    let mut file = File::open("test/attrib.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let mut pro = builder.build().unwrap();
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut pro);
+    assert!(ver.is_ok());
+    FnPass::run(&mut AdceOpt::new(), &mut pro);
+
+    let mut out = stdout();
+    let mut printer = Printer::new(out.borrow_mut());
+    printer.print(&pro).unwrap();
+    let main = pro.func.iter().find(|f| f.name == "main").unwrap();
+    let calls: Vec<_> = main.dfs().flat_map(|b| b.inst.borrow().clone()).filter_map(|i| {
+        if let Inst::Call { func, arg: _, dst: _ } = i.as_ref() { Some(func.name.clone()) } else {
+            None
+        }
+    }).collect();
+    assert_eq!(calls, vec!["get", "set", "spin"]);
+
+    let mut mach = Machine::new();
+    let rcd = mach.run(&mut pro).unwrap();
+    println!("{:?}", rcd);
+
+    // Attribute violations
+    let src = "[pure]\nfn @f() -> i32 {\n%B:\n    $x <- mov i32 @g\n    ret $x\n}\n\
+               @g: i32\n[noreturn]\nfn @main() {\n%B:\n    ret\n}";
+    let tree = Parser::new(Lexer::from(src)).parse().unwrap();
+    let mut pro = Builder::new(tree).build().unwrap();
+    Pass::run(&mut ver, &mut pro);
+    for e in &ver.err { println!("{}", e) }
+    assert_eq!(ver.err.len(), 2);
+    let src = "[inline, noinline]\nfn @main() {\n%B:\n    ret\n}";
+    let tree = Parser::new(Lexer::from(src)).parse().unwrap();
+    let err = Builder::new(tree).build().err().unwrap();
+    assert!(matches!(err.kind(), ErrKind::ConflictingAttrib(_, _)));
+}
+
+#[test]
+fn test_trap() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::lang::value::Const;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+[ssa]
+fn @check($x: i64) -> i64 {
+%Begin:
+    $c <- lt i64 $x, 0
+    br $c ? %Fail : %Pos
+%Fail:
+    abort "negative"
+%Pos:
+    $d <- eq i64 $x, 0
+    br $d ? %Zero : %End
+%Zero:
+    unreachable
+%End:
+    ret $x
+}
+"#;
+    let build = |src: &str| Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build();
+    let mut pro = build(src).unwrap();
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut pro);
+    assert!(ver.is_ok());
+
+    // Branches to aborting blocks are kept, as the aborts have side effects
+    FnPass::run(&mut AdceOpt::new(), &mut pro);
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    assert!(out.contains("abort \"negative\"") && out.contains("unreachable"));
+    let run = |arg: i64| Machine::new().run_fn(&pro, &pro.func[0], vec![Const::I64(arg)])
+        .map(|(ret, _)| ret[0].get_const()).map_err(|e| e.msg().to_string());
+    assert_eq!(run(2), Ok(Const::I64(2)));
+    assert_eq!(run(-1), Err("abort: negative".to_string()));
+    assert_eq!(run(0), Err("reached unreachable instruction".to_string()));
+    assert!(build(&out).is_ok());
+
+    // Traps must terminate blocks
+    let mut pro = build(src).unwrap();
+    let block = pro.func[0].ent.borrow().clone();
+    block.inst.borrow_mut().push_front(ExtRc::new(Inst::Unreachable));
+    Pass::run(&mut ver, &mut pro);
+    for e in &ver.err { println!("{}", e) }
+    assert_eq!(ver.err.len(), 1);
+    assert_eq!(ver.err[0].index, Some(0));
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::lang::func::FnRef;
+use crate::lang::liveness::Liveness;
+use crate::lang::ssa::DefUseGraph;
+use crate::pass::util::LoopNodeRef;
+
+/// Analysis of a function which can be shared by passes
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Analysis {
+    /// Dominator tree, which is stored in the function itself
+    DomTree,
+    /// Def-use graph of SSA variables
+    DefUse,
+    /// Loop-nest forest
+    Loops,
+    /// Live variables at block boundaries
+    Liveness,
+}
+
+impl Analysis {
+    /// Analyses depending only on the CFG, which are preserved by passes that do not add,
+    /// remove or redirect edges.
+    pub const CFG: &'static [Analysis] = &[Analysis::DomTree, Analysis::Loops];
+}
+
+/// Analyses computed for a function
+#[derive(Default)]
+struct FnAnalyses {
+    /// Whether the dominator tree stored in the function is known to be up to date
+    dom: bool,
+    def_use: Option<DefUseGraph>,
+    loops: Option<Vec<LoopNodeRef>>,
+    liveness: Option<Rc<Liveness>>,
+}
+
+/// Cache of function analyses shared by passes in a pipeline.
+/// Analyses are computed on first request, and reused until invalidated by a pass that changes
+/// the program without preserving them. Handles returned are shared with the cache, so a pass
+/// should not modify them unless it also reports a change that does not preserve them.
+#[derive(Default)]
+pub struct AnalysisCache {
+    map: HashMap<FnRef, FnAnalyses>,
+    /// Number of analyses computed so far
+    computed: usize,
+}
+
+impl AnalysisCache {
+    pub fn new() -> AnalysisCache { Default::default() }
+
+    /// Make sure the dominator tree of `func` is up to date.
+    pub fn dom_tree(&mut self, func: &FnRef) {
+        let entry = self.map.entry(func.clone()).or_default();
+        if !entry.dom {
+            func.build_dom();
+            entry.dom = true;
+            self.computed += 1;
+        }
+    }
+
+    /// Get def-use graph of `func`, which should be in SSA form.
+    pub fn def_use(&mut self, func: &FnRef) -> DefUseGraph {
+        let entry = self.map.entry(func.clone()).or_default();
+        if let Some(graph) = &entry.def_use { return graph.clone(); }
+        let graph = DefUseGraph::new(func);
+        entry.def_use = Some(graph.clone());
+        self.computed += 1;
+        graph
+    }
+
+    /// Get loop-nest forest of `func`. The dominator tree is also brought up to date.
+    pub fn loops(&mut self, func: &FnRef) -> Vec<LoopNodeRef> {
+        self.dom_tree(func);
+        let entry = self.map.get_mut(func).unwrap();
+        if let Some(loops) = &entry.loops { return loops.clone(); }
+        let loops = func.analyze_loop();
+        entry.loops = Some(loops.clone());
+        self.computed += 1;
+        loops
+    }
+
+    /// Get liveness information of `func`.
+    pub fn liveness(&mut self, func: &FnRef) -> Rc<Liveness> {
+        let entry = self.map.entry(func.clone()).or_default();
+        if let Some(live) = &entry.liveness { return live.clone(); }
+        let live = Rc::new(func.liveness());
+        entry.liveness = Some(live.clone());
+        self.computed += 1;
+        live
+    }
+
+    /// Whether `analysis` of `func` is cached.
+    pub fn is_cached(&self, func: &FnRef, analysis: Analysis) -> bool {
+        self.map.get(func).is_some_and(|entry| match analysis {
+            Analysis::DomTree => entry.dom,
+            Analysis::DefUse => entry.def_use.is_some(),
+            Analysis::Loops => entry.loops.is_some(),
+            Analysis::Liveness => entry.liveness.is_some(),
+        })
+    }
+
+    /// Drop all analyses of all functions, except the ones in `preserved`.
+    pub fn invalidate(&mut self, preserved: &[Analysis]) {
+        let keep = |a| preserved.contains(&a);
+        self.map.values_mut().for_each(|entry| {
+            entry.dom &= keep(Analysis::DomTree);
+            if !keep(Analysis::DefUse) { entry.def_use = None; }
+            if !keep(Analysis::Loops) { entry.loops = None; }
+            if !keep(Analysis::Liveness) { entry.liveness = None; }
+        })
+    }
+
+    /// Number of analyses computed, rather than taken from the cache, so far
+    pub fn computed(&self) -> usize { self.computed }
+}
+
+#[test]
+fn test_analysis() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::Pass;
+    use crate::pass::copy::CopyProp;
+    use crate::pass::fold::ConstFold;
+    use crate::pass::licm::LicmOpt;
+    use crate::pass::lsr::LsrOpt;
+    use crate::pass::manager::PassManager;
+    use crate::pass::util::{DceOpt, PtrExp};
+    use crate::vm::exec::Machine;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let build = || {
+        let mut file = File::open("test/mat.ir").unwrap();
+        let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+        Builder::new(Parser::new(lexer).parse().unwrap()).build().unwrap()
+    };
+
+    // Analyses are reused until invalidated
+    let pro = build();
+    let func = pro.func.iter().find(|f| !f.analyze_loop().is_empty()).unwrap().clone();
+    let mut cache = AnalysisCache::new();
+    let loops = cache.loops(&func);
+    let def_use = cache.def_use(&func);
+    assert_eq!(cache.computed(), 3);
+    assert_eq!(cache.loops(&func).len(), loops.len());
+    cache.def_use(&func);
+    assert_eq!(cache.computed(), 3);
+    cache.invalidate(Analysis::CFG);
+    assert!(cache.is_cached(&func, Analysis::Loops));
+    assert!(!cache.is_cached(&func, Analysis::DefUse));
+    cache.invalidate(&[]);
+    assert!(!cache.is_cached(&func, Analysis::DomTree));
+    drop(def_use);
+
+    // Passes in a pipeline share analyses, and results are not affected
+    let expect = format!("{:?}", Machine::new().run(&pro).unwrap().global);
+    let mut mgr = PassManager::new().add("ptr", PtrExp::new()).add("licm", LicmOpt::new())
+        .add("lsr", LsrOpt::new()).add("copy", CopyProp::new()).add("fold", ConstFold::new())
+        .add("dce", DceOpt::new());
+    let mut pro = build();
+    mgr.run(&mut pro);
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+}
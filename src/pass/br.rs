@@ -0,0 +1,390 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{BinOp, Inst, InstRef};
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Const, SymbolRef, Value};
+use crate::pass::{FnPass, Pass};
+use crate::pass::analysis::Analysis;
+use crate::pass::remark::Remark;
+
+/// Conditional Branch Folding
+/// A branch whose targets are the same block is replaced by a jump to it. In SSA functions,
+/// a branch taken from a block with a single predecessor tells the value of its condition in
+/// blocks dominated by the target. If the condition is the result of comparing a variable with a
+/// constant, it also tells the relation between them, where the comparison is canonicalized to
+/// have the variable on the left. Facts on the same variable and constant are combined as they
+/// are collected along the dominator tree, so `lt $x, 5` being false and `ne $x, 5` being true
+/// decide `gt $x, 5`. Branches on conditions decided by these facts are folded to jumps, and
+/// edges to the targets not taken are removed along with the phi operands from them.
+pub struct BrFold {
+    /// Remarks made in the last run
+    remark: Vec<Remark>,
+}
+
+/// Fact that the relation between `val` and `cst` is one in `rel`, a set of `LT`, `EQ` and `GT`
+type Fact = (Value, Const, u8);
+
+const LT: u8 = 1;
+const EQ: u8 = 2;
+const GT: u8 = 4;
+const ANY: u8 = LT | EQ | GT;
+
+impl Pass for BrFold {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.remark.clear();
+        FnPass::run(self, pro)
+    }
+
+    fn preserved(&self) -> &'static [Analysis] { &[Analysis::DomTree] }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
+}
+
+impl FnPass for BrFold {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        let mut def = HashMap::new();
+        if func.ssa.get() {
+            for block in func.dfs() {
+                for instr in block.inst.borrow().iter() {
+                    if let Some(dst) = instr.dst() {
+                        def.insert(dst.borrow().clone(), instr.clone());
+                    }
+                }
+            }
+        }
+        let n_rm = self.remark.len();
+        self.visit(func, &func.ent.borrow(), &def, &mut vec![]);
+        if self.remark.len() == n_rm { return false; }
+
+        // Rebuild dominator tree and scope, since the structure of CFG has changed
+        func.build_dom();
+        if func.ssa.get() { func.rebuild_ssa_scope(); }
+        true
+    }
+}
+
+impl BrFold {
+    pub fn new() -> BrFold { BrFold { remark: vec![] } }
+
+    /// Fold branch of `block` with known `facts`, and visit its children in dominator tree.
+    fn visit(&mut self, func: &FnRef, block: &BlockRef, def: &HashMap<SymbolRef, InstRef>,
+             facts: &mut Vec<Fact>) {
+        // Fold branch of this block
+        if let Inst::Br { cond, tr, fls } = block.tail().as_ref() {
+            let (tr, fls) = (tr.borrow().clone(), fls.borrow().clone());
+            let taken = if tr == fls {
+                Some(true)
+            } else {
+                Self::eval(&cond.borrow(), def, facts)
+            };
+            if let Some(taken) = taken {
+                if tr == fls {
+                    *block.inst.borrow_mut().back_mut().unwrap() = ExtRc::new(Inst::Jmp {
+                        tgt: RefCell::new(tr.clone())
+                    });
+                } else {
+                    block.remove_edge(if taken { &fls } else { &tr });
+                }
+                let tgt = if taken { &tr } else { &fls };
+                self.remark.push(Remark::applied(
+                    &func.name, format!("folded branch in %{} to %{}", block.name, tgt.name)));
+            }
+        }
+
+        // Visit children with facts from branch of this block. Variables may be reassigned in
+        // non-SSA functions, so facts on them do not hold in dominated blocks.
+        for child in block.children() {
+            let len = facts.len();
+            if let (Inst::Br { cond, tr, fls }, [pred]) = (block.tail().as_ref(),
+                                                           child.pred().as_slice()) {
+                let taken = tr.borrow().deref() == &child;
+                if func.ssa.get() && pred == block && taken != (fls.borrow().deref() == &child) {
+                    Self::add_facts(&cond.borrow(), taken, def, facts);
+                }
+            }
+            self.visit(func, &child, def, facts);
+            facts.truncate(len);
+        }
+    }
+
+    /// Add facts implied by `cond` being `taken`.
+    fn add_facts(cond: &Value, taken: bool, def: &HashMap<SymbolRef, InstRef>,
+                 facts: &mut Vec<Fact>) {
+        let holds = |rel: u8| if taken { rel } else { ANY & !rel };
+        facts.push((cond.clone(), Const::I1(true), holds(EQ)));
+        if let Some((x, c, rel)) = Self::cmp_with_const(cond, def) {
+            facts.push((x, c, holds(rel)));
+        }
+    }
+
+    /// Decide value of `cond` from `facts`, if possible.
+    fn eval(cond: &Value, def: &HashMap<SymbolRef, InstRef>, facts: &[Fact]) -> Option<bool> {
+        if let Value::Const(Const::I1(c)) = cond { return Some(*c); }
+        if let Some(b) = Self::decide(Self::known(cond, &Const::I1(true), facts), EQ) {
+            return Some(b);
+        }
+        let (x, c, rel) = Self::cmp_with_const(cond, def)?;
+        if let Some(b) = Self::decide(Self::known(&x, &c, facts), rel) { return Some(b); }
+
+        // The variable may be known to equal another constant
+        facts.iter().rev().find_map(|(v, k, k_rel)| match *k_rel == EQ && *v == x {
+            true => Some(Self::rel_between(k, &c) & rel != 0),
+            false => None
+        })
+    }
+
+    /// Combine facts on relation between `val` and `cst`.
+    fn known(val: &Value, cst: &Const, facts: &[Fact]) -> u8 {
+        facts.iter().filter(|(v, k, _)| v == val && k == cst).fold(ANY, |rel, f| rel & f.2)
+    }
+
+    /// Decide whether relation in `rel` holds, given that the actual one is in `known`.
+    fn decide(known: u8, rel: u8) -> Option<bool> {
+        match known {
+            0 | ANY => None,
+            k if k & !rel == 0 => Some(true),
+            k if k & rel == 0 => Some(false),
+            _ => None
+        }
+    }
+
+    /// Relation between two constants of the same type
+    fn rel_between(a: &Const, b: &Const) -> u8 {
+        match a.as_i64().cmp(&b.as_i64()) {
+            Ordering::Less => LT,
+            Ordering::Equal => EQ,
+            Ordering::Greater => GT
+        }
+    }
+
+    /// If `cond` is defined by comparing a variable with a constant, return the variable, the
+    /// constant, and the relations between them that make the condition true. A constant on the
+    /// left is moved to the right, with the relations swapped. `def` is only built for SSA
+    /// functions, where neither the condition nor the variable can be redefined.
+    fn cmp_with_const(cond: &Value, def: &HashMap<SymbolRef, InstRef>)
+                      -> Option<(Value, Const, u8)> {
+        let sym = match cond {
+            Value::Var(sym) => sym,
+            _ => return None
+        };
+        let (op, fst, snd) = match def.get(sym)?.as_ref() {
+            Inst::Bin { op, fst, snd, dst: _ } if op.is_cmp() => (*op, fst, snd),
+            _ => return None
+        };
+        let rel = match op {
+            BinOp::Eq => EQ,
+            BinOp::Ne => LT | GT,
+            BinOp::Lt => LT,
+            BinOp::Le => LT | EQ,
+            BinOp::Gt => GT,
+            BinOp::Ge => GT | EQ,
+            _ => return None
+        };
+        let swap = |rel: u8| (rel & EQ) | if rel & LT != 0 { GT } else { 0 }
+            | if rel & GT != 0 { LT } else { 0 };
+        match (fst.borrow().deref(), snd.borrow().deref()) {
+            (Value::Var(x), Value::Const(c)) => Some((Value::Var(x.clone()), *c, rel)),
+            (Value::Const(c), Value::Var(x)) => Some((Value::Var(x.clone()), *c, swap(rel))),
+            _ => None
+        }
+    }
+}
+
+#[test]
+fn test_br_fold() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::manager::PassManager;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64 <- 2
+
+[ssa]
+fn @main() {
+%Begin:
+    $x <- mov i64 @r
+    $c <- eq i64 $x, 2
+    br $c ? %Two : %Other
+%Two:
+    $d <- ne i64 $x, 2
+    br $d ? %Bad : %Same
+%Same:
+    br $c ? %Join : %Join
+%Other:
+    $e <- eq i64 3, $x
+    br $e ? %Three : %Join
+%Three:
+    $f <- eq i64 $x, 2
+    br $f ? %Bad : %Join
+%Bad:
+    abort "unreachable"
+%Join:
+    $y <- phi i64 [%Same: 1] [%Other: 2] [%Three: 3]
+    @r <- mov i64 $y
+    ret
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    let mut mgr = PassManager::new().add("br", BrFold::new());
+    mgr.run(&mut pro);
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+
+    let report: Vec<_> = mgr.remarks().iter().map(|r| r.to_string()).collect();
+    assert_eq!(report, [
+        "br: applied in @main: folded branch in %Three to %Join",
+        "br: applied in @main: folded branch in %Two to %Same",
+        "br: applied in @main: folded branch in %Same to %Join",
+    ]);
+    assert!(!out.contains("%Bad"));
+    assert!(out.contains("jmp %Same") && out.contains("br $e ? %Three : %Join"));
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut pro);
+    assert!(ver.is_ok());
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
+
+#[test]
+fn test_br_ord() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::manager::PassManager;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64 <- 0
+@s: i64 <- 0
+
+[ssa]
+fn @main() {
+%Begin:
+    $x <- mov i64 @s
+    $c <- lt i64 $x, 5
+    br $c ? %Small : %Large
+%Small:
+    $d <- ge i64 $x, 5
+    br $d ? %Bad : %NotFive
+%NotFive:
+    $e <- gt i64 5, $x
+    br $e ? %Join : %Bad
+%Large:
+    $f <- ne i64 $x, 5
+    br $f ? %Above : %Join
+%Above:
+    $g <- gt i64 $x, 5
+    br $g ? %Join : %Bad
+%Bad:
+    abort "unreachable"
+%Join:
+    $y <- phi i64 [%NotFive: 1] [%Large: 2] [%Above: 3]
+    @r <- mov i64 $y
+    ret
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    let mut mgr = PassManager::new().add("br", BrFold::new());
+    mgr.run(&mut pro);
+
+    let report: Vec<_> = mgr.remarks().iter().map(|r| r.to_string()).collect();
+    assert_eq!(report, [
+        "br: applied in @main: folded branch in %Above to %Join",
+        "br: applied in @main: folded branch in %Small to %NotFive",
+        "br: applied in @main: folded branch in %NotFive to %Join",
+    ]);
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut pro);
+    assert!(ver.is_ok());
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
+
+#[test]
+fn test_br_non_ssa() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::manager::PassManager;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64 <- 0
+@s: i1 <- 1
+
+fn @main() {
+%Begin:
+    $c <- mov i1 @s
+    br $c ? %Then : %End
+%Then:
+    $c <- mov i1 0
+    br $c ? %One : %Two
+%One:
+    @r <- mov i64 1
+    jmp %End
+%Two:
+    @r <- mov i64 2
+    jmp %End
+%End:
+    ret
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    let mut mgr = PassManager::new().add("br", BrFold::new());
+    mgr.run(&mut pro);
+    assert!(mgr.remarks().is_empty());
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
+
+#[test]
+fn test_br_ord_non_ssa() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::manager::PassManager;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64 <- 0
+@s: i64 <- 3
+
+fn @main() {
+%Begin:
+    $x <- mov i64 @s
+    $c <- lt i64 $x, 5
+    br $c ? %Small : %End
+%Small:
+    $x <- mov i64 7
+    $d <- ge i64 $x, 5
+    br $d ? %Large : %End
+%Large:
+    @r <- mov i64 1
+    jmp %End
+%End:
+    ret
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    let mut mgr = PassManager::new().add("br", BrFold::new());
+    mgr.run(&mut pro);
+    assert!(mgr.remarks().is_empty());
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
@@ -0,0 +1,126 @@
+use std::cell::RefCell;
+use std::ops::Deref;
+
+use crate::lang::func::FnRef;
+use crate::lang::inst::{BinOp, Inst, PhiSrc};
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::Value;
+use crate::pass::{FnPass, Pass};
+
+/// Instruction Canonicalization
+/// Instructions computing the same value are rewritten to the same form, so that they are
+/// easier to match in value numbering, and the printed IR is stable:
+/// * Constant operand of commutative operator is moved to the right.
+/// * `gt` and `ge` are converted to `lt` and `le` with operands swapped.
+/// * Sources of phi instructions are ordered as the predecessors of their blocks.
+pub struct Canonicalize {}
+
+impl Canonicalize {
+    pub fn new() -> Canonicalize { Canonicalize {} }
+}
+
+impl Pass for Canonicalize {
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+}
+
+impl FnPass for Canonicalize {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        let mut changed = false;
+        func.iter_dom().for_each(|block| {
+            let pred = block.pred();
+            for instr in block.inst.borrow_mut().iter_mut() {
+                let new = match instr.as_ref() {
+                    Inst::Bin { op, fst, snd, dst: _ } if op.is_comm() => {
+                        let is_const = |v: &RefCell<Value>| matches!(v.borrow().deref(),
+                            Value::Const(_));
+                        if is_const(fst) && !is_const(snd) {
+                            fst.swap(snd);
+                            changed = true;
+                        }
+                        None
+                    }
+                    Inst::Bin { op: op @ BinOp::Gt, fst, snd, dst }
+                    | Inst::Bin { op: op @ BinOp::Ge, fst, snd, dst } => Some(Inst::Bin {
+                        op: if *op == BinOp::Gt { BinOp::Lt } else { BinOp::Le },
+                        fst: snd.clone(),
+                        snd: fst.clone(),
+                        dst: dst.clone(),
+                    }),
+                    Inst::Phi { src, dst } => {
+                        let pos = |(b, _): &PhiSrc| pred.iter()
+                            .position(|p| p == b.borrow().deref()).unwrap_or(pred.len());
+                        let mut sorted = src.clone();
+                        sorted.sort_by_key(pos);
+                        let same = sorted.iter().zip(src.iter())
+                            .all(|((a, _), (b, _))| a.borrow().deref() == b.borrow().deref());
+                        if same { None } else { Some(Inst::Phi { src: sorted, dst: dst.clone() }) }
+                    }
+                    _ => None
+                };
+                if let Some(new) = new {
+                    let new = ExtRc::new(new);
+                    func.move_inst_meta(instr, &new);
+                    *instr = new;
+                    changed = true;
+                }
+            }
+        });
+        changed
+    }
+}
+
+#[test]
+fn test_canon() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+
+fn @main() {
+%Begin:
+    jmp %Loop
+%Loop:
+    $s <- phi i64 [%Body: $s.1] [%Begin: 0]
+    $i <- phi i64 [%Begin: 0] [%Body: $i.1]
+    $c <- gt i64 10, $i
+    br $c ? %Body : %End
+%Body:
+    $t <- mul i64 2, $i
+    $s.1 <- add i64 1, $t
+    $i.1 <- add i64 $i, 1
+    $d <- ge i64 $s.1, 7
+    br $d ? %End : %Loop
+%End:
+    @r <- mov i64 $s
+    ret
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    Pass::run(&mut Canonicalize::new(), &mut pro);
+    Printer::new(&mut std::io::stdout()).print(&pro).unwrap();
+
+    let func = &pro.func[0];
+    func.iter_dom().for_each(|block| block.inst.borrow().iter().for_each(|instr| {
+        match instr.as_ref() {
+            Inst::Bin { op, fst, snd: _, dst: _ } => {
+                assert!(op != &BinOp::Gt && op != &BinOp::Ge);
+                if op.is_comm() { assert!(!matches!(fst.borrow().deref(), Value::Const(_))) }
+            }
+            Inst::Phi { src, dst: _ } => {
+                let src: Vec<_> = src.iter().map(|(b, _)| b.borrow().clone()).collect();
+                let pred: Vec<_> = block.pred().into_iter()
+                    .filter(|p| src.contains(p)).collect();
+                assert_eq!(src, pred);
+            }
+            _ => {}
+        }
+    }));
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
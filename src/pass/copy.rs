@@ -1,15 +1,18 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ops::Deref;
+use std::rc::Rc;
 
 use crate::lang::func::{BlockRef, DomTreeListener, Fn, FnRef};
 use crate::lang::inst::{Inst, InstRef};
 use crate::lang::Program;
 use crate::lang::ssa::{InstListener, ValueListener};
-use crate::lang::value::{SymbolRef, Value};
+use crate::lang::value::{Scope, SymbolRef, Value};
 use crate::pass::{FnPass, Pass};
+use crate::pass::analysis::Analysis;
 
 /// Copy Propagation
+/// Uses of copies are replaced with their sources, and the propagated copies are removed.
 pub struct CopyProp {}
 
 impl CopyProp {
@@ -17,23 +20,31 @@ impl CopyProp {
 }
 
 impl Pass for CopyProp {
-    fn run(&mut self, pro: &mut Program) { FnPass::run(self, pro) }
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+
+    fn preserved(&self) -> &'static [Analysis] { Analysis::CFG }
 }
 
 impl FnPass for CopyProp {
-    fn run_on_fn(&mut self, func: &FnRef) {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
         func.assert_ssa();
         let mut listener = CopyListener {
             map: Default::default(),
             def: vec![],
+            scope: func.scope.clone(),
+            changed: false,
         };
-        func.walk_dom(&mut listener)
+        func.walk_dom(&mut listener);
+        listener.changed
     }
 }
 
 struct CopyListener {
     map: HashMap<SymbolRef, Value>,
     def: Vec<Vec<SymbolRef>>,
+    scope: Rc<Scope>,
+    /// Whether any copy is propagated
+    changed: bool,
 }
 
 impl DomTreeListener for CopyListener {
@@ -44,6 +55,20 @@ impl DomTreeListener for CopyListener {
     fn on_enter(&mut self, block: BlockRef) {
         self.def.push(vec![]);
         InstListener::on_enter(self, block.clone());
+
+        // Remove propagated copies. All their uses are dominated by this block, so they will be
+        // replaced when visited.
+        let mut cursor = block.cursor();
+        while let Some(instr) = cursor.next() {
+            match instr.dst() {
+                Some(dst) if self.map.contains_key(dst.borrow().deref()) => {
+                    self.scope.remove(dst.borrow().name());
+                    cursor.erase();
+                    self.changed = true;
+                }
+                _ => {}
+            }
+        }
     }
 
     fn on_exit(&mut self, _block: BlockRef) {
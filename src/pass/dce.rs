@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use std::ops::Deref;
+
+use crate::lang::func::FnRef;
+use crate::lang::inst::Inst;
+use crate::lang::Program;
+use crate::lang::value::{GlobalVarRef, Symbol, SymbolRef, Value};
+use crate::pass::Pass;
+
+/// Whole-program Dead Global Code Elimination
+/// A function is removed if it cannot be reached from an entry point or any visible function,
+/// either by calls or by taking its address. Entry points are `@main` and functions marked
+/// `entry`. A global variable is removed if it is not visible and no reachable function loads or
+/// stores it. If there is no entry point, the entrance of the module is unknown, so all functions
+/// are kept.
+pub struct GlobalDce {
+    /// Functions reachable from the roots
+    live_fn: HashSet<FnRef>,
+    /// Global variables referred to by reachable functions
+    live_var: HashSet<GlobalVarRef>,
+}
+
+impl GlobalDce {
+    pub fn new() -> GlobalDce {
+        GlobalDce {
+            live_fn: Default::default(),
+            live_var: Default::default(),
+        }
+    }
+
+    fn visit_fn(&mut self, func: &FnRef) {
+        if !self.live_fn.insert(func.clone()) { return; }
+        func.iter_dom().for_each(|block| {
+            block.inst.borrow().iter().for_each(|instr| {
+                if let Inst::Call { func, arg: _, dst: _ } = instr.as_ref() {
+                    self.visit_fn(func)
+                }
+                instr.src().into_iter().for_each(|opd| {
+                    if let Value::Var(sym) = opd.borrow().deref() { self.visit_sym(sym) }
+                });
+                instr.dsts().iter().for_each(|dst| self.visit_sym(dst.borrow().deref()));
+            })
+        })
+    }
+
+    fn visit_sym(&mut self, sym: &SymbolRef) {
+        match sym.as_ref() {
+            Symbol::Global(var) => { self.live_var.insert(var.clone()); }
+            Symbol::Func(func) => self.visit_fn(func),
+            _ => {}
+        }
+    }
+}
+
+impl Pass for GlobalDce {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        // Mark functions and variables reachable from roots
+        self.live_fn.clear();
+        self.live_var.clear();
+        let has_entry = pro.entry().is_some();
+        pro.func.iter()
+            .filter(|f| !has_entry || f.is_entry() || f.linkage.get().is_visible())
+            .for_each(|f| self.visit_fn(f));
+        self.live_var.extend(pro.vars.iter().filter(|g| g.linkage.is_visible()).cloned());
+
+        // Remove unreachable ones from program
+        let len = (pro.func.len(), pro.vars.len());
+        pro.func.iter().filter(|f| !self.live_fn.contains(*f))
+            .for_each(|f| pro.global.remove(&f.name));
+        pro.func.retain(|f| self.live_fn.contains(f));
+        pro.vars.iter().filter(|g| !self.live_var.contains(*g))
+            .for_each(|g| pro.global.remove(&g.name));
+        pro.vars.retain(|g| self.live_var.contains(g));
+        (pro.func.len(), pro.vars.len()) != len
+    }
+}
+
+#[test]
+fn test_gdce() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+    use crate::lang::value::Const;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut file = File::open("test/gdce.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let mut pro = Builder::new(Parser::new(lexer).parse().unwrap()).build().unwrap();
+    GlobalDce::new().run(&mut pro);
+    Printer::new(&mut std::io::stdout()).print(&pro).unwrap();
+
+    let mut func: Vec<_> = pro.func.iter().map(|f| f.name.as_str()).collect();
+    func.sort();
+    assert_eq!(func, vec!["api", "apply", "inc", "main", "neg", "twice", "weak"]);
+    let mut vars: Vec<_> = pro.vars.iter().map(|g| g.name.as_str()).collect();
+    vars.sort();
+    assert_eq!(vars, vec!["a", "b", "c", "e"]);
+    assert!(pro.global.find("dead").is_none() && pro.global.find("d").is_none());
+
+    let rcd = Machine::new().run(&pro).unwrap();
+    let get = |name: &str| rcd.global.iter().find(|(g, _)| g.name == name).unwrap().1
+        .get_const();
+    assert_eq!(get("a"), Const::I64(-6));
+}
+
+#[test]
+fn test_entry() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::irc::ErrKind;
+    use crate::back::x64::X64Gen;
+    use crate::vm::exec::Machine;
+    use crate::lang::value::Const;
+
+    let src = r#"
+@r: i64
+
+fn @inc($x: i64) -> i64 {
+%Begin:
+    $y <- add i64 $x, 1
+    ret $y
+}
+
+fn @dead() {
+%Begin:
+    ret
+}
+
+[entry]
+fn @_start() {
+%Begin:
+    $a <- call i64 @inc(1)
+    @r <- mov i64 $a
+    ret
+}
+
+[entry]
+fn @init() {
+%Begin:
+    @r <- mov i64 5
+    ret
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let names = |pro: &Program| pro.entries().iter().map(|f| f.name.clone()).collect::<Vec<_>>();
+    assert_eq!(names(&pro), ["_start", "init"]);
+
+    // All entry points are kept, and execution starts from the first one
+    assert!(GlobalDce::new().run(&mut pro));
+    let func: Vec<_> = pro.func.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(func, ["inc", "_start", "init"]);
+    let rcd = Machine::new().run(&pro).unwrap();
+    assert_eq!(rcd.global.iter().find(|(g, _)| g.name == "r").unwrap().1.get_const(),
+               Const::I64(2));
+
+    // Entry points are visible to the linker
+    let mut out = vec![];
+    X64Gen::new(&mut out).emit(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains(".globl _start") && out.contains(".globl init"));
+    assert!(!out.contains(".globl inc"));
+
+    // Entry points have the same signature as `@main`
+    let src = "[entry]\nfn @f($x: i32) {\n%Begin:\n    ret\n}\n";
+    let err = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().err().unwrap();
+    assert!(matches!(err.kind(), ErrKind::InvalidEntry(f) if f == "f"));
+}
@@ -0,0 +1,225 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{GlobalVarRef, Symbol, Value};
+use crate::pass::Pass;
+
+/// Whole-program Dead Global Store Elimination
+/// A store to a global variable is removed if the variable is never read by any function
+/// reachable from entry points or visible functions, or if it is overwritten later in the same
+/// block without being read in between (including reads by the called functions). Visible
+/// variables may be read by other modules, so they are always live. An indirect call is assumed
+/// to call any function whose address is taken. Atomic instructions with release ordering
+/// publish all previous stores to other threads, so no store is overwritten across them.
+pub struct GlobalDse {
+    /// Global variables read by each function, directly or through its callees
+    refs: HashMap<FnRef, HashSet<GlobalVarRef>>,
+    /// Global variables read by any function whose address is taken
+    ind_refs: HashSet<GlobalVarRef>,
+    /// Global variables read anywhere in the reachable part of the program
+    live: HashSet<GlobalVarRef>,
+}
+
+impl GlobalDse {
+    pub fn new() -> GlobalDse {
+        GlobalDse {
+            refs: Default::default(),
+            ind_refs: Default::default(),
+            live: Default::default(),
+        }
+    }
+}
+
+impl Pass for GlobalDse {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        // Find functions whose address is taken, which are possible targets of indirect calls
+        let taken: HashSet<FnRef> = pro.func.iter().flat_map(|func| {
+            func.iter_dom().flat_map(|block| block.inst.borrow().clone()).flat_map(|instr| {
+                instr.src().into_iter().filter_map(|opd| match opd.borrow().deref() {
+                    Value::Var(sym) => match sym.as_ref() {
+                        Symbol::Func(f) => Some(f.clone()),
+                        _ => None
+                    }
+                    _ => None
+                }).collect::<Vec<_>>()
+            }).collect::<Vec<_>>()
+        }).collect();
+
+        // Build call graph and collect globals read directly by each function
+        let mut callees: HashMap<FnRef, HashSet<FnRef>> = HashMap::new();
+        for func in &pro.func {
+            let mut refs = HashSet::new();
+            let mut calls = HashSet::new();
+            func.iter_dom().for_each(|block| {
+                block.inst.borrow().iter().for_each(|instr| {
+                    match instr.as_ref() {
+                        Inst::Call { func, arg: _, dst: _ } => { calls.insert(func.clone()); }
+                        Inst::CallInd { func_ptr: _, arg: _, dst: _ } =>
+                            calls.extend(taken.iter().cloned()),
+                        _ => {}
+                    }
+                    instr.src().into_iter().for_each(|opd| {
+                        if let Value::Var(sym) = opd.borrow().deref() {
+                            if let Symbol::Global(g) = sym.as_ref() { refs.insert(g.clone()); }
+                        }
+                    })
+                })
+            });
+            self.refs.insert(func.clone(), refs);
+            callees.insert(func.clone(), calls);
+        }
+
+        // Propagate mod/ref information along call graph until a fixed point is reached
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for func in &pro.func {
+                let mut refs = self.refs[func].clone();
+                callees[func].iter().for_each(|callee| {
+                    refs.extend(self.refs.get(callee).into_iter().flatten().cloned())
+                });
+                if refs.len() != self.refs[func].len() {
+                    changed = true;
+                    self.refs.insert(func.clone(), refs);
+                }
+            }
+        }
+
+        self.ind_refs = taken.iter().flat_map(|f| self.refs[f].iter().cloned()).collect();
+
+        // Find globals read by functions reachable from entrance or other modules
+        let entries = pro.entries();
+        self.live = if entries.is_empty() {
+            self.refs.values().flatten().cloned().collect()
+        } else {
+            entries.iter().flat_map(|f| self.refs[f].iter().cloned()).collect()
+        };
+        pro.func.iter().filter(|f| f.linkage.get().is_visible())
+            .for_each(|f| self.live.extend(self.refs[f].iter().cloned()));
+        self.live.extend(pro.vars.iter().filter(|g| g.linkage.is_visible()).cloned());
+
+        // Eliminate dead stores in each function
+        let mut changed = false;
+        for func in &pro.func {
+            func.iter_dom().for_each(|block| changed |= self.elim_in_block(&block));
+        }
+
+        self.refs.clear();
+        self.ind_refs.clear();
+        self.live.clear();
+        changed
+    }
+}
+
+impl GlobalDse {
+    /// Eliminate dead stores in `block`, and return whether any is eliminated.
+    fn elim_in_block(&self, block: &BlockRef) -> bool {
+        let mut changed = false;
+        // Globals that will be overwritten later in this block before being read
+        let mut killed: HashSet<GlobalVarRef> = HashSet::new();
+        let mut new_list = Vec::with_capacity(block.inst.borrow().len());
+        for instr in block.inst.borrow().iter().rev() {
+            let dst = instr.dst().and_then(|dst| match dst.borrow().as_ref() {
+                Symbol::Global(g) => Some(g.clone()),
+                _ => None
+            });
+            let mut instr = instr.clone();
+            if let Some(g) = dst {
+                if !self.live.contains(&g) || killed.contains(&g) {
+                    match Self::remove_dst(&instr) {
+                        Some(new) => {
+                            changed |= new != instr;
+                            instr = new
+                        }
+                        None => { // the whole instruction is removed
+                            changed = true;
+                            continue;
+                        }
+                    }
+                } else {
+                    killed.insert(g);
+                }
+            }
+            match instr.as_ref() {
+                Inst::Call { func, arg: _, dst: _ } =>
+                    killed.retain(|g| !self.refs[func].contains(g)),
+                Inst::CallInd { func_ptr: _, arg: _, dst: _ } =>
+                    killed.retain(|g| !self.ind_refs.contains(g)),
+                Inst::AtomicSt { ord, src: _, ptr: _ }
+                | Inst::AtomicRmw { op: _, ord, ptr: _, val: _, dst: _ } if ord.is_release() =>
+                    killed.clear(),
+                _ => {}
+            }
+            instr.src().into_iter().for_each(|opd| {
+                if let Value::Var(sym) = opd.borrow().deref() {
+                    if let Symbol::Global(g) = sym.as_ref() { killed.remove(g); }
+                }
+            });
+            new_list.push(instr);
+        }
+        new_list.reverse();
+        block.inst.replace(new_list.into_iter().collect());
+        changed
+    }
+
+    /// Remove destination of the instruction. Return `None` if the instruction could be
+    /// completely removed, or the instruction without destination if it should be kept.
+    fn remove_dst(instr: &InstRef) -> Option<InstRef> {
+        match instr.as_ref() {
+            Inst::Call { func, arg, dst: _ } => Some(ExtRc::new(Inst::Call {
+                func: func.clone(),
+                arg: arg.iter().map(|a| RefCell::new(a.borrow().clone())).collect(),
+                dst: vec![],
+            })),
+            Inst::CallInd { func_ptr, arg, dst: _ } => Some(ExtRc::new(Inst::CallInd {
+                func_ptr: RefCell::new(func_ptr.borrow().clone()),
+                arg: arg.iter().map(|a| RefCell::new(a.borrow().clone())).collect(),
+                dst: vec![],
+            })),
+            // Heap allocation cannot be removed, keep the store
+            Inst::New { dst: _, len: _ } => Some(instr.clone()),
+            _ => None
+        }
+    }
+}
+
+#[test]
+fn test_gdse() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use std::io::stdout;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+    use std::borrow::BorrowMut;
+
+    let mut file = File::open("test/gdse.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let mut pro = builder.build().unwrap();
+    GlobalDse::new().run(&mut pro);
+
+    let mut out = stdout();
+    let mut printer = Printer::new(out.borrow_mut());
+    printer.print(&pro).unwrap();
+
+    let main = pro.func.iter().find(|f| f.name == "main").unwrap();
+    let stored: Vec<_> = main.ent.borrow().inst.borrow().iter().filter_map(|i| match i.as_ref() {
+        Inst::Mov { src: _, dst } => Some(dst.borrow().name().to_string()),
+        _ => None
+    }).collect();
+    assert_eq!(stored, vec!["a", "a", "d", "e"]);
+
+    // Stores are not killed across release barriers
+    let publish = pro.func.iter().find(|f| f.name == "publish").unwrap();
+    assert_eq!(publish.ent.borrow().inst.borrow().len(), 4);
+}
@@ -0,0 +1,149 @@
+use std::collections::HashSet;
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::Inst;
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::pass::{FnPass, Pass};
+use crate::pass::remark::Remark;
+
+/// Optimizations based on escape analysis
+/// Heap memory allocated by `new` whose address does not escape the function is unreachable
+/// after the function returns, so it is allocated on stack by `alloc` instead. Allocations in
+/// loops are kept on heap, since a stack slot would be shared by all the iterations. Stores,
+/// `memcpy` and `memset` to allocations that neither escape nor are loaded from are dead, and
+/// are removed.
+pub struct EscapeOpt {
+    /// Remarks made in the last run
+    remark: Vec<Remark>,
+}
+
+impl Pass for EscapeOpt {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.remark.clear();
+        FnPass::run(self, pro)
+    }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
+}
+
+impl FnPass for EscapeOpt {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        let info = func.escape();
+        let n_rm = self.remark.len();
+
+        // Remove stores to memory that is never read
+        let mut n_store = 0;
+        for block in func.dfs() {
+            let len = block.inst.borrow().len();
+            block.retain(|instr| {
+                let ptr = match instr.as_ref() {
+                    Inst::St { src: _, ptr } | Inst::Memcpy { src: _, ptr, len: _ }
+                    | Inst::Memset { src: _, ptr, len: _ } => ptr,
+                    _ => return true
+                };
+                match info.allocs_of(ptr.borrow().deref()) {
+                    Some(allocs) =>
+                        !allocs.iter().all(|a| !info.escapes(a) && !info.read.contains(a)),
+                    None => true
+                }
+            });
+            n_store += len - block.inst.borrow().len();
+        }
+        if n_store > 0 {
+            self.remark.push(Remark::applied(&func.name, format!("removed {} dead stores",
+                                                                  n_store)));
+        }
+
+        // Convert non-escaping heap allocations out of loops
+        let in_loop: HashSet<BlockRef> = func.analyze_loop().iter()
+            .flat_map(|node| node.borrow().all_blocks()).collect();
+        for block in func.dfs().filter(|b| !in_loop.contains(b)) {
+            let mut inst = block.inst.borrow_mut();
+            for instr in inst.iter_mut() {
+                let dst = match instr.as_ref() {
+                    Inst::New { dst, len: None } if !info.escapes(instr) => dst.clone(),
+                    _ => continue
+                };
+                let msg = format!("moved {} to stack", dst.borrow().to_string());
+                self.remark.push(Remark::applied(&func.name, msg));
+                let alloc = ExtRc::new(Inst::Alloc { dst });
+                func.move_inst_meta(instr, &alloc);
+                *instr = alloc;
+            }
+        }
+        self.remark.len() > n_rm
+    }
+}
+
+impl EscapeOpt {
+    pub fn new() -> EscapeOpt { EscapeOpt { remark: vec![] } }
+}
+
+#[test]
+fn test_escape() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::manager::PassManager;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+@p: *i64
+
+fn @main() {
+%Begin:
+    $a <- new i64
+    st i64 1 -> $a
+    $x <- ld i64 $a
+    $b <- new [4]i64
+    $b1 <- ptr *i64 $b [1]
+    st i64 2 -> $b1
+    $c <- alloc i64
+    st i64 3 -> $c
+    $d <- new i64
+    st i64 4 -> $d
+    @p <- mov *i64 $d
+    $e <- new i64
+    st i64 5 -> $e
+    $s <- call i64 @get($e)
+    $r <- add i64 $x, $s
+    @r <- mov i64 $r
+    ret
+}
+
+fn @get($q: *i64) -> i64 {
+%Begin:
+    $v <- ld i64 $q
+    ret $v
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    let mut mgr = PassManager::new().add("escape", EscapeOpt::new());
+    mgr.run(&mut pro);
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+
+    // `$a` and `$b` are moved to stack, and stores to `$b` and `$c` are dead. `$d` and `$e`
+    // escape through global variable and call.
+    let report: Vec<_> = mgr.remarks().iter().map(|r| r.to_string()).collect();
+    assert_eq!(report, [
+        "escape: applied in @main: removed 2 dead stores",
+        "escape: applied in @main: moved $a to stack",
+        "escape: applied in @main: moved $b to stack",
+    ]);
+    assert!(out.contains("$a <- alloc i64"));
+    assert!(out.contains("$b <- alloc [4]i64"));
+    assert!(out.contains("$d <- new i64"));
+    assert!(out.contains("$e <- new i64"));
+    assert!(!out.contains("st i64 2") && !out.contains("st i64 3"));
+    let after = Machine::new().run(&pro).unwrap();
+    let r = |g: &[_]| g.iter().map(|v| format!("{:?}", v)).find(|v| v.starts_with("(@r,"));
+    assert_eq!(r(&before.global), r(&after.global));
+}
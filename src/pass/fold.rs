@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+
+use crate::lang::func::FnRef;
+use crate::lang::inst::Inst;
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Const, GlobalVarRef, Symbol, SymbolRef, Type, Value};
+use crate::pass::{FnPass, Pass};
+use crate::pass::analysis::Analysis;
+use crate::pass::remark::Remark;
+
+/// Constant Folding
+/// Unary and binary operations whose operands are all constants are evaluated and replaced by
+/// `mov` instructions. For SSA functions, local variables assigned with constants are also
+/// substituted in their uses, so that folding could be carried out in chains.
+pub struct ConstFold {
+    /// Map SSA variables to their constant values
+    map: HashMap<SymbolRef, Const>,
+}
+
+impl ConstFold {
+    pub fn new() -> ConstFold { ConstFold { map: Default::default() } }
+}
+
+impl Pass for ConstFold {
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+
+    fn preserved(&self) -> &'static [Analysis] { Analysis::CFG }
+}
+
+impl FnPass for ConstFold {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        let ssa = func.ssa.get();
+        let mut folded = false;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            // Visit in dominator tree order, so that definitions are visited before uses in
+            // SSA functions, except for phi operands.
+            func.iter_dom().for_each(|block| {
+                let mut list = block.inst.borrow_mut();
+                for instr in list.iter_mut() {
+                    // Substitute constant variables
+                    if ssa {
+                        let mut opd = instr.src();
+                        if let Inst::Phi { src, dst: _ } = instr.as_ref() {
+                            opd = src.iter().map(|(_, v)| v).collect();
+                        }
+                        for v in opd {
+                            let c = match v.borrow().deref() {
+                                Value::Var(sym) => self.map.get(sym).cloned(),
+                                _ => None
+                            };
+                            if let Some(c) = c {
+                                v.replace(Value::Const(c));
+                                changed = true;
+                            }
+                        }
+                    }
+
+                    // Fold instruction
+                    if let Some(new) = instr.fold() {
+                        let new = ExtRc::new(new);
+                        func.move_inst_meta(instr, &new);
+                        *instr = new;
+                        changed = true;
+                    }
+
+                    // Record constant assignment
+                    if let Inst::Mov { src, dst } = instr.as_ref() {
+                        if let (true, Value::Const(c)) = (ssa, src.borrow().deref()) {
+                            if dst.borrow().is_local_var() {
+                                self.map.insert(dst.borrow().clone(), *c);
+                            }
+                        }
+                    }
+                }
+            });
+            folded |= changed;
+        }
+        self.map.clear();
+        folded
+    }
+}
+
+/// Constant Global Propagation
+/// Uses of global variables of integer type that always hold their initial values are replaced
+/// by these values, which are their initializers or zero. Constant variables are always such
+/// ones. When run on the whole program, internal variables never written by any function are
+/// also replaced. Other variables that are not internal may be written by other modules.
+pub struct GlobalConstProp {
+    /// Internal variables never written in the program, found before functions are processed
+    unwritten: HashSet<GlobalVarRef>,
+    /// Remarks made in the last run
+    remark: Vec<Remark>,
+}
+
+impl GlobalConstProp {
+    pub fn new() -> GlobalConstProp {
+        GlobalConstProp { unwritten: Default::default(), remark: vec![] }
+    }
+}
+
+impl Pass for GlobalConstProp {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.remark.clear();
+
+        // Find variables written by any function
+        let mut written: HashSet<GlobalVarRef> = HashSet::new();
+        for func in &pro.func {
+            for instr in func.dfs().flat_map(|b| b.inst.borrow().clone()) {
+                for dst in instr.dsts() {
+                    if let Symbol::Global(g) = dst.borrow().as_ref() { written.insert(g.clone()); }
+                }
+            }
+        }
+        self.unwritten = pro.vars.iter()
+            .filter(|g| !g.linkage.is_visible() && !written.contains(*g)).cloned().collect();
+
+        let changed = FnPass::run(self, pro);
+        self.unwritten.clear();
+        changed
+    }
+
+    fn preserved(&self) -> &'static [Analysis] { Analysis::CFG }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
+}
+
+impl FnPass for GlobalConstProp {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        let mut count = 0;
+        func.iter_dom().for_each(|block| {
+            block.inst.borrow().iter().for_each(|instr| {
+                instr.src().into_iter().for_each(|opd| {
+                    let c = match opd.borrow().deref() {
+                        Value::Var(sym) => match sym.as_ref() {
+                            Symbol::Global(g) if g.is_const || self.unwritten.contains(g) =>
+                                match g.ty.orig() {
+                                    ty @ Type::I(_) =>
+                                        Some(g.init.unwrap_or_else(|| Const::zero(&ty))),
+                                    _ => None // pointer constant cannot be represented
+                                }
+                            _ => None
+                        }
+                        _ => None
+                    };
+                    if let Some(c) = c {
+                        opd.replace(Value::Const(c));
+                        count += 1;
+                    }
+                })
+            })
+        });
+        if count > 0 {
+            self.remark.push(Remark::applied(&func.name,
+                                             format!("replaced {} reads of constant globals",
+                                                     count)));
+        }
+        count > 0
+    }
+}
+
+#[test]
+fn test_fold() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use std::io::stdout;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+    use std::borrow::BorrowMut;
+
+    let mut file = File::open("test/fold.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree).fold_const(true);
+    let mut pro = builder.build().unwrap();
+    FnPass::run(&mut ConstFold::new(), &mut pro);
+
+    let mut out = stdout();
+    let mut printer = Printer::new(out.borrow_mut());
+    printer.print(&pro).unwrap();
+}
+
+#[test]
+fn test_global_const() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::irc::ErrKind;
+    use crate::lang::print::Printer;
+    use std::io::stdout;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+    use std::borrow::BorrowMut;
+
+    let mut file = File::open("test/const.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let mut pro = builder.build().unwrap();
+    Pass::run(&mut GlobalConstProp::new(), &mut pro);
+    Pass::run(&mut ConstFold::new(), &mut pro);
+
+    let mut out = stdout();
+    let mut printer = Printer::new(out.borrow_mut());
+    printer.print(&pro).unwrap();
+
+    // Aliases of integer types are propagated as well
+    let src = "type @Int = i32 const @a: @Int @r: i32 \
+               fn @main() { %Begin: $s <- add i32 @a, 4 @r <- mov i32 $s ret }";
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    assert!(Pass::run(&mut GlobalConstProp::new(), &mut pro));
+    let mut out = vec![];
+    Printer::new(&mut out).print_fn(&pro.func[0]).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("add i32 0, 4"), "{}", out);
+
+    // Constant variables cannot be modified
+    let src = "const @c: i32 <- 1 fn @main() { %Begin: @c <- add i32 @c, 1 ret }";
+    let tree = Parser::new(Lexer::from(src)).parse().unwrap();
+    let err = Builder::new(tree).build().err().unwrap();
+    assert!(matches!(err.kind(), ErrKind::ConstVar(_)));
+}
+
+#[test]
+fn test_global_unwritten() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::manager::PassManager;
+    use crate::pass::util::DceOpt;
+    use crate::vm::exec::Machine;
+
+    let pro = || Builder::new(Parser::new(Lexer::from(r#"
+@n: i64 <- 3
+@z: i32
+@w: i64 <- 1
+export @e: i64 <- 5
+export const @k: i64 <- 7
+@r: i64
+
+[ssa]
+fn @get() -> i32 {
+%Begin:
+    ret @z
+}
+
+[ssa]
+fn @main() {
+%Begin:
+    $a <- mul i64 @n, @k
+    $b <- add i64 $a, @w
+    $c <- add i64 $b, @e
+    @w <- mov i64 2
+    $z <- call i32 @get()
+    $y <- sext i32 $z -> i64
+    $s <- add i64 $c, $y
+    @r <- mov i64 $s
+    ret
+}
+"#)).parse().unwrap()).build().unwrap();
+    let expect = format!("{:?}", Machine::new().run(&pro()).unwrap().global);
+
+    // `@n`, `@k` and `@z` are replaced, while `@w` is written and `@e` may be written by other
+    // modules
+    let mut pro = pro();
+    let mut mgr = PassManager::new().add("gconst", GlobalConstProp::new())
+        .add("fold", ConstFold::new()).add("dce", DceOpt::new());
+    assert!(mgr.run(&mut pro));
+    mgr.remarks().iter().for_each(|r| println!("{}", r));
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    assert!(out.contains("ret 0"));
+    assert!(!out.contains("mul"));
+    assert!(out.contains("add i64 21, @w"));
+    assert!(out.contains("@e"));
+
+    assert!(!Pass::run(&mut GlobalConstProp::new(), &mut pro));
+}
@@ -0,0 +1,253 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::Program;
+use crate::lang::ssa::{DefPos, DefUseGraph};
+use crate::lang::value::{SymbolRef, Value};
+use crate::pass::{FnPass, Pass};
+use crate::pass::analysis::{Analysis, AnalysisCache};
+use crate::pass::remark::Remark;
+use crate::pass::util::LoopNodeRef;
+
+/// Global Code Motion
+/// Following Click's algorithm, each movable instruction is scheduled as early as its operands
+/// allow, and as late as its uses allow. Among the blocks on the dominator tree path between the
+/// two, the one in the shallowest loop nest is chosen, preferring later ones. This hoists loop
+/// invariants out of loops, and sinks computations into the branches that use them. After GVN,
+/// an instruction merged from several blocks is placed at a point dominating all its uses.
+/// Only pure instructions which cannot trap are movable. Phis, loads and instructions with side
+/// effects stay where they are.
+pub struct GcmOpt {
+    /// Remarks made in the last run
+    remark: Vec<Remark>,
+}
+
+impl GcmOpt {
+    pub fn new() -> GcmOpt { GcmOpt { remark: vec![] } }
+
+    fn is_movable(instr: &InstRef) -> bool { instr.is_speculatable() }
+}
+
+impl Pass for GcmOpt {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.remark.clear();
+        FnPass::run(self, pro)
+    }
+
+    fn run_cached(&mut self, pro: &mut Program, cache: &mut AnalysisCache) -> bool {
+        self.remark.clear();
+        FnPass::run_cached(self, pro, cache)
+    }
+
+    fn preserved(&self) -> &'static [Analysis] { Analysis::CFG }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
+}
+
+impl FnPass for GcmOpt {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        self.run_on_fn_cached(func, &mut AnalysisCache::new())
+    }
+
+    fn run_on_fn_cached(&mut self, func: &FnRef, cache: &mut AnalysisCache) -> bool {
+        // GCM requires SSA form
+        func.assert_ssa();
+        let loops = cache.loops(func);
+        let def_use = cache.def_use(func);
+
+        // Compute depths of blocks in dominator tree and in loop-nest forest
+        let mut dom_depth = HashMap::new();
+        let mut block = HashMap::new();
+        for blk in func.iter_dom() {
+            let depth = blk.parent().map(|p| dom_depth[&p] + 1).unwrap_or(0);
+            dom_depth.insert(blk.clone(), depth);
+            blk.inst.borrow().iter().for_each(|i| { block.insert(i.clone(), blk.clone()); });
+        }
+        let mut loop_depth = HashMap::new();
+        let mut stack: Vec<(LoopNodeRef, usize)> = loops.into_iter().map(|n| (n, 1)).collect();
+        while let Some((node, depth)) = stack.pop() {
+            node.borrow().level_blocks().into_iter().for_each(|b| { loop_depth.insert(b, depth); });
+            stack.extend(node.borrow().nested.iter().map(|n| (n.clone(), depth + 1)));
+        }
+
+        // Schedule movable instructions, in dominator tree order for determinism
+        let movable: Vec<InstRef> = func.iter_dom()
+            .flat_map(|b| b.inst.borrow().iter().cloned().collect::<Vec<_>>())
+            .filter(Self::is_movable).collect();
+        let mut sched = Scheduler {
+            ent: func.ent.borrow().clone(),
+            def_use: &def_use,
+            block,
+            dom_depth,
+            loop_depth,
+            early: Default::default(),
+            done: Default::default(),
+            n_move: 0,
+        };
+        movable.iter().for_each(|instr| sched.schedule(instr));
+
+        if sched.n_move == 0 { return false; }
+        self.remark.push(Remark::applied(&func.name, format!("moved {} instructions",
+                                                              sched.n_move)));
+        true
+    }
+}
+
+/// Scheduling state of a function
+struct Scheduler<'a> {
+    ent: BlockRef,
+    /// Def-use information, which is updated as instructions are moved
+    def_use: &'a DefUseGraph,
+    /// Block where each instruction currently is
+    block: HashMap<InstRef, BlockRef>,
+    dom_depth: HashMap<BlockRef, usize>,
+    /// Number of loops containing each block. Blocks not in any loop are absent.
+    loop_depth: HashMap<BlockRef, usize>,
+    /// Earliest legal block of each movable instruction
+    early: HashMap<InstRef, BlockRef>,
+    /// Movable instructions whose final blocks are decided
+    done: HashSet<InstRef>,
+    /// Number of instructions moved to other blocks
+    n_move: usize,
+}
+
+impl Scheduler<'_> {
+    /// Find the earliest block where all operands of `instr` are available, which is the deepest
+    /// one in dominator tree among the blocks defining them.
+    fn early(&mut self, instr: &InstRef) -> BlockRef {
+        if !GcmOpt::is_movable(instr) { return self.block[instr].clone(); }
+        if let Some(blk) = self.early.get(instr) { return blk.clone(); }
+        let mut early = self.ent.clone();
+        for opd in instr.src() {
+            let def = match opd.borrow().deref() {
+                Value::Var(sym) if sym.is_local_var() => self.def_use.def(sym),
+                _ => continue
+            };
+            let blk = match def {
+                DefPos::Inst(_, def) => self.early(&def),
+                _ => continue
+            };
+            if self.dom_depth[&blk] > self.dom_depth[&early] { early = blk }
+        }
+        self.early.insert(instr.clone(), early.clone());
+        early
+    }
+
+    /// Decide final block of movable `instr`, after the ones of all its uses, and move it there.
+    fn schedule(&mut self, instr: &InstRef) {
+        if !self.done.insert(instr.clone()) { return; }
+        let dst = instr.dst().unwrap().borrow().clone();
+        let uses = self.def_use.uses(&dst);
+        uses.iter().filter(|u| GcmOpt::is_movable(u)).for_each(|u| self.schedule(u));
+
+        // Find the latest block, which is the lowest common dominator of the uses. Phi operands
+        // are used at the end of corresponding predecessors.
+        let mut late: Option<BlockRef> = None;
+        for u in &uses {
+            let blocks = match u.as_ref() {
+                Inst::Phi { src, dst: _ } => src.iter()
+                    .filter(|(_, v)| matches!(v.borrow().deref(), Value::Var(s) if *s == dst))
+                    .map(|(p, _)| p.borrow().clone()).collect(),
+                _ => match self.block.get(u) {
+                    Some(blk) => vec![blk.clone()],
+                    None => return // used in unreachable code
+                }
+            };
+            for blk in blocks {
+                if !self.dom_depth.contains_key(&blk) { return; }
+                late = Some(match late {
+                    Some(l) => self.common_dom(l, blk),
+                    None => blk
+                });
+            }
+        }
+        let late = match late {
+            Some(late) => late,
+            None => return // dead instruction is left for DCE
+        };
+
+        // Choose the block in the shallowest loop nest on the path from latest to earliest
+        let early = self.early(instr);
+        let mut best = late.clone();
+        let mut cur = late;
+        while cur != early {
+            cur = cur.parent().unwrap();
+            if self.loop_depth(&cur) < self.loop_depth(&best) { best = cur.clone() }
+        }
+
+        let orig = self.block[instr].clone();
+        if best != orig { self.move_to(instr, &dst, &orig, &best) }
+    }
+
+    /// Move `instr` from block `orig` to `tgt`, before its first use there if there is any, or
+    /// before the control flow instruction otherwise.
+    fn move_to(&mut self, instr: &InstRef, dst: &SymbolRef, orig: &BlockRef, tgt: &BlockRef) {
+        orig.retain(|i| i != instr);
+        self.def_use.erase(instr);
+        let uses = self.def_use.uses(dst);
+        let pos = tgt.inst.borrow().iter().position(|i| !i.is_phi() && uses.contains(i));
+        match pos {
+            Some(pos) => tgt.inst.borrow_mut().insert(pos, instr.clone()),
+            None => tgt.insert_before_ctrl(instr.clone())
+        }
+        self.def_use.insert(tgt, instr);
+        self.block.insert(instr.clone(), tgt.clone());
+        self.n_move += 1;
+    }
+
+    fn common_dom(&self, mut a: BlockRef, mut b: BlockRef) -> BlockRef {
+        while a != b {
+            if self.dom_depth[&a] >= self.dom_depth[&b] {
+                a = a.parent().unwrap()
+            } else {
+                b = b.parent().unwrap()
+            }
+        }
+        a
+    }
+
+    fn loop_depth(&self, block: &BlockRef) -> usize {
+        self.loop_depth.get(block).cloned().unwrap_or(0)
+    }
+}
+
+#[test]
+fn test_gcm() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+    use std::fs::File;
+    use std::convert::TryFrom;
+
+    let file = File::open("test/gcm.ir").unwrap();
+    let mut pro = Builder::new(Parser::new(Lexer::try_from(file).unwrap()).parse().unwrap())
+        .build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+
+    let mut gcm = GcmOpt::new();
+    assert!(Pass::run(&mut gcm, &mut pro));
+    gcm.remarks().iter().for_each(|r| println!("{}", r));
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut pro);
+    assert!(ver.is_ok());
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+
+    // Invariant is hoisted, trapping instruction is kept in loop, and the computation used only
+    // in one branch is sunk
+    let func = pro.func.iter().find(|f| f.name == "f").unwrap();
+    let dsts = |name: &str| func.dfs().find(|b| b.name == name).unwrap().inst.borrow().iter()
+        .filter_map(|i| i.dst().map(|d| d.borrow().name().to_string()))
+        .collect::<Vec<_>>();
+    assert!(dsts("Small").contains(&"t".to_string()));
+    assert!(dsts("Loop").contains(&"q".to_string()));
+    assert_eq!(dsts("Big"), vec!["a", "b"]);
+    assert_eq!(dsts("Begin"), vec!["c"]);
+
+    // Scheduling is stable
+    assert!(!Pass::run(&mut gcm, &mut pro));
+}
@@ -215,6 +215,14 @@ impl InstListener for GraphBuilder {
                 let dst = self.get_dst_vert(dst, op.to_string(), Some(def));
                 dst.add_opd(opd);
             }
+            Inst::Cast { op, opd, dst } => {
+                // Target type is part of the operator, since different conversions of the same
+                // operand are not congruent.
+                let opd = self.get_src_vert(opd);
+                let tag = format!("{} {}", op.to_string(), dst.borrow().get_type().to_string());
+                let dst = self.get_dst_vert(dst, tag, Some(def));
+                dst.add_opd(opd);
+            }
             Inst::Bin { op, fst, snd, dst } => {
                 let fst = self.get_src_vert(fst);
                 let snd = self.get_src_vert(snd);
@@ -222,31 +230,25 @@ impl InstListener for GraphBuilder {
                 dst.add_opd(fst);
                 dst.add_opd(snd);
             }
-            Inst::Call { func, arg, dst } => {
-                // Function returns are not SSA value. Because a function may modify global
-                // variables, and it may return different values even with the same parameters.
-                let dst_vert = ExtRc::new(SsaVert::new(
-                    VertTag::Cell(func.name.clone()),
-                    Some(def),
-                ));
-                self.graph.add(dst_vert.clone(), dst.as_ref().map(|dst| dst.borrow().clone()));
-                for a in arg {
-                    let a = self.get_src_vert(a);
-                    dst_vert.add_opd(a);
-                }
-            }
+            // Function returns are not SSA value. Because a function may modify global
+            // variables, and it may return different values even with the same parameters.
+            Inst::Call { func, arg, dst } =>
+                self.add_call(&func.name, arg.iter().collect(), dst, def),
+            Inst::CallInd { func_ptr, arg, dst } =>
+                self.add_call("call", std::iter::once(func_ptr).chain(arg.iter()).collect(), dst,
+                              def),
             Inst::Ret { val } => {
                 let vert = ExtRc::new(SsaVert::new(
                     VertTag::Consume("ret".to_string()),
                     Some(def),
                 ));
-                val.as_ref().map(|val| {
+                val.iter().for_each(|val| {
                     let src = self.get_src_vert(val);
                     vert.add_opd(src.clone());
                 });
                 self.graph.add(vert, None);
             }
-            Inst::Jmp { tgt: _ } => {} // nothing to do
+            Inst::Jmp { tgt: _ } | Inst::Unreachable | Inst::Abort { msg: _ } => {} // nothing to do
             Inst::Br { cond, tr: _, fls: _ } => {
                 let vert = ExtRc::new(SsaVert::new(
                     VertTag::Consume("br".to_string()),
@@ -296,6 +298,41 @@ impl InstListener for GraphBuilder {
                 vert.add_opd(ptr);
                 self.graph.add(vert, None);
             }
+            Inst::Memcpy { src, ptr, len } | Inst::Memset { src, ptr, len } => {
+                let vert = ExtRc::new(SsaVert::new(
+                    VertTag::Consume(instr.name()),
+                    Some(def),
+                ));
+                for opd in [src, ptr, len] {
+                    let opd = self.get_src_vert(opd);
+                    vert.add_opd(opd);
+                }
+                self.graph.add(vert, None);
+            }
+            // Results of atomic instructions depend on other threads, so they are opaque
+            Inst::AtomicLd { ord: _, ptr: _, dst }
+            | Inst::AtomicRmw { op: _, ord: _, ptr: _, val: _, dst } => {
+                let vert = ExtRc::new(SsaVert::new(
+                    VertTag::Cell(dst.borrow().name().to_string()),
+                    Some(def),
+                ));
+                for opd in instr.src() {
+                    let opd = self.get_src_vert(opd);
+                    vert.add_opd(opd);
+                }
+                self.graph.add(vert, Some(dst.borrow().clone()))
+            }
+            Inst::AtomicSt { ord: _, src, ptr } => {
+                let vert = ExtRc::new(SsaVert::new(
+                    VertTag::Consume(instr.name()),
+                    Some(def),
+                ));
+                for opd in [src, ptr] {
+                    let opd = self.get_src_vert(opd);
+                    vert.add_opd(opd);
+                }
+                self.graph.add(vert, None);
+            }
         }
     }
 
@@ -401,6 +438,15 @@ impl GraphBuilder {
                     self.graph.add(vert.clone(), None);
                     vert
                 }
+                // Address of a function never changes, so it is a value without operands.
+                Symbol::Func(_) => {
+                    let vert = ExtRc::new(SsaVert::new(
+                        VertTag::Value(sym.to_string()),
+                        None,
+                    ));
+                    self.graph.add(vert.clone(), None);
+                    vert
+                }
                 _ => unreachable!()
             },
             Value::Const(c) => {
@@ -414,6 +460,30 @@ impl GraphBuilder {
         }
     }
 
+    /// Create cell vertex for each destination of call, or one for the call if its results are
+    /// discarded.
+    fn add_call(&mut self, name: &str, opd: Vec<&RefCell<Value>>, dst: &[RefCell<SymbolRef>],
+                def: (BlockRef, InstRef))
+    {
+        let dst: Vec<_> = match dst.len() {
+            0 => vec![None],
+            _ => dst.iter().map(|d| Some(d.borrow().clone())).collect()
+        };
+        for sym in dst {
+            let dst_vert = ExtRc::new(SsaVert::new(
+                VertTag::Cell(name.to_string()),
+                Some(def.clone()),
+            ));
+            // Symbol of vertex cannot be found from the instruction if it returns a tuple
+            dst_vert.sym.replace(sym.clone().filter(|s| s.is_local_var()));
+            self.graph.add(dst_vert.clone(), sym);
+            for a in opd.iter() {
+                let a = self.get_src_vert(a);
+                dst_vert.add_opd(a);
+            }
+        }
+    }
+
     /// Create destination vertex with given symbol.
     fn get_dst_vert(&mut self, sym: &RefCell<SymbolRef>, op: String,
                     def: Option<(BlockRef, InstRef)>) -> VertRef
@@ -103,11 +103,11 @@ impl Gvn {
 pub struct GvnOpt {}
 
 impl Pass for GvnOpt {
-    fn run(&mut self, pro: &mut Program) { FnPass::run(self, pro) }
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
 }
 
 impl FnPass for GvnOpt {
-    fn run_on_fn(&mut self, func: &FnRef) {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
         // Number values
         let sym_num = Gvn::new().number(func);
 
@@ -115,9 +115,10 @@ impl FnPass for GvnOpt {
         let mut listener = GvnListener::new(sym_num);
         func.walk_dom(&mut listener);
 
-        // Clean code
-        CopyProp::new().run_on_fn(func);
-        func.elim_dead_code()
+        // Clean code. Redundant values are replaced by copies, which are then propagated, so
+        // the function is changed if and only if cleaning changes it.
+        let copied = CopyProp::new().run_on_fn(func);
+        func.elim_dead_code() || copied
     }
 }
 
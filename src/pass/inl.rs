@@ -1,21 +1,40 @@
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
+use std::fmt::{Display, Error, Formatter};
 use std::ops::Deref;
 
+use crate::lang::clone::CloneMap;
+use crate::lang::cost::{CostModel, DefaultCost};
 use crate::lang::func::{BlockGen, BlockRef, FnAttrib, FnRef};
-use crate::lang::inst::Inst;
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::meta::MetaVal;
 use crate::lang::Program;
 use crate::lang::util::ExtRc;
-use crate::lang::value::{SymbolGen, SymbolRef, Typed, Value};
+use crate::lang::value::{SymbolGen, Typed, Value};
 use crate::pass::Pass;
-
+use crate::pass::remark::Remark;
+
+/// Function inliner
+/// Functions with `inline` attribute are always inlined. If profile-guided inlining is enabled
+/// with `hot`, other callees are also inlined at hot call sites, as long as they are small enough
+/// and the size budget of the caller is not exhausted. Frequency of a call site is read from
+/// `!prof` metadata of the call instruction, or `!freq` metadata of its block. Sizes of callees
+/// are estimated by a cost model, which is `DefaultCost` unless set with `cost`.
 pub struct Inliner {
     /// Functions to be inlined
     tgt: HashSet<FnRef>,
-    /// Map blocks in callee to new blocks in caller
-    blk_map: HashMap<BlockRef, BlockRef>,
-    /// Map symbols in callee to values in caller
-    sym_map: HashMap<SymbolRef, SymbolRef>,
+    /// Minimal frequency of hot call sites, or `None` if profile-guided inlining is disabled
+    hot: Option<i64>,
+    /// Maximal size of callees inlined at hot call sites
+    max_size: usize,
+    /// Maximal size added to a caller by profile-guided inlining
+    budget: usize,
+    /// Size added to current caller
+    growth: usize,
+    /// Cost model estimating sizes of callees
+    cost: Box<dyn CostModel>,
+    /// Decisions made at call sites in the last run
+    pub decision: Vec<InlineDecision>,
     /// Stack of nested inlined functions
     /// It may happen that a inlined function calls another function that could be inlined. This
     /// allows for multiple levels of inline expansion.
@@ -32,7 +51,15 @@ pub struct Inliner {
 }
 
 impl Pass for Inliner {
-    fn run(&mut self, pro: &mut Program) {
+    fn remarks(&self) -> Vec<Remark> {
+        self.decision.iter().map(|d| if d.inlined {
+            Remark::applied(&d.caller, format!("inlined @{}, {}", d.callee, d.reason))
+        } else {
+            Remark::missed(&d.caller, format!("@{} not inlined, {}", d.callee, d.reason))
+        }).collect()
+    }
+
+    fn run(&mut self, pro: &mut Program) -> bool {
         // Make sure all functions is in SSA form
         // Actually, inlining does not rely on SSA property. However, an SSA function may call
         // a non-SSA function and the SSA property no longer holds. On the other hand, a non-SSA
@@ -42,6 +69,7 @@ impl Pass for Inliner {
         pro.func.iter().for_each(|f| f.assert_ssa());
 
         // Find target for inlining
+        self.decision.clear();
         self.tgt = pro.func.iter()
             .filter(|f| Self::can_inl(f)).cloned().collect();
 
@@ -57,6 +85,7 @@ impl Pass for Inliner {
             // Initialize block generator for this function
             self.blk_gen = Some(BlockGen::new(f.as_ref(), ""));
             self.sym_gen = SymbolGen::new(f.scope.clone(), "t");
+            self.growth = 0;
 
             // Process blocks in this function
             f.iter_dom().for_each(|b| self.proc_blk(f, b));
@@ -65,10 +94,9 @@ impl Pass for Inliner {
             f.build_dom();
 
             // Clear records for this function
-            self.blk_map.clear();
-            self.sym_map.clear();
             self.nested.clear();
         });
+        self.decision.iter().any(|d| d.inlined)
     }
 }
 
@@ -76,26 +104,92 @@ impl Inliner {
     pub fn new() -> Inliner {
         Inliner {
             tgt: Default::default(),
-            blk_map: Default::default(),
-            sym_map: Default::default(),
             nested: vec![],
             exit: vec![],
             blk_gen: None,
             sym_gen: SymbolGen::new(Default::default(), ""),
+            hot: None,
+            max_size: 20,
+            budget: 100,
+            growth: 0,
+            cost: Box::new(DefaultCost),
+            decision: vec![],
         }
     }
 
-    fn can_inl(f: &FnRef) -> bool { f.has_attrib(FnAttrib::Inline) }
+    /// Enable profile-guided inlining at call sites with frequency at least `freq`.
+    pub fn hot(mut self, freq: i64) -> Self {
+        self.hot = Some(freq);
+        self
+    }
+
+    /// Set maximal size of callees inlined at hot call sites.
+    pub fn max_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    /// Set maximal size added to a caller by profile-guided inlining.
+    pub fn budget(mut self, size: usize) -> Self {
+        self.budget = size;
+        self
+    }
+
+    /// Set cost model estimating sizes of callees.
+    pub fn cost(mut self, model: impl CostModel + 'static) -> Self {
+        self.cost = Box::new(model);
+        self
+    }
+
+    fn can_inl(f: &FnRef) -> bool {
+        f.has_attrib(FnAttrib::Inline) && !f.has_attrib(FnAttrib::NoInline)
+    }
+
+    /// Decide whether to inline `callee` called by `instr` in `blk`, and give the reason.
+    fn decide(&self, caller: &FnRef, blk: &BlockRef, instr: &InstRef, callee: &FnRef)
+              -> (bool, String)
+    {
+        if callee.has_attrib(FnAttrib::NoInline) { return (false, "marked noinline".into()); }
+        // If this function is on the nested stack, it is a recursive call. Inlining recursive
+        // call will lead to infinite recursion in inliner.
+        if self.nested.contains(callee) { return (false, "recursive call".into()); }
+        if self.tgt.contains(callee) { return (true, "marked inline".into()); }
+        let hot = match self.hot {
+            Some(hot) => hot,
+            None => return (false, "not marked inline".into())
+        };
+        let freq = match caller.inst_meta(instr, "prof")
+            .or_else(|| blk.meta.borrow().get("freq").cloned()) {
+            Some(MetaVal::Int(f)) => f,
+            _ => return (false, "no profile data".into())
+        };
+        if freq < hot { return (false, format!("cold call site (freq {})", freq)); }
+        let size = self.cost.fn_size(callee);
+        if size > self.max_size {
+            (false, format!("callee too large (size {})", size))
+        } else if self.growth + size > self.budget {
+            (false, format!("size budget exhausted (size {})", size))
+        } else {
+            (true, format!("hot call site (freq {}, size {})", freq, size))
+        }
+    }
 
     fn proc_blk(&mut self, caller: &FnRef, mut blk: BlockRef) {
         loop {
-            // Find the first call instruction
-            let pos = blk.inst.borrow().iter().position(|inst| match inst.as_ref() {
-                // Inline this function if it could be inlined, and it is not on the nested stack.
-                // If this function is on the nested stack, it is a recursive call. Inlining
-                // recursive call will lead to infinite recursion in inliner.
-                Inst::Call { func, arg: _, dst: _ }
-                if self.tgt.contains(func) && !self.nested.contains(func) => true,
+            // Find the first call instruction to be inlined, and record decisions of call
+            // instructions before it.
+            let inst: Vec<_> = blk.inst.borrow().iter().cloned().collect();
+            let pos = inst.iter().position(|instr| match instr.as_ref() {
+                Inst::Call { func, arg: _, dst: _ } => {
+                    let (inlined, reason) = self.decide(caller, &blk, instr, func);
+                    self.decision.push(InlineDecision {
+                        caller: caller.name.clone(),
+                        callee: func.name.clone(),
+                        inlined,
+                        reason,
+                    });
+                    inlined
+                }
                 _ => false
             });
             let pos = if let Some(pos) = pos { pos } else { return; };
@@ -105,10 +199,12 @@ impl Inliner {
             let (callee, arg, dst) = if let Inst::Call { func, arg, dst } = call.as_ref() {
                 (func, arg, dst)
             } else { unreachable!() };
+            self.growth += self.cost.fn_size(callee);
             let (ent, exit) = self.inl_fn(caller, callee, arg);
 
             // Split the block separated by call instruction
             let blk_split = self.blk_gen.as_mut().unwrap().rename(&blk);
+            blk_split.meta.replace(blk.meta.borrow().clone());
             blk_split.succ.replace(blk.succ.borrow().clone());
             let inst_split = blk.inst.borrow_mut().split_off(pos);
             blk_split.inst.replace(inst_split);
@@ -116,18 +212,18 @@ impl Inliner {
             // Insert blocks of callee into caller block
             blk.succ.replace(vec![ent.clone()]); // connect to entry block
             blk.push_back(ExtRc::new(Inst::Jmp { tgt: RefCell::new(ent.clone()) }));
-            ent.pred.replace(vec![blk.clone()]);
-            blk_split.pred.replace(exit.iter().map(|exit| { // connect to exit blocks
+            ent.set_pred(vec![blk.clone()]);
+            blk_split.set_pred(exit.iter().map(|exit| { // connect to exit blocks
                 exit.succ.replace(vec![blk_split.clone()]); // connect to the split block
                 exit
             }).cloned().collect());
 
             // Collect return result
             blk_split.inst.borrow_mut().pop_front(); // remove the call instruction
-            dst.as_ref().map(|dst| {
+            let (phi, mov): (Vec<_>, Vec<_>) = dst.iter().enumerate().map(|(i, dst)| {
                 // Create phi source operands
                 let phi_src: Vec<_> = exit.clone().into_iter().map(|exit| {
-                    let val = exit.inst.borrow().back().unwrap().src()[0].clone();
+                    let val = exit.inst.borrow().back().unwrap().src()[i].clone();
                     let ret_sym = self.sym_gen.gen(&val.borrow().get_type());
                     exit.insert_before_ctrl(ExtRc::new(Inst::Mov {
                         src: val,
@@ -139,15 +235,15 @@ impl Inliner {
                 // Assign returned result to destination
                 let ref dst_ty = dst.borrow().get_type();
                 let collect_sym = self.sym_gen.gen(dst_ty);
-                blk_split.push_front(ExtRc::new(Inst::Mov {
+                let mov = Inst::Mov {
                     src: RefCell::new(Value::Var(collect_sym.clone())),
                     dst: dst.clone(),
-                }));
-                blk_split.push_front(ExtRc::new(Inst::Phi { // add phi in front of split block
-                    src: phi_src,
-                    dst: RefCell::new(collect_sym),
-                }));
-            });
+                };
+                (Inst::Phi { src: phi_src, dst: RefCell::new(collect_sym) }, mov)
+            }).unzip();
+            // Add phi's in front of split block, followed by the moves
+            mov.into_iter().rev().for_each(|mov| blk_split.push_front(ExtRc::new(mov)));
+            phi.into_iter().rev().for_each(|phi| blk_split.push_front(ExtRc::new(phi)));
 
             // Connect exit blocks to split block of caller function
             exit.iter().for_each(|exit| {
@@ -176,81 +272,50 @@ impl Inliner {
         // Push this function to nested stack
         self.nested.push(callee.clone());
 
-        // Create corresponding blocks of callee
-        callee.iter_dom().for_each(|ref b| {
-            self.blk_map.insert(b.clone(), self.blk_gen.as_mut().unwrap().rename(b));
-        });
-        self.blk_map.iter().for_each(|(prev, new)| {
-            new.pred.replace(prev.pred.borrow().iter()
-                .map(|p| self.blk_map[p].clone()).collect()
-            );
-            new.succ.replace(prev.succ.borrow().iter()
-                .map(|s| self.blk_map[s].clone()).collect()
-            );
-        });
+        // Clone body of callee into caller
+        let mut map = CloneMap::new();
+        let (ent, exit) = callee.clone_body(caller, self.blk_gen.as_mut().unwrap(), &mut map);
 
         // Push current version of function exits to stack
-        self.exit.push(callee.exit.borrow().iter().map(|b| self.blk_map[b].clone()).collect());
-
-        // Map symbols
-        callee.scope.collect().into_iter().for_each(|ref s| {
-            self.sym_map.insert(s.clone(), self.sym_gen.rename(s));
-        });
-
-        // Transfer instructions to new block
-        self.blk_map.clone().iter().for_each(|(prev, new)| self.trans_inst(caller, prev, new));
+        self.exit.push(exit);
 
         // Assign arguments to parameters
-        let ent = self.blk_map[callee.ent.borrow().deref()].clone();
         callee.param.iter().zip(arg).for_each(|(p, a)| {
             ent.push_front(ExtRc::new(Inst::Mov {
                 src: a.clone(),
-                dst: RefCell::new(self.sym_map[p.borrow().deref()].clone()),
+                dst: RefCell::new(map.sym[p.borrow().deref()].clone()),
             }));
         });
 
+        // Do nested inline expansion in the new blocks, if some instruction calls another
+        // function that could be inlined.
+        callee.iter_dom().for_each(|b| self.proc_blk(caller, map.blk[&b].clone()));
+
         // Pop this function from nested stack
         self.nested.pop();
 
         // Return entry and exit blocks of inlined function
         (ent, self.exit.pop().unwrap())
     }
+}
 
-    /// Transfer instructions from one block to another. Possibly inline functions in the new block
-    /// if some instruction calls another function that could be inlined.
-    fn trans_inst(&mut self, caller: &FnRef, from: &BlockRef, to: &BlockRef) {
-        // Transfer instructions to new block
-        to.inst.replace(from.inst.borrow().iter().map(|inst| {
-            // Clone the original instruction
-            let inst = ExtRc::new(inst.as_ref().clone());
-
-            // Map symbols
-            inst.src().iter().for_each(|src| {
-                src.replace_with(|v| {
-                    match v {
-                        Value::Var(sym) if sym.is_local_var() =>
-                            Value::Var(self.sym_map[&sym].clone()),
-                        _ => v.clone()
-                    }
-                });
-            });
-            inst.dst().map(|dst| {
-                if dst.borrow().is_global_var() { return; }
-                let ref sym = dst.borrow().clone();
-                dst.replace(self.sym_map[sym].clone());
-            });
-
-            // Map blocks
-            inst.blk().iter().for_each(|blk| {
-                let ref b = blk.borrow().clone();
-                blk.replace(self.blk_map[b].clone());
-            });
-
-            inst
-        }).collect());
+/// Decision of inliner at a call site
+#[derive(Clone, Debug)]
+pub struct InlineDecision {
+    /// Name of the function where the call site is
+    pub caller: String,
+    /// Name of the called function
+    pub callee: String,
+    /// Whether the callee is inlined
+    pub inlined: bool,
+    /// Why the callee is or is not inlined
+    pub reason: String,
+}
 
-        // Do nested inline expansion in the new block
-        self.proc_blk(caller, to.clone());
+impl Display for InlineDecision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "@{} -> @{}: {}, {}", self.caller, self.callee,
+               if self.inlined { "inlined" } else { "not inlined" }, self.reason)
     }
 }
 
@@ -283,3 +348,91 @@ fn test_inl() {
     let rcd = mach.run(&mut pro).unwrap();
     println!("{:?}", rcd);
 }
+
+#[test]
+fn test_pgo_inl() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::manager::PassManager;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+
+[ssa]
+fn @main() {
+%Begin: !freq 1
+    $a <- call i64 @inc(1) !prof 100
+    $b <- call i64 @inc($a)
+    $c <- call i64 @big($b) !prof 100
+    jmp %Hot
+%Hot: !freq 50
+    $d <- call i64 @inc($c)
+    $e <- call i64 @main2($d)
+    @r <- mov i64 $e
+    ret
+}
+
+[ssa, noinline]
+fn @main2($x: i64) -> i64 {
+%Begin:
+    ret $x
+}
+
+[ssa]
+fn @inc($x: i64) -> i64 {
+%Begin:
+    $y <- add i64 $x, 1
+    ret $y
+}
+
+[ssa]
+fn @big($x: i64) -> i64 {
+%Begin:
+    $a <- add i64 $x, 1
+    $b <- add i64 $a, 1
+    $c <- add i64 $b, 1
+    ret $c
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let mut mgr = PassManager::new().add("inline", Inliner::new().hot(10).max_size(3));
+    mgr.run(&mut pro);
+    let report: Vec<_> = mgr.remarks().iter().map(|r| r.to_string()).collect();
+    report.iter().for_each(|l| println!("{}", l));
+    assert_eq!(report, [
+        "inline: applied in @main: inlined @inc, hot call site (freq 100, size 2)",
+        "inline: missed in @main: @inc not inlined, cold call site (freq 1)",
+        "inline: missed in @main: @big not inlined, callee too large (size 4)",
+        "inline: applied in @main: inlined @inc, hot call site (freq 50, size 2)",
+        "inline: missed in @main: @main2 not inlined, marked noinline",
+    ]);
+    assert_eq!(mgr.stats()[0].to_string(), "inline: 2 applied, 3 missed, 0 analysis");
+    let rcd = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", rcd.global[0].1.get_const()), "I64(7)");
+
+    // Size budget of the caller is exhausted after the first inlining
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let mut inl = Inliner::new().hot(10).budget(2);
+    Pass::run(&mut inl, &mut pro);
+    assert_eq!(inl.decision[3].to_string(),
+               "@main -> @inc: not inlined, size budget exhausted (size 2)");
+
+    // Callees are measured by the given cost model
+    struct FreeArith;
+    impl CostModel for FreeArith {
+        fn latency(&self, instr: &Inst) -> usize { DefaultCost.latency(instr) }
+        fn size(&self, instr: &Inst) -> usize {
+            match instr {
+                Inst::Bin { .. } => 0,
+                _ => DefaultCost.size(instr)
+            }
+        }
+    }
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let mut inl = Inliner::new().hot(10).max_size(3).cost(FreeArith);
+    Pass::run(&mut inl, &mut pro);
+    assert_eq!(inl.decision[2].to_string(),
+               "@main -> @big: inlined, hot call site (freq 100, size 1)");
+}
@@ -0,0 +1,379 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::iter::FromIterator;
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{BinOp, Inst, InstRef, UnOp};
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Const, SymbolGen, SymbolRef, Type, Typed, Value};
+use crate::pass::{FnPass, Pass};
+
+/// Operand of a lexical expression
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+enum Opd {
+    Var(SymbolRef),
+    Const(Const),
+}
+
+impl Opd {
+    /// Only local variables and constants are accepted, because global variables may be
+    /// modified by function calls.
+    fn from(val: &RefCell<Value>) -> Option<Opd> {
+        match val.borrow().deref() {
+            Value::Var(sym) if sym.is_local_var() => Some(Opd::Var(sym.clone())),
+            Value::Var(_) => None,
+            Value::Const(c) => Some(Opd::Const(*c))
+        }
+    }
+
+    fn to_value(&self) -> RefCell<Value> {
+        match self {
+            Opd::Var(sym) => RefCell::new(Value::Var(sym.clone())),
+            Opd::Const(c) => RefCell::new(Value::Const(*c))
+        }
+    }
+}
+
+/// Lexical expression, identified by its operator and operands
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+enum Expr {
+    Un(UnOp, Opd),
+    Bin(BinOp, Opd, Opd),
+}
+
+impl Expr {
+    fn from(instr: &Inst) -> Option<Expr> {
+        match instr {
+            Inst::Un { op, opd, dst: _ } => Some(Expr::Un(*op, Opd::from(opd)?)),
+            Inst::Bin { op, fst, snd, dst: _ } =>
+                Some(Expr::Bin(*op, Opd::from(fst)?, Opd::from(snd)?)),
+            _ => None
+        }
+    }
+
+    /// Whether this expression is killed by a definition of `sym`
+    fn uses(&self, sym: &SymbolRef) -> bool {
+        let var = Opd::Var(sym.clone());
+        match self {
+            Expr::Un(_, opd) => opd == &var,
+            Expr::Bin(_, fst, snd) => fst == &var || snd == &var
+        }
+    }
+
+    fn to_inst(&self, dst: SymbolRef) -> Inst {
+        let dst = RefCell::new(dst);
+        match self {
+            Expr::Un(op, opd) => Inst::Un { op: *op, opd: opd.to_value(), dst },
+            Expr::Bin(op, fst, snd) =>
+                Inst::Bin { op: *op, fst: fst.to_value(), snd: snd.to_value(), dst }
+        }
+    }
+}
+
+type ExprSet = BTreeSet<usize>;
+
+/// Local properties of a block
+#[derive(Default, Debug)]
+struct LocalSet {
+    /// Expressions computed in this block before any of their operands is defined
+    antloc: ExprSet,
+    /// Expressions computed in this block after which none of their operands is defined
+    comp: ExprSet,
+    /// Expressions whose operands are not defined in this block
+    transp: ExprSet,
+}
+
+/// Partial Redundancy Elimination by Lazy Code Motion
+/// See Knoop et al., Lazy Code Motion, PLDI 1992, and Drechsler and Stadel, A Variation of Knoop,
+/// Rüthing, and Steffen's Lazy Code Motion, SIGPLAN Notices 1993.
+/// Unlike `PreOpt`, this pass works on lexically identical expressions, so it only processes
+/// functions not in SSA form.
+pub struct LcmOpt {
+    /// All expressions found in the function
+    expr: Vec<(Expr, Type)>,
+    /// Map expressions to their indices in `expr`
+    index: HashMap<Expr, usize>,
+}
+
+impl Pass for LcmOpt {
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+}
+
+impl FnPass for LcmOpt {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        if func.ssa.get() { return false; }
+
+        // Make sure the CFG is edge split, so that insertions on edges can be placed in blocks
+        let split = func.split_edge();
+        let blocks: Vec<BlockRef> = func.rpo().collect();
+
+        // Collect expressions and compute local properties
+        self.expr.clear();
+        self.index.clear();
+        blocks.iter().for_each(|block| block.for_each(|instr| {
+            if let Some(expr) = Expr::from(&instr) {
+                if self.index.contains_key(&expr) { return; }
+                self.index.insert(expr.clone(), self.expr.len());
+                self.expr.push((expr, instr.dst().unwrap().borrow().get_type()));
+            }
+        }));
+        if self.expr.is_empty() { return split; }
+        let univ = ExprSet::from_iter(0..self.expr.len());
+        let local: HashMap<BlockRef, LocalSet> = blocks.iter()
+            .map(|block| (block.clone(), self.local_set(block))).collect();
+
+        // Compute available expressions in forward direction
+        let ent = func.ent.borrow().clone();
+        let mut av_out: HashMap<BlockRef, ExprSet> = blocks.iter()
+            .map(|block| (block.clone(), univ.clone())).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in &blocks {
+                let av_in = if block == &ent { ExprSet::new() } else {
+                    Self::meet(block.pred().iter().map(|pred| &av_out[pred]), &univ)
+                };
+                let new = Self::transfer(&local[block], &local[block].comp, &av_in);
+                if new != av_out[block] {
+                    av_out.insert(block.clone(), new);
+                    changed = true;
+                }
+            }
+        }
+
+        // Compute anticipated expressions in backward direction
+        let mut ant_in: HashMap<BlockRef, ExprSet> = blocks.iter()
+            .map(|block| (block.clone(), univ.clone())).collect();
+        let mut ant_out: HashMap<BlockRef, ExprSet> = HashMap::new();
+        changed = true;
+        while changed {
+            changed = false;
+            for block in blocks.iter().rev() {
+                let out = Self::meet(block.succ.borrow().iter().map(|succ| &ant_in[succ]),
+                                     &ExprSet::new());
+                let new = Self::transfer(&local[block], &local[block].antloc, &out);
+                ant_out.insert(block.clone(), out);
+                if new != ant_in[block] {
+                    ant_in.insert(block.clone(), new);
+                    changed = true;
+                }
+            }
+        }
+
+        // Compute earliest placement on each edge. The entrance block has a virtual incoming
+        // edge from outside of the function.
+        let earliest = |from: Option<&BlockRef>, to: &BlockRef| -> ExprSet {
+            match from {
+                Some(from) => ant_in[to].iter().copied().filter(|e| {
+                    !av_out[from].contains(e) &&
+                        (!local[from].transp.contains(e) || !ant_out[from].contains(e))
+                }).collect(),
+                None => ant_in[to].clone()
+            }
+        };
+
+        // Compute latest placement by delaying computations as far as possible
+        let mut later_in: HashMap<BlockRef, ExprSet> = blocks.iter()
+            .map(|block| (block.clone(), univ.clone())).collect();
+        let later = |from: Option<&BlockRef>, to: &BlockRef,
+                     later_in: &HashMap<BlockRef, ExprSet>| -> ExprSet {
+            let mut set = earliest(from, to);
+            if let Some(from) = from {
+                set.extend(later_in[from].difference(&local[from].antloc));
+            }
+            set
+        };
+        let in_edges = |block: &BlockRef| -> Vec<Option<BlockRef>> {
+            let mut edges: Vec<_> = block.pred().into_iter().map(Some).collect();
+            if block == &ent { edges.push(None) }
+            edges
+        };
+        changed = true;
+        while changed {
+            changed = false;
+            for block in &blocks {
+                let sets: Vec<_> = in_edges(block).iter()
+                    .map(|from| later(from.as_ref(), block, &later_in)).collect();
+                let new = Self::meet(sets.iter(), &univ);
+                if new != later_in[block] {
+                    later_in.insert(block.clone(), new);
+                    changed = true;
+                }
+            }
+        }
+
+        // Compute insertion set on each edge and deletion set in each block
+        let mut insert: Vec<(Option<BlockRef>, BlockRef, ExprSet)> = vec![];
+        for block in &blocks {
+            for from in in_edges(block) {
+                let set: ExprSet = later(from.as_ref(), block, &later_in)
+                    .difference(&later_in[block]).copied().collect();
+                if !set.is_empty() { insert.push((from, block.clone(), set)) }
+            }
+        }
+        let delete: HashMap<BlockRef, ExprSet> = blocks.iter().map(|block| {
+            (block.clone(), local[block].antloc.difference(&later_in[block]).copied().collect())
+        }).collect();
+
+        // Create temporaries for expressions to be moved. The others are left untouched.
+        let mut moved = ExprSet::new();
+        insert.iter().for_each(|(_, _, set)| moved.extend(set.iter()));
+        delete.values().for_each(|set| moved.extend(set.iter()));
+        if moved.is_empty() { return split; }
+        let mut gen = SymbolGen::new(func.scope.clone(), "l");
+        let tmp: HashMap<usize, SymbolRef> = moved.iter()
+            .map(|e| (*e, gen.gen(&self.expr[*e].1))).collect();
+
+        // Decide where to insert computations on edges. Insertions are placed at the end of
+        // predecessor if it has only one successor. Otherwise the edge is not critical, so the
+        // successor has only one incoming edge, and insertions are placed at its beginning.
+        let mut exit_set: HashMap<BlockRef, ExprSet> = HashMap::new();
+        let mut entry_set: HashMap<BlockRef, ExprSet> = HashMap::new();
+        for (from, to, set) in insert {
+            match from {
+                Some(from) if from.succ.borrow().len() == 1 => { exit_set.insert(from, set); }
+                _ => { entry_set.insert(to, set); }
+            }
+        }
+
+        // Replace redundant computations with temporaries, and insert computations
+        for block in &blocks {
+            let insert = entry_set.remove(block).unwrap_or_default();
+            self.rewrite(func, block, &tmp, &insert, delete[block].clone());
+            exit_set.remove(block).unwrap_or_default().iter().for_each(|e| {
+                let instr = self.expr[*e].0.to_inst(tmp[e].clone());
+                block.insert_before_ctrl(ExtRc::new(instr))
+            });
+        }
+        true
+    }
+}
+
+impl LcmOpt {
+    pub fn new() -> LcmOpt {
+        LcmOpt { expr: vec![], index: Default::default() }
+    }
+
+    fn local_set(&self, block: &BlockRef) -> LocalSet {
+        let mut set = LocalSet::default();
+        let instr: Vec<InstRef> = block.inst.borrow().iter().cloned().collect();
+
+        // Find upward exposed expressions
+        let mut def: Vec<SymbolRef> = vec![];
+        for instr in &instr {
+            if let Some(e) = Expr::from(instr) {
+                if !def.iter().any(|sym| e.uses(sym)) { set.antloc.insert(self.index[&e]); }
+            }
+            instr.dsts().iter().for_each(|dst| def.push(dst.borrow().clone()));
+        }
+
+        // Find downward exposed expressions
+        def.clear();
+        for instr in instr.iter().rev() {
+            instr.dsts().iter().for_each(|dst| def.push(dst.borrow().clone()));
+            if let Some(e) = Expr::from(instr) {
+                if !def.iter().any(|sym| e.uses(sym)) { set.comp.insert(self.index[&e]); }
+            }
+        }
+
+        // Find transparent expressions
+        set.transp = (0..self.expr.len())
+            .filter(|e| !def.iter().any(|sym| self.expr[*e].0.uses(sym))).collect();
+        set
+    }
+
+    /// Compute intersection of sets. If there is no set, `empty` is returned.
+    fn meet<'a, I>(mut sets: I, empty: &ExprSet) -> ExprSet
+        where I: Iterator<Item=&'a ExprSet>
+    {
+        match sets.next() {
+            Some(first) => sets.fold(first.clone(), |acc, set| {
+                acc.intersection(set).copied().collect()
+            }),
+            None => empty.clone()
+        }
+    }
+
+    /// Compute `gen ∪ (input ∩ transp)`.
+    fn transfer(local: &LocalSet, gen: &ExprSet, input: &ExprSet) -> ExprSet {
+        let mut out: ExprSet = input.intersection(&local.transp).copied().collect();
+        out.extend(gen.iter());
+        out
+    }
+
+    /// Rewrite instructions in `block`. Expressions in `insert` are computed at the beginning of
+    /// this block. The first computation of expressions in `delete` is redundant. Other
+    /// computations save their results to temporaries for later use.
+    fn rewrite(&self, func: &FnRef, block: &BlockRef, tmp: &HashMap<usize, SymbolRef>,
+               insert: &ExprSet, mut delete: ExprSet)
+    {
+        let mut new_list = vec![];
+        let mut avail = insert.clone();
+        for e in insert {
+            new_list.push(ExtRc::new(self.expr[*e].0.to_inst(tmp[e].clone())));
+        }
+        for instr in block.inst.borrow().iter() {
+            match Expr::from(instr).map(|e| self.index[&e]).filter(|e| tmp.contains_key(e)) {
+                Some(e) => {
+                    let dst = instr.dst().unwrap().clone();
+                    let mov = ExtRc::new(Inst::Mov {
+                        src: RefCell::new(Value::Var(tmp[&e].clone())),
+                        dst,
+                    });
+                    func.move_inst_meta(instr, &mov);
+                    // The value is already in the temporary if the computation is redundant
+                    if !avail.contains(&e) && !delete.remove(&e) {
+                        new_list.push(ExtRc::new(self.expr[e].0.to_inst(tmp[&e].clone())));
+                    }
+                    avail.insert(e);
+                    new_list.push(mov);
+                }
+                None => new_list.push(instr.clone())
+            }
+            instr.dst().map(|dst| {
+                avail.retain(|e| !self.expr[*e].0.uses(dst.borrow().deref()))
+            });
+        }
+        block.inst.replace(new_list.into_iter().collect());
+    }
+}
+
+#[test]
+fn test_lcm() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+    use std::io::stdout;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+    use std::borrow::BorrowMut;
+
+    let mut file = File::open("test/lcm.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let mut pro = builder.build().unwrap();
+    let mut mach = Machine::new();
+    let before = mach.run(&mut pro).unwrap();
+
+    let mut opt = LcmOpt::new();
+    Pass::run(&mut opt, &mut pro);
+    let mut out = stdout();
+    let mut printer = Printer::new(out.borrow_mut());
+    printer.print(&pro).unwrap();
+
+    // Loop invariant is hoisted out of loop body
+    let body = pro.func[1].iter_dom().find(|b| b.name == "Body").unwrap();
+    assert!(body.inst.borrow().iter()
+        .all(|i| !matches!(i.as_ref(), Inst::Bin { op: BinOp::Mul, .. })));
+
+    let mut mach = Machine::new();
+    let after = mach.run(&mut pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
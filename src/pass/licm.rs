@@ -3,33 +3,56 @@ use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
 
 use crate::lang::func::{BlockRef, FnRef};
-use crate::lang::inst::InstRef;
+use crate::lang::inst::{Inst, InstRef};
 use crate::lang::Program;
-use crate::lang::ssa::{DefPos, DefUseMap};
+use crate::lang::ssa::{DefPos, DefUseGraph};
 use crate::lang::util::WorkList;
 use crate::lang::value::{SymbolRef, Value};
 use crate::pass::{FnPass, Pass};
+use crate::pass::analysis::AnalysisCache;
+use crate::pass::remark::Remark;
 use crate::pass::util::LoopNodeRef;
 
-pub struct LicmOpt {}
+pub struct LicmOpt {
+    /// Remarks made in the last run
+    remark: Vec<Remark>,
+}
 
 impl Pass for LicmOpt {
-    fn run(&mut self, pro: &mut Program) { FnPass::run(self, pro) }
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.remark.clear();
+        FnPass::run(self, pro)
+    }
+
+    fn run_cached(&mut self, pro: &mut Program, cache: &mut AnalysisCache) -> bool {
+        self.remark.clear();
+        FnPass::run_cached(self, pro, cache)
+    }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
 }
 
 impl FnPass for LicmOpt {
-    fn run_on_fn(&mut self, func: &FnRef) {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        self.run_on_fn_cached(func, &mut AnalysisCache::new())
+    }
+
+    fn run_on_fn_cached(&mut self, func: &FnRef, cache: &mut AnalysisCache) -> bool {
         // LICM requires SSA form
         func.assert_ssa();
 
         // Build loop-nest trees
-        let trees = func.analyze_loop();
+        let trees = cache.loops(func);
+        let n_rm = self.remark.len();
+
+        // Get define-use information, which is updated as instructions are hoisted
+        let ref def_use = cache.def_use(func);
 
         // Hoist code in post order of loop-nest tree
         let mut stack: Vec<_> = trees.into_iter().map(|node| (node, false)).collect();
         loop {
             match stack.pop() {
-                Some((node, true)) => self.opt_loop(func, node),
+                Some((node, true)) => self.opt_loop(func, node, def_use),
                 Some((node, false)) => {
                     stack.push((node.clone(), true));
                     node.borrow().nested.clone().into_iter()
@@ -38,18 +61,14 @@ impl FnPass for LicmOpt {
                 None => break
             }
         }
+        self.remark.len() > n_rm
     }
 }
 
 impl LicmOpt {
-    pub fn new() -> LicmOpt { LicmOpt {} }
-
-    fn opt_loop(&self, func: &FnRef, node: LoopNodeRef) {
-        // Get define-use information
-        // This should be computed in each loop, because the definition point of a value may have
-        // changed when processing the previous loop.
-        let ref def_use = func.def_use();
+    pub fn new() -> LicmOpt { LicmOpt { remark: vec![] } }
 
+    fn opt_loop(&mut self, func: &FnRef, node: LoopNodeRef, def_use: &DefUseGraph) {
         // Build instruction work list
         let mut instr_list: HashSet<InstRef> = HashSet::new();
         let level = node.borrow().level_blocks();
@@ -61,7 +80,7 @@ impl LicmOpt {
         // Iteratively find all loop invariants and hoist them
         let ref header = node.borrow().header.clone();
         let ref mut hoist: HashMap<SymbolRef, BlockRef> = HashMap::new();
-        let ref mut removed: HashSet<InstRef> = HashSet::new();
+        let mut n_hoist = 0;
         loop {
             match work.pick() {
                 Some(instr) => {
@@ -72,8 +91,15 @@ impl LicmOpt {
                     }
                     let ref dst = dst.unwrap().borrow().clone();
 
-                    // Check whether this instruction has side effects
+                    // Check whether this instruction has side effects, or depends on its
+                    // position. Phis depend on control flow, loads may be affected by stores
+                    // in the loop, and trapping operations may not be executed in the loop.
                     if instr.has_side_effect() { continue; }
+                    match instr.as_ref() {
+                        Inst::Phi { src: _, dst: _ } | Inst::Ld { ptr: _, dst: _ } => continue,
+                        Inst::Bin { op, fst: _, snd: _, dst: _ } if op.is_trapping() => continue,
+                        _ => {}
+                    }
 
                     // Check whether all operands are loop invariant
                     let src = instr.src();
@@ -86,31 +112,36 @@ impl LicmOpt {
                         .fold(func.ent.borrow().clone(), |a, b| {
                             if a.strict_dom(&b) { b } else { a }
                         });
+                    if let DefPos::Inst(orig, _) = def_use.def(dst) {
+                        orig.retain(|i| *i != instr);
+                    }
+                    def_use.erase(&instr);
                     blk.insert_before_ctrl(instr.clone());
+                    def_use.insert(&blk, &instr);
                     hoist.insert(dst.clone(), blk);
-                    removed.insert(instr);
+                    instr_list.remove(&instr);
+                    n_hoist += 1;
 
                     // Add uses of destination symbol to worklist
-                    def_use[dst].uses.iter()
+                    def_use.uses(dst).iter()
                         .filter(|u| instr_list.contains(u))
                         .for_each(|u| work.insert(u.clone()))
                 }
                 None => break
             }
         }
-
-        // Remove instruction in their original block
-        level.iter().for_each(|blk| {
-            blk.inst.borrow_mut().retain(|instr| !removed.contains(instr))
-        })
+        if n_hoist > 0 {
+            self.remark.push(Remark::applied(&func.name, format!(
+                "hoisted {} instructions out of loop %{}", n_hoist, header.name)));
+        }
     }
 
-    fn is_invariant(val: &RefCell<Value>, header: &BlockRef, def_use: &DefUseMap,
+    fn is_invariant(val: &RefCell<Value>, header: &BlockRef, def_use: &DefUseGraph,
                     hoist: &HashMap<SymbolRef, BlockRef>) -> bool
     {
         match val.borrow().deref() {
             Value::Const(_) => true,
-            Value::Var(sym) if sym.is_local_var() => match &def_use[sym].def {
+            Value::Var(sym) if sym.is_local_var() => match def_use.def(sym) {
                 DefPos::Param => true,
                 DefPos::Inst(blk, _) => blk.strict_dom(header) || hoist.contains_key(sym),
                 DefPos::None => unreachable!()
@@ -119,17 +150,17 @@ impl LicmOpt {
         }
     }
 
-    fn def_block(val: &RefCell<Value>, func: &FnRef, def_use: &DefUseMap,
+    fn def_block(val: &RefCell<Value>, func: &FnRef, def_use: &DefUseGraph,
                  hoist: &HashMap<SymbolRef, BlockRef>) -> BlockRef
     {
         let ent = func.ent.borrow().clone();
         match val.borrow().deref() {
             Value::Const(_) => ent,
-            Value::Var(sym) => match &def_use[sym].def {
+            Value::Var(sym) => match def_use.def(sym) {
                 DefPos::Param => ent,
                 DefPos::Inst(blk, _) => match hoist.get(sym) {
                     Some(new_blk) => new_blk.clone(),
-                    None => blk.clone()
+                    None => blk
                 }
                 DefPos::None => unreachable!()
             }
@@ -167,7 +198,11 @@ fn test_licm() {
     // println!("ptr: {:?}", mach.run(&pro).unwrap());
     FnPass::run(&mut PreOpt::new(), &mut pro);
     // println!("pre: {:?}", mach.run(&pro).unwrap());
-    FnPass::run(&mut LicmOpt::new(), &mut pro);
+    let mut licm = LicmOpt::new();
+    Pass::run(&mut licm, &mut pro);
+    licm.remarks().iter().for_each(|r| println!("{}", r));
+    assert!(licm.remarks().iter().all(|r| r.msg.starts_with("hoisted")));
+    assert!(!licm.remarks().is_empty());
     // println!("licm: {:?}", mach.run(&pro).unwrap());
     FnPass::run(&mut OsrOpt::new(), &mut pro);
     println!("osr: {:?}", mach.run(&pro).unwrap());
@@ -175,4 +210,45 @@ fn test_licm() {
     let mut out = stdout();
     let mut printer = Printer::new(out.borrow_mut());
     printer.print(&pro).unwrap();
-}
\ No newline at end of file
+}
+#[test]
+fn test_licm_atomic() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::manager::PassManager;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let src = std::fs::read_to_string("test/atomic.ir").unwrap();
+    let mut pro = Builder::new(Parser::new(Lexer::from(src.as_str())).parse().unwrap())
+        .build().unwrap();
+    let expect = format!("{:?}", Machine::new().run(&pro).unwrap().global);
+    assert!(expect.contains("I64(42)") && expect.contains("I64(288)"));
+
+    // Atomic instructions are printed in the same syntax
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let printed = String::from_utf8(out).unwrap();
+    let reparsed = Builder::new(Parser::new(Lexer::from(printed.as_str())).parse().unwrap())
+        .build().unwrap();
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&reparsed).unwrap().global));
+
+    // Invariant computation is hoisted out of the spin loop, but the atomic load is not, and
+    // atomic instructions whose results are not used are kept.
+    let count = |pro: &Program| pro.func.iter().flat_map(|f| f.dfs())
+        .flat_map(|b| b.inst.borrow().clone()).filter(|i| i.is_atomic()).count();
+    let n_atomic = count(&pro);
+    pro.func.iter().for_each(|f| f.to_ssa());
+    PassManager::parse("adce,licm,dce").unwrap().run(&mut pro);
+    let mut ver = VerifyPass::new();
+    ver.run(&mut pro);
+    assert!(ver.is_ok());
+    assert_eq!(count(&pro), n_atomic);
+    let wait = pro.func.iter().find(|f| f.name == "wait").unwrap();
+    let spin = wait.dfs().find(|b| b.name == "Spin").unwrap();
+    let names: Vec<_> = spin.inst.borrow().iter().map(|i| i.name()).collect();
+    assert!(names.contains(&"ld.atomic".to_string()) && !names.contains(&"mul".to_string()));
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+}
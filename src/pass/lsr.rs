@@ -0,0 +1,251 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{BinOp, Inst, InstRef};
+use crate::lang::Program;
+use crate::lang::ssa::{DefPos, DefUseGraph};
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Const, Scope, SymbolGen, SymbolRef, Type, Typed, Value};
+use crate::pass::{FnPass, Pass};
+use crate::pass::analysis::AnalysisCache;
+use crate::pass::util::LoopNodeRef;
+
+/// Loop Strength Reduction of pointer arithmetic
+/// A pointer `ptr *T $base, $i` in a loop, where `$base` is loop invariant and `$i` is a basic
+/// induction variable incremented by a constant step, is replaced by a pointer phi in the loop
+/// header. The phi starts at `$base` offset by the initial value of `$i`, and is offset by the
+/// step in each iteration, so that no indexing is done on `$base` in the loop.
+/// Only pointers with offsets are reduced. Indices should be expanded to offsets by `PtrExp`
+/// before this pass. The reduced pointers become copies of the phis, which can be removed by
+/// copy propagation.
+pub struct LsrOpt {
+    /// Symbol generator for current function
+    gen: SymbolGen,
+}
+
+/// Basic induction variable of a loop
+struct IndVar {
+    /// Symbol defined by the phi in loop header
+    sym: SymbolRef,
+    /// Initial value from preheader
+    init: RefCell<Value>,
+    /// Block and instruction where this variable is incremented
+    incr: (BlockRef, InstRef),
+    /// Constant step of the increment
+    step: i64,
+}
+
+impl Pass for LsrOpt {
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+
+    fn run_cached(&mut self, pro: &mut Program, cache: &mut AnalysisCache) -> bool {
+        FnPass::run_cached(self, pro, cache)
+    }
+}
+
+impl FnPass for LsrOpt {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        self.run_on_fn_cached(func, &mut AnalysisCache::new())
+    }
+
+    fn run_on_fn_cached(&mut self, func: &FnRef, cache: &mut AnalysisCache) -> bool {
+        // LSR requires SSA form
+        func.assert_ssa();
+        self.gen = SymbolGen::new(func.scope.clone(), "t");
+
+        // Reduce pointers in post order of loop-nest tree, so that inner loops are reduced first
+        let def_use = cache.def_use(func);
+        let mut stack: Vec<_> = cache.loops(func).into_iter().map(|n| (n, false)).collect();
+        let mut changed = false;
+        loop {
+            match stack.pop() {
+                Some((node, true)) => changed |= self.opt_loop(func, &node, &def_use),
+                Some((node, false)) => {
+                    stack.push((node.clone(), true));
+                    node.borrow().nested.clone().into_iter()
+                        .for_each(|n| stack.push((n, false)));
+                }
+                None => break
+            }
+        }
+        changed
+    }
+}
+
+impl LsrOpt {
+    pub fn new() -> LsrOpt {
+        LsrOpt { gen: SymbolGen::new(Rc::new(Scope::new()), "") }
+    }
+
+    /// Reduce pointers in loop of `node`, and return whether any is reduced.
+    fn opt_loop(&mut self, func: &FnRef, node: &LoopNodeRef, def_use: &DefUseGraph) -> bool {
+        // Find the only preheader of this loop
+        let header = node.borrow().header.clone();
+        let blocks = node.borrow().all_blocks();
+        let (pre, latch): (Vec<_>, Vec<_>) = header.pred().into_iter()
+            .partition(|b| !blocks.contains(b));
+        if pre.len() != 1 { return false; }
+        let pre = &pre[0];
+
+        // Find basic induction variables
+        let iv: HashMap<_, _> = header.inst.borrow().iter().take_while(|i| i.is_phi())
+            .filter_map(|phi| Self::ind_var(phi, pre, &latch, &blocks, def_use))
+            .map(|iv| (iv.sym.clone(), iv)).collect();
+        if iv.is_empty() { return false; }
+
+        // Replace pointers indexed by induction variables with pointer phis. Pointers with the
+        // same base and induction variable share one phi.
+        let mut red: HashMap<(SymbolRef, SymbolRef), SymbolRef> = HashMap::new();
+        for block in &blocks {
+            let ptr: Vec<InstRef> = block.inst.borrow().iter()
+                .filter(|i| matches!(i.as_ref(), Inst::Ptr { base: _, off: Some(_), ind, dst: _ }
+                    if ind.is_empty())).cloned().collect();
+            for instr in ptr {
+                let (base, off, dst) = match instr.as_ref() {
+                    Inst::Ptr { base, off: Some(off), ind: _, dst } => (base, off, dst),
+                    _ => unreachable!()
+                };
+                let base = match base.borrow().deref() {
+                    Value::Var(sym) if Self::is_invariant(sym, &header, def_use) => sym.clone(),
+                    _ => continue
+                };
+                let iv = match off.borrow().deref() {
+                    Value::Var(sym) if iv.contains_key(sym) => &iv[sym],
+                    _ => continue
+                };
+                let phi = red.entry((base.clone(), iv.sym.clone()))
+                    .or_insert_with(|| self.reduce(&base, iv, pre, &header, &latch)).clone();
+                let mov = ExtRc::new(Inst::Mov {
+                    src: RefCell::new(Value::Var(phi)),
+                    dst: dst.clone(),
+                });
+                func.move_inst_meta(&instr, &mov);
+                let pos = block.inst.borrow().iter().position(|i| *i == instr).unwrap();
+                block.inst.borrow_mut()[pos] = mov;
+            }
+        }
+        !red.is_empty()
+    }
+
+    /// Create pointer phi for `base` indexed by `iv`, and return its symbol.
+    fn reduce(&mut self, base: &SymbolRef, iv: &IndVar, pre: &BlockRef, header: &BlockRef,
+              latch: &[BlockRef]) -> SymbolRef
+    {
+        let ty = base.get_type();
+        let (init, phi, next) = (self.gen.gen(&ty), self.gen.gen(&ty), self.gen.gen(&ty));
+
+        // Compute initial pointer in preheader
+        pre.insert_before_ctrl(ExtRc::new(Inst::Ptr {
+            base: RefCell::new(Value::Var(base.clone())),
+            off: Some(iv.init.clone()),
+            ind: vec![],
+            dst: RefCell::new(init.clone()),
+        }));
+
+        // Merge initial and incremented pointers in header
+        let mut src = vec![(RefCell::new(pre.clone()), RefCell::new(Value::Var(init)))];
+        latch.iter().for_each(|b| {
+            src.push((RefCell::new(b.clone()), RefCell::new(Value::Var(next.clone()))))
+        });
+        header.push_front(ExtRc::new(Inst::Phi { src, dst: RefCell::new(phi.clone()) }));
+
+        // Offset the pointer right after the induction variable is incremented
+        let (blk, incr) = &iv.incr;
+        let pos = blk.inst.borrow().iter().position(|i| i == incr).unwrap();
+        blk.inst.borrow_mut().insert(pos + 1, ExtRc::new(Inst::Ptr {
+            base: RefCell::new(Value::Var(phi.clone())),
+            off: Some(RefCell::new(Value::Const(Const::I64(iv.step)))),
+            ind: vec![],
+            dst: RefCell::new(next),
+        }));
+        phi
+    }
+
+    /// Recognize `phi` in loop header as a basic induction variable. Its operand from every
+    /// latch should be the same variable, defined by adding a constant to (or subtracting a
+    /// constant from) the phi in the loop.
+    fn ind_var(phi: &InstRef, pre: &BlockRef, latch: &[BlockRef], blocks: &[BlockRef],
+               def_use: &DefUseGraph) -> Option<IndVar>
+    {
+        let (src, sym) = match phi.as_ref() {
+            Inst::Phi { src, dst } => (src, dst.borrow().clone()),
+            _ => return None
+        };
+        if sym.get_type() != Type::I(64) { return None; }
+        let opd = |blk: &BlockRef| src.iter().find(|(b, _)| b.borrow().deref() == blk)
+            .map(|(_, v)| v.borrow().clone());
+        let init = opd(pre)?;
+        let next = match opd(latch.first()?)? {
+            Value::Var(next) => next,
+            _ => return None
+        };
+        if latch.iter().any(|b| !matches!(opd(b), Some(Value::Var(ref v)) if *v == next)) {
+            return None;
+        }
+        let (blk, incr) = match def_use.def(&next) {
+            DefPos::Inst(blk, incr) if blocks.contains(&blk) => (blk, incr),
+            _ => return None
+        };
+        let is_iv = |v: &RefCell<Value>| matches!(v.borrow().deref(), Value::Var(v) if *v == sym);
+        let step = |v: &RefCell<Value>| match v.borrow().deref() {
+            Value::Const(Const::I64(c)) => Some(*c),
+            _ => None
+        };
+        let step = match incr.as_ref() {
+            Inst::Bin { op: BinOp::Add, fst, snd, dst: _ } if is_iv(fst) => step(snd)?,
+            Inst::Bin { op: BinOp::Add, fst, snd, dst: _ } if is_iv(snd) => step(fst)?,
+            Inst::Bin { op: BinOp::Sub, fst, snd, dst: _ } if is_iv(fst) =>
+                step(snd)?.checked_neg()?,
+            _ => return None
+        };
+        Some(IndVar { sym, init: RefCell::new(init), incr: (blk, incr), step })
+    }
+
+    /// Whether `sym` is defined outside the loop with `header`.
+    fn is_invariant(sym: &SymbolRef, header: &BlockRef, def_use: &DefUseGraph) -> bool {
+        if !sym.is_local_var() { return false; }
+        match def_use.def(sym) {
+            DefPos::Param => true,
+            DefPos::Inst(blk, _) => blk.strict_dom(header),
+            DefPos::None => false
+        }
+    }
+}
+
+#[test]
+fn test_lsr() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::copy::CopyProp;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut file = File::open("test/sum.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let mut pro = Builder::new(Parser::new(lexer).parse().unwrap()).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+
+    Pass::run(&mut LsrOpt::new(), &mut pro);
+    Pass::run(&mut CopyProp::new(), &mut pro);
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut pro);
+    assert!(ver.is_ok());
+
+    // Pointers in loops are no longer indexed by induction variables
+    assert!(!out.contains("ptr *i32 $a, $i.1"));
+    assert!(out.contains("ptr *i32 $t1, 1"));
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
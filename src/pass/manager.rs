@@ -0,0 +1,357 @@
+use std::collections::HashSet;
+use std::io::Write;
+
+use crate::lang::print::Printer;
+use crate::lang::Program;
+use crate::pass::analysis::AnalysisCache;
+use crate::pass::Pass;
+use crate::pass::remark::{PassStats, Remark, RemarkKind};
+
+/// Pipeline of passes which are run in the order they are added.
+/// For debugging, the program can be printed before or after selected passes, and the pipeline
+/// can be stopped before a given pass, which allows bisecting the pass that causes a
+/// miscompilation. These are configured with methods or command line style options, such as
+/// `--print-after=gvn`.
+///
+/// In fixpoint mode, the pipeline is run round-robin until a whole round reports no change, or
+/// a cap of rounds is reached. This suits a set of cleanups, such as copy propagation, folding
+/// and dead code elimination, which expose opportunities to each other.
+///
+/// Analyses are cached across passes in a run, and invalidated after each pass which changes
+/// the program, except the ones it declares to preserve.
+pub struct PassManager {
+    /// Passes with their names
+    pass: Vec<(String, Box<dyn Pass>)>,
+    /// Names of passes before which the program is printed
+    print_before: HashSet<String>,
+    /// Names of passes after which the program is printed
+    print_after: HashSet<String>,
+    /// Whether to print the program around all passes
+    print_before_all: bool,
+    print_after_all: bool,
+    /// Index of the pass before which the pipeline stops
+    stop: Option<usize>,
+    /// Maximal number of rounds in fixpoint mode, or `None` if the pipeline is run once
+    max_rounds: Option<usize>,
+    /// Number of rounds in the last run
+    rounds: usize,
+    /// Remarks collected from passes in the last run
+    collected: Vec<Remark>,
+    /// Sink of printed program
+    out: Box<dyn Write>,
+}
+
+impl PassManager {
+    pub fn new() -> PassManager {
+        PassManager {
+            pass: vec![],
+            print_before: Default::default(),
+            print_after: Default::default(),
+            print_before_all: false,
+            print_after_all: false,
+            stop: None,
+            max_rounds: None,
+            rounds: 0,
+            collected: vec![],
+            out: Box::new(std::io::stderr()),
+        }
+    }
+
+    /// Append a pass to the pipeline.
+    pub fn add(mut self, name: &str, pass: impl Pass + 'static) -> Self {
+        self.pass.push((name.to_string(), Box::new(pass)));
+        self
+    }
+
+    /// Append a pass created by `create_pass` from its name.
+    pub fn add_named(mut self, name: &str) -> Result<Self, String> {
+        let pass = create_pass(name).ok_or(format!("unknown pass {}", name))?;
+        self.pass.push((name.to_string(), pass));
+        Ok(self)
+    }
+
+    /// Build a pipeline from its textual description, which is a whitespace separated list of
+    /// options accepted by `option` and comma separated pass names, such as
+    /// `"sccp,gvn,copy,dce --fixpoint=4"`.
+    pub fn parse(desc: &str) -> Result<PassManager, String> {
+        let mut mgr = PassManager::new();
+        for item in desc.split_whitespace() {
+            if item.starts_with("--") {
+                mgr = mgr.option(item)?;
+                continue;
+            }
+            for name in item.split(',').filter(|s| !s.is_empty()) {
+                mgr = mgr.add_named(name)?;
+            }
+        }
+        Ok(mgr)
+    }
+
+    /// Print program before passes with name `name`.
+    pub fn print_before(mut self, name: &str) -> Self {
+        self.print_before.insert(name.to_string());
+        self
+    }
+
+    /// Print program after passes with name `name`.
+    pub fn print_after(mut self, name: &str) -> Self {
+        self.print_after.insert(name.to_string());
+        self
+    }
+
+    /// Run the pipeline repeatedly until no pass reports a change, for at most `max_rounds`
+    /// rounds.
+    pub fn fixpoint(mut self, max_rounds: usize) -> Self {
+        self.max_rounds = Some(max_rounds);
+        self
+    }
+
+    /// Stop the pipeline before the pass at `index`, counting from zero. Passes at and after
+    /// this index are skipped. In fixpoint mode, passes are counted across rounds.
+    pub fn stop_at(mut self, index: usize) -> Self {
+        self.stop = Some(index);
+        self
+    }
+
+    /// Set sink of printed program. Standard error is used by default.
+    pub fn output(mut self, out: impl Write + 'static) -> Self {
+        self.out = Box::new(out);
+        self
+    }
+
+    /// Configure with an option. Supported options are `--print-before=<names>`,
+    /// `--print-after=<names>`, `--print-before-all`, `--print-after-all` and
+    /// `--stop-at=<index>` and `--fixpoint=<rounds>`, where `<names>` is a comma separated list
+    /// of pass names.
+    pub fn option(mut self, opt: &str) -> Result<Self, String> {
+        let (key, val) = match opt.find('=') {
+            Some(i) => (&opt[..i], Some(&opt[i + 1..])),
+            None => (opt, None)
+        };
+        let names = |val: Option<&str>| val.map(|v| v.split(',').map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty()).collect::<Vec<_>>())
+            .ok_or(format!("option {} requires a value", key));
+        match key {
+            "--print-before" => self.print_before.extend(names(val)?),
+            "--print-after" => self.print_after.extend(names(val)?),
+            "--print-before-all" => self.print_before_all = true,
+            "--print-after-all" => self.print_after_all = true,
+            "--stop-at" => {
+                let val = val.ok_or(format!("option {} requires a value", key))?;
+                self.stop = Some(val.parse().map_err(|_| format!("invalid index {}", val))?)
+            }
+            "--fixpoint" => {
+                let val = val.ok_or(format!("option {} requires a value", key))?;
+                self.max_rounds = Some(val.parse().map_err(|_| format!("invalid count {}", val))?)
+            }
+            _ => return Err(format!("unknown option {}", key))
+        }
+        Ok(self)
+    }
+
+    /// Number of passes in the pipeline
+    pub fn len(&self) -> usize { self.pass.len() }
+
+    /// Whether the pipeline has no passes
+    pub fn is_empty(&self) -> bool { self.pass.is_empty() }
+
+    /// Number of rounds the pipeline is run in the last run. This is one if fixpoint mode is not
+    /// enabled.
+    pub fn rounds(&self) -> usize { self.rounds }
+
+    /// Statistics of remarks made by each pass in the last run, in pipeline order.
+    pub fn stats(&self) -> Vec<PassStats> {
+        let remarks = self.remarks();
+        self.pass.iter().map(|(name, _)| {
+            let count = |kind| remarks.iter()
+                .filter(|r| r.pass == *name && r.kind == kind).count();
+            PassStats {
+                pass: name.clone(),
+                applied: count(RemarkKind::Applied),
+                missed: count(RemarkKind::Missed),
+                analysis: count(RemarkKind::Analysis),
+            }
+        }).collect()
+    }
+
+    fn dump(&mut self, pro: &Program, when: &str, i: usize, step: usize) {
+        let name = &self.pass[i].0;
+        writeln!(self.out, "// IR dump {} {} (#{})", when, name, step).unwrap();
+        Printer::new(self.out.as_mut()).print(pro).unwrap();
+    }
+}
+
+/// Create a pass by its name in pipeline descriptions, or `None` if there is no such pass.
+/// Passes are named after their modules, except that `dce` is the local one in `util`, `gdce`
+/// is the global one in `dce`, and `gconst` is the global constant propagation in `fold`.
+pub fn create_pass(name: &str) -> Option<Box<dyn Pass>> {
+    use crate::pass::*;
+    Some(match name {
+        "adce" => Box::new(adce::AdceOpt::new()),
+        "br" => Box::new(br::BrFold::new()),
+        "canon" => Box::new(canon::Canonicalize::new()),
+        "copy" => Box::new(copy::CopyProp::new()),
+        "dce" => Box::new(util::DceOpt::new()),
+        "dse" => Box::new(dse::GlobalDse::new()),
+        "escape" => Box::new(escape::EscapeOpt::new()),
+        "fold" => Box::new(fold::ConstFold::new()),
+        "gcm" => Box::new(gcm::GcmOpt::new()),
+        "gconst" => Box::new(fold::GlobalConstProp::new()),
+        "gdce" => Box::new(dce::GlobalDce::new()),
+        "gvn" => Box::new(gvn::GvnOpt {}),
+        "idiom" => Box::new(mem::LoopIdiom::new()),
+        "inline" => Box::new(inl::Inliner::new()),
+        "lcm" => Box::new(lcm::LcmOpt::new()),
+        "licm" => Box::new(licm::LicmOpt::new()),
+        "lsr" => Box::new(lsr::LsrOpt::new()),
+        "memexp" => Box::new(mem::MemExp::new()),
+        "merge" => Box::new(merge::MergeFn::new()),
+        "osr" => Box::new(osr::OsrOpt::new()),
+        "outline" => Box::new(outline::Outliner::new()),
+        "pre" => Box::new(pre::PreOpt::new()),
+        "ptr" => Box::new(util::PtrExp::new()),
+        "ret" => Box::new(ret::RetProp::new()),
+        "rewrite" => Box::new(rewrite::PatternRewrite::with_defaults()),
+        "sanitize" => Box::new(sanitize::SanitizePass::new()),
+        "sccp" => Box::new(sccp::SccpOpt::new()),
+        "spec" => Box::new(spec::FnSpec::new()),
+        "sroa" => Box::new(sroa::SroaOpt::new()),
+        "verify" => Box::new(verify::VerifyPass::new()),
+        _ => return None
+    })
+}
+
+impl Pass for PassManager {
+    /// Collect remarks of all passes in the last run, with names of the passes filled in.
+    /// Remarks from nested pass managers keep their own names.
+    fn remarks(&self) -> Vec<Remark> { self.collected.clone() }
+
+    /// Run the pipeline. In fixpoint mode, the result tells whether any round changes the
+    /// program.
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.run_cached(pro, &mut AnalysisCache::new())
+    }
+
+    fn run_cached(&mut self, pro: &mut Program, cache: &mut AnalysisCache) -> bool {
+        let max_rounds = self.max_rounds.unwrap_or(1);
+        let mut changed = false;
+        let mut step = 0;
+        self.rounds = 0;
+        self.collected.clear();
+        'round: while self.rounds < max_rounds {
+            self.rounds += 1;
+            let mut round_changed = false;
+            for i in 0..self.pass.len() {
+                if self.stop == Some(step) {
+                    writeln!(self.out, "// pipeline stopped before {} (#{})", self.pass[i].0,
+                             step).unwrap();
+                    break 'round;
+                }
+                if self.print_before_all || self.print_before.contains(&self.pass[i].0) {
+                    self.dump(pro, "before", i, step)
+                }
+                let (name, pass) = &mut self.pass[i];
+                if pass.run_cached(pro, cache) {
+                    cache.invalidate(pass.preserved());
+                    round_changed = true;
+                }
+                self.collected.extend(pass.remarks().into_iter().map(|mut r| {
+                    if r.pass.is_empty() { r.pass = name.clone(); }
+                    r
+                }));
+                if self.print_after_all || self.print_after.contains(&self.pass[i].0) {
+                    self.dump(pro, "after", i, step)
+                }
+                step += 1;
+            }
+            changed |= round_changed;
+            if !round_changed { break; }
+        }
+        changed
+    }
+}
+
+#[test]
+fn test_manager() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::copy::CopyProp;
+    use crate::pass::fold::ConstFold;
+    use crate::pass::util::DceOpt;
+    use crate::vm::exec::Machine;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Sink that can be read after the pipeline is run
+    #[derive(Clone)]
+    struct Buf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    let src = r#"
+@r: i64
+
+[ssa]
+fn @main() {
+%Begin:
+    $a <- mov i64 2
+    $b <- add i64 $a, 3
+    @r <- mul i64 $b, $b
+    ret
+}
+"#;
+    let build = || Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let run = |stop: Option<usize>| {
+        let buf = Buf(Default::default());
+        let mut mgr = PassManager::new().add("copy", CopyProp::new())
+            .add("fold", ConstFold::new()).output(buf.clone())
+            .option("--print-after=copy,fold").unwrap();
+        if let Some(i) = stop { mgr = mgr.option(&format!("--stop-at={}", i)).unwrap() }
+        let mut pro = build();
+        mgr.run(&mut pro);
+        let out = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        println!("{}", out);
+        let rcd = Machine::new().run(&pro).unwrap();
+        (out, format!("{:?}", rcd.global))
+    };
+
+    // Dumps are made after selected passes, and results do not depend on where the pipeline
+    // stops
+    let (out, all) = run(None);
+    assert!(out.contains("// IR dump after copy (#0)"));
+    assert!(out.contains("// IR dump after fold (#1)"));
+    let (out, first) = run(Some(1));
+    assert!(out.contains("// IR dump after copy (#0)"));
+    assert!(out.contains("// pipeline stopped before fold (#1)"));
+    assert!(!out.contains("after fold"));
+    assert_eq!(all, first);
+
+    // Cleanups are repeated until none of them changes the program, and a converged pipeline
+    // reports no change
+    let mut mgr = PassManager::new().add("dce", DceOpt::new()).add("copy", CopyProp::new())
+        .add("fold", ConstFold::new()).option("--fixpoint=10").unwrap();
+    let mut pro = build();
+    assert!(mgr.run(&mut pro));
+    assert!(mgr.rounds() > 1 && mgr.rounds() <= 10);
+    assert_eq!(all, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+    assert!(!mgr.run(&mut pro));
+    assert_eq!(mgr.rounds(), 1);
+
+    // Rounds are capped
+    let mut mgr = PassManager::new().add("dce", DceOpt::new()).add("fold", ConstFold::new())
+        .fixpoint(1);
+    assert!(mgr.run(&mut build()));
+    assert_eq!(mgr.rounds(), 1);
+
+    assert!(PassManager::new().option("--print-after").is_err());
+    assert!(PassManager::new().option("--fixpoint=-1").is_err());
+    assert!(PassManager::new().option("--stop-at=x").is_err());
+    assert!(PassManager::new().option("--unknown").is_err());
+}
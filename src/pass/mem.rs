@@ -0,0 +1,407 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::lang::escape::EscapeInfo;
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{BinOp, Inst, InstRef};
+use crate::lang::Program;
+use crate::lang::ssa::{DefPos, DefUseGraph};
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Const, Scope, SymbolGen, SymbolRef, Type, Typed, Value};
+use crate::pass::{FnPass, Pass};
+use crate::pass::analysis::AnalysisCache;
+use crate::pass::remark::Remark;
+use crate::pass::util::LoopNodeRef;
+
+/// Loop Idiom Recognition
+/// A loop copying elements from an array to another one by one is replaced by a `memcpy`, and
+/// a loop storing the same value to elements of an array by a `memset`, both placed in the
+/// preheader. The loop should consist of a header and a body. The header only branches to the
+/// body if `lt i64 $i, $n` holds, where `$i` is a basic induction variable incremented by one in
+/// the body, and `$n` is loop invariant. The body only accesses elements `ptr *T $a, $i` of
+/// loop invariant `$a`, either loading from one array and storing to another, or storing a loop
+/// invariant value. For `memcpy`, the two arrays should come from different allocations in the
+/// function, so that they never overlap. Values computed in the loop should not be used after
+/// it. Requires SSA form.
+pub struct LoopIdiom {
+    /// Symbol generator for current function
+    gen: SymbolGen,
+    /// Remarks made in the last run
+    remark: Vec<Remark>,
+}
+
+/// Memory operation recognized in a loop body
+enum Idiom {
+    /// Copy from base of the first pointer to base of the second one
+    Copy(SymbolRef, SymbolRef),
+    /// Store the value to base of the pointer
+    Fill(RefCell<Value>, SymbolRef),
+}
+
+impl Pass for LoopIdiom {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.remark.clear();
+        FnPass::run(self, pro)
+    }
+
+    fn run_cached(&mut self, pro: &mut Program, cache: &mut AnalysisCache) -> bool {
+        self.remark.clear();
+        FnPass::run_cached(self, pro, cache)
+    }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
+}
+
+impl FnPass for LoopIdiom {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        self.run_on_fn_cached(func, &mut AnalysisCache::new())
+    }
+
+    fn run_on_fn_cached(&mut self, func: &FnRef, cache: &mut AnalysisCache) -> bool {
+        // Loop idiom recognition requires SSA form
+        func.assert_ssa();
+        self.gen = SymbolGen::new(func.scope.clone(), "t");
+
+        // Only innermost loops are recognized
+        let def_use = cache.def_use(func);
+        let info = func.escape();
+        let mut stack = cache.loops(func);
+        let mut n_rep = 0;
+        while let Some(node) = stack.pop() {
+            if node.borrow().nested.is_empty() {
+                if self.opt_loop(func, &node, &def_use, &info) { n_rep += 1; }
+            } else {
+                stack.extend(node.borrow().nested.iter().cloned());
+            }
+        }
+        if n_rep == 0 { return false; }
+
+        // Rebuild dominator tree and scope, since the loops are removed
+        func.build_dom();
+        func.rebuild_ssa_scope();
+        true
+    }
+}
+
+impl LoopIdiom {
+    pub fn new() -> LoopIdiom {
+        LoopIdiom { gen: SymbolGen::new(Rc::new(Scope::new()), ""), remark: vec![] }
+    }
+
+    /// Replace loop of `node` with a memory operation. Returns whether it is replaced.
+    fn opt_loop(&mut self, func: &FnRef, node: &LoopNodeRef, def_use: &DefUseGraph,
+                info: &EscapeInfo) -> bool
+    {
+        // Match structure of the loop
+        let header = node.borrow().header.clone();
+        let body = match node.borrow().level.as_slice() {
+            [body] => body.clone(),
+            _ => return false
+        };
+        let pre = match header.pred().as_slice() {
+            [a, b] if *b == body => a.clone(),
+            [a, b] if *a == body => b.clone(),
+            _ => return false
+        };
+        if pre.succ.borrow().len() != 1 || body.pred() != vec![header.clone()] {
+            return false;
+        }
+        let loop_inst: HashSet<InstRef> = header.inst.borrow().iter()
+            .chain(body.inst.borrow().iter()).cloned().collect();
+        let header_inst: Vec<_> = header.inst.borrow().iter().cloned().collect();
+        let (iv, init, next, n, exit) = match header_inst.as_slice() {
+            [phi, cmp, br] => match (phi.as_ref(), cmp.as_ref(), br.as_ref()) {
+                (Inst::Phi { src, dst: iv }, Inst::Bin { op: BinOp::Lt, fst, snd, dst: cond },
+                    Inst::Br { cond: br_cond, tr, fls })
+                if Self::is_sym(fst, &iv.borrow()) && Self::is_sym(br_cond, &cond.borrow())
+                    && *tr.borrow() == body && *fls.borrow() != body
+                    && *fls.borrow() != header => {
+                    let opd = |blk: &BlockRef| src.iter().find(|(b, _)| b.borrow().deref() == blk)
+                        .map(|(_, v)| v.borrow().clone());
+                    let next = match opd(&body) {
+                        Some(Value::Var(next)) => next,
+                        _ => return false
+                    };
+                    (iv.borrow().clone(), opd(&pre).unwrap(), next, snd.borrow().clone(),
+                     fls.borrow().clone())
+                }
+                _ => return false
+            }
+            _ => return false
+        };
+        if iv.get_type() != Type::I(64) || !Self::is_invariant(&n, &header, def_use) {
+            return false;
+        }
+
+        // Match instructions in the body
+        let body_inst: Vec<_> = body.inst.borrow().iter().cloned().collect();
+        let (tail, body_inst) = body_inst.split_last().unwrap();
+        if !matches!(tail.as_ref(), Inst::Jmp { tgt } if *tgt.borrow() == header) {
+            return false;
+        }
+        let mut ptr: Vec<(SymbolRef, SymbolRef)> = vec![];
+        let mut ld: Vec<(SymbolRef, SymbolRef)> = vec![];
+        let mut st: Vec<(RefCell<Value>, SymbolRef)> = vec![];
+        let mut incr = false;
+        for instr in body_inst {
+            match instr.as_ref() {
+                Inst::Bin { op: BinOp::Add, fst, snd, dst }
+                if *dst.borrow() == next && Self::is_sym(fst, &iv)
+                    && *snd.borrow() == Value::Const(Const::I64(1)) => incr = true,
+                Inst::Ptr { base, off: Some(off), ind, dst } if ind.is_empty()
+                    && Self::is_sym(off, &iv) => match base.borrow().deref() {
+                    Value::Var(base) if base.get_type() == dst.borrow().get_type()
+                        && Self::is_invariant(&Value::Var(base.clone()), &header, def_use) =>
+                        ptr.push((dst.borrow().clone(), base.clone())),
+                    _ => return false
+                }
+                Inst::Ld { ptr: p, dst } => match p.borrow().deref() {
+                    Value::Var(p) => ld.push((dst.borrow().clone(), p.clone())),
+                    _ => return false
+                }
+                Inst::St { src, ptr: p } => match p.borrow().deref() {
+                    Value::Var(p) => st.push((src.clone(), p.clone())),
+                    _ => return false
+                }
+                _ => return false
+            }
+        }
+        if !incr || st.len() != 1 { return false; }
+        let base_of = |p: &SymbolRef| ptr.iter().find(|(d, _)| d == p).map(|(_, b)| b.clone());
+        let (val, dst_ptr) = st.pop().unwrap();
+        let dst = match base_of(&dst_ptr) {
+            Some(dst) => dst,
+            None => return false
+        };
+        let idiom = match (ptr.as_slice(), ld.as_slice()) {
+            ([_], []) if Self::is_invariant(&val.borrow(), &header, def_use) =>
+                Idiom::Fill(val, dst),
+            ([_, _], [(v, src_ptr)]) if Self::is_sym(&val, v) && *src_ptr != dst_ptr => {
+                let src = match base_of(src_ptr) {
+                    Some(src) => src,
+                    None => return false
+                };
+                if !Self::is_disjoint(&src, &dst, info) { return false; }
+                Idiom::Copy(src, dst)
+            }
+            _ => return false
+        };
+
+        // Values computed in the loop should only be used in it
+        let defined = loop_inst.iter().flat_map(|i| i.dsts().into_iter()
+            .map(|d| d.borrow().clone()).collect::<Vec<_>>());
+        for sym in defined {
+            if def_use.uses(&sym).iter().any(|u| !loop_inst.contains(u)) { return false; }
+        }
+
+        // Compute pointers and length from the initial value of induction variable
+        let zero = init == Value::Const(Const::I64(0));
+        let mut offset = |base: SymbolRef| -> RefCell<Value> {
+            if zero { return RefCell::new(Value::Var(base)); }
+            let dst = self.gen.gen(&base.get_type());
+            pre.insert_before_ctrl(ExtRc::new(Inst::Ptr {
+                base: RefCell::new(Value::Var(base)),
+                off: Some(RefCell::new(init.clone())),
+                ind: vec![],
+                dst: RefCell::new(dst.clone()),
+            }));
+            RefCell::new(Value::Var(dst))
+        };
+        let (name, src, ptr) = match idiom {
+            Idiom::Copy(src, dst) => ("memcpy", offset(src), offset(dst)),
+            Idiom::Fill(val, dst) => ("memset", val, offset(dst)),
+        };
+        let len = RefCell::new(if zero { n } else {
+            let len = self.gen.gen(&Type::I(64));
+            pre.insert_before_ctrl(ExtRc::new(Inst::Bin {
+                op: BinOp::Sub,
+                fst: RefCell::new(n),
+                snd: RefCell::new(init),
+                dst: RefCell::new(len.clone()),
+            }));
+            Value::Var(len)
+        });
+        pre.insert_before_ctrl(ExtRc::new(match name {
+            "memcpy" => Inst::Memcpy { src, ptr, len },
+            _ => Inst::Memset { src, ptr, len }
+        }));
+
+        // Bypass the loop
+        pre.replace_succ(&header, exit);
+        self.remark.push(Remark::applied(&func.name, format!(
+            "replaced loop %{} with {}", header.name, name)));
+        true
+    }
+
+    fn is_sym(val: &RefCell<Value>, sym: &SymbolRef) -> bool {
+        matches!(val.borrow().deref(), Value::Var(v) if v == sym)
+    }
+
+    /// Whether `val` is a constant or a local variable defined outside the loop with `header`.
+    fn is_invariant(val: &Value, header: &BlockRef, def_use: &DefUseGraph) -> bool {
+        match val {
+            Value::Const(_) => true,
+            Value::Var(sym) if sym.is_local_var() => match def_use.def(sym) {
+                DefPos::Param => true,
+                DefPos::Inst(blk, _) => blk.strict_dom(header),
+                DefPos::None => false
+            }
+            Value::Var(_) => false
+        }
+    }
+
+    /// Whether pointers `a` and `b` always point to different allocations.
+    fn is_disjoint(a: &SymbolRef, b: &SymbolRef, info: &EscapeInfo) -> bool {
+        let allocs = |s: &SymbolRef| info.allocs_of(&Value::Var(s.clone()));
+        match (allocs(a), allocs(b)) {
+            (Some(a), Some(b)) => a.iter().all(|x| !b.contains(x)),
+            _ => false
+        }
+    }
+}
+
+/// Expansion of memory operations
+/// `memcpy` and `memset` with constant lengths of at most `max_len` elements are expanded to
+/// loads and stores of each element, which are cheaper than loops for a few elements. Those with
+/// non-positive constant lengths do nothing, and are removed.
+pub struct MemExp {
+    /// Maximal number of elements expanded
+    max_len: i64,
+}
+
+impl MemExp {
+    pub fn new() -> MemExp { MemExp { max_len: 8 } }
+
+    /// Set maximal number of elements expanded.
+    pub fn max_len(mut self, len: i64) -> Self {
+        self.max_len = len;
+        self
+    }
+
+    /// Expand `instr` to loads and stores, or return `None` if its length is not a small
+    /// constant.
+    fn expand(&self, instr: &Inst, gen: &mut SymbolGen) -> Option<Vec<InstRef>> {
+        let (src, ptr, len) = match instr {
+            Inst::Memcpy { src, ptr, len } | Inst::Memset { src, ptr, len } => (src, ptr, len),
+            _ => return None
+        };
+        let len = match len.borrow().deref() {
+            Value::Const(c) if c.as_i64() <= self.max_len => c.as_i64().max(0),
+            _ => return None
+        };
+        let is_copy = matches!(instr, Inst::Memcpy { src: _, ptr: _, len: _ });
+        let ptr_ty = ptr.borrow().get_type();
+        let mut inst = vec![];
+        for i in 0..len {
+            let val = if is_copy {
+                let from = Self::elem(src, i, gen, &mut inst);
+                let val = gen.gen(&ptr_ty.tgt_type());
+                inst.push(ExtRc::new(Inst::Ld { ptr: from, dst: RefCell::new(val.clone()) }));
+                RefCell::new(Value::Var(val))
+            } else { src.clone() };
+            let to = Self::elem(ptr, i, gen, &mut inst);
+            inst.push(ExtRc::new(Inst::St { src: val, ptr: to }));
+        }
+        Some(inst)
+    }
+
+    /// Get pointer to `i`th element from `base`, pushing its computation to `inst`.
+    fn elem(base: &RefCell<Value>, i: i64, gen: &mut SymbolGen, inst: &mut Vec<InstRef>)
+            -> RefCell<Value> {
+        if i == 0 { return base.clone(); }
+        let dst = gen.gen(&base.borrow().get_type());
+        inst.push(ExtRc::new(Inst::Ptr {
+            base: base.clone(),
+            off: Some(RefCell::new(Value::Const(Const::I64(i)))),
+            ind: vec![],
+            dst: RefCell::new(dst.clone()),
+        }));
+        RefCell::new(Value::Var(dst))
+    }
+}
+
+impl Pass for MemExp {
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+}
+
+impl FnPass for MemExp {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        let mut gen = SymbolGen::new(func.scope.clone(), "t");
+        let mut changed = false;
+        for block in func.dfs() {
+            let inst: Vec<InstRef> = block.inst.borrow().iter().cloned().collect();
+            let mut new = Vec::with_capacity(inst.len());
+            for instr in inst {
+                match self.expand(&instr, &mut gen) {
+                    Some(exp) => {
+                        new.extend(exp);
+                        changed = true;
+                    }
+                    None => new.push(instr)
+                }
+            }
+            block.inst.replace(new.into_iter().collect());
+        }
+        changed
+    }
+}
+
+#[test]
+fn test_mem() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+    use std::fs::File;
+    use std::convert::TryFrom;
+
+    let file = File::open("test/mem.ir").unwrap();
+    let mut pro = Builder::new(Parser::new(Lexer::try_from(file).unwrap()).parse().unwrap())
+        .build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    let print = |pro: &Program| {
+        let mut out = vec![];
+        Printer::new(&mut out).print(pro).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    // Fill and copy loops are replaced, while the others are kept
+    let mut idiom = LoopIdiom::new();
+    Pass::run(&mut idiom, &mut pro);
+    let out = print(&pro);
+    assert_eq!(idiom.remarks().len(), 2);
+    assert!(out.contains("memset i64 3 -> $p, 16"));
+    assert!(out.contains("memcpy i64"));
+    assert!(out.contains("%Init:") && out.contains("%Sum:"));
+    assert!(!out.contains("%Fill:") && !out.contains("%Copy:"));
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut pro);
+    assert!(ver.is_ok());
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+
+    // Only memory operations of small constant lengths are expanded
+    Pass::run(&mut MemExp::new().max_len(16), &mut pro);
+    let out = print(&pro);
+    assert!(!out.contains("memset") && out.contains("memcpy"));
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+
+    // Copy between overlapping memory is a runtime error
+    let src = r#"
+fn @main() {
+%Begin:
+    $n <- new [4]i64
+    $a <- ptr *i64 $n [0]
+    $b <- ptr *i64 $a, 1
+    memcpy i64 $a -> $b, 2
+    ret
+}
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    assert!(Machine::new().run(&pro).is_err());
+}
@@ -0,0 +1,364 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, Fn, FnRef};
+use crate::lang::inst::Inst;
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Linkage, Symbol, SymbolRef, Typed, Value};
+use crate::pass::Pass;
+use crate::pass::analysis::Analysis;
+use crate::pass::remark::Remark;
+
+/// Identical Function Merging
+/// Functions are hashed by a canonical form of their bodies, in which blocks and local variables
+/// are numbered in order of appearance, so that functions differing only in names of these are
+/// identical. A function referring to itself is also identical to another one that does the same.
+/// Calls to duplicates are redirected to a canonical copy, and the duplicates are removed.
+/// Merging is repeated until no more functions are identical, because callers of merged
+/// functions may become identical.
+///
+/// A function is only removed if no one can observe it is gone: it must be internal, not an entry
+/// point, and its address must not be taken in the program. Weak functions may be replaced at link
+/// time, so they are neither removed nor chosen as canonical copies.
+pub struct MergeFn {
+    /// Remarks made in the last run
+    remark: Vec<Remark>,
+}
+
+impl MergeFn {
+    pub fn new() -> MergeFn { MergeFn { remark: vec![] } }
+
+    /// Functions whose addresses are taken in the program
+    fn addr_taken(pro: &Program) -> HashSet<FnRef> {
+        let mut taken = HashSet::new();
+        for func in &pro.func {
+            for instr in func.dfs().flat_map(|b| b.inst.borrow().clone()) {
+                instr.src().iter().for_each(|opd| if let Value::Var(sym) = opd.borrow().deref() {
+                    if let Symbol::Func(f) = sym.as_ref() { taken.insert(f.clone()); }
+                });
+            }
+        }
+        taken
+    }
+
+    /// Redirect calls in `func` according to `map`.
+    fn redirect(func: &FnRef, map: &HashMap<FnRef, FnRef>) {
+        for block in func.dfs() {
+            let mut cursor = block.cursor();
+            while let Some(instr) = cursor.next() {
+                let new = match instr.as_ref() {
+                    Inst::Call { func: callee, arg, dst } => match map.get(callee) {
+                        Some(canon) => Inst::Call {
+                            func: canon.clone(),
+                            arg: arg.clone(),
+                            dst: dst.clone(),
+                        },
+                        None => continue
+                    }
+                    _ => continue
+                };
+                let new = ExtRc::new(new);
+                cursor.replace(new.clone());
+                func.move_inst_meta(&instr, &new);
+            }
+        }
+    }
+}
+
+impl Pass for MergeFn {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.remark.clear();
+        let taken = Self::addr_taken(pro);
+        let removable = |f: &FnRef| f.linkage.get() == Linkage::Internal && !f.is_entry()
+            && !taken.contains(f);
+        let mut changed = false;
+        loop {
+            // Group functions by canonical forms, in program order
+            let mut groups: HashMap<String, Vec<FnRef>> = HashMap::new();
+            let mut keys = vec![];
+            for func in pro.func.iter().filter(|f| f.linkage.get() != Linkage::Weak) {
+                let key = match canon_fn(func) {
+                    Some(key) => key,
+                    None => continue
+                };
+                let group = groups.entry(key.clone()).or_default();
+                if group.is_empty() { keys.push(key) }
+                group.push(func.clone());
+            }
+
+            // Map removable duplicates to canonical copies, preferring ones that must stay
+            let mut map = HashMap::new();
+            for key in keys {
+                let group = &groups[&key];
+                if group.len() < 2 { continue; }
+                let canon = group.iter().find(|f| !removable(f)).unwrap_or(&group[0]);
+                for dup in group.iter().filter(|f| *f != canon && removable(f)) {
+                    self.remark.push(Remark::applied(&dup.name,
+                                                     format!("merged into @{}", canon.name)));
+                    map.insert(dup.clone(), canon.clone());
+                }
+            }
+            if map.is_empty() { break; }
+
+            // Redirect calls and remove duplicates
+            pro.func.retain(|f| !map.contains_key(f));
+            map.keys().for_each(|f| pro.global.remove(&f.name));
+            pro.func.iter().for_each(|f| Self::redirect(f, &map));
+            changed = true;
+        }
+        changed
+    }
+
+    fn preserved(&self) -> &'static [Analysis] { Analysis::CFG }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
+}
+
+/// Canonical form of function body, or `None` if it cannot be decided. Names of blocks and
+/// local variables are replaced by their numbers in order of appearance, and names of other
+/// symbols are kept.
+fn canon_fn(func: &FnRef) -> Option<String> {
+    if func.has_block_args() { return None; }
+    let mut canon = Canon { func: Some(func), ..Default::default() };
+    let attrib: Vec<_> = func.attrib.iter().map(|a| a.to_string()).collect();
+    write!(canon.out, "[{}] ", attrib.join(", ")).unwrap();
+    func.param.iter().for_each(|p| canon.sym(&p.borrow()));
+    writeln!(canon.out, "-> {}", func.ret.to_string()).unwrap();
+    let blocks: Vec<BlockRef> = func.rpo().collect();
+    blocks.iter().for_each(|b| { canon.blk(b); });
+    for block in &blocks {
+        let idx = canon.blk(block);
+        writeln!(canon.out, "%{}:", idx).unwrap();
+        block.inst.borrow().iter().for_each(|instr| canon.instr(instr));
+    }
+    Some(canon.out)
+}
+
+/// Builder of canonical form of a sequence of instructions, which is also used to find identical
+/// regions in `outline`. Two sequences are identical if and only if their canonical forms are
+/// equal, so every part of an instruction that affects its semantics must be written.
+#[derive(Default)]
+pub(crate) struct Canon<'a> {
+    /// Function whose references to itself are written as `@self`
+    pub func: Option<&'a Fn>,
+    sym: HashMap<SymbolRef, usize>,
+    blk: HashMap<BlockRef, usize>,
+    pub out: String,
+}
+
+impl Canon<'_> {
+    fn is_self(&self, func: &Fn) -> bool {
+        self.func.is_some_and(|f| std::ptr::eq(func, f))
+    }
+
+    fn sym(&mut self, sym: &SymbolRef) {
+        let len = self.sym.len();
+        let s = match sym.as_ref() {
+            Symbol::Local { name: _, ty } => {
+                let n = *self.sym.entry(sym.clone()).or_insert(len);
+                format!("${}: {}", n, ty.to_string())
+            }
+            Symbol::Func(f) if self.is_self(f) => "@self".to_string(),
+            _ => sym.to_string()
+        };
+        write!(self.out, "{} ", s).unwrap()
+    }
+
+    fn val(&mut self, val: &Value) {
+        match val {
+            Value::Var(sym) => self.sym(sym),
+            Value::Const(c) => write!(self.out, "{}: {} ", c.to_string(),
+                                      c.get_type().to_string()).unwrap()
+        }
+    }
+
+    fn blk(&mut self, block: &BlockRef) -> usize {
+        let len = self.blk.len();
+        *self.blk.entry(block.clone()).or_insert(len)
+    }
+
+    pub fn instr(&mut self, instr: &Inst) {
+        write!(self.out, "    {} ", instr.name()).unwrap();
+        instr.dsts().iter().for_each(|d| self.sym(&d.borrow()));
+        write!(self.out, "<- ").unwrap();
+        match instr {
+            Inst::Call { func, arg: _, dst: _ } => {
+                let name = if self.is_self(func) { "self" } else { &func.name };
+                write!(self.out, "@{} ", name).unwrap()
+            }
+            Inst::Abort { msg } => write!(self.out, "{:?} ", msg).unwrap(),
+            // Offset and indices are both operands
+            Inst::Ptr { base: _, off, ind: _, dst: _ } =>
+                write!(self.out, "{} ", off.is_some()).unwrap(),
+            Inst::AtomicLd { ord, ptr: _, dst: _ } | Inst::AtomicSt { ord, src: _, ptr: _ }
+            | Inst::AtomicRmw { op: _, ord, ptr: _, val: _, dst: _ } =>
+                write!(self.out, "{} ", ord.to_string()).unwrap(),
+            _ => {}
+        }
+        instr.src().iter().for_each(|opd| self.val(&opd.borrow()));
+        let blk: Vec<usize> = instr.blk().iter().map(|b| self.blk(&b.borrow())).collect();
+        blk.iter().for_each(|b| write!(self.out, "%{} ", b).unwrap());
+        writeln!(self.out).unwrap();
+    }
+}
+
+#[test]
+fn test_merge() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let pro = || Builder::new(Parser::new(Lexer::from(r#"
+@r: i64
+@p: *fn(i64) -> i64
+
+fn @add1($x: i64) -> i64 {
+%Begin:
+    $y <- add i64 $x, 1
+    ret $y
+}
+
+fn @inc($a: i64) -> i64 {
+%Entry:
+    $b <- add i64 $a, 1
+    ret $b
+}
+
+fn @inc2($n: i64) -> i64 {
+%B:
+    $m <- add i64 $n, 1
+    ret $m
+}
+
+fn @taken($x: i64) -> i64 {
+%Begin:
+    $y <- add i64 $x, 1
+    ret $y
+}
+
+fn @sub1($x: i64) -> i64 {
+%Begin:
+    $y <- sub i64 $x, 1
+    ret $y
+}
+
+fn @f($x: i64) -> i64 {
+%Begin:
+    $y <- call i64 @add1($x)
+    ret $y
+}
+
+fn @g($x: i64) -> i64 {
+%Begin:
+    $z <- call i64 @inc($x)
+    ret $z
+}
+
+fn @fact($n: i64) -> i64 {
+%Begin:
+    $c <- le i64 $n, 1
+    br $c ? %Ret : %Rec
+%Ret:
+    ret 1
+%Rec:
+    $m <- sub i64 $n, 1
+    $r <- call i64 @fact($m)
+    $s <- mul i64 $n, $r
+    ret $s
+}
+
+fn @fact2($k: i64) -> i64 {
+%Begin:
+    $c <- le i64 $k, 1
+    br $c ? %One : %More
+%One:
+    ret 1
+%More:
+    $m <- sub i64 $k, 1
+    $r <- call i64 @fact2($m)
+    $s <- mul i64 $k, $r
+    ret $s
+}
+
+export fn @api($x: i64) -> i64 {
+%Begin:
+    $y <- add i64 $x, 1
+    ret $y
+}
+
+fn @main() {
+%Begin:
+    @p <- mov *fn(i64) -> i64 @taken
+    $a <- call i64 @f(1)
+    $b <- call i64 @g($a)
+    $c <- call i64 @inc2($b)
+    $d <- call i64 @sub1($c)
+    $e <- call i64 @fact(4)
+    $h <- call i64 @fact2($d)
+    $s <- add i64 $e, $h
+    @r <- mov i64 $s
+    ret
+}
+"#)).parse().unwrap()).build().unwrap();
+    let expect = format!("{:?}", Machine::new().run(&pro()).unwrap().global);
+
+    let mut pro = pro();
+    let mut merge = MergeFn::new();
+    assert!(merge.run(&mut pro));
+    merge.remarks().iter().for_each(|r| println!("{}", r));
+    let mut ver = VerifyPass::new();
+    ver.run(&mut pro);
+    assert!(ver.is_ok());
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+
+    // Duplicates are merged into the first copy that must be kept, and callers of merged
+    // functions are merged in the next round. The exported copy is kept.
+    let names: Vec<_> = pro.func.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, vec!["taken", "sub1", "f", "fact", "api", "main"]);
+    let main = pro.func.iter().find(|f| f.name == "main").unwrap();
+    let callees: Vec<_> = main.ent.borrow().inst.borrow().iter().filter_map(|i| match i.as_ref() {
+        Inst::Call { func, arg: _, dst: _ } => Some(func.name.clone()),
+        _ => None
+    }).collect();
+    assert_eq!(callees, vec!["f", "f", "taken", "sub1", "fact", "fact"]);
+    assert!(pro.global.find("inc").is_none());
+
+    // Merging is stable
+    assert!(!merge.run(&mut pro));
+}
+
+#[test]
+fn test_merge_ord() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+
+    // Functions differing only in memory orderings are not identical
+    let mut pro = Builder::new(Parser::new(Lexer::from(r#"
+fn @seq_cst($p: *i64) -> i64 {
+%Begin:
+    $x <- ld.atomic seq_cst i64 $p
+    ret $x
+}
+
+fn @relaxed($p: *i64) -> i64 {
+%Begin:
+    $x <- ld.atomic relaxed i64 $p
+    ret $x
+}
+
+fn @main() {
+%Begin:
+    $p <- alloc i64
+    $x <- call i64 @seq_cst($p)
+    $y <- call i64 @relaxed($p)
+    ret
+}
+"#)).parse().unwrap()).build().unwrap();
+    assert!(!MergeFn::new().run(&mut pro));
+    assert_eq!(pro.func.len(), 3);
+}
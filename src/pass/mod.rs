@@ -1,27 +1,80 @@
 use crate::lang::func::FnRef;
 use crate::lang::Program;
+use crate::pass::analysis::{Analysis, AnalysisCache};
+use crate::pass::remark::Remark;
 
 pub mod util;
 pub mod graph;
 pub mod gvn;
+pub mod gcm;
 pub mod pre;
+pub mod lcm;
+pub mod sroa;
+pub mod sanitize;
 pub mod sccp;
 pub mod licm;
 pub mod osr;
+pub mod lsr;
 pub mod adce;
 pub mod copy;
 pub mod inl;
+pub mod merge;
+pub mod ret;
+pub mod spec;
+pub mod outline;
+pub mod dse;
+pub mod escape;
+pub mod mem;
+pub mod dce;
+pub mod fold;
+pub mod canon;
+pub mod rewrite;
+pub mod br;
+pub mod verify;
+pub mod manager;
+pub mod par;
+pub mod analysis;
+pub mod remark;
 
 /// Program pass trait
 pub trait Pass {
-    fn run(&mut self, pro: &mut Program);
+    /// Run this pass on `pro`, and return whether the program is modified. A pass may report a
+    /// change even if the program ends up the same, but should never miss one, since pipelines
+    /// rely on this to decide whether to run again.
+    fn run(&mut self, pro: &mut Program) -> bool;
+
+    /// Run this pass with analyses taken from `cache`. Passes which do not use cached analyses
+    /// simply run as `run`. The caller is responsible for invalidating the cache afterwards.
+    fn run_cached(&mut self, pro: &mut Program, _cache: &mut AnalysisCache) -> bool {
+        self.run(pro)
+    }
+
+    /// Analyses which are still valid after this pass changes the program. All analyses are
+    /// valid if it reports no change.
+    fn preserved(&self) -> &'static [Analysis] { &[] }
+
+    /// Remarks about what this pass did, or did not do, in the last run.
+    fn remarks(&self) -> Vec<Remark> { vec![] }
 }
 
 /// Function-level pass trait
+/// Functions are processed one by one. The IR is built on `Rc` and `RefCell`, so functions
+/// cannot be sent across threads as they are. To optimize them in parallel, wrap the pass in
+/// `ParFnPass`, which converts them to `FuncBody` first.
 pub trait FnPass: Pass {
-    fn run(&mut self, pro: &mut Program) {
-        pro.func.iter().for_each(|func| self.run_on_fn(func));
+    fn run(&mut self, pro: &mut Program) -> bool {
+        pro.func.iter().filter(|func| self.run_on_fn(func)).count() > 0
     }
 
-    fn run_on_fn(&mut self, f: &FnRef);
+    fn run_cached(&mut self, pro: &mut Program, cache: &mut AnalysisCache) -> bool {
+        pro.func.iter().filter(|func| self.run_on_fn_cached(func, cache)).count() > 0
+    }
+
+    /// Run this pass on function `f`, and return whether the function is modified.
+    fn run_on_fn(&mut self, f: &FnRef) -> bool;
+
+    /// Run this pass on function `f` with analyses taken from `cache`.
+    fn run_on_fn_cached(&mut self, f: &FnRef, _cache: &mut AnalysisCache) -> bool {
+        self.run_on_fn(f)
+    }
 }
\ No newline at end of file
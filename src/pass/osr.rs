@@ -52,11 +52,11 @@ struct Reduction {
 }
 
 impl Pass for OsrOpt {
-    fn run(&mut self, pro: &mut Program) { FnPass::run(self, pro) }
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
 }
 
 impl FnPass for OsrOpt {
-    fn run_on_fn(&mut self, func: &FnRef) {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
         // Set function-related members
         self.func = Some(func.clone());
         self.gen = SymbolGen::new(func.scope.clone(), "t");
@@ -110,8 +110,9 @@ impl FnPass for OsrOpt {
             }
         });
 
-        // Eliminate dead code
-        func.elim_dead_code();
+        // Eliminate dead code. All insertions and replacements come from reductions, so the
+        // function is changed if any reduction is made.
+        let changed = func.elim_dead_code() || !self.red.is_empty();
 
         // Clear records for this function
         self.low.clear();
@@ -119,6 +120,7 @@ impl FnPass for OsrOpt {
         self.header.clear();
         self.expr.clear();
         self.red.clear();
+        changed
     }
 }
 
@@ -385,8 +387,9 @@ impl OsrOpt {
         match (&fst.tag, &snd.tag) {
             // Do constant folding if possible
             (VertTag::Const(c1), VertTag::Const(c2)) => {
-                // Create constant vertex
-                let c = op.eval(*c1, *c2);
+                // Create constant vertex. Only `add`, `sub` and `mul` are reduced, which never
+                // trap.
+                let c = op.eval(*c1, *c2).unwrap();
                 let vert = ExtRc::new(SsaVert::new(VertTag::Const(c), None));
                 self.graph.add(vert.clone(), None);
                 vert
@@ -0,0 +1,358 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use crate::lang::clone::CloneMap;
+use crate::lang::func::{BasicBlock, BlockRef, Fn, FnRef};
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::Program;
+use crate::lang::ssa::Verifier;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Scope, Symbol, SymbolRef, Type, Typed, Value};
+use crate::pass::Pass;
+use crate::pass::analysis::Analysis;
+use crate::pass::merge::Canon;
+use crate::pass::remark::Remark;
+
+/// Maximal number of instructions in a region
+const MAX_LEN: usize = 32;
+
+/// Function Outlining
+/// Single-entry single-exit regions that appear several times in the program are extracted into
+/// new functions, and each occurrence is replaced by a call to it, which reduces code size.
+/// Regions are straight-line sequences of instructions in blocks of functions in SSA form, and
+/// two regions are identical if they differ only in names of local variables. Local variables
+/// used in a region but defined before it become parameters of the new function, and those
+/// defined in it and used after it in any occurrence are returned.
+///
+/// Regions cannot contain control flow, phi instructions or `alloc`, whose space would be released
+/// once the outlined function returns. The region saving the most instructions is outlined first,
+/// and this is repeated until no region saves any.
+pub struct Outliner {
+    /// Minimal number of instructions in an outlined region
+    min_len: usize,
+    /// Remarks made in the last run
+    remark: Vec<Remark>,
+}
+
+/// Occurrence of a region, which starts at index `start` of `block` in `func`
+#[derive(Clone)]
+struct Occur {
+    func: FnRef,
+    block: BlockRef,
+    start: usize,
+}
+
+impl Outliner {
+    pub fn new() -> Outliner { Outliner { min_len: 2, remark: vec![] } }
+
+    /// Set minimal number of instructions in an outlined region.
+    pub fn min_len(mut self, n: usize) -> Self {
+        self.min_len = n;
+        self
+    }
+
+    /// Whether `instr` can be part of an outlined region
+    fn can_outline(instr: &Inst) -> bool {
+        !instr.is_ctrl() && !matches!(instr, Inst::Phi { src: _, dst: _ } | Inst::Alloc { dst: _ })
+    }
+
+    /// Find the region that saves the most instructions if outlined. Returns its length and
+    /// occurrences, which do not overlap each other.
+    fn best_region(&self, pro: &Program) -> Option<(usize, Vec<Occur>)> {
+        // Group regions by canonical forms, in program order
+        let mut groups: HashMap<String, usize> = HashMap::new();
+        let mut regions: Vec<(usize, Vec<Occur>)> = vec![];
+        for func in pro.func.iter().filter(|f| f.ssa.get() && !f.has_block_args()) {
+            for block in func.dfs() {
+                let inst: Vec<_> = block.inst.borrow().iter().cloned().collect();
+                for start in 0..inst.len() {
+                    let mut canon = Canon::default();
+                    for (i, instr) in inst[start..].iter().take(MAX_LEN).enumerate() {
+                        if !Self::can_outline(instr) { break; }
+                        canon.instr(instr);
+                        let len = i + 1;
+                        if len < self.min_len { continue; }
+                        let idx = *groups.entry(canon.out.clone()).or_insert_with(|| {
+                            regions.push((len, vec![]));
+                            regions.len() - 1
+                        });
+                        let occur = Occur { func: func.clone(), block: block.clone(), start };
+                        let overlap = regions[idx].1.last().is_some_and(|prev| {
+                            prev.block == occur.block && prev.start + len > start
+                        });
+                        if !overlap { regions[idx].1.push(occur) }
+                    }
+                }
+            }
+        }
+
+        // Each occurrence is replaced by a call, and the outlined function has a return
+        let saving = |(len, occur): &(usize, Vec<Occur>)| {
+            (occur.len() * len) as isize - (occur.len() + len + 1) as isize
+        };
+        let mut best: Option<(usize, Vec<Occur>)> = None;
+        for region in regions.into_iter().filter(|r| r.1.len() > 1 && saving(r) > 0) {
+            if best.as_ref().is_none_or(|b| saving(&region) > saving(b)) { best = Some(region) }
+        }
+        best
+    }
+
+    /// Outline `len` instructions at each occurrence in `occur` into a new function.
+    fn outline(&mut self, pro: &mut Program, len: usize, occur: &[Occur]) {
+        // Decide values returned by the outlined function
+        let region = |o: &Occur| -> Vec<InstRef> {
+            o.block.inst.borrow().range(o.start..o.start + len).cloned().collect()
+        };
+        let n_def = interface(&region(&occur[0])).1.len();
+        let mut uses = HashMap::new();
+        let ret: Vec<usize> = (0..n_def).filter(|k| occur.iter().any(|o| {
+            let count = uses.entry(o.func.clone()).or_insert_with(|| use_count(&o.func));
+            let inner = use_count_in(&region(o));
+            let def = &interface(&region(o)).1[*k];
+            count.get(def).copied().unwrap_or(0) > inner.get(def).copied().unwrap_or(0)
+        })).collect();
+
+        // Create the function from the first occurrence
+        let first = region(&occur[0]);
+        let (live_in, def) = interface(&first);
+        let name = (0..).map(|i| format!("outlined.{}", i))
+            .find(|n| pro.global.find(n).is_none()).unwrap();
+        let scope = Scope::new();
+        let mut map = CloneMap::new();
+        for sym in live_in.iter().chain(def.iter()) {
+            let new = ExtRc::new(Symbol::Local {
+                name: sym.name().to_string(),
+                ty: sym.get_type(),
+            });
+            scope.insert(new.clone());
+            map.sym.insert(sym.clone(), new);
+        }
+        let param = live_in.iter().map(|s| RefCell::new(map.sym[s].clone())).collect();
+        let ret_ty = match ret.len() {
+            0 => Type::Void,
+            1 => def[ret[0]].get_type(),
+            _ => Type::Tuple(ret.iter().map(|k| def[*k].get_type()).collect())
+        };
+        let block = BasicBlock::new("Begin".to_string());
+        first.iter().for_each(|instr| block.push_back(map.clone_inst(instr)));
+        block.push_back(ExtRc::new(Inst::Ret {
+            val: ret.iter().map(|k| RefCell::new(Value::Var(map.sym[&def[*k]].clone())))
+                .collect()
+        }));
+        let func = Fn::new(name, scope, vec![], param, ret_ty, block);
+        func.exit.replace(vec![func.ent.borrow().clone()]);
+        func.build_dom();
+        let func = ExtRc::new(func);
+        func.walk_dom(&mut Verifier::new());
+        pro.global.insert(ExtRc::new(Symbol::Func(func.clone())));
+        pro.func.push(func.clone());
+
+        // Replace occurrences with calls, from the last one so that positions of the others in
+        // the same block are not changed
+        for o in occur.iter().rev() {
+            let (live_in, def) = interface(&region(o));
+            let call = ExtRc::new(Inst::Call {
+                func: func.clone(),
+                arg: live_in.into_iter().map(|s| RefCell::new(Value::Var(s))).collect(),
+                dst: ret.iter().map(|k| RefCell::new(def[*k].clone())).collect(),
+            });
+            let old: Vec<_> = o.block.inst.borrow_mut().drain(o.start..o.start + len).collect();
+            o.block.inst.borrow_mut().insert(o.start, call.clone());
+            if let Some(loc) = o.func.inst_loc(&old[0]) {
+                o.func.inst_loc.borrow_mut().insert(call, loc);
+            }
+        }
+        self.remark.push(Remark::applied(&occur[0].func.name, format!(
+            "{} instructions outlined into @{}, {} occurrences", len, func.name, occur.len())));
+    }
+}
+
+impl Pass for Outliner {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.remark.clear();
+        let mut changed = false;
+        while let Some((len, occur)) = self.best_region(pro) {
+            self.outline(pro, len, &occur);
+            changed = true;
+        }
+        changed
+    }
+
+    fn preserved(&self) -> &'static [Analysis] { Analysis::CFG }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
+}
+
+/// Local variables used in `region` before being defined (live-ins), and those defined in it,
+/// both in order of appearance.
+fn interface(region: &[InstRef]) -> (Vec<SymbolRef>, Vec<SymbolRef>) {
+    let mut live_in: Vec<SymbolRef> = vec![];
+    let mut def: Vec<SymbolRef> = vec![];
+    for instr in region {
+        for opd in instr.src() {
+            if let Value::Var(sym) = opd.borrow().deref() {
+                if sym.is_local_var() && !live_in.contains(sym) && !def.contains(sym) {
+                    live_in.push(sym.clone())
+                }
+            }
+        }
+        for dst in instr.dsts() {
+            let sym = dst.borrow().clone();
+            if sym.is_local_var() && !def.contains(&sym) { def.push(sym) }
+        }
+    }
+    (live_in, def)
+}
+
+/// Number of uses of each local variable in `func`
+fn use_count(func: &FnRef) -> HashMap<SymbolRef, usize> {
+    use_count_in(&func.dfs().flat_map(|b| b.inst.borrow().clone()).collect::<Vec<_>>())
+}
+
+fn use_count_in(inst: &[InstRef]) -> HashMap<SymbolRef, usize> {
+    let mut count = HashMap::new();
+    for instr in inst {
+        for opd in instr.src() {
+            if let Value::Var(sym) = opd.borrow().deref() {
+                if sym.is_local_var() { *count.entry(sym.clone()).or_insert(0) += 1 }
+            }
+        }
+    }
+    count
+}
+
+#[test]
+fn test_outline() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::manager::PassManager;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64
+
+[ssa]
+fn @f($x: i64, $y: i64) -> i64 {
+%Begin:
+    $a <- mul i64 $x, 3
+    $b <- add i64 $a, $y
+    $c <- xor i64 $b, 5
+    $d <- mul i64 $c, $c
+    ret $d
+}
+
+[ssa]
+fn @g($p: i64) -> i64 {
+%Begin:
+    $q <- add i64 $p, 1
+    $s <- alloc i64
+    $a <- mul i64 $q, 3
+    $b <- add i64 $a, $p
+    $c <- xor i64 $b, 5
+    $d <- mul i64 $c, $c
+    $e <- sub i64 $d, $b
+    st i64 $e -> $s
+    $t <- ld i64 $s
+    ret $t
+}
+
+[ssa]
+fn @main() {
+%Begin:
+    $u <- call i64 @f(1, 2)
+    $v <- call i64 @g($u)
+    @r <- mov i64 $v
+    ret
+}
+"#;
+    let build = || Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let mut pro = build();
+    let before = Machine::new().run(&pro).unwrap();
+    let mut mgr = PassManager::new().add("outline", Outliner::new());
+    assert!(mgr.run(&mut pro));
+    let report: Vec<_> = mgr.remarks().iter().map(|r| r.to_string()).collect();
+    assert_eq!(report, ["outline: applied in @f: 4 instructions outlined into @outlined.0, 2 \
+        occurrences"]);
+
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut pro);
+    assert!(ver.is_ok());
+
+    // Live-ins are passed as arguments, and live-outs of all occurrences are returned
+    assert!(out.contains("fn @outlined.0($x: i64, $y: i64) -> (i64, i64)"));
+    assert!(out.contains("$b, $d <- call (i64, i64) @outlined.0($x, $y)"));
+    assert!(out.contains("$b, $d <- call (i64, i64) @outlined.0($q, $p)"));
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+
+    // Outlining is stable, and shorter regions do not save any instruction
+    assert!(!mgr.run(&mut pro));
+    let mut pro = build();
+    assert!(!Pass::run(&mut Outliner::new().min_len(5), &mut pro));
+
+    // Different regions are outlined into functions with different names
+    let src = r#"
+@r: i64
+
+[ssa]
+fn @f($x: i64) -> i64 {
+%Begin:
+    $a <- mul i64 $x, 3
+    $b <- add i64 $a, $x
+    $c <- xor i64 $b, 5
+    $d <- mul i64 $c, $c
+    $e <- shl i64 $d, 2
+    $f <- or i64 $e, 7
+    $g <- sub i64 $f, $x
+    $h <- and i64 $g, 255
+    ret $h
+}
+
+[ssa]
+fn @g($y: i64) -> i64 {
+%Begin:
+    $a <- mul i64 $y, 3
+    $b <- add i64 $a, $y
+    $c <- xor i64 $b, 5
+    $d <- mul i64 $c, $c
+    $e <- add i64 $d, 1
+    $f <- shl i64 $e, 2
+    $g <- or i64 $f, 7
+    $h <- sub i64 $g, $y
+    $i <- and i64 $h, 255
+    ret $i
+}
+
+[ssa]
+fn @main() {
+%Begin:
+    $u <- call i64 @f(1)
+    $v <- call i64 @g($u)
+    @r <- mov i64 $v
+    ret
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    let mut outliner = Outliner::new();
+    assert!(Pass::run(&mut outliner, &mut pro));
+    assert_eq!(outliner.remarks().len(), 2);
+    let names: Vec<_> = pro.func.iter().map(|f| f.name.as_str())
+        .filter(|n| n.starts_with("outlined")).collect();
+    assert_eq!(names, ["outlined.0", "outlined.1"]);
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let pro = Builder::new(Parser::new(Lexer::from(out.as_str())).parse().unwrap()).build()
+        .unwrap();
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
@@ -0,0 +1,151 @@
+use crate::lang::arena::{FuncBody, Globals};
+use crate::lang::Program;
+use crate::pass::{FnPass, Pass};
+use crate::pass::remark::Remark;
+
+/// Run a function pass on the functions of a program in parallel.
+/// Functions are converted to `FuncBody`, which could be sent across threads, and split into
+/// chunks. Each thread rebuilds the functions of its chunk in a scope declaring the global
+/// symbols, and runs its own instance of the pass, created by `make`, on each of them alone.
+/// Changed functions are converted back and replace the original ones in order.
+///
+/// Since the other functions are only declared in the scope, the pass should only change the
+/// function it runs on, and should not look into bodies of other functions, which is already
+/// the case for function passes. Analyses that `Pass::run` of the pass does on the whole
+/// program are skipped, so the result is the same as running the pass sequentially only if its
+/// `Pass::run` just runs it on each function. Threads are only spawned if the `parallel`
+/// feature is enabled. Otherwise, the chunks are processed one by one.
+pub struct ParFnPass<F> {
+    make: F,
+    threads: usize,
+    remark: Vec<Remark>,
+}
+
+impl<F, P> ParFnPass<F> where F: Fn() -> P + Sync, P: FnPass {
+    pub fn new(make: F) -> ParFnPass<F> {
+        ParFnPass {
+            make,
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            remark: vec![],
+        }
+    }
+
+    /// Set number of threads, which is the available parallelism by default.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+}
+
+/// Result of running a pass on a function: its new body if it is changed, and the remarks.
+type FnResult = (Option<FuncBody>, Vec<Remark>);
+
+impl<F, P> Pass for ParFnPass<F> where F: Fn() -> P + Sync, P: FnPass {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        let glob = Globals::new(pro);
+        let body: Vec<FuncBody> = pro.func.iter().map(|f| FuncBody::from_fn(f, &glob)).collect();
+        let size = ((body.len() + self.threads - 1) / self.threads).max(1);
+        let chunks: Vec<&[FuncBody]> = body.chunks(size).collect();
+        let make = &self.make;
+        let run = |chunk: &[FuncBody]| run_chunk(make, &glob, chunk);
+
+        #[cfg(feature = "parallel")]
+        let result: Vec<FnResult> = std::thread::scope(|s| {
+            let handles: Vec<_> = chunks.iter().map(|chunk| s.spawn(move || run(chunk)))
+                .collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+        });
+        #[cfg(not(feature = "parallel"))]
+        let result: Vec<FnResult> = chunks.into_iter().flat_map(run).collect();
+
+        // Replace changed functions
+        self.remark.clear();
+        let mut changed = false;
+        for (old, (new, remark)) in pro.func.clone().iter().zip(result) {
+            self.remark.extend(remark);
+            if let Some(new) = new {
+                let new = new.to_fn(&glob, &pro.global);
+                pro.replace_fn(old, new);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
+}
+
+/// Rebuild and optimize each function in `chunk` with a new instance of the pass.
+/// The pass is run by `FnPass::run_on_fn`, so that any analysis its `Pass::run` does on the
+/// whole program is not done on the partial program of a thread. Remarks accumulate across
+/// functions, so those of each function are the ones added since the last function.
+fn run_chunk<F, P>(make: &F, glob: &Globals, chunk: &[FuncBody]) -> Vec<FnResult>
+    where F: Fn() -> P, P: FnPass
+{
+    let scope = glob.declare();
+    let mut pass = make();
+    let mut n_remark = 0;
+    chunk.iter().map(|body| {
+        let func = body.to_fn(glob, &scope);
+        let changed = pass.run_on_fn(&func);
+        let remark = pass.remarks().split_off(n_remark);
+        n_remark += remark.len();
+        (if changed { Some(FuncBody::from_fn(&func, glob)) } else { None }, remark)
+    }).collect()
+}
+
+#[test]
+fn test_par() {
+    use crate::irc::build::Builder;
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::lang::print::Printer;
+    use crate::pass::br::BrFold;
+    use crate::pass::gvn::GvnOpt;
+    use crate::pass::sccp::SccpOpt;
+    use crate::vm::exec::{Machine, VmRcd};
+    use crate::vm::mem::Reg;
+
+    fn build(path: &str) -> Program {
+        let src = std::fs::read_to_string(path).unwrap();
+        let pro = Builder::new(Parser::new(Lexer::from(src.as_str())).parse().unwrap())
+            .build().unwrap();
+        pro.func.iter().for_each(|f| f.to_ssa());
+        pro
+    }
+
+    fn print(pro: &Program) -> String {
+        let mut out = vec![];
+        Printer::new(&mut out).print(pro).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    let mut changed = false;
+    for path in ["test/sum.ir", "test/gdce.ir", "test/lcm.ir", "test/thread.ir"] {
+        let mut seq = build(path);
+        let expected = Machine::new().run(&seq).unwrap();
+        FnPass::run(&mut SccpOpt::new(), &mut seq);
+        FnPass::run(&mut GvnOpt {}, &mut seq);
+        let mut br = BrFold::new();
+        Pass::run(&mut br, &mut seq);
+
+        let mut par = build(path);
+        changed |= ParFnPass::new(SccpOpt::new).threads(2).run(&mut par);
+        changed |= ParFnPass::new(|| GvnOpt {}).threads(3).run(&mut par);
+        let mut par_br = ParFnPass::new(BrFold::new).threads(2);
+        changed |= par_br.run(&mut par);
+
+        assert_eq!(print(&seq), print(&par));
+        let remarks = |r: Vec<Remark>| r.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        assert_eq!(remarks(br.remarks()), remarks(par_br.remarks()));
+        let rcd = Machine::new().run(&par).unwrap();
+        // Pointers to heap are not compared, since they differ across runs.
+        let global = |rcd: &VmRcd| rcd.global.iter().filter_map(|(g, v)| match v {
+            Reg::Val(c) => Some((g.name.clone(), *c)),
+            Reg::Ptr { .. } => None
+        }).collect::<Vec<_>>();
+        assert_eq!(global(&expected), global(&rcd));
+        assert_eq!(expected.exit, rcd.exit);
+    }
+    assert!(changed);
+}
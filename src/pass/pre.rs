@@ -227,7 +227,9 @@ impl ValueTable {
                 let fst_cn = self.find_const(fst);
                 let snd_cn = self.find_const(snd);
                 if let (Some(l), Some(r)) = (fst_cn, snd_cn) {
-                    return Some(self.find_or_add(Expr::Const(op.eval(l, r))));
+                    if let Some(c) = op.eval(l, r) {
+                        return Some(self.find_or_add(Expr::Const(c)));
+                    }
                 }
                 let zero = Const::zero(ty);
                 let one = Const::one(ty);
@@ -305,17 +307,19 @@ impl ValueTable {
 /// See [https://www.cs.purdue.edu/homes/hosking/papers/cc04.pdf].
 pub struct PreOpt {
     table: ValueTable,
+    /// Whether any instruction is inserted in the function being processed
+    inserted: bool,
 }
 
 impl Pass for PreOpt {
-    fn run(&mut self, pro: &mut Program) { FnPass::run(self, pro) }
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
 }
 
 impl FnPass for PreOpt {
     //noinspection RsTypeCheck
-    fn run_on_fn(&mut self, func: &FnRef) {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
         // Make sure the CFG is edge split
-        func.split_edge();
+        self.inserted = func.split_edge();
 
         // Renumber the non-continuous symbols given by GVN
         let mut sym_num = Gvn::new().number(func);
@@ -402,7 +406,7 @@ impl FnPass for PreOpt {
                 });
 
                 // Insert instructions using work list algorithm
-                if block.pred.borrow().len() <= 1 { return; } // only insert at merge point
+                if block.pred().len() <= 1 { return; } // only insert at merge point
                 let mut work: WorkList<(usize, Expr)> = sets[block].antic_in.clone().into_iter()
                     .collect();
                 while !work.is_empty() {
@@ -415,7 +419,7 @@ impl FnPass for PreOpt {
                     let mut all_same = true;
                     let mut first_sym = None;
 
-                    block.pred.borrow().iter().for_each(|pred| {
+                    block.pred().iter().for_each(|pred| {
                         let (trans_num, trans_expr) =
                             self.phi_trans_one(num, Expr::Bin(expr.clone()), pred.clone(),
                                                block.clone());
@@ -441,7 +445,7 @@ impl FnPass for PreOpt {
                     // Operands in inserted expressions may depend on temporaries that has not yet
                     // been created. In this case, we just skip inserting phi instruction and wait
                     // for the next iteration.
-                    let success = block.pred.borrow().iter().all(|pred| {
+                    let success = block.pred().iter().all(|pred| {
                         self.insert_expr(pred, &mut avail, &mut sets, &mut gen)
                     });
                     if !success || sets[block].phi.contains_key(&num) { continue; }
@@ -449,7 +453,7 @@ impl FnPass for PreOpt {
                     self.table.add_num(num, Expr::Temp(dst_sym.clone()));
                     sets.get_mut(block).unwrap().avail_out
                         .insert(num, Expr::Temp(dst_sym.clone()));
-                    let phi_src = block.pred.borrow().clone().into_iter().map(|block| {
+                    let phi_src = block.pred().into_iter().map(|block| {
                         let sym = if let Expr::Temp(sym) = avail[&block].clone() {
                             sym
                         } else { unreachable!() };
@@ -462,6 +466,7 @@ impl FnPass for PreOpt {
                     sets.get_mut(block).unwrap().phi.insert(num, dst_sym.clone());
                     new_tmp.get_mut(block).unwrap().insert(num, dst_sym);
                     inserted = true;
+                    self.inserted = true;
                 }
             });
         }
@@ -486,14 +491,15 @@ impl FnPass for PreOpt {
             })
         });
 
-        // Propagate copy
-        CopyProp::new().run_on_fn(func)
+        // Propagate copy. Redundant computations are replaced by copies, so the function is
+        // changed if any copy is propagated.
+        CopyProp::new().run_on_fn(func) || self.inserted
     }
 }
 
 impl PreOpt {
     pub fn new() -> PreOpt {
-        PreOpt { table: ValueTable::new(0) }
+        PreOpt { table: ValueTable::new(0), inserted: false }
     }
 
     /// Insert corresponding instruction at given predecessor. Return whether this instruction
@@ -518,6 +524,7 @@ impl PreOpt {
 
                 // Insert instruction
                 let dst_sym = gen.gen(&op.res_type(&ty).unwrap());
+                self.inserted = true;
                 pred.insert_before_ctrl(ExtRc::new(Inst::Bin {
                     op,
                     fst: RefCell::new(fst_val),
@@ -561,6 +568,7 @@ impl PreOpt {
 
                 // Insert instruction
                 let dst_sym = gen.gen(&ty);
+                self.inserted = true;
                 pred.insert_before_ctrl(ExtRc::new(Inst::Ptr {
                     base: RefCell::new(base_val),
                     off: off_val,
@@ -0,0 +1,72 @@
+use std::fmt::{Display, Error, Formatter};
+
+/// Kind of remark
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum RemarkKind {
+    /// An optimization is applied
+    Applied,
+    /// An optimization is not applied, and the message tells why
+    Missed,
+    /// Information found by analysis, which does not change the program
+    Analysis,
+}
+
+impl Display for RemarkKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+/// Remark made by a pass about what it did, or did not do, to a function.
+/// Passes provide remarks of their last run through `Pass::remarks`, which are collected by
+/// `PassManager` with names of the passes filled in.
+#[derive(Clone, Debug)]
+pub struct Remark {
+    /// Name of the pass, which is empty until collected by the pass manager
+    pub pass: String,
+    pub kind: RemarkKind,
+    /// Name of the function concerned
+    pub func: String,
+    pub msg: String,
+}
+
+impl Remark {
+    pub fn new(kind: RemarkKind, func: &str, msg: impl Into<String>) -> Remark {
+        Remark { pass: String::new(), kind, func: func.to_string(), msg: msg.into() }
+    }
+
+    pub fn applied(func: &str, msg: impl Into<String>) -> Remark {
+        Self::new(RemarkKind::Applied, func, msg)
+    }
+
+    pub fn missed(func: &str, msg: impl Into<String>) -> Remark {
+        Self::new(RemarkKind::Missed, func, msg)
+    }
+
+    pub fn analysis(func: &str, msg: impl Into<String>) -> Remark {
+        Self::new(RemarkKind::Analysis, func, msg)
+    }
+}
+
+impl Display for Remark {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        if !self.pass.is_empty() { write!(f, "{}: ", self.pass)?; }
+        write!(f, "{} in @{}: {}", self.kind, self.func, self.msg)
+    }
+}
+
+/// Statistics of remarks made by a pass
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct PassStats {
+    pub pass: String,
+    pub applied: usize,
+    pub missed: usize,
+    pub analysis: usize,
+}
+
+impl Display for PassStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}: {} applied, {} missed, {} analysis", self.pass, self.applied, self.missed,
+               self.analysis)
+    }
+}
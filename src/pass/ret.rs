@@ -0,0 +1,210 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use crate::lang::func::FnRef;
+use crate::lang::inst::Inst;
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Const, Linkage, SymbolGen, Type, Typed, Value};
+use crate::pass::Pass;
+use crate::pass::analysis::Analysis;
+use crate::pass::remark::Remark;
+
+/// Return Value Propagation
+/// If every `ret` of a function returns the same constant at some position, the result of calls
+/// to it at this position is known. Each such result is assigned the constant by a `mov` after
+/// the call, and the call writes a new temporary instead. If all results of a call are constant
+/// and the call has side effects, it simply discards the results. Otherwise, the results of the
+/// call are all unused after copy propagation, so a call to a pure function can be removed by
+/// DCE. Weak functions may be replaced at link time, so their bodies are not trusted.
+pub struct RetProp {
+    /// Remarks made in the last run
+    remark: Vec<Remark>,
+}
+
+impl RetProp {
+    pub fn new() -> RetProp { RetProp { remark: vec![] } }
+
+    /// Find constants returned by `func` at each position, or `None` if the value at that
+    /// position is not a constant.
+    fn ret_const(func: &FnRef) -> Option<Vec<Option<Const>>> {
+        if func.linkage.get() == Linkage::Weak { return None; }
+        if let Type::Void = func.ret { return None; }
+        let mut res: Option<Vec<Option<Const>>> = None;
+        for block in func.dfs() {
+            if let Inst::Ret { val } = block.tail().as_ref() {
+                let cur: Vec<Option<Const>> = val.iter().map(|v| match v.borrow().deref() {
+                    Value::Const(c) => Some(*c),
+                    _ => None
+                }).collect();
+                res = Some(match res {
+                    Some(prev) => prev.into_iter().zip(cur)
+                        .map(|(p, c)| if p == c { p } else { None }).collect(),
+                    None => cur
+                });
+            }
+        }
+        res.filter(|r| r.iter().any(|c| c.is_some()))
+    }
+}
+
+impl Pass for RetProp {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.remark.clear();
+        let ret: HashMap<FnRef, Vec<Option<Const>>> = pro.func.iter()
+            .filter_map(|f| Self::ret_const(f).map(|r| (f.clone(), r))).collect();
+        if ret.is_empty() { return false; }
+
+        let mut changed = false;
+        for func in &pro.func {
+            let mut gen = SymbolGen::new(func.scope.clone(), "ret");
+            let mut count = 0;
+            for block in func.dfs() {
+                let mut cursor = block.cursor();
+                while let Some(instr) = cursor.next() {
+                    let (callee, arg, dst) = match instr.as_ref() {
+                        Inst::Call { func, arg, dst } if !dst.is_empty() => (func, arg, dst),
+                        _ => continue
+                    };
+                    let consts = match ret.get(callee) {
+                        Some(consts) => consts,
+                        None => continue
+                    };
+
+                    // Replace call with one writing temporaries or discarding results
+                    let discard = consts.iter().all(|c| c.is_some()) && instr.has_side_effect();
+                    let new_dst = if discard { vec![] } else {
+                        dst.iter().zip(consts).map(|(d, c)| match c {
+                            Some(_) => RefCell::new(gen.gen(&d.borrow().get_type())),
+                            None => d.clone()
+                        }).collect()
+                    };
+                    let new = ExtRc::new(Inst::Call {
+                        func: callee.clone(),
+                        arg: arg.clone(),
+                        dst: new_dst,
+                    });
+                    cursor.replace(new.clone());
+                    func.move_inst_meta(&instr, &new);
+
+                    // Assign constants to original destinations
+                    for (d, c) in dst.iter().zip(consts).rev() {
+                        if let Some(c) = c {
+                            cursor.insert_after(ExtRc::new(Inst::Mov {
+                                src: RefCell::new(Value::Const(*c)),
+                                dst: d.clone(),
+                            }))
+                        }
+                    }
+                    count += 1;
+                }
+            }
+            if count > 0 {
+                self.remark.push(Remark::applied(&func.name, format!(
+                    "propagated constant results of {} calls", count)));
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn preserved(&self) -> &'static [Analysis] { Analysis::CFG }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
+}
+
+#[test]
+fn test_ret() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::copy::CopyProp;
+    use crate::pass::fold::ConstFold;
+    use crate::pass::manager::PassManager;
+    use crate::pass::util::DceOpt;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let pro = || Builder::new(Parser::new(Lexer::from(r#"
+@r: i64
+@n: i64
+
+[pure, ssa]
+fn @four($x: i64) -> i64 {
+%Begin:
+    $c <- gt i64 $x, 0
+    br $c ? %Pos : %Neg
+%Pos:
+    ret 4
+%Neg:
+    ret 4
+}
+
+[ssa]
+fn @count() -> i64 {
+%Begin:
+    $m <- add i64 @n, 1
+    @n <- mov i64 $m
+    ret 1
+}
+
+[ssa]
+fn @pair($x: i64) -> (i64, i64) {
+%Begin:
+    $y <- add i64 $x, 1
+    ret 2, $y
+}
+
+[ssa]
+fn @var($x: i64) -> i64 {
+%Begin:
+    $c <- gt i64 $x, 0
+    br $c ? %Pos : %Neg
+%Pos:
+    ret 1
+%Neg:
+    ret 2
+}
+
+[ssa]
+fn @main() {
+%Begin:
+    $a <- call i64 @four(3)
+    $b <- call i64 @count()
+    $p, $q <- call (i64, i64) @pair($a)
+    $v <- call i64 @var($b)
+    $s <- add i64 $a, $b
+    $t <- add i64 $s, $p
+    $u <- add i64 $t, $q
+    $w <- add i64 $u, $v
+    @r <- mov i64 $w
+    ret
+}
+"#)).parse().unwrap()).build().unwrap();
+    let expect = format!("{:?}", Machine::new().run(&pro()).unwrap().global);
+
+    let mut pro = pro();
+    let mut mgr = PassManager::new().add("ret", RetProp::new()).add("copy", CopyProp::new())
+        .add("fold", ConstFold::new()).add("dce", DceOpt::new());
+    assert!(mgr.run(&mut pro));
+    mgr.remarks().iter().for_each(|r| println!("{}", r));
+    let mut ver = VerifyPass::new();
+    ver.run(&mut pro);
+    assert!(ver.is_ok());
+    assert_eq!(expect, format!("{:?}", Machine::new().run(&pro).unwrap().global));
+
+    // Call to pure function is removed, call with side effects discards its result, and only
+    // the constant element of a tuple is propagated
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    assert!(!out.contains("call i64 @four"));
+    assert!(out.contains("    call i64 @count()\n"));
+    assert!(out.contains("$ret1, $q <- call (i64, i64) @pair(4)"));
+    assert!(out.contains("$u <- add i64 7, $q"));
+    assert!(out.contains("$v <- call i64 @var(1)"));
+    assert!(out.contains("$w <- add i64 $u, $v"));
+}
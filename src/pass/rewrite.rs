@@ -0,0 +1,366 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Display, Error, Formatter};
+use std::ops::Deref;
+use std::str::FromStr;
+
+use crate::lang::func::FnRef;
+use crate::lang::inst::{BinOp, Inst, InstRef, UnOp};
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Const, SymbolRef, Type, Typed, Value};
+use crate::pass::{FnPass, Pass};
+use crate::pass::remark::Remark;
+
+/// Declare a peephole rewrite rule in S-expression form, such as
+/// `rewrite!((add ?x 0) => ?x)`. Patterns are variables `?x`, integer literals, or operations
+/// `(op pattern...)` named as in the textual IR. The left-hand side should be an operation,
+/// and the right-hand side a variable, a literal, or an operation on variables and literals.
+#[macro_export]
+macro_rules! rewrite {
+    (($($lhs:tt)*) => $($rhs:tt)+) => {
+        $crate::pass::rewrite::Rule::new($crate::rewrite_pattern!(($($lhs)*)),
+                                         $crate::rewrite_pattern!($($rhs)+))
+    };
+}
+
+/// Build a `Pattern` in the syntax of `rewrite!`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! rewrite_pattern {
+    (@args [$($acc:expr),*]) => { vec![$($acc),*] };
+    (@args [$($acc:expr),*] ? $v:ident $($rest:tt)*) => {
+        $crate::rewrite_pattern!(@args [$($acc,)* $crate::rewrite_pattern!(? $v)] $($rest)*)
+    };
+    (@args [$($acc:expr),*] $c:literal $($rest:tt)*) => {
+        $crate::rewrite_pattern!(@args [$($acc,)* $crate::rewrite_pattern!($c)] $($rest)*)
+    };
+    (@args [$($acc:expr),*] ($($sub:tt)*) $($rest:tt)*) => {
+        $crate::rewrite_pattern!(@args [$($acc,)* $crate::rewrite_pattern!(($($sub)*))]
+                                 $($rest)*)
+    };
+    (? $v:ident) => { $crate::pass::rewrite::Pattern::Var(stringify!($v).to_string()) };
+    ($c:literal) => { $crate::pass::rewrite::Pattern::Const($c) };
+    (($op:ident $($arg:tt)*)) => {
+        $crate::pass::rewrite::Pattern::op(stringify!($op),
+                                           $crate::rewrite_pattern!(@args [] $($arg)*))
+    };
+}
+
+/// Pattern of values computed by instructions
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Pattern {
+    /// Match any value. All occurrences of the same variable in a rule match the same value.
+    Var(String),
+    /// Match an integer constant of any type with this value
+    Const(i64),
+    Un(UnOp, Box<Pattern>),
+    Bin(BinOp, Box<Pattern>, Box<Pattern>),
+}
+
+impl Pattern {
+    /// Create operation pattern with operator named `name`. Panics if there is no such
+    /// operator, or if the number of arguments does not match.
+    pub fn op(name: &str, mut arg: Vec<Pattern>) -> Pattern {
+        if let Ok(op) = UnOp::from_str(name) {
+            assert_eq!(arg.len(), 1, "{} takes one operand", name);
+            Pattern::Un(op, Box::new(arg.remove(0)))
+        } else if let Ok(op) = BinOp::from_str(name) {
+            assert_eq!(arg.len(), 2, "{} takes two operands", name);
+            let fst = arg.remove(0);
+            Pattern::Bin(op, Box::new(fst), Box::new(arg.remove(0)))
+        } else {
+            panic!("{} is not an operator", name)
+        }
+    }
+
+    fn is_leaf(&self) -> bool { matches!(self, Pattern::Var(_) | Pattern::Const(_)) }
+
+    fn vars(&self) -> Vec<&str> {
+        match self {
+            Pattern::Var(v) => vec![v],
+            Pattern::Const(_) => vec![],
+            Pattern::Un(_, opd) => opd.vars(),
+            Pattern::Bin(_, fst, snd) => fst.vars().into_iter().chain(snd.vars()).collect()
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Pattern::Var(v) => write!(f, "?{}", v),
+            Pattern::Const(c) => write!(f, "{}", c),
+            Pattern::Un(op, opd) => write!(f, "({} {})", op.to_string(), opd),
+            Pattern::Bin(op, fst, snd) => write!(f, "({} {} {})", op.to_string(), fst, snd)
+        }
+    }
+}
+
+/// Rule rewriting instructions matching `lhs` to compute `rhs` instead
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub lhs: Pattern,
+    pub rhs: Pattern,
+}
+
+impl Rule {
+    /// Create a rule. Panics if the rule is not of the form accepted by `rewrite!`, or if
+    /// `rhs` uses variables not bound in `lhs`.
+    pub fn new(lhs: Pattern, rhs: Pattern) -> Rule {
+        assert!(!lhs.is_leaf(), "left-hand side of {} should be an operation", lhs);
+        match &rhs {
+            Pattern::Un(_, opd) => assert!(opd.is_leaf(), "{} is nested", rhs),
+            Pattern::Bin(_, fst, snd) =>
+                assert!(fst.is_leaf() && snd.is_leaf(), "{} is nested", rhs),
+            _ => {}
+        }
+        let bound = lhs.vars();
+        for v in rhs.vars() {
+            assert!(bound.contains(&v), "?{} is not bound in {}", v, lhs);
+        }
+        Rule { lhs, rhs }
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{} => {}", self.lhs, self.rhs)
+    }
+}
+
+/// Matcher of a pattern against instructions
+struct Matcher<'a> {
+    /// Definitions of SSA variables, through which operand patterns are matched
+    def: &'a HashMap<SymbolRef, InstRef>,
+    /// Values bound to pattern variables
+    bind: HashMap<String, Value>,
+}
+
+impl Matcher<'_> {
+    fn match_inst(&mut self, pat: &Pattern, instr: &Inst) -> bool {
+        match (pat, instr) {
+            (Pattern::Un(op, p), Inst::Un { op: o, opd, dst: _ }) if op == o =>
+                self.match_val(p, opd.borrow().deref()),
+            (Pattern::Bin(op, l, r), Inst::Bin { op: o, fst, snd, dst: _ }) if op == o =>
+                self.match_val(l, fst.borrow().deref()) && self.match_val(r, snd.borrow().deref()),
+            _ => false
+        }
+    }
+
+    fn match_val(&mut self, pat: &Pattern, val: &Value) -> bool {
+        match (pat, val) {
+            (Pattern::Var(v), val) => match self.bind.get(v) {
+                Some(b) => b == val,
+                None => {
+                    self.bind.insert(v.clone(), val.clone());
+                    true
+                }
+            }
+            (Pattern::Const(n), Value::Const(c)) => *c == Const::from_i64(*n, &c.get_type()),
+            (Pattern::Const(_), _) => false,
+            (_, Value::Var(sym)) => match self.def.get(sym) {
+                Some(def) => self.match_inst(pat, def),
+                None => false
+            }
+            _ => false
+        }
+    }
+
+    /// Build value of leaf pattern, with constants of type `ty`.
+    fn value(&self, pat: &Pattern, ty: &Type) -> Option<Value> {
+        match pat {
+            Pattern::Var(v) => self.bind.get(v).cloned(),
+            Pattern::Const(n) => match ty.orig() {
+                Type::I(_) => Some(Value::Const(Const::from_i64(*n, &ty.orig()))),
+                _ => None
+            }
+            _ => unreachable!()
+        }
+    }
+
+    /// Build instruction computing `pat` to `dst`. Returns `None` if types do not match.
+    fn build(&self, pat: &Pattern, dst: &RefCell<SymbolRef>) -> Option<Inst> {
+        let ty = dst.borrow().get_type();
+        // Constant operands take the type of variable operands, if there is any
+        let opd_ty = |opd: &[&Pattern]| opd.iter().find_map(|p| match p {
+            Pattern::Var(v) => self.bind.get(v).map(|v| v.get_type()),
+            _ => None
+        }).unwrap_or_else(|| ty.clone());
+        let inst = match pat {
+            Pattern::Un(op, opd) => {
+                let opd_ty = opd_ty(&[opd]);
+                if !op.is_avail_for(&opd_ty) || opd_ty != ty { return None; }
+                Inst::Un { op: *op, opd: RefCell::new(self.value(opd, &opd_ty)?), dst: dst.clone() }
+            }
+            Pattern::Bin(op, fst, snd) => {
+                let opd_ty = opd_ty(&[fst, snd]);
+                if op.res_type(&opd_ty) != Some(ty) { return None; }
+                Inst::Bin {
+                    op: *op,
+                    fst: RefCell::new(self.value(fst, &opd_ty)?),
+                    snd: RefCell::new(self.value(snd, &opd_ty)?),
+                    dst: dst.clone(),
+                }
+            }
+            _ => {
+                let src = self.value(pat, &ty)?;
+                if src.get_type() != ty { return None; }
+                Inst::Mov { src: RefCell::new(src), dst: dst.clone() }
+            }
+        };
+        Some(inst)
+    }
+}
+
+/// Peephole Rewrite
+/// Unary and binary instructions are rewritten by rules declared with `rewrite!`. Rules are
+/// tried in the order they are added, and the first one matching an instruction is applied.
+/// Operands are matched in order, so canonicalizing the function first lets fewer rules cover
+/// commutative operators. In SSA functions, nested patterns are matched against definitions of
+/// the operands, so the variables they bind are available at the rewritten instruction.
+/// Otherwise, only patterns on operands themselves are matched.
+pub struct PatternRewrite {
+    rule: Vec<Rule>,
+    /// Remarks made in the last run
+    remark: Vec<Remark>,
+}
+
+impl Pass for PatternRewrite {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.remark.clear();
+        FnPass::run(self, pro)
+    }
+
+    fn remarks(&self) -> Vec<Remark> { self.remark.clone() }
+}
+
+impl FnPass for PatternRewrite {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        let ssa = func.ssa.get();
+        let mut def: HashMap<SymbolRef, InstRef> = HashMap::new();
+        let mut count = vec![0; self.rule.len()];
+        // Visit in dominator tree order, so that definitions are rewritten before uses
+        func.iter_dom().for_each(|block| {
+            for instr in block.inst.borrow_mut().iter_mut() {
+                let dst = match instr.as_ref() {
+                    Inst::Un { op: _, opd: _, dst } | Inst::Bin { op: _, fst: _, snd: _, dst } =>
+                        dst.clone(),
+                    _ => continue
+                };
+                let new = self.rule.iter().enumerate().find_map(|(i, rule)| {
+                    let mut mat = Matcher { def: &def, bind: HashMap::new() };
+                    if !mat.match_inst(&rule.lhs, instr) { return None; }
+                    mat.build(&rule.rhs, &dst).map(|new| (i, new))
+                });
+                if let Some((i, new)) = new {
+                    count[i] += 1;
+                    let new = ExtRc::new(new);
+                    func.move_inst_meta(instr, &new);
+                    *instr = new;
+                }
+                if ssa && dst.borrow().is_local_var() {
+                    def.insert(dst.borrow().clone(), instr.clone());
+                }
+            }
+        });
+        let changed = count.iter().any(|n| *n > 0);
+        for (rule, n) in self.rule.iter().zip(count).filter(|(_, n)| *n > 0) {
+            self.remark.push(Remark::applied(&func.name, format!("rewrote {} by {}", n, rule)));
+        }
+        changed
+    }
+}
+
+impl PatternRewrite {
+    /// Create pass with no rules.
+    pub fn new() -> PatternRewrite { PatternRewrite { rule: vec![], remark: vec![] } }
+
+    /// Create pass with algebraic identities in `default_rules`.
+    pub fn with_defaults() -> PatternRewrite { Self::new().rules(Self::default_rules()) }
+
+    /// Add a rule after existing ones.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rule.push(rule);
+        self
+    }
+
+    /// Add rules after existing ones.
+    pub fn rules(mut self, rules: impl IntoIterator<Item=Rule>) -> Self {
+        self.rule.extend(rules);
+        self
+    }
+
+    /// Common algebraic identities, assuming constants are on the right of commutative
+    /// operators.
+    pub fn default_rules() -> Vec<Rule> {
+        vec![
+            rewrite!((add ?x 0) => ?x),
+            rewrite!((sub ?x 0) => ?x),
+            rewrite!((sub ?x ?x) => 0),
+            rewrite!((mul ?x 0) => 0),
+            rewrite!((mul ?x 1) => ?x),
+            rewrite!((div ?x 1) => ?x),
+            rewrite!((and ?x ?x) => ?x),
+            rewrite!((or ?x ?x) => ?x),
+            rewrite!((xor ?x ?x) => 0),
+            rewrite!((shl ?x 0) => ?x),
+            rewrite!((shr ?x 0) => ?x),
+            rewrite!((neg (neg ?x)) => ?x),
+            rewrite!((not (not ?x)) => ?x),
+            rewrite!((sub (add ?x ?y) ?y) => ?x),
+        ]
+    }
+}
+
+#[test]
+fn test_rewrite() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::manager::PassManager;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64 <- 5
+
+[ssa]
+fn @main() {
+%Begin:
+    $x <- mov i64 @r
+    $a <- add i64 $x, 0
+    $b <- mul i64 $a, 1
+    $c <- add i64 $b, 3
+    $d <- sub i64 $c, 3
+    $e <- xor i64 $d, $x
+    $f <- mul i64 $d, 2
+    $g <- add i64 $f, $e
+    @r <- mov i64 $g
+    ret
+}
+"#;
+    let rule = rewrite!((mul ?x 2) => (shl ?x 1));
+    assert_eq!(rule.to_string(), "(mul ?x 2) => (shl ?x 1)");
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    let mut mgr = PassManager::new().add("rewrite", PatternRewrite::with_defaults().rule(rule));
+    mgr.run(&mut pro);
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+
+    let report: Vec<_> = mgr.remarks().iter().map(|r| r.to_string()).collect();
+    assert_eq!(report, [
+        "rewrite: applied in @main: rewrote 1 by (add ?x 0) => ?x",
+        "rewrite: applied in @main: rewrote 1 by (mul ?x 1) => ?x",
+        "rewrite: applied in @main: rewrote 1 by (sub (add ?x ?y) ?y) => ?x",
+        "rewrite: applied in @main: rewrote 1 by (mul ?x 2) => (shl ?x 1)",
+    ]);
+    assert!(out.contains("$d <- mov i64 $b"));
+    assert!(out.contains("$e <- xor i64 $d, $x"));
+    assert!(out.contains("$f <- shl i64 $d, 1"));
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
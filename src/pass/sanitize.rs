@@ -0,0 +1,303 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+
+use crate::lang::func::{BlockGen, BlockRef, FnRef};
+use crate::lang::inst::{BinOp, CastOp, Inst, InstRef};
+use crate::lang::meta::MetaVal;
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Const, SymbolGen, SymbolRef, Type, Typed, Value};
+use crate::pass::{FnPass, Pass};
+
+/// Instrumentation of memory accesses for debugging.
+/// Runtime checks are inserted before `ld`, `st`, their atomic variants and `ptr` instructions.
+/// Pointers are checked against null, unless they are produced by `alloc`, `new` or `ptr`.
+/// Variable indices into arrays of known length, and offsets of pointers returned by `new`, are
+/// checked against their bounds. A failed check branches to a trap block of its kind, which
+/// aborts with a message naming the check.
+pub struct SanitizePass {}
+
+/// Kind of a runtime check
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+enum Check {
+    Null,
+    Bound,
+}
+
+impl Check {
+    fn name(&self) -> &'static str {
+        match self {
+            Check::Null => "null",
+            Check::Bound => "bound",
+        }
+    }
+}
+
+/// Per-function instrumentation context
+struct Context {
+    func: FnRef,
+    sym_gen: SymbolGen,
+    blk_gen: BlockGen,
+    /// Trap block of each check kind, created on first use
+    trap: HashMap<Check, BlockRef>,
+    /// Pointers which are never null
+    non_null: HashSet<SymbolRef>,
+    /// Number of elements of pointers returned by `new`
+    len: HashMap<SymbolRef, Value>,
+}
+
+impl SanitizePass {
+    pub fn new() -> SanitizePass { SanitizePass {} }
+}
+
+impl Pass for SanitizePass {
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+}
+
+impl FnPass for SanitizePass {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        // Find pointers which are known to be non-null or have tracked length. Only symbols
+        // with a single definition are considered.
+        let mut def: HashMap<SymbolRef, Vec<InstRef>> = HashMap::new();
+        func.param.iter().for_each(|p| { def.insert(p.borrow().clone(), vec![]); });
+        for block in func.iter_dom() {
+            for instr in block.inst.borrow().iter() {
+                for dst in instr.dsts() {
+                    def.entry(dst.borrow().clone()).or_default().push(instr.clone());
+                }
+            }
+        }
+        let single = |v: &Value| match v {
+            Value::Var(sym) if sym.is_local_var() => def.get(sym).is_some_and(|d| d.len() <= 1),
+            Value::Var(_) => false,
+            Value::Const(_) => true
+        };
+        let mut non_null = HashSet::new();
+        let mut len = HashMap::new();
+        for (sym, instr) in def.iter().filter(|(_, d)| d.len() == 1) {
+            match instr[0].as_ref() {
+                Inst::Alloc { dst: _ } | Inst::Ptr { base: _, off: _, ind: _, dst: _ } => {}
+                Inst::New { dst: _, len: None } => {
+                    len.insert(sym.clone(), Value::Const(Const::I64(1)));
+                }
+                Inst::New { dst: _, len: Some(l) } => {
+                    if single(l.borrow().deref()) { len.insert(sym.clone(), l.borrow().clone()); }
+                }
+                _ => continue
+            }
+            non_null.insert(sym.clone());
+        }
+
+        let mut ctx = Context {
+            func: func.clone(),
+            sym_gen: SymbolGen::new(func.scope.clone(), "chk"),
+            blk_gen: BlockGen::new(func, "Trap"),
+            trap: Default::default(),
+            non_null,
+            len,
+        };
+        let blocks: Vec<BlockRef> = func.iter_dom().collect();
+        blocks.iter().for_each(|block| ctx.instrument(block));
+        if ctx.trap.is_empty() { return false; }
+        func.build_dom();
+        true
+    }
+}
+
+impl Context {
+    /// Insert checks before instructions in `block`, splitting it at each check.
+    fn instrument(&mut self, block: &BlockRef) {
+        let old: Vec<InstRef> = block.inst.borrow_mut().drain(..).collect();
+        let succ: Vec<BlockRef> = block.succ.borrow_mut().drain(..).collect();
+        let mut cur = block.clone();
+        for instr in old {
+            for (kind, mut cond) in self.checks(&instr) {
+                // Branch to trap block if the check fails
+                let trap = self.trap(kind);
+                let cont = self.blk_gen.rename(block);
+                let cmp = cond.last().unwrap().dst().unwrap().borrow().clone();
+                let br = ExtRc::new(Inst::Br {
+                    cond: RefCell::new(Value::Var(cmp)),
+                    tr: RefCell::new(trap.clone()),
+                    fls: RefCell::new(cont.clone()),
+                });
+                self.func.set_inst_meta(&br, "likely", MetaVal::Int(0));
+                cond.push(br);
+                if let Some(loc) = self.func.inst_loc(&instr) {
+                    let mut inst_loc = self.func.inst_loc.borrow_mut();
+                    cond.iter().for_each(|i| { inst_loc.insert(i.clone(), loc.clone()); });
+                }
+                cur.inst.borrow_mut().extend(cond);
+                cur.connect(trap);
+                cur.connect(cont.clone());
+                cur = cont;
+            }
+            cur.push_back(instr);
+        }
+
+        // Move outgoing edges to the last split block
+        for s in succ {
+            if cur == *block {
+                block.connect(s);
+                continue;
+            }
+            block.disconnect(&s);
+            s.remove_pred(block);
+            cur.connect(s.clone());
+            s.inst.borrow_mut().iter_mut().for_each(|instr| {
+                if let Inst::Phi { src, dst } = instr.as_ref().clone() {
+                    let mut src = src.clone();
+                    src.iter_mut().filter(|(pred, _)| pred.borrow().deref() == block)
+                        .for_each(|(pred, _)| *pred = RefCell::new(cur.clone()));
+                    *instr = ExtRc::new(Inst::Phi { src, dst })
+                }
+            })
+        }
+    }
+
+    /// Build checks needed by `instr`. Each check is a list of instructions whose last
+    /// instruction computes the failure condition.
+    fn checks(&mut self, instr: &InstRef) -> Vec<(Check, Vec<InstRef>)> {
+        let mut checks = vec![];
+        match instr.as_ref() {
+            Inst::Ld { ptr, dst: _ } | Inst::St { src: _, ptr }
+            | Inst::AtomicLd { ord: _, ptr, dst: _ } | Inst::AtomicSt { ord: _, src: _, ptr }
+            | Inst::AtomicRmw { op: _, ord: _, ptr, val: _, dst: _ } =>
+                checks.extend(self.null_check(ptr.borrow().deref())),
+            Inst::Ptr { base, off, ind, dst: _ } => {
+                let base = base.borrow().clone();
+                checks.extend(self.null_check(&base));
+                if let (Some(off), Value::Var(sym)) = (off, &base) {
+                    if let Some(len) = self.len.get(sym).cloned() {
+                        checks.extend(self.bound_check(off.borrow().deref(), &len));
+                    }
+                }
+                let mut ty = base.get_type().tgt_type();
+                for idx in ind {
+                    ty = match ty.orig() {
+                        Type::Array { elem, len } => {
+                            let len = Value::Const(Const::I64(len as i64));
+                            checks.extend(self.bound_check(idx.borrow().deref(), &len));
+                            *elem
+                        }
+                        Type::Struct { field } | Type::Union { field } =>
+                            match idx.borrow().deref() {
+                                Value::Const(c) => field[c.as_i64() as usize].clone(),
+                                _ => break
+                            }
+                        _ => break
+                    }
+                }
+            }
+            _ => {}
+        }
+        checks
+    }
+
+    /// Check whether pointer `ptr` is null.
+    fn null_check(&mut self, ptr: &Value) -> Option<(Check, Vec<InstRef>)> {
+        match ptr {
+            Value::Var(sym) if !self.non_null.contains(sym) => {}
+            _ => return None
+        }
+        let null = self.sym_gen.gen(&ptr.get_type());
+        let cmp = self.sym_gen.gen(&Type::I(1));
+        Some((Check::Null, vec![
+            ExtRc::new(Inst::Cast {
+                op: CastOp::IntToPtr,
+                opd: RefCell::new(Value::Const(Const::I64(0))),
+                dst: RefCell::new(null.clone()),
+            }),
+            ExtRc::new(Inst::Bin {
+                op: BinOp::Eq,
+                fst: RefCell::new(ptr.clone()),
+                snd: RefCell::new(Value::Var(null)),
+                dst: RefCell::new(cmp),
+            }),
+        ]))
+    }
+
+    /// Check whether `idx` is out of range `[0, len)`.
+    fn bound_check(&mut self, idx: &Value, len: &Value) -> Option<(Check, Vec<InstRef>)> {
+        if let (Value::Const(i), Value::Const(l)) = (idx, len) {
+            if (0..l.as_i64()).contains(&i.as_i64()) { return None; }
+        }
+        let ty = Type::I(64);
+        let (neg, over, cmp) = (self.sym_gen.gen(&Type::I(1)), self.sym_gen.gen(&Type::I(1)),
+                                self.sym_gen.gen(&Type::I(1)));
+        let bin = |op, fst: Value, snd: Value, dst: &SymbolRef| ExtRc::new(Inst::Bin {
+            op,
+            fst: RefCell::new(fst),
+            snd: RefCell::new(snd),
+            dst: RefCell::new(dst.clone()),
+        });
+        Some((Check::Bound, vec![
+            bin(BinOp::Lt, idx.clone(), Value::Const(Const::zero(&ty)), &neg),
+            bin(BinOp::Ge, idx.clone(), len.clone(), &over),
+            bin(BinOp::Or, Value::Var(neg), Value::Var(over), &cmp),
+        ]))
+    }
+
+    /// Get trap block of check `kind`, or create one if it does not exist.
+    fn trap(&mut self, kind: Check) -> BlockRef {
+        if let Some(trap) = self.trap.get(&kind) { return trap.clone(); }
+        let trap = self.blk_gen.gen();
+        let abort = ExtRc::new(Inst::Abort { msg: format!("{} check failed", kind.name()) });
+        self.func.set_inst_meta(&abort, "sanitize", MetaVal::Str(kind.name().to_string()));
+        trap.push_back(abort);
+        self.trap.insert(kind, trap.clone());
+        trap
+    }
+}
+
+#[test]
+fn test_sanitize() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+    use std::io::stdout;
+    use std::borrow::BorrowMut;
+
+    let run = |arg: i64| {
+        let src = format!(r#"
+@r: i64
+
+fn @get($a: *[4]i64, $i: i64) -> i64 {{
+%Begin:
+    $p <- ptr *i64 $a [$i]
+    $v <- ld i64 $p
+    ret $v
+}}
+
+fn @main() {{
+%Begin:
+    $a <- alloc [4]i64
+    $n <- mov i64 3
+    $h <- new [$n]i64
+    $q <- ptr *i64 $h, 2
+    st i64 5 -> $q
+    $v <- call i64 @get($a, {})
+    @r <- add i64 $v, 1
+    ret
+}}
+"#, arg);
+        let tree = Parser::new(Lexer::from(src.as_str())).parse().unwrap();
+        let mut pro = Builder::new(tree).build().unwrap();
+        let mut opt = SanitizePass::new();
+        Pass::run(&mut opt, &mut pro);
+        let mut out = stdout();
+        Printer::new(out.borrow_mut()).print(&pro).unwrap();
+        let mut mach = Machine::new();
+        mach.run(&pro).map(|_| ()).map_err(|e| format!("{:?}", e))
+    };
+
+    // Valid accesses pass all checks, and invalid ones are caught in trap blocks
+    assert!(run(3).is_ok());
+    let err = run(4).unwrap_err();
+    assert!(err.contains("Trap") && err.contains("abort: bound check failed"));
+    let err = run(-1).unwrap_err();
+    assert!(err.contains("Trap") && err.contains("abort: bound check failed"));
+}
@@ -7,8 +7,9 @@ use crate::lang::inst::{BinOp, PhiSrc, UnOp};
 use crate::lang::inst::Inst;
 use crate::lang::Program;
 use crate::lang::util::{ExtRc, WorkList};
-use crate::lang::value::{Const, Symbol, SymbolRef, Value};
+use crate::lang::value::{Const, Symbol, SymbolRef, Typed, Value};
 use crate::pass::{FnPass, Pass};
+use crate::pass::analysis::Analysis;
 use crate::pass::graph::{GraphBuilder, SsaGraph, VertRef, VertTag};
 
 /// Sparse Conditional Constant Propagation
@@ -77,11 +78,13 @@ impl LatVal {
 }
 
 impl Pass for SccpOpt {
-    fn run(&mut self, pro: &mut Program) { FnPass::run(self, pro) }
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+
+    fn preserved(&self) -> &'static [Analysis] { &[Analysis::DomTree] }
 }
 
 impl FnPass for SccpOpt {
-    fn run_on_fn(&mut self, func: &FnRef) {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
         // Create value graph.
         let mut builder = GraphBuilder::new();
         func.walk_dom(&mut builder);
@@ -115,8 +118,9 @@ impl FnPass for SccpOpt {
         }
 
         // Apply code replacement
+        let mut changed = false;
         func.dfs().for_each(|block| {
-            block.inst.borrow_mut().retain(|instr| {
+            changed |= block.retain(|instr| {
                 // Remove constant definition
                 match instr.dst() {
                     Some(dst) if self.lat_from_sym(dst).is_const() => { return false; }
@@ -124,8 +128,10 @@ impl FnPass for SccpOpt {
                 }
                 // Possibly replace symbols with constants
                 instr.src().iter().for_each(|opd| {
-                    if let LatVal::Const(c) = self.lat_from_val(opd) {
+                    let is_var = opd.borrow().is_var();
+                    if let (LatVal::Const(c), true) = (self.lat_from_val(opd), is_var) {
                         opd.replace(Value::Const(c));
+                        changed = true;
                     }
                 });
                 true
@@ -144,6 +150,7 @@ impl FnPass for SccpOpt {
                             Inst::Jmp { tgt: RefCell::new(tgt) }
                         );
                         block.disconnect(&rm);
+                        changed = true;
                     }
                     _ => {}
                 }
@@ -160,6 +167,7 @@ impl FnPass for SccpOpt {
         self.lat.clear();
         self.edge_vis.clear();
         self.blk_vis.clear();
+        changed
     }
 }
 
@@ -245,6 +253,14 @@ impl SccpOpt {
     }
 
     fn eval_assign(&mut self, instr: &Inst) {
+        // Values returned by calls are unknown.
+        if instr.dst().is_none() {
+            for dst in instr.dsts() {
+                if self.lat_from_sym(dst) != LatVal::Bottom { self.update_sym(dst, LatVal::Bottom) }
+            }
+            return;
+        }
+
         // Decide whether this instruction should be evaluated.
         let dst = instr.dst().unwrap();
         let prev_lat = self.lat_from_sym(dst);
@@ -255,6 +271,11 @@ impl SccpOpt {
             Inst::Un { op, opd, dst: _ } => self.eval_un(*op, self.lat_from_val(opd)),
             Inst::Bin { op, fst, snd, dst: _ } =>
                 self.eval_bin(*op, self.lat_from_val(fst), self.lat_from_val(snd)),
+            Inst::Cast { op, opd, dst } => match self.lat_from_val(opd) {
+                LatVal::Const(c) => op.eval(c, &dst.borrow().get_type())
+                    .map(LatVal::Const).unwrap_or(LatVal::Bottom),
+                lat => lat
+            }
             // Skip move instruction, since their constantness depend on the symbol moved to it.
             Inst::Mov { src: _, dst: _ } => return,
             // Cannot compute lattice values for other instructions.
@@ -335,7 +356,11 @@ impl SccpOpt {
                     _ => LatVal::Bottom
                 }
             }
-            (LatVal::Const(l), LatVal::Const(r)) => LatVal::Const(op.eval(l, r))
+            // Division by zero traps, so its result is not a constant
+            (LatVal::Const(l), LatVal::Const(r)) => match op.eval(l, r) {
+                Some(c) => LatVal::Const(c),
+                None => LatVal::Bottom
+            }
         }
     }
 }
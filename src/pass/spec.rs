@@ -0,0 +1,257 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Display, Error, Formatter};
+use std::ops::Deref;
+
+use crate::lang::clone::CloneMap;
+use crate::lang::func::{BasicBlock, BlockGen, BlockRef, Fn, FnRef};
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::Program;
+use crate::lang::ssa::Verifier;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{Const, Scope, Symbol, Value};
+use crate::pass::{FnPass, Pass};
+use crate::pass::remark::Remark;
+use crate::pass::sccp::SccpOpt;
+
+/// Function specialization on constant arguments (procedure cloning)
+/// Direct calls in the program form the call graph. When a function is called with the same
+/// constants for some parameters at several call sites, a clone of it is created with those
+/// parameters bound to the constants, and the call sites are redirected to the clone, passing
+/// only the remaining arguments. The clone is then simplified by SCCP.
+/// Only functions in SSA form are specialized. The original functions are kept, as they may
+/// still be called elsewhere.
+pub struct FnSpec {
+    /// Minimal number of call sites sharing the constant arguments
+    min_sites: usize,
+    /// Specializations created in the last run
+    pub spec: Vec<Spec>,
+}
+
+/// A specialization of a function
+#[derive(Debug)]
+pub struct Spec {
+    /// Original function
+    pub orig: FnRef,
+    /// Clone of the original function
+    pub clone: FnRef,
+    /// Constant bound to each parameter, or `None` if the parameter is kept
+    pub arg: Vec<Option<Const>>,
+    /// Number of call sites redirected to the clone
+    pub sites: usize,
+}
+
+impl Display for Spec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let arg: Vec<_> = self.arg.iter()
+            .map(|c| c.map_or("_".to_string(), |c| c.to_string())).collect();
+        write!(f, "@{}({}) -> @{}, {} call sites", self.orig.name, arg.join(", "),
+               self.clone.name, self.sites)
+    }
+}
+
+/// A direct call site in the program
+type Site = (FnRef, BlockRef, InstRef);
+
+/// Callee and constant arguments shared by a group of call sites
+type Key = (FnRef, Vec<Option<Const>>);
+
+impl Pass for FnSpec {
+    fn remarks(&self) -> Vec<Remark> {
+        self.spec.iter().map(|s| Remark::applied(&s.orig.name, s.to_string())).collect()
+    }
+
+    fn run(&mut self, pro: &mut Program) -> bool {
+        // Group call sites by callee and constant arguments, in program order
+        self.spec.clear();
+        let mut groups: Vec<(Key, Vec<Site>)> = vec![];
+        let mut index: HashMap<Key, usize> = HashMap::new();
+        for caller in &pro.func {
+            for block in caller.iter_dom() {
+                for instr in block.inst.borrow().iter() {
+                    let (callee, arg) = match instr.as_ref() {
+                        Inst::Call { func, arg, dst: _ } => (func, arg),
+                        _ => continue
+                    };
+                    if !callee.ssa.get() || callee.name == "main" { continue; }
+                    let arg: Vec<_> = arg.iter().map(|a| match a.borrow().deref() {
+                        Value::Const(c) => Some(*c),
+                        _ => None
+                    }).collect();
+                    if arg.iter().all(|c| c.is_none()) { continue; }
+                    let key = (callee.clone(), arg);
+                    let i = *index.entry(key.clone()).or_insert_with(|| {
+                        groups.push((key, vec![]));
+                        groups.len() - 1
+                    });
+                    groups[i].1.push((caller.clone(), block.clone(), instr.clone()));
+                }
+            }
+        }
+
+        // Specialize functions for groups with enough call sites
+        for ((orig, arg), sites) in groups {
+            if sites.len() < self.min_sites { continue; }
+            let clone = Self::specialize(pro, &orig, &arg);
+            for (caller, block, instr) in &sites {
+                Self::redirect(caller, block, instr, &clone, &arg);
+            }
+            self.spec.push(Spec { orig, clone, arg, sites: sites.len() });
+        }
+        !self.spec.is_empty()
+    }
+}
+
+impl FnSpec {
+    pub fn new() -> FnSpec { FnSpec { min_sites: 2, spec: vec![] } }
+
+    /// Set minimal number of call sites sharing the constant arguments.
+    pub fn min_sites(mut self, n: usize) -> Self {
+        self.min_sites = n;
+        self
+    }
+
+    /// Create a clone of `orig` with parameters bound to constants in `arg`, and add it to the
+    /// program.
+    fn specialize(pro: &mut Program, orig: &FnRef, arg: &[Option<Const>]) -> FnRef {
+        let name = (0..).map(|i| format!("{}.{}", orig.name, i))
+            .find(|n| pro.global.find(n).is_none()).unwrap();
+        let mut func = Fn::new(name, Scope::new(), orig.attrib.clone(), vec![],
+                               orig.ret.clone(), BasicBlock::default());
+        let mut map = CloneMap::new();
+        let (ent, exit) = orig.clone_body(&func, &mut BlockGen::new(&func, ""), &mut map);
+
+        // Bound parameters are defined by constants at the beginning of the entrance
+        let mut param = vec![];
+        for (p, c) in orig.param.iter().zip(arg) {
+            let sym = map.sym[p.borrow().deref()].clone();
+            match c {
+                Some(c) => ent.push_front(ExtRc::new(Inst::Mov {
+                    src: RefCell::new(Value::Const(*c)),
+                    dst: RefCell::new(sym),
+                })),
+                None => param.push(RefCell::new(sym))
+            }
+        }
+        func.param = param;
+        func.ent.replace(ent);
+        func.exit.replace(exit);
+        func.meta.replace(orig.meta.borrow().clone());
+        func.build_dom();
+        let func = ExtRc::new(func);
+
+        // The clone is in SSA form as its original, which is confirmed by verification
+        func.walk_dom(&mut Verifier::new());
+        FnPass::run_on_fn(&mut SccpOpt::new(), &func);
+
+        pro.global.insert(ExtRc::new(Symbol::Func(func.clone())));
+        pro.func.push(func.clone());
+        func
+    }
+
+    /// Redirect call `instr` in `block` to `clone`, removing constant arguments.
+    fn redirect(caller: &FnRef, block: &BlockRef, instr: &InstRef, clone: &FnRef,
+                arg: &[Option<Const>]) {
+        let new = match instr.as_ref() {
+            Inst::Call { func: _, arg: args, dst } => ExtRc::new(Inst::Call {
+                func: clone.clone(),
+                arg: args.iter().zip(arg).filter(|(_, c)| c.is_none())
+                    .map(|(a, _)| a.clone()).collect(),
+                dst: dst.clone(),
+            }),
+            _ => unreachable!()
+        };
+        caller.move_inst_meta(instr, &new);
+        let pos = block.inst.borrow().iter().position(|i| i == instr).unwrap();
+        block.inst.borrow_mut()[pos] = new;
+    }
+}
+
+#[test]
+fn test_spec() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::inst::BinOp;
+    use crate::lang::print::Printer;
+    use crate::pass::manager::PassManager;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+
+    let src = r#"
+@r: i64 <- 3
+
+[ssa]
+fn @main() {
+%Begin:
+    $s <- mov i64 @r
+    $a <- call i64 @scale($s, 1)
+    $b <- call i64 @scale($a, 1)
+    $c <- call i64 @scale($b, 1)
+    $d <- call i64 @scale($c, 2)
+    @r <- mov i64 $d
+    ret
+}
+
+[ssa]
+fn @scale($x: i64, $k: i64) -> i64 {
+%Begin:
+    $c <- eq i64 $k, 1
+    br $c ? %One : %Many
+%One:
+    $y <- add i64 $x, 1
+    ret $y
+%Many:
+    $z <- mul i64 $x, $k
+    ret $z
+}
+"#;
+    let mut pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    let mut mgr = PassManager::new().add("spec", FnSpec::new());
+    mgr.run(&mut pro);
+    let report: Vec<_> = mgr.remarks().iter().map(|r| r.to_string()).collect();
+    assert_eq!(report, ["spec: applied in @scale: @scale(_, 1) -> @scale.0, 3 call sites"]);
+
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut pro);
+    assert!(ver.is_ok());
+
+    // Calls with the constant are redirected, and the branch is folded in the clone
+    assert_eq!(out.matches("call i64 @scale.0(").count(), 3);
+    assert!(out.contains("call i64 @scale($c, 2)"));
+    let clone = pro.func.iter().find(|f| f.name == "scale.0").unwrap();
+    assert_eq!(clone.param.len(), 1);
+    assert!(clone.iter_dom().all(|b| b.inst.borrow().iter().all(|i| {
+        !matches!(i.as_ref(), Inst::Br { .. } | Inst::Bin { op: BinOp::Mul, .. })
+    })));
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+
+    // Specializations of one function for different constants have different names
+    let src = src.replace("@scale($c, 2)", "@scale($c, 2)\n    $e <- call i64 @scale($d, 2)")
+        .replace("@r <- mov i64 $d", "@r <- mov i64 $e");
+    let mut pro = Builder::new(Parser::new(Lexer::from(src.as_str())).parse().unwrap()).build()
+        .unwrap();
+    let before = Machine::new().run(&pro).unwrap();
+    let mut spec = FnSpec::new();
+    Pass::run(&mut spec, &mut pro);
+    let report: Vec<_> = spec.remarks().iter().map(|r| r.to_string()).collect();
+    assert_eq!(report.len(), 2);
+    assert!(report.iter().any(|r| r.contains("@scale(_, 1) -> @scale.")));
+    assert!(report.iter().any(|r| r.contains("@scale(_, 2) -> @scale.")));
+    let mut names: Vec<_> = pro.func.iter().map(|f| f.name.as_str()).collect();
+    names.sort();
+    assert_eq!(names, ["main", "scale", "scale.0", "scale.1"]);
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let pro = Builder::new(Parser::new(Lexer::from(out.as_str())).parse().unwrap()).build()
+        .unwrap();
+    let after = Machine::new().run(&pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
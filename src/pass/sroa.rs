@@ -0,0 +1,259 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Deref;
+
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::Program;
+use crate::lang::util::ExtRc;
+use crate::lang::value::{SymbolGen, SymbolRef, Type, Typed, Value};
+use crate::pass::{FnPass, Pass};
+
+/// Scalar Replacement of Aggregates
+/// An `alloc` of structure or array type is split into separate `alloc`s of its scalar elements,
+/// if the aggregate never escapes and all of its elements are accessed through `ptr` with
+/// constant indices. The resulting pointers must only be used as address of `ld` and `st`.
+pub struct SroaOpt {}
+
+/// Aggregates with more scalar elements than this are not split.
+const MAX_ELEM: usize = 16;
+
+/// Information of a candidate aggregate allocation
+struct Aggr {
+    /// The `alloc` instruction and the block where it is defined
+    def: Option<(BlockRef, InstRef)>,
+    /// Number of definitions of this symbol
+    n_def: usize,
+    /// Whether this aggregate can be split
+    split: bool,
+    /// Accessed element paths, and their types
+    elem: BTreeMap<Vec<usize>, Type>,
+}
+
+impl SroaOpt {
+    pub fn new() -> SroaOpt { SroaOpt {} }
+
+    /// Count scalar elements in aggregate type `ty`
+    fn count_elem(ty: &Type) -> usize {
+        match ty.orig() {
+            Type::Array { elem, len } => Self::count_elem(&elem) * len,
+            Type::Struct { field } => field.iter().map(Self::count_elem).sum(),
+            _ => 1
+        }
+    }
+
+    /// Resolve constant indices `ind` into aggregate type `ty`. Return the index path and the
+    /// type of the scalar element, if all indices are in bound.
+    fn resolve(ty: &Type, off: &Option<RefCell<Value>>, ind: &Vec<RefCell<Value>>)
+               -> Option<(Vec<usize>, Type)>
+    {
+        match off.as_ref().map(|off| off.borrow().clone()) {
+            Some(Value::Const(c)) if c.as_u64() == 0 => {}
+            None => {}
+            _ => return None
+        }
+        if ind.is_empty() { return None; }
+        let mut ty = ty.clone();
+        let mut path = vec![];
+        for idx in ind {
+            let idx = match idx.borrow().deref() {
+                Value::Const(c) => c.as_u64() as usize,
+                _ => return None
+            };
+            ty = match ty.orig() {
+                Type::Array { elem, len } if idx < len => *elem,
+                Type::Struct { field } if idx < field.len() => field[idx].clone(),
+                _ => return None
+            };
+            path.push(idx);
+        }
+        if ty.is_reg() { Some((path, ty)) } else { None }
+    }
+}
+
+impl Pass for SroaOpt {
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+}
+
+impl FnPass for SroaOpt {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        // Find all aggregate allocations
+        let mut aggr: HashMap<SymbolRef, Aggr> = HashMap::new();
+        for block in func.iter_dom() {
+            for instr in block.inst.borrow().iter() {
+                let dst = match instr.dst() {
+                    Some(dst) => dst.borrow().clone(),
+                    None => continue
+                };
+                if let Some(a) = aggr.get_mut(&dst) {
+                    a.n_def += 1;
+                    continue;
+                }
+                let split = match instr.as_ref() {
+                    Inst::Alloc { dst: _ } => {
+                        let ty = dst.get_type().tgt_type();
+                        match ty.orig() {
+                            Type::Array { elem: _, len: _ } | Type::Struct { field: _ } =>
+                                Self::count_elem(&ty) <= MAX_ELEM,
+                            _ => false
+                        }
+                    }
+                    _ => false
+                };
+                aggr.insert(dst, Aggr {
+                    def: Some((block.clone(), instr.clone())),
+                    n_def: 1,
+                    split,
+                    elem: Default::default(),
+                });
+            }
+        }
+        aggr.retain(|_, a| a.split && a.n_def == 1);
+        if aggr.is_empty() { return false; }
+
+        // Check uses of aggregate pointers and element pointers
+        let mut elem_ptr: HashMap<SymbolRef, SymbolRef> = HashMap::new();
+        let mut ptr_inst: Vec<(BlockRef, InstRef)> = vec![];
+        for block in func.iter_dom() {
+            for instr in block.inst.borrow().iter() {
+                if let Inst::Ptr { base, off, ind, dst } = instr.as_ref() {
+                    if let Value::Var(sym) = base.borrow().deref() {
+                        if let Some(a) = aggr.get_mut(sym) {
+                            let ty = sym.get_type().tgt_type();
+                            match Self::resolve(&ty, off, ind) {
+                                Some((path, ty)) => {
+                                    a.elem.insert(path, ty);
+                                    elem_ptr.insert(dst.borrow().clone(), sym.clone());
+                                    ptr_inst.push((block.clone(), instr.clone()));
+                                }
+                                None => a.split = false
+                            }
+                            continue;
+                        }
+                    }
+                }
+                for opd in instr.src() {
+                    if let Value::Var(sym) = opd.borrow().deref() {
+                        if let Some(a) = aggr.get_mut(sym) { a.split = false }
+                    }
+                }
+            }
+        }
+
+        // Element pointers can only be used as address of load and store
+        for block in func.iter_dom() {
+            for instr in block.inst.borrow().iter() {
+                let addr = match instr.as_ref() {
+                    Inst::Ld { ptr, dst: _ } | Inst::St { src: _, ptr } => Some(ptr),
+                    _ => None
+                };
+                for opd in instr.src() {
+                    if let Some(ptr) = addr {
+                        if std::ptr::eq(ptr, opd) { continue; }
+                    }
+                    if let Value::Var(sym) = opd.borrow().deref() {
+                        if let Some(a) = elem_ptr.get(sym) {
+                            aggr.get_mut(a).unwrap().split = false
+                        }
+                    }
+                }
+            }
+        }
+        aggr.retain(|_, a| a.split);
+        if aggr.is_empty() { return false; }
+
+        // Replace each aggregate allocation with allocations of its accessed elements
+        let mut scalar: HashMap<(SymbolRef, Vec<usize>), SymbolRef> = HashMap::new();
+        let mut replaced: HashSet<InstRef> = HashSet::new();
+        for (sym, a) in aggr.iter_mut() {
+            let (block, alloc) = a.def.take().unwrap();
+            let pre = format!("{}.", sym.name().split('.').next().unwrap());
+            let mut gen = SymbolGen::new(func.scope.clone(), pre.as_str());
+            let mut new_list = vec![];
+            for (path, ty) in a.elem.iter() {
+                let new_sym = gen.gen(&Type::Ptr(Box::new(ty.clone())));
+                new_list.push(ExtRc::new(Inst::Alloc { dst: RefCell::new(new_sym.clone()) }));
+                scalar.insert((sym.clone(), path.clone()), new_sym);
+            }
+            if let Some(first) = new_list.first() { func.move_inst_meta(&alloc, first) }
+            let mut inst = block.inst.borrow_mut();
+            let pos = inst.iter().position(|i| i == &alloc).unwrap();
+            let mut rest = inst.split_off(pos);
+            rest.pop_front();
+            inst.extend(new_list);
+            inst.append(&mut rest);
+            replaced.insert(alloc);
+        }
+
+        // Replace element pointers with pointers to scalar allocations
+        for (block, instr) in ptr_inst {
+            let (base, path, dst) = match instr.as_ref() {
+                Inst::Ptr { base, off, ind, dst } => {
+                    let sym = match base.borrow().deref() {
+                        Value::Var(sym) if aggr.contains_key(sym) => sym.clone(),
+                        _ => continue
+                    };
+                    let ty = sym.get_type().tgt_type();
+                    let (path, _) = Self::resolve(&ty, off, ind).unwrap();
+                    (sym, path, dst.clone())
+                }
+                _ => unreachable!()
+            };
+            let mov = ExtRc::new(Inst::Mov {
+                src: RefCell::new(Value::Var(scalar[&(base, path)].clone())),
+                dst,
+            });
+            func.move_inst_meta(&instr, &mov);
+            let mut inst = block.inst.borrow_mut();
+            let pos = inst.iter().position(|i| i == &instr).unwrap();
+            *inst.iter_mut().nth(pos).unwrap() = mov;
+        }
+        for alloc in replaced.iter() {
+            if let Some(dst) = alloc.dst() { func.scope.remove(dst.borrow().name()) }
+        }
+        true
+    }
+}
+
+#[test]
+fn test_sroa() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+    use std::io::stdout;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+    use std::borrow::BorrowMut;
+
+    let mut file = File::open("test/sroa.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let mut pro = builder.build().unwrap();
+    let mut mach = Machine::new();
+    let before = mach.run(&mut pro).unwrap();
+
+    let mut opt = SroaOpt::new();
+    Pass::run(&mut opt, &mut pro);
+    let mut out = stdout();
+    let mut printer = Printer::new(out.borrow_mut());
+    printer.print(&pro).unwrap();
+
+    // Only the aggregate indexed by a variable is kept
+    let n_aggr = |func: &FnRef| func.iter_dom().map(|b| {
+        b.inst.borrow().iter().filter(|i| match i.as_ref() {
+            Inst::Alloc { dst } => !dst.borrow().get_type().tgt_type().is_reg(),
+            _ => false
+        }).count()
+    }).sum::<usize>();
+    assert_eq!(n_aggr(&pro.func[0]), 0);
+    assert_eq!(n_aggr(&pro.func[1]), 1);
+
+    let mut mach = Machine::new();
+    let after = mach.run(&mut pro).unwrap();
+    assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global));
+}
@@ -9,6 +9,7 @@ use crate::lang::Program;
 use crate::lang::util::{ExtRc, MutRc};
 use crate::lang::value::{Const, SymbolGen, Type, Typed, Value};
 use crate::pass::{FnPass, Pass};
+use crate::pass::analysis::Analysis;
 
 /// Wrapper for Dead Code Elimination as a separate pass
 pub struct DceOpt {}
@@ -18,11 +19,13 @@ impl DceOpt {
 }
 
 impl Pass for DceOpt {
-    fn run(&mut self, pro: &mut Program) { FnPass::run(self, pro) }
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
+
+    fn preserved(&self) -> &'static [Analysis] { Analysis::CFG }
 }
 
 impl FnPass for DceOpt {
-    fn run_on_fn(&mut self, f: &FnRef) { f.elim_dead_code() }
+    fn run_on_fn(&mut self, f: &FnRef) -> bool { f.elim_dead_code() }
 }
 
 #[derive(Clone, Debug)]
@@ -115,13 +118,14 @@ impl Fn {
     fn create_natural(blk: &BlockRef, header: &BlockRef) -> LoopNodeRef {
         // Perform DFS on reversed CFG with header block as boundary
         let mut visited = HashSet::new();
-        let mut stack = vec![blk.clone()];
+        // A self loop only contains its header
+        let mut stack = if blk == header { vec![] } else { vec![blk.clone()] };
         visited.insert(header.clone());
         loop {
             match stack.pop() {
                 Some(v) => {
                     visited.insert(v.clone());
-                    stack.append(&mut v.pred.borrow().iter()
+                    stack.append(&mut v.pred().iter()
                         .filter(|blk| !visited.contains(blk)).cloned().collect()
                     )
                 }
@@ -147,11 +151,12 @@ impl PtrExp {
 }
 
 impl Pass for PtrExp {
-    fn run(&mut self, pro: &mut Program) { FnPass::run(self, pro) }
+    fn run(&mut self, pro: &mut Program) -> bool { FnPass::run(self, pro) }
 }
 
 impl FnPass for PtrExp {
-    fn run_on_fn(&mut self, func: &FnRef) {
+    fn run_on_fn(&mut self, func: &FnRef) -> bool {
+        let mut changed = false;
         func.iter_dom().for_each(|block| {
             // Find pointer instruction with indices
             let ptr_list: Vec<InstRef> = block.inst.borrow().iter().filter(|instr| {
@@ -159,6 +164,7 @@ impl FnPass for PtrExp {
                     !ind.is_empty()
                 } else { false }
             }).cloned().collect();
+            changed |= !ptr_list.is_empty();
 
             // Expand pointer operation
             let mut gen = SymbolGen::new(func.scope.clone(), "t");
@@ -205,20 +211,21 @@ impl FnPass for PtrExp {
                                 expand.push(elem_instr);
                                 cur_ptr = elem_ptr;
                             }
-                            // For structure type, the only way to access its member is through
-                            // constant index. Therefore, it cannot be converted to offset.
-                            Type::Struct { field } => {
+                            // For structure and union types, the only way to access its member
+                            // is through constant index. Therefore, it cannot be converted to
+                            // offset.
+                            Type::Struct { field } | Type::Union { field } => {
                                 let idx =
                                     if let Value::Const(Const::I64(i)) = idx_val.borrow().deref() {
                                         *i
                                     } else { unreachable!() };
-                                let cur_ty = field[idx as usize].clone();
+                                cur_ty = field[idx as usize].clone();
                                 let ref elem_ptr_ty = Type::Ptr(Box::new(cur_ty.clone()));
                                 let elem_ptr = if i == ind.len() - 1 {
                                     dst.borrow().clone()
                                 } else { gen.gen(elem_ptr_ty) };
                                 let elem_instr = ExtRc::new(Inst::Ptr {
-                                    base: RefCell::new(Value::Var(elem_ptr.clone())),
+                                    base: RefCell::new(Value::Var(cur_ptr.clone())),
                                     off: None,
                                     ind: vec![idx_val.clone()],
                                     dst: RefCell::new(elem_ptr.clone()),
@@ -238,6 +245,7 @@ impl FnPass for PtrExp {
                     })
                 } else { unreachable!() }
             })
-        })
+        });
+        changed
     }
 }
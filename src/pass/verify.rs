@@ -0,0 +1,123 @@
+use crate::lang::Program;
+use crate::lang::ssa::Verifier;
+use crate::lang::verify::{TypeChecker, VerifyErr};
+use crate::pass::Pass;
+
+/// Verification Pass
+/// Check block terminators, types and references of all instructions, conformance of function
+/// bodies to their attributes, and SSA properties of functions in SSA form.
+/// This pass does not modify the program, so it can be inserted anywhere in a pipeline to check
+/// results of other passes.
+pub struct VerifyPass {
+    /// Errors found in the last run
+    pub err: Vec<VerifyErr>,
+}
+
+impl VerifyPass {
+    pub fn new() -> VerifyPass { VerifyPass { err: vec![] } }
+
+    /// Whether no error is found in the last run.
+    pub fn is_ok(&self) -> bool { self.err.is_empty() }
+}
+
+impl Pass for VerifyPass {
+    fn run(&mut self, pro: &mut Program) -> bool {
+        self.err.clear();
+        let mut ty = TypeChecker::new();
+        for func in &pro.func {
+            self.err.append(&mut func.verify_cfg());
+            ty.check_fn(pro, func);
+            self.err.append(&mut ty.err);
+            self.err.append(&mut func.verify_attrib());
+            if func.ssa.get() {
+                let mut ver = Verifier::new();
+                func.walk_dom(&mut ver);
+                self.err.append(&mut ver.err);
+            }
+        }
+        false
+    }
+}
+
+#[test]
+fn test_verify() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::inst::Inst;
+    use crate::lang::value::{Const, Value};
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut file = File::open("test/sum.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let mut pro = builder.build().unwrap();
+    let mut pass = VerifyPass::new();
+    pass.run(&mut pro);
+    assert!(pass.is_ok());
+
+    // Replace an operand with constant of wrong type
+    let func = pro.func[0].clone();
+    let block = func.ent.borrow().clone();
+    let instr = block.inst.borrow().iter()
+        .find(|i| matches!(i.as_ref(), Inst::Mov { src: _, dst: _ })).cloned().unwrap();
+    instr.src()[0].replace(Value::Const(Const::I64(0)));
+    pass.run(&mut pro);
+    for e in &pass.err { println!("{}", e) }
+    assert_eq!(pass.err.len(), 1);
+    assert_eq!(pass.err[0].block, block.name);
+    assert_eq!(pass.err[0].loc.as_ref().map(|loc| loc.line()), Some(5));
+}
+
+#[test]
+fn test_verify_ptr() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::inst::Inst;
+    use crate::lang::util::ExtRc;
+    use crate::lang::value::{Symbol, Type, Value};
+
+    let mut pro = Builder::new(Parser::new(Lexer::from(r#"
+type @S = { i32, [4]i64 }
+
+fn @f($s: *@S, $i: i64) -> i64 {
+%Begin:
+    $p <- ptr *i64 $s, $i [1, 2]
+    $x <- ld i64 $p
+    ret $x
+}
+"#)).parse().unwrap()).build().unwrap();
+    let mut pass = VerifyPass::new();
+    pass.run(&mut pro);
+    assert!(pass.is_ok());
+
+    // Destination type does not match the element type along the index chain
+    let func = pro.func[0].clone();
+    let instr = func.ent.borrow().inst.borrow()[0].clone();
+    let (ind, dst) = match instr.as_ref() {
+        Inst::Ptr { base: _, off: _, ind, dst } => (ind, dst),
+        _ => unreachable!()
+    };
+    let orig = dst.replace(ExtRc::new(Symbol::Local {
+        name: "q".to_string(),
+        ty: Type::Ptr(Box::new(Type::I(32))),
+    }));
+    pass.run(&mut pro);
+    for e in &pass.err { println!("{}", e) }
+    assert_eq!(pass.err.len(), 1);
+    assert_eq!(pass.err[0].msg, "expect type *i64, found *i32");
+
+    // Structure indices must be constant and in range
+    dst.replace(orig);
+    ind[0].replace(Value::Var(func.param[1].borrow().clone()));
+    pass.run(&mut pro);
+    assert_eq!(pass.err[0].msg, "expect constant index for structure or union");
+    ind[0].replace(ind[1].borrow().clone());
+    pass.run(&mut pro);
+    assert_eq!(pass.err[0].msg, "index 2 out of range 2");
+}
@@ -0,0 +1,35 @@
+//! Commonly used types of the crate, which can be imported at once with
+//! `use irl::prelude::*`. Items here are kept stable across versions, while their defining
+//! modules may be reorganized.
+
+pub use crate::driver::{run_source, RunErr};
+pub use crate::irc::{CompileErr, ErrKind, Loc};
+pub use crate::irc::build::Builder;
+pub use crate::irc::lex::Lexer;
+pub use crate::irc::parse::Parser;
+pub use crate::lang::Program;
+pub use crate::lang::func::{BasicBlock, BlockRef, Fn, FnAttrib, FnRef};
+pub use crate::lang::inst::{BinOp, CastOp, Inst, InstRef, UnOp};
+pub use crate::lang::print::Printer;
+pub use crate::lang::util::{ExtRc, PriorityWorkList, WorkList};
+pub use crate::lang::value::{Const, Scope, Symbol, SymbolRef, Type, Typed, Value};
+pub use crate::pass::{FnPass, Pass};
+pub use crate::pass::manager::PassManager;
+pub use crate::vm::exec::{Machine, RuntimeErr, VmRcd};
+
+#[test]
+fn test_prelude() {
+    let src = r#"
+@r: i64
+
+fn @main() {
+%Begin:
+    @r <- add i64 1, 2
+    ret
+}
+"#;
+    let tree = Parser::new(Lexer::from(src)).parse().unwrap();
+    let pro: Program = Builder::new(tree).build().unwrap();
+    let rcd: VmRcd = Machine::new().run(&pro).unwrap();
+    assert!(format!("{:?}", rcd).contains("@r = 3"));
+}
@@ -0,0 +1,223 @@
+use std::fmt::{Display, Error, Formatter};
+
+use crate::lang::Program;
+use crate::lang::value::{Const, Type, Typed};
+use crate::test_util::gen::Rng;
+use crate::vm::exec::Machine;
+use crate::vm::mem::Reg;
+
+/// Input of a program run: the function to call and its arguments
+#[derive(Clone, Debug)]
+pub struct Input {
+    pub func: String,
+    pub arg: Vec<Const>,
+}
+
+impl Display for Input {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let arg: Vec<_> = self.arg.iter().map(|a| a.to_string()).collect();
+        write!(f, "@{}({})", self.func, arg.join(", "))
+    }
+}
+
+/// Observable behavior of a program run
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Outcome {
+    /// The function returns normally, with its returned values and final values of global
+    /// variables. Pointers are only observed by their offsets, as memory spaces of different
+    /// runs cannot be compared.
+    Return { ret: Vec<String>, global: Vec<(String, String)> },
+    /// The program traps with an error message
+    Trap(String),
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Outcome::Return { ret, global } => {
+                write!(f, "return {}", if ret.is_empty() { "void".to_string() }
+                    else { ret.join(", ") })?;
+                global.iter().try_for_each(|(name, val)| write!(f, ", @{} = {}", name, val))
+            }
+            Outcome::Trap(msg) => write!(f, "trap: {}", msg)
+        }
+    }
+}
+
+/// Different behavior of two programs on an input
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    pub input: Input,
+    pub before: Outcome,
+    pub after: Outcome,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}: {} before, but {} after", self.input, self.before, self.after)
+    }
+}
+
+/// Check whether two programs, usually one before and one after a transformation, behave the
+/// same on all the inputs. If `inputs` is `None`, inputs are generated with `gen_inputs`.
+/// Both programs are run in the interpreter, and their returned values, final global states
+/// and traps are compared. The first input on which they differ is reported.
+pub fn equiv(before: &Program, after: &Program, inputs: Option<&[Input]>)
+             -> Result<(), Box<Mismatch>> {
+    let generated;
+    let inputs = match inputs {
+        Some(inputs) => inputs,
+        None => {
+            generated = gen_inputs(before, 0);
+            &generated
+        }
+    };
+    for input in inputs {
+        let (b, a) = (run(before, input), run(after, input));
+        if b != a {
+            return Err(Box::new(Mismatch { input: input.clone(), before: b, after: a }));
+        }
+    }
+    Ok(())
+}
+
+/// Number of generated argument lists for each function
+const N_INPUT: usize = 4;
+
+/// Generate inputs for a program from `seed`. `@main` is always run. Other functions whose
+/// parameters are all integers are each called with several argument lists, which include
+/// boundary values as well as small random ones. Functions are assumed to terminate on any
+/// input.
+pub fn gen_inputs(pro: &Program, seed: u64) -> Vec<Input> {
+    let mut rng = Rng::new(seed);
+    let mut inputs = vec![];
+    for func in pro.func.iter() {
+        let ty: Vec<Type> = func.param.iter().map(|p| p.borrow().get_type().orig()).collect();
+        if func.name == "main" && ty.is_empty() {
+            inputs.push(Input { func: func.name.clone(), arg: vec![] });
+            continue;
+        }
+        if func.name == "main" || !ty.iter().all(|t| matches!(t, Type::I(_))) { continue; }
+        let n = if ty.is_empty() { 1 } else { N_INPUT };
+        for i in 0..n {
+            let arg = ty.iter().map(|t| {
+                // The first argument list only contains zeros, and the second one only
+                // contains boundary values
+                let v = match i {
+                    0 => 0,
+                    1 => if rng.chance(50) { i64::MIN } else { i64::MAX },
+                    _ => rng.range(-16, 16)
+                };
+                Const::from_i64(v, t)
+            }).collect();
+            inputs.push(Input { func: func.name.clone(), arg });
+        }
+    }
+    inputs
+}
+
+/// Run the program on an input and observe its behavior.
+pub fn run(pro: &Program, input: &Input) -> Outcome {
+    let func = match pro.func.iter().find(|f| f.name == input.func) {
+        Some(func) => func,
+        None => return Outcome::Trap(format!("cannot find function @{}", input.func))
+    };
+    match Machine::new().run_fn(pro, func, input.arg.clone()) {
+        Ok((ret, rcd)) => Outcome::Return {
+            ret: ret.iter().map(observe).collect(),
+            global: rcd.global.iter().map(|(g, r)| (g.name.clone(), observe(r))).collect(),
+        },
+        Err(err) => Outcome::Trap(err.msg().to_string())
+    }
+}
+
+fn observe(reg: &Reg) -> String {
+    match reg {
+        Reg::Val(v) => v.to_string(),
+        Reg::Ptr { base: None, off } => format!("null+{}", off),
+        Reg::Ptr { base: Some(_), off } => format!("ptr+{}", off),
+    }
+}
+
+#[test]
+fn test_equiv() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::pass::FnPass;
+    use crate::pass::copy::CopyProp;
+    use crate::pass::fold::ConstFold;
+    use crate::test_util::gen::gen;
+
+    let build = |src: &str| Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build()
+        .unwrap();
+    let before = build(r#"
+@r: i64
+
+[ssa]
+fn @f($a: i64, $b: i64) -> i64 {
+%Begin:
+    $c <- add i64 $a, $b
+    $d <- div i64 $c, $b
+    ret $d
+}
+
+[ssa]
+fn @main() {
+%Begin:
+    @r <- call i64 @f(3, 4)
+    ret
+}
+"#);
+    // Division is dropped, so the trap on zero divisor is lost
+    let after = build(r#"
+@r: i64
+
+[ssa]
+fn @f($a: i64, $b: i64) -> i64 {
+%Begin:
+    $c <- add i64 $a, $b
+    ret $c
+}
+
+[ssa]
+fn @main() {
+%Begin:
+    @r <- call i64 @f(3, 4)
+    ret
+}
+"#);
+    assert!(equiv(&before, &before, None).is_ok());
+
+    // Main stores different values
+    let main = [Input { func: "main".to_string(), arg: vec![] }];
+    let err = equiv(&before, &after, Some(&main)).unwrap_err();
+    println!("{}", err);
+    assert_eq!(err.after, Outcome::Return {
+        ret: vec![],
+        global: vec![("r".to_string(), "7".to_string())],
+    });
+
+    // Trap on zero divisor is found with generated inputs
+    let err = equiv(&before, &after, None).unwrap_err();
+    println!("{}", err);
+    assert_eq!(err.input.func, "f");
+    assert!(matches!(err.before, Outcome::Trap(_)));
+    let arg = vec![Const::I64(3), Const::I64(2)];
+    assert!(equiv(&before, &after, Some(&[Input { func: "f".to_string(), arg }])).is_err());
+    let arg = vec![Const::I64(0), Const::I64(1)];
+    assert!(equiv(&before, &after, Some(&[Input { func: "f".to_string(), arg }])).is_ok());
+
+    // Passes preserve behavior on generated programs
+    for seed in 0..20 {
+        let before = gen(seed);
+        let mut after = gen(seed);
+        FnPass::run(&mut CopyProp::new(), &mut after);
+        FnPass::run(&mut ConstFold::new(), &mut after);
+        let inputs = gen_inputs(&before, seed);
+        assert!(inputs.iter().any(|i| i.arg.first().map(|a| a.get_type()) == Some(Type::I(64))));
+        if let Err(err) = equiv(&before, &after, Some(&inputs)) {
+            panic!("seed {}: {}", seed, err)
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use crate::irc::build::Builder;
+use crate::irc::lex::Lexer;
+use crate::irc::parse::Parser;
+use crate::lang::Program;
+
+/// Options of program generation
+#[derive(Clone, Debug)]
+pub struct GenConfig {
+    /// Number of functions, excluding `@main`
+    pub n_fn: usize,
+    /// Number of regions in each function body
+    pub n_region: usize,
+    /// Maximal nesting depth of branches and loops
+    pub max_depth: usize,
+    /// Maximal number of instructions in a straight-line region
+    pub max_inst: usize,
+}
+
+impl Default for GenConfig {
+    fn default() -> Self {
+        GenConfig { n_fn: 3, n_region: 4, max_depth: 2, max_inst: 4 }
+    }
+}
+
+/// Generator of random well-formed programs, for fuzz testing of passes.
+/// The same seed always produces the same program. All functions are in SSA form. Function
+/// bodies are built from straight-line code, diamonds with phi merges, and counted loops, and
+/// functions only call those defined before them, so all programs terminate. `@main` stores
+/// results of each function to a global variable, so that programs can be compared by their
+/// final global values in the interpreter.
+pub struct ProgramGen {
+    rng: Rng,
+    cfg: GenConfig,
+}
+
+impl ProgramGen {
+    pub fn new(seed: u64) -> ProgramGen {
+        ProgramGen { rng: Rng::new(seed), cfg: Default::default() }
+    }
+
+    pub fn config(mut self, cfg: GenConfig) -> Self {
+        self.cfg = cfg;
+        self
+    }
+
+    /// Generate a program in source form.
+    pub fn gen_source(&mut self) -> String {
+        let mut src = String::new();
+        (0..self.cfg.n_fn).for_each(|i| src += &format!("@g{}: i64\n", i));
+        src += "\n";
+        for i in 0..self.cfg.n_fn {
+            src += &FnGen::new(self, i).gen();
+            src += "\n";
+        }
+        src += "[ssa]\nfn @main() {\n%Begin:\n";
+        for i in 0..self.cfg.n_fn {
+            let (a, b) = (self.rng.range(-8, 8), self.rng.range(-8, 8));
+            src += &format!("    @g{} <- call i64 @f{}({}, {})\n", i, i, a, b);
+        }
+        src += "    ret\n}\n";
+        src
+    }
+
+    /// Generate a program.
+    pub fn gen(&mut self) -> Program {
+        let src = self.gen_source();
+        let tree = Parser::new(Lexer::from(src.as_str())).parse()
+            .unwrap_or_else(|e| panic!("{:?} in generated program:\n{}", e, src));
+        Builder::new(tree).build()
+            .unwrap_or_else(|e| panic!("{:?} in generated program:\n{}", e, src))
+    }
+}
+
+/// Generate a random program with default options.
+pub fn gen(seed: u64) -> Program { ProgramGen::new(seed).gen() }
+
+/// Pseudo-random number generator (xorshift64*)
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng { Rng(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1) }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// Random integer in `[lo, hi)`
+    pub(crate) fn range(&mut self, lo: i64, hi: i64) -> i64 {
+        lo + (self.next() % (hi - lo) as u64) as i64
+    }
+
+    /// Random index below `n`
+    pub(crate) fn below(&mut self, n: usize) -> usize { (self.next() % n as u64) as usize }
+
+    /// Random boolean which is true with probability `p` percent
+    pub(crate) fn chance(&mut self, p: u64) -> bool { self.next() % 100 < p }
+}
+
+/// Number of elements of the local array in each function
+const ARR_LEN: usize = 4;
+
+/// Generator of a single function
+struct FnGen<'a> {
+    pro: &'a mut ProgramGen,
+    /// Index of this function
+    idx: usize,
+    /// Generated blocks with their labels and instructions
+    blocks: Vec<(String, Vec<String>)>,
+    /// Number of generated variables
+    n_var: usize,
+}
+
+impl FnGen<'_> {
+    fn new(pro: &mut ProgramGen, idx: usize) -> FnGen<'_> {
+        FnGen { pro, idx, blocks: vec![], n_var: 0 }
+    }
+
+    fn gen(mut self) -> String {
+        // Entrance initializes local array, whose element pointers are available everywhere
+        self.new_block();
+        self.emit("$m <- alloc [4]i64".to_string());
+        for i in 0..ARR_LEN {
+            self.emit(format!("$p{} <- ptr *i64 $m [{}]", i, i));
+            self.emit(format!("st i64 {} -> $p{}", i, i));
+        }
+        let mut avail = vec!["$a".to_string(), "$b".to_string()];
+        for _ in 0..self.pro.cfg.n_region {
+            avail = self.region(avail, self.pro.cfg.max_depth);
+        }
+        let ret = self.pick(&avail);
+        self.emit(format!("ret {}", ret));
+
+        let mut s = format!("[ssa]\nfn @f{}($a: i64, $b: i64) -> i64 {{\n", self.idx);
+        for (label, inst) in self.blocks.iter() {
+            s += &format!("%{}:\n", label);
+            inst.iter().for_each(|i| s += &format!("    {}\n", i));
+        }
+        s += "}\n";
+        s
+    }
+
+    /// Generate a region, where values in `avail` are available at its beginning, and return
+    /// available values at its end.
+    fn region(&mut self, avail: Vec<String>, depth: usize) -> Vec<String> {
+        match self.pro.rng.below(if depth == 0 { 1 } else { 3 }) {
+            0 => self.straight(avail),
+            1 => self.diamond(avail, depth),
+            _ => self.counted_loop(avail, depth)
+        }
+    }
+
+    fn straight(&mut self, mut avail: Vec<String>) -> Vec<String> {
+        let n = 1 + self.pro.rng.below(self.pro.cfg.max_inst);
+        for _ in 0..n {
+            let dst = self.new_var();
+            let (x, y) = (self.opd(&avail), self.opd(&avail));
+            let rng = &mut self.pro.rng;
+            let instr = match rng.below(10) {
+                0 => format!("{} <- add i64 {}, {}", dst, x, y),
+                1 => format!("{} <- sub i64 {}, {}", dst, x, y),
+                2 => format!("{} <- mul i64 {}, {}", dst, x, y),
+                3 => format!("{} <- {} i64 {}, {}", dst, ["and", "or", "xor"][rng.below(3)], x,
+                             y),
+                4 => format!("{} <- {} i64 {}, {}", dst, ["shl", "shr"][rng.below(2)], x,
+                             rng.range(0, 64)),
+                5 => format!("{} <- {} i64 {}, {}", dst, ["div", "mod"][rng.below(2)], x,
+                             rng.range(1, 8)),
+                6 => format!("{} <- ld i64 $p{}", dst, rng.below(ARR_LEN)),
+                7 => format!("st i64 {} -> $p{}", x, rng.below(ARR_LEN)),
+                8 if self.idx > 0 => format!("{} <- call i64 @f{}({}, {})", dst,
+                                             rng.below(self.idx), x, y),
+                _ => format!("{} <- mov i64 {}", dst, x)
+            };
+            // Stores define no value
+            if !instr.starts_with("st") { avail.push(dst) }
+            self.emit(instr);
+        }
+        avail
+    }
+
+    fn diamond(&mut self, avail: Vec<String>, depth: usize) -> Vec<String> {
+        let cond = self.cond(&avail);
+        let (tr, fls, join) = (self.label(), self.label(), self.label());
+        self.emit(format!("br {} ? %{} : %{}", cond, tr, fls));
+
+        // Generate both branches, each of which may contain nested regions
+        let mut end = vec![];
+        for label in [tr, fls] {
+            self.blocks.push((label, vec![]));
+            let inner = self.region(avail.clone(), depth - 1);
+            let val = self.pick(&inner);
+            end.push((self.cur_label(), val));
+            self.emit(format!("jmp %{}", join));
+        }
+
+        // Merge values at join block
+        self.blocks.push((join, vec![]));
+        let dst = self.new_var();
+        self.emit(format!("{} <- phi i64 [%{}: {}] [%{}: {}]", dst, end[0].0, end[0].1, end[1].0,
+                          end[1].1));
+        let mut avail = avail;
+        avail.push(dst);
+        avail
+    }
+
+    fn counted_loop(&mut self, avail: Vec<String>, depth: usize) -> Vec<String> {
+        let pred = self.cur_label();
+        let (head, exit) = (self.label(), self.label());
+        let init = self.opd(&avail);
+        let trip = self.pro.rng.range(1, 5);
+        self.emit(format!("jmp %{}", head));
+
+        // Loop body starts at header, which holds induction variable and accumulator
+        self.blocks.push((head.clone(), vec![]));
+        let head_idx = self.blocks.len() - 1;
+        let (i, acc) = (self.new_var(), self.new_var());
+        let mut inner = avail.clone();
+        inner.push(i.clone());
+        inner.push(acc.clone());
+        let inner = self.region(inner, depth - 1);
+
+        // Update induction variable and accumulator in latch
+        let latch = self.cur_label();
+        let (next_i, next_acc, cmp) = (self.new_var(), self.new_var(), self.new_var());
+        let val = self.pick(&inner);
+        self.emit(format!("{} <- add i64 {}, 1", next_i, i));
+        self.emit(format!("{} <- add i64 {}, {}", next_acc, acc, val));
+        self.emit(format!("{} <- lt i64 {}, {}", cmp, next_i, trip));
+        self.emit(format!("br {} ? %{} : %{}", cmp, head, exit));
+        let phi = |dst: &str, init: &str, next: &str| {
+            format!("{} <- phi i64 [%{}: {}] [%{}: {}]", dst, pred, init, latch, next)
+        };
+        let head_inst = &mut self.blocks[head_idx].1;
+        head_inst.insert(0, phi(&acc, &init, &next_acc));
+        head_inst.insert(0, phi(&i, "0", &next_i));
+
+        self.blocks.push((exit, vec![]));
+        let mut avail = avail;
+        avail.push(next_acc);
+        avail
+    }
+
+    /// Generate a comparison of available values, and return its result.
+    fn cond(&mut self, avail: &[String]) -> String {
+        let dst = self.new_var();
+        let (x, y) = (self.pick(avail), self.opd(avail));
+        let op = ["eq", "ne", "lt", "le", "gt", "ge"][self.pro.rng.below(6)];
+        self.emit(format!("{} <- {} i64 {}, {}", dst, op, x, y));
+        dst
+    }
+
+    /// Pick an available value or a constant.
+    fn opd(&mut self, avail: &[String]) -> String {
+        if self.pro.rng.chance(25) {
+            self.pro.rng.range(-16, 16).to_string()
+        } else {
+            self.pick(avail)
+        }
+    }
+
+    /// Pick an available value, preferring recent ones.
+    fn pick(&mut self, avail: &[String]) -> String {
+        let n = avail.len();
+        let i = if self.pro.rng.chance(50) { n - 1 - self.pro.rng.below(n.min(3)) } else {
+            self.pro.rng.below(n)
+        };
+        avail[i].clone()
+    }
+
+    fn new_var(&mut self) -> String {
+        self.n_var += 1;
+        format!("$v{}", self.n_var)
+    }
+
+    fn new_block(&mut self) {
+        let label = self.label();
+        self.blocks.push((label, vec![]));
+    }
+
+    fn label(&mut self) -> String {
+        let label = format!("B{}", self.n_var);
+        self.n_var += 1;
+        label
+    }
+
+    fn cur_label(&self) -> String { self.blocks.last().unwrap().0.clone() }
+
+    fn emit(&mut self, instr: String) { self.blocks.last_mut().unwrap().1.push(instr) }
+}
+
+#[test]
+fn test_gen() {
+    use crate::lang::print::Printer;
+    use crate::pass::{FnPass, Pass};
+    use crate::pass::adce::AdceOpt;
+    use crate::pass::copy::CopyProp;
+    use crate::pass::fold::ConstFold;
+    use crate::pass::gvn::GvnOpt;
+    use crate::pass::licm::LicmOpt;
+    use crate::pass::sccp::SccpOpt;
+    use crate::pass::verify::VerifyPass;
+    use crate::vm::exec::Machine;
+    use std::io::stdout;
+
+    // The same seed produces the same program
+    assert_eq!(ProgramGen::new(7).gen_source(), ProgramGen::new(7).gen_source());
+
+    for seed in 0..50 {
+        let mut pro = gen(seed);
+        if seed == 0 { Printer::new(&mut stdout()).print(&pro).unwrap() }
+        let mut ver = VerifyPass::new();
+        ver.run(&mut pro);
+        assert!(ver.is_ok(), "seed {}: {:?}", seed, ver.err);
+        let before = Machine::new().run(&pro).unwrap();
+
+        // Optimizations preserve semantics
+        FnPass::run(&mut CopyProp::new(), &mut pro);
+        FnPass::run(&mut ConstFold::new(), &mut pro);
+        FnPass::run(&mut SccpOpt::new(), &mut pro);
+        FnPass::run(&mut GvnOpt {}, &mut pro);
+        FnPass::run(&mut LicmOpt::new(), &mut pro);
+        FnPass::run(&mut AdceOpt::new(), &mut pro);
+        ver.run(&mut pro);
+        assert!(ver.is_ok(), "seed {}: {:?}", seed, ver.err);
+        let after = Machine::new().run(&pro).unwrap();
+        assert_eq!(format!("{:?}", before.global), format!("{:?}", after.global), "seed {}",
+                   seed);
+    }
+}
@@ -1,20 +1,42 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::{Debug, Error, Formatter};
-use std::ops::{Add, Deref, DerefMut};
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
 
-use crate::lang::func::FnRef;
-use crate::lang::inst::{BinOp, Inst};
+use crate::irc::Loc;
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{BinOp, CastOp, Inst, MemOrd, RmwOp};
+use crate::lang::layout::DataLayout;
 use crate::lang::Program;
-use crate::lang::util::MutRc;
 use crate::lang::value::{Const, GlobalVarRef, Symbol, SymbolRef, Type, Typed, Value};
-use crate::vm::mem::{FrameRef, MemSpace, Reg, RegFile, Stack};
-use crate::vm::stat::Counter;
+use crate::vm::heap::{Heap, HeapStat};
+use crate::vm::mem::{FrameRef, HeapSpace, MemSpace, Reg, RegFile, Stack};
+use crate::vm::stat::{Counter, Profile};
+use crate::vm::thread::{Clock, Intrinsic, RaceDetector, Thread, ThreadState};
+
+/// Number of instructions after which the running thread is switched out
+const SLICE: usize = 64;
 
 pub struct Machine {
     global: HashMap<GlobalVarRef, Reg>,
     stack: Stack,
     count: Counter,
+    /// Memory spaces whose pointers have been converted to integers
+    exposed: Vec<MemSpace>,
+    heap: Heap,
+    /// Register files of callers in the running thread, which are roots of garbage collection
+    suspended: Vec<RegFile>,
+    /// All threads of current run, indexed by their IDs. The main thread is the first one.
+    threads: Vec<Thread>,
+    /// ID of the running thread
+    cur: usize,
+    race: RaceDetector,
+    /// Data layout of the running program. Pointers are registers stored in memory, so their
+    /// size is decided by the VM.
+    layout: DataLayout,
+    /// Profile of current run, if profiling is enabled
+    profile: Option<Profile>,
 }
 
 impl Machine {
@@ -23,131 +45,497 @@ impl Machine {
             global: Default::default(),
             stack: Stack::new(),
             count: Counter::new(),
+            exposed: vec![],
+            heap: Heap::new(),
+            suspended: vec![],
+            threads: vec![],
+            cur: 0,
+            race: Default::default(),
+            layout: Default::default(),
+            profile: None,
         }
     }
 
+    /// Set heap size in bytes that triggers the first garbage collection.
+    pub fn set_gc_threshold(&mut self, size: usize) { self.heap.set_threshold(size) }
+
+    /// Enable profiling, sampling executed instructions once every `period` instructions, or
+    /// disable it with `None`. The profile of each run is provided in its record.
+    pub fn set_profile(&mut self, period: Option<usize>) { self.profile = period.map(Profile::new) }
+
     pub fn run(&mut self, pro: &Program) -> Result<VmRcd, RuntimeErr> {
+        self.run_with_args(pro, &[])
+    }
+
+    /// Run the program with command line arguments `args`, starting from `@main`, or the first
+    /// other entry point if there is no `@main`. If the entry function takes parameters, it
+    /// receives the number of arguments and a vector of pointers to the arguments, each of which
+    /// is a null-terminated string on heap. The value returned by the entry function, if there
+    /// is one, is the exit code of the program.
+    pub fn run_with_args(&mut self, pro: &Program, args: &[&str]) -> Result<VmRcd, RuntimeErr> {
+        // Find program entrance and run that function
+        let main = match pro.entry() {
+            Some(main) => main,
+            None => return Err(RuntimeErr {
+                msg: format!("cannot find program entrance"),
+                frame: vec![],
+            })
+        };
+        self.layout = DataLayout { ptr_size: size_of::<Reg>(), ..pro.layout };
+        let arg = if main.param.is_empty() { vec![] } else {
+            vec![Reg::Val(Const::I64(args.len() as i64)), self.alloc_args(args)]
+        };
+        let (ret, mut rcd) = self.run_regs(pro, &main, arg)?;
+        rcd.exit = ret.first().map_or(0, |r| r.get_const().as_i64());
+        Ok(rcd)
+    }
+
+    /// Allocate argument vector on heap.
+    fn alloc_args(&mut self, args: &[&str]) -> Reg {
+        let ptr_ty = Type::Ptr(Box::new(Type::I(8)));
+        let ptr_size = ptr_ty.size_of(&self.layout);
+        let argv = self.heap.alloc(&ptr_ty, args.len(), ptr_size * args.len());
+        for (i, arg) in args.iter().enumerate() {
+            let len = arg.len() + 1;
+            let space = self.heap.alloc(&Type::I(8), len, len);
+            space.borrow_mut()[..arg.len()].copy_from_slice(arg.as_bytes());
+            let ptr = Reg::Ptr { base: Some(MemSpace::Heap(space)), off: 0 };
+            Self::write_by_type(argv.borrow_mut().deref_mut(), i * ptr_size, ptr);
+        }
+        Reg::Ptr { base: Some(MemSpace::Heap(argv)), off: 0 }
+    }
+
+    /// Run function `func` in the program with given arguments, instead of the program
+    /// entrance. The returned values of this function are also provided.
+    pub fn run_fn(&mut self, pro: &Program, func: &FnRef, arg: Vec<Const>)
+                  -> Result<(Vec<Reg>, VmRcd), RuntimeErr> {
+        self.layout = DataLayout { ptr_size: size_of::<Reg>(), ..pro.layout };
+        self.run_regs(pro, func, arg.into_iter().map(Reg::Val).collect())
+    }
+
+    fn run_regs(&mut self, pro: &Program, func: &FnRef, arg: Vec<Reg>)
+                -> Result<(Vec<Reg>, VmRcd), RuntimeErr> {
+
         // Initialize global variable
+        self.global.clear();
         pro.vars.iter().for_each(|var| {
             let mut reg = Reg::from(&var.ty);
             var.init.map(|init| reg.set_const(init));
             self.global.insert(var.clone(), reg);
         });
 
-        // Find program entrance and run that function
-        match pro.func.iter().find(|func| &func.name == "main") {
-            Some(main) => { self.call(main, vec![])?; }
-            None => self.err(format!("cannot find program entrance"))?
+        // Run the function
+        if arg.len() != func.param.len() {
+            self.err(format!("expect {} arguments for @{}, found {}", func.param.len(),
+                             func.name, arg.len()))?
         }
+        // Reset other per-run state, which may be left by a failed run
+        self.threads = vec![Thread::new(0, Clock::default())];
+        self.cur = 0;
+        self.exposed.clear();
+        self.stack.clear();
+        self.suspended.clear();
+        self.race = Default::default();
+        self.count.reset();
+        let ret = self.call(func, arg)?;
 
         // Collect machine statistics
         let mut global: Vec<_> = self.global.iter()
             .map(|(v, r)| (v.clone(), r.clone())).collect();
         global.sort_by_cached_key(|(v, _)| v.name.clone());
         let count = self.count;
+        self.exposed.clear();
+        let roots = self.roots(None);
+        let stacks: Vec<_> = self.threads.iter().map(|t| &t.stack).chain([&self.stack]).collect();
+        let heap = self.heap.finish(roots, &stacks, &self.layout);
+        let profile = self.profile.as_mut()
+            .map(|prof| std::mem::replace(prof, Profile::new(prof.period)));
 
         // Clear machine state for this program
         self.global.clear();
         self.stack.clear();
+        self.threads.clear();
+        self.race = Default::default();
         self.count.reset();
 
-        Ok(VmRcd { global, count })
+        Ok((ret, VmRcd { global, count, heap, exit: 0, profile }))
     }
 
-    fn call(&mut self, func: &FnRef, arg: Vec<Reg>) -> Result<Option<Reg>, RuntimeErr> {
-        // Pass arguments to parameters
-        let ref mut file: RegFile = func.param.iter().zip(arg.into_iter())
-            .map(|(p, r)| { (p.borrow().clone(), r) }).collect();
+    /// Run `func` in the main thread until it returns. Threads spawned by the program are
+    /// interleaved with the main one, and are discarded once the main thread returns.
+    fn call(&mut self, func: &FnRef, arg: Vec<Reg>) -> Result<Vec<Reg>, RuntimeErr> {
+        let mut file = self.enter(func, arg)?;
+        loop {
+            // Switch to another thread once the running one has used up its time slice
+            if self.threads.len() > 1 && self.count.num.is_multiple_of(SLICE) {
+                self.switch(&mut file)?;
+            }
+
+            // Fetch the instruction at current position of the running thread
+            let frame = self.stack.top();
+            let (cur_blk, instr) = {
+                let frame = frame.borrow();
+                let instr = frame.block.inst.borrow()[frame.instr].clone();
+                (frame.block.clone(), instr)
+            };
+            self.count.count(instr.as_ref());
+            if let Some(prof) = &mut self.profile {
+                prof.exec(&cur_blk);
+                if let Inst::Call { func: _, arg: _, dst: _ }
+                | Inst::CallInd { func_ptr: _, arg: _, dst: _ } = instr.as_ref() {
+                    prof.call(&instr)
+                }
+            }
+
+            // Execute the instruction, and decide whether to continue with the next one
+            let next = match instr.as_ref() {
+                Inst::Phi { src: _, dst: _ } => true,
+                Inst::Mov { src, dst } => {
+                    let reg = self.reg_from_src(src, &file);
+                    self.reg_to_dst(reg, dst, &mut file);
+                    true
+                }
+                Inst::Un { op, opd, dst } => {
+                    let opd = self.reg_from_src(opd, &file).get_const();
+                    let res = Reg::Val(op.eval(opd));
+                    self.reg_to_dst(res, dst, &mut file);
+                    true
+                }
+                Inst::Bin { op, fst, snd, dst } => {
+                    self.exec_bin(*op, fst, snd, dst, &mut file)?;
+                    true
+                }
+                Inst::Cast { op, opd, dst } => {
+                    self.exec_cast(*op, opd, dst, &mut file)?;
+                    true
+                }
+                Inst::Call { func, arg, dst } => self.exec_call(func, arg, dst, &mut file)?,
+                Inst::CallInd { func_ptr, arg, dst } =>
+                    self.exec_call_ind(func_ptr, arg, dst, &mut file)?,
+                Inst::Ret { val } => {
+                    let res = val.iter().map(|val| self.reg_from_src(val, &file)).collect();
+                    self.check_race()?;
+                    self.stack.pop_frame();
+                    if self.stack.len() > 0 {
+                        file = self.suspended.pop().unwrap();
+                        self.ret_to_caller(res, &mut file)?;
+                    } else if self.cur == 0 {
+                        return Ok(res);
+                    } else {
+                        // Values returned by other threads are discarded
+                        self.threads[self.cur].state = ThreadState::Done;
+                        file.clear();
+                        self.switch(&mut file)?;
+                    }
+                    false
+                }
+                Inst::Unreachable => {
+                    self.err("reached unreachable instruction".to_string())?;
+                    false
+                }
+                Inst::Abort { msg } => {
+                    self.err(format!("abort: {}", msg))?;
+                    false
+                }
+                Inst::Jmp { tgt } => {
+                    self.jump(&tgt.borrow(), &mut file);
+                    false
+                }
+                Inst::Br { cond, tr, fls } => {
+                    let cond = self.reg_from_src(cond, &file).get_const();
+                    let cond = if let Const::I1(b) = cond { b } else { unreachable!() };
+                    if let Some(prof) = &mut self.profile { prof.branch(&instr, cond) }
+                    let tgt = if cond { tr.borrow().clone() } else { fls.borrow().clone() };
+                    self.jump(&tgt, &mut file);
+                    false
+                }
+                Inst::Alloc { dst } => {
+                    let ty = dst.borrow().get_type().tgt_type();
+                    let ptr = self.stack.alloc(&ty, ty.size_of(&self.layout));
+                    self.reg_to_dst(ptr, dst, &mut file);
+                    true
+                }
+                Inst::New { dst, len } => {
+                    self.exec_new(dst, len, &mut file);
+                    true
+                }
+                Inst::Ptr { base, off, ind, dst } => {
+                    self.exec_ptr(base, off, ind, dst, &mut file)?;
+                    true
+                }
+                Inst::Ld { ptr, dst } => {
+                    self.exec_ld(ptr, dst, &mut file)?;
+                    true
+                }
+                Inst::St { src, ptr } => {
+                    self.exec_st(src, ptr, &file)?;
+                    true
+                }
+                Inst::Memcpy { src, ptr, len } => {
+                    self.exec_memcpy(src, ptr, len, &file)?;
+                    true
+                }
+                Inst::Memset { src, ptr, len } => {
+                    self.exec_memset(src, ptr, len, &file)?;
+                    true
+                }
+                // Threads are interleaved at instruction boundary, so atomic accesses are plain
+                // ones, which additionally synchronize threads.
+                Inst::AtomicLd { ord, ptr, dst } => {
+                    self.exec_ld(ptr, dst, &mut file)?;
+                    self.sync(*ord, ptr, &file);
+                    true
+                }
+                Inst::AtomicSt { ord, src, ptr } => {
+                    self.exec_st(src, ptr, &file)?;
+                    self.sync(*ord, ptr, &file);
+                    true
+                }
+                Inst::AtomicRmw { op, ord, ptr, val, dst } => {
+                    self.exec_rmw(*op, ptr, val, dst, &mut file)?;
+                    self.sync(*ord, ptr, &file);
+                    true
+                }
+            };
+            self.check_race()?;
+            if next { frame.borrow_mut().instr += 1 }
+        }
+    }
 
-        // Push a new frame to stack
+    /// Push a frame of `func` to stack of the running thread, and create its register file with
+    /// arguments `arg`.
+    fn enter(&mut self, func: &FnRef, arg: Vec<Reg>) -> Result<RegFile, RuntimeErr> {
         if self.stack.len() >= 256 {
             self.err(format!("stack overflow"))?
         }
+        if func.has_block_args() {
+            self.err(format!("@{} should be converted to phi form to run", func.name))?
+        }
         self.stack.push_frame(func);
-        let frame = self.stack.top();
+        if let Some(prof) = &mut self.profile {
+            prof.enter_fn(func);
+            prof.enter_block(&func.ent.borrow());
+        }
+        Ok(func.param.iter().zip(arg).map(|(p, r)| (p.borrow().clone(), r)).collect())
+    }
 
-        // Walk the CFG
-        let mut next_blk = func.ent.borrow().clone();
-        loop {
-            // Assign values to phi destinations
-            for phi in next_blk.inst.borrow().iter() {
-                match phi.as_ref() {
-                    Inst::Phi { src, dst } => {
-                        let src = &src.iter()
-                            .find(|(b, _)| b.borrow().deref() == &frame.borrow().block).unwrap().1;
-                        let val = match src.borrow().deref() {
-                            Value::Var(sym) => file[sym].clone(),
-                            Value::Const(c) => Reg::Val(*c)
-                        };
-                        self.reg_to_dst(val, dst, file)
-                    }
-                    _ => break
+    /// Transfer control of the top frame to block `tgt`, assigning values to its phi
+    /// destinations.
+    fn jump(&mut self, tgt: &BlockRef, file: &mut RegFile) {
+        let frame = self.stack.top();
+        for phi in tgt.inst.borrow().iter() {
+            match phi.as_ref() {
+                Inst::Phi { src, dst } => {
+                    let src = &src.iter()
+                        .find(|(b, _)| b.borrow().deref() == &frame.borrow().block).unwrap().1;
+                    let val = match src.borrow().deref() {
+                        Value::Var(sym) => file[sym].clone(),
+                        Value::Const(c) => Reg::Val(*c)
+                    };
+                    self.reg_to_dst(val, dst, file)
                 }
+                _ => break
             }
+        }
+        frame.borrow_mut().block = tgt.clone();
+        frame.borrow_mut().instr = 0;
+        if let Some(prof) = &mut self.profile { prof.enter_block(tgt) }
+    }
 
-            // Transfer to the new block and execute the remaining instructions
-            let cur_blk = next_blk.clone();
-            frame.borrow_mut().block = cur_blk.clone();
-            for instr in cur_blk.inst.borrow().iter() {
-                self.count.count(instr.as_ref());
-                match instr.as_ref() {
-                    Inst::Phi { src: _, dst: _ } => {}
-                    Inst::Mov { src, dst } => {
-                        let reg = self.reg_from_src(src, file);
-                        self.reg_to_dst(reg, dst, file);
-                    }
-                    Inst::Un { op, opd, dst } => {
-                        let opd = self.reg_from_src(opd, file).get_const();
-                        let res = Reg::Val(op.eval(opd));
-                        self.reg_to_dst(res, dst, file);
-                    }
-                    Inst::Bin { op, fst, snd, dst } => self.exec_bin(*op, fst, snd, dst, file),
-                    Inst::Call { func, arg, dst } => {
-                        let arg: Vec<_> = arg.iter().map(|a| self.reg_from_src(a, file)).collect();
-                        let res = self.call(func, arg)?;
-                        dst.as_ref().map(|dst| self.reg_to_dst(res.unwrap(), dst, file));
-                    }
-                    Inst::Ret { val } => {
-                        let res = val.as_ref().map(|val| self.reg_from_src(val, file));
-                        self.stack.pop_frame();
-                        return Ok(res);
-                    }
-                    Inst::Jmp { tgt } => {
-                        next_blk = tgt.borrow().clone();
-                        frame.borrow_mut().instr = 0;
-                        break;
-                    }
-                    Inst::Br { cond, tr, fls } => {
-                        let cond = self.reg_from_src(cond, file).get_const();
-                        let cond = if let Const::I1(b) = cond { b } else { unreachable!() };
-                        next_blk = if cond { tr.borrow().clone() } else { fls.borrow().clone() };
-                        frame.borrow_mut().instr = 0;
-                        break;
-                    }
-                    Inst::Alloc { dst } => {
-                        let ptr = self.stack.alloc(dst.borrow().get_type().tgt_type().size());
-                        self.reg_to_dst(ptr, dst, file);
-                    }
-                    Inst::New { dst, len } => self.exec_new(dst, len, file),
-                    Inst::Ptr { base, off, ind, dst } =>
-                        self.exec_ptr(base, off, ind, dst, file)?,
-                    Inst::Ld { ptr, dst } => self.exec_ld(ptr, dst, file)?,
-                    Inst::St { src, ptr } => self.exec_st(src, ptr, file)?
+    /// Assign values returned by callee to destinations of the call instruction in top frame,
+    /// and proceed to the next instruction.
+    fn ret_to_caller(&mut self, res: Vec<Reg>, file: &mut RegFile) -> Result<(), RuntimeErr> {
+        let frame = self.stack.top();
+        let instr = frame.borrow().block.inst.borrow()[frame.borrow().instr].clone();
+        match instr.as_ref() {
+            Inst::Call { func: _, arg: _, dst } | Inst::CallInd { func_ptr: _, arg: _, dst } =>
+                res.into_iter().zip(dst.iter())
+                    .for_each(|(res, dst)| self.reg_to_dst(res, dst, file)),
+            _ => unreachable!()
+        }
+        self.check_race()?;
+        frame.borrow_mut().instr += 1;
+        Ok(())
+    }
+
+    /// Call `func` in the running thread. Returns whether the caller should continue with the
+    /// next instruction, which is not the case when a frame is pushed or the thread is blocked.
+    fn exec_call(&mut self, func: &FnRef, arg: &[RefCell<Value>],
+                 dst: &[RefCell<SymbolRef>], file: &mut RegFile) -> Result<bool, RuntimeErr>
+    {
+        let arg: Vec<_> = arg.iter().map(|a| self.reg_from_src(a, file)).collect();
+        self.check_race()?;
+        if let Some(intr) = Intrinsic::from_fn(func) {
+            return self.exec_intrinsic(intr, func, arg, dst, file);
+        }
+        let callee = self.enter(func, arg)?;
+        self.suspended.push(std::mem::replace(file, callee));
+        Ok(false)
+    }
+
+    fn exec_call_ind(&mut self, func_ptr: &RefCell<Value>, arg: &[RefCell<Value>],
+                     dst: &[RefCell<SymbolRef>], file: &mut RegFile)
+                     -> Result<bool, RuntimeErr>
+    {
+        let func = self.reg_from_src(func_ptr, file);
+        let func = self.fn_from_ptr(func, "call")?;
+        // The pointer may be converted from an integer, so the signature of called function is
+        // checked at runtime.
+        let ptr_ty = func_ptr.borrow().get_type();
+        if Type::Ptr(Box::new(func.get_type())) != ptr_ty {
+            self.err(format!("call of @{} through pointer of type {}", func.name,
+                             ptr_ty.to_string()))?
+        }
+        self.exec_call(&func, arg, dst, file)
+    }
+
+    /// Get function pointed by `ptr`, which is used for operation `op`.
+    fn fn_from_ptr(&self, ptr: Reg, op: &str) -> Result<FnRef, RuntimeErr> {
+        let msg = match ptr {
+            Reg::Ptr { base: Some(MemSpace::Fn(func)), off: 0 } => return Ok(func),
+            Reg::Ptr { base: None, off: _ } => format!("{} of null function pointer", op),
+            _ => format!("{} of invalid function pointer", op)
+        };
+        Err(RuntimeErr { msg, frame: self.stack.unwind() })
+    }
+
+    fn exec_intrinsic(&mut self, intr: Intrinsic, func: &FnRef, arg: Vec<Reg>,
+                      dst: &[RefCell<SymbolRef>], file: &mut RegFile) -> Result<bool, RuntimeErr>
+    {
+        if !intr.check(func) {
+            self.err(format!("intrinsic @{} has invalid signature {}", func.name,
+                             func.get_type().to_string()))?
+        }
+        match intr {
+            Intrinsic::Spawn => {
+                // Check the function to be called in new thread
+                let mut arg = arg.into_iter();
+                let callee = self.fn_from_ptr(arg.next().unwrap(), "spawn")?;
+                let ptr_ty = func.param[0].borrow().get_type();
+                if Type::Ptr(Box::new(callee.get_type())) != ptr_ty {
+                    self.err(format!("spawn of @{} through pointer of type {}", callee.name,
+                                     ptr_ty.to_string()))?
+                }
+                if callee.has_block_args() {
+                    self.err(format!("@{} should be converted to phi form to run", callee.name))?
+                }
+                // Each thread has its own stack, so stack memory cannot be shared.
+                let arg: Vec<_> = arg.collect();
+                let on_stack = |r: &Reg| {
+                    matches!(r, Reg::Ptr { base: Some(MemSpace::Stack(_)), off: _ })
+                };
+                if arg.iter().any(on_stack) {
+                    self.err("stack memory passed to spawned thread".to_string())?
+                }
+
+                // Create the thread, whose events happen after those of current thread
+                let id = self.threads.len();
+                let mut thread = Thread::new(id, self.threads[self.cur].clock.clone());
+                thread.stack.push_frame(&callee);
+                if let Some(prof) = &mut self.profile {
+                    prof.enter_fn(&callee);
+                    prof.enter_block(&callee.ent.borrow());
                 }
-                frame.borrow_mut().instr += 1;
+                thread.file = callee.param.iter().zip(arg)
+                    .map(|(p, r)| (p.borrow().clone(), r)).collect();
+                self.threads.push(thread);
+                let cur = self.cur;
+                self.threads[cur].clock.tick(cur);
+                if let Some(dst) = dst.first() {
+                    self.reg_to_dst(Reg::Val(Const::I64(id as i64)), dst, file)
+                }
+                Ok(true)
             }
+            Intrinsic::Join => {
+                let id = arg[0].get_const().as_i64();
+                if id < 0 || id as usize >= self.threads.len() || id as usize == self.cur {
+                    self.err(format!("join of invalid thread {}", id))?
+                }
+                let id = id as usize;
+                if self.threads[id].state == ThreadState::Done {
+                    let clock = self.threads[id].clock.clone();
+                    self.threads[self.cur].clock.join(&clock);
+                    return Ok(true);
+                }
+                // Block current thread. It proceeds when it is resumed.
+                self.threads[self.cur].state = ThreadState::Join(id);
+                self.switch(file)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Suspend the running thread, and resume the next thread that is able to run in a
+    /// round-robin manner. `file` is the register file of the running thread.
+    fn switch(&mut self, file: &mut RegFile) -> Result<(), RuntimeErr> {
+        let n = self.threads.len();
+        let next = (1..=n).map(|i| (self.cur + i) % n).find(|id| match self.threads[*id].state {
+            ThreadState::Ready => true,
+            ThreadState::Join(t) => self.threads[t].state == ThreadState::Done,
+            ThreadState::Done => false
+        });
+        let next = match next {
+            Some(next) => next,
+            None => return self.err("deadlock: all threads are blocked".to_string())
+        };
+
+        // Swap the states of threads
+        for id in [self.cur, next] {
+            let thread = &mut self.threads[id];
+            std::mem::swap(&mut thread.stack, &mut self.stack);
+            std::mem::swap(&mut thread.suspended, &mut self.suspended);
+            std::mem::swap(&mut thread.file, file);
+        }
+        self.cur = next;
+
+        // Complete the join that blocks the resumed thread
+        if let ThreadState::Join(id) = self.threads[next].state {
+            let clock = self.threads[id].clock.clone();
+            self.threads[next].clock.join(&clock);
+            self.threads[next].state = ThreadState::Ready;
+            self.stack.top().borrow_mut().instr += 1;
+        }
+        Ok(())
+    }
+
+    /// Synchronize the running thread with others through memory pointed by `ptr`, according
+    /// to the ordering `ord` of an atomic access.
+    fn sync(&mut self, ord: MemOrd, ptr: &RefCell<Value>, file: &RegFile) {
+        if self.threads.len() <= 1 { return; }
+        let loc = match self.reg_from_src(ptr, file) {
+            Reg::Ptr { base: Some(base), off } => (base, off),
+            _ => return
+        };
+        let clock = &mut self.threads[self.cur].clock;
+        if ord.is_acquire() { self.race.acquire(&loc, clock) }
+        if ord.is_release() {
+            self.race.release(loc, clock);
+            clock.tick(self.cur);
+        }
+    }
+
+    /// Report the data race found in the last instruction, if there is one.
+    fn check_race(&mut self) -> Result<(), RuntimeErr> {
+        match self.race.take() {
+            Some(msg) => self.err(msg),
+            None => Ok(())
         }
     }
 
     fn exec_st(&mut self, src: &RefCell<Value>, ptr: &RefCell<Value>, file: &RegFile)
                -> Result<(), RuntimeErr>
     {
-        let ptr_reg = self.reg_from_src(ptr, file);
+        let ptr = self.reg_from_src(ptr, file);
         let src_ty = src.borrow().get_type();
         let src = self.reg_from_src(src, file);
-        match ptr_reg {
+        self.store(ptr, src, &src_ty)
+    }
+
+    /// Store register `src` of type `ty` to memory pointed by `ptr`.
+    fn store(&mut self, ptr: Reg, src: Reg, ty: &Type) -> Result<(), RuntimeErr> {
+        match ptr {
             Reg::Ptr { base, off } => {
-                let mem_end = off + src_ty.size();
+                let mem_end = off + ty.size_of(&self.layout);
                 match base.as_ref() {
                     None => self.err(format!("dereference of null pointer"))?,
                     Some(MemSpace::Stack(addr)) => match self.stack.get_mem_mut(*addr) {
@@ -162,6 +550,7 @@ impl Machine {
                             self.err(format!("memory access out of bound"))?
                         }
                     }
+                    Some(MemSpace::Fn(_)) => self.err("write to function".to_string())?
                 }
             }
             Reg::Val(_) => unreachable!()
@@ -182,40 +571,108 @@ impl Machine {
     }
 
     fn write<T>(mem: &mut Vec<u8>, addr: usize, val: T) {
+        // The memory may hold value of another type (e.g. through a union), which should not
+        // be dropped as `T`
         let ptr = &mut mem[addr] as *mut u8 as *mut T;
-        unsafe { *ptr = val }
+        unsafe { ptr.write_unaligned(val) }
     }
 
     fn exec_ld(&mut self, ptr: &RefCell<Value>, dst: &RefCell<SymbolRef>, file: &mut RegFile)
                -> Result<(), RuntimeErr>
     {
-        let ptr_reg = self.reg_from_src(ptr, file);
-        let ref dst_ty = dst.borrow().get_type();
-        match ptr_reg {
+        let ptr = self.reg_from_src(ptr, file);
+        let reg = self.load(ptr, &dst.borrow().get_type())?;
+        self.reg_to_dst(reg, dst, file);
+        Ok(())
+    }
+
+    /// Load register of type `ty` from memory pointed by `ptr`.
+    fn load(&self, ptr: Reg, ty: &Type) -> Result<Reg, RuntimeErr> {
+        match ptr {
             Reg::Ptr { base, off } => {
-                let mem_end = off + dst_ty.size();
+                let mem_end = off + ty.size_of(&self.layout);
                 match base.as_ref() {
                     None => self.err(format!("dereference of null pointer"))?,
                     Some(MemSpace::Stack(addr)) => match self.stack.get_mem(*addr) {
-                        Some(mem) if mem_end <= mem.len() => {
-                            let reg = Self::read_by_type(mem, off, dst_ty);
-                            self.reg_to_dst(reg, dst, file);
-                        }
+                        Some(mem) if mem_end <= mem.len() =>
+                            return Ok(Self::read_by_type(mem, off, ty)),
                         Some(_) => self.err(format!("memory access out of bound"))?,
                         None => self.err(format!("stack space does not exist"))?
                     }
                     Some(MemSpace::Heap(mem)) => {
                         if mem_end <= mem.borrow().len() {
-                            let reg = Self::read_by_type(mem.borrow().deref(), off, dst_ty);
-                            self.reg_to_dst(reg, dst, file);
+                            return Ok(Self::read_by_type(mem.borrow().deref(), off, ty));
                         } else {
                             self.err(format!("memory access out of bound"))?
                         }
                     }
+                    Some(MemSpace::Fn(_)) => self.err("read from function".to_string())?
                 }
+                unreachable!()
             }
             Reg::Val(_) => unreachable!()
         }
+    }
+
+    fn exec_rmw(&mut self, op: RmwOp, ptr: &RefCell<Value>, val: &RefCell<Value>,
+                dst: &RefCell<SymbolRef>, file: &mut RegFile) -> Result<(), RuntimeErr>
+    {
+        let ty = val.borrow().get_type();
+        let ptr = self.reg_from_src(ptr, file);
+        let val = self.reg_from_src(val, file);
+        let old = self.load(ptr.clone(), &ty)?;
+        let new = match op.bin_op() {
+            Some(bin) => Reg::Val(bin.eval(old.get_const(), val.get_const()).unwrap()),
+            None => val
+        };
+        self.store(ptr, new, &ty)?;
+        self.reg_to_dst(old, dst, file);
+        Ok(())
+    }
+
+    fn exec_memcpy(&mut self, src: &RefCell<Value>, ptr: &RefCell<Value>,
+                   len: &RefCell<Value>, file: &RegFile) -> Result<(), RuntimeErr>
+    {
+        let ty = src.borrow().get_type().tgt_type().orig();
+        let size = ty.size_of(&self.layout);
+        let (src, ptr) = (self.reg_from_src(src, file), self.reg_from_src(ptr, file));
+        let len = self.reg_from_src(len, file).get_const().as_i64();
+        if len <= 0 { return Ok(()); }
+        let len = len as usize;
+
+        // Copying between overlapping memory is undefined
+        let (src_off, ptr_off) = (src.get_off(), ptr.get_off());
+        let (mut src_base, mut ptr_base) = (src.clone(), ptr.clone());
+        src_base.set_off(0);
+        ptr_base.set_off(0);
+        if src_base == ptr_base && src_off < ptr_off + len * size
+            && ptr_off < src_off + len * size {
+            self.err(format!("memcpy between overlapping memory"))?
+        }
+
+        // Copy elements one by one, so that pointers in memory are kept
+        for i in 0..len {
+            let (mut from, mut to) = (src_base.clone(), ptr_base.clone());
+            from.set_off(src_off + i * size);
+            to.set_off(ptr_off + i * size);
+            let reg = self.load(from, &ty)?;
+            self.store(to, reg, &ty)?;
+        }
+        Ok(())
+    }
+
+    fn exec_memset(&mut self, src: &RefCell<Value>, ptr: &RefCell<Value>,
+                   len: &RefCell<Value>, file: &RegFile) -> Result<(), RuntimeErr>
+    {
+        let ty = src.borrow().get_type();
+        let size = ty.size_of(&self.layout);
+        let (src, ptr) = (self.reg_from_src(src, file), self.reg_from_src(ptr, file));
+        let len = self.reg_from_src(len, file).get_const().as_i64();
+        for i in 0..len.max(0) as usize {
+            let mut to = ptr.clone();
+            to.set_off(ptr.get_off() + i * size);
+            self.store(to, src.clone(), &ty)?;
+        }
         Ok(())
     }
 
@@ -232,17 +689,22 @@ impl Machine {
     }
 
     fn read<T: Clone>(mem: &Vec<u8>, addr: usize) -> T {
+        // The value may not be aligned in packed layout. It is still owned by the memory, so
+        // it should not be dropped here.
         let ptr = &mem[addr] as *const u8 as *const T;
-        unsafe { (*ptr).clone() }
+        ManuallyDrop::new(unsafe { ptr.read_unaligned() }).deref().clone()
     }
 
     fn exec_bin(&mut self, op: BinOp, fst: &RefCell<Value>, snd: &RefCell<Value>,
-                dst: &RefCell<SymbolRef>, file: &mut RegFile)
+                dst: &RefCell<SymbolRef>, file: &mut RegFile) -> Result<(), RuntimeErr>
     {
         let fst = self.reg_from_src(fst, file);
         let snd = self.reg_from_src(snd, file);
         let res = if fst.is_val() { // use built-in constant evaluation function
-            Reg::Val(op.eval(fst.get_const(), snd.get_const()))
+            match op.eval(fst.get_const(), snd.get_const()) {
+                Some(c) => Reg::Val(c),
+                None => return self.err(format!("division by zero"))
+            }
         } else {
             match op {
                 BinOp::Eq => Reg::Val(Const::I1(fst == snd)),
@@ -255,23 +717,69 @@ impl Machine {
             }
         };
         self.reg_to_dst(res, dst, file);
+        Ok(())
+    }
+
+    fn exec_cast(&mut self, op: CastOp, opd: &RefCell<Value>, dst: &RefCell<SymbolRef>,
+                 file: &mut RegFile) -> Result<(), RuntimeErr>
+    {
+        let opd = self.reg_from_src(opd, file);
+        let dst_ty = dst.borrow().get_type();
+        let res = match (op, opd) {
+            (CastOp::PtrToInt, Reg::Ptr { base, off }) => {
+                // The integer encodes index of the memory space in high 32 bits, and offset in
+                // low 32 bits. Null pointer is converted to its offset.
+                let idx = match base {
+                    Some(base) => match self.exposed.iter().position(|m| *m == base) {
+                        Some(i) => i + 1,
+                        None => {
+                            self.exposed.push(base);
+                            self.exposed.len()
+                        }
+                    }
+                    None => 0
+                };
+                Reg::Val(Const::I64(((idx as i64) << 32) + off as i64))
+            }
+            (CastOp::IntToPtr, Reg::Val(c)) => {
+                let v = c.as_i64();
+                let (idx, off) = ((v >> 32) as usize, (v & 0xffffffff) as usize);
+                match idx {
+                    0 => Reg::Ptr { base: None, off },
+                    i if i <= self.exposed.len() =>
+                        Reg::Ptr { base: Some(self.exposed[i - 1].clone()), off },
+                    _ => return self.err(format!("cannot convert integer {} to pointer", v))
+                }
+            }
+            (op, Reg::Val(c)) => Reg::Val(op.eval(c, &dst_ty).unwrap()),
+            _ => unreachable!()
+        };
+        self.reg_to_dst(res, dst, file);
+        Ok(())
     }
 
     fn exec_new(&mut self, dst: &RefCell<SymbolRef>, len: &Option<RefCell<Value>>,
                 file: &mut RegFile)
     {
         // Compute size of heap space to be dynamically allocated
-        let mut size = dst.borrow().get_type().tgt_type().size();
-        len.as_ref().map(|len| {
-            let len = self.reg_from_src(len, file).get_const();
-            let len = if let Const::I64(c) = len { c } else { unreachable!() };
-            size *= len as usize;
-        });
+        let ty = dst.borrow().get_type().tgt_type();
+        let len = match len {
+            Some(len) => match self.reg_from_src(len, file).get_const() {
+                Const::I64(c) => c as usize,
+                _ => unreachable!()
+            }
+            None => 1
+        };
 
-        // Allocate heap space
-        // This space will be handled by the mutable reference counter. No garbage collector is
-        // needed in this VM.
-        let space = MutRc::new(vec![0; size]);
+        // Allocate heap space, collecting garbage if the heap grows too large
+        let size = ty.size_of(&self.layout) * len;
+        if self.heap.need_collect(size) {
+            let roots = self.roots(Some(file));
+            let stacks: Vec<_> = self.threads.iter().map(|t| &t.stack).chain([&self.stack])
+                .collect();
+            self.heap.collect(roots, &stacks, &self.layout);
+        }
+        let space = self.heap.alloc(&ty, len, size);
         let ptr = Reg::Ptr {
             base: Some(MemSpace::Heap(space)),
             off: 0,
@@ -289,29 +797,22 @@ impl Machine {
         off.as_ref().map(|off| {
             let off = self.reg_from_src(off, file).get_const();
             let off = if let Const::I64(c) = off { c } else { unreachable!() };
-            size_off += off as usize * tgt_ty.size();
+            size_off += off as usize * tgt_ty.size_of(&self.layout);
         });
 
         // Compute element offset inside aggregate
         for idx in ind {
             let idx = self.reg_from_src(idx, file).get_const();
             let idx = if let Const::I64(c) = idx { c as usize } else { unreachable!() };
-            match tgt_ty.orig().clone() {
-                Type::Array { elem, len } => {
-                    if idx >= len {
-                        self.err(format!("index {} out of bound {}", idx, len))?
-                    }
-                    size_off += elem.size() * idx;
-                    tgt_ty = elem.deref().clone();
+            // Indices into structures and unions are checked at irc time, impossible to be out
+            // of bound
+            if let Type::Array { elem: _, len } = tgt_ty.orig() {
+                if idx >= len {
+                    self.err(format!("index {} out of bound {}", idx, len))?
                 }
-                Type::Struct { field } => {
-                    // indices into struct are checked at irc time, impossible to be out of
-                    // bound
-                    size_off += field[..idx].iter().map(|f| f.size()).fold(0, Add::add);
-                    tgt_ty = field[idx].clone();
-                }
-                _ => unreachable!()
             }
+            size_off += tgt_ty.field_offset(idx, &self.layout);
+            tgt_ty = tgt_ty.elem_type(idx);
         }
 
         // Store the new address
@@ -321,15 +822,22 @@ impl Machine {
         Ok(())
     }
 
-    fn reg_from_src(&self, src: &RefCell<Value>, file: &RegFile) -> Reg {
+    fn reg_from_src(&mut self, src: &RefCell<Value>, file: &RegFile) -> Reg {
         match src.borrow().deref() {
             Value::Var(sym) if sym.is_local_var() => match file.get(sym) {
                 Some(reg) => reg.clone(),
                 None => panic!("value {:?} undefined", src.borrow().deref())
             },
-            Value::Var(sym) => if let Symbol::Global(g) = sym.as_ref() {
-                self.global[g].clone()
-            } else { unreachable!() }
+            Value::Var(sym) => match sym.as_ref() {
+                Symbol::Global(g) => {
+                    if self.threads.len() > 1 {
+                        self.race.read(g, self.cur, &self.threads[self.cur].clock)
+                    }
+                    self.global[g].clone()
+                }
+                Symbol::Func(f) => Reg::Ptr { base: Some(MemSpace::Fn(f.clone())), off: 0 },
+                _ => unreachable!()
+            }
             Value::Const(c) => Reg::Val(*c)
         }
     }
@@ -337,11 +845,32 @@ impl Machine {
     fn reg_to_dst(&mut self, reg: Reg, dst: &RefCell<SymbolRef>, file: &mut RegFile) {
         match dst.borrow().as_ref() {
             sym if sym.is_local_var() => { file.insert(dst.borrow().clone(), reg); }
-            Symbol::Global(g) => { *self.global.get_mut(g).unwrap() = reg; }
+            Symbol::Global(g) => {
+                if self.threads.len() > 1 {
+                    self.race.write(g, self.cur, &self.threads[self.cur].clock)
+                }
+                *self.global.get_mut(g).unwrap() = reg;
+            }
             _ => unreachable!()
         }
     }
 
+    /// Collect heap spaces pointed by global variables, exposed pointers, and registers of all
+    /// active frames in all threads. `file` is the register file of current frame.
+    fn roots(&self, file: Option<&RegFile>) -> Vec<HeapSpace> {
+        let others = self.threads.iter()
+            .flat_map(|t| t.suspended.iter().chain([&t.file]).flat_map(|f| f.values()));
+        let regs = self.global.values().chain(self.suspended.iter().flat_map(|f| f.values()))
+            .chain(file.into_iter().flat_map(|f| f.values())).chain(others);
+        regs.filter_map(|r| match r {
+            Reg::Ptr { base: Some(MemSpace::Heap(space)), off: _ } => Some(space.clone()),
+            _ => None
+        }).chain(self.exposed.iter().filter_map(|m| match m {
+            MemSpace::Heap(space) => Some(space.clone()),
+            _ => None
+        })).collect()
+    }
+
     fn err(&self, msg: String) -> Result<(), RuntimeErr> {
         Err(RuntimeErr { msg, frame: self.stack.unwind() })
     }
@@ -351,11 +880,20 @@ impl Machine {
 pub struct VmRcd {
     pub global: Vec<(GlobalVarRef, Reg)>,
     pub count: Counter,
+    pub heap: HeapStat,
+    /// Exit code of the program, which is the value returned by `@main`, or zero if it returns
+    /// nothing
+    pub exit: i64,
+    /// Execution profile, if profiling is enabled
+    pub profile: Option<Profile>,
 }
 
 impl Debug for VmRcd {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        writeln!(f, "program terminated")?;
+        match self.exit {
+            0 => writeln!(f, "program terminated")?,
+            code => writeln!(f, "program terminated with exit code {}", code)?
+        }
         writeln!(f, "instructions: {}  time: {}", self.count.num, self.count.time)?;
         if !self.global.is_empty() {
             writeln!(f, "\nglobal variables: ")?;
@@ -367,6 +905,9 @@ impl Debug for VmRcd {
                 }
             }
         }
+        if !self.heap.ty.is_empty() {
+            write!(f, "\nheap: \n{:?}", self.heap)?;
+        }
         Ok(())
     }
 }
@@ -376,13 +917,25 @@ pub struct RuntimeErr {
     frame: Vec<FrameRef>,
 }
 
+impl RuntimeErr {
+    /// Message of this error
+    pub fn msg(&self) -> &str { &self.msg }
+
+    /// Source location of the instruction that causes this error, if there is one.
+    pub fn loc(&self) -> Option<Loc> { self.frame.last().and_then(|frame| frame.borrow().loc()) }
+}
+
 impl Debug for RuntimeErr {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         writeln!(f, "runtime error: {}", self.msg)?;
         writeln!(f, "call stack: ")?;
         for (i, frame) in self.frame.iter().rev().enumerate() {
-            writeln!(f, "{} @{}, %{:?}, #{}", i, frame.borrow().func.name,
-                     frame.borrow().block.name, frame.borrow().instr)?;
+            write!(f, "{} @{}, %{:?}, #{}", i, frame.borrow().func.name,
+                   frame.borrow().block.name, frame.borrow().instr)?;
+            match frame.borrow().loc() {
+                Some(loc) => writeln!(f, " ({})", loc)?,
+                None => writeln!(f)?
+            }
         }
         Ok(())
     }
@@ -409,3 +962,522 @@ fn test_exec() {
     let rcd = mach.run(&mut pro).unwrap();
     println!("{:?}", rcd);
 }
+
+#[test]
+fn test_overflow() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::vm::exec::Machine;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut file = File::open("test/ov.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree);
+    let mut pro = builder.build().unwrap();
+
+    let mut mach = Machine::new();
+    let rcd = mach.run(&mut pro).unwrap();
+    println!("{:?}", rcd);
+    let get = |name: &str| rcd.global.iter().find(|(g, _)| g.name == name).unwrap().1
+        .get_const();
+    assert_eq!((get("n"), get("f")), (Const::I32(12), Const::I32(479001600)));
+    assert_eq!((get("s"), get("m")), (Const::I8(127), Const::I64(i64::MAX)));
+
+    // Division by zero traps
+    let mut file = File::open("test/fold.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let tree = Parser::new(lexer).parse().unwrap();
+    let mut pro = Builder::new(tree).build().unwrap();
+    let err = mach.run(&mut pro).unwrap_err();
+    println!("{:?}", err);
+    let loc = err.loc().unwrap();
+    assert_eq!((loc.line(), loc.col()), (11, 4));
+}
+
+#[test]
+fn test_cast() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::{Read, stdout};
+
+    let mut file = File::open("test/cast.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let parser = Parser::new(lexer);
+    let tree = parser.parse().unwrap();
+    let builder = Builder::new(tree).fold_const(true);
+    let pro = builder.build().unwrap();
+
+    let mut out = stdout();
+    let mut printer = Printer::new(&mut out);
+    printer.print(&pro).unwrap();
+
+    let mut mach = Machine::new();
+    let rcd = mach.run(&pro).unwrap();
+    let z = rcd.global.iter().find(|(g, _)| g.name == "z").unwrap();
+    assert_eq!(z.1.get_const(), Const::I32(65728));
+
+    // Pointers exposed in a failed run cannot be recovered in the next one
+    let build = |src: &str| Builder::new(Parser::new(Lexer::from(src)).parse().unwrap())
+        .build().unwrap();
+    let expose = build("fn @main() { %Begin: $p <- new i64 \
+                        $i <- ptrtoint *i64 $p -> i64 abort \"stop\" }");
+    assert!(mach.run(&expose).is_err());
+    let recover = build("@v: i64 fn @main() { %Begin: $p <- inttoptr i64 4294967296 -> *i64 \
+                         $v <- ld i64 $p @v <- mov i64 $v ret }");
+    assert!(mach.run(&recover).is_err());
+    assert_eq!(mach.run(&pro).unwrap().global, rcd.global);
+}
+
+#[test]
+fn test_call_ind() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::pass::verify::VerifyPass;
+    use crate::pass::{FnPass, Pass};
+    use crate::pass::adce::AdceOpt;
+    use crate::pass::copy::CopyProp;
+    use crate::pass::dse::GlobalDse;
+    use crate::test_util::check::equiv;
+    use crate::vm::exec::Machine;
+
+    let build = |src: &str| Builder::new(Parser::new(Lexer::from(src)).parse()?).build();
+    let src = r#"
+@r: i64
+@s: i64
+@op: *fn(i64) -> i64
+
+[ssa]
+fn @twice($x: i64) -> i64 {
+%Begin:
+    $y <- add i64 $x, $x
+    ret $y
+}
+
+[ssa]
+fn @neg($x: i64) -> i64 {
+%Begin:
+    @s <- sub i64 0, $x
+    ret @s
+}
+
+[ssa]
+fn @apply($f: *fn(i64) -> i64, $x: i64) -> i64 {
+%Begin:
+    $y <- call i64 $f($x)
+    ret $y
+}
+
+[ssa]
+fn @main() {
+%Begin:
+    @op <- mov *fn(i64) -> i64 @neg
+    $f <- mov *fn(i64) -> i64 @twice
+    $a <- call i64 @apply($f, 3)
+    @s <- mov i64 0
+    $b <- call i64 @op($a)
+    @r <- add i64 $a, $b
+    ret
+}
+"#;
+    let mut before = build(src).unwrap();
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut before);
+    assert!(ver.is_ok());
+    Printer::new(&mut std::io::stdout()).print(&before).unwrap();
+    let rcd = Machine::new().run(&before).unwrap();
+    let get = |name: &str| rcd.global.iter().find(|(g, _)| g.name == name).unwrap().1
+        .get_const();
+    assert_eq!(get("r"), Const::I64(0));
+    assert_eq!(get("s"), Const::I64(-6));
+
+    // Indirect calls are conservatively handled by optimizations
+    let mut after = build(src).unwrap();
+    FnPass::run(&mut CopyProp::new(), &mut after);
+    FnPass::run(&mut AdceOpt::new(), &mut after);
+    Pass::run(&mut GlobalDse::new(), &mut after);
+    Printer::new(&mut std::io::stdout()).print(&after).unwrap();
+    if let Err(err) = equiv(&before, &after, None) { panic!("{}", err) }
+
+    // Calling null pointer traps
+    let pro = build(r#"
+@op: *fn()
+
+fn @main() {
+%Begin:
+    call @op()
+    ret
+}
+"#).unwrap();
+    let err = Machine::new().run(&pro).unwrap_err();
+    assert_eq!(err.msg(), "call of null function pointer");
+
+    // Callee must be of function pointer type
+    let err = build(r#"
+@op: *i64
+
+fn @main() {
+%Begin:
+    call @op()
+    ret
+}
+"#).err().unwrap();
+    println!("{:?}", err);
+    assert!(matches!(err.kind(), crate::irc::ErrKind::NotFn(_)));
+}
+
+#[test]
+fn test_multi_ret() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::irc::ErrKind;
+    use crate::lang::print::Printer;
+    use crate::pass::{FnPass, Pass};
+    use crate::pass::adce::AdceOpt;
+    use crate::pass::copy::CopyProp;
+    use crate::pass::gvn::GvnOpt;
+    use crate::pass::inl::Inliner;
+    use crate::pass::pre::PreOpt;
+    use crate::pass::sccp::SccpOpt;
+    use crate::pass::verify::VerifyPass;
+    use crate::test_util::check::equiv;
+
+    let build = |src: &str| Builder::new(Parser::new(Lexer::from(src)).parse()?).build();
+    let src = r#"
+@q: i64
+@r: i64
+
+[inline]
+fn @divmod($a: i64, $b: i64) -> (i64, i64) {
+%Begin:
+    $q <- div i64 $a, $b
+    $m <- mul i64 $q, $b
+    $r <- sub i64 $a, $m
+    ret $q, $r
+}
+
+fn @main() {
+%Begin:
+    $x <- mov i64 17
+    $y <- mov i64 5
+    $q, $r <- call (i64, i64) @divmod($x, $y)
+    $q, @r <- call (i64, i64) @divmod($q, 2)
+    @q <- add i64 $q, $r
+    ret
+}
+"#;
+    let mut before = build(src).unwrap();
+    let mut ver = VerifyPass::new();
+    Pass::run(&mut ver, &mut before);
+    assert!(ver.is_ok());
+    let mut out = vec![];
+    Printer::new(&mut out).print(&before).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    assert!(out.contains("-> (i64, i64)") && out.contains("ret $q, $r"));
+    let rcd = Machine::new().run(&before).unwrap();
+    let get = |name: &str| rcd.global.iter().find(|(g, _)| g.name == name).unwrap().1
+        .get_const();
+    assert_eq!(get("q"), Const::I64(3));
+    assert_eq!(get("r"), Const::I64(1));
+    let (ret, _) = Machine::new().run_fn(&before, &before.func[0], vec![Const::I64(9),
+                                                                        Const::I64(4)]).unwrap();
+    assert_eq!(ret.iter().map(|r| r.get_const()).collect::<Vec<_>>(),
+               vec![Const::I64(2), Const::I64(1)]);
+
+    // Printed program can be parsed again
+    assert!(build(&out).is_ok());
+
+    // Optimizations see every returned value
+    let mut after = build(src).unwrap();
+    after.func.iter().for_each(|f| f.to_ssa());
+    FnPass::run(&mut SccpOpt::new(), &mut after);
+    FnPass::run(&mut GvnOpt {}, &mut after);
+    FnPass::run(&mut PreOpt::new(), &mut after);
+    FnPass::run(&mut CopyProp::new(), &mut after);
+    FnPass::run(&mut AdceOpt::new(), &mut after);
+    Printer::new(&mut std::io::stdout()).print(&after).unwrap();
+    if let Err(err) = equiv(&before, &after, None) { panic!("{}", err) }
+    Pass::run(&mut Inliner::new(), &mut after);
+    Printer::new(&mut std::io::stdout()).print(&after).unwrap();
+    if let Err(err) = equiv(&before, &after, None) { panic!("{}", err) }
+
+    // Number of returned values must match
+    let err = |src: &str| {
+        let err = build(src).err().unwrap();
+        println!("{}", err);
+        err
+    };
+    let err1 = err(r#"
+fn @f() -> (i64, i64) {
+%Begin:
+    ret 1
+}
+"#);
+    assert!(matches!(err1.kind(), ErrKind::ReturnCount { expect: 2, found: 1 }));
+    let err2 = err(r#"
+fn @f() -> (i64, i64) {
+%Begin:
+    ret 1, 2
+}
+
+fn @main() {
+%Begin:
+    $a, $b, $c <- call (i64, i64) @f()
+    ret
+}
+"#);
+    assert!(matches!(err2.kind(), ErrKind::ReturnCount { expect: 2, found: 3 }));
+}
+
+#[test]
+fn test_union() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut file = File::open("test/union.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let tree = Parser::new(lexer).parse().unwrap();
+    let pro = Builder::new(tree).build().unwrap();
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let src = String::from_utf8(out).unwrap();
+    println!("{}", src);
+    assert!(src.contains("type @Word = union { i64, [2]i32, { i8, i16 }, *i64 }"));
+
+    // Size of union is the maximum of its fields
+    let word = pro.global.find("Word").unwrap().get_type();
+    assert_eq!(word.size_of(&DataLayout::x64()), 8);
+
+    let rcd = Machine::new().run(&pro).unwrap();
+    let get = |name: &str| rcd.global.iter().find(|(g, _)| g.name == name).unwrap().1
+        .get_const();
+    assert_eq!(get("lo"), Const::I32(2));
+    assert_eq!(get("hi"), Const::I32(1));
+    assert_eq!(get("b"), Const::I8(2));
+    assert_eq!(get("v"), Const::I64(7));
+
+    // Printed program can be parsed again
+    let tree = Parser::new(Lexer::from(src.as_str())).parse().unwrap();
+    assert!(Builder::new(tree).build().is_ok());
+
+    // Fields of union can only be indexed by constant
+    let src = r#"
+fn @f($i: i64) {
+%Begin:
+    $w <- alloc union { i64, i32 }
+    $p <- ptr *i64 $w [$i]
+    ret
+}
+"#;
+    let tree = Parser::new(Lexer::from(src)).parse().unwrap();
+    assert!(Builder::new(tree).build().is_err());
+}
+
+#[test]
+fn test_rec_type() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::layout::DataLayout;
+    use crate::lang::print::Printer;
+    use crate::vm::exec::Machine;
+    use std::fs::File;
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut file = File::open("test/list.ir").unwrap();
+    let lexer = Lexer::try_from(&mut file as &mut dyn Read).unwrap();
+    let tree = Parser::new(lexer).parse().unwrap();
+    let pro = Builder::new(tree).build().unwrap();
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let src = String::from_utf8(out).unwrap();
+    println!("{}", src);
+    assert!(src.contains("type @Node = { i64, *@Node }"));
+    let node = pro.global.find("Node").unwrap().get_type();
+    assert_eq!(node.size_of(&DataLayout::x64()), 16);
+    assert_eq!(node.elem_type(1).tgt_type(), node);
+
+    let rcd = Machine::new().run(&pro).unwrap();
+    let total = rcd.global.iter().find(|(g, _)| g.name == "total").unwrap();
+    assert_eq!(total.1.get_const(), Const::I64(6));
+
+    // Printed program can be built again
+    let tree = Parser::new(Lexer::from(src.as_str())).parse().unwrap();
+    let pro = Builder::new(tree).build().unwrap();
+    assert!(Machine::new().run(&pro).is_ok());
+}
+
+#[test]
+fn test_main_args() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+
+    let src = r#"
+@r: i64
+
+fn @main($argc: i64, $argv: **i8) -> i64 {
+%Begin:
+    $p <- ptr **i8 $argv, 1
+    $s <- ld *i8 $p
+    $q <- ptr *i8 $s, 1
+    $c <- ld i8 $q
+    $t <- ptr *i8 $s, 2
+    $z <- ld i8 $t
+    $d <- sext i8 $c -> i64
+    $e <- sext i8 $z -> i64
+    $f <- add i64 $d, $e
+    @r <- mov i64 $f
+    $x <- add i64 $argc, 40
+    ret $x
+}
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let rcd = Machine::new().run_with_args(&pro, &["prog", "ab"]).unwrap();
+    println!("{:?}", rcd);
+    assert_eq!(rcd.exit, 42);
+    assert_eq!(rcd.global[0].1.get_const(), Const::I64(98));
+    assert!(format!("{:?}", rcd).starts_with("program terminated with exit code 42"));
+
+    // Other signatures of main are rejected
+    for sig in ["fn @main($x: i64)", "fn @main() -> i32", "fn @main($c: i64, $v: *i8) -> i64"] {
+        let src = format!("{} {{\n%Begin:\n    unreachable\n}}\n", sig);
+        let tree = Parser::new(Lexer::from(src.as_str())).parse().unwrap();
+        assert!(Builder::new(tree).build().is_err(), "{} accepted", sig);
+    }
+}
+
+#[test]
+fn test_profile() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::lang::meta::MetaVal;
+    use crate::lang::print::Printer;
+
+    let src = r#"
+@r: i64
+
+fn @f($x: i64) -> i64 {
+%Begin:
+    $c <- lt i64 $x, 3
+    br $c ? %Small : %Big
+%Small:
+    ret 0
+%Big:
+    $y <- mul i64 $x, $x
+    ret $y
+}
+
+fn @main() {
+%Begin:
+    jmp %Loop
+%Loop:
+    $i <- phi i64 [%Begin: 0] [%Loop: $j]
+    $s <- phi i64 [%Begin: 0] [%Loop: $t]
+    $v <- call i64 @f($i)
+    $t <- add i64 $s, $v
+    $j <- add i64 $i, 1
+    $d <- lt i64 $j, 10
+    br $d ? %Loop : %End
+%End:
+    @r <- mov i64 $t
+    ret
+}
+"#;
+    let pro = Builder::new(Parser::new(Lexer::from(src)).parse().unwrap()).build().unwrap();
+    let mut mach = Machine::new();
+    assert!(mach.run(&pro).unwrap().profile.is_none());
+
+    // Blocks, calls and branches are counted exactly, and all instructions are sampled with
+    // period 1
+    mach.set_profile(Some(1));
+    let rcd = mach.run(&pro).unwrap();
+    let prof = rcd.profile.unwrap();
+    let (f, main) = (&pro.func[0], &pro.func[1]);
+    let block = |func: &FnRef, name: &str| func.dfs().find(|b| b.name == name).unwrap();
+    assert_eq!(prof.func[f], 10);
+    assert_eq!(prof.block[&block(main, "Loop")], 10);
+    assert_eq!(prof.block[&block(f, "Small")], 3);
+    assert_eq!(prof.inst_count(&block(main, "Loop")), 10 * 7);
+    assert_eq!(prof.hot_blocks()[0].0, block(main, "Loop"));
+    assert_eq!(prof.samples.values().sum::<usize>(), rcd.count.num);
+    let br = block(f, "Begin").tail();
+    assert_eq!(prof.branch[&br], (3, 7));
+
+    // Profile survives printing and parsing as metadata
+    prof.annotate(&pro);
+    let mut out = vec![];
+    Printer::new(&mut out).print(&pro).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    for s in ["fn @f($x: i64) -> i64 !calls 10", "br $c ? %Small : %Big !likely 0",
+        "%Big: !freq 7", "$v <- call i64 @f($i) !prof 10", "%End: !freq 1"] {
+        assert!(out.contains(s), "{} not printed", s);
+    }
+    let pro = Builder::new(Parser::new(Lexer::from(out.as_str())).parse().unwrap())
+        .build().unwrap();
+    assert_eq!(block(&pro.func[1], "Loop").meta.borrow().get("freq"), Some(&MetaVal::Int(10)));
+
+    // With a longer period, fewer samples are taken, and a new run starts a new profile
+    mach.set_profile(Some(4));
+    let rcd = mach.run(&pro).unwrap();
+    let prof = rcd.profile.unwrap();
+    assert_eq!(prof.samples.values().sum::<usize>(), rcd.count.num / 4);
+    let prof = mach.run(&pro).unwrap().profile.unwrap();
+    assert_eq!(prof.func[&pro.func[0]], 10);
+}
+
+#[test]
+fn test_thread() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::vm::exec::Machine;
+
+    let build = |src: &str| Builder::new(Parser::new(Lexer::from(src)).parse()?).build();
+    let src = std::fs::read_to_string("test/thread.ir").unwrap();
+    let pro = build(&src).unwrap();
+    let rcd = Machine::new().run(&pro).unwrap();
+    let get = |name: &str| rcd.global.iter().find(|(g, _)| g.name == name).unwrap().1
+        .get_const();
+    assert_eq!(get("r"), Const::I64(42));
+    assert_eq!(get("s"), Const::I64(200));
+
+    // Data is read without synchronization
+    let racy = src.replace("ld.atomic acquire", "ld.atomic relaxed");
+    let err = Machine::new().run(&build(&racy).unwrap()).unwrap_err();
+    assert_eq!(err.msg(), "data race on @data: write in thread 3 and read in thread 0");
+
+    // Threads wait for each other
+    let dead = src.replace("    ret\n}\n\n// Increment", "    call @thread.join(0)\n    ret\n}\n\n\
+        // Increment").replace("%Spin:", "%Spin:\n    call @thread.join($p)");
+    let err = Machine::new().run(&build(&dead).unwrap()).unwrap_err();
+    assert_eq!(err.msg(), "deadlock: all threads are blocked");
+
+    // Intrinsics must be defined with expected signatures
+    let bad = src.replace("$t: i64) {", "$t: i32) {").replace("join($c1)", "join(0)")
+        .replace("join($c2)", "join(1)");
+    let err = Machine::new().run(&build(&bad).unwrap()).unwrap_err();
+    assert_eq!(err.msg(), "intrinsic @thread.join has invalid signature fn(i32)");
+}
@@ -0,0 +1,257 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Error, Formatter};
+use std::mem::{ManuallyDrop, size_of};
+use std::ops::Deref;
+use std::rc::{Rc, Weak};
+
+use crate::lang::layout::DataLayout;
+use crate::lang::util::MutRc;
+use crate::lang::value::Type;
+use crate::vm::mem::{HeapSpace, MemSpace, Reg, Stack};
+
+/// Heap space size that triggers the first collection, in bytes
+const INIT_THRESHOLD: usize = 1 << 20;
+
+/// Object allocated by `new`
+struct Object {
+    /// Storage of this object. The heap does not keep objects alive by itself.
+    space: Weak<RefCell<Vec<u8>>>,
+    /// Type of elements
+    ty: Type,
+    /// Number of elements
+    len: usize,
+    /// Size in bytes
+    size: usize,
+}
+
+/// Allocation statistics of a single type
+#[derive(Clone, Default, Debug)]
+pub struct TypeStat {
+    /// Number of allocated objects
+    pub n_alloc: usize,
+    /// Total bytes of allocated objects
+    pub alloc_size: usize,
+    /// Number of freed objects
+    pub n_free: usize,
+    /// Total bytes of freed objects
+    pub free_size: usize,
+}
+
+/// Heap statistics of a program execution
+#[derive(Clone, Default)]
+pub struct HeapStat {
+    /// Statistics of each element type, keyed by the type name
+    pub ty: BTreeMap<String, TypeStat>,
+    /// Number of garbage collections
+    pub n_gc: usize,
+    /// Maximal bytes of live objects
+    pub peak: usize,
+    /// Objects still reachable from global variables when the program terminates, and their
+    /// total bytes. These are never freed by the program.
+    pub n_live: usize,
+    pub live: usize,
+}
+
+impl Debug for HeapStat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        writeln!(f, "collections: {}  peak: {}  live at exit: {} ({} objects)",
+                 self.n_gc, self.peak, self.live, self.n_live)?;
+        for (ty, s) in self.ty.iter() {
+            writeln!(f, "{}: allocated {} ({} objects)  freed {} ({} objects)", ty,
+                     s.alloc_size, s.n_alloc, s.free_size, s.n_free)?;
+        }
+        Ok(())
+    }
+}
+
+/// Heap memory manager of the VM.
+/// Heap spaces are reference counted, but pointers stored in memory are not tracked by the
+/// counters, so spaces reachable from memory are never freed by counting alone. A mark-sweep
+/// collection traces pointers from the roots through stack and heap memory, and releases the
+/// storage of all unreachable objects.
+pub struct Heap {
+    /// All objects which are not freed
+    obj: Vec<Object>,
+    /// Total bytes of objects in `obj`
+    size: usize,
+    /// Size of heap that triggers next collection
+    threshold: usize,
+    /// Size of heap that triggers the first collection
+    init_threshold: usize,
+    stat: HeapStat,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            obj: vec![],
+            size: 0,
+            threshold: INIT_THRESHOLD,
+            init_threshold: INIT_THRESHOLD,
+            stat: Default::default(),
+        }
+    }
+
+    /// Set heap size that triggers the first collection.
+    pub fn set_threshold(&mut self, size: usize) {
+        self.threshold = size;
+        self.init_threshold = size;
+    }
+
+    /// Whether a collection should be done before allocating `size` more bytes
+    pub fn need_collect(&self, size: usize) -> bool { self.size + size > self.threshold }
+
+    /// Allocate an object of `len` elements of type `ty`, which takes `size` bytes.
+    pub fn alloc(&mut self, ty: &Type, len: usize, size: usize) -> HeapSpace {
+        let space = MutRc::new(vec![0; size]);
+        let obj = Object { space: Rc::downgrade(&space.0), ty: ty.clone(), len, size };
+        let stat = self.stat.ty.entry(ty.to_string()).or_default();
+        stat.n_alloc += 1;
+        stat.alloc_size += obj.size;
+        self.size += obj.size;
+        self.stat.peak = self.stat.peak.max(self.size);
+        self.obj.push(obj);
+        space
+    }
+
+    /// Collect objects that are not reachable from `roots` or memory of `stacks`.
+    pub fn collect(&mut self, roots: Vec<HeapSpace>, stacks: &[&Stack], layout: &DataLayout) {
+        self.stat.n_gc += 1;
+        let index: HashMap<*const RefCell<Vec<u8>>, usize> = self.obj.iter().enumerate()
+            .map(|(i, o)| (o.space.as_ptr(), i)).collect();
+        let mut marked = vec![false; self.obj.len()];
+        let mut work = roots;
+        for (ty, mem) in stacks.iter().flat_map(|s| s.spaces()) {
+            Self::scan(mem, ty, 1, layout, &mut work)
+        }
+
+        // Mark all reachable objects
+        while let Some(space) = work.pop() {
+            let i = match index.get(&Rc::as_ptr(&space.0)) {
+                Some(i) => *i,
+                None => continue
+            };
+            if marked[i] { continue; }
+            marked[i] = true;
+            let obj = &self.obj[i];
+            Self::scan(&space.borrow(), &obj.ty, obj.len, layout, &mut work);
+        }
+
+        // Sweep unreachable objects
+        let obj = std::mem::take(&mut self.obj);
+        for (obj, marked) in obj.into_iter().zip(marked) {
+            if marked {
+                self.obj.push(obj);
+                continue;
+            }
+            // Storage is released here. Objects freed by reference counting are also counted.
+            if let Some(space) = obj.space.upgrade() { *space.borrow_mut() = vec![] }
+            let stat = self.stat.ty.get_mut(&obj.ty.to_string()).unwrap();
+            stat.n_free += 1;
+            stat.free_size += obj.size;
+            self.size -= obj.size;
+        }
+        self.threshold = self.init_threshold.max(self.size * 2);
+    }
+
+    /// Collect all unreachable objects at program termination, and return the statistics.
+    pub fn finish(&mut self, roots: Vec<HeapSpace>, stacks: &[&Stack], layout: &DataLayout)
+                  -> HeapStat {
+        self.collect(roots, stacks, layout);
+        self.stat.n_gc -= 1; // not triggered by the program
+        self.stat.n_live = self.obj.len();
+        self.stat.live = self.size;
+        let stat = std::mem::take(&mut self.stat);
+        self.obj.clear();
+        self.size = 0;
+        self.threshold = self.init_threshold;
+        stat
+    }
+
+    /// Push heap spaces pointed by pointers in memory `mem`, which holds `len` elements of
+    /// type `ty`, to `work`.
+    fn scan(mem: &[u8], ty: &Type, len: usize, layout: &DataLayout,
+            work: &mut Vec<HeapSpace>) {
+        let mut slot = vec![];
+        Self::ptr_slot(ty, 0, layout, &mut slot);
+        if slot.is_empty() { return; }
+        let size = ty.size_of(layout);
+        for i in 0..len {
+            for off in slot.iter().map(|s| i * size + s) {
+                if off + size_of::<Reg>() > mem.len() { continue; }
+                // The register in memory is borrowed, so it should not be dropped here
+                let ptr = &mem[off] as *const u8 as *const Reg;
+                let reg = ManuallyDrop::new(unsafe { ptr.read_unaligned() });
+                if let Reg::Ptr { base: Some(MemSpace::Heap(space)), off: _ } = reg.deref() {
+                    work.push(space.clone())
+                }
+            }
+        }
+    }
+
+    /// Find offsets of pointers in type `ty`. Pointers in unions are not traced, as it is
+    /// unknown which field a union currently holds.
+    fn ptr_slot(ty: &Type, base: usize, layout: &DataLayout, slot: &mut Vec<usize>) {
+        match ty.orig() {
+            Type::Ptr(_) => slot.push(base),
+            Type::Array { elem: _, len } => (0..len).for_each(|i| {
+                Self::ptr_slot(&ty.elem_type(i), base + ty.field_offset(i, layout), layout, slot)
+            }),
+            Type::Struct { field } => (0..field.len()).for_each(|i| {
+                Self::ptr_slot(&field[i], base + ty.field_offset(i, layout), layout, slot)
+            }),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_heap() {
+    use crate::irc::lex::Lexer;
+    use crate::irc::parse::Parser;
+    use crate::irc::build::Builder;
+    use crate::vm::exec::Machine;
+
+    // Each iteration links a new integer to a new box, which are both dropped in the next
+    // iteration
+    let src = r#"
+type @Box = { i64, *i64 }
+
+@r: i64
+@keep: *@Box
+
+fn @main() {
+%Begin:
+    jmp %Loop
+%Loop:
+    $i <- phi i64 [%Begin: 0] [%Loop: $j]
+    $a <- new @Box
+    $b <- new i64
+    $p <- ptr **i64 $a [1]
+    st *i64 $b -> $p
+    $j <- add i64 $i, 1
+    $c <- lt i64 $j, 100
+    br $c ? %Loop : %End
+%End:
+    @keep <- new @Box
+    $v <- ptr *i64 @keep [0]
+    st i64 $j -> $v
+    @r <- ld i64 $v
+    ret
+}
+"#;
+    let tree = Parser::new(Lexer::from(src)).parse().unwrap();
+    let pro = Builder::new(tree).build().unwrap();
+    let mut mach = Machine::new();
+    mach.set_gc_threshold(256);
+    let rcd = mach.run(&pro).unwrap();
+    println!("{:?}", rcd);
+    for ty in ["@Box", "i64"] {
+        let stat = &rcd.heap.ty[ty];
+        assert_eq!(stat.n_alloc - stat.n_free, if ty == "@Box" { 1 } else { 0 });
+    }
+    assert!(rcd.heap.n_gc > 0);
+    assert!(rcd.heap.peak <= 512);
+    assert_eq!(rcd.heap.n_live, 1);
+}
@@ -1,10 +1,8 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Error, Formatter};
-use std::mem::size_of;
-use std::ops::Add;
-use std::rc::Rc;
 
-use crate::lang::func::{BlockRef, Fn, FnRef};
+use crate::irc::Loc;
+use crate::lang::func::{BlockRef, FnRef};
 use crate::lang::util::MutRc;
 use crate::lang::value::{Const, SymbolRef, Type};
 
@@ -13,6 +11,8 @@ use crate::lang::value::{Const, SymbolRef, Type};
 pub enum MemSpace {
     Stack(usize),
     Heap(HeapSpace),
+    /// Code of a function, which can only be called, not accessed
+    Fn(FnRef),
 }
 
 /// Use reference counting to manage heap memory
@@ -30,10 +30,12 @@ pub struct Stack {
     frame: Vec<FrameRef>,
     /// All allocated spaces
     alloc: Vec<Vec<u8>>,
+    /// Types of allocated spaces
+    ty: Vec<Type>,
 }
 
 impl Stack {
-    pub fn new() -> Stack { Stack { frame: vec![], alloc: vec![] } }
+    pub fn new() -> Stack { Stack { frame: vec![], alloc: vec![], ty: vec![] } }
 
     pub fn unwind(&self) -> Vec<FrameRef> { self.frame.clone() }
 
@@ -55,15 +57,18 @@ impl Stack {
         self.frame.pop().map(|frame| {
             for _ in 0..frame.borrow().count {
                 self.alloc.pop();
+                self.ty.pop();
             }
         });
     }
 
     pub fn top(&mut self) -> FrameRef { self.frame.last().unwrap().clone() }
 
-    pub fn alloc(&mut self, size: usize) -> Reg {
+    /// Allocate `size` bytes for a value of type `ty` in the top frame.
+    pub fn alloc(&mut self, ty: &Type, size: usize) -> Reg {
         let addr = self.alloc.len();
         self.alloc.push(vec![0; size]);
+        self.ty.push(ty.clone());
         self.top().borrow_mut().count += 1;
         Reg::Ptr { base: Some(MemSpace::Stack(addr)), off: 0 }
     }
@@ -74,9 +79,15 @@ impl Stack {
         self.alloc.get_mut(addr)
     }
 
+    /// Iterate all allocated spaces, along with their types.
+    pub fn spaces(&self) -> impl Iterator<Item=(&Type, &Vec<u8>)> {
+        self.ty.iter().zip(self.alloc.iter())
+    }
+
     pub fn clear(&mut self) {
         self.frame.clear();
         self.alloc.clear();
+        self.ty.clear();
     }
 }
 
@@ -93,6 +104,13 @@ pub struct Frame {
     count: usize,
 }
 
+impl Frame {
+    /// Source location of the instruction being executed, if there is one.
+    pub fn loc(&self) -> Option<Loc> {
+        self.block.inst.borrow().get(self.instr).and_then(|instr| self.func.inst_loc(instr))
+    }
+}
+
 /// Frames must be held as references instead of values, because previous function calls will hold
 /// reference to their frames, which prevent the stack from growing.
 pub type FrameRef = MutRc<Frame>;
@@ -168,18 +186,3 @@ impl Reg {
     }
 }
 
-impl Type {
-    /// Indicate runtime size of types in this VM
-    pub fn size(&self) -> usize {
-        match self {
-            Type::Void => 0,
-            Type::I(1) => size_of::<bool>(),
-            Type::I(b) => *b as usize / 8,
-            Type::Ptr(_) => size_of::<Reg>(),
-            Type::Array { elem, len } => elem.size() * *len,
-            Type::Struct { field } => field.iter().map(|f| f.size()).fold(0, Add::add),
-            Type::Fn { param: _, ret: _ } => size_of::<Rc<Fn>>(),
-            Type::Alias(_) => self.orig().size(),
-        }
-    }
-}
@@ -1,3 +1,5 @@
 pub mod exec;
 pub mod mem;
-pub mod stat;
\ No newline at end of file
+pub mod heap;
+pub mod stat;
+pub mod thread;
\ No newline at end of file
@@ -1,7 +1,10 @@
-use std::ops::{Add, Deref};
+use std::collections::HashMap;
 
-use crate::lang::inst::{BinOp, Inst};
-use crate::lang::value::{Type, Typed, Value};
+use crate::lang::cost::{CostModel, DefaultCost};
+use crate::lang::func::{BlockRef, FnRef};
+use crate::lang::inst::{Inst, InstRef};
+use crate::lang::meta::MetaVal;
+use crate::lang::Program;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Counter {
@@ -21,73 +24,108 @@ impl Counter {
 
     pub fn count(&mut self, instr: &Inst) {
         self.num += 1;
-        let mut time = match instr {
-            Inst::Mov { src: _, dst: _ } => MOV,
-            Inst::Un { op: _, opd: _, dst: _ } => UN_OP,
-            Inst::Bin { op, fst, snd: _, dst: _ } => {
-                let ty = fst.borrow().get_type();
-                match op {
-                    op if op.is_bitwise() | op.is_cmp() | op.is_shift() => FAST_BIN,
-                    BinOp::Add | BinOp::Sub => FAST_BIN,
-                    BinOp::Mul => IMUL,
-                    BinOp::Div => match ty {
-                        Type::I(64) => I64_DIV,
-                        Type::I(_) => IDIV,
-                        _ => unreachable!()
+        self.time += DefaultCost.latency(instr);
+    }
+}
+
+/// Execution profile of a program run in VM.
+/// Entries of functions and blocks, executions of calls and outcomes of branches are counted
+/// exactly. Executed instructions are sampled once every `period` instructions, and each
+/// sample is attributed to the block being executed, so a longer period trades accuracy of hot
+/// path detection for speed.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    /// Number of executed instructions between two samples
+    pub period: usize,
+    /// Number of instructions to execute before the next sample
+    next: usize,
+    /// Number of calls of each function
+    pub func: HashMap<FnRef, usize>,
+    /// Number of times each block is entered
+    pub block: HashMap<BlockRef, usize>,
+    /// Number of samples taken in each block
+    pub samples: HashMap<BlockRef, usize>,
+    /// Number of times each call instruction is executed
+    pub call: HashMap<InstRef, usize>,
+    /// Number of times each branch goes to its true and false targets
+    pub branch: HashMap<InstRef, (usize, usize)>,
+}
+
+impl Profile {
+    pub fn new(period: usize) -> Profile {
+        let period = period.max(1);
+        Profile {
+            period,
+            next: period,
+            func: Default::default(),
+            block: Default::default(),
+            samples: Default::default(),
+            call: Default::default(),
+            branch: Default::default(),
+        }
+    }
+
+    pub fn enter_fn(&mut self, func: &FnRef) { *self.func.entry(func.clone()).or_default() += 1 }
+
+    pub fn enter_block(&mut self, block: &BlockRef) {
+        *self.block.entry(block.clone()).or_default() += 1
+    }
+
+    /// Count an instruction executed in `block`, and take a sample if the period elapses.
+    pub fn exec(&mut self, block: &BlockRef) {
+        self.next -= 1;
+        if self.next == 0 {
+            *self.samples.entry(block.clone()).or_default() += 1;
+            self.next = self.period;
+        }
+    }
+
+    pub fn call(&mut self, instr: &InstRef) { *self.call.entry(instr.clone()).or_default() += 1 }
+
+    pub fn branch(&mut self, instr: &InstRef, taken: bool) {
+        let (tr, fls) = self.branch.entry(instr.clone()).or_default();
+        if taken { *tr += 1 } else { *fls += 1 }
+    }
+
+    /// Estimated number of instructions executed in `block`.
+    pub fn inst_count(&self, block: &BlockRef) -> usize {
+        self.samples.get(block).cloned().unwrap_or(0) * self.period
+    }
+
+    /// Blocks with samples, sorted by decreasing number of estimated instructions executed in
+    /// them. Ties are broken by entry count and then by name.
+    pub fn hot_blocks(&self) -> Vec<(BlockRef, usize)> {
+        let mut hot: Vec<_> = self.samples.keys().map(|b| (b.clone(), self.inst_count(b)))
+            .collect();
+        hot.sort_by(|(a, x), (b, y)| y.cmp(x).then_with(|| self.block[b].cmp(&self.block[a]))
+            .then_with(|| a.name.cmp(&b.name)));
+        hot
+    }
+
+    /// Attach the profile to `pro` as metadata, in the form read by profile-guided passes:
+    /// `!calls` of functions, `!freq` of blocks, `!prof` of calls and `!likely` of branches.
+    /// Blocks of called functions that are never entered get `!freq 0`. Existing metadata with
+    /// these keys are overwritten. Since metadata is printed and parsed with the program, the
+    /// profile can be saved with the IR and reused in later compilations.
+    pub fn annotate(&self, pro: &Program) {
+        for func in &pro.func {
+            let calls = match self.func.get(func) {
+                Some(n) => *n,
+                None => continue
+            };
+            func.meta.borrow_mut().insert("calls".to_string(), MetaVal::Int(calls as i64));
+            for block in func.dfs() {
+                let freq = self.block.get(&block).cloned().unwrap_or(0);
+                block.meta.borrow_mut().insert("freq".to_string(), MetaVal::Int(freq as i64));
+                for instr in block.inst.borrow().iter() {
+                    if let Some(n) = self.call.get(instr) {
+                        func.set_inst_meta(instr, "prof", MetaVal::Int(*n as i64))
                     }
-                    _ => unreachable!()
-                }
-            }
-            Inst::Call { func: _, arg, dst: _ } => CALL + arg.len() * MOV,
-            Inst::Ret { val: _ } => RET,
-            Inst::Jmp { tgt: _ } | Inst::Br { cond: _, tr: _, fls: _ } => JMP,
-            Inst::Phi { src: _, dst: _ } => MOV,
-            Inst::Alloc { dst: _ } => MOV,
-            Inst::New { dst: _, len: _ } => NEW,
-            Inst::Ptr { base: _, off, ind, dst: _ } => {
-                let mut opd: Vec<_> = ind.iter().collect();
-                off.as_ref().map(|off| opd.push(off));
-                opd.iter().map(|opd| {
-                    match opd.borrow().deref() {
-                        // all constant offset can be computed at irc time
-                        Value::Const(_) => 0,
-                        // variable offset require one multiplication and one addition
-                        Value::Var(_) => IMUL + FAST_BIN
+                    if let Some((tr, fls)) = self.branch.get(instr) {
+                        func.set_inst_meta(instr, "likely", MetaVal::Int((tr >= fls) as i64))
                     }
-                }).fold(1, Add::add)
+                }
             }
-            Inst::Ld { ptr: _, dst: _ } | Inst::St { src: _, ptr: _ } => MEM,
-        };
-        instr.dst().map(|dst| if !dst.borrow().is_local_var() { time += GLB_PEN });
-        self.time += time;
+        }
     }
 }
-
-/// Weights for all basic operations
-/// These values are based on number if clock cycles to do the corresponding computation in modern
-/// processors. They can be changed to suit your need.
-/// See [https://www.agner.org/optimize/]
-
-/// Move between registers
-const MOV: usize = 1;
-/// If the instruction writes to global variable, add this additional penalty.
-const GLB_PEN: usize = 1;
-/// Unary operations
-const UN_OP: usize = 1;
-/// Binary operations that can be done fast
-const FAST_BIN: usize = 1;
-/// Multiplication of integers
-const IMUL: usize = 2;
-/// Division of integers
-const IDIV: usize = 10;
-const I64_DIV: usize = 40;
-/// Call a function
-const CALL: usize = 3;
-/// Pop stack pointer
-const RET: usize = 1;
-/// Jump
-const JMP: usize = 1;
-/// Allocate memory on heap
-const NEW: usize = 10;
-/// Memory access
-const MEM: usize = 2;
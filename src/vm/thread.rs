@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::lang::func::FnRef;
+use crate::lang::value::{GlobalVarRef, Type, Typed};
+use crate::vm::mem::{MemSpace, RegFile, Stack};
+
+/// Functions whose calls are interpreted by the VM instead of executing their bodies. Their bodies
+/// are still ordinary IR, which should provide a sequential implementation for other targets.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Intrinsic {
+    /// `@thread.spawn($f, $a...)` calls function pointer `$f` with the remaining arguments in a
+    /// new thread, and returns ID of this thread as `i64` if it has a return value.
+    Spawn,
+    /// `@thread.join($t)` blocks until thread `$t` returns. Its returned values are discarded.
+    Join,
+}
+
+impl Intrinsic {
+    pub fn from_fn(func: &FnRef) -> Option<Intrinsic> {
+        match func.name.as_str() {
+            "thread.spawn" => Some(Intrinsic::Spawn),
+            "thread.join" => Some(Intrinsic::Join),
+            _ => None
+        }
+    }
+
+    /// Whether `func` has the signature this intrinsic expects.
+    pub fn check(&self, func: &FnRef) -> bool {
+        let param: Vec<_> = func.param.iter().map(|p| p.borrow().get_type()).collect();
+        match self {
+            Intrinsic::Spawn => match param.split_first() {
+                Some((Type::Ptr(tgt), arg)) => match tgt.as_ref() {
+                    Type::Fn { param, ret: _ } => param.as_slice() == arg &&
+                        (func.ret == Type::Void || func.ret == Type::I(64)),
+                    _ => false
+                }
+                _ => false
+            }
+            Intrinsic::Join => param == [Type::I(64)] && func.ret == Type::Void
+        }
+    }
+}
+
+/// A thread of execution in the VM. The running thread keeps its stack and registers in the
+/// machine, so these fields are only valid for suspended threads.
+pub struct Thread {
+    pub stack: Stack,
+    /// Register file of the top frame
+    pub file: RegFile,
+    /// Register files of callers
+    pub suspended: Vec<RegFile>,
+    pub state: ThreadState,
+    pub clock: Clock,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ThreadState {
+    Ready,
+    /// Blocked by `@thread.join` until the given thread is done
+    Join(usize),
+    Done,
+}
+
+impl Thread {
+    pub fn new(id: usize, clock: Clock) -> Thread {
+        let mut clock = clock;
+        clock.tick(id);
+        Thread {
+            stack: Stack::new(),
+            file: Default::default(),
+            suspended: vec![],
+            state: ThreadState::Ready,
+            clock,
+        }
+    }
+}
+
+/// Vector clock of a thread, which records the latest event of each thread that happens before
+/// the current event of this thread.
+#[derive(Clone, Default, Debug)]
+pub struct Clock(Vec<usize>);
+
+impl Clock {
+    pub fn get(&self, id: usize) -> usize { self.0.get(id).copied().unwrap_or(0) }
+
+    /// Advance time of thread `id`.
+    pub fn tick(&mut self, id: usize) {
+        if self.0.len() <= id { self.0.resize(id + 1, 0) }
+        self.0[id] += 1;
+    }
+
+    /// Merge events known by another clock.
+    pub fn join(&mut self, other: &Clock) {
+        if self.0.len() < other.0.len() { self.0.resize(other.0.len(), 0) }
+        self.0.iter_mut().zip(other.0.iter()).for_each(|(t, o)| *t = (*t).max(*o))
+    }
+}
+
+/// Detect data races on global variables with vector clocks. Two accesses to a global variable
+/// race if they are from different threads, at least one of them writes, and neither happens
+/// before the other. Threads synchronize by spawning, joining, and atomic accesses with acquire
+/// and release orderings.
+#[derive(Default)]
+pub struct RaceDetector {
+    access: HashMap<GlobalVarRef, Access>,
+    /// Clocks released to memory locations by atomic accesses
+    sync: BTreeMap<(MemSpace, usize), Clock>,
+    /// Message of the first race found
+    race: Option<String>,
+}
+
+/// Access history of a global variable. Each access is recorded as pair of thread ID and its time.
+#[derive(Default)]
+struct Access {
+    write: Option<(usize, usize)>,
+    read: Vec<(usize, usize)>,
+}
+
+impl RaceDetector {
+    pub fn read(&mut self, var: &GlobalVarRef, id: usize, clock: &Clock) {
+        let access = self.access.entry(var.clone()).or_default();
+        if let Some((w, t)) = access.write {
+            if w != id && t > clock.get(w) && self.race.is_none() {
+                self.race = Some(Self::msg(var, "write", w, "read", id))
+            }
+        }
+        access.read.retain(|(r, _)| *r != id);
+        access.read.push((id, clock.get(id)));
+    }
+
+    pub fn write(&mut self, var: &GlobalVarRef, id: usize, clock: &Clock) {
+        let access = self.access.entry(var.clone()).or_default();
+        let prev = access.write.iter().map(|acc| (acc, "write"))
+            .chain(access.read.iter().map(|acc| (acc, "read")))
+            .find(|((a, t), _)| *a != id && *t > clock.get(*a));
+        if let Some(((a, _), kind)) = prev {
+            if self.race.is_none() { self.race = Some(Self::msg(var, kind, *a, "write", id)) }
+        }
+        access.write = Some((id, clock.get(id)));
+        access.read.clear();
+    }
+
+    /// Merge clock released to memory location into `clock`.
+    pub fn acquire(&self, loc: &(MemSpace, usize), clock: &mut Clock) {
+        if let Some(rel) = self.sync.get(loc) { clock.join(rel) }
+    }
+
+    /// Release `clock` to memory location.
+    pub fn release(&mut self, loc: (MemSpace, usize), clock: &Clock) {
+        self.sync.entry(loc).or_default().join(clock)
+    }
+
+    /// Take message of the race found, if there is one.
+    pub fn take(&mut self) -> Option<String> { self.race.take() }
+
+    fn msg(var: &GlobalVarRef, prev: &str, prev_id: usize, cur: &str, cur_id: usize) -> String {
+        format!("data race on @{}: {} in thread {} and {} in thread {}", var.name, prev, prev_id,
+                cur, cur_id)
+    }
+}